@@ -0,0 +1,364 @@
+//! Admission control for model generations.
+//!
+//! At most `chat.max_concurrent_generations` generations run at once
+//! (0 = unlimited); later requests wait, up to `chat.max_queued`.
+//! Interactive waiters are always served before batch ones, and
+//! `chat.interactive_reserved_generations` slots are kept free of batch
+//! work. A waiter can see its position and an estimated wait derived from a
+//! moving average of recent generation times.
+
+use ds_core::config::ChatSection;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use serde::{Deserialize, Serialize};
+use tokio::sync::oneshot;
+
+/// Scheduling class of a chat request, highest first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    Interactive,
+    Batch,
+}
+
+impl Priority {
+    pub const ALL: [Priority; 2] = [Priority::Interactive, Priority::Batch];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Priority::Interactive => "interactive",
+            Priority::Batch => "batch",
+        }
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+pub struct Admission {
+    max_running: usize,
+    max_queued: usize,
+    /// Slots batch work may never take
+    reserved: usize,
+    inner: Mutex<Inner>,
+    /// Moving average of generation time in ms; 0 until one finishes
+    avg_ms: AtomicU64,
+}
+
+#[derive(Default)]
+struct Inner {
+    /// Per [`Priority`]
+    running: [usize; 2],
+    queues: [VecDeque<Waiter>; 2],
+    next_id: u64,
+}
+
+impl Inner {
+    fn total_running(&self) -> usize {
+        self.running.iter().sum()
+    }
+
+    fn total_queued(&self) -> usize {
+        self.queues.iter().map(VecDeque::len).sum()
+    }
+}
+
+struct Waiter {
+    id: u64,
+    tx: oneshot::Sender<()>,
+}
+
+/// Outcome of [`Admission::enqueue`]
+pub enum Ticket {
+    Admitted(Permit),
+    Queued(Queued),
+}
+
+impl Admission {
+    pub fn new(max_running: usize, max_queued: usize) -> Self {
+        Self::with_reserved(max_running, max_queued, 0)
+    }
+
+    /// Keep `reserved` of the `max_running` slots for interactive work
+    pub fn with_reserved(max_running: usize, max_queued: usize, reserved: usize) -> Self {
+        Self {
+            max_running,
+            max_queued,
+            reserved: reserved.min(max_running),
+            inner: Mutex::default(),
+            avg_ms: AtomicU64::new(0),
+        }
+    }
+
+    pub fn from_config(cfg: &ChatSection) -> Self {
+        Self::with_reserved(
+            cfg.max_concurrent_generations as usize,
+            cfg.max_queued as usize,
+            cfg.interactive_reserved_generations as usize,
+        )
+    }
+
+    fn has_room(&self, inner: &Inner, priority: Priority) -> bool {
+        let limit = match priority {
+            Priority::Interactive => self.max_running,
+            Priority::Batch => self.max_running - self.reserved,
+        };
+        self.max_running == 0 || inner.total_running() < limit
+    }
+
+    /// Take a slot now or join the queue; `None` when the queue is full
+    pub fn enqueue(self: &Arc<Self>, priority: Priority) -> Option<Ticket> {
+        let mut inner = self.inner.lock().unwrap();
+        // Nobody of the same or a higher class may be overtaken
+        let ahead = inner.queues[..=priority.index()].iter().any(|q| !q.is_empty());
+        if self.has_room(&inner, priority) && !ahead {
+            inner.running[priority.index()] += 1;
+            return Some(Ticket::Admitted(Permit::new(self.clone(), priority, None)));
+        }
+        if inner.total_queued() >= self.max_queued {
+            return None;
+        }
+        let (tx, rx) = oneshot::channel();
+        inner.next_id += 1;
+        let id = inner.next_id;
+        inner.queues[priority.index()].push_back(Waiter { id, tx });
+        Some(Ticket::Queued(Queued {
+            admission: self.clone(),
+            priority,
+            id,
+            rx,
+            since: Instant::now(),
+            done: false,
+        }))
+    }
+
+    /// Return a finished generation's slot and hand free slots to waiters,
+    /// highest class first
+    fn release(&self, priority: Priority) {
+        let mut inner = self.inner.lock().unwrap();
+        let running = &mut inner.running[priority.index()];
+        *running = running.saturating_sub(1);
+        'dispatch: loop {
+            for class in Priority::ALL {
+                if !self.has_room(&inner, class) {
+                    continue;
+                }
+                if let Some(waiter) = inner.queues[class.index()].pop_front() {
+                    if waiter.tx.send(()).is_ok() {
+                        inner.running[class.index()] += 1;
+                    }
+                    continue 'dispatch;
+                }
+            }
+            return;
+        }
+    }
+
+    fn record(&self, took: Duration) {
+        let sample = took.as_millis().max(1) as u64;
+        let _ = self.avg_ms.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |avg| {
+            Some(if avg == 0 { sample } else { (avg * 4 + sample) / 5 })
+        });
+    }
+
+    /// Expected wait at 1-based queue `position`, once any generation has
+    /// finished to base it on
+    pub fn estimated_wait(&self, position: usize) -> Option<Duration> {
+        let avg = self.avg_ms.load(Ordering::Relaxed);
+        if avg == 0 || self.max_running == 0 {
+            return None;
+        }
+        let rounds = position.div_ceil(self.max_running) as u64;
+        Some(Duration::from_millis(avg * rounds))
+    }
+
+    pub fn running(&self) -> usize {
+        self.inner.lock().unwrap().total_running()
+    }
+
+    pub fn queued(&self) -> usize {
+        self.inner.lock().unwrap().total_queued()
+    }
+}
+
+/// A running generation's slot, released on drop
+pub struct Permit {
+    admission: Arc<Admission>,
+    priority: Priority,
+    started: Instant,
+    queued: Option<Duration>,
+}
+
+impl Permit {
+    fn new(admission: Arc<Admission>, priority: Priority, queued: Option<Duration>) -> Self {
+        Self { admission, priority, started: Instant::now(), queued }
+    }
+
+    pub fn priority(&self) -> Priority {
+        self.priority
+    }
+
+    /// Time spent waiting before admission; `None` if admitted at once
+    pub fn queued_for(&self) -> Option<Duration> {
+        self.queued
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.admission.record(self.started.elapsed());
+        self.admission.release(self.priority);
+    }
+}
+
+/// A place in the queue; leaving (drop) before admission gives it up
+pub struct Queued {
+    admission: Arc<Admission>,
+    priority: Priority,
+    id: u64,
+    rx: oneshot::Receiver<()>,
+    since: Instant,
+    done: bool,
+}
+
+impl Queued {
+    /// 1-based place in line behind every higher-class waiter; 0 once a
+    /// slot has been handed over
+    pub fn position(&self) -> usize {
+        let inner = self.admission.inner.lock().unwrap();
+        let index = self.priority.index();
+        let ahead: usize = inner.queues[..index].iter().map(VecDeque::len).sum();
+        inner.queues[index]
+            .iter()
+            .position(|w| w.id == self.id)
+            .map_or(0, |i| ahead + i + 1)
+    }
+
+    pub fn estimated_wait(&self) -> Option<Duration> {
+        self.admission.estimated_wait(self.position())
+    }
+
+    /// Wait for a slot; cancel-safe, so it can be raced against a timer
+    pub async fn admitted(&mut self) -> Permit {
+        // The sender lives in the queue until it is used, so this only
+        // resolves on hand-over
+        let _ = (&mut self.rx).await;
+        self.done = true;
+        Permit::new(self.admission.clone(), self.priority, Some(self.since.elapsed()))
+    }
+}
+
+impl Drop for Queued {
+    fn drop(&mut self) {
+        if self.done {
+            return;
+        }
+        let mut inner = self.admission.inner.lock().unwrap();
+        let queue = &mut inner.queues[self.priority.index()];
+        if let Some(i) = queue.iter().position(|w| w.id == self.id) {
+            queue.remove(i);
+            return;
+        }
+        drop(inner);
+        // Handed a slot but never claimed it: pass it on
+        if self.rx.try_recv().is_ok() {
+            self.admission.release(self.priority);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn admitted(ticket: Option<Ticket>) -> Permit {
+        match ticket {
+            Some(Ticket::Admitted(permit)) => permit,
+            _ => panic!("expected admission"),
+        }
+    }
+
+    fn queued(ticket: Option<Ticket>) -> Queued {
+        match ticket {
+            Some(Ticket::Queued(queued)) => queued,
+            _ => panic!("expected to queue"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fifo_hand_over_and_positions() {
+        let admission = Arc::new(Admission::new(1, 2));
+        let first = admitted(admission.enqueue(Priority::Interactive));
+        let mut second = queued(admission.enqueue(Priority::Interactive));
+        let third = queued(admission.enqueue(Priority::Interactive));
+        assert!(admission.enqueue(Priority::Interactive).is_none(), "queue is full");
+        assert_eq!((second.position(), third.position()), (1, 2));
+        assert_eq!(second.estimated_wait(), None);
+
+        drop(first);
+        let permit = second.admitted().await;
+        assert!(permit.queued_for().is_some());
+        assert_eq!(third.position(), 1);
+        assert_eq!(admission.running(), 1);
+        // A generation has finished, so there is now an estimate
+        assert!(third.estimated_wait().is_some());
+        drop(permit);
+        drop(third);
+        assert_eq!((admission.running(), admission.queued()), (0, 0));
+    }
+
+    #[tokio::test]
+    async fn test_abandoned_waiters_do_not_leak_slots() {
+        let admission = Arc::new(Admission::new(1, 4));
+        let first = admitted(admission.enqueue(Priority::Interactive));
+        let gone = queued(admission.enqueue(Priority::Interactive));
+        let mut next = queued(admission.enqueue(Priority::Interactive));
+        // Leaves while waiting
+        drop(gone);
+        assert_eq!(next.position(), 1);
+        let handed = queued(admission.enqueue(Priority::Interactive));
+        drop(first);
+        let permit = next.admitted().await;
+        drop(permit);
+        // Handed the slot but dropped before claiming it
+        drop(handed);
+        assert_eq!((admission.running(), admission.queued()), (0, 0));
+        admitted(admission.enqueue(Priority::Interactive));
+    }
+
+    #[tokio::test]
+    async fn test_interactive_overtakes_batch_and_keeps_its_reservation() {
+        let admission = Arc::new(Admission::with_reserved(2, 8, 1));
+        let batch = admitted(admission.enqueue(Priority::Batch));
+        // The reserved slot is not available to batch work
+        let mut waiting_batch = queued(admission.enqueue(Priority::Batch));
+        let interactive = admitted(admission.enqueue(Priority::Interactive));
+        let mut waiting_interactive = queued(admission.enqueue(Priority::Interactive));
+        assert_eq!((waiting_interactive.position(), waiting_batch.position()), (1, 2));
+
+        drop(batch);
+        let permit = waiting_interactive.admitted().await;
+        assert_eq!(permit.priority(), Priority::Interactive);
+        assert_eq!(waiting_batch.position(), 1);
+        drop(interactive);
+        // One interactive generation still runs, which leaves only the
+        // reserved slot free
+        assert_eq!(waiting_batch.position(), 1);
+        drop(permit);
+        let batch = waiting_batch.admitted().await;
+        drop(batch);
+        assert_eq!((admission.running(), admission.queued()), (0, 0));
+    }
+
+    #[test]
+    fn test_unlimited_never_queues() {
+        let admission = Arc::new(Admission::new(0, 0));
+        let permits: Vec<_> = (0..10).map(|_| admitted(admission.enqueue(Priority::Interactive))).collect();
+        assert_eq!(admission.running(), 10);
+        drop(permits);
+        assert_eq!(admission.running(), 0);
+    }
+}
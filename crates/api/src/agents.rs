@@ -0,0 +1,334 @@
+//! Managed agent runs.
+//!
+//! A run alternates model steps with the tool calls they ask for, like a
+//! chat with `tools`, but is bounded by its own step count and wall-clock
+//! budget instead of `tools.max_rounds`. Each model step waits for a
+//! generation slot at the run's priority and gives it back while the tools
+//! run. Every step is stored in `agent_steps` as it finishes, so a run can
+//! be traced afterwards, and a run finishes even if its client disconnects.
+
+use crate::{
+    admission::Priority,
+    quota::{self, StreamSlot},
+    state::AppState,
+    summarize,
+    tools::Toolset,
+};
+use chrono::{DateTime, Utc};
+use ds_core::config::QuotaSection;
+use ds_model::{ChatMessage, ChatRequest, ToolCall, ToolSpec, FINISH_ERROR};
+use ds_types::{AgentRunId, UserId};
+use futures_util::{future::join_all, StreamExt};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::{PgPool, Row};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+/// Agent runs by finish reason
+pub const RUNS: &str = "deepersensor_agent_runs_total";
+
+/// The last step allowed still asked for tools
+pub const FINISH_MAX_STEPS: &str = "max_steps";
+/// The wall-clock budget ran out
+pub const FINISH_BUDGET: &str = "budget";
+
+/// One entry of a run's trace
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Step {
+    /// A model generation
+    Model {
+        step: u32,
+        content: String,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        tool_calls: Vec<ToolCall>,
+        finish_reason: String,
+        tokens: u64,
+        duration_ms: u64,
+    },
+    /// A tool call asked for by the model step with the same number
+    Tool {
+        step: u32,
+        name: String,
+        arguments: Value,
+        /// `ok`, `error`, `timeout`, or `unknown`
+        outcome: String,
+        /// As handed back to the model
+        result: String,
+        duration_ms: u64,
+    },
+}
+
+impl Step {
+    fn kind(&self) -> &'static str {
+        match self {
+            Self::Model { .. } => "model",
+            Self::Tool { .. } => "tool",
+        }
+    }
+}
+
+/// How a run ended
+#[derive(Debug, Clone, Serialize)]
+pub struct Finished {
+    /// The model's own (`stop`, `length`), or `max_steps`, `budget`, `error`
+    pub finish_reason: String,
+    /// Content of the last model step
+    pub output: String,
+    /// Model steps completed
+    pub steps: u32,
+    pub output_tokens: u64,
+    pub elapsed_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Progress of a run, in order
+pub enum Progress {
+    Step(Step),
+    Done(Finished),
+}
+
+/// A run checked and ready to start
+pub struct Spec {
+    pub id: AgentRunId,
+    pub user_id: UserId,
+    pub model: String,
+    /// The prompt after the guard
+    pub messages: Vec<ChatMessage>,
+    pub tools: Toolset,
+    pub priority: Priority,
+    pub max_steps: u32,
+    pub budget: Duration,
+    /// The caller's quota, for threshold warnings
+    pub quota: QuotaSection,
+}
+
+/// A stored run and its trace
+#[derive(Debug, Serialize)]
+pub struct Run {
+    pub id: AgentRunId,
+    pub user_id: UserId,
+    pub model: String,
+    /// `{ model, messages, tools, max_steps, budget_secs, priority }` as
+    /// received
+    pub request: Value,
+    /// `running` or `completed`
+    pub status: String,
+    pub finish_reason: Option<String>,
+    pub output: Option<String>,
+    pub steps: u32,
+    pub output_tokens: u64,
+    pub error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+    pub trace: Vec<Step>,
+}
+
+pub async fn create(db: &PgPool, spec: &Spec, request: &Value) -> sqlx::Result<()> {
+    sqlx::query("INSERT INTO agent_runs (id, user_id, model, request) VALUES ($1, $2, $3, $4::jsonb)")
+        .bind(spec.id)
+        .bind(spec.user_id)
+        .bind(&spec.model)
+        .bind(request.to_string())
+        .execute(db)
+        .await?;
+    Ok(())
+}
+
+pub async fn get(db: &PgPool, id: AgentRunId) -> sqlx::Result<Option<Run>> {
+    let Some(row) = sqlx::query(
+        "SELECT user_id, model, request::text AS request, status, finish_reason, output, steps, output_tokens, \
+         error, created_at, finished_at FROM agent_runs WHERE id = $1",
+    )
+    .bind(id)
+    .fetch_optional(db)
+    .await?
+    else {
+        return Ok(None);
+    };
+    let details: Vec<String> = sqlx::query_scalar("SELECT detail::text FROM agent_steps WHERE run_id = $1 ORDER BY seq")
+        .bind(id)
+        .fetch_all(db)
+        .await?;
+    let decode = |e: serde_json::Error| sqlx::Error::Decode(Box::new(e));
+    let request: String = row.try_get("request")?;
+    Ok(Some(Run {
+        id,
+        user_id: row.try_get("user_id")?,
+        model: row.try_get("model")?,
+        request: serde_json::from_str(&request).map_err(decode)?,
+        status: row.try_get("status")?,
+        finish_reason: row.try_get("finish_reason")?,
+        output: row.try_get("output")?,
+        steps: row.try_get::<i32, _>("steps")? as u32,
+        output_tokens: row.try_get::<i64, _>("output_tokens")? as u64,
+        error: row.try_get("error")?,
+        created_at: row.try_get("created_at")?,
+        finished_at: row.try_get("finished_at")?,
+        trace: details.iter().map(|d| serde_json::from_str(d)).collect::<Result<_, _>>().map_err(decode)?,
+    }))
+}
+
+async fn record_step(db: &PgPool, id: AgentRunId, seq: i32, step: &Step) -> sqlx::Result<()> {
+    sqlx::query("INSERT INTO agent_steps (run_id, seq, kind, detail) VALUES ($1, $2, $3, $4::jsonb)")
+        .bind(id)
+        .bind(seq)
+        .bind(step.kind())
+        .bind(serde_json::json!(step).to_string())
+        .execute(db)
+        .await?;
+    Ok(())
+}
+
+async fn record_finish(db: &PgPool, id: AgentRunId, finished: &Finished) -> sqlx::Result<()> {
+    sqlx::query(
+        "UPDATE agent_runs SET status = 'completed', finish_reason = $2, output = $3, steps = $4, \
+         output_tokens = $5, error = $6, finished_at = NOW() WHERE id = $1",
+    )
+    .bind(id)
+    .bind(&finished.finish_reason)
+    .bind(&finished.output)
+    .bind(finished.steps as i32)
+    .bind(finished.output_tokens as i64)
+    .bind(&finished.error)
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+/// Store `step` and pass it on to the client, if it is still listening
+struct Tracer<'a> {
+    db: &'a PgPool,
+    id: AgentRunId,
+    seq: i32,
+    tx: mpsc::UnboundedSender<Progress>,
+}
+
+impl Tracer<'_> {
+    async fn record(&mut self, step: Step) {
+        if let Err(e) = record_step(self.db, self.id, self.seq, &step).await {
+            tracing::warn!(error = %e, run_id = %self.id, "recording agent step failed");
+        }
+        self.seq += 1;
+        let _ = self.tx.send(Progress::Step(step));
+    }
+}
+
+/// Run `spec` to its end, holding the caller's stream `slot` throughout
+pub async fn run(state: AppState, spec: Spec, slot: StreamSlot, tx: mpsc::UnboundedSender<Progress>) {
+    let _slot = slot;
+    let started = Instant::now();
+    let deadline = tokio::time::Instant::from_std(started + spec.budget);
+    let mut tracer = Tracer { db: &state.db, id: spec.id, seq: 0, tx: tx.clone() };
+    let specs = spec.tools.specs();
+    let mut messages = spec.messages.clone();
+    let (mut steps, mut tokens, mut output, mut error) = (0, 0, String::new(), None);
+    let finish_reason = loop {
+        let step_started = Instant::now();
+        let generated = match tokio::time::timeout_at(deadline, generate(&state, &spec, &messages, &specs)).await {
+            Ok(Ok(generated)) => generated,
+            Ok(Err(e)) => {
+                error = Some(e);
+                break FINISH_ERROR.to_string();
+            }
+            Err(_) => break FINISH_BUDGET.to_string(),
+        };
+        steps += 1;
+        tokens += generated.tokens;
+        output.clone_from(&generated.content);
+        let calls = generated.tool_calls.clone();
+        tracer
+            .record(Step::Model {
+                step: steps,
+                content: generated.content.clone(),
+                tool_calls: generated.tool_calls,
+                finish_reason: generated.finish_reason.clone(),
+                tokens: generated.tokens,
+                duration_ms: step_started.elapsed().as_millis() as u64,
+            })
+            .await;
+        if calls.is_empty() {
+            break generated.finish_reason;
+        }
+        if steps == spec.max_steps {
+            break FINISH_MAX_STEPS.to_string();
+        }
+        let pending = join_all(calls.iter().map(|call| async {
+            let call_started = Instant::now();
+            (spec.tools.call(&state, spec.user_id, call).await, call_started.elapsed())
+        }));
+        let Ok(results) = tokio::time::timeout_at(deadline, pending).await else {
+            break FINISH_BUDGET.to_string();
+        };
+        messages.push(ChatMessage { role: "assistant".into(), content: generated.content, tool_calls: calls.clone() });
+        for (call, (called, took)) in calls.into_iter().zip(results) {
+            messages.push(ChatMessage { role: "tool".into(), content: called.content.clone(), ..Default::default() });
+            tracer
+                .record(Step::Tool {
+                    step: steps,
+                    name: call.name,
+                    arguments: call.arguments,
+                    outcome: called.outcome.into(),
+                    result: called.content,
+                    duration_ms: took.as_millis() as u64,
+                })
+                .await;
+        }
+    };
+    let finished = Finished {
+        finish_reason,
+        output,
+        steps,
+        output_tokens: tokens,
+        elapsed_ms: started.elapsed().as_millis() as u64,
+        error,
+    };
+    state.metrics.incr(RUNS, &[("finish_reason", &finished.finish_reason)]);
+    if tokens > 0 {
+        quota::record(&state, &spec.quota, spec.user_id, tokens).await;
+    }
+    if let Err(e) = record_finish(&state.db, spec.id, &finished).await {
+        tracing::warn!(error = %e, run_id = %spec.id, "finishing agent run failed");
+    }
+    tracing::info!(
+        run_id = %spec.id,
+        user_id = %spec.user_id,
+        steps,
+        finish_reason = %finished.finish_reason,
+        ms = finished.elapsed_ms,
+        "agent run finished"
+    );
+    let _ = tx.send(Progress::Done(finished));
+}
+
+/// One model step's output
+#[derive(Default)]
+struct Generated {
+    content: String,
+    tool_calls: Vec<ToolCall>,
+    finish_reason: String,
+    /// Content chunks (roughly tokens)
+    tokens: u64,
+}
+
+/// One generation, redacted like a chat response
+async fn generate(state: &AppState, spec: &Spec, messages: &[ChatMessage], tools: &[ToolSpec]) -> Result<Generated, String> {
+    let _permit = summarize::admit(state, spec.priority).await.map_err(|e| e.to_string())?;
+    let (model, messages, tools) = (spec.model.clone(), messages.to_vec(), tools.to_vec());
+    let request = ChatRequest { model, messages, tools, ..Default::default() };
+    let stream = state.provider.chat_stream(request).await.map_err(|e| e.to_string())?;
+    let mut stream = state.redactor.filter(ds_model::with_terminal_frame(stream, spec.model.as_str()));
+    let mut generated = Generated::default();
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(|e| e.to_string())?;
+        generated.tokens += u64::from(!chunk.content.is_empty());
+        generated.content.push_str(&chunk.content);
+        generated.tool_calls.extend(chunk.tool_calls);
+        if let Some(reason) = chunk.finish_reason {
+            generated.finish_reason = reason;
+        }
+    }
+    Ok(generated)
+}
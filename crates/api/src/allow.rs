@@ -0,0 +1,62 @@
+//! Accurate `OPTIONS` answers for every route.
+//!
+//! The CORS layer answers every `OPTIONS` request itself, with the methods
+//! in `CORS_ALLOW_METHODS` whatever the path, and axum adds the route's
+//! `Allow` header outside every layer. [`wrap`] sits around the whole
+//! router so it sees both: `Allow` gains `OPTIONS`, and
+//! `Access-Control-Allow-Methods` is narrowed to what the route serves.
+//! `/v2` paths served by `/v1` handlers report the `/v1` route's methods.
+//! Unknown paths keep the CORS answer.
+
+use axum::{
+    body::Body,
+    extract::Request,
+    http::{header, HeaderValue, Method},
+    response::Response,
+    Router,
+};
+use std::convert::Infallible;
+use tower::ServiceExt;
+
+/// `router` with its `OPTIONS` answers completed
+pub fn wrap(router: Router) -> Router {
+    Router::new().fallback_service(tower::service_fn(move |req: Request| {
+        let router = router.clone();
+        async move { Ok::<_, Infallible>(respond(router, req).await) }
+    }))
+}
+
+async fn call(router: Router, req: Request) -> Response {
+    let Ok::<_, Infallible>(response) = router.oneshot(req).await;
+    response
+}
+
+async fn respond(router: Router, req: Request) -> Response {
+    if req.method() != Method::OPTIONS {
+        return call(router, req).await;
+    }
+    let fallback = req.uri().path().strip_prefix("/v2/").map(|rest| format!("/v1/{rest}"));
+    let mut response = call(router.clone(), req).await;
+    let mut allow = response.headers().get(header::ALLOW).cloned();
+    if let (None, Some(path)) = (&allow, fallback) {
+        let probe = Request::options(path).body(Body::empty()).expect("valid probe request");
+        allow = call(router, probe).await.headers().get(header::ALLOW).cloned();
+    }
+    let Some(allow) = allow.as_ref().and_then(|v| v.to_str().ok()) else {
+        return response;
+    };
+    let mut methods: Vec<&str> = allow.split(',').map(str::trim).filter(|m| !m.is_empty()).collect();
+    if !methods.contains(&"OPTIONS") {
+        methods.push("OPTIONS");
+    }
+    let headers = response.headers_mut();
+    let cors = headers.get(header::ACCESS_CONTROL_ALLOW_METHODS).and_then(|v| v.to_str().ok()).map(|configured| {
+        configured.split(',').map(str::trim).filter(|m| methods.contains(m)).collect::<Vec<_>>().join(",")
+    });
+    if let Some(cors) = cors {
+        headers.insert(header::ACCESS_CONTROL_ALLOW_METHODS, HeaderValue::from_str(&cors).expect("method names"));
+    }
+    let allow = HeaderValue::from_str(&methods.join(",")).expect("method names");
+    headers.insert(header::ALLOW, allow);
+    response
+}
@@ -0,0 +1,128 @@
+//! Long-term history of selected counters, for figures Prometheus no
+//! longer holds.
+//!
+//! The `analytics` job writes what each counter in `ANALYTICS_METRICS`
+//! gained on this instance since its last snapshot into the current UTC
+//! hour of `metric_snapshots`, so instances add up. Hours older than
+//! `ANALYTICS_HOURLY_DAYS` are compacted into days, and days older than
+//! `ANALYTICS_RETENTION_DAYS` dropped. Counts gained after an instance's
+//! last snapshot are lost when it stops.
+
+use crate::{metrics::label_value, state::AppState};
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
+use ds_core::config::AnalyticsSection;
+use serde::Serialize;
+use sqlx::PgPool;
+use std::collections::BTreeMap;
+
+/// What each series had reached at this instance's last snapshot
+#[derive(Default)]
+pub struct Exported(DashMap<(&'static str, String), u64>);
+
+/// Write what the tracked counters gained since the last snapshot,
+/// returning how many series changed
+pub async fn snapshot(state: &AppState, exported: &Exported) -> sqlx::Result<usize> {
+    let cfg = &state.config().analytics;
+    let gained: Vec<_> = state
+        .metrics
+        .series(|name| cfg.tracks(name))
+        .into_iter()
+        .filter_map(|(name, labels, value)| {
+            let last = exported.0.get(&(name, labels.clone())).map_or(0, |v| *v);
+            (value > last).then(|| (name, labels, value, value - last))
+        })
+        .collect();
+    if gained.is_empty() {
+        return Ok(0);
+    }
+    let names: Vec<&str> = gained.iter().map(|g| g.0).collect();
+    let labels: Vec<&str> = gained.iter().map(|g| g.1.as_str()).collect();
+    let deltas: Vec<i64> = gained.iter().map(|g| g.3 as i64).collect();
+    sqlx::query(
+        "INSERT INTO metric_snapshots (granularity, bucket, name, labels, value) \
+         SELECT 'hour', date_trunc('hour', NOW() AT TIME ZONE 'UTC') AT TIME ZONE 'UTC', * \
+         FROM UNNEST($1::text[], $2::text[], $3::bigint[]) \
+         ON CONFLICT (granularity, bucket, name, labels) \
+         DO UPDATE SET value = metric_snapshots.value + EXCLUDED.value",
+    )
+    .bind(&names)
+    .bind(&labels)
+    .bind(&deltas)
+    .execute(&state.db)
+    .await?;
+    // Only once written, so a failed snapshot is retried in full
+    for (name, labels, value, _) in &gained {
+        exported.0.insert((name, labels.clone()), *value);
+    }
+    Ok(gained.len())
+}
+
+/// Fold hours past the hourly window into their UTC days and drop days
+/// past retention
+pub async fn compact(db: &PgPool, cfg: &AnalyticsSection) -> sqlx::Result<()> {
+    let mut tx = db.begin().await?;
+    sqlx::query(
+        "WITH moved AS ( \
+             DELETE FROM metric_snapshots \
+             WHERE granularity = 'hour' AND bucket < NOW() - make_interval(days => $1) \
+             RETURNING bucket, name, labels, value) \
+         INSERT INTO metric_snapshots (granularity, bucket, name, labels, value) \
+         SELECT 'day', date_trunc('day', bucket AT TIME ZONE 'UTC') AT TIME ZONE 'UTC', name, labels, SUM(value) \
+         FROM moved GROUP BY 2, 3, 4 \
+         ON CONFLICT (granularity, bucket, name, labels) \
+         DO UPDATE SET value = metric_snapshots.value + EXCLUDED.value",
+    )
+    .bind(cfg.hourly_days as i32)
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query("DELETE FROM metric_snapshots WHERE bucket < NOW() - make_interval(days => $1)")
+        .bind(cfg.retention_days as i32)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await
+}
+
+#[derive(Debug, Serialize)]
+pub struct Point {
+    pub bucket: DateTime<Utc>,
+    /// By the value of the label asked for, or under `total`
+    pub values: BTreeMap<String, u64>,
+}
+
+/// `metric` per `granularity` (`hour` or `day`, in UTC) from `from` until
+/// `to`, summed by the label `by`. Compacted days have no hours, so they
+/// appear at midnight whatever the granularity.
+pub async fn history(
+    db: &PgPool,
+    metric: &str,
+    by: Option<&str>,
+    granularity: &str,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> sqlx::Result<Vec<Point>> {
+    let rows: Vec<(DateTime<Utc>, String, i64)> = sqlx::query_as(
+        "SELECT date_trunc($1, bucket AT TIME ZONE 'UTC') AT TIME ZONE 'UTC' AS at, labels, SUM(value)::BIGINT \
+         FROM metric_snapshots WHERE name = $2 AND bucket >= $3 AND bucket < $4 \
+         GROUP BY 1, 2 ORDER BY 1",
+    )
+    .bind(granularity)
+    .bind(metric)
+    .bind(from)
+    .bind(to)
+    .fetch_all(db)
+    .await?;
+    let mut points: Vec<Point> = Vec::new();
+    for (bucket, labels, value) in rows {
+        let key = match by {
+            Some(label) => label_value(&labels, label).unwrap_or_default(),
+            None => "total".into(),
+        };
+        if points.last().is_none_or(|p| p.bucket != bucket) {
+            points.push(Point { bucket, values: BTreeMap::new() });
+        }
+        let point = points.last_mut().expect("pushed above");
+        *point.values.entry(key).or_default() += value as u64;
+    }
+    Ok(points)
+}
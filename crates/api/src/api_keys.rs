@@ -0,0 +1,239 @@
+//! Organization-owned API keys.
+//!
+//! A key belongs to an org and is attributed to the member who created it:
+//! requests made with it act as that member (quotas, stream slots) while
+//! usage is also recorded against the key and org. The secret is shown once
+//! at creation; only its SHA-256 is stored, which is enough because keys
+//! are long random strings rather than passwords.
+//!
+//! Keys created with `signed` also get a signing secret, and requests made
+//! with them must be signed with it (see [`crate::signing`]).
+
+use crate::{
+    auth_middleware::AuthUser,
+    events,
+    signing::{SigningSecret, SECRET_PREFIX},
+};
+use chrono::NaiveDate;
+use ds_types::{ApiKeyId, OrgId, UserId};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use sqlx::{PgPool, Row};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+/// Every secret starts with this, so keys and JWTs can share the
+/// `Authorization: Bearer` header
+pub const KEY_PREFIX: &str = "dsk_";
+
+/// Per-key model policy carried on [`AuthUser`]
+#[derive(Clone, Debug)]
+pub struct KeyAuth {
+    pub id: ApiKeyId,
+    /// Used when a request names no model
+    pub default_model: Option<String>,
+    /// Models the key may call; empty allows any
+    pub allowed_models: Vec<String>,
+    /// Set for keys whose requests must be signed
+    pub signing_secret: Option<SigningSecret>,
+}
+
+impl KeyAuth {
+    pub fn allows_model(&self, model: &str) -> bool {
+        self.allowed_models.is_empty() || self.allowed_models.iter().any(|m| m == model)
+    }
+}
+
+/// Settings chosen when a key is created
+pub struct NewKey<'a> {
+    pub org_id: OrgId,
+    pub created_by: UserId,
+    pub name: &'a str,
+    pub scopes: &'a [String],
+    pub default_model: Option<&'a str>,
+    pub allowed_models: &'a [String],
+    /// Require signed requests
+    pub signed: bool,
+}
+
+/// A stored key with the secrets to hand out once
+pub struct IssuedKey {
+    pub id: ApiKeyId,
+    pub key: String,
+    pub signing_secret: Option<String>,
+}
+
+/// A key as listed to org admins (never the secret)
+#[derive(Debug, Serialize)]
+pub struct KeyInfo {
+    pub id: ApiKeyId,
+    pub org_id: OrgId,
+    pub created_by: UserId,
+    pub name: String,
+    /// First characters of the secret, to tell keys apart
+    pub prefix: String,
+    pub scopes: Vec<String>,
+    pub default_model: Option<String>,
+    pub allowed_models: Vec<String>,
+    /// Whether requests with the key must be signed
+    pub signed: bool,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub last_used_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub revoked_at: Option<chrono::DateTime<chrono::Utc>>,
+    /// Tokens generated with this key today (UTC)
+    pub tokens_today: u64,
+}
+
+fn hash(secret: &str) -> String {
+    hex::encode(Sha256::digest(secret.as_bytes()))
+}
+
+fn random_secret(prefix: &str) -> String {
+    format!("{prefix}{}{}", uuid::Uuid::new_v4().simple(), uuid::Uuid::new_v4().simple())
+}
+
+/// Store a new key and return it with the secrets to hand out once
+pub async fn create(db: &PgPool, key: NewKey<'_>) -> sqlx::Result<IssuedKey> {
+    let id = ApiKeyId::generate();
+    let secret = random_secret(KEY_PREFIX);
+    let signing_secret = key.signed.then(|| random_secret(SECRET_PREFIX));
+    let mut tx = db.begin().await?;
+    sqlx::query(
+        "INSERT INTO api_keys \
+         (id, org_id, created_by, name, prefix, secret_hash, scopes, default_model, allowed_models, signing_secret) \
+         VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)",
+    )
+    .bind(id)
+    .bind(key.org_id)
+    .bind(key.created_by)
+    .bind(key.name)
+    .bind(&secret[..KEY_PREFIX.len() + 8])
+    .bind(hash(&secret))
+    .bind(key.scopes)
+    .bind(key.default_model)
+    .bind(key.allowed_models)
+    .bind(&signing_secret)
+    .execute(&mut *tx)
+    .await?;
+    let created = events::ApiKeyCreated { key_id: id, org_id: key.org_id, created_by: key.created_by };
+    events::enqueue(&mut tx, &created).await?;
+    tx.commit().await?;
+    Ok(IssuedKey { id, key: secret, signing_secret })
+}
+
+/// Resolve a presented secret to the member it acts as, stamping
+/// `last_used_at`; `None` for unknown or revoked keys
+pub async fn authenticate(db: &PgPool, secret: &str) -> sqlx::Result<Option<AuthUser>> {
+    let row = sqlx::query(
+        "UPDATE api_keys SET last_used_at = NOW() \
+         WHERE secret_hash = $1 AND revoked_at IS NULL \
+         RETURNING id, org_id, created_by, scopes, default_model, allowed_models, signing_secret",
+    )
+    .bind(hash(secret))
+    .fetch_optional(db)
+    .await?;
+    let Some(row) = row else { return Ok(None) };
+    Ok(Some(AuthUser {
+        user_id: row.try_get("created_by")?,
+        email: None,
+        roles: Vec::new(),
+        scopes: row.try_get("scopes")?,
+        org_id: Some(row.try_get("org_id")?),
+        session_id: None,
+        api_key: Some(KeyAuth {
+            id: row.try_get("id")?,
+            default_model: row.try_get("default_model")?,
+            allowed_models: row.try_get("allowed_models")?,
+            signing_secret: row.try_get::<Option<String>, _>("signing_secret")?.map(SigningSecret),
+        }),
+        impersonated_by: None,
+    }))
+}
+
+pub async fn list(db: &PgPool, org_id: OrgId) -> sqlx::Result<Vec<KeyInfo>> {
+    let rows = sqlx::query(
+        "SELECT k.id, k.org_id, k.created_by, k.name, k.prefix, k.scopes, k.default_model, \
+         k.allowed_models, k.signing_secret IS NOT NULL AS signed, k.created_at, k.last_used_at, k.revoked_at, \
+         COALESCE(u.tokens, 0) AS tokens_today \
+         FROM api_keys k LEFT JOIN api_key_usage_daily u \
+         ON u.key_id = k.id AND u.day = (NOW() AT TIME ZONE 'UTC')::date \
+         WHERE k.org_id = $1 ORDER BY k.created_at",
+    )
+    .bind(org_id)
+    .fetch_all(db)
+    .await?;
+    rows.iter()
+        .map(|row| {
+            Ok(KeyInfo {
+                id: row.try_get("id")?,
+                org_id: row.try_get("org_id")?,
+                created_by: row.try_get("created_by")?,
+                name: row.try_get("name")?,
+                prefix: row.try_get("prefix")?,
+                scopes: row.try_get("scopes")?,
+                default_model: row.try_get("default_model")?,
+                allowed_models: row.try_get("allowed_models")?,
+                signed: row.try_get("signed")?,
+                created_at: row.try_get("created_at")?,
+                last_used_at: row.try_get("last_used_at")?,
+                revoked_at: row.try_get("revoked_at")?,
+                tokens_today: row.try_get::<i64, _>("tokens_today")? as u64,
+            })
+        })
+        .collect()
+}
+
+/// Revoke a key of `org_id`; returns false if unknown or already revoked
+pub async fn revoke(db: &PgPool, org_id: OrgId, id: ApiKeyId) -> sqlx::Result<bool> {
+    let mut tx = db.begin().await?;
+    let revoked = sqlx::query(
+        "UPDATE api_keys SET revoked_at = NOW() \
+         WHERE id = $1 AND org_id = $2 AND revoked_at IS NULL",
+    )
+    .bind(id)
+    .bind(org_id)
+    .execute(&mut *tx)
+    .await?
+    .rows_affected();
+    if revoked == 1 {
+        events::enqueue(&mut tx, &events::ApiKeyRevoked { key_id: id, org_id }).await?;
+    }
+    tx.commit().await?;
+    Ok(revoked == 1)
+}
+
+/// Tokens generated with a key, queued for [`record_usage`]
+pub struct KeyUsage {
+    pub key_id: ApiKeyId,
+    pub org_id: OrgId,
+    pub user_id: UserId,
+    pub day: NaiveDate,
+    pub tokens: u64,
+}
+
+/// Add generated tokens to their keys' daily totals in one statement
+pub async fn record_usage(db: &PgPool, rows: &[KeyUsage]) -> sqlx::Result<()> {
+    let mut summed: HashMap<(ApiKeyId, NaiveDate), (OrgId, UserId, i64)> = HashMap::new();
+    for row in rows {
+        let entry = summed.entry((row.key_id, row.day)).or_insert((row.org_id, row.user_id, 0));
+        entry.2 += row.tokens as i64;
+    }
+    let keys: Vec<Uuid> = summed.keys().map(|(key, _)| Uuid::from(*key)).collect();
+    let days: Vec<NaiveDate> = summed.keys().map(|(_, day)| *day).collect();
+    let orgs: Vec<Uuid> = summed.values().map(|(org, _, _)| Uuid::from(*org)).collect();
+    let users: Vec<Uuid> = summed.values().map(|(_, user, _)| Uuid::from(*user)).collect();
+    let tokens: Vec<i64> = summed.values().map(|(_, _, tokens)| *tokens).collect();
+    sqlx::query(
+        "INSERT INTO api_key_usage_daily (key_id, day, org_id, user_id, tokens) \
+         SELECT * FROM UNNEST($1::uuid[], $2::date[], $3::uuid[], $4::uuid[], $5::bigint[]) \
+         ON CONFLICT (key_id, day) DO UPDATE SET tokens = api_key_usage_daily.tokens + EXCLUDED.tokens",
+    )
+    .bind(&keys)
+    .bind(&days)
+    .bind(&orgs)
+    .bind(&users)
+    .bind(&tokens)
+    .execute(db)
+    .await?;
+    Ok(())
+}
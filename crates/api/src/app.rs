@@ -2,29 +2,44 @@ use std::{net::SocketAddr, sync::Arc, time::Duration};
 use axum::Router;
 use tower::{limit::ConcurrencyLimitLayer, ServiceBuilder};
 use axum::http;
-use tower_http::request_id::{RequestId, MakeRequestId};
-use tower_http::{trace::TraceLayer, request_id::{PropagateRequestIdLayer, SetRequestIdLayer}, limit::RequestBodyLimitLayer, set_header::SetResponseHeaderLayer};
-use axum::http::HeaderValue;
+use tower_http::{trace::TraceLayer, request_id::{PropagateRequestIdLayer, SetRequestIdLayer}};
+use tower_http::decompression::RequestDecompressionLayer;
 use ds_core::config::AppConfig;
-use ds_model::{ModelProvider, OllamaProvider};
+use ds_model::{AnthropicProvider, ModelProvider, OllamaProvider, OpenAiProvider, ProviderRegistry};
 use http::header::HeaderName;
-use crate::{state::AppState, cors::build_cors, routes, observability::REQUEST_ID_HEADER};
-// security headers layer available (currently not applied)
-use uuid::Uuid;
-
-#[derive(Clone)]
-struct MakeRequestUuid;
-impl MakeRequestId for MakeRequestUuid {
-    fn make_request_id<B>(&mut self, _request: &http::Request<B>) -> Option<RequestId> {
-        let id = Uuid::new_v4().to_string();
-        Some(RequestId::new(http::HeaderValue::from_str(&id).expect("valid uuid header value")))
-    }
-}
+use crate::{state::AppState, cors::build_cors, routes, request_id::{MakeRequestUuid, REQUEST_ID_HEADER}, security::with_security_headers};
 
 pub async fn build_app(cfg: Arc<AppConfig>) -> AppStateAndRouter {
-    let provider = Arc::new(OllamaProvider::new(cfg.ollama.base_url.clone(), Duration::from_millis(cfg.ollama.default_timeout_ms))) as Arc<dyn ModelProvider>;
-    let db = sqlx::PgPool::connect_lazy(cfg.database_url()).expect("valid db url");
-    let state = AppState::new(provider, cfg.clone(), db);
+    let http = crate::egress::build_client(&cfg.egress, None).expect("valid egress config");
+    let ollama_client = crate::egress::ollama_client(&cfg).expect("valid ollama client certificate");
+    let mut bases = cfg.ollama.base_urls().into_iter();
+    let ollama = Arc::new(OllamaProvider::with_client(bases.next().expect("OLLAMA_BASE_URL is set"), ollama_client, Duration::from_millis(cfg.ollama.default_timeout_ms))
+        .with_fallbacks(bases)
+        .with_auth(crate::egress::ollama_auth(&cfg).expect("valid ollama credentials"))
+        .with_captured_headers(cfg.ollama.capture_headers.split(',').filter(|h| !h.trim().is_empty())));
+    crate::egress::watch_ollama_identity(cfg.clone(), ollama.clone());
+    crate::egress::watch_ollama_endpoints(cfg.clone(), ollama.clone());
+    // Ollama serves unprefixed model names; other backends register here
+    let mut registry = ProviderRegistry::new().with("ollama", ollama);
+    if !cfg.openai.base_url.is_empty() {
+        let timeout = Duration::from_millis(cfg.openai.timeout_ms);
+        let openai = OpenAiProvider::new(&cfg.openai.base_url, &cfg.openai.api_key, http.clone(), timeout);
+        registry = registry.with("openai", Arc::new(openai));
+    }
+    if !cfg.anthropic.base_url.is_empty() {
+        let section = &cfg.anthropic;
+        let timeout = Duration::from_millis(section.timeout_ms);
+        let anthropic =
+            AnthropicProvider::new(&section.base_url, &section.api_key, http.clone(), timeout, section.max_tokens);
+        registry = registry.with("anthropic", Arc::new(anthropic));
+    }
+    let provider = Arc::new(registry) as Arc<dyn ModelProvider>;
+    // Vector searches filter by owner, so let HNSW scans continue past
+    // ef_search until enough rows match, still in distance order
+    let options = cfg.database_url().parse::<sqlx::postgres::PgConnectOptions>().expect("valid db url");
+    let db = sqlx::PgPool::connect_lazy_with(options.options([("hnsw.iterative_scan", "strict_order")]));
+    let state = AppState::new(provider, cfg.clone(), db, http);
+    state.usage.start(&state);
     let cors = build_cors(&cfg);
     let request_id_header: HeaderName = REQUEST_ID_HEADER.parse().expect("valid x-request-id header name");
 
@@ -32,56 +47,61 @@ pub async fn build_app(cfg: Arc<AppConfig>) -> AppStateAndRouter {
         .make_span_with(|req: &http::Request<_>| {
             let method = req.method().clone();
             let uri = req.uri().path().to_string();
-            tracing::info_span!("request", %method, %uri, status = tracing::field::Empty)
+            let empty = tracing::field::Empty;
+            tracing::info_span!("request", %method, %uri, status = empty, request_id = empty, trace_id = empty)
         })
         .on_response(|res: &http::Response<_>, latency: std::time::Duration, span: &tracing::Span| {
             let status = res.status().as_u16();
-            span.record("status", &tracing::field::display(status));
+            span.record("status", tracing::field::display(status));
             tracing::info!(parent: span, status, latency_ms = latency.as_millis(), "request.completed");
         });
-    let body_limit = RequestBodyLimitLayer::new(cfg.http.max_request_size_bytes as usize);
+    // Bytes on the wire are limited before decompression; a gzip body's
+    // output after it, so a small bomb cannot expand without bound. Disabled,
+    // encoded bodies reach handlers as they came. Upload parts are exempt
+    // from both limits; their handler has its own.
+    let body_limit = cfg.http.max_request_size_bytes as usize;
+    let gzip = cfg.http.request_decompression;
+    let decompression = RequestDecompressionLayer::new().gzip(gzip).pass_through_unaccepted(!gzip);
+    let decompressed_limit = cfg.http.max_decompressed_bytes.max(cfg.http.max_request_size_bytes) as usize;
 
     let middleware = ServiceBuilder::new()
         .layer(SetRequestIdLayer::new(request_id_header.clone(), MakeRequestUuid))
         .layer(PropagateRequestIdLayer::new(request_id_header.clone()))
         .layer(trace)
-        .layer(body_limit)
+        .layer(axum::middleware::from_fn_with_state(body_limit, crate::body_limit::limit))
+        .layer(decompression)
         .layer(ConcurrencyLimitLayer::new(1024));
 
-    // Security headers (applied globally)
-    let strict = SetResponseHeaderLayer::if_not_present(
-        http::header::STRICT_TRANSPORT_SECURITY,
-        HeaderValue::from_static("max-age=63072000; includeSubDomains; preload"));
-    let cto = SetResponseHeaderLayer::if_not_present(
-        http::header::X_CONTENT_TYPE_OPTIONS,
-        HeaderValue::from_static("nosniff"));
-    let frame = SetResponseHeaderLayer::if_not_present(
-        http::header::X_FRAME_OPTIONS,
-        HeaderValue::from_static("DENY"));
-    let csp = SetResponseHeaderLayer::if_not_present(
-        http::HeaderName::from_static("content-security-policy"),
-        HeaderValue::from_static("default-src 'none'; frame-ancestors 'none'; base-uri 'none'; form-action 'self'; connect-src 'self'; script-src 'self'; style-src 'self'; img-src 'self' data:; font-src 'self'; object-src 'none'"));
-    let referrer = SetResponseHeaderLayer::if_not_present(
-        http::HeaderName::from_static("referrer-policy"),
-        HeaderValue::from_static("no-referrer"));
-    let perms = SetResponseHeaderLayer::if_not_present(
-        http::HeaderName::from_static("permissions-policy"),
-        HeaderValue::from_static("geolocation=(), microphone=(), camera=(), fullscreen=(self)"));
-
-    let router = Router::new()
-        .merge(routes::routes())
-        .layer(strict)
-        .layer(cto)
-        .layer(frame)
-        .layer(csp)
-        .layer(referrer)
-        .layer(perms)
+    let router = with_security_headers(Router::new().merge(routes::routes()))
+        .layer(axum::middleware::from_fn(crate::localize::localize_errors))
+        .layer(axum::middleware::from_fn(crate::context::attach))
+        .layer(axum::middleware::from_fn_with_state(state.metrics.clone(), crate::metrics::record_response))
+        .layer(axum::middleware::from_fn_with_state(versions(&state), crate::versioning::track))
+        .layer(axum::middleware::from_fn_with_state(slow_watch(&state), crate::slow::watch))
+        // Inflated bodies, once `middleware` has decompressed them
+        .layer(axum::middleware::from_fn_with_state(decompressed_limit, crate::body_limit::limit))
         .layer(middleware)
-        .layer(cors);
+        .layer(cors)
+        // require_auth reads the state from request extensions
+        .layer(axum::Extension(state.clone()));
     AppStateAndRouter { state, router }
 }
 
+fn slow_watch(state: &AppState) -> Arc<crate::slow::Watch> {
+    let thresholds = crate::slow::Thresholds::from_config(&state.cfg);
+    Arc::new(crate::slow::Watch { thresholds, metrics: state.metrics.clone() })
+}
+
+fn versions(state: &AppState) -> Arc<crate::versioning::Versions> {
+    Arc::new(crate::versioning::Versions::from_config(&state.cfg, state.metrics.clone()))
+}
+
 #[derive(Clone)]
 pub struct AppStateAndRouter { pub state: AppState, pub router: Router<AppState> }
 
+impl AppStateAndRouter {
+    /// The router as served, with per-route `OPTIONS` answers
+    pub fn into_router(self) -> Router { crate::allow::wrap(self.router.with_state(self.state)) }
+}
+
 pub fn server_addr(cfg: &AppConfig) -> SocketAddr { format!("{}:{}", cfg.app.host, cfg.app.port).parse().expect("invalid bind address") }
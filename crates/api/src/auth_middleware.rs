@@ -1,26 +1,78 @@
+use crate::context::RequestContext;
+use crate::db_metrics::Timed;
 use axum::{
     extract::Request,
+    http::HeaderValue,
     middleware::Next,
     response::Response,
 };
-use ds_auth::verify_jwt;
 use ds_core::error::ApiError;
+use ds_types::{OrgId, SessionId, UserId};
 
 /// Extracted user claims from JWT
 #[derive(Clone, Debug)]
 pub struct AuthUser {
-    pub user_id: String,
+    pub user_id: UserId,
     pub email: Option<String>,
+    pub roles: Vec<String>,
+    pub scopes: Vec<String>,
+    pub org_id: Option<OrgId>,
+    pub session_id: Option<SessionId>,
+    /// Set when authenticated with an org API key rather than a JWT
+    pub api_key: Option<crate::api_keys::KeyAuth>,
+    /// The admin acting as this user, for an impersonation token
+    pub impersonated_by: Option<UserId>,
 }
 
-/// JWT authentication middleware extractor
+/// Response header naming the admin behind an impersonated request
+pub const IMPERSONATED_BY_HEADER: &str = "x-impersonated-by";
+
+/// Role granted by `users.role = 'admin'`
+pub const ROLE_ADMIN: &str = "admin";
+
+impl AuthUser {
+    pub fn has_role(&self, role: &str) -> bool {
+        self.roles.iter().any(|r| r == role)
+    }
+
+    /// Unscoped session tokens pass every scope check
+    pub fn has_scope(&self, scope: &str) -> bool {
+        ds_auth::scope::allows(&self.scopes, scope)
+    }
+}
+
+/// Reject callers without the admin role, and impersonation tokens even
+/// of admins; layer inside `require_auth`
+pub async fn require_admin(req: Request, next: Next) -> Result<Response, ApiError> {
+    let user = req.extensions().get::<AuthUser>().ok_or(ApiError::Unauthorized)?;
+    if !user.has_role(ROLE_ADMIN) || user.impersonated_by.is_some() {
+        tracing::warn!(user_id = %user.user_id, path = %req.uri().path(), "admin route denied");
+        return Err(ApiError::Forbidden);
+    }
+    Ok(next.run(req).await)
+}
+
+/// Reject scoped credentials that lack `scope`; layer inside `require_auth`:
+///
+/// `.route_layer(middleware::from_fn(|req, next| require_scope(scope::CHAT_WRITE, req, next)))`
+pub async fn require_scope(scope: &'static str, req: Request, next: Next) -> Result<Response, ApiError> {
+    let user = req.extensions().get::<AuthUser>().ok_or(ApiError::Unauthorized)?;
+    if !user.has_scope(scope) {
+        tracing::warn!(user_id = %user.user_id, scope, path = %req.uri().path(), "missing scope");
+        return Err(ApiError::Forbidden);
+    }
+    Ok(next.run(req).await)
+}
+
+/// JWT / API key authentication middleware extractor
 /// 
-/// This middleware extracts and verifies the JWT token from the Authorization header.
+/// This middleware extracts and verifies the JWT token (or `dsk_` org API
+/// key) from the Authorization header, then checks the caller against the
+/// route policy, and records the caller in the request context.
 /// The AppState is accessed via request extensions since middleware runs after state is attached.
-pub async fn require_auth(
-    mut req: Request,
-    next: Next,
-) -> Result<Response, ApiError> {
+pub async fn require_auth(ctx: RequestContext, mut req: Request, next: Next) -> Result<Response, ApiError> {
+    let auth_timer = crate::slow::PhaseTimer::start(crate::slow::Phase::Auth);
+
     // Extract Authorization header
     let auth_header = req
         .headers()
@@ -40,28 +92,106 @@ pub async fn require_auth(
     let state = req
         .extensions()
         .get::<crate::state::AppState>()
+        .cloned()
         .ok_or_else(|| {
             tracing::error!("app state not found in request extensions");
             ApiError::Internal
         })?;
 
-    let cfg = state.config();
-
-    // Verify JWT
-    let claims =
-        verify_jwt(token, &cfg.security.jwt_secret, &cfg.security.jwt_issuer).map_err(|e| {
+    let user = if token.starts_with(crate::api_keys::KEY_PREFIX) {
+        crate::api_keys::authenticate(&state.db, token)
+            .timed(&state.metrics, "api_keys.authenticate")
+            .await
+            .map_err(|e| {
+                tracing::error!(error = %e, "api key lookup failed");
+                ApiError::Internal
+            })?
+            .ok_or_else(|| {
+                tracing::warn!("unknown or revoked api key");
+                ApiError::Unauthorized
+            })?
+    } else {
+        // Verify JWT
+        let claims = state.tokens.verify_access(token).map_err(|e| {
             tracing::warn!(error = %e, "jwt verification failed");
             ApiError::Unauthorized
         })?;
 
-    // Extract user info from claims
-    let user = AuthUser {
-        user_id: claims.sub,
-        email: claims.email,
+        // Extract user info from claims
+        AuthUser {
+            user_id: claims.sub,
+            email: claims.email,
+            roles: claims.roles,
+            scopes: claims.scopes,
+            org_id: claims.org_id,
+            session_id: claims.sid,
+            api_key: None,
+            impersonated_by: claims.act.map(|act| act.sub),
+        }
     };
 
-    // Insert user into request extensions for handlers to access
-    req.extensions_mut().insert(user);
+    crate::slow::note_user(&user);
 
-    Ok(next.run(req).await)
+    // Keys created as signed prove each request with their signing secret
+    if let Some(key) = &user.api_key {
+        if let Some(secret) = &key.signing_secret {
+            let (req_method, req_path) = (req.method().clone(), req.uri().path().to_string());
+            req = crate::signing::verify(&state, key, secret, req).await.map_err(|reason| {
+                tracing::warn!(
+                    reason = reason.as_str(),
+                    key_id = %key.id,
+                    method = %req_method,
+                    path = %req_path,
+                    ip = %ctx.ip,
+                    "security.signature.rejected"
+                );
+                state.metrics.incr(crate::signing::REJECTIONS, &[("reason", reason.as_str())]);
+                ApiError::Unauthorized
+            })?;
+        }
+    }
+
+    let subject = crate::policy::Subject {
+        user: &user,
+        method: req.method().as_str(),
+        path: req.uri().path(),
+        ip: ctx.ip,
+        at: chrono::Utc::now(),
+    };
+    if let Err(denial) = state.policy.check(&subject) {
+        tracing::warn!(
+            rule = denial.rule,
+            condition = denial.condition,
+            user_id = %user.user_id,
+            method = %req.method(),
+            path = %req.uri().path(),
+            ip = %ctx.ip,
+            "security.policy.denied"
+        );
+        state.metrics.incr(crate::policy::DENIALS, &[("rule", denial.rule)]);
+        return Err(ApiError::Forbidden);
+    }
+
+    // Handlers read the caller from the context, or on its own
+    req.extensions_mut().insert(RequestContext { user: Some(user.clone()), ..ctx });
+    let Some(actor) = user.impersonated_by else {
+        req.extensions_mut().insert(user);
+        drop(auth_timer);
+        return Ok(next.run(req).await);
+    };
+    // Every request made as someone else is on the record
+    tracing::info!(
+        by = %actor,
+        user_id = %user.user_id,
+        method = %req.method(),
+        path = %req.uri().path(),
+        "audit.impersonation.request"
+    );
+    req.extensions_mut().insert(user);
+    drop(auth_timer);
+    let mut response = next.run(req).await;
+    if let Ok(value) = HeaderValue::from_str(&actor.to_string()) {
+        response.headers_mut().insert(IMPERSONATED_BY_HEADER, value);
+    }
+    Ok(response)
 }
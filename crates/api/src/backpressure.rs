@@ -0,0 +1,193 @@
+use crate::metrics::Metrics;
+use ds_core::config::StreamSection;
+use ds_model::ChatStream;
+use std::sync::Arc;
+use tokio::sync::mpsc::{self, error::TrySendError};
+
+const STREAMS_OPEN: &str = "deepersensor_streams_open";
+const BUFFERED: &str = "deepersensor_stream_buffered_chunks";
+
+/// Keeps the open/buffered gauges honest however the stream ends
+struct Gauges {
+    metrics: Arc<Metrics>,
+}
+
+impl Drop for Gauges {
+    fn drop(&mut self) {
+        self.metrics.gauge_add(STREAMS_OPEN, -1);
+    }
+}
+
+struct Receiver {
+    rx: mpsc::Receiver<ds_model::ModelResult<ds_model::ChatChunk>>,
+    metrics: Arc<Metrics>,
+}
+
+impl Drop for Receiver {
+    fn drop(&mut self) {
+        // Chunks still queued when the client went away
+        self.metrics.gauge_add(BUFFERED, -(self.rx.len() as i64));
+    }
+}
+
+/// What to do when a client reads slower than the model generates
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackpressurePolicy {
+    /// Stop reading upstream until the client catches up
+    Pause,
+    /// Discard content chunks and report the count on the terminal frame
+    Drop,
+}
+
+impl BackpressurePolicy {
+    pub fn from_config(value: &str) -> Self {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "drop" => BackpressurePolicy::Drop,
+            _ => BackpressurePolicy::Pause,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            BackpressurePolicy::Pause => "pause",
+            BackpressurePolicy::Drop => "drop",
+        }
+    }
+}
+
+/// Decouple the provider stream from the response body with a bounded
+/// channel so a slow client cannot make chunks pile up unbounded.
+///
+/// The upstream is driven by a spawned task; when the client disconnects the
+/// receiver is dropped, the task stops, and the upstream request is cancelled.
+pub fn bounded(stream: ChatStream, cfg: &StreamSection, metrics: Arc<Metrics>) -> ChatStream {
+    let policy = BackpressurePolicy::from_config(&cfg.backpressure_policy);
+    let (tx, rx) = mpsc::channel(cfg.channel_capacity.max(1) as usize);
+    let mut receiver = Receiver { rx, metrics: metrics.clone() };
+    metrics.gauge_add(STREAMS_OPEN, 1);
+    let gauges = Gauges { metrics: metrics.clone() };
+
+    tokio::spawn(async move {
+        use futures_util::StreamExt;
+        let _gauges = gauges;
+        let mut stream = stream;
+        let mut dropped = 0u64;
+        while let Some(mut item) = stream.next().await {
+            let terminal = match &mut item {
+                Ok(chunk) if chunk.done => {
+                    if dropped > 0 {
+                        chunk.dropped_chunks = Some(dropped);
+                    }
+                    true
+                }
+                Ok(_) => false,
+                // Errors are always delivered
+                Err(_) => true,
+            };
+            // Counted before sending so the receiver never sees it negative
+            metrics.gauge_add(BUFFERED, 1);
+            let item = match tx.try_send(item) {
+                Ok(()) => continue,
+                Err(TrySendError::Closed(_)) => {
+                    metrics.gauge_add(BUFFERED, -1);
+                    return;
+                }
+                Err(TrySendError::Full(item)) => item,
+            };
+            metrics.incr(
+                "deepersensor_stream_backpressure_total",
+                &[("policy", policy.as_str())],
+            );
+            if policy == BackpressurePolicy::Drop && !terminal {
+                metrics.gauge_add(BUFFERED, -1);
+                dropped += 1;
+                metrics.incr("deepersensor_stream_dropped_chunks_total", &[]);
+                continue;
+            }
+            if tx.send(item).await.is_err() {
+                metrics.gauge_add(BUFFERED, -1);
+                return;
+            }
+        }
+    });
+
+    Box::pin(async_stream::stream! {
+        while let Some(item) = receiver.rx.recv().await {
+            receiver.metrics.gauge_add(BUFFERED, -1);
+            yield item;
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ds_model::{ChatChunk, ModelResult};
+    use futures_util::StreamExt;
+
+    fn source(n: usize) -> ChatStream {
+        let chunks: Vec<ModelResult<ChatChunk>> = (0..n)
+            .map(|i| {
+                Ok(ChatChunk {
+                    model: "m".into(),
+                    content: i.to_string(),
+                    done: i == n - 1,
+                    ..Default::default()
+                })
+            })
+            .collect();
+        Box::pin(futures_util::stream::iter(chunks))
+    }
+
+    fn cfg(capacity: u64, policy: &str) -> StreamSection {
+        StreamSection {
+            channel_capacity: capacity,
+            backpressure_policy: policy.into(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pause_policy_delivers_everything() {
+        let metrics = Arc::new(Metrics::default());
+        let mut out = bounded(source(50), &cfg(2, "pause"), metrics);
+        let mut seen = Vec::new();
+        while let Some(item) = out.next().await {
+            tokio::task::yield_now().await;
+            seen.push(item.unwrap());
+        }
+        assert_eq!(seen.len(), 50);
+        assert!(seen.last().unwrap().done);
+        assert_eq!(seen.last().unwrap().dropped_chunks, None);
+    }
+
+    #[tokio::test]
+    async fn test_drop_policy_flags_terminal_frame() {
+        let metrics = Arc::new(Metrics::default());
+        let mut out = bounded(source(50), &cfg(2, "drop"), metrics);
+        // Let the producer run ahead of a stalled client
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        let mut seen = Vec::new();
+        while let Some(item) = out.next().await {
+            seen.push(item.unwrap());
+        }
+        let last = seen.last().unwrap();
+        assert!(last.done);
+        let dropped = last.dropped_chunks.unwrap();
+        assert!(dropped > 0);
+        assert_eq!(seen.len() as u64 + dropped, 50);
+    }
+
+    #[tokio::test]
+    async fn test_gauges_settle_after_client_disconnect() {
+        let metrics = Arc::new(Metrics::default());
+        let mut out = bounded(source(50), &cfg(4, "pause"), metrics.clone());
+        out.next().await.unwrap().unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        assert_eq!(metrics.gauge(STREAMS_OPEN), 1);
+        assert!(metrics.gauge(BUFFERED) > 0);
+        drop(out);
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        assert_eq!(metrics.gauge(STREAMS_OPEN), 0);
+        assert_eq!(metrics.gauge(BUFFERED), 0);
+    }
+}
@@ -0,0 +1,184 @@
+//! In-process write batching.
+//!
+//! A [`Batcher`] queues rows in a bounded channel and hands them to its
+//! writer in batches: once `max_items` are waiting, or `interval` after the
+//! first of them arrived, whichever comes first. A full queue makes callers
+//! wait instead of dropping rows. [`Batcher::drain`] at shutdown writes
+//! what is queued; rows pushed after that are written one at a time. A
+//! batch that fails to write is logged and counted, not retried.
+
+use crate::metrics::Metrics;
+use futures_util::future::BoxFuture;
+use std::{
+    sync::{Arc, Mutex, OnceLock},
+    time::Duration,
+};
+use tokio::{sync::mpsc, task::JoinHandle};
+use tokio_util::sync::CancellationToken;
+
+/// Rows written by batch and result (`ok`, `error`)
+pub const ROWS: &str = "deepersensor_batch_rows_total";
+/// Writes (one statement each) by batch and result
+pub const FLUSHES: &str = "deepersensor_batch_flushes_total";
+
+type Writer<T> = Arc<dyn Fn(Vec<T>) -> BoxFuture<'static, anyhow::Result<()>> + Send + Sync>;
+
+pub struct Batcher<T> {
+    name: &'static str,
+    max_items: usize,
+    interval: Duration,
+    tx: mpsc::Sender<T>,
+    /// Taken by `start`; rows pushed before then wait in the channel
+    rx: Mutex<Option<mpsc::Receiver<T>>>,
+    writer: OnceLock<Writer<T>>,
+    closed: CancellationToken,
+    task: Mutex<Option<JoinHandle<()>>>,
+    metrics: Arc<Metrics>,
+}
+
+impl<T: Send + 'static> Batcher<T> {
+    pub fn new(name: &'static str, max_items: usize, interval: Duration, capacity: usize, metrics: Arc<Metrics>) -> Self {
+        let (tx, rx) = mpsc::channel(capacity.max(1));
+        Self {
+            name,
+            max_items: max_items.max(1),
+            interval,
+            tx,
+            rx: Mutex::new(Some(rx)),
+            writer: OnceLock::new(),
+            closed: CancellationToken::new(),
+            task: Mutex::new(None),
+            metrics,
+        }
+    }
+
+    /// Write batches with `writer` from now on; later calls do nothing
+    pub fn start<F, Fut>(&self, writer: F)
+    where
+        F: Fn(Vec<T>) -> Fut + Send + Sync + 'static,
+        Fut: std::future::Future<Output = anyhow::Result<()>> + Send + 'static,
+    {
+        let Some(rx) = self.rx.lock().expect("batch receiver").take() else { return };
+        let writer: Writer<T> = Arc::new(move |rows| Box::pin(writer(rows)));
+        let _ = self.writer.set(writer.clone());
+        let flush = Flush { name: self.name, writer, metrics: self.metrics.clone() };
+        let task = tokio::spawn(run(flush, rx, self.max_items, self.interval, self.closed.clone()));
+        *self.task.lock().expect("batch task") = Some(task);
+    }
+
+    /// Queue a row, waiting while the queue is full
+    pub async fn push(&self, row: T) {
+        let Err(mpsc::error::SendError(row)) = self.tx.send(row).await else { return };
+        // Drained already
+        if let Some(writer) = self.writer.get() {
+            let flush = Flush { name: self.name, writer: writer.clone(), metrics: self.metrics.clone() };
+            flush.write(vec![row]).await;
+        }
+    }
+
+    /// Write everything queued and stop batching
+    pub async fn drain(&self) {
+        self.closed.cancel();
+        let task = self.task.lock().expect("batch task").take();
+        if let Some(task) = task {
+            let _ = task.await;
+        }
+    }
+}
+
+struct Flush<T> {
+    name: &'static str,
+    writer: Writer<T>,
+    metrics: Arc<Metrics>,
+}
+
+impl<T> Flush<T> {
+    async fn write(&self, rows: Vec<T>) {
+        let count = rows.len();
+        let result = match (self.writer)(rows).await {
+            Ok(()) => "ok",
+            Err(e) => {
+                tracing::error!(error = %e, batch = self.name, rows = count, "batched write failed; rows lost");
+                "error"
+            }
+        };
+        self.metrics.add(ROWS, &[("batch", self.name), ("result", result)], count as u64);
+        self.metrics.incr(FLUSHES, &[("batch", self.name), ("result", result)]);
+    }
+}
+
+async fn run<T>(
+    flush: Flush<T>,
+    mut rx: mpsc::Receiver<T>,
+    max_items: usize,
+    interval: Duration,
+    closed: CancellationToken,
+) {
+    let mut batch = Vec::with_capacity(max_items);
+    loop {
+        let first = tokio::select! {
+            row = rx.recv() => row,
+            _ = closed.cancelled() => None,
+        };
+        let Some(first) = first else { break };
+        batch.push(first);
+        let deadline = tokio::time::sleep(interval);
+        tokio::pin!(deadline);
+        while batch.len() < max_items {
+            tokio::select! {
+                row = rx.recv() => match row {
+                    Some(row) => batch.push(row),
+                    None => break,
+                },
+                _ = &mut deadline => break,
+                _ = closed.cancelled() => break,
+            }
+        }
+        flush.write(std::mem::replace(&mut batch, Vec::with_capacity(max_items))).await;
+    }
+    rx.close();
+    while let Some(row) = rx.recv().await {
+        batch.push(row);
+        if batch.len() == max_items {
+            flush.write(std::mem::take(&mut batch)).await;
+        }
+    }
+    if !batch.is_empty() {
+        flush.write(batch).await;
+    }
+    tracing::info!(batch = flush.name, "batched writes drained");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_batches_by_size_and_interval_then_drains() {
+        let metrics = Arc::new(Metrics::default());
+        let batcher = Batcher::new("test", 3, Duration::from_millis(50), 100, metrics.clone());
+        let written = Arc::new(Mutex::new(Vec::<Vec<u32>>::new()));
+        // Queued before the writer starts
+        batcher.push(1).await;
+        let sink = written.clone();
+        batcher.start(move |rows| {
+            let sink = sink.clone();
+            async move {
+                sink.lock().unwrap().push(rows);
+                Ok(())
+            }
+        });
+        for row in 2..=4 {
+            batcher.push(row).await;
+        }
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert_eq!(*written.lock().unwrap(), [vec![1, 2, 3], vec![4]]);
+
+        batcher.push(5).await;
+        batcher.drain().await;
+        batcher.push(6).await;
+        assert_eq!(written.lock().unwrap()[2..], [vec![5], vec![6]]);
+        assert_eq!(metrics.sum_by(ROWS, "result").get("ok"), Some(&6));
+        assert_eq!(metrics.total(FLUSHES), 4);
+    }
+}
@@ -0,0 +1,115 @@
+//! Content-addressed file contents.
+//!
+//! With a blob store configured, contents are kept once per distinct
+//! SHA-256 under `sha256/<first two hex digits>/<hash>`, however many files
+//! share them. The `blobs` table counts the files referencing each blob:
+//! storing a file whose contents are already stored only takes a reference,
+//! and deleting one gives it back. The `blob_gc` job deletes blobs nobody
+//! has referenced for `files.gc_grace_secs`.
+//!
+//! Taking a reference locks the blob's row until the file is committed, so
+//! concurrent uploads of the same bytes write them once, and collection
+//! (which holds the same lock while deleting) never removes a blob a file
+//! is about to point at.
+
+use crate::{metrics::Metrics, state::AppState};
+use ds_store::{BlobStore, ByteStream};
+use sha2::{Digest, Sha256};
+use sqlx::PgConnection;
+
+/// Blob references taken by result (`stored`, `deduplicated`)
+pub const WRITES: &str = "deepersensor_blob_writes_total";
+/// Unreferenced blobs deleted by the `blob_gc` job
+pub const COLLECTED: &str = "deepersensor_blobs_collected_total";
+
+/// A referenced blob
+pub struct Blob {
+    pub hash: String,
+    pub key: String,
+}
+
+pub fn key(hash: &str) -> String {
+    format!("sha256/{}/{hash}", &hash[..2])
+}
+
+/// Reference the blob holding `data`, writing it to `store` unless it is
+/// already there; the reference is only kept if `conn`'s transaction
+/// commits
+pub async fn acquire(
+    conn: &mut PgConnection,
+    store: &dyn BlobStore,
+    metrics: &Metrics,
+    data: &[u8],
+) -> anyhow::Result<Blob> {
+    let hash = hex::encode(Sha256::digest(data));
+    acquire_stream(conn, store, metrics, hash, data.len() as u64, ds_store::once(data.to_vec())).await
+}
+
+/// [`acquire`] for contents already hashed, read from `data` only if they
+/// have to be written
+pub async fn acquire_stream(
+    conn: &mut PgConnection,
+    store: &dyn BlobStore,
+    metrics: &Metrics,
+    hash: String,
+    bytes: u64,
+    data: ByteStream,
+) -> anyhow::Result<Blob> {
+    let stored: bool = sqlx::query_scalar(
+        "INSERT INTO blobs (hash, bytes, refs) VALUES ($1, $2, 1) \
+         ON CONFLICT (hash) DO UPDATE SET refs = blobs.refs + 1, unreferenced_at = NULL \
+         RETURNING stored",
+    )
+    .bind(&hash)
+    .bind(bytes as i64)
+    .fetch_one(&mut *conn)
+    .await?;
+    let key = key(&hash);
+    if stored {
+        metrics.incr(WRITES, &[("result", "deduplicated")]);
+    } else {
+        let written = store.put(&key, data).await?;
+        anyhow::ensure!(written == bytes, "blob {hash}: wrote {written} of {bytes} bytes");
+        sqlx::query("UPDATE blobs SET stored = true WHERE hash = $1").bind(&hash).execute(&mut *conn).await?;
+        metrics.incr(WRITES, &[("result", "stored")]);
+    }
+    Ok(Blob { hash, key })
+}
+
+/// Give back a file's reference to `hash`
+pub async fn release(conn: &mut PgConnection, hash: &str) -> sqlx::Result<()> {
+    sqlx::query(
+        "UPDATE blobs SET refs = refs - 1, \
+         unreferenced_at = CASE WHEN refs = 1 THEN NOW() ELSE unreferenced_at END \
+         WHERE hash = $1",
+    )
+    .bind(hash)
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+/// Delete every blob unreferenced for longer than `files.gc_grace_secs`,
+/// returning how many were deleted
+pub async fn collect(state: &AppState, store: &dyn BlobStore) -> anyhow::Result<u64> {
+    let grace = state.config().files.gc_grace_secs as f64;
+    let mut collected = 0;
+    loop {
+        let mut tx = state.db.begin().await?;
+        let hash: Option<String> = sqlx::query_scalar(
+            "SELECT hash FROM blobs WHERE refs = 0 AND unreferenced_at < NOW() - make_interval(secs => $1) \
+             LIMIT 1 FOR UPDATE SKIP LOCKED",
+        )
+        .bind(grace)
+        .fetch_optional(&mut *tx)
+        .await?;
+        let Some(hash) = hash else { break };
+        // Deleted while the row is locked, so nobody references it meanwhile
+        store.delete(&key(&hash)).await?;
+        sqlx::query("DELETE FROM blobs WHERE hash = $1").bind(&hash).execute(&mut *tx).await?;
+        tx.commit().await?;
+        state.metrics.incr(COLLECTED, &[]);
+        collected += 1;
+    }
+    Ok(collected)
+}
@@ -0,0 +1,40 @@
+//! Request body limits. Bodies are held to `MAX_REQUEST_SIZE_BYTES` on the
+//! wire and `MAX_DECOMPRESSED_REQUEST_BYTES` once inflated, except parts of
+//! resumable uploads (`PATCH /v1/uploads/{id}`), which their handler holds
+//! to `FILES_UPLOAD_PART_BYTES` instead.
+//!
+//! A declared `Content-Length` over the limit is refused before the body is
+//! read; a body without one is cut off once it crosses the limit, which
+//! extractors answer with 413.
+
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::{header, Method},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use ds_core::error::ApiError;
+use http_body_util::Limited;
+
+/// Limit the body to `limit` bytes, unless it is an upload part
+pub async fn limit(State(limit): State<usize>, req: Request, next: Next) -> Response {
+    if is_upload_part(&req) {
+        return next.run(req).await;
+    }
+    let declared = req.headers().get(header::CONTENT_LENGTH).and_then(|v| v.to_str().ok()?.parse::<u64>().ok());
+    if declared.is_some_and(|len| len > limit as u64) {
+        return ApiError::PayloadTooLarge(format!("body exceeds {limit} bytes")).into_response();
+    }
+    next.run(req.map(|body| Body::new(Limited::new(body, limit)))).await
+}
+
+/// `PATCH /{version}/uploads/{id}`
+fn is_upload_part(req: &Request) -> bool {
+    let mut segments = req.uri().path().trim_start_matches('/').split('/');
+    req.method() == Method::PATCH
+        && segments.next().is_some()
+        && segments.next() == Some("uploads")
+        && segments.next().is_some_and(|id| !id.is_empty())
+        && segments.next().is_none()
+}
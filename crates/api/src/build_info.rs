@@ -0,0 +1,37 @@
+//! Release metadata captured at compile time by `build.rs`
+
+use serde::Serialize;
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+/// Short commit SHA, or `unknown` when built outside a checkout without `GIT_SHA`
+pub const GIT_SHA: &str = env!("DS_GIT_SHA");
+/// RFC 3339, UTC
+pub const BUILD_TIMESTAMP: &str = env!("DS_BUILD_TIMESTAMP");
+/// Comma-separated Cargo features of the `api` crate
+const FEATURES: &str = env!("DS_FEATURES");
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BuildInfo {
+    pub version: &'static str,
+    pub git_sha: &'static str,
+    pub build_timestamp: &'static str,
+    pub features: Vec<&'static str>,
+}
+
+pub fn build_info() -> BuildInfo {
+    BuildInfo {
+        version: VERSION,
+        git_sha: GIT_SHA,
+        build_timestamp: BUILD_TIMESTAMP,
+        features: FEATURES.split(',').filter(|f| !f.is_empty()).collect(),
+    }
+}
+
+/// The `deepersensor_info` gauge, labelled for release tracking
+pub fn render_info_metric(out: &mut String) {
+    out.push_str("# HELP deepersensor_info API build information\n");
+    out.push_str("# TYPE deepersensor_info gauge\n");
+    out.push_str(&format!(
+        "deepersensor_info{{version=\"{VERSION}\",git_sha=\"{GIT_SHA}\",build_timestamp=\"{BUILD_TIMESTAMP}\",features=\"{FEATURES}\"}} 1\n"
+    ));
+}
@@ -0,0 +1,222 @@
+//! How document text is cut into chunks before embedding.
+//!
+//! Every strategy keeps chunks within `documents.chunk_chars` characters:
+//! - `fixed`: windows of exactly that many characters, each repeating the
+//!   last `documents.chunk_overlap` of the one before, so a passage cut at
+//!   a boundary is still found whole in one of them
+//! - `sentence`: as many whole sentences as fit, falling back to words for
+//!   a sentence longer than a chunk
+//! - `semantic`: sentences in order, starting a new chunk where the next
+//!   sentence's embedding drifts below `documents.semantic_threshold`
+//!   (cosine) from the last, i.e. where the topic changes. This embeds
+//!   every sentence, so it costs one embedding call per sentence on top of
+//!   one per chunk.
+
+use crate::{semantic_cache::cosine, state::AppState, summarize};
+use ds_model::ModelError;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Strategy {
+    Fixed,
+    #[default]
+    Sentence,
+    Semantic,
+}
+
+impl Strategy {
+    pub const NAMES: &'static [&'static str] = &["fixed", "sentence", "semantic"];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Strategy::Fixed => "fixed",
+            Strategy::Sentence => "sentence",
+            Strategy::Semantic => "semantic",
+        }
+    }
+}
+
+impl FromStr for Strategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fixed" => Ok(Strategy::Fixed),
+            "sentence" => Ok(Strategy::Sentence),
+            "semantic" => Ok(Strategy::Semantic),
+            other => Err(format!("unknown chunking strategy {other:?}; expected one of {}", Strategy::NAMES.join(", "))),
+        }
+    }
+}
+
+/// Cut `text` with `strategy` and the configured sizes
+pub async fn chunk(state: &AppState, strategy: Strategy, text: &str) -> Result<Vec<String>, ModelError> {
+    let cfg = &state.config().documents;
+    let max = cfg.chunk_chars as usize;
+    Ok(match strategy {
+        Strategy::Fixed => fixed(text, max, cfg.chunk_overlap as usize),
+        Strategy::Sentence => pack(sentences(text), max),
+        Strategy::Semantic => semantic(state, &cfg.embedding_model, text, max, cfg.semantic_threshold).await?,
+    })
+}
+
+/// Windows of `max` characters, each starting `overlap` characters before
+/// the last one ended
+pub fn fixed(text: &str, max: usize, overlap: usize) -> Vec<String> {
+    let chars: Vec<char> = text.chars().collect();
+    let max = max.max(1);
+    let step = max.saturating_sub(overlap).max(1);
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    while start < chars.len() {
+        let end = (start + max).min(chars.len());
+        let chunk: String = chars[start..end].iter().collect();
+        if !chunk.trim().is_empty() {
+            chunks.push(chunk);
+        }
+        if end == chars.len() {
+            break;
+        }
+        start += step;
+    }
+    chunks
+}
+
+/// Sentences with their trailing punctuation, ending at `.`, `!` or `?`
+/// before whitespace, or at a line break
+pub fn sentences(text: &str) -> Vec<&str> {
+    let mut out = Vec::new();
+    let mut start = 0;
+    let mut chars = text.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        let ends = match c {
+            '\n' => true,
+            '.' | '!' | '?' => chars.peek().is_none_or(|(_, next)| next.is_whitespace()),
+            _ => false,
+        };
+        if ends {
+            let stop = i + c.len_utf8();
+            if !text[start..stop].trim().is_empty() {
+                out.push(&text[start..stop]);
+            }
+            start = stop;
+        }
+    }
+    if !text[start..].trim().is_empty() {
+        out.push(&text[start..]);
+    }
+    out
+}
+
+/// Consecutive sentences joined into chunks of at most `max` characters
+fn pack(sentences: Vec<&str>, max: usize) -> Vec<String> {
+    let mut chunks = Vec::new();
+    let (mut current, mut len) = (String::new(), 0);
+    for sentence in sentences {
+        for piece in summarize::split(sentence, max) {
+            let piece_len = piece.chars().count();
+            if len > 0 && len + piece_len > max {
+                chunks.push(std::mem::take(&mut current));
+                len = 0;
+            }
+            current.push_str(&piece);
+            len += piece_len;
+        }
+    }
+    if !current.trim().is_empty() {
+        chunks.push(current);
+    }
+    chunks.into_iter().map(|c| c.trim().to_string()).collect()
+}
+
+/// Sentences grouped until the topic shifts or the chunk is full
+async fn semantic(
+    state: &AppState,
+    model: &str,
+    text: &str,
+    max: usize,
+    threshold: f64,
+) -> Result<Vec<String>, ModelError> {
+    let mut groups: Vec<Vec<&str>> = Vec::new();
+    let (mut len, mut last): (usize, Option<Vec<f32>>) = (0, None);
+    for sentence in sentences(text) {
+        let embedding = state.provider.embed(model, sentence.trim()).await?;
+        let sentence_len = sentence.chars().count();
+        let shifted = last.as_ref().is_some_and(|last| cosine(last, &embedding) < threshold);
+        match groups.last_mut() {
+            Some(group) if !shifted && len + sentence_len <= max => {
+                group.push(sentence);
+                len += sentence_len;
+            }
+            _ => {
+                groups.push(vec![sentence]);
+                len = sentence_len;
+            }
+        }
+        last = Some(embedding);
+    }
+    // A group only exceeds `max` when one sentence does
+    Ok(groups.into_iter().flat_map(|group| pack(group, max)).collect())
+}
+
+/// Character offsets (end exclusive) of each of `chunks` in the `text`
+/// they were cut from, in order; `None` for one not found there verbatim
+pub fn offsets(text: &str, chunks: &[String]) -> Vec<Option<(usize, usize)>> {
+    // Chunks start in order but may overlap, so each search starts just
+    // after the last chunk's start; (byte, char) positions of that start
+    let (mut from, mut chars) = (0, 0);
+    chunks
+        .iter()
+        .map(|chunk| {
+            let at = from + text[from..].find(chunk.as_str())?;
+            let start = chars + text[from..at].chars().count();
+            let step = text[at..].chars().next().map_or(0, char::len_utf8);
+            (from, chars) = (at + step, start + usize::from(step > 0));
+            Some((start, start + chunk.chars().count()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fixed_windows_overlap() {
+        assert_eq!(fixed("abcdefghij", 4, 1), ["abcd", "defg", "ghij"]);
+        assert_eq!(fixed("abcdefghij", 4, 0), ["abcd", "efgh", "ij"]);
+        // An overlap as large as the window still advances
+        assert_eq!(fixed("abc", 2, 5), ["ab", "bc"]);
+        assert!(fixed("", 4, 1).is_empty());
+    }
+
+    #[test]
+    fn test_sentences_pack_without_splitting() {
+        let text = "Dr. Who? No. It is 3.5 km away!\nNext line";
+        assert_eq!(sentences(text), ["Dr.", " Who?", " No.", " It is 3.5 km away!", "Next line"]);
+        assert_eq!(pack(vec!["One two. ", "Three four. ", "Five."], 20), ["One two.", "Three four. Five."]);
+        assert_eq!(pack(vec!["A sentence far too long to fit."], 12).len(), 3);
+    }
+
+    #[test]
+    fn test_offsets_locate_overlapping_chunks() {
+        let text = "héllo wörld";
+        let chunks = fixed(text, 5, 2);
+        let found: Vec<_> = offsets(text, &chunks).into_iter().map(Option::unwrap).collect();
+        assert_eq!(found, [(0, 5), (3, 8), (6, 11)]);
+        for ((start, end), chunk) in found.iter().zip(&chunks) {
+            assert_eq!(&text.chars().skip(*start).take(end - start).collect::<String>(), chunk);
+        }
+        let text = "One two. Three four. Five.";
+        assert_eq!(offsets(text, &pack(sentences(text), 20)), [Some((0, 20)), Some((21, 26))]);
+        assert_eq!(offsets("abc", &["x".to_string()]), [None]);
+    }
+
+    #[test]
+    fn test_strategy_names() {
+        for name in Strategy::NAMES {
+            assert_eq!(name.parse::<Strategy>().unwrap().as_str(), *name);
+        }
+        assert!("paragraph".parse::<Strategy>().is_err());
+    }
+}
@@ -0,0 +1,215 @@
+//! Collections: named groups of documents, searched together and shared
+//! with an org.
+//!
+//! Every query here checks the caller's access itself, so no route can
+//! reach a collection it should not:
+//! - read (search it, see its documents): the owner, and members of the
+//!   org it is shared with
+//! - write (add documents): the owner, and admins of that org
+//! - manage (rename, share, delete): the owner alone
+//!
+//! A collection's chunks are embedded with its `embedding_model`
+//! (`documents.embedding_model` by default); changing it makes them stale
+//! until [re-embedded](crate::reembed).
+
+use crate::orgs::ORG_ADMIN;
+use chrono::{DateTime, Utc};
+use ds_types::{CollectionId, OrgId, UserId};
+use serde::Serialize;
+use sqlx::{postgres::PgRow, PgPool, Row};
+
+/// What a caller wants to do with a collection
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Access {
+    Read,
+    Write,
+    Manage,
+}
+
+impl Access {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Access::Read => "read",
+            Access::Write => "write",
+            Access::Manage => "manage",
+        }
+    }
+}
+
+/// A collection as one caller sees it
+#[derive(Debug, Clone, Serialize)]
+pub struct Collection {
+    pub id: CollectionId,
+    pub name: String,
+    pub owner_id: UserId,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub org_id: Option<OrgId>,
+    pub embedding_model: String,
+    /// Documents in it, in any status
+    pub documents: i64,
+    /// The most the caller may do with it
+    #[serde(serialize_with = "access_name")]
+    pub access: Access,
+    pub created_at: DateTime<Utc>,
+}
+
+fn access_name<S: serde::Serializer>(access: &Access, s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_str(access.as_str())
+}
+
+/// Why a collection could not be used
+#[derive(Debug, thiserror::Error)]
+pub enum AccessError {
+    /// Missing, or not readable by the caller, who cannot tell which
+    #[error("no such collection")]
+    NotFound,
+    #[error("{} access to the collection is required", .0.as_str())]
+    Forbidden(Access),
+    /// Sharing with an org the caller is not in
+    #[error("not a member of the organization")]
+    NotMember,
+    #[error(transparent)]
+    Db(#[from] sqlx::Error),
+}
+
+/// Ids of the collections `user` (bound as `param`) may read, as a
+/// subquery for other tables' queries
+pub(crate) fn readable_ids(param: &str) -> String {
+    format!(
+        "SELECT c.id FROM collections c LEFT JOIN org_members m ON m.org_id = c.org_id AND m.user_id = {param} \
+         WHERE c.owner_id = {param} OR m.user_id IS NOT NULL"
+    )
+}
+
+const SELECT: &str = "SELECT c.id, c.name, c.owner_id, c.org_id, c.embedding_model, c.created_at, m.role, \
+    (SELECT COUNT(*) FROM documents d WHERE d.collection_id = c.id) AS documents \
+    FROM collections c LEFT JOIN org_members m ON m.org_id = c.org_id AND m.user_id = $1";
+
+fn from_row(user: UserId, row: &PgRow) -> sqlx::Result<Collection> {
+    let owner_id: UserId = row.try_get("owner_id")?;
+    let role: Option<String> = row.try_get("role")?;
+    let access = match role.as_deref() {
+        _ if owner_id == user => Access::Manage,
+        Some(ORG_ADMIN) => Access::Write,
+        _ => Access::Read,
+    };
+    Ok(Collection {
+        id: row.try_get("id")?,
+        name: row.try_get("name")?,
+        owner_id,
+        org_id: row.try_get("org_id")?,
+        embedding_model: row.try_get("embedding_model")?,
+        documents: row.try_get("documents")?,
+        access,
+        created_at: row.try_get("created_at")?,
+    })
+}
+
+/// Whether `user` belongs to `org`; sharing needs it
+async fn is_member(db: &PgPool, user: UserId, org: OrgId) -> sqlx::Result<bool> {
+    sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM org_members WHERE org_id = $1 AND user_id = $2)")
+        .bind(org)
+        .bind(user)
+        .fetch_one(db)
+        .await
+}
+
+/// Create a collection owned by `owner`, shared with `org` when given
+pub async fn create(
+    db: &PgPool,
+    owner: UserId,
+    name: &str,
+    org: Option<OrgId>,
+    embedding_model: &str,
+) -> Result<Collection, AccessError> {
+    if let Some(org) = org {
+        if !is_member(db, owner, org).await? {
+            return Err(AccessError::NotMember);
+        }
+    }
+    let id = CollectionId::generate();
+    sqlx::query("INSERT INTO collections (id, owner_id, org_id, name, embedding_model) VALUES ($1, $2, $3, $4, $5)")
+        .bind(id)
+        .bind(owner)
+        .bind(org)
+        .bind(name)
+        .bind(embedding_model)
+        .execute(db)
+        .await?;
+    authorize(db, owner, id, Access::Manage).await
+}
+
+/// `id` if `user` may use it for `access`
+pub async fn authorize(db: &PgPool, user: UserId, id: CollectionId, access: Access) -> Result<Collection, AccessError> {
+    let row = sqlx::query(&format!("{SELECT} WHERE c.id = $2 AND (c.owner_id = $1 OR m.user_id IS NOT NULL)"))
+        .bind(user)
+        .bind(id)
+        .fetch_optional(db)
+        .await?;
+    let collection = from_row(user, &row.ok_or(AccessError::NotFound)?)?;
+    if collection.access < access {
+        return Err(AccessError::Forbidden(access));
+    }
+    Ok(collection)
+}
+
+/// Each of `ids` that `user` may read; fails on the first that is not
+pub async fn authorize_all(db: &PgPool, user: UserId, ids: &[CollectionId]) -> Result<Vec<Collection>, AccessError> {
+    let rows = sqlx::query(&format!("{SELECT} WHERE c.id = ANY($2) AND (c.owner_id = $1 OR m.user_id IS NOT NULL)"))
+        .bind(user)
+        .bind(ids)
+        .fetch_all(db)
+        .await?;
+    let found = rows.iter().map(|row| from_row(user, row)).collect::<sqlx::Result<Vec<_>>>()?;
+    if ids.iter().any(|id| !found.iter().any(|c| c.id == *id)) {
+        return Err(AccessError::NotFound);
+    }
+    Ok(found)
+}
+
+/// The collections `user` may read, newest first
+pub async fn list(db: &PgPool, user: UserId) -> sqlx::Result<Vec<Collection>> {
+    let rows =
+        sqlx::query(&format!("{SELECT} WHERE c.owner_id = $1 OR m.user_id IS NOT NULL ORDER BY c.created_at DESC"))
+            .bind(user)
+            .fetch_all(db)
+            .await?;
+    rows.iter().map(|row| from_row(user, row)).collect()
+}
+
+/// Rename one of `user`'s collections, set whom it is shared with, and
+/// change its embedding model when given
+pub async fn update(
+    db: &PgPool,
+    user: UserId,
+    id: CollectionId,
+    name: &str,
+    org: Option<OrgId>,
+    embedding_model: Option<&str>,
+) -> Result<Collection, AccessError> {
+    authorize(db, user, id, Access::Manage).await?;
+    if let Some(org) = org {
+        if !is_member(db, user, org).await? {
+            return Err(AccessError::NotMember);
+        }
+    }
+    sqlx::query(
+        "UPDATE collections SET name = $3, org_id = $4, embedding_model = COALESCE($5, embedding_model) \
+         WHERE id = $1 AND owner_id = $2",
+    )
+    .bind(id)
+    .bind(user)
+    .bind(name)
+    .bind(org)
+    .bind(embedding_model)
+    .execute(db)
+    .await?;
+    authorize(db, user, id, Access::Manage).await
+}
+
+/// Delete one of `user`'s collections with its documents
+pub async fn delete(db: &PgPool, user: UserId, id: CollectionId) -> Result<(), AccessError> {
+    authorize(db, user, id, Access::Manage).await?;
+    sqlx::query("DELETE FROM collections WHERE id = $1 AND owner_id = $2").bind(id).bind(user).execute(db).await?;
+    Ok(())
+}
@@ -0,0 +1,131 @@
+//! What is known about a request before its handler runs, gathered once.
+//!
+//! `attach` builds a [`RequestContext`] just inside the request span and
+//! records the request and trace ids on that span, so every log line under
+//! it (audit events included) carries them. `require_auth` fills in the
+//! caller. Layers read it from the request extensions; handlers take it as
+//! an extractor.
+
+use crate::{auth_middleware::AuthUser, request_id::REQUEST_ID_HEADER};
+use axum::{
+    extract::{ConnectInfo, FromRequestParts, Request},
+    http::{header, request::Parts},
+    middleware::Next,
+    response::Response,
+};
+use ds_core::{
+    error::{ApiError, ApiResult},
+    i18n::Locale,
+};
+use ds_types::OrgId;
+use std::net::{IpAddr, SocketAddr};
+
+/// W3C trace context header
+pub const TRACEPARENT_HEADER: &str = "traceparent";
+
+#[derive(Clone, Debug)]
+pub struct RequestContext {
+    pub request_id: String,
+    pub ip: IpAddr,
+    /// From the caller's `traceparent`, when it sent a valid one
+    pub trace: Option<TraceIds>,
+    /// Negotiated from `Accept-Language`; errors are rendered in it
+    pub locale: Locale,
+    /// Set by `require_auth`
+    pub user: Option<AuthUser>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TraceIds {
+    pub trace_id: String,
+    /// The caller's span
+    pub parent_id: String,
+}
+
+impl TraceIds {
+    /// `00-<trace id>-<parent id>-<flags>` in lowercase hex; all-zero ids
+    /// are invalid
+    pub fn parse(header: &str) -> Option<Self> {
+        let mut parts = header.trim().split('-');
+        let (version, trace_id, parent_id, flags) = (parts.next()?, parts.next()?, parts.next()?, parts.next()?);
+        let hex = |s: &str, len| s.len() == len && s.bytes().all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b));
+        let zero = |s: &str| s.bytes().all(|b| b == b'0');
+        let valid = version == "00"
+            && parts.next().is_none()
+            && hex(trace_id, 32)
+            && hex(parent_id, 16)
+            && hex(flags, 2)
+            && !zero(trace_id)
+            && !zero(parent_id);
+        valid.then(|| Self { trace_id: trace_id.into(), parent_id: parent_id.into() })
+    }
+}
+
+impl RequestContext {
+    /// The caller, on routes behind `require_auth`
+    pub fn user(&self) -> ApiResult<&AuthUser> {
+        self.user.as_ref().ok_or(ApiError::Unauthorized)
+    }
+
+    pub fn org_id(&self) -> Option<OrgId> {
+        self.user.as_ref().and_then(|u| u.org_id)
+    }
+
+    fn from_request(req: &Request, ip: IpAddr) -> Self {
+        let value = |name| req.headers().get(name).and_then(|v| v.to_str().ok());
+        Self {
+            request_id: value(REQUEST_ID_HEADER).unwrap_or_default().to_string(),
+            ip,
+            trace: value(TRACEPARENT_HEADER).and_then(TraceIds::parse),
+            locale: value(header::ACCEPT_LANGUAGE.as_str()).map(Locale::negotiate).unwrap_or_default(),
+            user: None,
+        }
+    }
+}
+
+/// Build the request's context; layer inside the request span and the
+/// request id layer
+pub async fn attach(ConnectInfo(addr): ConnectInfo<SocketAddr>, mut req: Request, next: Next) -> Response {
+    let ctx = RequestContext::from_request(&req, addr.ip());
+    let span = tracing::Span::current();
+    span.record("request_id", ctx.request_id.as_str());
+    if let Some(trace) = &ctx.trace {
+        span.record("trace_id", trace.trace_id.as_str());
+    }
+    req.extensions_mut().insert(ctx);
+    next.run(req).await
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for RequestContext {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        parts.extensions.get::<Self>().cloned().ok_or_else(|| {
+            tracing::error!("request context not attached");
+            ApiError::Internal
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_traceparent_parsing() {
+        let ids = TraceIds::parse("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01").unwrap();
+        assert_eq!(ids.trace_id, "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(ids.parent_id, "00f067aa0ba902b7");
+        for bad in [
+            "01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra",
+            "garbage",
+        ] {
+            assert_eq!(TraceIds::parse(bad), None, "{bad}");
+        }
+    }
+}
@@ -0,0 +1,265 @@
+//! Stored conversations and their rolling summaries.
+//!
+//! A chat sent with a `conversation_id` is prompted with the conversation's
+//! summary and the turns not yet folded into it, then stored as new turns.
+//! Once the unsummarized turns pass `summarize.history_trigger_chars`, a
+//! background pass folds all but the newest
+//! `summarize.history_recent_messages` into the summary, so the prompt stays
+//! bounded however long the conversation runs.
+
+use crate::{admission::Priority, state::AppState, summarize};
+use chrono::{DateTime, Utc};
+use ds_core::error::{ApiError, ApiResult};
+use ds_model::{ChatMessage, Citation};
+use ds_types::{ConversationId, UserId};
+use serde::Serialize;
+use sqlx::{PgPool, Row};
+use uuid::Uuid;
+
+#[derive(Debug, Serialize)]
+pub struct Conversation {
+    pub id: ConversationId,
+    pub title: String,
+    /// Rolling summary of the older turns; empty until the first pass
+    pub summary: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub summary_updated_at: Option<DateTime<Utc>>,
+    pub message_count: u64,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Message {
+    pub id: Uuid,
+    pub role: String,
+    pub content: String,
+    /// Post-generation processor results, by processor
+    pub metadata: serde_json::Value,
+    /// Document passages an assistant reply was given, as in its chat's
+    /// response
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub citations: Vec<Citation>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// What a new turn is prompted with
+#[derive(Debug, Default)]
+pub struct History {
+    pub summary: String,
+    /// Turns not yet folded into the summary, oldest first
+    pub recent: Vec<ChatMessage>,
+}
+
+impl History {
+    /// The summary (as a system message), the recent turns, then `turn`
+    pub fn prompt(&self, turn: &[ChatMessage]) -> Vec<ChatMessage> {
+        let summary = (!self.summary.is_empty()).then(|| ChatMessage {
+            role: "system".into(),
+            content: format!("Summary of the earlier conversation:\n{}", self.summary),
+            ..Default::default()
+        });
+        summary.into_iter().chain(self.recent.iter().cloned()).chain(turn.iter().cloned()).collect()
+    }
+}
+
+pub async fn create(db: &PgPool, user: UserId, title: &str) -> sqlx::Result<Conversation> {
+    let id = ConversationId::generate();
+    let created_at: DateTime<Utc> =
+        sqlx::query_scalar("INSERT INTO conversations (id, user_id, title) VALUES ($1, $2, $3) RETURNING created_at")
+            .bind(id)
+            .bind(user)
+            .bind(title)
+            .fetch_one(db)
+            .await?;
+    Ok(Conversation {
+        id,
+        title: title.into(),
+        summary: String::new(),
+        summary_updated_at: None,
+        message_count: 0,
+        created_at,
+    })
+}
+
+/// `user`'s conversation; `None` for a missing one or someone else's
+pub async fn get(db: &PgPool, user: UserId, id: ConversationId) -> sqlx::Result<Option<Conversation>> {
+    let row = sqlx::query(
+        "SELECT c.title, c.summary, c.summary_updated_at, c.created_at, \
+         (SELECT COUNT(*) FROM messages m WHERE m.conversation_id = c.id) AS message_count \
+         FROM conversations c WHERE c.id = $1 AND c.user_id = $2",
+    )
+    .bind(id)
+    .bind(user)
+    .fetch_optional(db)
+    .await?;
+    let Some(row) = row else { return Ok(None) };
+    Ok(Some(Conversation {
+        id,
+        title: row.try_get("title")?,
+        summary: row.try_get("summary")?,
+        summary_updated_at: row.try_get("summary_updated_at")?,
+        message_count: row.try_get::<i64, _>("message_count")? as u64,
+        created_at: row.try_get("created_at")?,
+    }))
+}
+
+/// Every message of `user`'s conversation, oldest first
+pub async fn messages(db: &PgPool, user: UserId, id: ConversationId) -> sqlx::Result<Option<Vec<Message>>> {
+    let owned: Option<bool> = sqlx::query_scalar("SELECT true FROM conversations WHERE id = $1 AND user_id = $2")
+        .bind(id)
+        .bind(user)
+        .fetch_optional(db)
+        .await?;
+    if owned.is_none() {
+        return Ok(None);
+    }
+    let rows = sqlx::query(
+        "SELECT id, role, content, metadata::text AS metadata, citations::text AS citations, created_at FROM messages \
+         WHERE conversation_id = $1 ORDER BY seq",
+    )
+    .bind(id)
+    .fetch_all(db)
+    .await?;
+    let messages = rows
+        .iter()
+        .map(|row| {
+            let metadata: String = row.try_get("metadata")?;
+            let citations: String = row.try_get("citations")?;
+            Ok(Message {
+                id: row.try_get("id")?,
+                role: row.try_get("role")?,
+                content: row.try_get("content")?,
+                metadata: serde_json::from_str(&metadata).unwrap_or_default(),
+                citations: serde_json::from_str(&citations).unwrap_or_default(),
+                created_at: row.try_get("created_at")?,
+            })
+        })
+        .collect::<sqlx::Result<Vec<_>>>()?;
+    Ok(Some(messages))
+}
+
+/// The summary and unsummarized turns of `user`'s conversation
+pub async fn history(db: &PgPool, user: UserId, id: ConversationId) -> sqlx::Result<Option<History>> {
+    let Some(summary) = sqlx::query_scalar::<_, String>("SELECT summary FROM conversations WHERE id = $1 AND user_id = $2")
+        .bind(id)
+        .bind(user)
+        .fetch_optional(db)
+        .await?
+    else {
+        return Ok(None);
+    };
+    let recent = unsummarized(db, id).await?.into_iter().map(|(_, message)| message).collect();
+    Ok(Some(History { summary, recent }))
+}
+
+/// Turns after the summary, with their sequence numbers
+async fn unsummarized(db: &PgPool, id: ConversationId) -> sqlx::Result<Vec<(i64, ChatMessage)>> {
+    let rows: Vec<(i64, String, String)> = sqlx::query_as(
+        "SELECT m.seq, m.role, m.content FROM messages m JOIN conversations c ON c.id = m.conversation_id \
+         WHERE c.id = $1 AND m.seq > c.summarized_through ORDER BY m.seq",
+    )
+    .bind(id)
+    .fetch_all(db)
+    .await?;
+    Ok(rows.into_iter().map(|(seq, role, content)| (seq, ChatMessage { role, content, ..Default::default() })).collect())
+}
+
+/// Store a finished turn: the caller's messages, then the reply with the
+/// passages it was given; returns the reply's message id
+pub async fn append(
+    db: &PgPool,
+    id: ConversationId,
+    turn: &[ChatMessage],
+    reply: &str,
+    citations: &[Citation],
+) -> sqlx::Result<Uuid> {
+    let mut tx = db.begin().await?;
+    let reply = ChatMessage { role: "assistant".into(), content: reply.into(), ..Default::default() };
+    let citations = serde_json::to_value(citations).unwrap_or_default();
+    let mut message_id = Uuid::nil();
+    for (i, m) in turn.iter().chain([&reply]).enumerate() {
+        message_id = Uuid::new_v4();
+        let cited = if i == turn.len() { citations.clone() } else { serde_json::json!([]) };
+        sqlx::query("INSERT INTO messages (id, conversation_id, role, content, citations) VALUES ($1, $2, $3, $4, $5)")
+            .bind(message_id)
+            .bind(id)
+            .bind(&m.role)
+            .bind(&m.content)
+            .bind(cited)
+            .execute(&mut *tx)
+            .await?;
+    }
+    tx.commit().await?;
+    Ok(message_id)
+}
+
+/// Fold older turns into the summary if the unsummarized history is over
+/// the trigger, summarizing with `model` at batch priority; returns the
+/// tokens generated. A pass that loses a race with another one for the
+/// same conversation is discarded.
+pub async fn compact(state: &AppState, id: ConversationId, model: &str) -> ApiResult<u64> {
+    let cfg = &state.config().summarize;
+    let db_error = |e: sqlx::Error| {
+        tracing::error!(error = %e, conversation_id = %id, "conversation summary query failed");
+        ApiError::Internal
+    };
+    let turns = unsummarized(&state.db, id).await.map_err(db_error)?;
+    let chars: usize = turns.iter().map(|(_, m)| m.content.chars().count()).sum();
+    let keep = cfg.history_recent_messages as usize;
+    if cfg.history_trigger_chars == 0 || chars as u64 <= cfg.history_trigger_chars || turns.len() <= keep {
+        return Ok(0);
+    }
+    let (older, _) = turns.split_at(turns.len() - keep);
+    let through = older.last().map_or(0, |(seq, _)| *seq);
+    let (summary, previous): (String, i64) =
+        sqlx::query_as("SELECT summary, summarized_through FROM conversations WHERE id = $1")
+            .bind(id)
+            .fetch_one(&state.db)
+            .await
+            .map_err(db_error)?;
+    let mut text = if summary.is_empty() { String::new() } else { format!("Earlier summary: {summary}\n\n") };
+    text += &older.iter().map(|(_, m)| format!("{}: {}", m.role, m.content)).collect::<Vec<_>>().join("\n\n");
+
+    let (messages, mut tokens) = summarize::reduce_to_prompt(state, model, Priority::Batch, &text, |_| {}).await?;
+    let _permit = summarize::admit(state, Priority::Batch).await?;
+    let (summary, generated) =
+        summarize::complete(state, model, messages).await.map_err(|e| summarize::upstream_error(model, e))?;
+    tokens += generated;
+    let updated = sqlx::query(
+        "UPDATE conversations SET summary = $1, summarized_through = $2, summary_updated_at = NOW() \
+         WHERE id = $3 AND summarized_through = $4",
+    )
+    .bind(summary.trim())
+    .bind(through)
+    .bind(id)
+    .bind(previous)
+    .execute(&state.db)
+    .await
+    .map_err(db_error)?
+    .rows_affected();
+    tracing::info!(conversation_id = %id, through, stored = updated == 1, "conversation summarized");
+    Ok(tokens)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(role: &str, content: &str) -> ChatMessage {
+        ChatMessage { role: role.into(), content: content.into(), ..Default::default() }
+    }
+
+    #[test]
+    fn test_prompt_puts_summary_before_recent_turns() {
+        let turn = [message("user", "and then?")];
+        let fresh = History { summary: String::new(), recent: vec![message("user", "hi"), message("assistant", "hello")] };
+        let prompt = fresh.prompt(&turn);
+        assert_eq!(prompt.iter().map(|m| m.content.as_str()).collect::<Vec<_>>(), ["hi", "hello", "and then?"]);
+
+        let summarized = History { summary: "They greeted each other.".into(), recent: vec![] };
+        let prompt = summarized.prompt(&turn);
+        assert_eq!(prompt.len(), 2);
+        assert_eq!(prompt[0].role, "system");
+        assert!(prompt[0].content.ends_with("They greeted each other."));
+    }
+}
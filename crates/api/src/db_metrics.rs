@@ -0,0 +1,61 @@
+//! Database time by query name.
+//!
+//! Repository calls wrapped with [`Timed::timed`] are recorded under a
+//! name (`module.function` for repository functions): their latency, pool
+//! acquisition included, in `deepersensor_db_query_duration_seconds`, and
+//! their failures by kind in `deepersensor_db_query_errors_total`.
+//! Connections held across several statements come from [`acquire`], which
+//! records the wait for one. Statements made outside both are still timed
+//! per request by `slow::QueryTimer`, just not by name.
+
+use crate::metrics::Metrics;
+use sqlx::{pool::PoolConnection, PgPool, Postgres};
+use std::{future::Future, time::Instant};
+
+/// Call latency by query name
+pub const DURATION: &str = "deepersensor_db_query_duration_seconds";
+/// Failed calls by query name and kind
+pub const ERRORS: &str = "deepersensor_db_query_errors_total";
+/// Waits in [`acquire`]
+pub const ACQUIRE: &str = "deepersensor_db_acquire_duration_seconds";
+
+pub trait Timed<T>: Future<Output = sqlx::Result<T>> + Sized {
+    /// Record this call's latency and failure as `name`
+    fn timed(self, metrics: &Metrics, name: &'static str) -> impl Future<Output = sqlx::Result<T>> {
+        async move {
+            let start = Instant::now();
+            let result = self.await;
+            metrics.observe(DURATION, &[("query", name)], start.elapsed());
+            if let Err(e) = &result {
+                metrics.incr(ERRORS, &[("query", name), ("kind", kind(e))]);
+            }
+            result
+        }
+    }
+}
+
+impl<T, F: Future<Output = sqlx::Result<T>>> Timed<T> for F {}
+
+/// A pooled connection, recording how long it took to get
+pub async fn acquire(metrics: &Metrics, pool: &PgPool) -> sqlx::Result<PoolConnection<Postgres>> {
+    let start = Instant::now();
+    let result = pool.acquire().await;
+    metrics.observe(ACQUIRE, &[], start.elapsed());
+    if let Err(e) = &result {
+        metrics.incr(ERRORS, &[("query", "acquire"), ("kind", kind(e))]);
+    }
+    result
+}
+
+fn kind(e: &sqlx::Error) -> &'static str {
+    match e {
+        sqlx::Error::RowNotFound => "row_not_found",
+        sqlx::Error::PoolTimedOut => "pool_timed_out",
+        sqlx::Error::PoolClosed => "pool_closed",
+        sqlx::Error::Database(db) if db.is_unique_violation() => "unique_violation",
+        sqlx::Error::Database(_) => "database",
+        sqlx::Error::Io(_) | sqlx::Error::Tls(_) => "connection",
+        sqlx::Error::ColumnDecode { .. } | sqlx::Error::Decode(_) | sqlx::Error::ColumnNotFound(_) => "decode",
+        _ => "other",
+    }
+}
@@ -0,0 +1,387 @@
+//! Text documents searched by the `rag_search` tool.
+//!
+//! A document is cut into chunks of at most `documents.chunk_chars`
+//! characters with one of the [`chunking`] strategies, and each chunk is
+//! embedded for [`retrieval`](crate::retrieval) with the embedding model of
+//! the document's [collection](crate::collections), else
+//! `documents.embedding_model`.
+//!
+//! Text sent as such is indexed at once ([`add`]). A stored file (PDF,
+//! DOCX, HTML, text) is queued instead ([`add_file`]): the `documents` job
+//! claims it, extracts its text and metadata with `ds_parse`, and indexes
+//! it, moving its `status` from `pending` through `processing` to `ready`,
+//! or `failed` with the reason in `error`. Errors that may pass (the
+//! embedding model being down) are retried up to `documents.max_attempts`
+//! times; a file that cannot be parsed fails at once. A document left
+//! `processing` by an instance that died is taken over after
+//! `documents.process_timeout_secs`.
+
+use crate::{
+    chunking::{self, Strategy},
+    collections::{self, Collection},
+    db_metrics::{self, Timed},
+    files,
+    state::AppState,
+};
+use chrono::{DateTime, Utc};
+use ds_types::{CollectionId, DocumentId, FileId, UserId};
+use serde::Serialize;
+use sqlx::{Connection, PgConnection, PgPool, Row};
+
+/// Documents processed from files by result (`ready`, `failed`, `retry`)
+pub const PROCESSED: &str = "deepersensor_documents_processed_total";
+
+/// A document and how far its processing has got
+#[derive(Debug, Serialize)]
+pub struct Document {
+    pub id: DocumentId,
+    /// Empty until processing finds one, for files added without a title
+    pub title: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub collection_id: Option<CollectionId>,
+    /// `pending`, `processing`, `ready`, or `failed`
+    pub status: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file_id: Option<FileId>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    pub chunking: String,
+    pub metadata: serde_json::Value,
+    pub chars: i64,
+    pub chunks: i32,
+    pub created_at: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub processed_at: Option<DateTime<Utc>>,
+}
+
+/// Why a document could not be added
+#[derive(Debug, thiserror::Error)]
+pub enum AddError {
+    #[error("embedding failed: {0}")]
+    Embed(#[from] ds_model::ModelError),
+    #[error(transparent)]
+    Db(#[from] sqlx::Error),
+}
+
+/// The strategy named by `documents.chunking`
+pub fn default_strategy(state: &AppState) -> Strategy {
+    state.config().documents.chunking.parse().unwrap_or_else(|e| {
+        tracing::warn!(error = %e, "DOCUMENTS_CHUNKING is invalid; chunking by sentence");
+        Strategy::default()
+    })
+}
+
+/// The model `collection`'s chunks are embedded with
+fn embedding_model<'a>(state: &'a AppState, collection: Option<&'a Collection>) -> &'a str {
+    collection.map_or(&state.config().documents.embedding_model, |c| &c.embedding_model)
+}
+
+/// Chunk, embed, and store `text`, in `collection` when given (which the
+/// caller has checked `user` may write to); returns the id and the chunk
+/// count
+pub async fn add(
+    state: &AppState,
+    user: UserId,
+    title: &str,
+    text: &str,
+    strategy: Strategy,
+    collection: Option<&Collection>,
+) -> Result<(DocumentId, usize), AddError> {
+    let model = embedding_model(state, collection);
+    let chunks = chunking::chunk(state, strategy, text).await?;
+    let embeddings = embed(state, model, &chunks).await?;
+    let offsets = chunking::offsets(text, &chunks);
+    let id = DocumentId::generate();
+    let mut conn = db_metrics::acquire(&state.metrics, &state.db).await?;
+    let mut tx = conn.begin().await?;
+    sqlx::query(
+        "INSERT INTO documents (id, user_id, title, chars, chunking, chunks, collection_id, processed_at) \
+         VALUES ($1, $2, $3, $4, $5, $6, $7, NOW())",
+    )
+    .bind(id)
+    .bind(user)
+    .bind(title)
+    .bind(text.chars().count() as i64)
+    .bind(strategy.as_str())
+    .bind(chunks.len() as i32)
+    .bind(collection.map(|c| c.id))
+    .execute(&mut *tx)
+    .await?;
+    write_chunks(&mut tx, id, (model, &state.config().documents.embedding_version), &chunks, &offsets, &embeddings)
+        .await?;
+    tx.commit().await?;
+    Ok((id, chunks.len()))
+}
+
+async fn embed(state: &AppState, model: &str, chunks: &[String]) -> Result<Vec<Vec<f32>>, ds_model::ModelError> {
+    let mut embeddings = Vec::with_capacity(chunks.len());
+    for chunk in chunks {
+        embeddings.push(state.provider.embed(model, chunk).await?);
+    }
+    Ok(embeddings)
+}
+
+/// Store a document's chunks, embedded with `(model, version)`
+async fn write_chunks(
+    conn: &mut PgConnection,
+    id: DocumentId,
+    (model, version): (&str, &str),
+    chunks: &[String],
+    offsets: &[Option<(usize, usize)>],
+    embeddings: &[Vec<f32>],
+) -> sqlx::Result<()> {
+    for (ordinal, ((chunk, offsets), embedding)) in chunks.iter().zip(offsets).zip(embeddings).enumerate() {
+        sqlx::query(
+            "INSERT INTO document_chunks (document_id, ordinal, content, embedding_model, embedding_version, \
+             embedding, start_char, end_char) VALUES ($1, $2, $3, $4, $5, $6::real[]::vector, $7, $8)",
+        )
+        .bind(id)
+        .bind(ordinal as i32)
+        .bind(chunk)
+        .bind(model)
+        .bind(version)
+        .bind(embedding)
+        .bind(offsets.map(|(start, _)| start as i32))
+        .bind(offsets.map(|(_, end)| end as i32))
+        .execute(&mut *conn)
+        .await?;
+    }
+    Ok(())
+}
+
+/// Media type of one of `user`'s servable files
+pub async fn file_type(db: &PgPool, user: UserId, file: FileId) -> sqlx::Result<Option<String>> {
+    sqlx::query_scalar("SELECT content_type FROM files WHERE id = $1 AND user_id = $2 AND status = 'clean'")
+        .bind(file)
+        .bind(user)
+        .fetch_optional(db)
+        .await
+}
+
+/// Queue `file` to be parsed and indexed, in `collection` when given (as
+/// for [`add`]); `title` may be empty to take the one the file gives
+pub async fn add_file(
+    db: &PgPool,
+    user: UserId,
+    file: FileId,
+    content_type: &str,
+    title: &str,
+    strategy: Strategy,
+    collection: Option<CollectionId>,
+) -> sqlx::Result<DocumentId> {
+    let id = DocumentId::generate();
+    sqlx::query(
+        "INSERT INTO documents (id, user_id, title, chars, status, file_id, content_type, chunking, collection_id) \
+         VALUES ($1, $2, $3, 0, 'pending', $4, $5, $6, $7)",
+    )
+    .bind(id)
+    .bind(user)
+    .bind(title)
+    .bind(file)
+    .bind(content_type)
+    .bind(strategy.as_str())
+    .bind(collection)
+    .execute(db)
+    .await?;
+    Ok(id)
+}
+
+/// One of `user`'s documents, or one in a collection `user` may read
+pub async fn get(db: &PgPool, user: UserId, id: DocumentId) -> sqlx::Result<Option<Document>> {
+    let row = sqlx::query(&format!(
+        "SELECT id, title, collection_id, status, error, file_id, content_type, chunking, metadata, chars, chunks, \
+         created_at, processed_at FROM documents \
+         WHERE id = $1 AND (user_id = $2 OR collection_id IN ({}))",
+        collections::readable_ids("$2")
+    ))
+    .bind(id)
+    .bind(user)
+    .fetch_optional(db)
+    .await?;
+    let Some(row) = row else { return Ok(None) };
+    Ok(Some(Document {
+        id: row.try_get("id")?,
+        title: row.try_get("title")?,
+        collection_id: row.try_get("collection_id")?,
+        status: row.try_get("status")?,
+        error: row.try_get("error")?,
+        file_id: row.try_get("file_id")?,
+        content_type: row.try_get("content_type")?,
+        chunking: row.try_get("chunking")?,
+        metadata: row.try_get("metadata")?,
+        chars: row.try_get("chars")?,
+        chunks: row.try_get("chunks")?,
+        created_at: row.try_get("created_at")?,
+        processed_at: row.try_get("processed_at")?,
+    }))
+}
+
+/// A document claimed for processing
+struct Claimed {
+    id: DocumentId,
+    user: UserId,
+    file: Option<FileId>,
+    content_type: String,
+    title: String,
+    chunking: String,
+    /// The collection's, else `None` for `documents.embedding_model`
+    embedding_model: Option<String>,
+    /// Also fences the result: only the latest claim may record one
+    attempts: i32,
+}
+
+/// A document's text, chunked and embedded
+struct Indexed {
+    title: Option<String>,
+    chars: i64,
+    metadata: ds_parse::Metadata,
+    chunks: Vec<String>,
+    offsets: Vec<Option<(usize, usize)>>,
+    /// What `embeddings` were made with
+    model: String,
+    embeddings: Vec<Vec<f32>>,
+}
+
+/// Why processing stopped
+enum Failure {
+    /// Will not go better on another try
+    Permanent(String),
+    Transient(String),
+}
+
+/// Process queued documents until none is left, returning how many were
+/// taken
+pub async fn process_pending(state: &AppState) -> anyhow::Result<usize> {
+    let mut taken = 0;
+    while let Some(claimed) = claim(state).await? {
+        taken += 1;
+        match index(state, &claimed).await {
+            Ok(indexed) => finish(state, &claimed, indexed).await?,
+            Err(failure) => fail(state, &claimed, failure).await?,
+        }
+    }
+    Ok(taken)
+}
+
+async fn claim(state: &AppState) -> sqlx::Result<Option<Claimed>> {
+    let timeout = state.config().documents.process_timeout_secs as f64;
+    let row = sqlx::query(
+        "UPDATE documents SET status = 'processing', claimed_at = NOW(), attempts = attempts + 1 \
+         WHERE id = (SELECT id FROM documents WHERE status = 'pending' \
+                     OR (status = 'processing' AND claimed_at < NOW() - make_interval(secs => $1)) \
+                     ORDER BY created_at LIMIT 1 FOR UPDATE SKIP LOCKED) \
+         RETURNING id, user_id, file_id, content_type, title, chunking, attempts, \
+         (SELECT c.embedding_model FROM collections c WHERE c.id = collection_id) AS embedding_model",
+    )
+    .bind(timeout)
+    .fetch_optional(&state.db)
+    .timed(&state.metrics, "documents.claim")
+    .await?;
+    let Some(row) = row else { return Ok(None) };
+    Ok(Some(Claimed {
+        id: row.try_get("id")?,
+        user: row.try_get("user_id")?,
+        file: row.try_get("file_id")?,
+        content_type: row.try_get::<Option<String>, _>("content_type")?.unwrap_or_default(),
+        title: row.try_get("title")?,
+        chunking: row.try_get("chunking")?,
+        embedding_model: row.try_get("embedding_model")?,
+        attempts: row.try_get("attempts")?,
+    }))
+}
+
+async fn index(state: &AppState, doc: &Claimed) -> Result<Indexed, Failure> {
+    let transient = |e: &dyn std::fmt::Display| Failure::Transient(e.to_string());
+    let file = match doc.file {
+        Some(file) => files::get(state, file).await.map_err(|e| transient(&e))?,
+        None => None,
+    };
+    let Some(file) = file.filter(|f| f.user_id == doc.user) else {
+        return Err(Failure::Permanent("the file was deleted".into()));
+    };
+    let data = ds_store::collect(file.data).await.map_err(|e| transient(&e))?;
+    let content_type = doc.content_type.clone();
+    let parsed = tokio::task::spawn_blocking(move || ds_parse::parse(&content_type, &data))
+        .await
+        .map_err(|e| transient(&e))?
+        .map_err(|e| Failure::Permanent(e.to_string()))?;
+    let chars = parsed.text.chars().count() as u64;
+    if chars == 0 {
+        return Err(Failure::Permanent("no text found".into()));
+    }
+    let max_chars = state.config().documents.max_chars;
+    if chars > max_chars {
+        return Err(Failure::Permanent(format!("{chars} characters of text, more than {max_chars}")));
+    }
+    let strategy = doc.chunking.parse().unwrap_or_default();
+    let chunks = chunking::chunk(state, strategy, &parsed.text).await.map_err(|e| transient(&e))?;
+    let model = doc.embedding_model.as_deref().unwrap_or(&state.config().documents.embedding_model);
+    let embeddings = embed(state, model, &chunks).await.map_err(|e| transient(&e))?;
+    let offsets = chunking::offsets(&parsed.text, &chunks);
+    Ok(Indexed {
+        title: parsed.metadata.title.clone(),
+        chars: chars as i64,
+        metadata: parsed.metadata,
+        chunks,
+        offsets,
+        model: model.into(),
+        embeddings,
+    })
+}
+
+async fn finish(state: &AppState, doc: &Claimed, indexed: Indexed) -> anyhow::Result<()> {
+    let title = match (doc.title.is_empty(), indexed.title) {
+        (false, _) => doc.title.clone(),
+        (true, Some(title)) => title.chars().take(200).collect(),
+        (true, None) => "Untitled".into(),
+    };
+    let mut conn = db_metrics::acquire(&state.metrics, &state.db).await?;
+    let mut tx = conn.begin().await?;
+    let current = sqlx::query(
+        "UPDATE documents SET status = 'ready', error = NULL, title = $3, chars = $4, chunks = $5, metadata = $6, \
+         processed_at = NOW() WHERE id = $1 AND attempts = $2 AND status = 'processing'",
+    )
+    .bind(doc.id)
+    .bind(doc.attempts)
+    .bind(&title)
+    .bind(indexed.chars)
+    .bind(indexed.chunks.len() as i32)
+    .bind(serde_json::to_value(&indexed.metadata)?)
+    .execute(&mut *tx)
+    .await?
+    .rows_affected();
+    if current == 0 {
+        // Taken over meanwhile; the newer claim records the result
+        return Ok(());
+    }
+    let embedded_with = (indexed.model.as_str(), state.config().documents.embedding_version.as_str());
+    write_chunks(&mut tx, doc.id, embedded_with, &indexed.chunks, &indexed.offsets, &indexed.embeddings).await?;
+    tx.commit().await?;
+    state.metrics.incr(PROCESSED, &[("result", "ready")]);
+    tracing::info!(document_id = %doc.id, user_id = %doc.user, chunks = indexed.chunks.len(), "document.processed");
+    Ok(())
+}
+
+async fn fail(state: &AppState, doc: &Claimed, failure: Failure) -> sqlx::Result<()> {
+    let (permanent, error) = match failure {
+        Failure::Permanent(error) => (true, error),
+        Failure::Transient(error) => (false, error),
+    };
+    let gives_up = permanent || doc.attempts as u32 >= state.config().documents.max_attempts;
+    sqlx::query(
+        "UPDATE documents SET status = $3, error = $4, processed_at = CASE WHEN $5 THEN NOW() END \
+         WHERE id = $1 AND attempts = $2 AND status = 'processing'",
+    )
+    .bind(doc.id)
+    .bind(doc.attempts)
+    .bind(if gives_up { "failed" } else { "pending" })
+    .bind(&error)
+    .bind(gives_up)
+    .execute(&state.db)
+    .await?;
+    state.metrics.incr(PROCESSED, &[("result", if gives_up { "failed" } else { "retry" })]);
+    tracing::warn!(document_id = %doc.id, user_id = %doc.user, error = %error, retry = !gives_up, "document processing failed");
+    Ok(())
+}
@@ -0,0 +1,167 @@
+//! Response encodings picked from the request's `Accept` header.
+//!
+//! A response is either one document (JSON or MessagePack) or a stream of
+//! [`Frame`]s (SSE or NDJSON). Handlers build the value or the frames once
+//! and leave the wire format to this module. Anything unrecognised gets
+//! JSON rather than `406`, as do `*/*` and a missing header.
+
+use axum::{
+    body::{Body, Bytes},
+    http::{header, HeaderMap, HeaderValue},
+    response::{
+        sse::{Event, Sse},
+        IntoResponse, Response,
+    },
+    Json,
+};
+use futures_util::{Stream, StreamExt};
+use serde::Serialize;
+use std::convert::Infallible;
+
+pub const NDJSON: &str = "application/x-ndjson";
+pub const MSGPACK: &str = "application/msgpack";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Encoding {
+    /// One JSON document
+    #[default]
+    Json,
+    /// One MessagePack document, with the same field names as JSON
+    MsgPack,
+    /// Server-sent events
+    Sse,
+    /// One JSON object per line: `{"event": ..., "data": ...}`
+    NdJson,
+}
+
+impl Encoding {
+    /// Media ranges are tried by descending q-value (ties keep header
+    /// order); parameters other than `q` are ignored
+    pub fn negotiate(headers: &HeaderMap) -> Self {
+        let Some(accept) = headers.get(header::ACCEPT).and_then(|v| v.to_str().ok()) else {
+            return Self::Json;
+        };
+        let mut ranges: Vec<(&str, f32)> = accept
+            .split(',')
+            .filter_map(|range| {
+                let mut parts = range.split(';');
+                let media = parts.next()?.trim();
+                let q = parts
+                    .find_map(|p| p.trim().strip_prefix("q="))
+                    .map_or(Some(1.0), |q| q.trim().parse::<f32>().ok())?;
+                (!media.is_empty() && q > 0.0).then_some((media, q))
+            })
+            .collect();
+        ranges.sort_by(|a, b| b.1.total_cmp(&a.1));
+        ranges.into_iter().find_map(|(media, _)| Self::for_media(media)).unwrap_or_default()
+    }
+
+    fn for_media(media: &str) -> Option<Self> {
+        match media.to_ascii_lowercase().as_str() {
+            "application/json" | "application/*" | "*/*" => Some(Self::Json),
+            "application/msgpack" | "application/x-msgpack" | "application/vnd.msgpack" => Some(Self::MsgPack),
+            "text/event-stream" => Some(Self::Sse),
+            "application/x-ndjson" | "application/ndjson" => Some(Self::NdJson),
+            _ => None,
+        }
+    }
+
+    /// Whether frames are sent as they are produced
+    pub fn is_stream(self) -> bool {
+        matches!(self, Self::Sse | Self::NdJson)
+    }
+}
+
+/// One event of a streamed response, its data already serialized as JSON
+#[derive(Debug, Clone)]
+pub struct Frame {
+    pub event: &'static str,
+    pub data: String,
+}
+
+impl Frame {
+    pub fn new<T: Serialize>(event: &'static str, data: &T) -> Self {
+        Self { event, data: serde_json::to_string(data).expect("frame data serializes") }
+    }
+
+    /// An in-band failure, for when the headers are already sent
+    pub fn error(message: &str) -> Self {
+        Self::new("error", &serde_json::json!({ "error": message }))
+    }
+
+    fn ndjson_line(&self) -> Bytes {
+        Bytes::from(format!("{{\"event\":\"{}\",\"data\":{}}}\n", self.event, self.data))
+    }
+}
+
+impl From<Frame> for Event {
+    fn from(frame: Frame) -> Self {
+        Event::default().event(frame.event).data(frame.data)
+    }
+}
+
+fn vary_accept(mut response: Response) -> Response {
+    response.headers_mut().insert(header::VARY, HeaderValue::from_static("accept"));
+    response
+}
+
+/// `value` as one document; stream encodings fall back to JSON
+pub fn document<T: Serialize>(encoding: Encoding, value: &T) -> Response {
+    let response = match encoding {
+        Encoding::MsgPack => match rmp_serde::to_vec_named(value) {
+            Ok(bytes) => ([(header::CONTENT_TYPE, MSGPACK)], bytes).into_response(),
+            Err(e) => {
+                tracing::error!(error = %e, "msgpack encoding failed");
+                ds_core::error::ApiError::Internal.into_response()
+            }
+        },
+        Encoding::Json | Encoding::Sse | Encoding::NdJson => Json(value).into_response(),
+    };
+    vary_accept(response)
+}
+
+/// `frames` as they arrive; document encodings fall back to SSE
+pub fn stream<S>(encoding: Encoding, frames: S) -> Response
+where
+    S: Stream<Item = Frame> + Send + 'static,
+{
+    let response = match encoding {
+        Encoding::NdJson => {
+            let body = Body::from_stream(frames.map(|frame| Ok::<_, Infallible>(frame.ndjson_line())));
+            ([(header::CONTENT_TYPE, NDJSON), (header::CACHE_CONTROL, "no-cache")], body).into_response()
+        }
+        Encoding::Sse | Encoding::Json | Encoding::MsgPack => {
+            Sse::new(frames.map(|frame| Ok::<_, Infallible>(Event::from(frame)))).into_response()
+        }
+    };
+    vary_accept(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn accept(value: &str) -> Encoding {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ACCEPT, value.parse().unwrap());
+        Encoding::negotiate(&headers)
+    }
+
+    #[test]
+    fn test_accept_negotiation() {
+        assert_eq!(Encoding::negotiate(&HeaderMap::new()), Encoding::Json);
+        assert_eq!(accept("application/msgpack"), Encoding::MsgPack);
+        assert_eq!(accept("text/event-stream"), Encoding::Sse);
+        assert_eq!(accept("application/x-ndjson; charset=utf-8"), Encoding::NdJson);
+        assert_eq!(accept("application/json;q=0.5, application/vnd.msgpack"), Encoding::MsgPack);
+        assert_eq!(accept("text/html, application/ndjson;q=0.1"), Encoding::NdJson);
+        assert_eq!(accept("text/event-stream;q=0, */*"), Encoding::Json);
+        assert_eq!(accept("text/html"), Encoding::Json);
+    }
+
+    #[test]
+    fn test_ndjson_lines_wrap_the_frame() {
+        let line = Frame::new("chunk", &serde_json::json!({ "content": "hi" })).ndjson_line();
+        assert_eq!(&line[..], b"{\"event\":\"chunk\",\"data\":{\"content\":\"hi\"}}\n");
+    }
+}
@@ -0,0 +1,168 @@
+//! Post-generation processors.
+//!
+//! Once a conversation turn is stored, every registered [`Processor`] runs
+//! on it in the background, and whatever it returns is merged into the
+//! assistant message's `metadata` under the processor's name. Built-ins are
+//! enabled with `enrich.processors`; deployments add their own with
+//! [`Processors::with`] on `AppState::processors` without touching routes.
+
+use crate::state::AppState;
+use async_trait::async_trait;
+use ds_core::config::AppConfig;
+use ds_model::ChatMessage;
+use ds_types::{ConversationId, UserId};
+use futures_util::future::join_all;
+use serde_json::Value;
+use std::{sync::Arc, time::Duration};
+use uuid::Uuid;
+
+/// Processor runs by processor and result
+pub const RUNS: &str = "deepersensor_enrichment_total";
+
+pub type ProcessorResult = Result<Option<Value>, Box<dyn std::error::Error + Send + Sync>>;
+
+/// A stored turn handed to processors
+#[derive(Debug, Clone)]
+pub struct Turn {
+    pub conversation_id: ConversationId,
+    /// The assistant message results are attached to
+    pub message_id: Uuid,
+    pub user_id: UserId,
+    pub model: String,
+    /// The caller's messages for this turn
+    pub messages: Vec<ChatMessage>,
+    pub reply: String,
+}
+
+#[async_trait]
+pub trait Processor: Send + Sync {
+    /// Key the result is stored under in the message's `metadata`
+    fn name(&self) -> &'static str;
+
+    /// `None` when there is nothing to attach
+    async fn process(&self, state: &AppState, turn: &Turn) -> ProcessorResult;
+}
+
+/// The processors run on every stored turn
+#[derive(Clone, Default)]
+pub struct Processors(Vec<Arc<dyn Processor>>);
+
+impl Processors {
+    /// Built-ins named in `enrich.processors`; unknown names are skipped
+    pub fn from_config(cfg: &AppConfig) -> Self {
+        let mut processors = Self::default();
+        for name in cfg.enrich.processors.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+            match name {
+                "title" => processors = processors.with(Title),
+                other => tracing::warn!(processor = other, "unknown post-generation processor; skipped"),
+            }
+        }
+        processors
+    }
+
+    pub fn with(mut self, processor: impl Processor + 'static) -> Self {
+        self.0.push(Arc::new(processor));
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Run every processor on `turn` concurrently, each bounded by
+    /// `enrich.timeout_ms`, and attach the results
+    pub async fn run(&self, state: &AppState, turn: &Turn) {
+        let timeout = Duration::from_millis(state.config().enrich.timeout_ms);
+        join_all(self.0.iter().map(|p| async move {
+            let result = match tokio::time::timeout(timeout, p.process(state, turn)).await {
+                Ok(Ok(Some(value))) => match attach(&state.db, turn.message_id, p.name(), &value).await {
+                    Ok(()) => "attached",
+                    Err(e) => {
+                        tracing::warn!(error = %e, processor = p.name(), "attaching processor result failed");
+                        "error"
+                    }
+                },
+                Ok(Ok(None)) => "empty",
+                Ok(Err(e)) => {
+                    tracing::warn!(
+                        error = %e,
+                        processor = p.name(),
+                        conversation_id = %turn.conversation_id,
+                        "post-generation processor failed"
+                    );
+                    "error"
+                }
+                Err(_) => "timeout",
+            };
+            state.metrics.incr(RUNS, &[("processor", p.name()), ("result", result)]);
+        }))
+        .await;
+    }
+}
+
+async fn attach(db: &sqlx::PgPool, message_id: Uuid, name: &str, value: &Value) -> sqlx::Result<()> {
+    sqlx::query("UPDATE messages SET metadata = metadata || jsonb_build_object($1::text, $2::jsonb) WHERE id = $3")
+        .bind(name)
+        .bind(value.to_string())
+        .bind(message_id)
+        .execute(db)
+        .await?;
+    Ok(())
+}
+
+/// Longest title the `title` processor produces, in characters
+const TITLE_CHARS: usize = 60;
+
+/// Titles a turn after the first sentence of the caller's first message,
+/// without a model call
+pub struct Title;
+
+#[async_trait]
+impl Processor for Title {
+    fn name(&self) -> &'static str {
+        "title"
+    }
+
+    async fn process(&self, _state: &AppState, turn: &Turn) -> ProcessorResult {
+        let text = turn.messages.iter().find(|m| m.role == "user").map_or("", |m| m.content.as_str());
+        Ok(title(text).map(Value::String))
+    }
+}
+
+/// First sentence (or line) of `text`, whitespace folded, cut at a word
+/// boundary to fit `TITLE_CHARS`
+pub fn title(text: &str) -> Option<String> {
+    let first = text.trim().split(['\n', '.', '?', '!']).next().unwrap_or("");
+    let mut out = String::new();
+    for word in first.split_whitespace() {
+        if out.chars().count() + word.chars().count() + usize::from(!out.is_empty()) > TITLE_CHARS {
+            break;
+        }
+        if !out.is_empty() {
+            out.push(' ');
+        }
+        out.push_str(word);
+    }
+    if out.is_empty() {
+        // A single word longer than the limit
+        out = first.trim().chars().take(TITLE_CHARS).collect();
+    }
+    (!out.is_empty()).then_some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_title_takes_the_first_sentence_within_the_limit() {
+        assert_eq!(title("  How do I  rotate keys? Also, why.").as_deref(), Some("How do I rotate keys"));
+        assert_eq!(title("plan\nsecond line").as_deref(), Some("plan"));
+        let long = "word ".repeat(30);
+        let cut = title(&long).unwrap();
+        assert!(cut.chars().count() <= TITLE_CHARS && cut.ends_with("word"), "{cut}");
+        assert_eq!(title(&"x".repeat(100)).unwrap().chars().count(), TITLE_CHARS);
+        assert_eq!(title("   "), None);
+        assert_eq!(title("?"), None);
+    }
+}
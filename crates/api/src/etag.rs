@@ -0,0 +1,87 @@
+//! Conditional GETs for polled resources.
+//!
+//! `conditional` is a route layer: a successful `GET` or `HEAD` response
+//! gets a weak `ETag` from a hash of its body, and a request whose
+//! `If-None-Match` already names it gets `304` without the body. The
+//! handler still runs, so this saves bandwidth and client work rather than
+//! server work. Bodies larger than `MAX_BODY`, or of unknown length, are
+//! left alone.
+
+use crate::state::AppState;
+use axum::{
+    body::{Body, HttpBody},
+    extract::Request,
+    http::{header, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Extension,
+};
+use sha2::{Digest, Sha256};
+
+/// Conditional GETs by result (`not_modified`, `modified`)
+pub const RESPONSES: &str = "deepersensor_etag_responses_total";
+
+/// Largest body buffered to hash
+const MAX_BODY: u64 = 1024 * 1024;
+
+/// `W/"…"` from the first 128 bits of the body's SHA-256; weak, since
+/// compression may change the bytes sent
+pub fn tag(body: &[u8]) -> String {
+    format!("W/\"{}\"", hex::encode(&Sha256::digest(body)[..16]))
+}
+
+/// Weak comparison against an `If-None-Match` list
+pub fn matches(if_none_match: &str, etag: &str) -> bool {
+    let opaque = |t: &str| t.trim().trim_start_matches("W/").to_string();
+    let etag = opaque(etag);
+    if_none_match.split(',').any(|candidate| candidate.trim() == "*" || opaque(candidate) == etag)
+}
+
+pub async fn conditional(Extension(state): Extension<AppState>, req: Request, next: Next) -> Response {
+    if req.method() != Method::GET && req.method() != Method::HEAD {
+        return next.run(req).await;
+    }
+    let if_none_match = req.headers().get(header::IF_NONE_MATCH).and_then(|v| v.to_str().ok()).map(str::to_string);
+    let response = next.run(req).await;
+    let size = response.body().size_hint().upper();
+    if response.status() != StatusCode::OK || size.is_none_or(|len| len > MAX_BODY) {
+        return response;
+    }
+    let (mut parts, body) = response.into_parts();
+    let bytes = match axum::body::to_bytes(body, MAX_BODY as usize).await {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            tracing::error!(error = %e, "buffering response for etag failed");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    let etag = tag(&bytes);
+    parts.headers.insert(header::ETAG, HeaderValue::from_str(&etag).expect("hex etag is a valid header"));
+    if if_none_match.is_some_and(|inm| matches(&inm, &etag)) {
+        state.metrics.incr(RESPONSES, &[("result", "not_modified")]);
+        parts.status = StatusCode::NOT_MODIFIED;
+        parts.headers.remove(header::CONTENT_LENGTH);
+        parts.headers.remove(header::CONTENT_TYPE);
+        return Response::from_parts(parts, Body::empty());
+    }
+    state.metrics.incr(RESPONSES, &[("result", "modified")]);
+    Response::from_parts(parts, Body::from(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_if_none_match_comparison() {
+        let etag = tag(b"[\"llama3\"]");
+        assert!(etag.starts_with("W/\"") && etag.len() == 2 + 32 + 2);
+        let opaque = etag.trim_start_matches("W/");
+        assert!(matches(&etag, &etag));
+        assert!(matches(opaque, &etag));
+        assert!(matches(&format!("\"other\", {etag}"), &etag));
+        assert!(matches("*", &etag));
+        assert!(!matches("\"other\"", &etag));
+        assert_ne!(tag(b"[]"), etag);
+    }
+}
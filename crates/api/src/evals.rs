@@ -0,0 +1,405 @@
+//! Eval sets and scored runs of them.
+//!
+//! An eval set is a list of prompts, each with properties its response is
+//! expected to have. A run sends every prompt to every requested model, at
+//! most `chat.eval_concurrency` at a time and through the admission queue
+//! as batch work, scores each response, and stores the result.
+
+use crate::{
+    admission::{Priority, Ticket},
+    guard,
+    state::AppState,
+};
+use ds_model::{ChatMessage, ChatRequest};
+use ds_types::{EvalRunId, EvalSetId, UserId};
+use futures_util::StreamExt;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use sqlx::{PgPool, Row};
+use std::time::Instant;
+use tokio::sync::mpsc;
+use validator::{Validate, ValidationError};
+
+/// Similarity to `reference` required when a case does not set one
+pub const DEFAULT_MIN_SIMILARITY: f64 = 0.8;
+
+#[derive(Debug, Clone, Serialize, Deserialize, Validate)]
+pub struct EvalCase {
+    #[validate(length(min = 1, message = "prompt is required"))]
+    pub prompt: String,
+    #[serde(default)]
+    #[validate(nested)]
+    pub expect: Expect,
+}
+
+/// Properties a response must have; every one given must hold to pass
+#[derive(Debug, Clone, Default, Serialize, Deserialize, Validate)]
+pub struct Expect {
+    /// Substrings the response must contain
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub contains: Vec<String>,
+    /// Substrings the response must not contain
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub not_contains: Vec<String>,
+    /// Regex the response must match
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[validate(custom(function = "valid_pattern"))]
+    pub pattern: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u64>,
+    /// Expected response, diffed word by word against the actual one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reference: Option<String>,
+    /// Similarity to `reference` (0 to 1) required to pass
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[validate(range(min = 0.0, max = 1.0, message = "between 0 and 1 required"))]
+    pub min_similarity: Option<f64>,
+}
+
+fn valid_pattern(pattern: &str) -> Result<(), ValidationError> {
+    Regex::new(pattern)
+        .map(|_| ())
+        .map_err(|e| ValidationError::new("pattern").with_message(e.to_string().into()))
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Check {
+    /// e.g. `contains:foo`, `max_tokens`, `similarity`
+    pub check: String,
+    pub passed: bool,
+}
+
+/// One case run against one model
+#[derive(Debug, Clone, Serialize)]
+pub struct CaseResult {
+    pub case_index: usize,
+    pub model: String,
+    pub output: String,
+    pub output_tokens: u64,
+    pub latency_ms: u64,
+    pub checks: Vec<Check>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub similarity: Option<f64>,
+    pub passed: bool,
+    /// Set when the generation itself failed; such a case never passes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ModelScore {
+    pub model: String,
+    pub passed: usize,
+    pub total: usize,
+    /// `passed / total`
+    pub score: f64,
+}
+
+/// Word-level diff ratio of two texts: twice the longest common
+/// subsequence over the combined length (1.0 = identical)
+pub fn similarity(actual: &str, expected: &str) -> f64 {
+    let a: Vec<&str> = actual.split_whitespace().collect();
+    let b: Vec<&str> = expected.split_whitespace().collect();
+    if a.is_empty() && b.is_empty() {
+        return 1.0;
+    }
+    let mut prev = vec![0usize; b.len() + 1];
+    for word in &a {
+        let mut row = vec![0usize; b.len() + 1];
+        for (j, other) in b.iter().enumerate() {
+            row[j + 1] = if word == other { prev[j] + 1 } else { row[j].max(prev[j + 1]) };
+        }
+        prev = row;
+    }
+    2.0 * prev[b.len()] as f64 / (a.len() + b.len()) as f64
+}
+
+/// Check `output` against `expect`; returns the checks and, when a
+/// reference is given, the similarity to it
+pub fn score(expect: &Expect, output: &str, tokens: u64) -> (Vec<Check>, Option<f64>) {
+    let mut checks = Vec::new();
+    for s in &expect.contains {
+        checks.push(Check { check: format!("contains:{s}"), passed: output.contains(s.as_str()) });
+    }
+    for s in &expect.not_contains {
+        checks.push(Check { check: format!("not_contains:{s}"), passed: !output.contains(s.as_str()) });
+    }
+    if let Some(pattern) = &expect.pattern {
+        // Validated when the set was created
+        let passed = Regex::new(pattern).is_ok_and(|re| re.is_match(output));
+        checks.push(Check { check: format!("pattern:{pattern}"), passed });
+    }
+    if let Some(max) = expect.max_tokens {
+        checks.push(Check { check: "max_tokens".into(), passed: tokens <= max });
+    }
+    let similarity = expect.reference.as_deref().map(|reference| similarity(output, reference));
+    if let Some(similarity) = similarity {
+        let min = expect.min_similarity.unwrap_or(DEFAULT_MIN_SIMILARITY);
+        checks.push(Check { check: "similarity".into(), passed: similarity >= min });
+    }
+    (checks, similarity)
+}
+
+/// Per-model pass counts, in `models` order
+pub fn summarize(models: &[String], results: &[CaseResult]) -> Vec<ModelScore> {
+    models
+        .iter()
+        .map(|model| {
+            let of_model = results.iter().filter(|r| &r.model == model);
+            let total = of_model.clone().count();
+            let passed = of_model.filter(|r| r.passed).count();
+            ModelScore {
+                model: model.clone(),
+                passed,
+                total,
+                score: if total == 0 { 0.0 } else { passed as f64 / total as f64 },
+            }
+        })
+        .collect()
+}
+
+pub async fn create_set(db: &PgPool, name: &str, created_by: UserId, cases: &[EvalCase]) -> sqlx::Result<EvalSetId> {
+    let id = EvalSetId::generate();
+    let cases = serde_json::to_string(cases).map_err(|e| sqlx::Error::Encode(Box::new(e)))?;
+    sqlx::query("INSERT INTO eval_sets (id, name, created_by, cases) VALUES ($1, $2, $3, $4::jsonb)")
+        .bind(id)
+        .bind(name)
+        .bind(created_by)
+        .bind(cases)
+        .execute(db)
+        .await?;
+    Ok(id)
+}
+
+pub async fn get_cases(db: &PgPool, id: EvalSetId) -> sqlx::Result<Option<Vec<EvalCase>>> {
+    let cases: Option<String> = sqlx::query_scalar("SELECT cases::text FROM eval_sets WHERE id = $1")
+        .bind(id)
+        .fetch_optional(db)
+        .await?;
+    cases
+        .map(|c| serde_json::from_str(&c).map_err(|e| sqlx::Error::Decode(Box::new(e))))
+        .transpose()
+}
+
+pub async fn create_run(db: &PgPool, set: EvalSetId, models: &[String]) -> sqlx::Result<EvalRunId> {
+    let id = EvalRunId::generate();
+    sqlx::query("INSERT INTO eval_runs (id, eval_set_id, models) VALUES ($1, $2, $3)")
+        .bind(id)
+        .bind(set)
+        .bind(models)
+        .execute(db)
+        .await?;
+    Ok(id)
+}
+
+async fn record_result(db: &PgPool, run: EvalRunId, r: &CaseResult) -> sqlx::Result<()> {
+    let checks = serde_json::to_string(&r.checks).map_err(|e| sqlx::Error::Encode(Box::new(e)))?;
+    sqlx::query(
+        "INSERT INTO eval_results (run_id, case_index, model, output, output_tokens, latency_ms, \
+         checks, similarity, passed, error) VALUES ($1, $2, $3, $4, $5, $6, $7::jsonb, $8, $9, $10)",
+    )
+    .bind(run)
+    .bind(r.case_index as i32)
+    .bind(&r.model)
+    .bind(&r.output)
+    .bind(r.output_tokens as i64)
+    .bind(r.latency_ms as i64)
+    .bind(checks)
+    .bind(r.similarity)
+    .bind(r.passed)
+    .bind(&r.error)
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+#[derive(Debug, Serialize)]
+pub struct RunOut {
+    pub id: EvalRunId,
+    pub eval_set_id: EvalSetId,
+    pub models: Vec<String>,
+    /// `running` or `completed`
+    pub status: String,
+    pub summary: Vec<ModelScore>,
+    pub results: Vec<CaseResult>,
+}
+
+pub async fn get_run(db: &PgPool, id: EvalRunId) -> sqlx::Result<Option<RunOut>> {
+    let Some(run) = sqlx::query("SELECT eval_set_id, models, status FROM eval_runs WHERE id = $1")
+        .bind(id)
+        .fetch_optional(db)
+        .await?
+    else {
+        return Ok(None);
+    };
+    let rows = sqlx::query(
+        "SELECT case_index, model, output, output_tokens, latency_ms, checks::text AS checks, \
+         similarity, passed, error FROM eval_results WHERE run_id = $1 ORDER BY case_index, model",
+    )
+    .bind(id)
+    .fetch_all(db)
+    .await?;
+    let results = rows
+        .iter()
+        .map(|row| {
+            let checks: String = row.try_get("checks")?;
+            Ok(CaseResult {
+                case_index: row.try_get::<i32, _>("case_index")? as usize,
+                model: row.try_get("model")?,
+                output: row.try_get("output")?,
+                output_tokens: row.try_get::<i64, _>("output_tokens")? as u64,
+                latency_ms: row.try_get::<i64, _>("latency_ms")? as u64,
+                checks: serde_json::from_str(&checks).map_err(|e| sqlx::Error::Decode(Box::new(e)))?,
+                similarity: row.try_get("similarity")?,
+                passed: row.try_get("passed")?,
+                error: row.try_get("error")?,
+            })
+        })
+        .collect::<sqlx::Result<Vec<_>>>()?;
+    let models: Vec<String> = run.try_get("models")?;
+    Ok(Some(RunOut {
+        id,
+        eval_set_id: run.try_get("eval_set_id")?,
+        summary: summarize(&models, &results),
+        models,
+        status: run.try_get("status")?,
+        results,
+    }))
+}
+
+/// Progress of a run, in completion order
+pub enum Progress {
+    Result { result: CaseResult, completed: usize, total: usize },
+    Done(Vec<ModelScore>),
+}
+
+/// Run every case against every model, storing each result as it lands.
+///
+/// Progress goes to `tx` when anyone is still listening; the run finishes
+/// either way.
+pub async fn run(
+    state: AppState,
+    run_id: EvalRunId,
+    requested_by: UserId,
+    cases: Vec<EvalCase>,
+    models: Vec<String>,
+    tx: mpsc::UnboundedSender<Progress>,
+) {
+    let jobs: Vec<(usize, EvalCase, String)> = models
+        .iter()
+        .flat_map(|model| cases.iter().cloned().enumerate().map(move |(i, case)| (i, case, model.clone())))
+        .collect();
+    let total = jobs.len();
+    let concurrency = (state.config().chat.eval_concurrency as usize).max(1);
+    let mut results = Vec::with_capacity(total);
+    let mut pending = futures_util::stream::iter(jobs)
+        .map(|(i, case, model)| {
+            let state = state.clone();
+            async move { run_case(&state, requested_by, i, &case, &model).await }
+        })
+        .buffer_unordered(concurrency);
+    while let Some(result) = pending.next().await {
+        if let Err(e) = record_result(&state.db, run_id, &result).await {
+            tracing::warn!(error = %e, run_id = %run_id, "recording eval result failed");
+        }
+        results.push(result.clone());
+        let _ = tx.send(Progress::Result { result, completed: results.len(), total });
+    }
+    if let Err(e) = sqlx::query("UPDATE eval_runs SET status = 'completed', finished_at = NOW() WHERE id = $1")
+        .bind(run_id)
+        .execute(&state.db)
+        .await
+    {
+        tracing::warn!(error = %e, run_id = %run_id, "finishing eval run failed");
+    }
+    let summary = summarize(&models, &results);
+    tracing::info!(run_id = %run_id, cases = cases.len(), models = models.len(), "eval run completed");
+    let _ = tx.send(Progress::Done(summary));
+}
+
+async fn run_case(state: &AppState, user: UserId, case_index: usize, case: &EvalCase, model: &str) -> CaseResult {
+    let started = Instant::now();
+    let generated = generate(state, user, model, &case.prompt).await;
+    let latency_ms = started.elapsed().as_millis() as u64;
+    match generated {
+        Ok((output, output_tokens)) => {
+            let (checks, similarity) = score(&case.expect, &output, output_tokens);
+            CaseResult {
+                case_index,
+                model: model.to_string(),
+                passed: checks.iter().all(|c| c.passed),
+                output,
+                output_tokens,
+                latency_ms,
+                checks,
+                similarity,
+                error: None,
+            }
+        }
+        Err(error) => CaseResult {
+            case_index,
+            model: model.to_string(),
+            output: String::new(),
+            output_tokens: 0,
+            latency_ms,
+            checks: Vec::new(),
+            similarity: None,
+            passed: false,
+            error: Some(error),
+        },
+    }
+}
+
+/// One batch-priority generation, redacted like a chat response
+async fn generate(state: &AppState, user: UserId, model: &str, prompt: &str) -> Result<(String, u64), String> {
+    let _permit = match state.admission.enqueue(Priority::Batch) {
+        Some(Ticket::Admitted(permit)) => permit,
+        Some(Ticket::Queued(mut queued)) => queued.admitted().await,
+        None => return Err("generation queue is full".into()),
+    };
+    let messages = vec![ChatMessage { role: "user".into(), content: prompt.to_string(), ..Default::default() }];
+    let messages = guard::prepare_messages(state.config(), user, messages).map_err(|e| e.to_string())?;
+    let stream = state
+        .provider
+        .chat_stream(ChatRequest { model: model.to_string(), messages, ..Default::default() })
+        .await
+        .map_err(|e| e.to_string())?;
+    let mut stream = state.redactor.filter(ds_model::with_terminal_frame(stream, model));
+    let (mut output, mut tokens) = (String::new(), 0u64);
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(|e| e.to_string())?;
+        tokens += u64::from(!chunk.content.is_empty());
+        output.push_str(&chunk.content);
+    }
+    Ok((output, tokens))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_similarity_is_a_word_diff_ratio() {
+        assert_eq!(similarity("", ""), 1.0);
+        assert_eq!(similarity("the quick fox", "the  quick\nfox"), 1.0);
+        assert_eq!(similarity("a b c d", "a x c y"), 0.5);
+        assert_eq!(similarity("anything", ""), 0.0);
+    }
+
+    #[test]
+    fn test_score_checks_every_property() {
+        let expect = Expect {
+            contains: vec!["Paris".into()],
+            not_contains: vec!["London".into()],
+            pattern: Some(r"^The capital".into()),
+            max_tokens: Some(3),
+            reference: Some("The capital is Paris".into()),
+            min_similarity: None,
+        };
+        let (checks, similarity) = score(&expect, "The capital is Paris.", 4);
+        let failed: Vec<_> = checks.iter().filter(|c| !c.passed).map(|c| c.check.as_str()).collect();
+        // "Paris." differs from "Paris" as a word: 2 * 3 / 8
+        assert_eq!(similarity, Some(0.75));
+        assert_eq!(failed, ["max_tokens", "similarity"]);
+    }
+}
@@ -0,0 +1,299 @@
+//! Domain events for downstream consumers, through a transactional outbox.
+//!
+//! A change and the event describing it are written in one transaction
+//! ([`enqueue`]), so neither is kept without the other. The `events` job
+//! then publishes pending rows in order with the configured [`Publisher`]
+//! and marks them published; a row whose publish fails stops the batch
+//! and is retried at the next tick. Delivery is at least once: a row can
+//! be published again if marking it fails, so consumers deduplicate on
+//! the envelope `id`.
+//!
+//! Every payload is wrapped in an [`Envelope`] carrying its `type` and a
+//! per-type schema `version`, bumped whenever a field changes meaning or
+//! is removed. `events.publisher = nats` (JetStream, built with the
+//! `nats` feature) publishes to `<topic>.<type>`; `kafka` (the `kafka`
+//! feature) to `topic`, keyed by event id.
+
+use crate::metrics::Metrics;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use ds_core::config::EventsSection;
+use ds_types::{ApiKeyId, OrgId, UserId};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgConnection, PgPool, Row};
+use std::sync::Arc;
+use uuid::Uuid;
+
+/// Events published by type and result (`ok`, `error`)
+pub const PUBLISHED: &str = "deepersensor_events_published_total";
+
+/// A payload and the schema it follows
+pub trait Event: Serialize {
+    /// Dotted name, `<aggregate>.<what happened>`
+    const TYPE: &'static str;
+    const VERSION: i32;
+}
+
+#[derive(Debug, Serialize)]
+pub struct UserSignedUp {
+    pub user_id: UserId,
+}
+
+impl Event for UserSignedUp {
+    const TYPE: &'static str = "user.signed_up";
+    const VERSION: i32 = 1;
+}
+
+#[derive(Debug, Serialize)]
+pub struct OrgCreated {
+    pub org_id: OrgId,
+    pub owner: UserId,
+}
+
+impl Event for OrgCreated {
+    const TYPE: &'static str = "org.created";
+    const VERSION: i32 = 1;
+}
+
+#[derive(Debug, Serialize)]
+pub struct ApiKeyCreated {
+    pub key_id: ApiKeyId,
+    pub org_id: OrgId,
+    pub created_by: UserId,
+}
+
+impl Event for ApiKeyCreated {
+    const TYPE: &'static str = "api_key.created";
+    const VERSION: i32 = 1;
+}
+
+#[derive(Debug, Serialize)]
+pub struct ApiKeyRevoked {
+    pub key_id: ApiKeyId,
+    pub org_id: OrgId,
+}
+
+impl Event for ApiKeyRevoked {
+    const TYPE: &'static str = "api_key.revoked";
+    const VERSION: i32 = 1;
+}
+
+/// What consumers receive
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Envelope {
+    pub id: Uuid,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub version: i32,
+    pub occurred_at: DateTime<Utc>,
+    pub data: serde_json::Value,
+}
+
+/// Write `event` to the outbox, in the caller's transaction
+pub async fn enqueue<E: Event>(conn: &mut PgConnection, event: &E) -> sqlx::Result<()> {
+    let payload = serde_json::to_value(event).map_err(|e| sqlx::Error::Encode(Box::new(e)))?;
+    sqlx::query("INSERT INTO outbox_events (event_id, type, version, payload) VALUES ($1, $2, $3, $4)")
+        .bind(Uuid::new_v4())
+        .bind(E::TYPE)
+        .bind(E::VERSION)
+        .bind(payload)
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+#[async_trait]
+pub trait Publisher: Send + Sync {
+    /// Short backend name for logs
+    fn name(&self) -> &'static str;
+
+    /// Return once the broker has accepted the event
+    async fn publish(&self, event: &Envelope) -> anyhow::Result<()>;
+}
+
+/// The publisher named by `events.publisher`; `None` publishes nothing
+pub fn from_config(cfg: &EventsSection) -> Option<Arc<dyn Publisher>> {
+    match cfg.publisher.as_str() {
+        "" => None,
+        "log" => Some(Arc::new(Log)),
+        #[cfg(feature = "nats")]
+        "nats" => Some(Arc::new(Nats::new(&cfg.nats_url, &cfg.topic))),
+        #[cfg(feature = "kafka")]
+        "kafka" => match Kafka::new(&cfg.kafka_brokers, &cfg.topic) {
+            Ok(kafka) => Some(Arc::new(kafka)),
+            Err(e) => {
+                tracing::error!(error = %e, "kafka producer could not be created; events stay pending");
+                Some(Arc::new(Unavailable(e.to_string())))
+            }
+        },
+        other => {
+            // Keeping events pending beats pruning them as if none were wanted
+            tracing::error!(publisher = other, "unknown or not built event publisher; events stay pending");
+            Some(Arc::new(Unavailable(format!("event publisher `{other}` is not available"))))
+        }
+    }
+}
+
+/// Publish up to `batch` pending events in order, returning how many were
+/// published; stops at the first failure
+pub async fn relay(db: &PgPool, publisher: &dyn Publisher, batch: i64, metrics: &Metrics) -> anyhow::Result<usize> {
+    let mut tx = db.begin().await?;
+    // Locked so instances relaying at once take different rows
+    let rows = sqlx::query(
+        "SELECT id, event_id, type, version, occurred_at, payload FROM outbox_events \
+         WHERE published_at IS NULL ORDER BY id LIMIT $1 FOR UPDATE SKIP LOCKED",
+    )
+    .bind(batch)
+    .fetch_all(&mut *tx)
+    .await?;
+    let mut published = Vec::with_capacity(rows.len());
+    for row in &rows {
+        let id: i64 = row.try_get("id")?;
+        let event = Envelope {
+            id: row.try_get("event_id")?,
+            kind: row.try_get("type")?,
+            version: row.try_get("version")?,
+            occurred_at: row.try_get("occurred_at")?,
+            data: row.try_get("payload")?,
+        };
+        if let Err(e) = publisher.publish(&event).await {
+            tracing::warn!(error = %e, publisher = publisher.name(), event_id = %event.id, "publishing event failed");
+            metrics.incr(PUBLISHED, &[("type", &event.kind), ("result", "error")]);
+            sqlx::query("UPDATE outbox_events SET attempts = attempts + 1, last_error = $2 WHERE id = $1")
+                .bind(id)
+                .bind(e.to_string())
+                .execute(&mut *tx)
+                .await?;
+            break;
+        }
+        metrics.incr(PUBLISHED, &[("type", &event.kind), ("result", "ok")]);
+        published.push(id);
+    }
+    sqlx::query("UPDATE outbox_events SET published_at = NOW(), attempts = attempts + 1 WHERE id = ANY($1)")
+        .bind(&published)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    Ok(published.len())
+}
+
+/// Delete published events older than `events.retention_hours`, and with
+/// no publisher, unpublished ones as well
+pub async fn prune(db: &PgPool, cfg: &EventsSection) -> sqlx::Result<u64> {
+    let pruned = sqlx::query(
+        "DELETE FROM outbox_events \
+         WHERE occurred_at < NOW() - make_interval(hours => $1) AND (published_at IS NOT NULL OR $2)",
+    )
+    .bind(cfg.retention_hours as i32)
+    .bind(cfg.publisher.is_empty())
+    .execute(db)
+    .await?
+    .rows_affected();
+    Ok(pruned)
+}
+
+/// Writes each event to the log, for development
+pub struct Log;
+
+#[async_trait]
+impl Publisher for Log {
+    fn name(&self) -> &'static str {
+        "log"
+    }
+
+    async fn publish(&self, event: &Envelope) -> anyhow::Result<()> {
+        tracing::info!(event_id = %event.id, r#type = %event.kind, version = event.version, data = %event.data, "event.published");
+        Ok(())
+    }
+}
+
+/// Stands in for a configured publisher this build cannot use
+struct Unavailable(String);
+
+#[async_trait]
+impl Publisher for Unavailable {
+    fn name(&self) -> &'static str {
+        "unavailable"
+    }
+
+    async fn publish(&self, _event: &Envelope) -> anyhow::Result<()> {
+        anyhow::bail!("{}", self.0)
+    }
+}
+
+/// JetStream, which acknowledges once the event is stored and drops
+/// redeliveries within its duplicate window by `Nats-Msg-Id`. A stream
+/// must cover `<topic>.>`.
+#[cfg(feature = "nats")]
+pub struct Nats {
+    url: String,
+    topic: String,
+    /// Connected on first use, so startup does not wait for the broker
+    context: tokio::sync::OnceCell<async_nats::jetstream::Context>,
+}
+
+#[cfg(feature = "nats")]
+impl Nats {
+    pub fn new(url: &str, topic: &str) -> Self {
+        Self { url: url.to_string(), topic: topic.to_string(), context: tokio::sync::OnceCell::new() }
+    }
+}
+
+#[cfg(feature = "nats")]
+#[async_trait]
+impl Publisher for Nats {
+    fn name(&self) -> &'static str {
+        "nats"
+    }
+
+    async fn publish(&self, event: &Envelope) -> anyhow::Result<()> {
+        let context = self
+            .context
+            .get_or_try_init(|| async { anyhow::Ok(async_nats::jetstream::new(async_nats::connect(&self.url).await?)) })
+            .await?;
+        let mut headers = async_nats::HeaderMap::new();
+        headers.insert(async_nats::header::NATS_MESSAGE_ID, event.id.to_string().as_str());
+        let subject = format!("{}.{}", self.topic, event.kind);
+        let ack = context.publish_with_headers(subject, headers, serde_json::to_vec(event)?.into()).await?;
+        ack.await?;
+        Ok(())
+    }
+}
+
+/// An idempotent producer waiting for every in-sync replica
+#[cfg(feature = "kafka")]
+pub struct Kafka {
+    producer: rdkafka::producer::FutureProducer,
+    topic: String,
+}
+
+#[cfg(feature = "kafka")]
+impl Kafka {
+    pub fn new(brokers: &str, topic: &str) -> anyhow::Result<Self> {
+        let producer = rdkafka::ClientConfig::new()
+            .set("bootstrap.servers", brokers)
+            .set("acks", "all")
+            .set("enable.idempotence", "true")
+            .create()?;
+        Ok(Self { producer, topic: topic.to_string() })
+    }
+}
+
+#[cfg(feature = "kafka")]
+#[async_trait]
+impl Publisher for Kafka {
+    fn name(&self) -> &'static str {
+        "kafka"
+    }
+
+    async fn publish(&self, event: &Envelope) -> anyhow::Result<()> {
+        let key = event.id.to_string();
+        let payload = serde_json::to_vec(event)?;
+        let record = rdkafka::producer::FutureRecord::to(&self.topic).key(&key).payload(&payload);
+        self.producer
+            .send(record, std::time::Duration::from_secs(10))
+            .await
+            .map_err(|(e, _)| e)?;
+        Ok(())
+    }
+}
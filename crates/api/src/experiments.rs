@@ -0,0 +1,226 @@
+//! A/B model experiments.
+//!
+//! An active experiment intercepts chats for one model and sends each user
+//! to one of its variants by weight. The first assignment is stored, so a
+//! user stays on their variant for the life of the experiment, and every
+//! generation records the experiment and variant it ran under.
+
+use ds_types::{ExperimentId, UserId};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::{PgPool, Row};
+use validator::Validate;
+
+#[derive(Debug, Clone, Serialize, Deserialize, Validate)]
+pub struct Variant {
+    #[validate(length(min = 1, max = 50, message = "between 1 and 50 characters required"))]
+    pub name: String,
+    #[validate(custom(function = "crate::extract::rules::model_name"))]
+    pub model: String,
+    /// Share of traffic relative to the other variants
+    #[validate(range(min = 1, max = 10000, message = "between 1 and 10000 required"))]
+    pub weight: u32,
+}
+
+/// The variant a user's chat runs under
+#[derive(Debug, Clone)]
+pub struct Assignment {
+    pub experiment_id: ExperimentId,
+    pub variant: String,
+    pub model: String,
+}
+
+/// Weighted pick that is stable for a given experiment and user
+pub fn pick(experiment: ExperimentId, user: UserId, variants: &[Variant]) -> Option<&Variant> {
+    let total: u64 = variants.iter().map(|v| u64::from(v.weight)).sum();
+    if total == 0 {
+        return None;
+    }
+    let digest = Sha256::new()
+        .chain_update(experiment.as_uuid().as_bytes())
+        .chain_update(user.as_uuid().as_bytes())
+        .finalize();
+    let mut point = u64::from_be_bytes(digest[..8].try_into().expect("8 bytes")) % total;
+    variants.iter().find(|v| {
+        let hit = point < u64::from(v.weight);
+        point = point.saturating_sub(u64::from(v.weight));
+        hit
+    })
+}
+
+/// Start an experiment on `model`; fails if one is already running on it
+pub async fn create(db: &PgPool, name: &str, model: &str, variants: &[Variant]) -> sqlx::Result<ExperimentId> {
+    let id = ExperimentId::generate();
+    let mut tx = db.begin().await?;
+    sqlx::query("INSERT INTO experiments (id, name, model) VALUES ($1, $2, $3)")
+        .bind(id)
+        .bind(name)
+        .bind(model)
+        .execute(&mut *tx)
+        .await?;
+    for v in variants {
+        sqlx::query("INSERT INTO experiment_variants (experiment_id, name, model, weight) VALUES ($1, $2, $3, $4)")
+            .bind(id)
+            .bind(&v.name)
+            .bind(&v.model)
+            .bind(v.weight as i32)
+            .execute(&mut *tx)
+            .await?;
+    }
+    tx.commit().await?;
+    Ok(id)
+}
+
+/// Stop routing traffic; returns false if there was no running experiment
+pub async fn stop(db: &PgPool, id: ExperimentId) -> sqlx::Result<bool> {
+    let stopped = sqlx::query(
+        "UPDATE experiments SET status = 'stopped', stopped_at = NOW() WHERE id = $1 AND status = 'active'",
+    )
+    .bind(id)
+    .execute(db)
+    .await?
+    .rows_affected();
+    Ok(stopped == 1)
+}
+
+/// The variant `user` gets for a chat on `model`, if an experiment is
+/// running on it
+pub async fn assign(db: &PgPool, user: UserId, model: &str) -> sqlx::Result<Option<Assignment>> {
+    let rows = sqlx::query(
+        "SELECT e.id, v.name, v.model, v.weight, a.variant AS assigned \
+         FROM experiments e \
+         JOIN experiment_variants v ON v.experiment_id = e.id \
+         LEFT JOIN experiment_assignments a ON a.experiment_id = e.id AND a.user_id = $2 \
+         WHERE e.model = $1 AND e.status = 'active' ORDER BY v.name",
+    )
+    .bind(model)
+    .bind(user)
+    .fetch_all(db)
+    .await?;
+    let Some(first) = rows.first() else { return Ok(None) };
+    let experiment_id: ExperimentId = first.try_get("id")?;
+    let assigned: Option<String> = first.try_get("assigned")?;
+    let variants = rows
+        .iter()
+        .map(|row| {
+            Ok(Variant {
+                name: row.try_get("name")?,
+                model: row.try_get("model")?,
+                weight: row.try_get::<i32, _>("weight")? as u32,
+            })
+        })
+        .collect::<sqlx::Result<Vec<_>>>()?;
+
+    if let Some(v) = assigned.and_then(|name| variants.iter().find(|v| v.name == name)) {
+        return Ok(Some(Assignment { experiment_id, variant: v.name.clone(), model: v.model.clone() }));
+    }
+    let Some(v) = pick(experiment_id, user, &variants) else { return Ok(None) };
+    sqlx::query(
+        "INSERT INTO experiment_assignments (experiment_id, user_id, variant) VALUES ($1, $2, $3) \
+         ON CONFLICT DO NOTHING",
+    )
+    .bind(experiment_id)
+    .bind(user)
+    .bind(&v.name)
+    .execute(db)
+    .await?;
+    Ok(Some(Assignment { experiment_id, variant: v.name.clone(), model: v.model.clone() }))
+}
+
+#[derive(Debug, Serialize)]
+pub struct Results {
+    pub id: ExperimentId,
+    pub name: String,
+    pub model: String,
+    /// `active` or `stopped`
+    pub status: String,
+    pub variants: Vec<VariantResults>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct VariantResults {
+    pub name: String,
+    pub model: String,
+    pub weight: u32,
+    /// Users assigned so far
+    pub users: u64,
+    pub generations: u64,
+    pub output_tokens: u64,
+    pub avg_output_tokens: f64,
+    /// Share of generations that ended with `finish_reason: "error"`
+    pub error_rate: f64,
+}
+
+pub async fn results(db: &PgPool, id: ExperimentId) -> sqlx::Result<Option<Results>> {
+    let Some(experiment) = sqlx::query("SELECT name, model, status FROM experiments WHERE id = $1")
+        .bind(id)
+        .fetch_optional(db)
+        .await?
+    else {
+        return Ok(None);
+    };
+    let rows = sqlx::query(
+        "SELECT v.name, v.model, v.weight, \
+         (SELECT COUNT(*) FROM experiment_assignments a \
+          WHERE a.experiment_id = v.experiment_id AND a.variant = v.name) AS users, \
+         COUNT(g.id) AS generations, \
+         COALESCE(SUM(g.output_tokens), 0)::BIGINT AS output_tokens, \
+         COUNT(g.id) FILTER (WHERE g.finish_reason = 'error') AS errors \
+         FROM experiment_variants v \
+         LEFT JOIN generations g ON g.experiment_id = v.experiment_id AND g.variant = v.name \
+         WHERE v.experiment_id = $1 \
+         GROUP BY v.experiment_id, v.name, v.model, v.weight ORDER BY v.name",
+    )
+    .bind(id)
+    .fetch_all(db)
+    .await?;
+    let ratio = |part: i64, whole: i64| if whole == 0 { 0.0 } else { part as f64 / whole as f64 };
+    let variants = rows
+        .iter()
+        .map(|row| {
+            let generations: i64 = row.try_get("generations")?;
+            let output_tokens: i64 = row.try_get("output_tokens")?;
+            Ok(VariantResults {
+                name: row.try_get("name")?,
+                model: row.try_get("model")?,
+                weight: row.try_get::<i32, _>("weight")? as u32,
+                users: row.try_get::<i64, _>("users")? as u64,
+                generations: generations as u64,
+                output_tokens: output_tokens as u64,
+                avg_output_tokens: ratio(output_tokens, generations),
+                error_rate: ratio(row.try_get("errors")?, generations),
+            })
+        })
+        .collect::<sqlx::Result<Vec<_>>>()?;
+    Ok(Some(Results {
+        id,
+        name: experiment.try_get("name")?,
+        model: experiment.try_get("model")?,
+        status: experiment.try_get("status")?,
+        variants,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn variant(name: &str, weight: u32) -> Variant {
+        Variant { name: name.into(), model: format!("{name}-model"), weight }
+    }
+
+    #[test]
+    fn test_pick_is_sticky_and_follows_weights() {
+        let experiment = ExperimentId::generate();
+        let variants = [variant("control", 3), variant("treatment", 1)];
+        let user = UserId::generate();
+        let first = pick(experiment, user, &variants).unwrap().name.clone();
+        assert!((0..10).all(|_| pick(experiment, user, &variants).unwrap().name == first));
+
+        let treated = (0..4000)
+            .filter(|_| pick(experiment, UserId::generate(), &variants).unwrap().name == "treatment")
+            .count();
+        assert!((800..1200).contains(&treated), "{treated}");
+        assert!(pick(experiment, user, &[]).is_none());
+    }
+}
@@ -0,0 +1,162 @@
+use axum::{
+    body::Bytes,
+    extract::{rejection::JsonRejection, FromRequest, Request},
+    http::StatusCode,
+    Json,
+};
+use ds_core::error::{ApiError, FieldError};
+use serde::de::DeserializeOwned;
+use validator::{Validate, ValidationErrors, ValidationErrorsKind};
+
+/// JSON body that is deserialized and then checked with its `Validate`
+/// derive before the handler runs.
+///
+/// Malformed JSON keeps the status axum would give it; failed rules are
+/// rejected as `ApiError::Validation` with one entry per field.
+pub struct ValidatedJson<T>(pub T);
+
+impl<T, S> FromRequest<S> for ValidatedJson<T>
+where
+    T: DeserializeOwned + Validate,
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        // Buffered first, so the timing covers decoding rather than upload
+        let (parts, body) = req.into_parts();
+        let bytes = Bytes::from_request(Request::from_parts(parts.clone(), body), state)
+            .await
+            .map_err(|e| match e.status() {
+                StatusCode::PAYLOAD_TOO_LARGE => ApiError::PayloadTooLarge(e.body_text()),
+                _ => ApiError::BadRequest(e.body_text()),
+            })?;
+        let started = std::time::Instant::now();
+        let decoded = Json::<T>::from_request(Request::from_parts(parts, bytes.into()), state).await;
+        crate::slow::record(crate::slow::Phase::Serialization, started.elapsed());
+        let Json(value) = decoded.map_err(json_rejection)?;
+        value
+            .validate()
+            .map_err(|e| ApiError::Validation(field_errors(&e)))?;
+        Ok(Self(value))
+    }
+}
+
+fn json_rejection(rejection: JsonRejection) -> ApiError {
+    match rejection.status() {
+        StatusCode::UNPROCESSABLE_ENTITY => ApiError::Unprocessable(rejection.body_text()),
+        StatusCode::PAYLOAD_TOO_LARGE => ApiError::PayloadTooLarge(rejection.body_text()),
+        _ => ApiError::BadRequest(rejection.body_text()),
+    }
+}
+
+/// Flatten nested validator errors into `field.sub[0].name` paths, sorted
+/// so responses are stable
+pub fn field_errors(errors: &ValidationErrors) -> Vec<FieldError> {
+    let mut out = Vec::new();
+    collect(errors, "", &mut out);
+    out.sort_by(|a, b| (&a.field, &a.code).cmp(&(&b.field, &b.code)));
+    out
+}
+
+fn collect(errors: &ValidationErrors, prefix: &str, out: &mut Vec<FieldError>) {
+    for (name, kind) in errors.errors() {
+        let path = if prefix.is_empty() { name.to_string() } else { format!("{prefix}.{name}") };
+        match kind {
+            ValidationErrorsKind::Field(list) => out.extend(list.iter().map(|e| FieldError {
+                field: path.clone(),
+                code: e.code.to_string(),
+                message: e.message.as_deref().unwrap_or(&e.code).to_string(),
+            })),
+            ValidationErrorsKind::Struct(inner) => collect(inner, &path, out),
+            ValidationErrorsKind::List(items) => {
+                for (i, inner) in items {
+                    collect(inner, &format!("{path}[{i}]"), out);
+                }
+            }
+        }
+    }
+}
+
+/// `#[validate(custom(function = ...))]` adapters over the rules in
+/// `validation`, so derive-based DTOs and hand-written checks agree
+pub mod rules {
+    use crate::validation;
+    use ds_core::error::{ApiError, ApiResult};
+    use validator::ValidationError;
+
+    fn rule(result: ApiResult<()>, code: &'static str) -> Result<(), ValidationError> {
+        result.map_err(|e| {
+            let message = match e {
+                ApiError::Unprocessable(message) => message,
+                other => other.to_string(),
+            };
+            ValidationError::new(code).with_message(message.into())
+        })
+    }
+
+    pub fn email(value: &str) -> Result<(), ValidationError> {
+        rule(validation::validate_email(value), "email")
+    }
+
+    pub fn password(value: &str) -> Result<(), ValidationError> {
+        rule(validation::validate_password(value), "password")
+    }
+
+    pub fn model_name(value: &str) -> Result<(), ValidationError> {
+        rule(validation::validate_model_name(value), "model_name")
+    }
+
+    pub fn temperature(value: f64) -> Result<(), ValidationError> {
+        rule(validation::validate_temperature(value), "range")
+    }
+
+    pub fn top_p(value: f64) -> Result<(), ValidationError> {
+        rule(validation::validate_top_p(value), "range")
+    }
+
+    pub fn top_k(value: u32) -> Result<(), ValidationError> {
+        rule(validation::validate_top_k(value), "range")
+    }
+
+    pub fn max_tokens(value: u32) -> Result<(), ValidationError> {
+        rule(validation::validate_max_tokens(value), "range")
+    }
+
+    pub fn stop_sequences(value: &[String]) -> Result<(), ValidationError> {
+        rule(validation::validate_stop_sequences(value), "stop")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Deserialize, Validate)]
+    struct Outer {
+        #[validate(custom(function = "rules::email"))]
+        email: String,
+        #[validate(nested)]
+        inner: Inner,
+    }
+
+    #[derive(Deserialize, Validate)]
+    struct Inner {
+        #[validate(length(min = 1, message = "name is required"))]
+        name: String,
+    }
+
+    #[test]
+    fn test_field_errors_are_flattened_and_sorted() {
+        let dto = Outer { email: "nope".into(), inner: Inner { name: String::new() } };
+        let fields = field_errors(&dto.validate().unwrap_err());
+        assert_eq!(
+            fields,
+            vec![
+                FieldError { field: "email".into(), code: "email".into(), message: "invalid email format".into() },
+                FieldError { field: "inner.name".into(), code: "length".into(), message: "name is required".into() },
+            ]
+        );
+    }
+}
@@ -0,0 +1,332 @@
+//! Stored files and their signed download URLs.
+//!
+//! A download URL carries its expiry and an HMAC over the file id and that
+//! expiry, so `GET /v1/files/{id}/download` needs no session: anyone holding
+//! the URL may fetch the file until it expires. The signature is checked
+//! before anything is read, so forged and expired URLs cost no query.
+//!
+//! Files enter through [`ingest`], which scans them first; only `clean`
+//! files are ever served or re-signed. Contents are kept in the configured
+//! blob store (`files.store`), shared between files with the same bytes,
+//! or in the row when none is set; files keep being served from wherever
+//! they were written.
+
+use crate::{
+    blobs,
+    db_metrics::Timed,
+    metrics::Metrics,
+    scan::{self, Verdict},
+    state::AppState,
+};
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use ds_core::{
+    config::AppConfig,
+    error::{ApiError, ApiResult},
+};
+use ds_store::ByteStream;
+use ds_types::{FileId, UserId};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use sqlx::{PgConnection, PgExecutor, PgPool, Row};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// A stored file's contents
+pub struct StoredFile {
+    pub user_id: UserId,
+    pub content_type: String,
+    pub bytes: u64,
+    pub data: ByteStream,
+}
+
+/// Why a download URL was refused
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Denied {
+    /// Not signed by this server for this file and expiry
+    BadSignature,
+    /// Genuine, but past its expiry
+    Expired,
+}
+
+impl Denied {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Denied::BadSignature => "bad_signature",
+            Denied::Expired => "expired",
+        }
+    }
+}
+
+/// Scan `data` and store it; an infected file is quarantined and rejected
+/// with 422, and a scanner failure rejects it unless `files.scan_fail_open`
+pub async fn ingest(state: &AppState, user: UserId, content_type: &str, data: &[u8]) -> ApiResult<FileId> {
+    let screened = screen(state, user, ds_store::once(data.to_vec())).await?;
+    let id = insert(state, user, content_type, data, screened.status, screened.result.as_deref())
+        .await
+        .map_err(store_error)?;
+    screened.accept(id, user)
+}
+
+/// Store a file as `clean` without scanning it
+pub async fn store(state: &AppState, user: UserId, content_type: &str, data: &[u8]) -> anyhow::Result<FileId> {
+    insert(state, user, content_type, data, "clean", None).await
+}
+
+/// How a file is stored after scanning
+pub(crate) struct Screened {
+    /// `clean` or `quarantined`
+    pub status: &'static str,
+    /// What the scanner reported, `None` when unscanned
+    pub result: Option<String>,
+}
+
+impl Screened {
+    /// The stored file, or 422 if it was quarantined
+    pub fn accept(self, id: FileId, user: UserId) -> ApiResult<FileId> {
+        if self.status == "clean" {
+            return Ok(id);
+        }
+        let found = self.result.unwrap_or_default();
+        tracing::warn!(file_id = %id, user_id = %user, found = %found, "security.file_quarantined");
+        Err(ApiError::Unprocessable(format!("file rejected by malware scan: {found}")))
+    }
+}
+
+/// Pass `data` through the scanner, if there is one. Infected files are
+/// kept for review but never served.
+pub(crate) async fn screen(state: &AppState, user: UserId, data: ByteStream) -> ApiResult<Screened> {
+    let Some(scanner) = &state.scanner else {
+        return Ok(Screened { status: "clean", result: None });
+    };
+    match scanner.scan(data).await {
+        Ok(Verdict::Clean) => {
+            state.metrics.incr(scan::SCANS, &[("result", "clean")]);
+            Ok(Screened { status: "clean", result: Some("clean".into()) })
+        }
+        Ok(Verdict::Infected(found)) => {
+            state.metrics.incr(scan::SCANS, &[("result", "infected")]);
+            Ok(Screened { status: "quarantined", result: Some(found) })
+        }
+        Err(e) => {
+            state.metrics.incr(scan::SCANS, &[("result", "error")]);
+            tracing::error!(error = %e, scanner = scanner.name(), user_id = %user, "file scan failed");
+            if !state.config().files.scan_fail_open {
+                return Err(ApiError::Internal);
+            }
+            Ok(Screened { status: "clean", result: None })
+        }
+    }
+}
+
+pub(crate) fn store_error(e: anyhow::Error) -> ApiError {
+    tracing::error!(error = %e, "storing file failed");
+    ApiError::Internal
+}
+
+/// With a blob store, the row references the blob holding the contents
+/// (see [`blobs`]); without one, the contents go in the row.
+async fn insert(
+    state: &AppState,
+    user: UserId,
+    content_type: &str,
+    data: &[u8],
+    status: &str,
+    scan_result: Option<&str>,
+) -> anyhow::Result<FileId> {
+    let mut tx = state.db.begin().await?;
+    let blob = match &state.blobs {
+        Some(store) => Some(blobs::acquire(&mut tx, store.as_ref(), &state.metrics, data).await?),
+        None => None,
+    };
+    let file = NewFile {
+        user,
+        content_type,
+        bytes: data.len() as u64,
+        data: blob.is_none().then_some(data),
+        blob: blob.as_ref(),
+        status,
+        scan_result,
+    };
+    let id = insert_row(&mut tx, &state.metrics, &file).await?;
+    tx.commit().await?;
+    Ok(id)
+}
+
+/// A file row; exactly one of `data` and `blob` holds the contents
+pub(crate) struct NewFile<'a> {
+    pub user: UserId,
+    pub content_type: &'a str,
+    pub bytes: u64,
+    pub data: Option<&'a [u8]>,
+    pub blob: Option<&'a blobs::Blob>,
+    pub status: &'a str,
+    pub scan_result: Option<&'a str>,
+}
+
+/// Insert `file` in the caller's transaction
+pub(crate) async fn insert_row(conn: &mut PgConnection, metrics: &Metrics, file: &NewFile<'_>) -> sqlx::Result<FileId> {
+    let id = FileId::generate();
+    sqlx::query(
+        "INSERT INTO files (id, user_id, content_type, bytes, data, blob_key, content_hash, status, scan_result, scanned_at) \
+         VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, CASE WHEN $9 IS NULL THEN NULL ELSE NOW() END)",
+    )
+    .bind(id)
+    .bind(file.user)
+    .bind(file.content_type)
+    .bind(file.bytes as i64)
+    .bind(file.data)
+    .bind(file.blob.map(|b| &b.key))
+    .bind(file.blob.map(|b| &b.hash))
+    .bind(file.status)
+    .bind(file.scan_result)
+    .execute(conn)
+    .timed(metrics, "files.insert")
+    .await?;
+    Ok(id)
+}
+
+/// Bytes `user` keeps: their servable files plus the declared size of
+/// their open uploads
+pub async fn usage<'e>(db: impl PgExecutor<'e>, user: UserId) -> sqlx::Result<u64> {
+    let used: i64 = sqlx::query_scalar(
+        "SELECT (SELECT COALESCE(SUM(bytes), 0) FROM files WHERE user_id = $1 AND status = 'clean')::BIGINT \
+              + (SELECT COALESCE(SUM(bytes), 0) FROM uploads WHERE user_id = $1)::BIGINT",
+    )
+    .bind(user)
+    .fetch_one(db)
+    .await?;
+    Ok(used as u64)
+}
+
+/// Refuse `bytes` more from `user` once it would take them past
+/// `files.user_quota_bytes`, counting usage through `db`
+pub async fn check_quota<'e>(state: &AppState, db: impl PgExecutor<'e>, user: UserId, bytes: u64) -> ApiResult<()> {
+    let quota = state.config().files.user_quota_bytes;
+    if quota == 0 {
+        return Ok(());
+    }
+    let used = usage(db, user).timed(&state.metrics, "files.usage").await.map_err(|e| {
+        tracing::error!(error = %e, "storage usage lookup failed");
+        ApiError::Internal
+    })?;
+    if used + bytes > quota {
+        return Err(ApiError::PayloadTooLarge(format!("storage quota of {quota} bytes exceeded ({used} in use)")));
+    }
+    Ok(())
+}
+
+/// Delete one of `user`'s servable files; false when there is no such file
+pub async fn delete(state: &AppState, user: UserId, id: FileId) -> anyhow::Result<bool> {
+    let mut tx = state.db.begin().await?;
+    let row = sqlx::query(
+        "DELETE FROM files WHERE id = $1 AND user_id = $2 AND status = 'clean' RETURNING blob_key, content_hash",
+    )
+    .bind(id)
+    .bind(user)
+    .fetch_optional(&mut *tx)
+    .timed(&state.metrics, "files.delete")
+    .await?;
+    let Some(row) = row else { return Ok(false) };
+    let (key, hash): (Option<String>, Option<String>) = (row.try_get("blob_key")?, row.try_get("content_hash")?);
+    if let Some(hash) = &hash {
+        blobs::release(&mut tx, hash).await?;
+    }
+    tx.commit().await?;
+    // Written before contents were shared, so this file's alone
+    if let (Some(key), None, Some(store)) = (key, hash, &state.blobs) {
+        if let Err(e) = store.delete(&key).await {
+            tracing::warn!(error = %e, file_id = %id, key, "deleting file blob failed");
+        }
+    }
+    Ok(true)
+}
+
+/// A servable (`clean`) file, from its row or the blob store
+pub async fn get(state: &AppState, id: FileId) -> anyhow::Result<Option<StoredFile>> {
+    let row = sqlx::query(
+        "SELECT user_id, content_type, bytes, data, blob_key FROM files WHERE id = $1 AND status = 'clean'",
+    )
+    .bind(id)
+    .fetch_optional(&state.db)
+    .timed(&state.metrics, "files.get")
+    .await?;
+    let Some(row) = row else { return Ok(None) };
+    let data = match (row.try_get::<Option<Vec<u8>>, _>("data")?, row.try_get::<Option<String>, _>("blob_key")?) {
+        (Some(data), _) => ds_store::once(data),
+        (None, Some(key)) => {
+            let blobs = state.blobs.as_ref().context("file is in a blob store but FILES_STORE is unset")?;
+            blobs.get(&key).await?.with_context(|| format!("blob {key} of file {id} is missing"))?
+        }
+        (None, None) => anyhow::bail!("file {id} has no contents"),
+    };
+    Ok(Some(StoredFile {
+        user_id: row.try_get("user_id")?,
+        content_type: row.try_get("content_type")?,
+        bytes: row.try_get::<i64, _>("bytes")? as u64,
+        data,
+    }))
+}
+
+/// Whether `id` is a servable file belonging to `user`
+pub async fn owned(db: &PgPool, user: UserId, id: FileId) -> sqlx::Result<bool> {
+    let found: Option<bool> =
+        sqlx::query_scalar("SELECT true FROM files WHERE id = $1 AND user_id = $2 AND status = 'clean'")
+        .bind(id)
+        .bind(user)
+        .fetch_optional(db)
+        .await?;
+    Ok(found.is_some())
+}
+
+/// HMAC keyed by the URL signing secret, over a message no token can take
+/// the form of
+fn mac(secret: &str, id: FileId, exp: i64) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(format!("file-download:{id}:{exp}").as_bytes());
+    mac
+}
+
+/// Hex signature for downloading `id` until `exp` (unix seconds)
+pub fn signature(secret: &str, id: FileId, exp: i64) -> String {
+    hex::encode(mac(secret, id, exp).finalize().into_bytes())
+}
+
+/// Check that `sig` was issued for `id` and `exp`, and that `exp` has not
+/// passed
+pub fn verify(secret: &str, id: FileId, exp: i64, sig: &str, now: DateTime<Utc>) -> Result<(), Denied> {
+    let sig = hex::decode(sig).map_err(|_| Denied::BadSignature)?;
+    // Constant-time comparison
+    mac(secret, id, exp).verify_slice(&sig).map_err(|_| Denied::BadSignature)?;
+    if exp <= now.timestamp() {
+        return Err(Denied::Expired);
+    }
+    Ok(())
+}
+
+/// Absolute download URL for `id` valid for `ttl_secs`, with its expiry
+pub fn signed_url(cfg: &AppConfig, id: FileId, ttl_secs: u64) -> (String, DateTime<Utc>) {
+    let expires_at = Utc::now() + chrono::Duration::seconds(ttl_secs as i64);
+    let exp = expires_at.timestamp();
+    let sig = signature(cfg.file_signing_secret(), id, exp);
+    let base = cfg.app.public_url.trim_end_matches('/');
+    (format!("{base}/v1/files/{id}/download?exp={exp}&sig={sig}"), expires_at)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_signatures_bind_file_expiry_and_secret() {
+        let (id, now) = (FileId::generate(), Utc::now());
+        let exp = now.timestamp() + 60;
+        let sig = signature("secret", id, exp);
+        assert_eq!(verify("secret", id, exp, &sig, now), Ok(()));
+        assert_eq!(verify("secret", id, exp + 1, &sig, now), Err(Denied::BadSignature));
+        assert_eq!(verify("secret", FileId::generate(), exp, &sig, now), Err(Denied::BadSignature));
+        assert_eq!(verify("other", id, exp, &sig, now), Err(Denied::BadSignature));
+        assert_eq!(verify("secret", id, exp, "not-hex", now), Err(Denied::BadSignature));
+        assert_eq!(verify("secret", id, exp, &sig, now + chrono::Duration::seconds(61)), Err(Denied::Expired));
+    }
+}
@@ -0,0 +1,291 @@
+//! Structured records of model generations.
+//!
+//! Every chat generation is stored with its request, resolved model,
+//! provider, and a hash of the model's output (not the output itself), so
+//! a generation can be looked up by id and replayed against another model
+//! to check for regressions. While streaming, each is listed in
+//! [`InFlight`] so operators can find and cancel runaway ones.
+
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
+use ds_model::{ChatChunk, ChatStream, FINISH_CANCELLED};
+use ds_types::{ApiKeyId, ExperimentId, GenerationId, OrgId, UserId};
+use futures_util::StreamExt;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use sqlx::{PgPool, Row};
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+use tokio_util::sync::CancellationToken;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Generation {
+    pub id: GenerationId,
+    pub user_id: UserId,
+    pub org_id: Option<OrgId>,
+    pub api_key_id: Option<ApiKeyId>,
+    pub provider: String,
+    pub model: String,
+    /// `{ messages, priority }` as received
+    pub request: serde_json::Value,
+    pub finish_reason: String,
+    /// Hex SHA-256 of the concatenated output content
+    pub output_hash: String,
+    pub output_tokens: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub queued_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub replay_of: Option<GenerationId>,
+    /// Experiment and variant that picked `model`, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub experiment_id: Option<ExperimentId>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub variant: Option<String>,
+    /// Provider response headers named in `OLLAMA_CAPTURE_HEADERS`, from
+    /// the last upstream call
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub upstream_headers: BTreeMap<String, String>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Hash and token count of an output, fed chunk by chunk
+#[derive(Default)]
+pub struct OutputDigest {
+    hasher: Sha256,
+    tokens: u64,
+}
+
+impl OutputDigest {
+    pub fn update(&mut self, content: &str) {
+        if !content.is_empty() {
+            self.hasher.update(content.as_bytes());
+            self.tokens += 1;
+        }
+    }
+
+    pub fn tokens(&self) -> u64 {
+        self.tokens
+    }
+
+    /// Hex hash of everything seen so far
+    pub fn hash(&self) -> String {
+        hex::encode(self.hasher.clone().finalize())
+    }
+}
+
+pub async fn record(db: &PgPool, g: &Generation) -> sqlx::Result<()> {
+    sqlx::query(
+        "INSERT INTO generations (id, user_id, org_id, api_key_id, provider, model, request, \
+         finish_reason, output_hash, output_tokens, queued_ms, replay_of, experiment_id, variant, \
+         upstream_headers, created_at) \
+         VALUES ($1, $2, $3, $4, $5, $6, $7::jsonb, $8, $9, $10, $11, $12, $13, $14, $15::jsonb, $16)",
+    )
+    .bind(g.id)
+    .bind(g.user_id)
+    .bind(g.org_id)
+    .bind(g.api_key_id)
+    .bind(&g.provider)
+    .bind(&g.model)
+    .bind(g.request.to_string())
+    .bind(&g.finish_reason)
+    .bind(&g.output_hash)
+    .bind(g.output_tokens as i64)
+    .bind(g.queued_ms.map(|ms| ms as i64))
+    .bind(g.replay_of)
+    .bind(g.experiment_id)
+    .bind(&g.variant)
+    .bind(serde_json::to_string(&g.upstream_headers).expect("string map serializes"))
+    .bind(g.created_at)
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+pub async fn get(db: &PgPool, id: GenerationId) -> sqlx::Result<Option<Generation>> {
+    let row = sqlx::query(
+        "SELECT id, user_id, org_id, api_key_id, provider, model, request::text AS request, \
+         finish_reason, output_hash, output_tokens, queued_ms, replay_of, experiment_id, variant, \
+         upstream_headers::text AS upstream_headers, created_at FROM generations WHERE id = $1",
+    )
+    .bind(id)
+    .fetch_optional(db)
+    .await?;
+    let Some(row) = row else { return Ok(None) };
+    let request: String = row.try_get("request")?;
+    let upstream_headers: String = row.try_get("upstream_headers")?;
+    Ok(Some(Generation {
+        id: row.try_get("id")?,
+        user_id: row.try_get("user_id")?,
+        org_id: row.try_get("org_id")?,
+        api_key_id: row.try_get("api_key_id")?,
+        provider: row.try_get("provider")?,
+        model: row.try_get("model")?,
+        request: serde_json::from_str(&request).map_err(|e| sqlx::Error::Decode(Box::new(e)))?,
+        finish_reason: row.try_get("finish_reason")?,
+        output_hash: row.try_get("output_hash")?,
+        output_tokens: row.try_get::<i64, _>("output_tokens")? as u64,
+        queued_ms: row.try_get::<Option<i64>, _>("queued_ms")?.map(|ms| ms as u64),
+        replay_of: row.try_get("replay_of")?,
+        experiment_id: row.try_get("experiment_id")?,
+        variant: row.try_get("variant")?,
+        upstream_headers: serde_json::from_str(&upstream_headers).map_err(|e| sqlx::Error::Decode(Box::new(e)))?,
+        created_at: row.try_get("created_at")?,
+    }))
+}
+
+/// The `X-Upstream-*` name a captured header reaches clients under:
+/// `x-model-version` becomes `x-upstream-model-version`
+pub fn pass_through_name(captured: &str) -> String {
+    let name = captured.to_ascii_lowercase();
+    format!("x-upstream-{}", name.strip_prefix("x-").unwrap_or(&name))
+}
+
+/// Generations streaming on this instance
+#[derive(Default)]
+pub struct InFlight(DashMap<GenerationId, Arc<Running>>);
+
+struct Running {
+    user_id: UserId,
+    model: String,
+    started_at: DateTime<Utc>,
+    tokens: AtomicU64,
+    cancel: CancellationToken,
+}
+
+/// An in-flight generation, as listed for operators
+#[derive(Debug, Serialize)]
+pub struct RunningInfo {
+    pub id: GenerationId,
+    pub user_id: UserId,
+    pub model: String,
+    pub started_at: DateTime<Utc>,
+    /// Non-empty chunks streamed so far
+    pub tokens: u64,
+}
+
+impl InFlight {
+    /// List `id` until the returned guard drops
+    pub fn register(self: &Arc<Self>, id: GenerationId, user_id: UserId, model: &str) -> Tracked {
+        let running = Arc::new(Running {
+            user_id,
+            model: model.to_string(),
+            started_at: Utc::now(),
+            tokens: AtomicU64::new(0),
+            cancel: CancellationToken::new(),
+        });
+        self.0.insert(id, running.clone());
+        Tracked { in_flight: self.clone(), id, running }
+    }
+
+    /// Oldest first
+    pub fn list(&self) -> Vec<RunningInfo> {
+        let mut out: Vec<RunningInfo> = self
+            .0
+            .iter()
+            .map(|r| RunningInfo {
+                id: *r.key(),
+                user_id: r.user_id,
+                model: r.model.clone(),
+                started_at: r.started_at,
+                tokens: r.tokens.load(Ordering::Relaxed),
+            })
+            .collect();
+        out.sort_by_key(|r| r.started_at);
+        out
+    }
+
+    /// Stop `id`'s stream, returning its owner; `None` if it is not
+    /// streaming here
+    pub fn cancel(&self, id: GenerationId) -> Option<UserId> {
+        let running = self.0.get(&id)?;
+        running.cancel.cancel();
+        Some(running.user_id)
+    }
+}
+
+/// Keeps a generation listed in [`InFlight`]
+pub struct Tracked {
+    in_flight: Arc<InFlight>,
+    id: GenerationId,
+    running: Arc<Running>,
+}
+
+impl Tracked {
+    /// Count a chunk of output
+    pub fn update(&self, content: &str) {
+        self.running.tokens.fetch_add(u64::from(!content.is_empty()), Ordering::Relaxed);
+    }
+
+    /// End `stream` with a `cancelled` done frame once the generation is
+    /// cancelled, dropping the upstream request
+    pub fn cancellable(&self, stream: ChatStream, model: &str) -> ChatStream {
+        let (cancel, model) = (self.running.cancel.clone(), Arc::<str>::from(model));
+        Box::pin(async_stream::stream! {
+            let mut stream = stream;
+            loop {
+                tokio::select! {
+                    biased;
+                    _ = cancel.cancelled() => break,
+                    item = stream.next() => match item {
+                        Some(item) => yield item,
+                        None => return,
+                    },
+                }
+            }
+            drop(stream);
+            let reason = Some(FINISH_CANCELLED.to_string());
+            yield Ok(ChatChunk { model, done: true, finish_reason: reason, ..Default::default() });
+        })
+    }
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        self.in_flight.0.remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_digest_ignores_chunking() {
+        let mut whole = OutputDigest::default();
+        whole.update("hello world");
+        let mut split = OutputDigest::default();
+        for part in ["hello", "", " world"] {
+            split.update(part);
+        }
+        assert_eq!(whole.hash(), split.hash());
+        assert_eq!(split.tokens(), 2);
+        assert_eq!(
+            OutputDigest::default().hash(),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_generation_ends_with_a_cancelled_frame() {
+        let in_flight = Arc::new(InFlight::default());
+        let id = GenerationId::generate();
+        let tracked = in_flight.register(id, UserId::generate(), "m");
+        // Never ends on its own
+        let upstream: ChatStream = Box::pin(futures_util::stream::pending());
+        let mut stream = tracked.cancellable(upstream, "m");
+        tracked.update("hello");
+        assert_eq!(in_flight.list()[0].tokens, 1);
+
+        assert!(in_flight.cancel(id).is_some());
+        let last = stream.next().await.unwrap().unwrap();
+        assert_eq!((last.done, last.finish_reason.as_deref()), (true, Some(FINISH_CANCELLED)));
+        assert!(stream.next().await.is_none());
+        drop(tracked);
+        assert!(in_flight.list().is_empty() && in_flight.cancel(id).is_none());
+    }
+}
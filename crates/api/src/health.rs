@@ -0,0 +1,157 @@
+//! Dependency health: the database and model provider checks, and their
+//! last known state.
+//!
+//! Every check, from `/health` or the `health` job, is recorded. A
+//! dependency whose result differs from its last one (dependencies start
+//! out assumed healthy) has transitioned: the change is logged as a
+//! `health.transition` event, counted, and POSTed to `HEALTH_WEBHOOK_URL`.
+//! State is per instance, so each instance reports what it sees.
+//!
+//! The Ollama check also detects the upstream's version, as does startup,
+//! so features it lacks are refused up front (see
+//! [`Capabilities`](ds_model::Capabilities)) and an upgrade is noticed.
+
+use crate::state::AppState;
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
+use ds_model::Capabilities;
+use serde::Serialize;
+use std::time::{Duration, Instant};
+
+/// Health transitions by dependency and new state (`healthy`, `unhealthy`)
+pub const TRANSITIONS: &str = "deepersensor_health_transitions_total";
+
+pub const DATABASE: &str = "database";
+pub const OLLAMA: &str = "ollama";
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ServiceStatus {
+    pub healthy: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latency_ms: Option<u64>,
+    /// The upstream's version, once detected
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub capabilities: Option<Capabilities>,
+}
+
+impl ServiceStatus {
+    fn up(start: Instant) -> Self {
+        let latency_ms = Some(start.elapsed().as_millis() as u64);
+        Self { healthy: true, error: None, latency_ms, version: None, capabilities: None }
+    }
+
+    fn down(error: String) -> Self {
+        Self { healthy: false, error: Some(error), latency_ms: None, version: None, capabilities: None }
+    }
+}
+
+/// Last known state of each dependency
+#[derive(Default)]
+pub struct Tracker {
+    last: DashMap<&'static str, bool>,
+}
+
+impl Tracker {
+    /// Record a check; true when it differs from the last one
+    pub fn observe(&self, dependency: &'static str, healthy: bool) -> bool {
+        self.last.insert(dependency, healthy).unwrap_or(true) != healthy
+    }
+}
+
+/// JSON POSTed to `HEALTH_WEBHOOK_URL`
+#[derive(Debug, Serialize)]
+struct TransitionEvent<'a> {
+    event: &'static str,
+    dependency: &'static str,
+    healthy: bool,
+    error: Option<&'a str>,
+    at: DateTime<Utc>,
+}
+
+pub async fn check_database(state: &AppState) -> ServiceStatus {
+    let start = Instant::now();
+    let status = match sqlx::query("SELECT 1 as health_check").fetch_one(&state.db).await {
+        Ok(_) => ServiceStatus::up(start),
+        Err(e) => {
+            tracing::error!(error = %e, "database health check failed");
+            ServiceStatus::down(e.to_string())
+        }
+    };
+    record(state, DATABASE, &status).await;
+    status
+}
+
+pub async fn check_ollama(state: &AppState) -> ServiceStatus {
+    let start = Instant::now();
+    let (models, _) = tokio::join!(state.provider.list_models(), detect_version(state));
+    let mut status = match models {
+        Ok(_) => ServiceStatus::up(start),
+        Err(e) => {
+            tracing::warn!(error = %e, "ollama health check failed");
+            ServiceStatus::down(e.to_string())
+        }
+    };
+    if let Some(detected) = state.provider.detected() {
+        status.version = Some(detected.version);
+        status.capabilities = Some(detected.capabilities);
+    }
+    record(state, OLLAMA, &status).await;
+    status
+}
+
+/// Ask the upstream its version; a failure keeps the last one detected
+pub async fn detect_version(state: &AppState) {
+    if let Err(e) = state.provider.detect().await {
+        tracing::warn!(error = %e, "ollama version detection failed");
+    }
+}
+
+/// Both checks, concurrently
+pub async fn check_all(state: &AppState) -> (ServiceStatus, ServiceStatus) {
+    tokio::join!(check_database(state), check_ollama(state))
+}
+
+async fn record(state: &AppState, dependency: &'static str, status: &ServiceStatus) {
+    if !state.health.observe(dependency, status.healthy) {
+        return;
+    }
+    let to = if status.healthy { "healthy" } else { "unhealthy" };
+    state.metrics.incr(TRANSITIONS, &[("dependency", dependency), ("to", to)]);
+    let error = status.error.as_deref();
+    tracing::warn!(event = "health.transition", dependency, to, error, "dependency health changed");
+    let url = &state.config().health.webhook_url;
+    if url.is_empty() {
+        return;
+    }
+    let event = TransitionEvent { event: "health.transition", dependency, healthy: status.healthy, error, at: Utc::now() };
+    let sent = state
+        .http
+        .post(url)
+        .timeout(Duration::from_secs(5))
+        .json(&event)
+        .send()
+        .await
+        .and_then(|r| r.error_for_status());
+    if let Err(e) = sent {
+        tracing::warn!(error = %e, dependency, "health webhook failed");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_only_changes_are_transitions() {
+        let tracker = Tracker::default();
+        assert!(!tracker.observe(DATABASE, true), "assumed healthy at first");
+        assert!(tracker.observe(OLLAMA, false));
+        assert!(!tracker.observe(OLLAMA, false));
+        assert!(!tracker.observe(DATABASE, true));
+        assert!(tracker.observe(OLLAMA, true));
+    }
+}
@@ -0,0 +1,211 @@
+//! Hedged provider calls.
+//!
+//! An upstream that usually answers `/api/tags` in 20ms now and then takes
+//! seconds, and those stragglers set the p99 of every route listing models
+//! or embedding a query. [`HedgedProvider`] sends a second, identical
+//! attempt once the first has run `HEDGE_DELAY_MS` and returns whichever
+//! answers first, dropping (and so cancelling) the other. Only the
+//! operations in `HEDGE_OPERATIONS` are hedged; they must be idempotent and
+//! cheap, which chats are not, so chat streams always go straight through.
+//!
+//! An attempt failing before the delay is returned as is: errors are not
+//! slowness. Once both are running, a failure waits for the other attempt.
+//! Each hedge sent counts in `deepersensor_hedged_requests_total` by
+//! operation and by the attempt whose answer was used.
+
+use crate::metrics::Metrics;
+use async_trait::async_trait;
+use ds_core::config::HedgeSection;
+use ds_model::{ChatRequest, ChatStream, Detected, ModelProvider, ModelResult};
+use std::{future::Future, sync::Arc, time::Duration};
+
+pub const HEDGED: &str = "deepersensor_hedged_requests_total";
+
+/// Operations that may be hedged
+const OPERATIONS: &[&str] = &["list_models", "embed"];
+
+pub struct HedgedProvider {
+    inner: Arc<dyn ModelProvider>,
+    delay: Duration,
+    operations: Vec<&'static str>,
+    metrics: Arc<Metrics>,
+}
+
+impl HedgedProvider {
+    /// `inner` itself when the config hedges nothing
+    pub fn wrap(inner: Arc<dyn ModelProvider>, cfg: &HedgeSection, metrics: Arc<Metrics>) -> Arc<dyn ModelProvider> {
+        let mut operations = Vec::new();
+        for name in cfg.operations.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+            match OPERATIONS.iter().find(|op| **op == name) {
+                Some(op) => operations.push(*op),
+                None => tracing::warn!(operation = name, "ignoring unknown HEDGE_OPERATIONS entry"),
+            }
+        }
+        if cfg.delay_ms == 0 || operations.is_empty() {
+            return inner;
+        }
+        Arc::new(Self { inner, delay: Duration::from_millis(cfg.delay_ms), operations, metrics })
+    }
+
+    /// Run `call`, and run it again if the first attempt outlasts the delay
+    async fn hedged<T, F, Fut>(&self, operation: &'static str, call: F) -> ModelResult<T>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = ModelResult<T>>,
+    {
+        if !self.operations.contains(&operation) {
+            return call().await;
+        }
+        let primary = call();
+        tokio::pin!(primary);
+        tokio::select! {
+            result = &mut primary => return result,
+            _ = tokio::time::sleep(self.delay) => {}
+        }
+        let hedge = call();
+        tokio::pin!(hedge);
+        let (result, winner) = tokio::select! {
+            result = &mut primary => match result {
+                Ok(answer) => (Ok(answer), "primary"),
+                Err(e) => {
+                    tracing::debug!(error = %e, operation, "primary attempt failed; waiting for the hedge");
+                    (hedge.await, "hedge")
+                }
+            },
+            result = &mut hedge => match result {
+                Ok(answer) => (Ok(answer), "hedge"),
+                Err(e) => {
+                    tracing::debug!(error = %e, operation, "hedged attempt failed; waiting for the primary");
+                    (primary.await, "primary")
+                }
+            },
+        };
+        self.metrics.incr(HEDGED, &[("operation", operation), ("winner", winner)]);
+        result
+    }
+}
+
+#[async_trait]
+impl ModelProvider for HedgedProvider {
+    fn name(&self) -> &'static str {
+        self.inner.name()
+    }
+
+    async fn detect(&self) -> ModelResult<Option<Detected>> {
+        self.inner.detect().await
+    }
+
+    fn detected(&self) -> Option<Detected> {
+        self.inner.detected()
+    }
+
+    fn route(&self, model: &str) -> Option<(Arc<dyn ModelProvider>, String)> {
+        self.inner.route(model)
+    }
+
+    fn routes(&self) -> Vec<&'static str> {
+        self.inner.routes()
+    }
+
+    async fn list_models(&self) -> ModelResult<Vec<String>> {
+        self.hedged("list_models", || self.inner.list_models()).await
+    }
+
+    async fn chat_stream(&self, req: ChatRequest) -> ModelResult<ChatStream> {
+        self.inner.chat_stream(req).await
+    }
+
+    async fn embed(&self, model: &str, input: &str) -> ModelResult<Vec<f32>> {
+        self.hedged("embed", || self.inner.embed(model, input)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ds_model::ModelError;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Answers its `n`th call after `delays[n]`, failing where `fail` says
+    struct Staggered {
+        calls: AtomicUsize,
+        delays: Vec<u64>,
+        fail: Vec<bool>,
+    }
+
+    #[async_trait]
+    impl ModelProvider for Staggered {
+        fn name(&self) -> &'static str {
+            "staggered"
+        }
+
+        async fn list_models(&self) -> ModelResult<Vec<String>> {
+            let n = self.calls.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(self.delays[n])).await;
+            match self.fail[n] {
+                true => Err(ModelError::Unavailable(format!("attempt {n}"))),
+                false => Ok(vec![format!("attempt {n}")]),
+            }
+        }
+
+        async fn chat_stream(&self, _req: ChatRequest) -> ModelResult<ChatStream> {
+            unimplemented!()
+        }
+    }
+
+    fn hedged(delays: &[u64], fail: &[bool], metrics: &Arc<Metrics>) -> (Arc<dyn ModelProvider>, Arc<Staggered>) {
+        let inner = Arc::new(Staggered { calls: AtomicUsize::new(0), delays: delays.to_vec(), fail: fail.to_vec() });
+        let cfg = HedgeSection { delay_ms: 50, operations: "list_models".into() };
+        (HedgedProvider::wrap(inner.clone(), &cfg, metrics.clone()), inner)
+    }
+
+    fn wins(metrics: &Metrics) -> Vec<(String, u64)> {
+        metrics.sum_by(HEDGED, "winner").into_iter().collect()
+    }
+
+    #[tokio::test]
+    async fn test_fast_answers_are_not_hedged() {
+        let metrics = Arc::new(Metrics::default());
+        let (provider, inner) = hedged(&[0], &[false], &metrics);
+        assert_eq!(provider.list_models().await.unwrap(), ["attempt 0"]);
+        assert_eq!(inner.calls.load(Ordering::SeqCst), 1);
+        assert!(wins(&metrics).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_slow_attempt_loses_to_the_hedge() {
+        let metrics = Arc::new(Metrics::default());
+        let (provider, inner) = hedged(&[5000, 0], &[false, false], &metrics);
+        let started = std::time::Instant::now();
+        assert_eq!(provider.list_models().await.unwrap(), ["attempt 1"]);
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert_eq!(inner.calls.load(Ordering::SeqCst), 2);
+        assert_eq!(wins(&metrics), [("hedge".to_string(), 1)]);
+    }
+
+    #[tokio::test]
+    async fn test_failed_hedge_waits_for_the_primary() {
+        let metrics = Arc::new(Metrics::default());
+        let (provider, _) = hedged(&[150, 0], &[false, true], &metrics);
+        assert_eq!(provider.list_models().await.unwrap(), ["attempt 0"]);
+        assert_eq!(wins(&metrics), [("primary".to_string(), 1)]);
+    }
+
+    #[tokio::test]
+    async fn test_early_failure_is_returned_unhedged() {
+        let metrics = Arc::new(Metrics::default());
+        let (provider, inner) = hedged(&[0], &[true], &metrics);
+        assert!(matches!(provider.list_models().await, Err(ModelError::Unavailable(_))));
+        assert_eq!(inner.calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_nothing_to_hedge_leaves_the_provider_bare() {
+        let metrics = Arc::new(Metrics::default());
+        let inner: Arc<dyn ModelProvider> = hedged(&[], &[], &metrics).1;
+        let off = HedgeSection { delay_ms: 0, operations: "list_models".into() };
+        assert!(Arc::ptr_eq(&HedgedProvider::wrap(inner.clone(), &off, metrics.clone()), &inner));
+        let unknown = HedgeSection { delay_ms: 50, operations: "chat".into() };
+        assert!(Arc::ptr_eq(&HedgedProvider::wrap(inner.clone(), &unknown, metrics), &inner));
+    }
+}
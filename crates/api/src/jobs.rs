@@ -0,0 +1,135 @@
+//! Background jobs, run on a fixed period by every instance.
+//!
+//! A job that fails is logged and counted, then runs again at its next
+//! tick. Jobs whose work must happen once across instances (such as a due
+//! schedule) claim it in the database, so any number of instances may run
+//! them.
+
+use crate::{analytics, blobs, documents, events, health, rate_limit, reembed, schedules, state::AppState, uploads};
+use std::{future::Future, sync::Arc, time::Duration};
+use tokio::time::MissedTickBehavior;
+
+/// Job ticks by job and result (`ok`, `error`)
+pub const RUNS: &str = "deepersensor_jobs_total";
+
+/// How often refilled rate limiter buckets and expired in-memory signing
+/// nonces are dropped
+const SWEEP_PERIOD: Duration = Duration::from_secs(60);
+
+/// Start every enabled job; call once the migrations have run
+pub fn start(state: &AppState) {
+    let cfg = state.config();
+    every(state.clone(), "rate_limit_sweep", SWEEP_PERIOD, |state| async move {
+        rate_limit::sweep(&state);
+        Ok(())
+    });
+    if cfg.signing.nonce_store == "memory" {
+        every(state.clone(), "nonce_sweep", SWEEP_PERIOD, |state| async move {
+            state.nonces.sweep();
+            Ok(())
+        });
+    }
+    if cfg.schedules.poll_secs > 0 {
+        every(state.clone(), "schedules", Duration::from_secs(cfg.schedules.poll_secs), |state| async move {
+            schedules::run_due(&state).await?;
+            Ok(())
+        });
+    }
+    if cfg.analytics.snapshot_secs > 0 {
+        let exported = Arc::new(analytics::Exported::default());
+        every(state.clone(), "analytics", Duration::from_secs(cfg.analytics.snapshot_secs), move |state| {
+            let exported = exported.clone();
+            async move {
+                analytics::snapshot(&state, &exported).await?;
+                analytics::compact(&state.db, &state.config().analytics).await?;
+                Ok(())
+            }
+        });
+    }
+    if cfg.health.check_secs > 0 {
+        every(state.clone(), "health", Duration::from_secs(cfg.health.check_secs), |state| async move {
+            health::check_all(&state).await;
+            Ok(())
+        });
+    }
+    if let (Some(store), true) = (state.blobs.clone(), cfg.files.gc_secs > 0) {
+        every(state.clone(), "blob_gc", Duration::from_secs(cfg.files.gc_secs), move |state| {
+            let store = store.clone();
+            async move {
+                blobs::collect(&state, store.as_ref()).await?;
+                Ok(())
+            }
+        });
+    }
+    if let (Some(store), true) = (state.blobs.clone(), cfg.files.gc_secs > 0) {
+        every(state.clone(), "upload_expiry", Duration::from_secs(cfg.files.gc_secs), move |state| {
+            let store = store.clone();
+            async move {
+                uploads::expire(&state, store.as_ref()).await?;
+                Ok(())
+            }
+        });
+    }
+    if cfg.documents.process_secs > 0 {
+        every(state.clone(), "documents", Duration::from_secs(cfg.documents.process_secs), |state| async move {
+            documents::process_pending(&state).await?;
+            Ok(())
+        });
+    }
+    if cfg.documents.reembed_secs > 0 {
+        every(state.clone(), "reembed", Duration::from_secs(cfg.documents.reembed_secs), |state| async move {
+            reembed::run_pending(&state).await?;
+            Ok(())
+        });
+    }
+    if cfg.events.relay_secs > 0 {
+        let publisher = events::from_config(&cfg.events);
+        every(state.clone(), "events", Duration::from_secs(cfg.events.relay_secs), move |state| {
+            let publisher = publisher.clone();
+            async move {
+                let cfg = &state.config().events;
+                if let Some(publisher) = publisher {
+                    // Drain the backlog rather than one batch per tick
+                    while events::relay(&state.db, publisher.as_ref(), cfg.batch.into(), &state.metrics).await?
+                        == cfg.batch as usize
+                    {}
+                }
+                Ok(())
+            }
+        });
+        every(state.clone(), "events_prune", Duration::from_secs(3600), |state| async move {
+            events::prune(&state.db, &state.config().events).await?;
+            Ok(())
+        });
+    }
+    if state.db_router.has_replicas() && cfg.database.replica_check_secs > 0 {
+        every(state.clone(), "replicas", Duration::from_secs(cfg.database.replica_check_secs), |state| async move {
+            state.db_router.refresh().await;
+            Ok(())
+        });
+    }
+}
+
+/// Run `job` every `period`; a tick that overruns delays the next one
+/// rather than bunching them up
+pub fn every<F, Fut>(state: AppState, name: &'static str, period: Duration, job: F)
+where
+    F: Fn(AppState) -> Fut + Send + 'static,
+    Fut: Future<Output = anyhow::Result<()>> + Send,
+{
+    tokio::spawn(async move {
+        let mut tick = tokio::time::interval(period);
+        tick.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            tick.tick().await;
+            let result = match job(state.clone()).await {
+                Ok(()) => "ok",
+                Err(e) => {
+                    tracing::warn!(error = %e, job = name, "background job failed");
+                    "error"
+                }
+            };
+            state.metrics.incr(RUNS, &[("job", name), ("result", result)]);
+        }
+    });
+}
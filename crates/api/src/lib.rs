@@ -0,0 +1,66 @@
+pub mod admission;
+pub mod agents;
+pub mod allow;
+pub mod analytics;
+pub mod api_keys;
+pub mod app;
+pub mod auth_middleware;
+pub mod backpressure;
+pub mod batch;
+pub mod blobs;
+pub mod body_limit;
+pub mod build_info;
+pub mod chunking;
+pub mod collections;
+pub mod context;
+pub mod conversations;
+pub mod db_metrics;
+pub mod db_router;
+pub mod documents;
+pub mod cors;
+pub mod egress;
+pub mod encode;
+pub mod etag;
+pub mod enrich;
+pub mod evals;
+pub mod events;
+pub mod experiments;
+pub mod extract;
+pub mod files;
+pub mod generations;
+pub mod guard;
+pub mod health;
+pub mod hedge;
+pub mod jobs;
+pub mod localize;
+pub mod metrics;
+pub mod migrations;
+pub mod notifications;
+pub mod observability;
+pub mod orgs;
+pub mod policy;
+pub mod quota;
+pub mod rate_limit;
+pub mod redact;
+pub mod reembed;
+pub mod retrieval;
+pub mod request_id;
+pub mod routes;
+pub mod scan;
+pub mod schedules;
+pub mod security;
+pub mod self_test;
+pub mod semantic_cache;
+pub mod server;
+pub mod sessions;
+pub mod shutdown;
+pub mod signing;
+pub mod slow;
+pub mod state;
+pub mod summarize;
+pub mod tenants;
+pub mod tool_egress;
+pub mod tools;
+pub mod uploads;
+pub mod validation;
+pub mod versioning;
@@ -0,0 +1,30 @@
+//! Re-render error bodies in the caller's preferred language
+
+use crate::context::RequestContext;
+use axum::{
+    extract::Request,
+    http::header,
+    middleware::Next,
+    response::Response,
+};
+use ds_core::{error::ApiError, i18n::Locale};
+
+/// Localize any `ApiError` response into the request context's locale.
+///
+/// Handlers and extractors always render English; only the `message`
+/// changes here, so `code` and `fields` stay identical across locales.
+pub async fn localize_errors(req: Request, next: Next) -> Response {
+    let locale = req.extensions().get::<RequestContext>().map(|ctx| ctx.locale).unwrap_or_default();
+    let response = next.run(req).await;
+    if locale == Locale::En {
+        return response;
+    }
+    let Some(err) = response.extensions().get::<ApiError>().cloned() else {
+        return response;
+    };
+    let (mut parts, _) = response.into_parts();
+    let (localized, body) = err.to_response(locale).into_parts();
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts.headers.extend(localized.headers);
+    Response::from_parts(parts, body)
+}
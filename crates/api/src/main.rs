@@ -1,17 +1,6 @@
-mod app;
-mod auth_middleware;
-mod cors;
-mod observability;
-mod rate_limit;
-mod request_id;
-mod routes;
-mod security;
-mod shutdown;
-mod state;
-mod validation;
-use crate::app::{build_app, server_addr};
-use crate::observability::init_tracing;
-use crate::shutdown::shutdown_signal;
+use api::app::{build_app, server_addr};
+use api::observability::init_tracing;
+use api::shutdown::shutdown_signal;
 use ds_core::config::AppConfig;
 use std::sync::Arc;
 use tokio::net::TcpListener;
@@ -20,45 +9,50 @@ use tracing::{info, warn};
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let cfg = Arc::new(AppConfig::load()?);
+    if std::env::args().skip(1).any(|arg| arg == "--self-test") {
+        let app = build_app(cfg).await;
+        let report = api::self_test::run(&app.state).await;
+        print!("{report}");
+        std::process::exit(if report.passed() { 0 } else { 1 });
+    }
     enforce_prod_secrets(&cfg)?;
-    init_tracing(&cfg);
+    // Refuse to start with a policy that would deny every request
+    let policy = api::policy::Policy::from_config(&cfg)?;
+    // Held so buffered log lines reach the file before exit
+    let _log_guard = init_tracing(&cfg)?;
+    if !policy.is_empty() {
+        info!(rules = policy.len(), file = %cfg.security.policy_file, "route policy loaded");
+    }
 
     let addr = server_addr(&cfg);
     let app_state_and_router = build_app(cfg.clone()).await;
-    // Run database migrations at startup (fail-fast if they cannot run)
-    // If migrations are not available in this workspace, skip running them during local builds.
-    let migrations_path = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("../../migrations");
-    if migrations_path.exists() {
-        if let Err(e) = sqlx::migrate!("../../migrations")
-            .run(&app_state_and_router.state.db)
-            .await
-        {
-            anyhow::bail!("failed running migrations: {e}");
-        }
-    } else {
-        tracing::warn!("migrations directory not found, skipping migrations");
-    }
+    // Apply or verify migrations per MIGRATIONS_MODE before serving
+    api::migrations::prepare(&cfg, &app_state_and_router.state.db).await?;
+    api::jobs::start(&app_state_and_router.state);
+    // Features the upstream lacks are refused from the first request on
+    api::health::detect_version(&app_state_and_router.state).await;
+    #[cfg(unix)]
+    tokio::spawn(api::observability::cycle_on_sigusr1());
     info!(%addr, env = %cfg.app.env, "starting server");
 
-    let router_with_state = app_state_and_router
-        .router
-        .with_state(app_state_and_router.state.clone());
-    let make_svc = router_with_state.into_make_service_with_connect_info::<std::net::SocketAddr>();
+    let state = app_state_and_router.state.clone();
+    let router = app_state_and_router.into_router();
     let listener = TcpListener::bind(addr).await?;
-    axum::serve(listener, make_svc)
-        .with_graceful_shutdown(shutdown_signal())
-        .await?;
+    let limits = api::server::Limits::from_config(&cfg.http);
+    api::server::serve(listener, router, limits, state.metrics.clone(), shutdown_signal()).await;
+    // Usage from the last requests is still queued
+    state.usage.drain().await;
     Ok(())
 }
 
 fn enforce_prod_secrets(cfg: &AppConfig) -> anyhow::Result<()> {
     if cfg.is_production() {
         let secret = &cfg.security.jwt_secret;
-        if secret == "dev_insecure_change_me" || secret.len() < 32 {
+        if secret == api::self_test::DEFAULT_JWT_SECRET || secret.len() < 32 {
             anyhow::bail!("insecure JWT_SECRET for production; must be overridden and >=32 chars");
         }
     } else {
-        if cfg.security.jwt_secret == "dev_insecure_change_me" {
+        if cfg.security.jwt_secret == api::self_test::DEFAULT_JWT_SECRET {
             warn!("running with default insecure JWT secret - DO NOT USE IN PRODUCTION");
         }
     }
@@ -0,0 +1,555 @@
+use axum::{extract::{Request, State}, middleware::Next, response::Response};
+use dashmap::DashMap;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// HELP text for dynamically registered counters
+const HELP: &[(&str, &str)] = &[
+    (
+        "deepersensor_chat_streams_total",
+        "Chat streams by terminal finish reason",
+    ),
+    (
+        "deepersensor_chat_stream_errors_total",
+        "Upstream errors raised mid-stream",
+    ),
+    (
+        "deepersensor_stream_backpressure_total",
+        "Times a response stream channel was full",
+    ),
+    (
+        "deepersensor_stream_dropped_chunks_total",
+        "Chunks discarded under the drop backpressure policy",
+    ),
+    (
+        "deepersensor_chat_tokens_total",
+        "Content chunks (roughly one token each) streamed per model",
+    ),
+    (
+        "deepersensor_chat_queue_wait_ms_total",
+        "Milliseconds chats waited in the admission queue, by priority class",
+    ),
+    (
+        "deepersensor_chat_generations_total",
+        "Chat generations started, by priority class",
+    ),
+    (
+        "deepersensor_semantic_cache_lookups_total",
+        "Semantic cache lookups by result (hit, miss, error)",
+    ),
+    (
+        "deepersensor_db_reads_total",
+        "Reads routed by the replica router, by target (replica, primary)",
+    ),
+    (
+        "deepersensor_transcriptions_total",
+        "Speech-to-text requests by result (ok, error)",
+    ),
+    (
+        "deepersensor_image_generations_total",
+        "Image generation requests by result (ok, error)",
+    ),
+    (
+        "deepersensor_file_downloads_total",
+        "Signed URL file downloads by result (ok, bad_signature, expired, not_found)",
+    ),
+    (
+        "deepersensor_file_scans_total",
+        "Malware scans of stored files by result (clean, infected, error)",
+    ),
+    (
+        "deepersensor_tool_egress_total",
+        "Server-side tool HTTP calls by tool and result (allowed, denied, error)",
+    ),
+    (
+        "deepersensor_tool_calls_total",
+        "Server-side tool calls by tool and result (ok, error, timeout, unknown)",
+    ),
+    (
+        "deepersensor_agent_runs_total",
+        "Agent runs by finish reason (stop, length, max_steps, budget, error)",
+    ),
+    (
+        "deepersensor_schedule_runs_total",
+        "Scheduled prompt runs by result (delivered, failed)",
+    ),
+    (
+        "deepersensor_jobs_total",
+        "Background job ticks by job and result (ok, error)",
+    ),
+    (
+        "deepersensor_notifications_total",
+        "Notification deliveries by kind, channel, and result (delivered, failed, skipped)",
+    ),
+    (
+        "deepersensor_policy_denials_total",
+        "Requests refused by a route policy rule, by rule",
+    ),
+    (
+        "deepersensor_signature_rejections_total",
+        "Requests with a signed API key refused, by reason",
+    ),
+    (
+        "deepersensor_slow_total",
+        "Requests logged as slow, by kind (request, query)",
+    ),
+    (
+        "deepersensor_enrichment_total",
+        "Post-generation processor runs by processor and result (attached, empty, error, timeout)",
+    ),
+    (
+        "deepersensor_http_responses_total",
+        "HTTP responses by status class",
+    ),
+    (
+        "deepersensor_etag_responses_total",
+        "GETs answered by the etag layer, by result (not_modified, modified)",
+    ),
+    (
+        "deepersensor_api_requests_total",
+        "Requests by API version (v1, v2, none for unversioned routes)",
+    ),
+    (
+        "deepersensor_deprecated_requests_total",
+        "Requests to routes listed in API_DEPRECATED_ROUTES, by pattern",
+    ),
+    (
+        "deepersensor_health_transitions_total",
+        "Dependency health changes, by dependency and new state (healthy, unhealthy)",
+    ),
+    (
+        "deepersensor_db_query_duration_seconds",
+        "Database calls by query name, pool acquisition included",
+    ),
+    (
+        "deepersensor_db_query_errors_total",
+        "Failed database calls by query name and error kind",
+    ),
+    (
+        "deepersensor_db_acquire_duration_seconds",
+        "Waits for a pooled database connection held across statements",
+    ),
+    (
+        "deepersensor_blob_writes_total",
+        "Files stored in the blob store, by result (stored, deduplicated)",
+    ),
+    (
+        "deepersensor_blobs_collected_total",
+        "Unreferenced blobs deleted from the blob store",
+    ),
+    (
+        "deepersensor_resumable_uploads_total",
+        "Resumable uploads by how they ended (completed, aborted, expired)",
+    ),
+    (
+        "deepersensor_documents_processed_total",
+        "Documents processed from files, by result (ready, failed, retry)",
+    ),
+    (
+        "deepersensor_document_reranks_total",
+        "Document search rerank passes, by result (ok, error); errors keep the fused order",
+    ),
+    (
+        "deepersensor_document_stale_chunks_total",
+        "Chunks left out of vector searches for being embedded with another model or version",
+    ),
+    (
+        "deepersensor_document_chunks_reembedded_total",
+        "Stale chunks re-embedded by the reembed job",
+    ),
+    (
+        "deepersensor_events_published_total",
+        "Outbox events handed to the publisher, by type and result (ok, error)",
+    ),
+    (
+        "deepersensor_batch_rows_total",
+        "Rows written by batched writers, by batch and result (ok, error)",
+    ),
+    (
+        "deepersensor_batch_flushes_total",
+        "Statements written by batched writers, by batch and result (ok, error)",
+    ),
+    (
+        "deepersensor_hedged_requests_total",
+        "Provider calls hedged with a second attempt, by operation and the attempt answering (primary, hedge)",
+    ),
+    (
+        "deepersensor_connections_dropped_total",
+        "Client connections closed by the server, by reason (per_ip_limit, header_timeout)",
+    ),
+    (
+        "deepersensor_streams_open",
+        "Response streams with a live upstream task",
+    ),
+    (
+        "deepersensor_stream_buffered_chunks",
+        "Chunks waiting in response stream channels",
+    ),
+];
+
+/// Upper bounds in seconds of every histogram's buckets
+const BUCKETS: [f64; 12] = [0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+
+/// Observations per bucket (not cumulative; `+Inf` is `count`)
+#[derive(Default)]
+struct Histogram {
+    buckets: [AtomicU64; BUCKETS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        if let Some(i) = BUCKETS.iter().position(|le| secs <= *le) {
+            self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+}
+
+/// A histogram's rendered labels, bucket counts, count and sum in
+/// microseconds, read for rendering
+type HistogramSeries = (String, Vec<u64>, u64, u64);
+
+/// In-process counter registry rendered in Prometheus text format.
+///
+/// Series are keyed by metric name plus a rendered label set, so new counters
+/// need no registration beyond an entry in `HELP`.
+#[derive(Default)]
+pub struct Metrics {
+    counters: DashMap<(&'static str, String), AtomicU64>,
+    gauges: DashMap<&'static str, AtomicI64>,
+    histograms: DashMap<(&'static str, String), Histogram>,
+    /// Trailing-hour request counts for the admin stats endpoint
+    pub requests: RequestWindow,
+}
+
+impl Metrics {
+    pub fn incr(&self, name: &'static str, labels: &[(&str, &str)]) {
+        self.add(name, labels, 1);
+    }
+
+    pub fn add(&self, name: &'static str, labels: &[(&str, &str)], value: u64) {
+        let key = (name, render_labels(labels));
+        if let Some(counter) = self.counters.get(&key) {
+            counter.fetch_add(value, Ordering::Relaxed);
+            return;
+        }
+        self.counters
+            .entry(key)
+            .or_default()
+            .fetch_add(value, Ordering::Relaxed);
+    }
+
+    pub fn gauge_add(&self, name: &'static str, delta: i64) {
+        if let Some(gauge) = self.gauges.get(name) {
+            gauge.fetch_add(delta, Ordering::Relaxed);
+            return;
+        }
+        self.gauges.entry(name).or_default().fetch_add(delta, Ordering::Relaxed);
+    }
+
+    /// Record a duration in the histogram `name`
+    pub fn observe(&self, name: &'static str, labels: &[(&str, &str)], elapsed: Duration) {
+        let key = (name, render_labels(labels));
+        if let Some(histogram) = self.histograms.get(&key) {
+            histogram.observe(elapsed);
+            return;
+        }
+        self.histograms.entry(key).or_default().observe(elapsed);
+    }
+
+    /// Observations of the histogram `name` counted by the value of one
+    /// label
+    pub fn count_by(&self, name: &str, label: &str) -> BTreeMap<String, u64> {
+        let mut out = BTreeMap::new();
+        for entry in self.histograms.iter().filter(|e| e.key().0 == name) {
+            let value = label_value(&entry.key().1, label).unwrap_or_default();
+            *out.entry(value).or_default() += entry.value().count.load(Ordering::Relaxed);
+        }
+        out
+    }
+
+    pub fn gauge(&self, name: &str) -> i64 {
+        self.gauges.get(name).map_or(0, |g| g.load(Ordering::Relaxed))
+    }
+
+    /// Sum of every series of `name`
+    pub fn total(&self, name: &str) -> u64 {
+        self.counters
+            .iter()
+            .filter(|e| e.key().0 == name)
+            .map(|e| e.value().load(Ordering::Relaxed))
+            .sum()
+    }
+
+    /// Series of `name` summed by the value of one label
+    pub fn sum_by(&self, name: &str, label: &str) -> BTreeMap<String, u64> {
+        let mut out = BTreeMap::new();
+        for entry in self.counters.iter().filter(|e| e.key().0 == name) {
+            let value = label_value(&entry.key().1, label).unwrap_or_default();
+            *out.entry(value).or_default() += entry.value().load(Ordering::Relaxed);
+        }
+        out
+    }
+
+    /// Every series of the counters `keep` selects, with its rendered
+    /// label set and value
+    pub fn series(&self, keep: impl Fn(&str) -> bool) -> Vec<(&'static str, String, u64)> {
+        self.counters
+            .iter()
+            .filter(|e| keep(e.key().0))
+            .map(|e| (e.key().0, e.key().1.clone(), e.value().load(Ordering::Relaxed)))
+            .collect()
+    }
+
+    /// Append all counters, histograms and gauges to a Prometheus
+    /// exposition body
+    pub fn render(&self, out: &mut String) {
+        let mut grouped: BTreeMap<&'static str, Vec<(String, u64)>> = BTreeMap::new();
+        for entry in self.counters.iter() {
+            let (name, labels) = entry.key();
+            grouped
+                .entry(name)
+                .or_default()
+                .push((labels.clone(), entry.value().load(Ordering::Relaxed)));
+        }
+        for (name, mut series) in grouped {
+            series.sort();
+            let _ = writeln!(out, "\n# HELP {name} {}", help(name));
+            let _ = writeln!(out, "# TYPE {name} counter");
+            for (labels, value) in series {
+                let _ = writeln!(out, "{name}{labels} {value}");
+            }
+        }
+        let mut histograms: BTreeMap<&'static str, Vec<HistogramSeries>> = BTreeMap::new();
+        for entry in self.histograms.iter() {
+            let ((name, labels), histogram) = (entry.key(), entry.value());
+            let buckets = histogram.buckets.iter().map(|b| b.load(Ordering::Relaxed)).collect();
+            let count = histogram.count.load(Ordering::Relaxed);
+            let sum_micros = histogram.sum_micros.load(Ordering::Relaxed);
+            histograms.entry(name).or_default().push((labels.clone(), buckets, count, sum_micros));
+        }
+        for (name, mut series) in histograms {
+            series.sort();
+            let _ = writeln!(out, "\n# HELP {name} {}", help(name));
+            let _ = writeln!(out, "# TYPE {name} histogram");
+            for (labels, buckets, count, sum_micros) in series {
+                let mut cumulative = 0;
+                for (le, bucket) in BUCKETS.iter().zip(buckets) {
+                    cumulative += bucket;
+                    let _ = writeln!(out, "{name}_bucket{} {cumulative}", with_le(&labels, &le.to_string()));
+                }
+                let _ = writeln!(out, "{name}_bucket{} {count}", with_le(&labels, "+Inf"));
+                let _ = writeln!(out, "{name}_sum{labels} {}", sum_micros as f64 / 1e6);
+                let _ = writeln!(out, "{name}_count{labels} {count}");
+            }
+        }
+        let mut gauges: Vec<_> = self
+            .gauges
+            .iter()
+            .map(|e| (*e.key(), e.value().load(Ordering::Relaxed)))
+            .collect();
+        gauges.sort();
+        for (name, value) in gauges {
+            let _ = writeln!(out, "\n# HELP {name} {}", help(name));
+            let _ = writeln!(out, "# TYPE {name} gauge");
+            let _ = writeln!(out, "{name} {value}");
+        }
+    }
+}
+
+fn help(name: &str) -> &'static str {
+    HELP.iter().find(|(n, _)| *n == name).map(|(_, h)| *h).unwrap_or("")
+}
+
+/// Request and 5xx counts over the trailing hour, in one-minute slots.
+///
+/// Slots are recycled lock-free; a request racing a slot reset may be lost,
+/// which is fine for a dashboard figure.
+pub struct RequestWindow {
+    slots: [Slot; 60],
+}
+
+#[derive(Default)]
+struct Slot {
+    minute: AtomicU64,
+    total: AtomicU64,
+    errors: AtomicU64,
+}
+
+impl Default for RequestWindow {
+    fn default() -> Self {
+        Self { slots: std::array::from_fn(|_| Slot::default()) }
+    }
+}
+
+impl RequestWindow {
+    pub fn record(&self, error: bool) {
+        self.record_at(current_minute(), error);
+    }
+
+    /// `(total, errors)` over the last 60 minutes
+    pub fn last_hour(&self) -> (u64, u64) {
+        self.last_hour_at(current_minute())
+    }
+
+    fn record_at(&self, minute: u64, error: bool) {
+        let slot = &self.slots[(minute % 60) as usize];
+        let seen = slot.minute.load(Ordering::Acquire);
+        if seen != minute
+            && slot
+                .minute
+                .compare_exchange(seen, minute, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+        {
+            slot.total.store(0, Ordering::Release);
+            slot.errors.store(0, Ordering::Release);
+        }
+        slot.total.fetch_add(1, Ordering::Relaxed);
+        if error {
+            slot.errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn last_hour_at(&self, minute: u64) -> (u64, u64) {
+        self.slots
+            .iter()
+            .filter(|s| {
+                let m = s.minute.load(Ordering::Acquire);
+                m + 60 > minute && m <= minute
+            })
+            .fold((0, 0), |(t, e), s| {
+                (t + s.total.load(Ordering::Relaxed), e + s.errors.load(Ordering::Relaxed))
+            })
+    }
+}
+
+fn current_minute() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() / 60
+}
+
+/// Count every response by status class and in the trailing-hour window
+pub async fn record_response(State(metrics): State<Arc<Metrics>>, req: Request, next: Next) -> Response {
+    let response = next.run(req).await;
+    let status = response.status();
+    let class = match status.as_u16() {
+        100..=199 => "1xx",
+        200..=299 => "2xx",
+        300..=399 => "3xx",
+        400..=499 => "4xx",
+        _ => "5xx",
+    };
+    metrics.incr("deepersensor_http_responses_total", &[("class", class)]);
+    metrics.requests.record(status.is_server_error());
+    response
+}
+
+/// Value of `key` in a label set rendered by `render_labels`
+pub(crate) fn label_value(rendered: &str, key: &str) -> Option<String> {
+    let inner = rendered.strip_prefix('{')?.strip_suffix('}')?;
+    let mut rest = inner;
+    while !rest.is_empty() {
+        let (k, after) = rest.split_once("=\"")?;
+        let mut value = String::new();
+        let mut chars = after.char_indices();
+        let end = loop {
+            match chars.next()? {
+                (_, '\\') => match chars.next()?.1 {
+                    'n' => value.push('\n'),
+                    c => value.push(c),
+                },
+                (i, '"') => break i,
+                (_, c) => value.push(c),
+            }
+        };
+        if k == key {
+            return Some(value);
+        }
+        rest = after[end + 1..].strip_prefix(',').unwrap_or("");
+    }
+    None
+}
+
+/// A rendered label set with a bucket's `le` label added
+fn with_le(rendered: &str, le: &str) -> String {
+    match rendered.strip_suffix('}') {
+        Some(inner) => format!("{inner},le=\"{le}\"}}"),
+        None => format!("{{le=\"{le}\"}}"),
+    }
+}
+
+fn render_labels(labels: &[(&str, &str)]) -> String {
+    if labels.is_empty() {
+        return String::new();
+    }
+    let inner = labels
+        .iter()
+        .map(|(k, v)| {
+            let v = v
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{k}=\"{v}\"")
+        })
+        .collect::<Vec<_>>()
+        .join(",");
+    format!("{{{inner}}}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sum_by_label() {
+        let m = Metrics::default();
+        m.add("t", &[("model", "llama3.2"), ("kind", "a")], 3);
+        m.add("t", &[("model", "llama3.2"), ("kind", "b")], 2);
+        m.add("t", &[("model", r#"we"ird\m"#)], 1);
+        m.incr("other", &[("model", "x")]);
+        let by_model = m.sum_by("t", "model");
+        assert_eq!(by_model.get("llama3.2"), Some(&5));
+        assert_eq!(by_model.get(r#"we"ird\m"#), Some(&1));
+        assert_eq!(m.total("t"), 6);
+    }
+
+    #[test]
+    fn test_histogram_buckets_are_cumulative() {
+        let m = Metrics::default();
+        m.observe("h", &[("query", "a")], Duration::from_millis(3));
+        m.observe("h", &[("query", "a")], Duration::from_millis(40));
+        m.observe("h", &[("query", "a")], Duration::from_secs(9));
+        m.observe("h", &[], Duration::from_micros(500));
+        assert_eq!(m.count_by("h", "query").get("a"), Some(&3));
+        let mut out = String::new();
+        m.render(&mut out);
+        assert!(out.contains("# TYPE h histogram\n"));
+        assert!(out.contains("h_bucket{query=\"a\",le=\"0.001\"} 0\n"));
+        assert!(out.contains("h_bucket{query=\"a\",le=\"0.005\"} 1\n"));
+        assert!(out.contains("h_bucket{query=\"a\",le=\"0.05\"} 2\n"));
+        assert!(out.contains("h_bucket{query=\"a\",le=\"5\"} 2\n"));
+        assert!(out.contains("h_bucket{query=\"a\",le=\"+Inf\"} 3\n"));
+        assert!(out.contains("h_sum{query=\"a\"} 9.043\n"));
+        assert!(out.contains("h_count{query=\"a\"} 3\n"));
+        assert!(out.contains("h_bucket{le=\"0.001\"} 1\n"));
+    }
+
+    #[test]
+    fn test_request_window_expires_old_minutes() {
+        let w = RequestWindow::default();
+        w.record_at(1_000, false);
+        w.record_at(1_000, true);
+        w.record_at(1_030, false);
+        assert_eq!(w.last_hour_at(1_030), (3, 1));
+        assert_eq!(w.last_hour_at(1_060), (1, 0));
+        // Slot 1_000 is reused for minute 1_060
+        w.record_at(1_060, true);
+        assert_eq!(w.last_hour_at(1_060), (2, 1));
+    }
+}
@@ -0,0 +1,166 @@
+//! Schema migrations and how far the database is from this build.
+//!
+//! Instances starting together (two versions during a blue/green deploy,
+//! or replicas of one) take a Postgres advisory lock before looking at
+//! pending migrations, so exactly one applies them while the rest wait and
+//! then find nothing left to do. A database with migrations this build
+//! does not know is expected on the old side of such a deploy: the server
+//! starts anyway, and `/readiness` fails unless
+//! `MIGRATIONS_ALLOW_NEWER_SCHEMA` is set.
+
+use chrono::{DateTime, Utc};
+use ds_core::config::AppConfig;
+use serde::Serialize;
+use sqlx::{migrate::Migrator, PgPool, Row};
+use std::{collections::HashSet, fmt};
+
+/// Advisory lock key held while migrating ("dsmigrat")
+pub const LOCK_KEY: i64 = 0x6473_6d69_6772_6174;
+
+/// The migrations built into this binary
+pub fn migrator() -> Migrator {
+    let mut migrator = sqlx::migrate!("../../migrations");
+    // A newer build's migrations are not an error; see `Schema::Ahead`
+    migrator.set_ignore_missing(true);
+    // Serialized by `LOCK_KEY` instead, held across the pending check
+    migrator.set_locking(false);
+    migrator
+}
+
+/// The database's schema relative to this build's
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum Schema {
+    Current,
+    /// `pending` of this build's migrations are not applied yet
+    Behind { pending: usize, expected: i64 },
+    /// Migrations up to `db` from a newer build are applied
+    Ahead { db: i64, expected: i64 },
+}
+
+impl fmt::Display for Schema {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Schema::Current => write!(f, "schema current"),
+            Schema::Behind { pending, expected } => write!(f, "schema behind: {pending} pending, build expects {expected}"),
+            Schema::Ahead { db, expected } => write!(f, "schema ahead: database at {db}, build expects {expected}"),
+        }
+    }
+}
+
+/// One row of the migrations table
+#[derive(Debug, Clone, Serialize)]
+pub struct Applied {
+    pub version: i64,
+    pub description: String,
+    pub installed_on: DateTime<Utc>,
+    /// False when it failed part way
+    pub success: bool,
+    #[serde(skip)]
+    pub checksum: Vec<u8>,
+    #[serde(rename = "execution_ns")]
+    pub execution_time: i64,
+}
+
+/// Every migration recorded in the database, oldest first; none before the
+/// first run
+pub async fn applied(db: &PgPool) -> sqlx::Result<Vec<Applied>> {
+    let exists: bool = sqlx::query_scalar("SELECT to_regclass('_sqlx_migrations') IS NOT NULL").fetch_one(db).await?;
+    if !exists {
+        return Ok(Vec::new());
+    }
+    let rows = sqlx::query(
+        "SELECT version, description, installed_on, success, checksum, execution_time \
+         FROM _sqlx_migrations ORDER BY version",
+    )
+    .fetch_all(db)
+    .await?;
+    rows.iter()
+        .map(|row| {
+            Ok(Applied {
+                version: row.try_get("version")?,
+                description: row.try_get("description")?,
+                installed_on: row.try_get("installed_on")?,
+                success: row.try_get("success")?,
+                checksum: row.try_get("checksum")?,
+                execution_time: row.try_get("execution_time")?,
+            })
+        })
+        .collect()
+}
+
+/// Compare the successfully applied migrations to this build's
+pub async fn schema(db: &PgPool) -> sqlx::Result<Schema> {
+    let applied: HashSet<i64> = applied(db).await?.into_iter().filter(|m| m.success).map(|m| m.version).collect();
+    Ok(compare(&applied, migrator().iter().map(|m| m.version)))
+}
+
+fn compare(applied: &HashSet<i64>, built: impl Iterator<Item = i64>) -> Schema {
+    let built: Vec<i64> = built.collect();
+    let expected = built.iter().copied().max().unwrap_or(0);
+    let pending = built.iter().filter(|v| !applied.contains(v)).count();
+    let newest = applied.iter().copied().max().unwrap_or(0);
+    if pending > 0 {
+        Schema::Behind { pending, expected }
+    } else if newest > expected {
+        Schema::Ahead { db: newest, expected }
+    } else {
+        Schema::Current
+    }
+}
+
+/// Bring the schema up to this build per `MIGRATIONS_MODE` before serving
+pub async fn prepare(cfg: &AppConfig, db: &PgPool) -> anyhow::Result<()> {
+    match cfg.database.migrations.as_str() {
+        "run" => run(db).await,
+        "verify" => match schema(db).await? {
+            behind @ Schema::Behind { .. } => {
+                anyhow::bail!("{behind}; MIGRATIONS_MODE=verify does not apply them")
+            }
+            schema => {
+                tracing::info!(%schema, "migrations verified");
+                Ok(())
+            }
+        },
+        other => anyhow::bail!("unknown MIGRATIONS_MODE {other}; expected run or verify"),
+    }
+}
+
+/// Apply pending migrations under the advisory lock
+async fn run(db: &PgPool) -> anyhow::Result<()> {
+    let mut conn = db.acquire().await?;
+    let locked: bool = sqlx::query_scalar("SELECT pg_try_advisory_lock($1)").bind(LOCK_KEY).fetch_one(&mut *conn).await?;
+    if !locked {
+        tracing::info!("another instance is migrating; waiting for it");
+        sqlx::query("SELECT pg_advisory_lock($1)").bind(LOCK_KEY).execute(&mut *conn).await?;
+    }
+    let result = async {
+        let before = schema(db).await?;
+        migrator().run(&mut *conn).await?;
+        anyhow::Ok(before)
+    }
+    .await;
+    sqlx::query("SELECT pg_advisory_unlock($1)").bind(LOCK_KEY).execute(&mut *conn).await?;
+    match result? {
+        Schema::Behind { pending, expected } => tracing::info!(pending, expected, "migrations applied"),
+        schema => tracing::info!(%schema, "no migrations to apply"),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_schema_comparison() {
+        let applied = |versions: &[i64]| versions.iter().copied().collect::<HashSet<_>>();
+        let built = || [1, 2, 3].into_iter();
+        assert_eq!(compare(&applied(&[1, 2, 3]), built()), Schema::Current);
+        assert_eq!(compare(&applied(&[1, 2]), built()), Schema::Behind { pending: 1, expected: 3 });
+        // A skipped migration is pending even when newer ones are applied
+        assert_eq!(compare(&applied(&[1, 3]), built()), Schema::Behind { pending: 1, expected: 3 });
+        assert_eq!(compare(&applied(&[1, 2, 3, 4]), built()), Schema::Ahead { db: 4, expected: 3 });
+        assert_eq!(compare(&applied(&[]), built()), Schema::Behind { pending: 3, expected: 3 });
+    }
+}
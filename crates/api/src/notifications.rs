@@ -0,0 +1,198 @@
+//! Notifications to users: the channels configured for this deployment,
+//! each user's preferences, and sending to a user by id.
+//!
+//! Users' webhooks are reached through the tool egress policy like any
+//! other user-supplied URL.
+
+use crate::{
+    metrics::Metrics,
+    tool_egress::{EgressError, ToolEgress},
+};
+use async_trait::async_trait;
+use ds_core::config::{AppConfig, NotifySection};
+use ds_notify::{
+    Backoff, Channel, DeliveryError, DkimSettings, EmailChannel, FileMailer, Kind, LogMailer, Mailer, Notification,
+    Notifier, Recipient, Rendered, SmtpMailer, SmtpSettings, SmtpTls, Templates, WebhookEvent, WEBHOOK,
+};
+use ds_types::UserId;
+use reqwest::Method;
+use serde::{Deserialize, Serialize};
+use sqlx::{PgPool, Row};
+use std::{collections::BTreeMap, sync::Arc, time::Duration};
+
+/// Deliveries by kind, channel, and result (`delivered`, `failed`, `skipped`)
+pub const SENT: &str = "deepersensor_notifications_total";
+
+/// The notifier for `cfg`, counting deliveries in `metrics`
+pub fn from_config(cfg: &AppConfig, egress: Arc<ToolEgress>, metrics: Arc<Metrics>) -> Notifier {
+    let n = &cfg.notify;
+    let backoff = Backoff {
+        max_attempts: n.max_attempts.max(1),
+        base: Duration::from_millis(n.backoff_ms),
+        max: Duration::from_millis(n.max_backoff_ms),
+    };
+    let observer = {
+        let metrics = metrics.clone();
+        Arc::new(move |kind: Kind, channel: &'static str, result: &'static str| {
+            metrics.incr(SENT, &[("kind", kind.as_str()), ("channel", channel), ("result", result)]);
+        })
+    };
+    let templates = Templates::new((!n.templates_dir.is_empty()).then(|| n.templates_dir.clone().into()));
+    let notifier = Notifier::new(backoff).templates(templates).observe(observer).with(UserWebhook { egress, metrics });
+    match mailer(n) {
+        Ok(Some(mailer)) => notifier.with(EmailChannel::new(&n.email_from, mailer)),
+        Ok(None) => notifier,
+        Err(e) => {
+            tracing::error!(error = %e, mailer = %n.mailer, "mailer unavailable; no email will be sent");
+            notifier
+        }
+    }
+}
+
+fn mailer(n: &NotifySection) -> Result<Option<Arc<dyn Mailer>>, String> {
+    Ok(Some(match n.mailer.as_str() {
+        "" => return Ok(None),
+        "log" => Arc::new(LogMailer),
+        "file" => Arc::new(FileMailer::new(&n.mail_dir)),
+        "smtp" => {
+            let tls = SmtpTls::parse(&n.smtp_tls).ok_or_else(|| format!("unknown SMTP TLS mode {}", n.smtp_tls))?;
+            let dkim = match [&n.dkim_selector, &n.dkim_domain, &n.dkim_key_path].iter().all(|v| !v.is_empty()) {
+                true => Some(DkimSettings {
+                    selector: n.dkim_selector.clone(),
+                    domain: n.dkim_domain.clone(),
+                    private_key: std::fs::read_to_string(&n.dkim_key_path)
+                        .map_err(|e| format!("DKIM key {} unreadable: {e}", n.dkim_key_path))?,
+                    algorithm: n.dkim_algorithm.clone(),
+                }),
+                false => None,
+            };
+            let settings = SmtpSettings {
+                host: n.smtp_host.clone(),
+                port: n.smtp_port,
+                tls,
+                username: n.smtp_username.clone(),
+                password: n.smtp_password.clone(),
+                pool_size: n.smtp_pool_size,
+                timeout: Duration::from_secs(n.smtp_timeout_secs),
+                max_per_sec: n.smtp_max_per_sec,
+                dkim,
+            };
+            Arc::new(SmtpMailer::new(&settings).map_err(|e| e.to_string())?)
+        }
+        other => return Err(format!("unknown mailer {other}")),
+    }))
+}
+
+/// POSTs `{ event, subject, text, data }` to the user's webhook
+struct UserWebhook {
+    egress: Arc<ToolEgress>,
+    metrics: Arc<Metrics>,
+}
+
+#[async_trait]
+impl Channel for UserWebhook {
+    fn name(&self) -> &'static str {
+        WEBHOOK
+    }
+
+    fn address<'a>(&self, to: &'a Recipient) -> Option<&'a str> {
+        to.webhook_url.as_deref()
+    }
+
+    async fn deliver(&self, url: &str, n: &Notification, rendered: &Rendered) -> Result<(), DeliveryError> {
+        let event = WebhookEvent {
+            event: n.kind.as_str(),
+            subject: &rendered.subject,
+            text: &rendered.text,
+            data: &n.vars,
+        };
+        let body = serde_json::to_vec(&event).map_err(|e| DeliveryError::Permanent(e.to_string()))?;
+        let sent = self.egress.fetch(&self.metrics, "notification_webhook", Method::POST, url, Some(body)).await;
+        match sent {
+            Ok(resp) if (200..300).contains(&resp.status) => Ok(()),
+            Ok(resp) if resp.status == 429 || resp.status >= 500 => {
+                Err(DeliveryError::Retryable(format!("webhook answered {}", resp.status)))
+            }
+            Ok(resp) => Err(DeliveryError::Permanent(format!("webhook answered {}", resp.status))),
+            Err(e @ EgressError::Denied(_)) => Err(DeliveryError::Permanent(e.to_string())),
+            Err(e) => Err(DeliveryError::Retryable(e.to_string())),
+        }
+    }
+}
+
+/// A user's choices; kinds missing from `channels` use their defaults
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Preferences {
+    pub webhook_url: Option<String>,
+    /// BCP 47 tag emails and webhook texts are written in; English when
+    /// unset
+    pub locale: Option<String>,
+    #[serde(default)]
+    pub channels: BTreeMap<String, Vec<String>>,
+}
+
+impl Preferences {
+    fn channels(&self, kind: Kind) -> Vec<&str> {
+        match self.channels.get(kind.as_str()) {
+            Some(chosen) => chosen.iter().map(String::as_str).collect(),
+            None => kind.default_channels().to_vec(),
+        }
+    }
+}
+
+pub async fn preferences(db: &PgPool, user_id: UserId) -> sqlx::Result<Preferences> {
+    let row = sqlx::query(
+        "SELECT webhook_url, locale, channels::text AS channels FROM notification_preferences WHERE user_id = $1",
+    )
+    .bind(user_id)
+    .fetch_optional(db)
+    .await?;
+    let Some(row) = row else { return Ok(Preferences::default()) };
+    let channels: String = row.try_get("channels")?;
+    Ok(Preferences {
+        webhook_url: row.try_get("webhook_url")?,
+        locale: row.try_get("locale")?,
+        channels: serde_json::from_str(&channels).unwrap_or_default(),
+    })
+}
+
+pub async fn set_preferences(db: &PgPool, user_id: UserId, prefs: &Preferences) -> sqlx::Result<()> {
+    let channels = serde_json::to_string(&prefs.channels).unwrap_or_else(|_| "{}".into());
+    sqlx::query(
+        "INSERT INTO notification_preferences (user_id, webhook_url, locale, channels) \
+         VALUES ($1, $2, $3, $4::jsonb) \
+         ON CONFLICT (user_id) DO UPDATE SET webhook_url = EXCLUDED.webhook_url, locale = EXCLUDED.locale, \
+         channels = EXCLUDED.channels, updated_at = NOW()",
+    )
+    .bind(user_id)
+    .bind(&prefs.webhook_url)
+    .bind(&prefs.locale)
+    .bind(channels)
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+/// Send `notification` to `user_id` on the channels they chose for its
+/// kind; delivery continues in the background
+pub async fn notify(
+    db: &PgPool,
+    notifier: &Arc<Notifier>,
+    user_id: UserId,
+    notification: Notification,
+) -> sqlx::Result<()> {
+    let email: Option<String> = sqlx::query_scalar("SELECT email FROM users WHERE id = $1")
+        .bind(user_id)
+        .fetch_optional(db)
+        .await?;
+    let prefs = preferences(db, user_id).await?;
+    let channels = prefs.channels(notification.kind);
+    let to = Recipient {
+        user_id: Some(user_id),
+        email,
+        webhook_url: prefs.webhook_url.clone(),
+        locale: prefs.locale.clone(),
+    };
+    notifier.send(&to, &channels, notification);
+    Ok(())
+}
@@ -1,11 +1,323 @@
-use tracing_subscriber::{fmt, EnvFilter, layer::SubscriberExt, util::SubscriberInitExt};
-use ds_core::config::AppConfig;
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, OnceLock},
+    time::Duration,
+};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tracing_appender::{
+    non_blocking::{NonBlocking, WorkerGuard},
+    rolling::{RollingFileAppender, Rotation},
+};
+use tracing_subscriber::{
+    filter::{ParseError, Targets},
+    fmt, reload, EnvFilter, Layer, Registry,
+    layer::SubscriberExt,
+    util::SubscriberInitExt,
+};
+use ds_core::config::{AppConfig, LoggingSection};
 
-pub fn init_tracing(_cfg: &AppConfig) {
-    let env_filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+/// The `RUST_LOG` directives, or `info`
+fn initial_directives() -> String {
+    std::env::var(EnvFilter::DEFAULT_ENV).ok().filter(|d| EnvFilter::try_new(d).is_ok()).unwrap_or_else(|| "info".into())
+}
+
+/// Set up logging to stdout and, with `LOG_FILE`, to a rotated file. The
+/// returned guard flushes the file writer when dropped, so hold it until
+/// the process exits.
+pub fn init_tracing(cfg: &AppConfig) -> anyhow::Result<Option<WorkerGuard>> {
     // Use JSON formatting consistently for now to avoid type incompatibilities
     let fmt_layer = fmt::layer().json().with_target(false);
-    tracing_subscriber::registry().with(env_filter).with(fmt_layer).init();
+    let (file_layer, guard) = match file_writer(&cfg.logging)? {
+        Some((writer, guard)) => {
+            let layer = fmt::layer().json().with_target(false).with_ansi(false).with_writer(writer);
+            (Some(layer), Some(guard))
+        }
+        None => (None, None),
+    };
+    // One filter for stdout and the file, swappable while running
+    let initial = initial_directives();
+    let (filter, handle) = reload::Layer::new(EnvFilter::new(&initial));
+    let logs = fmt_layer.and_then(file_layer).with_filter(filter);
+    // Statement timings reach the slow request watch whatever RUST_LOG keeps
+    let query_timer = (cfg.slow.request_ms > 0 || cfg.slow.query_ms > 0).then(|| {
+        let slow = (cfg.slow.query_ms > 0).then(|| std::time::Duration::from_millis(cfg.slow.query_ms));
+        crate::slow::QueryTimer { slow }.with_filter(Targets::new().with_target("sqlx::query", tracing::Level::DEBUG))
+    });
+    // Listens on TOKIO_CONSOLE_BIND (default 127.0.0.1:6669)
+    #[cfg(feature = "console")]
+    let console = Some(console_subscriber::spawn());
+    #[cfg(not(feature = "console"))]
+    let console: Option<tracing_subscriber::layer::Identity> = None;
+    tracing_subscriber::registry().with(logs).with(query_timer).with(console).init();
+    let revert_after = Duration::from_secs(cfg.logging.level_revert_secs);
+    let _ = LEVELS.set(Arc::new(LogLevels::new(handle, initial, revert_after)));
+    Ok(guard)
+}
+
+static LEVELS: OnceLock<Arc<LogLevels>> = OnceLock::new();
+
+/// The live log filter, once [`init_tracing`] has installed one
+pub fn log_levels() -> Option<&'static Arc<LogLevels>> {
+    LEVELS.get()
+}
+
+/// The filter applied to every log line, changed at runtime by
+/// `PUT /v1/admin/log-level` and SIGUSR1 and put back to the startup
+/// directives when the change expires
+pub struct LogLevels {
+    handle: reload::Handle<EnvFilter, Registry>,
+    initial: String,
+    /// Default lifetime of a change; zero keeps it
+    revert_after: Duration,
+    current: Mutex<Current>,
+}
+
+struct Current {
+    directives: String,
+    reverts_at: Option<DateTime<Utc>>,
+    /// Bumped by every change, so a stale revert does nothing
+    generation: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LogLevel {
+    pub directives: String,
+    pub initial: String,
+    pub reverts_at: Option<DateTime<Utc>>,
+}
+
+/// What SIGUSR1 steps through after the startup directives
+const CYCLE: [&str; 2] = ["debug", "trace"];
+
+impl LogLevels {
+    pub fn new(handle: reload::Handle<EnvFilter, Registry>, initial: String, revert_after: Duration) -> Self {
+        let current = Current { directives: initial.clone(), reverts_at: None, generation: 0 };
+        Self { handle, initial, revert_after, current: Mutex::new(current) }
+    }
+
+    pub fn current(&self) -> LogLevel {
+        let current = self.current.lock().expect("log level lock");
+        LogLevel {
+            directives: current.directives.clone(),
+            initial: self.initial.clone(),
+            reverts_at: current.reverts_at,
+        }
+    }
+
+    /// Filter with `directives` until `revert_after` (the configured
+    /// default when `None`, for good when zero) has passed
+    pub fn set(self: &Arc<Self>, directives: &str, revert_after: Option<Duration>) -> Result<LogLevel, ParseError> {
+        let filter = EnvFilter::try_new(directives)?;
+        let revert_after = revert_after.unwrap_or(self.revert_after);
+        let reverts_at = (!revert_after.is_zero())
+            .then(|| Utc::now() + chrono::Duration::from_std(revert_after).unwrap_or(chrono::Duration::MAX));
+        let generation = self.apply(&mut self.current.lock().expect("log level lock"), filter, directives, reverts_at);
+        if reverts_at.is_some() {
+            let levels = Arc::clone(self);
+            tokio::spawn(async move {
+                tokio::time::sleep(revert_after).await;
+                let mut current = levels.current.lock().expect("log level lock");
+                if current.generation == generation {
+                    levels.apply(&mut current, EnvFilter::new(&levels.initial), &levels.initial, None);
+                    drop(current);
+                    tracing::info!(directives = %levels.initial, "log filter change expired");
+                }
+            });
+        }
+        Ok(self.current())
+    }
+
+    /// Go back to the startup directives
+    pub fn reset(&self) -> LogLevel {
+        let mut current = self.current.lock().expect("log level lock");
+        self.apply(&mut current, EnvFilter::new(&self.initial), &self.initial, None);
+        drop(current);
+        self.current()
+    }
+
+    /// Step to the next of the startup directives, `debug`, and `trace`
+    pub fn cycle(self: &Arc<Self>) -> LogLevel {
+        let current = self.current().directives;
+        let next = match CYCLE.iter().position(|d| *d == current) {
+            None => Some(CYCLE[0]),
+            Some(i) => CYCLE.get(i + 1).copied(),
+        };
+        match next {
+            Some(directives) => self.set(directives, None).expect("cycle directives parse"),
+            None => self.reset(),
+        }
+    }
+
+    /// Swap in `filter`, under the lock on `current` so changes apply in
+    /// the order recorded
+    fn apply(
+        &self,
+        current: &mut Current,
+        filter: EnvFilter,
+        directives: &str,
+        reverts_at: Option<DateTime<Utc>>,
+    ) -> u64 {
+        if let Err(e) = self.handle.reload(filter) {
+            tracing::error!(error = %e, "log filter reload failed");
+        }
+        current.directives = directives.to_string();
+        current.reverts_at = reverts_at;
+        current.generation += 1;
+        current.generation
+    }
 }
 
-pub const REQUEST_ID_HEADER: &str = "x-request-id";
\ No newline at end of file
+/// Step the log filter on every SIGUSR1, for hosts without admin API access
+#[cfg(unix)]
+pub async fn cycle_on_sigusr1() {
+    use tokio::signal::unix::{signal, SignalKind};
+    let Some(levels) = log_levels() else { return };
+    let mut usr1 = match signal(SignalKind::user_defined1()) {
+        Ok(usr1) => usr1,
+        Err(e) => {
+            tracing::warn!(error = %e, "cannot listen for SIGUSR1; log level cycling disabled");
+            return;
+        }
+    };
+    while usr1.recv().await.is_some() {
+        let level = levels.cycle();
+        tracing::warn!(directives = %level.directives, reverts_at = ?level.reverts_at, "log filter changed by SIGUSR1");
+    }
+}
+
+/// A non-blocking writer to `LOG_FILE`, rotated as configured
+fn file_writer(cfg: &LoggingSection) -> anyhow::Result<Option<(NonBlocking, WorkerGuard)>> {
+    if cfg.file.is_empty() {
+        return Ok(None);
+    }
+    let path = Path::new(&cfg.file);
+    let (dir, name) = match (path.parent(), path.file_name()) {
+        (Some(dir), Some(name)) => (if dir.as_os_str().is_empty() { Path::new(".") } else { dir }, name),
+        _ => anyhow::bail!("LOG_FILE {:?} does not name a file", cfg.file),
+    };
+    fs::create_dir_all(dir).map_err(|e| anyhow::anyhow!("cannot create log directory {}: {e}", dir.display()))?;
+    let rotation = match cfg.file_rotation.as_str() {
+        "size" => {
+            let file = SizeRotating::open(path, cfg.file_max_bytes, cfg.file_keep)?;
+            return Ok(Some(tracing_appender::non_blocking(file)));
+        }
+        "daily" => Rotation::DAILY,
+        "hourly" => Rotation::HOURLY,
+        "never" => Rotation::NEVER,
+        other => anyhow::bail!("unknown LOG_FILE_ROTATION {other:?}; expected daily, hourly, size, or never"),
+    };
+    let mut builder = RollingFileAppender::builder().rotation(rotation).filename_prefix(name.to_string_lossy());
+    if cfg.file_keep > 0 {
+        // Counts the current file too
+        builder = builder.max_log_files(cfg.file_keep + 1);
+    }
+    Ok(Some(tracing_appender::non_blocking(builder.build(dir)?)))
+}
+
+/// A log file moved aside as `<file>.1` once it reaches `max_bytes`,
+/// earlier ones shifting up and those past `keep` removed
+struct SizeRotating {
+    path: PathBuf,
+    max_bytes: u64,
+    keep: usize,
+    file: File,
+    written: u64,
+}
+
+impl SizeRotating {
+    fn open(path: &Path, max_bytes: u64, keep: usize) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let written = file.metadata()?.len();
+        Ok(Self { path: path.to_path_buf(), max_bytes, keep, file, written })
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{n}"));
+        name.into()
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        if self.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            let _ = fs::remove_file(self.rotated(self.keep));
+            for n in (1..self.keep).rev() {
+                let _ = fs::rename(self.rotated(n), self.rotated(n + 1));
+            }
+            fs::rename(&self.path, self.rotated(1))?;
+        }
+        self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        self.written = 0;
+        Ok(())
+    }
+}
+
+impl Write for SizeRotating {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // Whole lines go to one file; one longer than the limit gets its own
+        if self.written > 0 && self.written + buf.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+        let n = self.file.write(buf)?;
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_size_rotation_keeps_the_newest_files() {
+        let dir = std::env::temp_dir().join(format!("ds-log-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("api.log");
+        let mut file = SizeRotating::open(&path, 10, 2).unwrap();
+        for line in ["first\n", "second\n", "third\n", "fourth\n"] {
+            file.write_all(line.as_bytes()).unwrap();
+        }
+        let read = |p: PathBuf| fs::read_to_string(p).unwrap();
+        assert_eq!(read(path.clone()), "fourth\n");
+        assert_eq!(read(file.rotated(1)), "third\n");
+        assert_eq!(read(file.rotated(2)), "second\n");
+        assert!(!file.rotated(3).exists());
+        // Reopening appends and counts what is already there
+        drop(file);
+        let mut file = SizeRotating::open(&path, 10, 2).unwrap();
+        file.write_all(b"fifth\n").unwrap();
+        assert_eq!(read(file.rotated(1)), "fourth\n");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_log_filter_changes_apply_and_expire() {
+        let (filter, handle) = reload::Layer::new(EnvFilter::new("info"));
+        let subscriber = tracing_subscriber::registry().with(fmt::layer().with_writer(io::sink).with_filter(filter));
+        let _default = tracing::subscriber::set_default(subscriber);
+        let levels = Arc::new(LogLevels::new(handle, "info".into(), Duration::ZERO));
+        assert!(!tracing::enabled!(tracing::Level::DEBUG));
+
+        assert!(levels.set("api=loud", None).is_err());
+        let level = levels.set("debug", Some(Duration::from_millis(20))).unwrap();
+        assert!(level.reverts_at.is_some());
+        assert!(tracing::enabled!(tracing::Level::DEBUG));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(levels.current().directives, "info");
+        assert!(!tracing::enabled!(tracing::Level::DEBUG));
+
+        // SIGUSR1 steps through debug and trace and back; zero keeps each
+        let steps: Vec<String> = (0..3).map(|_| levels.cycle().directives).collect();
+        assert_eq!(steps, ["debug", "trace", "info"]);
+        assert!(levels.current().reverts_at.is_none());
+    }
+}
@@ -0,0 +1,55 @@
+//! Organizations and their members.
+
+use crate::events;
+use ds_types::{OrgId, UserId};
+use sqlx::PgPool;
+
+/// Manages the org's members and API keys
+pub const ORG_ADMIN: &str = "admin";
+pub const ORG_MEMBER: &str = "member";
+
+/// Create an org with `owner` as its first admin
+pub async fn create(db: &PgPool, name: &str, owner: UserId) -> sqlx::Result<OrgId> {
+    let id = OrgId::generate();
+    let mut tx = db.begin().await?;
+    sqlx::query("INSERT INTO organizations (id, name) VALUES ($1, $2)")
+        .bind(id)
+        .bind(name)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query("INSERT INTO org_members (org_id, user_id, role) VALUES ($1, $2, $3)")
+        .bind(id)
+        .bind(owner)
+        .bind(ORG_ADMIN)
+        .execute(&mut *tx)
+        .await?;
+    events::enqueue(&mut tx, &events::OrgCreated { org_id: id, owner }).await?;
+    tx.commit().await?;
+    Ok(id)
+}
+
+/// `user_id`'s role in `org_id`, or `None` if not a member
+pub async fn member_role(db: &PgPool, org_id: OrgId, user_id: UserId) -> sqlx::Result<Option<String>> {
+    sqlx::query_scalar("SELECT role FROM org_members WHERE org_id = $1 AND user_id = $2")
+        .bind(org_id)
+        .bind(user_id)
+        .fetch_optional(db)
+        .await
+}
+
+/// Add (or change the role of) the user registered as `email`; returns
+/// false when there is no such user
+pub async fn upsert_member(db: &PgPool, org_id: OrgId, email: &str, role: &str) -> sqlx::Result<bool> {
+    let added = sqlx::query(
+        "INSERT INTO org_members (org_id, user_id, role) \
+         SELECT $1, id, $3 FROM users WHERE email = $2 \
+         ON CONFLICT (org_id, user_id) DO UPDATE SET role = EXCLUDED.role",
+    )
+    .bind(org_id)
+    .bind(email)
+    .bind(role)
+    .execute(db)
+    .await?
+    .rows_affected();
+    Ok(added == 1)
+}
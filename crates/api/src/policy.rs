@@ -0,0 +1,313 @@
+//! Route policy: rules from `POLICY_FILE` that authenticated requests must
+//! satisfy, checked by `require_auth` once the caller is known.
+//!
+//! The file is a JSON array of rules. A rule applies to requests whose path
+//! matches one of its `routes` (`*` matches any run of characters) and,
+//! when `methods` is given, whose method is listed. Every condition an
+//! applying rule sets must then hold, or the request is refused with `403`
+//! and logged as `security.policy.denied`:
+//!
+//! ```json
+//! [{ "name": "admin-office", "routes": ["/v1/admin/*"], "roles": ["admin"],
+//!    "cidrs": ["10.0.0.0/8"], "days": ["mon", "tue", "wed", "thu", "fri"], "hours": "08:00-18:00" }]
+//! ```
+//!
+//! `roles` needs any one of the roles, `cidrs` the client address in one
+//! of the networks, `days` and `hours` (UTC; a window may wrap past
+//! midnight) the time of the request.
+
+use crate::auth_middleware::AuthUser;
+use chrono::{DateTime, Datelike, NaiveTime, Utc, Weekday};
+use ds_core::config::AppConfig;
+use ipnet::IpNet;
+use serde::Deserialize;
+use std::net::IpAddr;
+
+/// Requests refused by a policy rule, by rule
+pub const DENIALS: &str = "deepersensor_policy_denials_total";
+
+#[derive(Debug, thiserror::Error)]
+pub enum PolicyError {
+    #[error("reading {0}: {1}")]
+    Read(String, std::io::Error),
+    #[error("parsing {0}: {1}")]
+    Parse(String, serde_json::Error),
+    #[error("rule {rule}: {message}")]
+    Rule { rule: String, message: String },
+}
+
+/// A rule as written in the policy file
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleSpec {
+    name: String,
+    routes: Vec<String>,
+    #[serde(default)]
+    methods: Vec<String>,
+    #[serde(default)]
+    roles: Vec<String>,
+    #[serde(default)]
+    cidrs: Vec<String>,
+    #[serde(default)]
+    days: Vec<String>,
+    hours: Option<String>,
+}
+
+#[derive(Debug)]
+struct Rule {
+    name: String,
+    routes: Vec<String>,
+    /// Uppercased
+    methods: Vec<String>,
+    roles: Vec<String>,
+    cidrs: Vec<IpNet>,
+    days: Vec<Weekday>,
+    /// Start inclusive, end exclusive
+    hours: Option<(NaiveTime, NaiveTime)>,
+}
+
+/// Why a request was refused
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Denial<'a> {
+    pub rule: &'a str,
+    /// `role`, `network`, `day`, `hours`, or `policy` when there are no
+    /// usable rules to check
+    pub condition: &'static str,
+}
+
+/// The request as the rules see it
+pub struct Subject<'a> {
+    pub user: &'a AuthUser,
+    pub method: &'a str,
+    pub path: &'a str,
+    pub ip: IpAddr,
+    pub at: DateTime<Utc>,
+}
+
+#[derive(Debug, Default)]
+pub struct Policy {
+    rules: Vec<Rule>,
+    /// Set when the policy file could not be used; every request is refused
+    closed: bool,
+}
+
+impl Policy {
+    /// The rules in `POLICY_FILE`; none when it is unset
+    pub fn from_config(cfg: &AppConfig) -> Result<Self, PolicyError> {
+        let path = &cfg.security.policy_file;
+        if path.is_empty() {
+            return Ok(Self::default());
+        }
+        let text = std::fs::read_to_string(path).map_err(|e| PolicyError::Read(path.clone(), e))?;
+        Self::parse(&text).map_err(|e| match e {
+            PolicyError::Parse(_, e) => PolicyError::Parse(path.clone(), e),
+            other => other,
+        })
+    }
+
+    pub fn parse(json: &str) -> Result<Self, PolicyError> {
+        let specs: Vec<RuleSpec> = serde_json::from_str(json).map_err(|e| PolicyError::Parse(String::new(), e))?;
+        let rules = specs.into_iter().map(Rule::compile).collect::<Result<_, _>>()?;
+        Ok(Self { rules, closed: false })
+    }
+
+    /// Refuses everything, for when the policy file cannot be used
+    pub fn deny_all() -> Self {
+        Self { rules: Vec::new(), closed: true }
+    }
+
+    pub fn len(&self) -> usize {
+        self.rules.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// The first rule `subject` breaks, if any
+    pub fn check(&self, subject: &Subject) -> Result<(), Denial<'_>> {
+        if self.closed {
+            return Err(Denial { rule: "unusable-policy", condition: "policy" });
+        }
+        for rule in self.rules.iter().filter(|r| r.applies(subject)) {
+            if let Some(condition) = rule.broken(subject) {
+                return Err(Denial { rule: &rule.name, condition });
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Rule {
+    fn compile(spec: RuleSpec) -> Result<Self, PolicyError> {
+        let invalid = |message: String| PolicyError::Rule { rule: spec.name.clone(), message };
+        if spec.routes.is_empty() {
+            return Err(invalid("at least one route is required".into()));
+        }
+        let cidrs = spec
+            .cidrs
+            .iter()
+            .map(|c| c.parse::<IpNet>().or_else(|_| c.parse::<IpAddr>().map(IpNet::from)))
+            .collect::<Result<_, _>>()
+            .map_err(|e| invalid(format!("invalid cidr: {e}")))?;
+        let days = spec
+            .days
+            .iter()
+            .map(|d| d.parse::<Weekday>().map_err(|_| invalid(format!("unknown day {d}"))))
+            .collect::<Result<_, _>>()?;
+        let hours = match &spec.hours {
+            None => None,
+            Some(window) => {
+                let parsed = window.split_once('-').and_then(|(start, end)| {
+                    let time = |t: &str| NaiveTime::parse_from_str(t.trim(), "%H:%M").ok();
+                    Some((time(start)?, time(end)?))
+                });
+                Some(parsed.ok_or_else(|| invalid(format!("hours {window} is not HH:MM-HH:MM")))?)
+            }
+        };
+        Ok(Self {
+            methods: spec.methods.iter().map(|m| m.to_ascii_uppercase()).collect(),
+            name: spec.name,
+            routes: spec.routes,
+            roles: spec.roles,
+            cidrs,
+            days,
+            hours,
+        })
+    }
+
+    fn applies(&self, s: &Subject) -> bool {
+        let method = self.methods.is_empty() || self.methods.iter().any(|m| m == s.method);
+        method && self.routes.iter().any(|pattern| glob(pattern, s.path))
+    }
+
+    fn broken(&self, s: &Subject) -> Option<&'static str> {
+        if !self.roles.is_empty() && !self.roles.iter().any(|r| s.user.has_role(r)) {
+            return Some("role");
+        }
+        if !self.cidrs.is_empty() && !self.cidrs.iter().any(|net| net.contains(&s.ip)) {
+            return Some("network");
+        }
+        if !self.days.is_empty() && !self.days.contains(&s.at.weekday()) {
+            return Some("day");
+        }
+        if let Some((start, end)) = self.hours {
+            let now = s.at.time();
+            let inside = match start <= end {
+                true => start <= now && now < end,
+                // Wraps past midnight
+                false => now >= start || now < end,
+            };
+            if !inside {
+                return Some("hours");
+            }
+        }
+        None
+    }
+}
+
+/// Does `path` match `pattern`, where `*` matches any run of characters?
+pub(crate) fn glob(pattern: &str, path: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = path.strip_prefix(first) else { return false };
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else { return rest.is_empty() };
+    for part in middle {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use ds_types::UserId;
+
+    fn user(roles: &[&str]) -> AuthUser {
+        AuthUser {
+            user_id: UserId::generate(),
+            email: None,
+            roles: roles.iter().map(|r| r.to_string()).collect(),
+            scopes: Vec::new(),
+            org_id: None,
+            session_id: None,
+            api_key: None,
+            impersonated_by: None,
+        }
+    }
+
+    #[test]
+    fn test_glob() {
+        assert!(glob("/v1/admin/*", "/v1/admin/stats"));
+        assert!(!glob("/v1/admin/*", "/v1/administrators"));
+        assert!(glob("/v1/orgs/*/keys", "/v1/orgs/abc/keys"));
+        assert!(!glob("/v1/orgs/*/keys", "/v1/orgs/abc/keys/1"));
+        assert!(glob("/v1/chat", "/v1/chat") && !glob("/v1/chat", "/v1/chat/stream"));
+        assert!(glob("*", "/anything"));
+    }
+
+    #[test]
+    fn test_rule_conditions() {
+        let policy = Policy::parse(
+            r#"[{ "name": "admin-office", "routes": ["/v1/admin/*"], "methods": ["delete"], "roles": ["admin"],
+                  "cidrs": ["10.0.0.0/8", "192.168.1.7"], "days": ["mon", "tue", "wed", "thu", "fri"],
+                  "hours": "08:00-18:00" }]"#,
+        )
+        .unwrap();
+        // Tuesday 10:00 UTC
+        let office = Utc.with_ymd_and_hms(2026, 10, 13, 10, 0, 0).unwrap();
+        let (admin, member) = (user(&["admin"]), user(&[]));
+        let subject = |user, method, ip: &str, at| Subject {
+            user,
+            method,
+            path: "/v1/admin/rate-limits/1.2.3.4",
+            ip: ip.parse().unwrap(),
+            at,
+        };
+        let condition = |s: Subject| policy.check(&s).err().map(|d| d.condition);
+        assert_eq!(condition(subject(&admin, "DELETE", "10.1.2.3", office)), None);
+        assert_eq!(condition(subject(&admin, "DELETE", "192.168.1.7", office)), None);
+        assert_eq!(condition(subject(&member, "DELETE", "10.1.2.3", office)), Some("role"));
+        assert_eq!(condition(subject(&admin, "DELETE", "8.8.8.8", office)), Some("network"));
+        let saturday = Utc.with_ymd_and_hms(2026, 10, 17, 10, 0, 0).unwrap();
+        assert_eq!(condition(subject(&admin, "DELETE", "10.1.2.3", saturday)), Some("day"));
+        let evening = Utc.with_ymd_and_hms(2026, 10, 13, 18, 0, 0).unwrap();
+        assert_eq!(condition(subject(&admin, "DELETE", "10.1.2.3", evening)), Some("hours"));
+        // Other methods are not covered by the rule
+        assert_eq!(condition(subject(&member, "GET", "8.8.8.8", saturday)), None);
+    }
+
+    #[test]
+    fn test_hours_may_wrap_past_midnight() {
+        let policy = Policy::parse(r#"[{ "name": "night", "routes": ["*"], "hours": "22:00-06:00" }]"#).unwrap();
+        let anyone = user(&[]);
+        let at = |h| Subject {
+            user: &anyone,
+            method: "GET",
+            path: "/v1/limits",
+            ip: "127.0.0.1".parse().unwrap(),
+            at: Utc.with_ymd_and_hms(2026, 10, 13, h, 30, 0).unwrap(),
+        };
+        assert!(policy.check(&at(23)).is_ok() && policy.check(&at(5)).is_ok());
+        assert!(policy.check(&at(12)).is_err());
+        assert!(Policy::deny_all().check(&at(12)).is_err());
+    }
+
+    #[test]
+    fn test_invalid_rules_are_rejected() {
+        for json in [
+            r#"[{ "name": "r", "routes": [] }]"#,
+            r#"[{ "name": "r", "routes": ["*"], "cidrs": ["10.0.0.0/33"] }]"#,
+            r#"[{ "name": "r", "routes": ["*"], "days": ["someday"] }]"#,
+            r#"[{ "name": "r", "routes": ["*"], "hours": "9-5" }]"#,
+            r#"[{ "name": "r", "routes": ["*"], "role": ["admin"] }]"#,
+        ] {
+            assert!(Policy::parse(json).is_err(), "{json}");
+        }
+    }
+}
@@ -0,0 +1,388 @@
+//! Per-user consumption limits: the daily token and image quotas
+//! (persisted) and concurrent chat streams (per instance, each listed
+//! for `/v1/admin/debug/tasks`).
+//!
+//! Token usage, the user's and the API key's, is written by
+//! [`UsageWriter`] in batches, so totals trail generations by up to
+//! `USAGE_FLUSH_MS`.
+
+use crate::batch::Batcher;
+use crate::state::AppState;
+use crate::db_metrics::Timed;
+use chrono::{NaiveDate, Utc};
+use dashmap::DashMap;
+use ds_core::config::QuotaSection;
+use ds_notify::Notification;
+use ds_types::UserId;
+use serde::Serialize;
+use sqlx::PgPool;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+/// Percentages of the daily quota that emit a `quota.threshold` event
+pub const THRESHOLDS: [u64; 2] = [80, 95];
+
+/// Add generated tokens to today's (UTC) total for `user_id`, returning
+/// the new total
+pub async fn record_tokens(db: &PgPool, user_id: UserId, tokens: u64) -> sqlx::Result<u64> {
+    let total: i64 = sqlx::query_scalar(
+        "INSERT INTO token_usage_daily (user_id, day, tokens) \
+         VALUES ($1, (NOW() AT TIME ZONE 'UTC')::date, $2) \
+         ON CONFLICT (user_id, day) DO UPDATE SET tokens = token_usage_daily.tokens + EXCLUDED.tokens \
+         RETURNING tokens",
+    )
+    .bind(user_id)
+    .bind(tokens as i64)
+    .fetch_one(db)
+    .await?;
+    Ok(total as u64)
+}
+
+/// Thresholds passed when usage went from `before` to `after`
+pub fn crossed(daily: u64, before: u64, after: u64) -> impl Iterator<Item = u64> {
+    THRESHOLDS
+        .into_iter()
+        .filter(move |pct| daily > 0 && before * 100 < pct * daily && after * 100 >= pct * daily)
+}
+
+#[derive(Serialize)]
+struct ThresholdEvent {
+    event: &'static str,
+    user_id: UserId,
+    threshold_percent: u64,
+    used_tokens: u64,
+    daily_tokens: u64,
+}
+
+/// Tokens to add to a user's daily total, queued for [`write_tokens`]
+pub struct TokenUsage {
+    pub user_id: UserId,
+    pub day: NaiveDate,
+    pub tokens: u64,
+    /// The deployment's quota, or the user's org's, when it was used
+    pub quota: QuotaSection,
+}
+
+/// Usage rows, written in batches off the request path
+pub struct UsageWriter {
+    pub tokens: Batcher<TokenUsage>,
+    pub keys: Batcher<crate::api_keys::KeyUsage>,
+}
+
+impl UsageWriter {
+    pub fn from_config(cfg: &QuotaSection, metrics: Arc<crate::metrics::Metrics>) -> Self {
+        let interval = Duration::from_millis(cfg.usage_flush_ms);
+        let (size, queue) = (cfg.usage_batch_size, cfg.usage_queue);
+        Self {
+            tokens: Batcher::new("token_usage", size, interval, queue, metrics.clone()),
+            keys: Batcher::new("api_key_usage", size, interval, queue, metrics),
+        }
+    }
+
+    /// Start writing; rows queued until then are kept
+    pub fn start(&self, state: &AppState) {
+        let db = state.db.clone();
+        self.keys.start(move |rows| {
+            let db = db.clone();
+            async move { Ok(crate::api_keys::record_usage(&db, &rows).await?) }
+        });
+        let state = state.clone();
+        self.tokens.start(move |rows| {
+            let state = state.clone();
+            async move { write_tokens(&state, rows).await }
+        });
+    }
+
+    /// Write everything queued; rows recorded afterwards are written
+    /// directly
+    pub async fn drain(&self) {
+        tokio::join!(self.tokens.drain(), self.keys.drain());
+    }
+}
+
+/// Queue `tokens` of usage; once written, `quota.threshold` is emitted for
+/// every threshold of `quota` (the deployment's, or the user's org's) it
+/// crossed, once per user and UTC day, and the user is sent a
+/// `quota.warning` notification at the same time
+pub async fn record(state: &AppState, quota: &QuotaSection, user_id: UserId, tokens: u64) {
+    let day = Utc::now().date_naive();
+    state.usage.tokens.push(TokenUsage { user_id, day, tokens, quota: quota.clone() }).await;
+}
+
+/// Add a batch of usage to the daily totals in one statement, then emit
+/// what each user's total crossed
+async fn write_tokens(state: &AppState, rows: Vec<TokenUsage>) -> anyhow::Result<()> {
+    let mut summed: HashMap<(UserId, NaiveDate), (u64, QuotaSection)> = HashMap::new();
+    for row in rows {
+        let entry = summed.entry((row.user_id, row.day)).or_insert((0, row.quota));
+        entry.0 += row.tokens;
+    }
+    let users: Vec<Uuid> = summed.keys().map(|(user, _)| Uuid::from(*user)).collect();
+    let days: Vec<NaiveDate> = summed.keys().map(|(_, day)| *day).collect();
+    let tokens: Vec<i64> = summed.values().map(|(tokens, _)| *tokens as i64).collect();
+    let totals: Vec<(UserId, NaiveDate, i64)> = sqlx::query_as(
+        "INSERT INTO token_usage_daily (user_id, day, tokens) \
+         SELECT * FROM UNNEST($1::uuid[], $2::date[], $3::bigint[]) \
+         ON CONFLICT (user_id, day) DO UPDATE SET tokens = token_usage_daily.tokens + EXCLUDED.tokens \
+         RETURNING user_id, day, tokens",
+    )
+    .bind(&users)
+    .bind(&days)
+    .bind(&tokens)
+    .fetch_all(&state.db)
+    .timed(&state.metrics, "quota.write_tokens")
+    .await?;
+    for (user_id, day, after) in totals {
+        let Some((added, quota)) = summed.get(&(user_id, day)) else { continue };
+        let after = after as u64;
+        if let Err(e) = notify_crossed(state, quota, user_id, day, after.saturating_sub(*added), after).await {
+            tracing::warn!(error = %e, user_id = %user_id, "quota threshold notification failed");
+        }
+    }
+    Ok(())
+}
+
+/// Emit `quota.threshold` for each threshold passed going from `before`
+/// to `after` that was not already passed on `day`
+async fn notify_crossed(
+    state: &AppState,
+    quota: &QuotaSection,
+    user_id: UserId,
+    day: NaiveDate,
+    before: u64,
+    after: u64,
+) -> sqlx::Result<()> {
+    let db = &state.db;
+    for pct in crossed(quota.daily_tokens, before, after) {
+        let first = sqlx::query(
+            "INSERT INTO quota_notifications (user_id, day, threshold) \
+             VALUES ($1, $2, $3) ON CONFLICT DO NOTHING",
+        )
+        .bind(user_id)
+        .bind(day)
+        .bind(pct as i16)
+        .execute(db)
+        .await?
+        .rows_affected()
+            == 1;
+        if !first {
+            continue;
+        }
+        tracing::warn!(
+            event = "quota.threshold",
+            user_id = %user_id,
+            threshold_percent = pct,
+            used_tokens = after,
+            daily_tokens = quota.daily_tokens,
+            "quota threshold reached"
+        );
+        let warning = Notification::quota_warning(pct, after, quota.daily_tokens);
+        crate::notifications::notify(db, &state.notifier, user_id, warning)
+            .timed(&state.metrics, "notifications.notify")
+            .await?;
+        if !quota.webhook_url.is_empty() {
+            let event = ThresholdEvent {
+                event: "quota.threshold",
+                user_id,
+                threshold_percent: pct,
+                used_tokens: after,
+                daily_tokens: quota.daily_tokens,
+            };
+            let sent = state
+                .http
+                .post(&quota.webhook_url)
+                .timeout(Duration::from_secs(5))
+                .json(&event)
+                .send()
+                .await
+                .and_then(|r| r.error_for_status());
+            if let Err(e) = sent {
+                tracing::warn!(error = %e, user_id = %user_id, "quota webhook failed");
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Tokens `user_id` has generated so far today (UTC)
+pub async fn tokens_today(db: &PgPool, user_id: UserId) -> sqlx::Result<u64> {
+    let used: Option<i64> = sqlx::query_scalar(
+        "SELECT tokens FROM token_usage_daily \
+         WHERE user_id = $1 AND day = (NOW() AT TIME ZONE 'UTC')::date",
+    )
+    .bind(user_id)
+    .fetch_optional(db)
+    .await?;
+    Ok(used.unwrap_or(0) as u64)
+}
+
+/// Add generated images to today's (UTC) count for `user_id`, returning
+/// the new count
+pub async fn record_images(db: &PgPool, user_id: UserId, images: u64) -> sqlx::Result<u64> {
+    let total: i64 = sqlx::query_scalar(
+        "INSERT INTO image_usage_daily (user_id, day, images) \
+         VALUES ($1, (NOW() AT TIME ZONE 'UTC')::date, $2) \
+         ON CONFLICT (user_id, day) DO UPDATE SET images = image_usage_daily.images + EXCLUDED.images \
+         RETURNING images",
+    )
+    .bind(user_id)
+    .bind(images as i64)
+    .fetch_one(db)
+    .await?;
+    Ok(total as u64)
+}
+
+/// Images `user_id` has generated so far today (UTC)
+pub async fn images_today(db: &PgPool, user_id: UserId) -> sqlx::Result<u64> {
+    let used: Option<i64> = sqlx::query_scalar(
+        "SELECT images FROM image_usage_daily \
+         WHERE user_id = $1 AND day = (NOW() AT TIME ZONE 'UTC')::date",
+    )
+    .bind(user_id)
+    .fetch_optional(db)
+    .await?;
+    Ok(used.unwrap_or(0) as u64)
+}
+
+/// Seconds until the daily quota resets at UTC midnight
+pub fn secs_until_reset() -> u64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    86_400 - now % 86_400
+}
+
+/// Chat streams currently open, counted per user
+#[derive(Default)]
+pub struct StreamSlots {
+    open: DashMap<UserId, u64>,
+    active: DashMap<u64, Arc<Active>>,
+    next_id: AtomicU64,
+}
+
+/// What an open stream is doing
+struct Active {
+    user_id: UserId,
+    route: &'static str,
+    model: String,
+    started: Instant,
+    bytes: AtomicU64,
+}
+
+/// An open stream, as listed for operators
+#[derive(Debug, Serialize)]
+pub struct StreamInfo {
+    pub user_id: UserId,
+    pub route: &'static str,
+    pub model: String,
+    pub age_ms: u64,
+    /// Model output passed on so far
+    pub bytes_sent: u64,
+}
+
+impl StreamSlots {
+    /// Claim a slot for a `route` stream from `model`, or `None` when
+    /// `user_id` already has `max` open (0 = unlimited). The slot is
+    /// released when the guard drops.
+    pub fn acquire(self: &Arc<Self>, user_id: UserId, route: &'static str, model: &str, max: u64) -> Option<StreamSlot> {
+        let mut open = self.open.entry(user_id).or_default();
+        if max > 0 && *open >= max {
+            return None;
+        }
+        *open += 1;
+        drop(open);
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let active = Arc::new(Active {
+            user_id,
+            route,
+            model: model.to_string(),
+            started: Instant::now(),
+            bytes: AtomicU64::new(0),
+        });
+        self.active.insert(id, active.clone());
+        Some(StreamSlot { slots: self.clone(), id, active })
+    }
+
+    pub fn in_use(&self, user_id: UserId) -> u64 {
+        self.open.get(&user_id).map_or(0, |n| *n)
+    }
+
+    /// Every open stream, oldest first
+    pub fn list(&self) -> Vec<StreamInfo> {
+        let mut streams: Vec<(Instant, StreamInfo)> = self
+            .active
+            .iter()
+            .map(|a| {
+                let info = StreamInfo {
+                    user_id: a.user_id,
+                    route: a.route,
+                    model: a.model.clone(),
+                    age_ms: a.started.elapsed().as_millis() as u64,
+                    bytes_sent: a.bytes.load(Ordering::Relaxed),
+                };
+                (a.started, info)
+            })
+            .collect();
+        streams.sort_by_key(|(started, _)| *started);
+        streams.into_iter().map(|(_, info)| info).collect()
+    }
+}
+
+pub struct StreamSlot {
+    slots: Arc<StreamSlots>,
+    id: u64,
+    active: Arc<Active>,
+}
+
+impl StreamSlot {
+    /// Count `bytes` of model output passed on to the client
+    pub fn sent(&self, bytes: usize) {
+        self.active.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+}
+
+impl Drop for StreamSlot {
+    fn drop(&mut self) {
+        self.slots.active.remove(&self.id);
+        // Remove the entry at zero so idle users cost nothing
+        self.slots.open.remove_if_mut(&self.active.user_id, |_, open| {
+            *open = open.saturating_sub(1);
+            *open == 0
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_thresholds_fire_once_when_crossed() {
+        assert_eq!(crossed(100, 0, 79).count(), 0);
+        assert_eq!(crossed(100, 79, 80).collect::<Vec<_>>(), [80]);
+        assert_eq!(crossed(100, 10, 120).collect::<Vec<_>>(), [80, 95]);
+        assert_eq!(crossed(100, 80, 94).count(), 0);
+        assert_eq!(crossed(0, 0, 1_000).count(), 0);
+    }
+
+    #[test]
+    fn test_stream_slots() {
+        let slots = Arc::new(StreamSlots::default());
+        let user = UserId::generate();
+        let first = slots.acquire(user, "chat", "m", 2).unwrap();
+        let second = slots.acquire(user, "summarize", "m", 2).unwrap();
+        assert!(slots.acquire(user, "chat", "m", 2).is_none());
+        assert!(slots.acquire(UserId::generate(), "chat", "m", 2).is_some());
+        drop(first);
+        assert_eq!(slots.in_use(user), 1);
+        assert!(slots.acquire(user, "chat", "m", 0).is_some());
+
+        second.sent(5);
+        let listed = slots.list();
+        assert_eq!(listed.len(), 1);
+        assert_eq!((listed[0].route, listed[0].bytes_sent), ("summarize", 5));
+        drop(second);
+        assert!(slots.list().is_empty());
+    }
+}
@@ -1,28 +1,14 @@
-use std::{sync::Arc, time::Instant, net::IpAddr};
+use std::net::IpAddr;
+use axum::{extract::Request, middleware::Next, response::Response, Extension};
 use dashmap::DashMap;
 use ds_core::error::{ApiError, ApiResult};
-use crate::state::AppState;
-
-#[derive(Clone)]
-pub struct TokenBucket { tokens: Arc<tokio::sync::Mutex<(u64, Instant)>>, rate_per_min: u64, burst: u64 }
-
-impl TokenBucket {
-    pub fn new(rate_per_min: u64, burst: u64) -> Self { Self { tokens: Arc::new(tokio::sync::Mutex::new((burst, Instant::now()))), rate_per_min, burst } }
-    pub async fn allow(&self) -> bool {
-        let per_sec = self.rate_per_min as f64 / 60.0;
-        let mut guard = self.tokens.lock().await;
-        let (ref mut available, ref mut last) = *guard;
-        let now = Instant::now();
-        let elapsed = now.duration_since(*last).as_secs_f64();
-        if elapsed > 0.0 {
-            let refill = (per_sec * elapsed) as u64; 
-            if refill > 0 { *available = (*available + refill).min(self.burst); *last = now; }
-        }
-        if *available > 0 { *available -= 1; true } else { false }
-    }
-}
+use crate::{context::RequestContext, slow::{Phase, PhaseTimer}, state::AppState};
+
+// Shared with the bench crate so the limiter can be measured in isolation
+pub use ds_core::rate_limit::TokenBucket;
 
 pub async fn rate_limit(state: &AppState, ip: IpAddr) -> ApiResult<()> {
+    let _timer = PhaseTimer::start(Phase::RateLimit);
     if !state.cfg.rate_limit.enabled { return Ok(()); }
     let key = ip.to_string();
     let entry = state.rate_map.entry(key).or_insert_with(|| TokenBucket::new(state.cfg.rate_limit.requests_per_minute, state.cfg.rate_limit.burst));
@@ -30,4 +16,121 @@ pub async fn rate_limit(state: &AppState, ip: IpAddr) -> ApiResult<()> {
     Ok(())
 }
 
+/// Per-email login throttle, applied whether or not the account exists so
+/// it cannot be used to probe for registered emails
+pub async fn login_attempt(state: &AppState, email: &str) -> ApiResult<()> {
+    let _timer = PhaseTimer::start(Phase::RateLimit);
+    let per_minute = state.cfg.security.login_attempts_per_minute;
+    if per_minute == 0 { return Ok(()); }
+    let key = email.trim().to_lowercase();
+    let entry = state.login_map.entry(key).or_insert_with(|| TokenBucket::new(per_minute, state.cfg.security.login_burst));
+    if !entry.allow().await { return Err(ApiError::RateLimited); }
+    Ok(())
+}
+
+/// Drop every limiter's refilled buckets; run periodically by the
+/// `rate_limit_sweep` job. Keys come from clients (any submitted email, any
+/// source address), so without this the maps grow for the life of the
+/// process. A full bucket behaves exactly like a missing one, so nothing is
+/// forgotten.
+pub fn sweep(state: &AppState) {
+    for (_, map) in limiters(state) {
+        evict_idle(map);
+    }
+}
+
+fn evict_idle(map: &DashMap<String, TokenBucket>) {
+    map.retain(|_, bucket| !bucket.is_idle());
+}
+
+/// Per-IP limit as a route layer, for groups applied with
+/// `middleware::from_fn(rate_limit::per_ip)`
+pub async fn per_ip(
+    Extension(state): Extension<AppState>,
+    ctx: RequestContext,
+    req: Request,
+    next: Next,
+) -> ApiResult<Response> {
+    rate_limit(&state, ctx.ip).await?;
+    Ok(next.run(req).await)
+}
+
+/// One limiter bucket as shown to operators
+#[derive(Debug, serde::Serialize)]
+pub struct BucketInfo {
+    /// `ip` (per-IP request limiter) or `login` (per-email login throttle)
+    pub limiter: &'static str,
+    pub key: String,
+    #[serde(flatten)]
+    pub status: ds_core::rate_limit::BucketStatus,
+}
+
+fn limiters(state: &AppState) -> [(&'static str, &DashMap<String, TokenBucket>); 2] {
+    [("ip", &state.rate_map), ("login", &state.login_map)]
+}
+
+/// Buckets for `key` in every limiter, or all buckets when `key` is `None`
+pub async fn inspect(state: &AppState, key: Option<&str>) -> Vec<BucketInfo> {
+    let mut out = Vec::new();
+    for (limiter, map) in limiters(state) {
+        // Clone out of the map so no shard lock is held across an await
+        let buckets: Vec<(String, TokenBucket)> = match key {
+            Some(key) => map
+                .get(&normalize(limiter, key))
+                .map(|b| (b.key().clone(), b.value().clone()))
+                .into_iter()
+                .collect(),
+            None => map
+                .iter()
+                .map(|b| (b.key().clone(), b.value().clone()))
+                .collect(),
+        };
+        for (key, bucket) in buckets {
+            out.push(BucketInfo {
+                limiter,
+                key,
+                status: bucket.status().await,
+            });
+        }
+    }
+    out.sort_by(|a, b| (a.limiter, &a.key).cmp(&(b.limiter, &b.key)));
+    out
+}
+
+/// Drop `key`'s buckets so its next request starts with a full burst;
+/// returns how many were removed
+pub fn reset(state: &AppState, key: &str) -> usize {
+    limiters(state)
+        .into_iter()
+        .filter(|(limiter, map)| map.remove(&normalize(limiter, key)).is_some())
+        .count()
+}
+
+/// Login buckets are keyed by lowercased email
+fn normalize(limiter: &str, key: &str) -> String {
+    match limiter {
+        "login" => key.trim().to_lowercase(),
+        _ => key.trim().to_string(),
+    }
+}
+
 pub fn _rate_map_len(map: &DashMap<String, TokenBucket>) -> usize { map.len() }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_evict_idle_keeps_drained_buckets() {
+        let map = DashMap::new();
+        for i in 0..100 {
+            map.insert(format!("user{i}@example.com"), TokenBucket::new(5, 1));
+        }
+        let drained = TokenBucket::new(5, 1);
+        assert!(drained.allow().await);
+        map.insert("attacker@example.com".to_string(), drained);
+        evict_idle(&map);
+        assert_eq!(map.len(), 1);
+        assert!(!map.get("attacker@example.com").unwrap().allow().await);
+    }
+}
@@ -0,0 +1,288 @@
+use ds_core::config::AppConfig;
+use ds_model::{ChatChunk, ChatStream};
+use futures_util::StreamExt;
+use regex::{Captures, Regex};
+use std::sync::Arc;
+
+/// A single output redaction rule
+pub struct Rule {
+    pub name: String,
+    pattern: Regex,
+    /// Extra check on the matched text (e.g. Luhn for card numbers)
+    validator: Option<fn(&str) -> bool>,
+    marker: String,
+}
+
+impl Rule {
+    pub fn new(name: impl Into<String>, pattern: Regex, marker: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            pattern,
+            validator: None,
+            marker: marker.into(),
+        }
+    }
+
+    /// Rule matching a literal string verbatim
+    pub fn literal(name: impl Into<String>, text: &str, marker: impl Into<String>) -> Self {
+        Self::new(
+            name,
+            Regex::new(&regex::escape(text)).expect("escaped literal is a valid regex"),
+            marker,
+        )
+    }
+
+    fn with_validator(mut self, validator: fn(&str) -> bool) -> Self {
+        self.validator = Some(validator);
+        self
+    }
+}
+
+fn builtin_rule(name: &str, marker: &str) -> Option<Rule> {
+    let pattern = match name {
+        "api_key" => {
+            r"\b(?:(?:sk|pk|rk)[-_](?:live_|test_|proj-)?[A-Za-z0-9_-]{20,}|ghp_[A-Za-z0-9]{36}|xox[abprs]-[A-Za-z0-9-]{10,})\b"
+        }
+        "aws_key" => r"\b(?:AKIA|ASIA)[0-9A-Z]{16}\b",
+        "jwt" => r"\beyJ[A-Za-z0-9_-]{8,}\.[A-Za-z0-9_-]{8,}\.[A-Za-z0-9_-]{8,}",
+        "card_number" => r"\b(?:\d[ -]?){12,18}\d\b",
+        "email" => r"\b[A-Za-z0-9._%+-]+@[A-Za-z0-9.-]+\.[A-Za-z]{2,}\b",
+        _ => return None,
+    };
+    let rule = Rule::new(name, Regex::new(pattern).expect("valid builtin pattern"), marker);
+    Some(if name == "card_number" {
+        rule.with_validator(luhn_valid)
+    } else {
+        rule
+    })
+}
+
+/// Luhn checksum over the digits of a candidate card number
+fn luhn_valid(candidate: &str) -> bool {
+    let digits: Vec<u32> = candidate.chars().filter_map(|c| c.to_digit(10)).collect();
+    if !(13..=19).contains(&digits.len()) {
+        return false;
+    }
+    let sum: u32 = digits
+        .iter()
+        .rev()
+        .enumerate()
+        .map(|(i, &d)| {
+            if i % 2 == 1 {
+                let dd = d * 2;
+                if dd > 9 {
+                    dd - 9
+                } else {
+                    dd
+                }
+            } else {
+                d
+            }
+        })
+        .sum();
+    sum.is_multiple_of(10)
+}
+
+/// Streaming redaction stage applied between the provider stream and the
+/// response encoder.
+pub struct Redactor {
+    rules: Vec<Rule>,
+    holdback: usize,
+}
+
+impl Redactor {
+    pub fn new(rules: Vec<Rule>, holdback: usize) -> Self {
+        Self { rules, holdback }
+    }
+
+    /// Build the configured DLP rules plus the system-prompt shield.
+    pub fn from_config(cfg: &AppConfig) -> Self {
+        let mut rules = crate::guard::shield_rules(&cfg.guard.system_prompt);
+        // Literal fragments must be fully buffered before they can match
+        let mut holdback = rules
+            .iter()
+            .map(|r| r.pattern.as_str().len())
+            .max()
+            .unwrap_or(0);
+
+        let red = &cfg.redaction;
+        if red.enabled {
+            for name in red.rules.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+                match builtin_rule(name, &red.marker) {
+                    Some(rule) => rules.push(rule),
+                    None => tracing::warn!(rule = %name, "unknown redaction rule ignored"),
+                }
+            }
+            for (idx, pattern) in red.custom_patterns.split_whitespace().enumerate() {
+                match Regex::new(pattern) {
+                    Ok(re) => rules.push(Rule::new(format!("custom_{idx}"), re, red.marker.clone())),
+                    Err(e) => tracing::warn!(error = %e, index = idx, "invalid redaction pattern ignored"),
+                }
+            }
+            holdback = holdback.max(red.holdback_bytes as usize);
+        }
+        Self::new(rules, holdback)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Redact `pending` and return it with the byte offset up to which the
+    /// text is safe to emit. Unless `done`, the tail is held back and any
+    /// match touching the end is deferred since it may still grow.
+    fn scrub(&self, pending: &str, done: bool) -> (String, usize) {
+        let mut text = pending.to_string();
+        for rule in &self.rules {
+            let len = text.len();
+            let replaced = rule.pattern.replace_all(&text, |caps: &Captures| {
+                let m = caps.get(0).expect("whole match");
+                let deferred = !done && m.end() == len;
+                let valid = rule.validator.is_none_or(|v| v(m.as_str()));
+                if deferred || !valid {
+                    m.as_str().to_string()
+                } else {
+                    tracing::debug!(rule = %rule.name, "output redacted");
+                    rule.marker.clone()
+                }
+            });
+            text = replaced.into_owned();
+        }
+        if done {
+            let len = text.len();
+            return (text, len);
+        }
+        let mut cut = char_floor(&text, text.len().saturating_sub(self.holdback));
+        for rule in &self.rules {
+            for m in rule.pattern.find_iter(&text) {
+                if m.end() == text.len() {
+                    cut = cut.min(m.start());
+                }
+            }
+        }
+        (text, cut)
+    }
+
+    /// Wrap a provider stream with this redaction stage.
+    pub fn filter(self: &Arc<Self>, stream: ChatStream) -> ChatStream {
+        if self.is_empty() {
+            return stream;
+        }
+        let redactor = self.clone();
+        Box::pin(async_stream::stream! {
+            let mut stream = stream;
+            let mut pending = String::new();
+            let mut model: Arc<str> = Arc::from("");
+            while let Some(item) = stream.next().await {
+                let chunk = match item {
+                    Ok(chunk) => chunk,
+                    Err(e) => {
+                        yield Err(e);
+                        continue;
+                    }
+                };
+                model.clone_from(&chunk.model);
+                pending.push_str(&chunk.content);
+                let (mut text, cut) = redactor.scrub(&pending, chunk.done);
+                pending = text.split_off(cut);
+                // Tool calls pass even while the text beside them is held back
+                if text.is_empty() && !chunk.done && chunk.tool_calls.is_empty() {
+                    continue;
+                }
+                yield Ok(ChatChunk { content: text, ..chunk });
+            }
+            if !pending.is_empty() {
+                let (text, _) = redactor.scrub(&pending, true);
+                yield Ok(ChatChunk { model, content: text, ..Default::default() });
+            }
+        })
+    }
+}
+
+fn char_floor(s: &str, mut idx: usize) -> usize {
+    while idx > 0 && !s.is_char_boundary(idx) {
+        idx -= 1;
+    }
+    idx
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunked(pieces: &[&str]) -> ChatStream {
+        let chunks: Vec<ds_model::ModelResult<ChatChunk>> = pieces
+            .iter()
+            .enumerate()
+            .map(|(i, p)| {
+                Ok(ChatChunk {
+                    model: "m".into(),
+                    content: p.to_string(),
+                    done: i == pieces.len() - 1,
+                    ..Default::default()
+                })
+            })
+            .collect();
+        Box::pin(futures_util::stream::iter(chunks))
+    }
+
+    async fn run(redactor: Redactor, pieces: &[&str]) -> String {
+        Arc::new(redactor)
+            .filter(chunked(pieces))
+            .map(|c| c.unwrap().content)
+            .collect::<Vec<_>>()
+            .await
+            .concat()
+    }
+
+    fn dlp(rules: &[&str]) -> Redactor {
+        let rules = rules
+            .iter()
+            .map(|n| builtin_rule(n, "[REDACTED]").unwrap())
+            .collect();
+        Redactor::new(rules, 64)
+    }
+
+    #[test]
+    fn test_luhn() {
+        assert!(luhn_valid("4111 1111 1111 1111"));
+        assert!(luhn_valid("5500-0000-0000-0004"));
+        assert!(!luhn_valid("4111 1111 1111 1112"));
+        assert!(!luhn_valid("1234"));
+    }
+
+    #[tokio::test]
+    async fn test_redacts_key_split_across_chunks() {
+        let out = run(
+            dlp(&["aws_key"]),
+            &["your key is AKIA", "IOSFODNN7", "EXAMPLE and more", ""],
+        )
+        .await;
+        assert_eq!(out, "your key is [REDACTED] and more");
+    }
+
+    #[tokio::test]
+    async fn test_redacts_card_but_not_arbitrary_digits() {
+        let out = run(
+            dlp(&["card_number"]),
+            &["pay with 4111 1111 ", "1111 1111 today; order 1234567890123 ok", ""],
+        )
+        .await;
+        assert_eq!(out, "pay with [REDACTED] today; order 1234567890123 ok");
+    }
+
+    #[tokio::test]
+    async fn test_growing_match_is_not_partially_emitted() {
+        // A key longer than the holdback window must still be redacted whole
+        let key = format!("sk-live_{}", "a".repeat(120));
+        let (head, tail) = key.split_at(40);
+        let out = run(dlp(&["api_key"]), &["token: ", head, tail, " end", ""]).await;
+        assert_eq!(out, "token: [REDACTED] end");
+    }
+
+    #[tokio::test]
+    async fn test_passthrough_without_matches() {
+        let out = run(dlp(&["api_key", "jwt"]), &["héllo ", "wörld ✓", ""]).await;
+        assert_eq!(out, "héllo wörld ✓");
+    }
+}
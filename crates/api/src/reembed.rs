@@ -0,0 +1,282 @@
+//! Re-embedding chunks whose embedding went stale.
+//!
+//! Every chunk records the model and `documents.embedding_version` it was
+//! embedded with. One whose model is no longer its collection's (or
+//! `documents.embedding_model` outside collections), or whose version is
+//! not the configured one, cannot be compared with a query embedded now:
+//! [vector retrieval](crate::retrieval) leaves it out, counts it, and
+//! queues a run here for its scope, as does changing a collection's model.
+//!
+//! A run covers one collection, or one user's documents outside any. The
+//! `reembed` job claims runs oldest first and re-embeds their stale chunks
+//! `documents.reembed_batch` at a time, recording how many it has done
+//! after each batch, until none is left. A run whose embedding fails is
+//! retried on the job's next tick, up to `documents.max_attempts` times; one
+//! left `running` by an instance that died is taken over after
+//! `documents.process_timeout_secs`.
+
+use crate::{
+    db_metrics::{self, Timed},
+    state::AppState,
+};
+use chrono::{DateTime, Utc};
+use ds_types::{CollectionId, DocumentId, UserId};
+use serde::Serialize;
+use sqlx::{Connection, Row};
+use uuid::Uuid;
+
+/// Stale chunks left out of vector searches
+pub const STALE: &str = "deepersensor_document_stale_chunks_total";
+/// Chunks re-embedded by the `reembed` job
+pub const REEMBEDDED: &str = "deepersensor_document_chunks_reembedded_total";
+
+/// The chunks a run re-embeds
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Target {
+    Collection(CollectionId),
+    /// The user's documents outside any collection
+    Own(UserId),
+}
+
+impl Target {
+    fn columns(&self) -> (Option<CollectionId>, Option<UserId>) {
+        match *self {
+            Target::Collection(id) => (Some(id), None),
+            Target::Own(user) => (None, Some(user)),
+        }
+    }
+}
+
+/// A run and how far it has got
+#[derive(Debug, Serialize)]
+pub struct Progress {
+    pub id: Uuid,
+    /// `pending`, `running`, `done`, or `failed`
+    pub status: String,
+    /// Stale chunks found, including any found after the run was queued
+    pub total: i32,
+    /// Of those, re-embedded so far
+    pub done: i32,
+    /// Stale chunks in its scope now
+    pub remaining: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub finished_at: Option<DateTime<Utc>>,
+}
+
+/// Whether chunk `c` (of a document joined to its collection as `k`) is
+/// stale, given the default model and the version bound as these params
+pub(crate) fn stale(model: &str, version: &str) -> String {
+    format!("(c.embedding_model <> COALESCE(k.embedding_model, {model}) OR c.embedding_version <> {version})")
+}
+
+/// Chunks of the collection `$1`, else of user `$2`'s documents outside
+/// any collection
+const CHUNKS: &str = "FROM document_chunks c JOIN documents d ON d.id = c.document_id \
+    LEFT JOIN collections k ON k.id = d.collection_id \
+    WHERE (d.collection_id = $1 OR ($1::UUID IS NULL AND d.collection_id IS NULL AND d.user_id = $2))";
+
+/// Queue re-embedding `target`'s stale chunks, if it has any; a run already
+/// queued or running takes in those found since. Returns whether any are
+pub async fn enqueue(state: &AppState, target: Target) -> sqlx::Result<bool> {
+    let cfg = &state.config().documents;
+    let (collection, user) = target.columns();
+    let stale: i64 = sqlx::query_scalar(&format!("SELECT COUNT(*) {CHUNKS} AND {}", stale("$3", "$4")))
+        .bind(collection)
+        .bind(user)
+        .bind(&cfg.embedding_model)
+        .bind(&cfg.embedding_version)
+        .fetch_one(&state.db)
+        .await?;
+    if stale == 0 {
+        return Ok(false);
+    }
+    sqlx::query(
+        "INSERT INTO reembeddings (id, collection_id, user_id, total) VALUES ($1, $2, $3, $4) \
+         ON CONFLICT ((COALESCE(collection_id, user_id))) WHERE status IN ('pending', 'running') \
+         DO UPDATE SET total = reembeddings.done + EXCLUDED.total, updated_at = NOW()",
+    )
+    .bind(Uuid::new_v4())
+    .bind(collection)
+    .bind(user)
+    .bind(stale as i32)
+    .execute(&state.db)
+    .await?;
+    Ok(true)
+}
+
+/// The latest run for `target`
+pub async fn latest(state: &AppState, target: Target) -> sqlx::Result<Option<Progress>> {
+    let cfg = &state.config().documents;
+    let (collection, user) = target.columns();
+    let row = sqlx::query(&format!(
+        "SELECT r.id, r.status, r.total, r.done, r.error, r.created_at, r.updated_at, r.finished_at, \
+         (SELECT COUNT(*) {CHUNKS} AND {}) AS remaining \
+         FROM reembeddings r WHERE COALESCE(r.collection_id, r.user_id) = COALESCE($1, $2) \
+         ORDER BY r.created_at DESC LIMIT 1",
+        stale("$3", "$4")
+    ))
+    .bind(collection)
+    .bind(user)
+    .bind(&cfg.embedding_model)
+    .bind(&cfg.embedding_version)
+    .fetch_optional(&state.db)
+    .await?;
+    let Some(row) = row else { return Ok(None) };
+    Ok(Some(Progress {
+        id: row.try_get("id")?,
+        status: row.try_get("status")?,
+        total: row.try_get("total")?,
+        done: row.try_get("done")?,
+        remaining: row.try_get("remaining")?,
+        error: row.try_get("error")?,
+        created_at: row.try_get("created_at")?,
+        updated_at: row.try_get("updated_at")?,
+        finished_at: row.try_get("finished_at")?,
+    }))
+}
+
+/// A run claimed by this instance
+struct Claimed {
+    id: Uuid,
+    target: Target,
+    /// Also fences progress: only the latest claim may record any
+    attempts: i32,
+}
+
+/// Why a run stopped short
+#[derive(Debug, thiserror::Error)]
+enum Failure {
+    #[error("embedding failed: {0}")]
+    Embed(#[from] ds_model::ModelError),
+    #[error(transparent)]
+    Db(#[from] sqlx::Error),
+}
+
+/// Work through queued runs, returning how many chunks were re-embedded
+pub async fn run_pending(state: &AppState) -> anyhow::Result<usize> {
+    let mut reembedded = 0;
+    while let Some(run) = claim(state).await? {
+        match reembed(state, &run, &mut reembedded).await {
+            Ok(()) => {}
+            Err(Failure::Embed(e)) => {
+                // A model that is down fails every run alike; wait a tick
+                fail(state, &run, &e.to_string()).await?;
+                break;
+            }
+            Err(Failure::Db(e)) => return Err(e.into()),
+        }
+    }
+    Ok(reembedded)
+}
+
+async fn claim(state: &AppState) -> sqlx::Result<Option<Claimed>> {
+    let timeout = state.config().documents.process_timeout_secs as f64;
+    let row = sqlx::query(
+        "UPDATE reembeddings SET status = 'running', claimed_at = NOW(), updated_at = NOW(), attempts = attempts + 1 \
+         WHERE id = (SELECT id FROM reembeddings WHERE status = 'pending' \
+                     OR (status = 'running' AND claimed_at < NOW() - make_interval(secs => $1)) \
+                     ORDER BY created_at LIMIT 1 FOR UPDATE SKIP LOCKED) \
+         RETURNING id, collection_id, user_id, attempts",
+    )
+    .bind(timeout)
+    .fetch_optional(&state.db)
+    .timed(&state.metrics, "reembed.claim")
+    .await?;
+    let Some(row) = row else { return Ok(None) };
+    let target = match row.try_get::<Option<CollectionId>, _>("collection_id")? {
+        Some(id) => Target::Collection(id),
+        None => Target::Own(row.try_get("user_id")?),
+    };
+    Ok(Some(Claimed { id: row.try_get("id")?, target, attempts: row.try_get("attempts")? }))
+}
+
+/// Re-embed `run`'s stale chunks a batch at a time until none is left or
+/// the run is taken over
+async fn reembed(state: &AppState, run: &Claimed, reembedded: &mut usize) -> Result<(), Failure> {
+    let cfg = &state.config().documents;
+    let (collection, user) = run.target.columns();
+    loop {
+        let batch: Vec<(DocumentId, i32, String, String)> = sqlx::query_as(&format!(
+            "SELECT c.document_id, c.ordinal, c.content, COALESCE(k.embedding_model, $3) {CHUNKS} AND {} \
+             ORDER BY c.document_id, c.ordinal LIMIT $5",
+            stale("$3", "$4")
+        ))
+        .bind(collection)
+        .bind(user)
+        .bind(&cfg.embedding_model)
+        .bind(&cfg.embedding_version)
+        .bind(i64::from(cfg.reembed_batch.max(1)))
+        .fetch_all(&state.db)
+        .await?;
+        if batch.is_empty() {
+            sqlx::query(
+                "UPDATE reembeddings SET status = 'done', error = NULL, updated_at = NOW(), finished_at = NOW() \
+                 WHERE id = $1 AND attempts = $2 AND status = 'running'",
+            )
+            .bind(run.id)
+            .bind(run.attempts)
+            .execute(&state.db)
+            .await?;
+            tracing::info!(reembedding_id = %run.id, target = ?run.target, "reembedding.done");
+            return Ok(());
+        }
+        let mut embeddings = Vec::with_capacity(batch.len());
+        for (_, _, content, model) in &batch {
+            embeddings.push(state.provider.embed(model, content).await?);
+        }
+        let mut conn = db_metrics::acquire(&state.metrics, &state.db).await?;
+        let mut tx = conn.begin().await?;
+        let current = sqlx::query(
+            "UPDATE reembeddings SET done = done + $3, claimed_at = NOW(), updated_at = NOW() \
+             WHERE id = $1 AND attempts = $2 AND status = 'running'",
+        )
+        .bind(run.id)
+        .bind(run.attempts)
+        .bind(batch.len() as i32)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+        if current == 0 {
+            // Taken over meanwhile; the newer claim carries on
+            return Ok(());
+        }
+        for ((document, ordinal, _, model), embedding) in batch.iter().zip(&embeddings) {
+            sqlx::query(
+                "UPDATE document_chunks SET embedding = $3::real[]::vector, embedding_model = $4, \
+                 embedding_version = $5 WHERE document_id = $1 AND ordinal = $2",
+            )
+            .bind(document)
+            .bind(ordinal)
+            .bind(embedding)
+            .bind(model)
+            .bind(&cfg.embedding_version)
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+        state.metrics.add(REEMBEDDED, &[], batch.len() as u64);
+        *reembedded += batch.len();
+    }
+}
+
+async fn fail(state: &AppState, run: &Claimed, error: &str) -> sqlx::Result<()> {
+    let gives_up = run.attempts as u32 >= state.config().documents.max_attempts;
+    sqlx::query(
+        "UPDATE reembeddings SET status = $3, error = $4, updated_at = NOW(), \
+         finished_at = CASE WHEN $5 THEN NOW() END WHERE id = $1 AND attempts = $2 AND status = 'running'",
+    )
+    .bind(run.id)
+    .bind(run.attempts)
+    .bind(if gives_up { "failed" } else { "pending" })
+    .bind(error)
+    .bind(gives_up)
+    .execute(&state.db)
+    .await?;
+    let retry = !gives_up;
+    tracing::warn!(reembedding_id = %run.id, target = ?run.target, error = %error, retry, "reembedding failed");
+    Ok(())
+}
@@ -0,0 +1,757 @@
+//! Operator-facing endpoints: the Prometheus scrape target and the
+//! admin-only `/v1/admin` API
+
+use crate::{
+    analytics::{self, Point},
+    auth_middleware::{require_admin, require_auth, require_scope, AuthUser},
+    db_metrics::Timed,
+    db_router::ReplicaInfo,
+    extract::{rules, ValidatedJson},
+    generations::{self, Generation, OutputDigest, RunningInfo},
+    guard,
+    migrations::{self, Applied, Schema},
+    observability::{self, LogLevel, LogLevels},
+    quota::StreamInfo,
+    rate_limit::{self, BucketInfo},
+    semantic_cache,
+    state::AppState,
+};
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    middleware,
+    response::IntoResponse,
+    routing::{delete, get, post},
+    Extension, Json, Router,
+};
+use ds_auth::scope;
+use ds_core::error::{ApiError, ApiResult, FieldError};
+use ds_model::{ChatMessage, ChatRequest, FINISH_STOP};
+use ds_types::{GenerationId, UserId};
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use sqlx::Row;
+use validator::Validate;
+use std::{collections::BTreeMap, time::Duration};
+
+pub fn router() -> Router<AppState> {
+    let admin = Router::new()
+        .route("/v1/admin/stats", get(stats))
+        .route("/v1/admin/stats/history", get(stats_history))
+        .route("/v1/admin/config", get(config))
+        .route("/v1/admin/rate-limits", get(rate_limits))
+        .route("/v1/admin/rate-limits/{key}", delete(reset_rate_limit))
+        .route("/v1/admin/generations", get(running_generations))
+        .route("/v1/admin/generations/{id}", delete(cancel_generation))
+        .route("/v1/admin/generations/{id}/replay", post(replay_generation))
+        .route("/v1/admin/semantic-cache", delete(flush_semantic_cache))
+        .route("/v1/admin/impersonate/{user_id}", post(impersonate))
+        .route("/v1/admin/log-level", get(log_level).put(set_log_level).delete(reset_log_level))
+        .route("/v1/admin/debug/tasks", get(debug_tasks))
+        .route("/v1/admin/db", get(db))
+        .route_layer(middleware::from_fn(require_admin))
+        .route_layer(middleware::from_fn(|req, next| require_scope(scope::ADMIN_ALL, req, next)))
+        .route_layer(middleware::from_fn(require_auth));
+
+    Router::new().route("/metrics", get(metrics)).merge(admin)
+}
+
+async fn metrics(State(state): State<AppState>) -> impl IntoResponse {
+    let mut output = String::new();
+    crate::build_info::render_info_metric(&mut output);
+
+    output.push_str("\n# HELP deepersensor_db_pool_size Database connection pool size\n");
+    output.push_str("# TYPE deepersensor_db_pool_size gauge\n");
+    output.push_str(&format!(
+        "deepersensor_db_pool_size{{}} {}\n",
+        state.db.size()
+    ));
+
+    output.push_str("\n# HELP deepersensor_db_pool_idle Idle database connections\n");
+    output.push_str("# TYPE deepersensor_db_pool_idle gauge\n");
+    output.push_str(&format!(
+        "deepersensor_db_pool_idle{{}} {}\n",
+        state.db.num_idle()
+    ));
+
+    output.push_str("\n# HELP deepersensor_rate_limit_buckets Active rate limit buckets\n");
+    output.push_str("# TYPE deepersensor_rate_limit_buckets gauge\n");
+    output.push_str(&format!(
+        "deepersensor_rate_limit_buckets{{}} {}\n",
+        state.rate_map.len()
+    ));
+
+    state.metrics.render(&mut output);
+
+    (StatusCode::OK, output)
+}
+
+#[derive(Serialize)]
+struct StatsOut {
+    users: u64,
+    active_sessions: u64,
+    requests_last_hour: RequestStats,
+    chat: ChatStats,
+    queues: QueueStats,
+}
+
+#[derive(Serialize)]
+struct RequestStats {
+    total: u64,
+    /// 5xx responses
+    errors: u64,
+    error_rate: f64,
+}
+
+#[derive(Serialize)]
+struct ChatStats {
+    /// Since process start, by finish reason
+    streams: BTreeMap<String, u64>,
+    /// Share of streams that ended in an upstream error
+    error_rate: f64,
+    tokens_by_model: BTreeMap<String, u64>,
+}
+
+#[derive(Serialize)]
+struct QueueStats {
+    streams_open: i64,
+    stream_buffered_chunks: i64,
+    db_pool_size: u32,
+    db_pool_idle: usize,
+}
+
+/// Aggregate counts for the internal dashboard. Database figures are
+/// exact; request and chat figures are per instance.
+async fn stats(State(state): State<AppState>) -> ApiResult<Json<StatsOut>> {
+    let (users, active_sessions): (i64, i64) = sqlx::query_as(
+        "SELECT (SELECT COUNT(*) FROM users), \
+         (SELECT COUNT(*) FROM sessions WHERE revoked_at IS NULL AND expires_at > NOW())",
+    )
+    .fetch_one(&state.db)
+    .await
+    .map_err(|e| {
+        tracing::error!(error = %e, "admin stats query failed");
+        ApiError::Internal
+    })?;
+
+    let metrics = &state.metrics;
+    let (total, errors) = metrics.requests.last_hour();
+    let streams = metrics.sum_by("deepersensor_chat_streams_total", "finish_reason");
+    let stream_total: u64 = streams.values().sum();
+    let stream_errors = streams.get(ds_model::FINISH_ERROR).copied().unwrap_or(0);
+
+    Ok(Json(StatsOut {
+        users: users as u64,
+        active_sessions: active_sessions as u64,
+        requests_last_hour: RequestStats { total, errors, error_rate: ratio(errors, total) },
+        chat: ChatStats {
+            error_rate: ratio(stream_errors, stream_total),
+            streams,
+            tokens_by_model: metrics.sum_by("deepersensor_chat_tokens_total", "model"),
+        },
+        queues: QueueStats {
+            streams_open: metrics.gauge("deepersensor_streams_open"),
+            stream_buffered_chunks: metrics.gauge("deepersensor_stream_buffered_chunks"),
+            db_pool_size: state.db.size(),
+            db_pool_idle: state.db.num_idle(),
+        },
+    }))
+}
+
+#[derive(Deserialize)]
+struct HistoryQuery {
+    /// One of `ANALYTICS_METRICS`
+    metric: String,
+    /// Label to break the counts down by (`model`); omitted sums them
+    by: Option<String>,
+    /// `day` (default) or `hour`
+    granularity: Option<String>,
+    /// Defaults to 30 days before `to`
+    from: Option<chrono::DateTime<chrono::Utc>>,
+    /// Defaults to now
+    to: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[derive(Serialize)]
+struct HistoryOut {
+    metric: String,
+    granularity: String,
+    by: Option<String>,
+    points: Vec<Point>,
+}
+
+/// A counter's history as kept by the analytics job, summed over
+/// instances
+async fn stats_history(
+    State(state): State<AppState>,
+    Query(query): Query<HistoryQuery>,
+) -> ApiResult<Json<HistoryOut>> {
+    let granularity = query.granularity.unwrap_or_else(|| "day".into());
+    let to = query.to.unwrap_or_else(chrono::Utc::now);
+    let from = query.from.unwrap_or(to - chrono::Duration::days(30));
+    let mut fields = Vec::new();
+    if !state.cfg.analytics.tracks(&query.metric) {
+        fields.push(FieldError {
+            field: "metric".into(),
+            code: "metric".into(),
+            message: "not one of ANALYTICS_METRICS".into(),
+        });
+    }
+    if !matches!(granularity.as_str(), "hour" | "day") {
+        fields.push(FieldError {
+            field: "granularity".into(),
+            code: "granularity".into(),
+            message: "hour or day".into(),
+        });
+    }
+    if from >= to {
+        fields.push(FieldError { field: "from".into(), code: "range".into(), message: "must be before to".into() });
+    }
+    if !fields.is_empty() {
+        return Err(ApiError::Validation(fields));
+    }
+    let points = analytics::history(state.db_router.read(), &query.metric, query.by.as_deref(), &granularity, from, to)
+        .timed(&state.metrics, "analytics.history")
+        .await
+        .map_err(|e| {
+            tracing::error!(error = %e, "stats history query failed");
+            ApiError::Internal
+        })?;
+    Ok(Json(HistoryOut { metric: query.metric, granularity, by: query.by, points }))
+}
+
+#[derive(Serialize)]
+struct ConfigOut {
+    config: serde_json::Value,
+    sources: Vec<ds_core::config::SettingSource>,
+}
+
+/// The effective config (secrets masked) and where each setting came from
+async fn config(State(state): State<AppState>) -> Json<ConfigOut> {
+    Json(ConfigOut {
+        config: state.cfg.masked(),
+        sources: state.cfg.sources.clone(),
+    })
+}
+
+#[derive(Deserialize)]
+struct RateLimitQuery {
+    /// An IP or an email; omitted lists every bucket
+    key: Option<String>,
+}
+
+/// Limiter buckets on this instance, for diagnosing a throttled customer
+async fn rate_limits(
+    State(state): State<AppState>,
+    Query(query): Query<RateLimitQuery>,
+) -> Json<Vec<BucketInfo>> {
+    Json(rate_limit::inspect(&state, query.key.as_deref()).await)
+}
+
+/// Clear every bucket for `key` on this instance
+async fn reset_rate_limit(
+    State(state): State<AppState>,
+    Path(key): Path<String>,
+) -> ApiResult<StatusCode> {
+    let removed = rate_limit::reset(&state, &key);
+    tracing::info!(key = %key, removed, "rate limit buckets reset by admin");
+    if removed == 0 {
+        return Err(ApiError::NotFound);
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Deserialize)]
+struct FlushQuery {
+    /// Only entries generated with this model; omitted flushes everything
+    model: Option<String>,
+}
+
+#[derive(Serialize)]
+struct FlushOut {
+    flushed: u64,
+}
+
+async fn flush_semantic_cache(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Query(query): Query<FlushQuery>,
+) -> ApiResult<Json<FlushOut>> {
+    let flushed = semantic_cache::flush(&state.db, query.model.as_deref())
+        .timed(&state.metrics, "semantic_cache.flush")
+        .await
+        .map_err(|e| {
+            tracing::error!(error = %e, "semantic cache flush failed");
+            ApiError::Internal
+        })?;
+    tracing::info!(by = %user.user_id, model = ?query.model, flushed, "audit.semantic_cache.flushed");
+    Ok(Json(FlushOut { flushed }))
+}
+
+/// The live log filter; absent when something other than `init_tracing`
+/// set up logging, as in tests
+fn live_log_levels() -> ApiResult<&'static std::sync::Arc<LogLevels>> {
+    observability::log_levels().ok_or(ApiError::NotFound)
+}
+
+async fn log_level() -> ApiResult<Json<LogLevel>> {
+    Ok(Json(live_log_levels()?.current()))
+}
+
+#[derive(Deserialize, Validate)]
+struct LogLevelIn {
+    /// `RUST_LOG` syntax, e.g. `info,api=debug,sqlx=warn`
+    #[validate(custom(function = "log_directives"))]
+    directives: String,
+    /// Revert to the startup filter after this long, 0 never; defaults to
+    /// `LOG_LEVEL_REVERT_SECS`
+    revert_after_secs: Option<u64>,
+}
+
+fn log_directives(value: &str) -> Result<(), validator::ValidationError> {
+    tracing_subscriber::EnvFilter::try_new(value)
+        .map(drop)
+        .map_err(|e| validator::ValidationError::new("directives").with_message(e.to_string().into()))
+}
+
+async fn set_log_level(
+    Extension(user): Extension<AuthUser>,
+    ValidatedJson(input): ValidatedJson<LogLevelIn>,
+) -> ApiResult<Json<LogLevel>> {
+    let levels = live_log_levels()?;
+    // Before the change, which may filter out info lines
+    tracing::info!(
+        by = %user.user_id,
+        directives = %input.directives,
+        revert_after_secs = ?input.revert_after_secs,
+        "audit.log_level.changed"
+    );
+    let level = levels.set(&input.directives, input.revert_after_secs.map(Duration::from_secs)).map_err(|e| {
+        tracing::error!(error = %e, "log filter rejected after validation");
+        ApiError::Internal
+    })?;
+    Ok(Json(level))
+}
+
+async fn reset_log_level(Extension(user): Extension<AuthUser>) -> ApiResult<Json<LogLevel>> {
+    let levels = live_log_levels()?;
+    let level = levels.reset();
+    tracing::info!(by = %user.user_id, directives = %level.directives, "audit.log_level.reset");
+    Ok(Json(level))
+}
+
+#[derive(Serialize)]
+struct TasksOut {
+    runtime: RuntimeOut,
+    /// Open chat, summarize, and agent streams on this instance
+    streams: Vec<StreamInfo>,
+}
+
+#[derive(Serialize)]
+struct RuntimeOut {
+    workers: usize,
+    alive_tasks: usize,
+    /// Tasks waiting for a worker
+    queued_tasks: usize,
+}
+
+/// A snapshot for diagnosing stuck streams; per-task detail needs
+/// `tokio-console` against a build with the `console` feature
+async fn debug_tasks(State(state): State<AppState>) -> Json<TasksOut> {
+    let metrics = tokio::runtime::Handle::current().metrics();
+    let runtime = RuntimeOut {
+        workers: metrics.num_workers(),
+        alive_tasks: metrics.num_alive_tasks(),
+        queued_tasks: metrics.global_queue_depth(),
+    };
+    Json(TasksOut { runtime, streams: state.streams.list() })
+}
+
+#[derive(Deserialize)]
+struct DbQuery {
+    /// List statements running at least this long; default 1000
+    min_ms: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct DbOut {
+    schema: Schema,
+    migrations: MigrationsOut,
+    pool: PoolOut,
+    /// `DATABASE_REPLICA_URLS` with their last measured lag
+    replicas: Vec<ReplicaInfo>,
+    long_queries: Vec<LongQuery>,
+}
+
+#[derive(Serialize)]
+struct MigrationsOut {
+    /// This build's newest migration
+    expected: i64,
+    applied: Vec<AppliedOut>,
+    pending: Vec<PendingOut>,
+}
+
+#[derive(Serialize)]
+struct AppliedOut {
+    #[serde(flatten)]
+    migration: Applied,
+    /// False when the file changed since it was applied; null when this
+    /// build does not have it
+    checksum_matches: Option<bool>,
+}
+
+#[derive(Serialize)]
+struct PendingOut {
+    version: i64,
+    description: String,
+}
+
+/// This instance's pool
+#[derive(Serialize)]
+struct PoolOut {
+    size: u32,
+    idle: usize,
+    max_connections: u32,
+    min_connections: u32,
+}
+
+#[derive(Serialize)]
+struct LongQuery {
+    pid: i32,
+    state: Option<String>,
+    user: Option<String>,
+    application: Option<String>,
+    client_addr: Option<String>,
+    running_ms: i64,
+    wait_event_type: Option<String>,
+    wait_event: Option<String>,
+    /// First 2000 characters; parameters are not included
+    query: Option<String>,
+}
+
+/// Schema drift, pool use, and slow statements, for diagnosing the
+/// database in production. Long queries are every session's in this
+/// database, not only this instance's; without `pg_read_all_stats` other
+/// users' statements read `<insufficient privilege>`.
+async fn db(State(state): State<AppState>, Query(query): Query<DbQuery>) -> ApiResult<Json<DbOut>> {
+    let failed = |e: sqlx::Error| {
+        tracing::error!(error = %e, "admin db report query failed");
+        ApiError::Internal
+    };
+    let applied = migrations::applied(&state.db).await.map_err(failed)?;
+    let schema = migrations::schema(&state.db).await.map_err(failed)?;
+    let migrator = migrations::migrator();
+    let built: BTreeMap<i64, &[u8]> = migrator.iter().map(|m| (m.version, &*m.checksum)).collect();
+    let pending = migrator
+        .iter()
+        .filter(|m| !applied.iter().any(|a| a.version == m.version && a.success))
+        .map(|m| PendingOut { version: m.version, description: m.description.to_string() })
+        .collect();
+    let applied = applied
+        .into_iter()
+        .map(|migration| {
+            let checksum_matches = built.get(&migration.version).map(|c| *c == migration.checksum.as_slice());
+            AppliedOut { migration, checksum_matches }
+        })
+        .collect();
+    let options = state.db.options();
+    let pool = PoolOut {
+        size: state.db.size(),
+        idle: state.db.num_idle(),
+        max_connections: options.get_max_connections(),
+        min_connections: options.get_min_connections(),
+    };
+    let rows = sqlx::query(
+        "SELECT pid, state, usename, application_name, client_addr::text AS client_addr, \
+         (EXTRACT(EPOCH FROM now() - query_start) * 1000)::bigint AS running_ms, \
+         wait_event_type, wait_event, LEFT(query, 2000) AS query \
+         FROM pg_stat_activity \
+         WHERE datname = current_database() AND pid <> pg_backend_pid() AND state <> 'idle' \
+         AND query_start <= now() - $1 * interval '1 millisecond' \
+         ORDER BY query_start LIMIT 50",
+    )
+    .bind(query.min_ms.unwrap_or(1000).max(0))
+    .fetch_all(&state.db)
+    .await
+    .map_err(failed)?;
+    let long_queries = rows
+        .iter()
+        .map(|row| {
+            Ok(LongQuery {
+                pid: row.try_get("pid")?,
+                state: row.try_get("state")?,
+                user: row.try_get("usename")?,
+                application: row.try_get("application_name")?,
+                client_addr: row.try_get("client_addr")?,
+                running_ms: row.try_get("running_ms")?,
+                wait_event_type: row.try_get("wait_event_type")?,
+                wait_event: row.try_get("wait_event")?,
+                query: row.try_get("query")?,
+            })
+        })
+        .collect::<Result<_, sqlx::Error>>()
+        .map_err(failed)?;
+    let migrations = MigrationsOut { expected: migrator.iter().map(|m| m.version).max().unwrap_or(0), applied, pending };
+    Ok(Json(DbOut { schema, migrations, pool, replicas: state.db_router.replicas(), long_queries }))
+}
+
+/// Generations streaming on this instance, oldest first
+async fn running_generations(State(state): State<AppState>) -> Json<Vec<RunningInfo>> {
+    Json(state.generations.list())
+}
+
+/// Stop a generation streaming on this instance; it ends with a
+/// `cancelled` done frame and is recorded with that finish reason
+async fn cancel_generation(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Path(id): Path<GenerationId>,
+) -> ApiResult<StatusCode> {
+    let owner = state.generations.cancel(id).ok_or(ApiError::NotFound)?;
+    tracing::info!(by = %user.user_id, generation_id = %id, user_id = %owner, "audit.generation.cancelled");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Deserialize, Validate)]
+struct ReplayIn {
+    /// Model to re-run the original request against
+    #[validate(custom(function = "rules::model_name"))]
+    model: String,
+}
+
+#[derive(Serialize)]
+struct ReplayOut {
+    original: ReplaySide,
+    replay: ReplaySide,
+    /// Whether both outputs hash the same
+    same_output: bool,
+    /// The replay's output, after redaction
+    output: String,
+}
+
+#[derive(Serialize)]
+struct ReplaySide {
+    id: GenerationId,
+    model: String,
+    output_hash: String,
+    output_tokens: u64,
+}
+
+impl From<&Generation> for ReplaySide {
+    fn from(g: &Generation) -> Self {
+        Self {
+            id: g.id,
+            model: g.model.clone(),
+            output_hash: g.output_hash.clone(),
+            output_tokens: g.output_tokens,
+        }
+    }
+}
+
+/// Re-run a recorded generation's messages against another model and
+/// compare outputs. The replay is recorded too, owned by the admin and
+/// linked through `replay_of`; it bypasses admission and quota.
+async fn replay_generation(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Path(id): Path<GenerationId>,
+    ValidatedJson(input): ValidatedJson<ReplayIn>,
+) -> ApiResult<Json<ReplayOut>> {
+    let db_error = |e: sqlx::Error| {
+        tracing::error!(error = %e, generation_id = %id, "generation lookup failed");
+        ApiError::Internal
+    };
+    let original = generations::get(&state.db, id)
+        .timed(&state.metrics, "generations.get")
+        .await
+        .map_err(db_error)?
+        .ok_or(ApiError::NotFound)?;
+    let messages: Vec<ChatMessage> =
+        serde_json::from_value(original.request["messages"].clone()).map_err(|e| {
+            tracing::error!(error = %e, generation_id = %id, "stored generation request is malformed");
+            ApiError::Internal
+        })?;
+    let messages = guard::prepare_messages(state.config(), original.user_id, messages)?;
+    // Sampled as the original was; recorded before options existed, by default
+    let options = serde_json::from_value(original.request["options"].clone()).unwrap_or_default();
+
+    let created_at = chrono::Utc::now();
+    let stream = state
+        .provider
+        .chat_stream(ChatRequest { model: input.model.clone(), messages, options, ..Default::default() })
+        .await
+        .map_err(|e| {
+            tracing::error!(error = %e, generation_id = %id, model = %input.model, "replay start failed");
+            ApiError::Internal
+        })?;
+    let mut stream = ds_model::with_terminal_frame(stream, input.model.clone());
+    // Hashed before redaction, like the original
+    let mut digest = OutputDigest::default();
+    let mut chunks = Vec::new();
+    let mut finish_reason = FINISH_STOP.to_string();
+    let mut upstream_headers = BTreeMap::new();
+    while let Some(chunk) = stream.next().await {
+        let mut chunk = chunk.map_err(|e| {
+            tracing::error!(error = %e, generation_id = %id, "replay failed mid-generation");
+            ApiError::Internal
+        })?;
+        if !chunk.upstream_headers.is_empty() {
+            upstream_headers = std::mem::take(&mut chunk.upstream_headers);
+        }
+        digest.update(&chunk.content);
+        if let Some(reason) = &chunk.finish_reason {
+            finish_reason = reason.clone();
+        }
+        chunks.push(Ok(chunk));
+    }
+    let mut output = String::new();
+    let mut redacted = state.redactor.filter(Box::pin(futures_util::stream::iter(chunks)));
+    while let Some(Ok(chunk)) = redacted.next().await {
+        output.push_str(&chunk.content);
+    }
+
+    let replay = Generation {
+        id: GenerationId::generate(),
+        user_id: user.user_id,
+        org_id: None,
+        api_key_id: None,
+        provider: ds_model::resolve(&state.provider, &input.model).0.name().to_string(),
+        model: input.model,
+        request: original.request.clone(),
+        finish_reason,
+        output_hash: digest.hash(),
+        output_tokens: digest.tokens(),
+        queued_ms: None,
+        replay_of: Some(original.id),
+        experiment_id: None,
+        variant: None,
+        upstream_headers,
+        created_at,
+    };
+    generations::record(&state.db, &replay).timed(&state.metrics, "generations.record").await.map_err(db_error)?;
+    tracing::info!(generation_id = %id, replay_id = %replay.id, by = %user.user_id, model = %replay.model, "audit.generation.replayed");
+    Ok(Json(ReplayOut {
+        same_output: original.output_hash == replay.output_hash,
+        original: ReplaySide::from(&original),
+        replay: ReplaySide::from(&replay),
+        output,
+    }))
+}
+
+#[derive(Deserialize, Validate)]
+struct ImpersonateIn {
+    /// Why, for the audit trail
+    #[validate(length(min = 1, max = 500, message = "between 1 and 500 characters required"))]
+    reason: String,
+    /// At most, and by default, `IMPERSONATION_TTL_SECS`
+    ttl_secs: Option<u64>,
+    /// Read-only when omitted; never `admin:*`
+    scopes: Option<Vec<String>>,
+}
+
+#[derive(Serialize)]
+struct ImpersonateOut {
+    access_token: String,
+    token_type: &'static str,
+    expires_in: u64,
+    user_id: UserId,
+    scopes: Vec<String>,
+}
+
+/// Scopes an impersonation token gets unless the admin asks for others
+const IMPERSONATION_SCOPES: [&str; 2] = [scope::CHAT_READ, scope::MODELS_READ];
+
+/// Issue a short-lived access token acting as `user_id`. It carries the
+/// admin in its `act` claim, has no session or refresh token, cannot use
+/// admin routes, and every request made with it is audited and answered
+/// with `X-Impersonated-By`.
+async fn impersonate(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Path(target): Path<UserId>,
+    ValidatedJson(input): ValidatedJson<ImpersonateIn>,
+) -> ApiResult<Json<ImpersonateOut>> {
+    let max_ttl = state.config().security.impersonation_ttl_secs;
+    let scopes = input.scopes.unwrap_or_else(|| IMPERSONATION_SCOPES.map(String::from).to_vec());
+    let mut fields = Vec::new();
+    if input.ttl_secs.is_some_and(|ttl| ttl == 0 || ttl > max_ttl) {
+        fields.push(FieldError {
+            field: "ttl_secs".into(),
+            code: "range".into(),
+            message: format!("between 1 and {max_ttl} required"),
+        });
+    }
+    // An empty list would be an unrestricted token
+    if scopes.is_empty() {
+        fields.push(FieldError {
+            field: "scopes".into(),
+            code: "length".into(),
+            message: "at least one scope required".into(),
+        });
+    }
+    for (i, requested) in scopes.iter().enumerate() {
+        let admin = scope::grants(requested, scope::ADMIN_ALL);
+        if admin || scope::validate(requested).is_err() {
+            fields.push(FieldError {
+                field: format!("scopes[{i}]"),
+                code: "scope".into(),
+                message: match admin {
+                    true => "admin scopes cannot be impersonated".into(),
+                    false => format!("unknown scope {requested}"),
+                },
+            });
+        }
+    }
+    if !fields.is_empty() {
+        return Err(ApiError::Validation(fields));
+    }
+    if target == user.user_id {
+        return Err(ApiError::Unprocessable("cannot impersonate yourself".into()));
+    }
+
+    let row = sqlx::query("SELECT email, role FROM users WHERE id = $1")
+        .bind(target)
+        .fetch_optional(&state.db)
+        .await
+        .map_err(|e| {
+            tracing::error!(error = %e, user_id = %target, "impersonation target lookup failed");
+            ApiError::Internal
+        })?
+        .ok_or(ApiError::NotFound)?;
+    let (email, role): (String, String) = (
+        row.try_get("email").map_err(|_| ApiError::Internal)?,
+        row.try_get("role").map_err(|_| ApiError::Internal)?,
+    );
+    if role == crate::auth_middleware::ROLE_ADMIN {
+        tracing::warn!(by = %user.user_id, user_id = %target, "impersonation of an admin refused");
+        return Err(ApiError::Forbidden);
+    }
+
+    let ttl = input.ttl_secs.unwrap_or(max_ttl);
+    let claims = state
+        .tokens
+        .claims(target)
+        .ttl(Duration::from_secs(ttl))
+        .email(email)
+        .role(role)
+        .scopes(&scopes)
+        .actor(user.user_id)
+        .build();
+    let access_token = state.tokens.issue(&claims).map_err(|e| {
+        tracing::error!(error = %e, "impersonation token generation failed");
+        ApiError::Internal
+    })?;
+    tracing::info!(
+        by = %user.user_id,
+        user_id = %target,
+        reason = %input.reason,
+        ttl_secs = ttl,
+        scopes = ?scopes,
+        "audit.impersonation.issued"
+    );
+    Ok(Json(ImpersonateOut { access_token, token_type: "Bearer", expires_in: ttl, user_id: target, scopes }))
+}
+
+fn ratio(part: u64, whole: u64) -> f64 {
+    if whole == 0 { 0.0 } else { part as f64 / whole as f64 }
+}
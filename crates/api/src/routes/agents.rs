@@ -0,0 +1,184 @@
+//! Managed agent runs (JWT or API key; `chat:write` to run, `chat:read` to
+//! read a run's trace)
+
+use crate::{
+    admission::Priority,
+    agents::{self, Progress, Run, Spec},
+    auth_middleware::{require_auth, require_scope, AuthUser, ROLE_ADMIN},
+    db_metrics::Timed,
+    extract::{rules, ValidatedJson},
+    guard,
+    routes::chat::{self, QUOTA_REMAINING},
+    state::AppState,
+    tenants,
+};
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, HeaderValue},
+    middleware,
+    response::sse::{Event, Sse},
+    routing::{get, post},
+    Extension, Json, Router,
+};
+use ds_auth::scope;
+use ds_core::error::{ApiError, ApiResult, FieldError};
+use ds_model::ChatMessage;
+use ds_types::AgentRunId;
+use futures_util::Stream;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use validator::Validate;
+
+pub fn router() -> Router<AppState> {
+    let write = Router::new()
+        .route("/v1/agents/run", post(run))
+        .route_layer(middleware::from_fn(|req, next| require_scope(scope::CHAT_WRITE, req, next)));
+    let read = Router::new()
+        .route("/v1/agents/runs/{id}", get(get_run))
+        .route_layer(middleware::from_fn(|req, next| require_scope(scope::CHAT_READ, req, next)));
+    write.merge(read).route_layer(middleware::from_fn(require_auth))
+}
+
+#[derive(Deserialize, Validate)]
+struct RunIn {
+    /// May be omitted when the API key has a default model
+    #[validate(custom(function = "rules::model_name"))]
+    model: Option<String>,
+    priority: Option<Priority>,
+    #[validate(length(min = 1, max = 64, message = "between 1 and 64 messages required"))]
+    messages: Vec<ChatMessage>,
+    /// Server-side tools the model may call, by name
+    #[serde(default)]
+    #[validate(length(max = 16, message = "at most 16 tools"))]
+    tools: Vec<String>,
+    /// Model steps; at most, and by default, `agents.max_steps`
+    max_steps: Option<u32>,
+    /// Wall-clock seconds; at most, and by default, `agents.budget_secs`
+    budget_secs: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct StartedOut {
+    run_id: AgentRunId,
+    model: String,
+    tools: Vec<&'static str>,
+    max_steps: u32,
+    budget_secs: u64,
+}
+
+#[derive(Serialize)]
+struct DoneOut {
+    run_id: AgentRunId,
+    #[serde(flatten)]
+    finished: agents::Finished,
+}
+
+/// `value` if it lies in `1..=max`, else a field error
+fn within(field: &str, value: Option<u64>, max: u64, fields: &mut Vec<FieldError>) -> u64 {
+    match value {
+        None => max,
+        Some(v) if (1..=max).contains(&v) => v,
+        Some(_) => {
+            fields.push(FieldError {
+                field: field.into(),
+                code: "range".into(),
+                message: format!("between 1 and {max} required"),
+            });
+            max
+        }
+    }
+}
+
+/// Start a run and stream it: `started`, one `step` per model generation
+/// and tool call as each finishes, then `done`. The run continues and is
+/// stored even if the client disconnects.
+async fn run(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    ValidatedJson(input): ValidatedJson<RunIn>,
+) -> ApiResult<(HeaderMap, Sse<impl Stream<Item = Result<Event, axum::Error>>>)> {
+    let cfg = tenants::for_user(&state, &user).await?;
+    let mut fields = Vec::new();
+    let max_steps = within("max_steps", input.max_steps.map(u64::from), cfg.agents.max_steps.into(), &mut fields);
+    let budget_secs = within("budget_secs", input.budget_secs, cfg.agents.budget_secs, &mut fields);
+    if !fields.is_empty() {
+        return Err(ApiError::Validation(fields));
+    }
+    chat::check_messages(&input.messages, &cfg.chat)?;
+    let model = chat::resolve_model(&cfg.chat, &user, input.model.as_deref())?;
+    let tools = state.tools.select(&user, &input.tools)?;
+    let slot = state
+        .streams
+        .acquire(user.user_id, "agents", &model, cfg.chat.max_concurrent_streams)
+        .ok_or(ApiError::RateLimited)?;
+    let quota_remaining = chat::check_quota(&state, &cfg.quota, &user).await?;
+    let messages = guard::prepare_messages(&cfg, user.user_id, input.messages.clone())?;
+    let priority = chat::resolve_priority(&user, input.priority);
+    let spec = Spec {
+        id: AgentRunId::generate(),
+        user_id: user.user_id,
+        model,
+        messages,
+        tools,
+        priority,
+        max_steps: max_steps as u32,
+        budget: Duration::from_secs(budget_secs),
+        quota: cfg.quota.clone(),
+    };
+    let request = serde_json::json!({
+        "model": spec.model,
+        "messages": input.messages,
+        "tools": spec.tools.names(),
+        "max_steps": max_steps,
+        "budget_secs": budget_secs,
+        "priority": priority,
+    });
+    agents::create(&state.db, &spec, &request).timed(&state.metrics, "agents.create").await.map_err(|e| {
+        tracing::error!(error = %e, "creating agent run failed");
+        ApiError::Internal
+    })?;
+    tracing::info!(run_id = %spec.id, user_id = %user.user_id, model = %spec.model, max_steps, budget_secs, "agent run");
+
+    let mut headers = HeaderMap::new();
+    if let Some(remaining) = quota_remaining {
+        headers.insert(QUOTA_REMAINING, HeaderValue::from(remaining));
+    }
+    let started = StartedOut {
+        run_id: spec.id,
+        model: spec.model.clone(),
+        tools: spec.tools.names(),
+        max_steps: spec.max_steps,
+        budget_secs,
+    };
+    let run_id = spec.id;
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(agents::run(state, spec, slot, tx));
+    Ok((
+        headers,
+        Sse::new(async_stream::stream! {
+            yield Event::default().event("started").json_data(&started);
+            while let Some(progress) = rx.recv().await {
+                yield match progress {
+                    Progress::Step(step) => Event::default().event("step").json_data(&step),
+                    Progress::Done(finished) => Event::default().event("done").json_data(DoneOut { run_id, finished }),
+                };
+            }
+        }),
+    ))
+}
+
+async fn get_run(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Path(id): Path<AgentRunId>,
+) -> ApiResult<Json<Run>> {
+    let run = agents::get(&state.db, id).timed(&state.metrics, "agents.get").await.map_err(|e| {
+        tracing::error!(error = %e, run_id = %id, "agent run lookup failed");
+        ApiError::Internal
+    })?;
+    match run {
+        // Someone else's run is indistinguishable from a missing one
+        Some(run) if run.user_id == user.user_id || user.has_role(ROLE_ADMIN) => Ok(Json(run)),
+        _ => Err(ApiError::NotFound),
+    }
+}
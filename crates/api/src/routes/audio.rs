@@ -0,0 +1,95 @@
+//! Speech to text (JWT or API key with `chat:write`, rate limited per IP)
+
+use crate::{
+    auth_middleware::{require_auth, require_scope, AuthUser},
+    extract::{field_errors, rules},
+    rate_limit,
+    state::AppState,
+};
+use axum::{
+    body::Body,
+    extract::{Query, State},
+    http::{header, HeaderMap},
+    middleware,
+    routing::post,
+    Extension, Json, Router,
+};
+use ds_auth::scope;
+use ds_core::error::{ApiError, ApiResult};
+use ds_model::transcribe::{Transcription, TranscriptionRequest};
+use serde::Deserialize;
+use validator::{Validate, ValidationError};
+
+/// Transcriptions by result (`ok`, `error`)
+pub const TRANSCRIPTIONS: &str = "deepersensor_transcriptions_total";
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/v1/audio/transcriptions", post(transcribe))
+        .route_layer(middleware::from_fn(rate_limit::per_ip))
+        .route_layer(middleware::from_fn(|req, next| require_scope(scope::CHAT_WRITE, req, next)))
+        .route_layer(middleware::from_fn(require_auth))
+}
+
+#[derive(Deserialize, Validate)]
+struct TranscribeQuery {
+    /// Backend model; defaults to `transcribe.model`
+    #[validate(custom(function = "rules::model_name"))]
+    model: Option<String>,
+    #[validate(custom(function = "language_code"))]
+    language: Option<String>,
+}
+
+/// ISO-639-1 style codes such as `en` or `pt-BR`
+fn language_code(value: &str) -> Result<(), ValidationError> {
+    let valid = (2..=8).contains(&value.len()) && value.chars().all(|c| c.is_ascii_alphabetic() || c == '-');
+    if valid { Ok(()) } else { Err(ValidationError::new("language").with_message("expected a language code such as en".into())) }
+}
+
+/// The raw audio is the request body, typed by its `Content-Type`
+async fn transcribe(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Query(query): Query<TranscribeQuery>,
+    headers: HeaderMap,
+    body: Body,
+) -> ApiResult<Json<Transcription>> {
+    query.validate().map_err(|e| ApiError::Validation(field_errors(&e)))?;
+    let Some(transcriber) = state.transcriber.clone() else {
+        // Disabled unless a backend is configured
+        return Err(ApiError::NotFound);
+    };
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.split(';').next().unwrap_or("").trim().to_ascii_lowercase())
+        .filter(|v| v.starts_with("audio/") || v == "video/webm" || v == "application/octet-stream")
+        .ok_or_else(|| ApiError::BadRequest("Content-Type must be an audio type".into()))?;
+    let cfg = &state.config().transcribe;
+    let limit = cfg.max_bytes as usize;
+    let audio = axum::body::to_bytes(body, limit)
+        .await
+        .map_err(|_| ApiError::PayloadTooLarge(format!("audio exceeds {limit} bytes")))?;
+    if audio.is_empty() {
+        return Err(ApiError::BadRequest("audio body is empty".into()));
+    }
+    tracing::info!(user_id = %user.user_id, backend = transcriber.name(), bytes = audio.len(), "transcription request");
+
+    let request = TranscriptionRequest {
+        audio,
+        content_type,
+        model: query.model.unwrap_or_else(|| cfg.model.clone()),
+        language: query.language,
+    };
+    match transcriber.transcribe(request).await {
+        Ok(transcription) => {
+            state.metrics.incr(TRANSCRIPTIONS, &[("result", "ok")]);
+            Ok(Json(transcription))
+        }
+        Err(e) => {
+            state.metrics.incr(TRANSCRIPTIONS, &[("result", "error")]);
+            tracing::error!(error = %e, user_id = %user.user_id, backend = transcriber.name(), "transcription failed");
+            Err(ApiError::Internal)
+        }
+    }
+}
@@ -0,0 +1,275 @@
+//! Signup, login, refresh, and logout (rate limited per IP to slow brute
+//! force and signup abuse)
+
+use crate::{
+    auth_middleware::{require_auth, AuthUser},
+    context::RequestContext,
+    db_metrics::Timed,
+    events,
+    extract::{rules, ValidatedJson},
+    rate_limit,
+    sessions::{self, Redeem},
+    state::AppState,
+};
+use axum::{
+    extract::State,
+    http::StatusCode,
+    middleware,
+    routing::post,
+    Extension, Json, Router,
+};
+use ds_auth::{hash_password, verify_password, verify_password_dummy};
+use ds_core::error::{ApiError, ApiResult};
+use serde::{Deserialize, Serialize};
+use validator::Validate;
+use ds_types::{SessionId, TokenId, UserId};
+use sqlx::PgPool;
+
+pub fn router() -> Router<AppState> {
+    let authed = Router::new()
+        .route("/v1/auth/logout", post(logout))
+        .route_layer(middleware::from_fn(require_auth));
+
+    Router::new()
+        .route("/v1/auth/signup", post(signup))
+        .route("/v1/auth/login", post(login))
+        .route("/v1/auth/refresh", post(refresh))
+        .merge(authed)
+        .route_layer(middleware::from_fn(rate_limit::per_ip))
+}
+
+#[derive(Deserialize, Validate)]
+struct SignupIn {
+    #[validate(custom(function = "rules::email"))]
+    email: String,
+    #[validate(custom(function = "rules::password"))]
+    password: String,
+}
+#[derive(Serialize)]
+struct SignupOut {
+    id: UserId,
+    email: String,
+}
+#[derive(Deserialize, Validate)]
+struct LoginIn {
+    #[validate(length(min = 1, message = "email is required"))]
+    email: String,
+    #[validate(length(min = 1, message = "password is required"))]
+    password: String,
+}
+#[derive(Deserialize, Validate)]
+struct RefreshIn {
+    #[validate(length(min = 1, message = "refresh_token is required"))]
+    refresh_token: String,
+}
+#[derive(Serialize)]
+struct LoginOut {
+    access_token: String,
+    refresh_token: String,
+}
+
+async fn signup(
+    State(state): State<AppState>,
+    ValidatedJson(input): ValidatedJson<SignupIn>,
+) -> ApiResult<Json<SignupOut>> {
+    let hash = hash_password(&input.password).map_err(|e| {
+        tracing::error!(error = %e, "password hashing failed");
+        ApiError::Internal
+    })?;
+
+    let id = UserId::generate();
+
+    match insert_user(&state.db, id, &input.email, &hash).await {
+        Ok(_) => {
+            tracing::info!(user_id = %id, email = %input.email, "audit.signup.success");
+            Ok(Json(SignupOut {
+                id,
+                email: input.email,
+            }))
+        }
+        Err(e) => {
+            // Check for unique constraint violation (duplicate email)
+            if let Some(db_err) = e.as_database_error() {
+                if db_err.is_unique_violation() {
+                    tracing::debug!(email = %input.email, "duplicate email signup attempt");
+                    return Err(ApiError::Unprocessable("email already registered".into()));
+                }
+            }
+
+            tracing::error!(error = %e, email = %input.email, "audit.signup.fail");
+            Err(ApiError::Internal)
+        }
+    }
+}
+
+/// Store the user and its `user.signed_up` event together
+async fn insert_user(db: &PgPool, id: UserId, email: &str, hash: &str) -> sqlx::Result<()> {
+    let mut tx = db.begin().await?;
+    sqlx::query("INSERT INTO users (id,email,password_hash) VALUES ($1,$2,$3)")
+        .bind(id)
+        .bind(email)
+        .bind(hash)
+        .execute(&mut *tx)
+        .await?;
+    events::enqueue(&mut tx, &events::UserSignedUp { user_id: id }).await?;
+    tx.commit().await
+}
+
+async fn login(
+    State(state): State<AppState>,
+    ctx: RequestContext,
+    ValidatedJson(input): ValidatedJson<LoginIn>,
+) -> ApiResult<Json<LoginOut>> {
+    rate_limit::login_attempt(&state, &input.email).await.inspect_err(|_| {
+        tracing::warn!(ip = %ctx.ip, "audit.login.throttled");
+    })?;
+
+    let rec_opt = sqlx::query("SELECT id, email, password_hash, role FROM users WHERE email=$1")
+        .bind(&input.email)
+        .fetch_optional(&state.db)
+        .await
+        .map_err(|e| {
+            tracing::error!(error = %e, "login query failed");
+            ApiError::Internal
+        })?;
+
+    // Unknown emails still pay for an Argon2 verify and get the same error,
+    // so neither timing nor body reveals whether the account exists
+    let Some(rec) = rec_opt else {
+        verify_password_dummy(&input.password);
+        tracing::debug!(email = %input.email, ip = %ctx.ip, "login attempt for non-existent user");
+        return Err(ApiError::Unauthorized);
+    };
+
+    use sqlx::Row;
+    let id: UserId = rec.try_get("id").map_err(|_| ApiError::Internal)?;
+    let _email: String = rec.try_get("email").map_err(|_| ApiError::Internal)?;
+    let role: String = rec.try_get("role").map_err(|_| ApiError::Internal)?;
+    let password_hash: String = rec
+        .try_get("password_hash")
+        .map_err(|_| ApiError::Internal)?;
+
+    let (valid, needs_rehash) = verify_password(&input.password, &password_hash).map_err(|e| {
+        tracing::error!(error = %e, "password verification failed");
+        ApiError::Internal
+    })?;
+
+    if !valid {
+        tracing::warn!(user_id = %id, email = %input.email, ip = %ctx.ip, "audit.login.fail.invalid_password");
+        return Err(ApiError::Unauthorized);
+    }
+
+    // Rehash password if needed (parameters changed)
+    if needs_rehash {
+        if let Ok(new_hash) = hash_password(&input.password) {
+            let _ = sqlx::query("UPDATE users SET password_hash=$1 WHERE id=$2")
+                .bind(&new_hash)
+                .bind(id)
+                .execute(&state.db)
+                .await;
+            tracing::debug!(user_id = %id, "password rehashed with updated parameters");
+        }
+    }
+
+    tracing::info!(user_id = %id, email = %input.email, "audit.login.success");
+
+    let (sid, jti) = sessions::create(&state.db, id, state.tokens.refresh_ttl())
+        .timed(&state.metrics, "sessions.create")
+        .await
+        .map_err(|e| {
+            tracing::error!(error = %e, user_id = %id, "session create failed");
+            ApiError::Internal
+        })?;
+
+    Ok(Json(issue_pair(&state, id, Some(&input.email), &role, sid, jti)?))
+}
+
+/// Redeem a refresh token for a new access/refresh pair (rotation). A
+/// superseded refresh token revokes its whole session.
+async fn refresh(
+    State(state): State<AppState>,
+    ctx: RequestContext,
+    ValidatedJson(input): ValidatedJson<RefreshIn>,
+) -> ApiResult<Json<LoginOut>> {
+    let (claims, sid, jti) = state.tokens.verify_refresh(&input.refresh_token).map_err(|e| {
+        tracing::debug!(error = %e, "refresh token rejected");
+        ApiError::Unauthorized
+    })?;
+
+    let redeemed = sessions::rotate(&state.db, sid, claims.sub, jti, state.tokens.refresh_ttl())
+        .timed(&state.metrics, "sessions.rotate")
+        .await
+        .map_err(|e| {
+            tracing::error!(error = %e, session_id = %sid, "session rotate failed");
+            ApiError::Internal
+        })?;
+
+    match redeemed {
+        Redeem::Rotated(next) => {
+            // Re-read the role so a demotion takes effect at the next refresh
+            let role: String = sqlx::query_scalar("SELECT role FROM users WHERE id=$1")
+                .bind(claims.sub)
+                .fetch_optional(&state.db)
+                .await
+                .map_err(|e| {
+                    tracing::error!(error = %e, "refresh role lookup failed");
+                    ApiError::Internal
+                })?
+                .ok_or(ApiError::Unauthorized)?;
+            tracing::info!(user_id = %claims.sub, session_id = %sid, "audit.refresh.success");
+            Ok(Json(issue_pair(&state, claims.sub, claims.email.as_deref(), &role, sid, next)?))
+        }
+        Redeem::Invalid => Err(ApiError::Unauthorized),
+        Redeem::Reused => {
+            tracing::warn!(user_id = %claims.sub, session_id = %sid, ip = %ctx.ip, "audit.refresh.reuse_detected");
+            Err(ApiError::Unauthorized)
+        }
+    }
+}
+
+/// Revoke the caller's session so its refresh token stops working; the
+/// access token itself stays valid until it expires.
+async fn logout(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+) -> ApiResult<StatusCode> {
+    if let Some(sid) = user.session_id {
+        let revoked = sessions::revoke(&state.db, sid, sessions::REVOKED_LOGOUT)
+            .timed(&state.metrics, "sessions.revoke")
+            .await
+            .map_err(|e| {
+                tracing::error!(error = %e, session_id = %sid, "session revoke failed");
+                ApiError::Internal
+            })?;
+        if revoked {
+            tracing::info!(user_id = %user.user_id, session_id = %sid, "audit.logout");
+        }
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+fn issue_pair(
+    state: &AppState,
+    user_id: UserId,
+    email: Option<&str>,
+    role: &str,
+    sid: SessionId,
+    jti: TokenId,
+) -> ApiResult<LoginOut> {
+    let mut access = state.tokens.claims(user_id).session_id(sid).role(role);
+    let mut refresh = state.tokens.refresh_claims(user_id, sid, jti);
+    if let Some(email) = email {
+        access = access.email(email);
+        refresh = refresh.email(email);
+    }
+    let issue = |claims| {
+        state.tokens.issue(&claims).map_err(|e| {
+            tracing::error!(error = %e, "token generation failed");
+            ApiError::Internal
+        })
+    };
+    Ok(LoginOut {
+        access_token: issue(access.build())?,
+        refresh_token: issue(refresh.build())?,
+    })
+}
@@ -0,0 +1,887 @@
+//! Chat completions (JWT required)
+
+use crate::{
+    admission::{Permit, Priority, Queued, Ticket},
+    api_keys,
+    auth_middleware::{require_auth, require_scope, AuthUser},
+    backpressure,
+    conversations,
+    db_metrics::Timed,
+    encode::{self, Encoding, Frame},
+    enrich,
+    experiments::{self, Assignment},
+    extract::{rules, ValidatedJson},
+    generations::{self, Generation, OutputDigest},
+    guard, quota,
+    retrieval::{self, Hit, Retrieval, Search, SearchError},
+    routes::collections::access_error,
+    semantic_cache::{self, Lookup},
+    state::AppState,
+    tenants,
+    tools::{self, Toolset},
+    validation,
+};
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::{header, HeaderMap, HeaderName, HeaderValue},
+    middleware::{self, Next},
+    response::{sse::Event, IntoResponse, Response},
+    routing::post,
+    Extension, Router,
+};
+use ds_auth::scope;
+use ds_core::{
+    config::{AppConfig, ChatSection, QuotaSection},
+    error::{ApiError, ApiResult, FieldError},
+};
+use ds_model::{
+    ChatChunk, ChatMessage, ChatRequest, ChatStream, Citation, GenerationOptions, ModelError, ToolCall, Usage,
+    FINISH_ERROR, FINISH_STOP,
+};
+use ds_types::{CollectionId, ConversationId, GenerationId};
+use futures_util::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use validator::Validate;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Milliseconds chats spent waiting for a generation slot, per priority
+const QUEUE_WAIT_MS: &str = "deepersensor_chat_queue_wait_ms_total";
+
+/// Tokens left of the caller's nominal daily quota before this request
+pub(crate) const QUOTA_REMAINING: HeaderName = HeaderName::from_static("x-quota-remaining");
+
+/// Id of the generation record, for `GET /v1/generations/{id}`
+const GENERATION_ID: HeaderName = HeaderName::from_static("x-generation-id");
+
+/// `hit` or `miss` when the semantic cache was consulted
+const CACHE_STATUS: HeaderName = HeaderName::from_static("x-cache");
+
+/// Route names used by `semantic_cache.routes`
+const ROUTE_CHAT: &str = "chat";
+const ROUTE_CHAT_STREAM: &str = "chat_stream";
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/v1/chat", post(chat))
+        .route("/v1/chat/stream", post(chat_stream_sse))
+        .route_layer(middleware::from_fn(limit_body))
+        .route_layer(middleware::from_fn(|req, next| require_scope(scope::CHAT_WRITE, req, next)))
+        .route_layer(middleware::from_fn(require_auth))
+}
+
+/// Reject bodies that cannot fit `chat.max_total_chars` before parsing.
+///
+/// A declared `Content-Length` over the limit is refused without reading;
+/// otherwise reading stops as soon as the limit is crossed.
+async fn limit_body(Extension(state): Extension<AppState>, req: Request, next: Next) -> Response {
+    let limit = state.cfg.chat.max_body_bytes();
+    let too_large = || ApiError::PayloadTooLarge(format!("chat body exceeds {limit} bytes")).into_response();
+    let declared = req
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<usize>().ok());
+    if declared.is_some_and(|len| len > limit) {
+        return too_large();
+    }
+    let (parts, body) = req.into_parts();
+    match axum::body::to_bytes(body, limit).await {
+        Ok(bytes) => next.run(Request::from_parts(parts, Body::from(bytes))).await,
+        Err(_) => too_large(),
+    }
+}
+
+/// Per-message and request-wide length limits from `chat` config.
+///
+/// Lengths are Unicode scalar values, so CJK or emoji text gets the same
+/// allowance as ASCII and the byte bound in `limit_body` still holds.
+pub(crate) fn check_messages(messages: &[ChatMessage], limits: &ChatSection) -> ApiResult<()> {
+    let mut fields = Vec::new();
+    for (i, m) in messages.iter().enumerate() {
+        if let Err(ApiError::Unprocessable(message)) =
+            validation::validate_message_content(&m.content, limits.max_message_chars as usize)
+        {
+            fields.push(FieldError {
+                field: format!("messages[{i}].content"),
+                code: "message_content".into(),
+                message,
+            });
+        }
+    }
+    let total: usize = messages.iter().map(|m| m.content.chars().count()).sum();
+    if total as u64 > limits.max_total_chars {
+        fields.push(FieldError {
+            field: "messages".into(),
+            code: "total_length".into(),
+            message: format!("messages exceed {} characters in total", limits.max_total_chars),
+        });
+    }
+    if fields.is_empty() { Ok(()) } else { Err(ApiError::Validation(fields)) }
+}
+
+/// The requested model, or the API key's default; keys restricted to a
+/// model list may not call anything else, and neither may anyone outside
+/// the allowed models of their org or the deployment
+pub(crate) fn resolve_model(cfg: &ChatSection, user: &AuthUser, requested: Option<&str>) -> ApiResult<String> {
+    let key = user.api_key.as_ref();
+    let Some(model) = requested.or_else(|| key.and_then(|k| k.default_model.as_deref())) else {
+        return Err(ApiError::Validation(vec![FieldError {
+            field: "model".into(),
+            code: "required".into(),
+            message: "model is required".into(),
+        }]));
+    };
+    if key.is_some_and(|k| !k.allows_model(model)) {
+        tracing::warn!(user_id = %user.user_id, model, "model not allowed for api key");
+        return Err(ApiError::Forbidden);
+    }
+    if !cfg.allows_model(model) {
+        tracing::warn!(user_id = %user.user_id, org_id = ?user.org_id, model, "model not allowed");
+        return Err(ApiError::Forbidden);
+    }
+    Ok(model.to_string())
+}
+
+/// `model` of the named provider: prefixed with it unless it is the
+/// default, which serves unprefixed names
+fn with_provider(state: &AppState, provider: Option<&str>, model: Option<&str>) -> ApiResult<Option<String>> {
+    let Some(provider) = provider else { return Ok(model.map(str::to_string)) };
+    let field = |field: &str, code: &str, message: String| {
+        ApiError::Validation(vec![FieldError { field: field.into(), code: code.into(), message }])
+    };
+    let Some(model) = model else { return Err(field("model", "required", "model is required with provider".into())) };
+    let mut routes = state.provider.routes();
+    if routes.is_empty() {
+        routes.push(state.provider.name());
+    }
+    if !routes.contains(&provider) {
+        let message = format!("no provider named {provider}; expected one of {}", routes.join(", "));
+        return Err(field("provider", "unknown_provider", message));
+    }
+    Ok(Some(if routes[0] == provider { model.to_string() } else { format!("{provider}/{model}") }))
+}
+
+#[derive(Deserialize, Validate)]
+struct ChatIn {
+    /// May be omitted when the API key has a default model; `<provider>/`
+    /// before it picks a provider other than the default
+    #[validate(custom(function = "rules::model_name"))]
+    model: Option<String>,
+    /// Serve `model` with this provider instead of naming it in the prefix
+    provider: Option<String>,
+    /// `interactive` or `batch`; defaults to, and is capped at, the
+    /// caller's highest allowed class
+    priority: Option<Priority>,
+    #[validate(
+        length(min = 1, max = 64, message = "between 1 and 64 messages required")
+    )]
+    messages: Vec<ChatMessage>,
+    /// Continue a stored conversation: `messages` are the new turn, sent
+    /// after its summary and recent turns and stored with the reply
+    conversation_id: Option<ConversationId>,
+    /// Server-side tools the model may call, by name
+    #[serde(default)]
+    #[validate(length(max = 16, message = "at most 16 tools"))]
+    tools: Vec<String>,
+    /// Search documents for the turn's last user message and give the
+    /// model the passages found
+    #[validate(nested)]
+    retrieval: Option<RetrievalIn>,
+    /// Sampling settings passed to the provider
+    #[validate(nested)]
+    options: Option<OptionsIn>,
+}
+
+/// The upstream's defaults where left out
+#[derive(Deserialize, Validate)]
+struct OptionsIn {
+    #[validate(custom(function = "rules::temperature"))]
+    temperature: Option<f64>,
+    #[validate(custom(function = "rules::top_p"))]
+    top_p: Option<f64>,
+    #[validate(custom(function = "rules::top_k"))]
+    top_k: Option<u32>,
+    #[validate(custom(function = "rules::max_tokens"))]
+    max_tokens: Option<u32>,
+    seed: Option<u64>,
+    #[serde(default)]
+    #[validate(custom(function = "rules::stop_sequences"))]
+    stop: Vec<String>,
+}
+
+impl OptionsIn {
+    fn to_options(&self) -> GenerationOptions {
+        GenerationOptions {
+            temperature: self.temperature,
+            top_p: self.top_p,
+            top_k: self.top_k,
+            max_tokens: self.max_tokens,
+            seed: self.seed,
+            stop: self.stop.clone(),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Validate)]
+struct RetrievalIn {
+    /// Collections the caller may read; its own documents when empty
+    #[serde(default)]
+    #[validate(length(max = 16, message = "at most 16 collections"))]
+    collection_ids: Vec<CollectionId>,
+    /// `vector`, `keyword`, or `hybrid`; `documents.retrieval` by default
+    retrieval: Option<String>,
+    #[serde(default)]
+    rerank: bool,
+    /// Passages to give the model, 4 by default
+    #[validate(range(min = 1, max = 16, message = "between 1 and 16"))]
+    limit: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct ChatOut {
+    model: Arc<str>,
+    content: String,
+    done: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    finish_reason: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    queued_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tool_calls: Vec<ToolCall>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    citations: Vec<Citation>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    usage: Option<Usage>,
+}
+
+/// Encoded as `Accept` asks: one JSON or MessagePack document of every
+/// chunk, or the frames of `/v1/chat/stream` as SSE or NDJSON
+async fn chat(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    accept: HeaderMap,
+    ValidatedJson(input): ValidatedJson<ChatIn>,
+) -> ApiResult<(HeaderMap, Response)> {
+    let encoding = Encoding::negotiate(&accept);
+    tracing::info!(
+        user_id = %user.user_id,
+        model = ?input.model,
+        message_count = input.messages.len(),
+        ?encoding,
+        "chat request"
+    );
+
+    let mut prepared = prepare_chat(&state, &user, &input, ROUTE_CHAT).await?;
+    let mut headers = chat_headers(&prepared);
+    if encoding.is_stream() {
+        let frames = chat_frames(&state, &user, prepared).await?;
+        return Ok((headers, encode::stream(encoding, frames)));
+    }
+    let stream = if let Some(response) = prepared.take_cache_hit() {
+        cached_stream(&state, &prepared.model, response)
+    } else {
+        // Without a stream to report progress on, just wait for a slot
+        let permit = match state.admission.enqueue(prepared.priority).ok_or(ApiError::RateLimited)? {
+            Ticket::Admitted(permit) => permit,
+            Ticket::Queued(mut queued) => queued.admitted().await,
+        };
+        start_chat_stream(&state, &user, prepared, permit).await?
+    };
+    let mut out = Vec::new();
+    futures_util::pin_mut!(stream);
+    while let Some(chunk) = stream.next().await {
+        // Mid-stream failures are logged and counted in start_chat_stream
+        let c: ChatChunk = chunk.map_err(model_error)?;
+        for (name, value) in &c.upstream_headers {
+            if let (Ok(name), Ok(value)) = (HeaderName::try_from(name.as_str()), HeaderValue::from_str(value)) {
+                headers.insert(name, value);
+            }
+        }
+        out.push(ChatOut {
+            model: c.model,
+            content: c.content,
+            done: c.done,
+            finish_reason: c.finish_reason,
+            queued_ms: c.queued_ms,
+            tool_calls: c.tool_calls,
+            citations: c.citations,
+            usage: c.usage,
+        });
+    }
+    Ok((headers, encode::document(encoding, &out)))
+}
+
+async fn chat_stream_sse(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    ValidatedJson(input): ValidatedJson<ChatIn>,
+) -> ApiResult<(HeaderMap, Response)> {
+    tracing::info!(
+        user_id = %user.user_id,
+        model = ?input.model,
+        message_count = input.messages.len(),
+        "chat stream request"
+    );
+
+    let prepared = prepare_chat(&state, &user, &input, ROUTE_CHAT_STREAM).await?;
+    let headers = chat_headers(&prepared);
+    let frames = chat_frames(&state, &user, prepared).await?;
+    Ok((headers, encode::stream(Encoding::Sse, frames)))
+}
+
+type FrameStream = std::pin::Pin<Box<dyn Stream<Item = Frame> + Send>>;
+
+/// A prepared chat as frames: `queued` while it waits for a slot, then a
+/// `chunk` per chunk
+async fn chat_frames(state: &AppState, user: &AuthUser, mut prepared: PreparedChat) -> ApiResult<FrameStream> {
+    if let Some(response) = prepared.take_cache_hit() {
+        let stream = cached_stream(state, &prepared.model, response);
+        return Ok(Box::pin(chunk_frames(state, stream)));
+    }
+    Ok(match state.admission.enqueue(prepared.priority).ok_or(ApiError::RateLimited)? {
+        Ticket::Admitted(permit) => {
+            let stream = start_chat_stream(state, user, prepared, permit).await?;
+            Box::pin(chunk_frames(state, stream))
+        }
+        Ticket::Queued(queued) => Box::pin(queued_frames(state.clone(), user.clone(), prepared, queued)),
+    })
+}
+
+fn chunk_frames(state: &AppState, stream: ChatStream) -> impl Stream<Item = Frame> {
+    let stream = backpressure::bounded(stream, &state.cfg.stream, state.metrics.clone());
+    stream.map(|chunk| match chunk {
+        Ok(chat_chunk) => Frame::new("chunk", &chat_chunk),
+        // Followed by a terminal `done` chunk with `finish_reason: "error"`
+        Err(e) => Frame::error(&e.client_message()),
+    })
+}
+
+pub(crate) fn chunk_events(state: &AppState, stream: ChatStream) -> impl Stream<Item = Result<Event, axum::Error>> {
+    chunk_frames(state, stream).map(|frame| Ok(frame.into()))
+}
+
+/// A cached response replayed as one content chunk and the `done` frame,
+/// through the same redaction as a live one
+fn cached_stream(state: &AppState, model: &str, response: String) -> ChatStream {
+    let model: Arc<str> = Arc::from(model);
+    let chunks = vec![
+        Ok(ChatChunk { model: model.clone(), content: response, ..Default::default() }),
+        Ok(ChatChunk { model, done: true, finish_reason: Some(FINISH_STOP.into()), ..Default::default() }),
+    ];
+    state.redactor.filter(Box::pin(futures_util::stream::iter(chunks)))
+}
+
+/// A model provider failure as its client is told: a refusal of the
+/// request itself is the caller's to fix, anything else the upstream's
+pub(crate) fn model_error(e: ModelError) -> ApiError {
+    match e {
+        ModelError::Unsupported(_) | ModelError::ModelNotFound(_) | ModelError::Rejected(_) => {
+            ApiError::Unprocessable(e.client_message())
+        }
+        e => ApiError::BadGateway(e.client_message()),
+    }
+}
+
+pub(crate) fn error_event(message: &str) -> Event {
+    Frame::error(message).into()
+}
+
+/// How often a waiting client is told its place in line
+const QUEUE_POLL: Duration = Duration::from_millis(500);
+
+#[derive(Serialize)]
+struct QueuedOut {
+    position: usize,
+    /// `null` until a generation has finished to estimate from
+    estimated_wait_ms: Option<u64>,
+}
+
+/// `queued` frames while waiting for a generation slot (on entry and
+/// whenever the position changes), then the chat itself
+fn queued_frames(
+    state: AppState,
+    user: AuthUser,
+    prepared: PreparedChat,
+    mut queued: Queued,
+) -> impl Stream<Item = Frame> {
+    async_stream::stream! {
+        let mut reported = None;
+        let permit = loop {
+            let position = queued.position();
+            if reported != Some(position) && position > 0 {
+                reported = Some(position);
+                let out = QueuedOut {
+                    position,
+                    estimated_wait_ms: queued.estimated_wait().map(|d| d.as_millis() as u64),
+                };
+                yield Frame::new("queued", &out);
+            }
+            tokio::select! {
+                permit = queued.admitted() => break permit,
+                _ = tokio::time::sleep(QUEUE_POLL) => {}
+            }
+        };
+        match start_chat_stream(&state, &user, prepared, permit).await {
+            Ok(stream) => {
+                let frames = chunk_frames(&state, stream);
+                futures_util::pin_mut!(frames);
+                while let Some(frame) = frames.next().await {
+                    yield frame;
+                }
+            }
+            // Headers are already sent, so report the failure in-band
+            Err(e) => yield Frame::error(&e.to_string()),
+        }
+    }
+}
+
+/// Everything checked before a request may take a generation slot
+struct PreparedChat {
+    generation_id: GenerationId,
+    created_at: chrono::DateTime<chrono::Utc>,
+    /// Recorded with the generation, before the prompt guard rewrites it
+    request: serde_json::Value,
+    /// Set when an experiment replaced the requested model
+    experiment: Option<Assignment>,
+    model: String,
+    messages: Vec<ChatMessage>,
+    slot: quota::StreamSlot,
+    quota_remaining: Option<u64>,
+    priority: Priority,
+    route: &'static str,
+    cache: Option<Lookup>,
+    /// The conversation this turn continues, and the turn to store
+    conversation: Option<(ConversationId, Vec<ChatMessage>)>,
+    /// Tools the model may call
+    tools: Toolset,
+    /// Passages retrieved for the chat, as numbered in its prompt
+    citations: Vec<Citation>,
+    options: GenerationOptions,
+    /// The deployment's config with the caller's org overrides applied
+    cfg: Arc<AppConfig>,
+}
+
+impl PreparedChat {
+    /// The cached response, if the semantic cache had one
+    fn take_cache_hit(&mut self) -> Option<String> {
+        match self.cache.take() {
+            Some(Lookup::Hit(response)) => Some(response),
+            other => {
+                self.cache = other;
+                None
+            }
+        }
+    }
+}
+
+/// Semantic cache entries are shared within an org, else per user
+fn cache_owner(user: &AuthUser) -> uuid::Uuid {
+    user.org_id.map_or(*user.user_id.as_uuid(), |org| *org.as_uuid())
+}
+
+/// Validate limits, resolve the model (and any experiment variant),
+/// claim the caller's stream slot, check the daily quota, run the prompt
+/// guard, and consult the semantic cache
+async fn prepare_chat(
+    state: &AppState,
+    user: &AuthUser,
+    input: &ChatIn,
+    route: &'static str,
+) -> ApiResult<PreparedChat> {
+    let cfg = tenants::for_user(state, user).await?;
+    check_messages(&input.messages, &cfg.chat)?;
+    let model = with_provider(state, input.provider.as_deref(), input.model.as_deref())?;
+    let requested = resolve_model(&cfg.chat, user, model.as_deref())?;
+    let tools = state.tools.select(user, &input.tools)?;
+    let (upstream, _) = ds_model::resolve(&state.provider, &requested);
+    if !tools.is_empty() && !upstream.capabilities().tools {
+        let version = upstream.detected().map(|d| d.version).unwrap_or_default();
+        return Err(ApiError::Validation(vec![FieldError {
+            field: "tools".into(),
+            code: "unsupported".into(),
+            message: format!("the model upstream ({} {version}) does not support tools", upstream.name()),
+        }]));
+    }
+    let experiment = experiments::assign(&state.db, user.user_id, &requested)
+        .timed(&state.metrics, "experiments.assign")
+        .await
+        .map_err(|e| {
+            tracing::error!(error = %e, "experiment assignment failed");
+            ApiError::Internal
+        })?;
+    let model = experiment.as_ref().map_or_else(|| requested.clone(), |a| a.model.clone());
+    let slot = state
+        .streams
+        .acquire(user.user_id, route, &model, cfg.chat.max_concurrent_streams)
+        .ok_or(ApiError::RateLimited)?;
+    let quota_remaining = check_quota(state, &cfg.quota, user).await?;
+    let prompt = match input.conversation_id {
+        Some(id) => {
+            let history = conversations::history(&state.db, user.user_id, id)
+                .timed(&state.metrics, "conversations.history")
+                .await
+                .map_err(|e| {
+                    tracing::error!(error = %e, conversation_id = %id, "conversation lookup failed");
+                    ApiError::Internal
+                })?;
+            history.ok_or(ApiError::NotFound)?.prompt(&input.messages)
+        }
+        None => input.messages.clone(),
+    };
+    let mut messages = guard::prepare_messages(&cfg, user.user_id, prompt.clone())?;
+    let mut citations = Vec::new();
+    if let Some(options) = &input.retrieval {
+        let hits = retrieve(state, user, options, &input.messages).await?;
+        if !hits.is_empty() {
+            // Just before the turn it was retrieved for
+            messages.insert(messages.len() - 1, retrieval::context(&hits));
+            citations = hits.iter().map(Hit::citation).collect();
+        }
+    }
+    let priority = resolve_priority(user, input.priority);
+    let options = input.options.as_ref().map(OptionsIn::to_options).unwrap_or_default();
+    // A conversation's history makes every prompt unique, and tool results
+    // and documents can change between calls, so none of them is cached;
+    // nor are replies sampled other than by default
+    let cache = match input.conversation_id {
+        None if tools.is_empty() && input.retrieval.is_none() && options.is_empty() => {
+            semantic_cache::lookup(state, cache_owner(user), route, &model, &messages).await
+        }
+        _ => None,
+    };
+    let mut request = serde_json::json!({"model": requested, "messages": prompt, "priority": priority});
+    if let Some(id) = input.conversation_id {
+        request["conversation_id"] = serde_json::json!(id);
+    }
+    if !tools.is_empty() {
+        request["tools"] = serde_json::json!(tools.names());
+    }
+    if let Some(options) = &input.retrieval {
+        request["retrieval"] = serde_json::json!(options);
+    }
+    if !options.is_empty() {
+        request["options"] = serde_json::json!(options);
+    }
+    Ok(PreparedChat {
+        generation_id: GenerationId::generate(),
+        created_at: chrono::Utc::now(),
+        request,
+        experiment,
+        model,
+        messages,
+        slot,
+        quota_remaining,
+        priority,
+        route,
+        cache,
+        conversation: input.conversation_id.map(|id| (id, input.messages.clone())),
+        tools,
+        citations,
+        options,
+        cfg,
+    })
+}
+
+/// Most characters of a message searched for
+const RETRIEVAL_QUERY_CHARS: usize = 2000;
+
+/// Passages for the last user message of `turn`
+async fn retrieve(
+    state: &AppState,
+    user: &AuthUser,
+    options: &RetrievalIn,
+    turn: &[ChatMessage],
+) -> ApiResult<Vec<Hit>> {
+    let Some(message) = turn.iter().rev().find(|m| m.role == "user") else { return Ok(Vec::new()) };
+    let query: String = message.content.chars().take(RETRIEVAL_QUERY_CHARS).collect();
+    let invalid = |field: &str, message: String| {
+        ApiError::Validation(vec![FieldError { field: field.into(), code: "invalid".into(), message }])
+    };
+    let kind = match options.retrieval.as_deref() {
+        Some(name) => name.parse::<Retrieval>().map_err(|message| invalid("retrieval.retrieval", message))?,
+        None => retrieval::default_retrieval(state),
+    };
+    let search = Search { retrieval: kind, rerank: options.rerank, limit: options.limit.unwrap_or(4) };
+    let scope = retrieval::scope(options.collection_ids.clone());
+    let found = retrieval::search(state, user.user_id, &query, &scope, &search).await.map_err(|e| match e {
+        SearchError::RerankDisabled => invalid("retrieval.rerank", e.to_string()),
+        SearchError::Access(e) => access_error(e),
+        e => {
+            tracing::error!(error = %e, user_id = %user.user_id, "chat retrieval failed");
+            ApiError::Internal
+        }
+    })?;
+    Ok(found.hits)
+}
+
+/// Refuse callers past their daily quota (plus grace); otherwise the
+/// tokens left of the nominal quota, when one is configured
+pub(crate) async fn check_quota(state: &AppState, cfg: &QuotaSection, user: &AuthUser) -> ApiResult<Option<u64>> {
+    if cfg.daily_tokens == 0 {
+        return Ok(None);
+    }
+    let used = quota::tokens_today(&state.db, user.user_id)
+        .timed(&state.metrics, "quota.tokens_today")
+        .await
+        .map_err(|e| {
+            tracing::error!(error = %e, "quota lookup failed");
+            ApiError::Internal
+        })?;
+    if used >= cfg.hard_limit() {
+        return Err(ApiError::RateLimited);
+    }
+    Ok(Some(cfg.daily_tokens.saturating_sub(used)))
+}
+
+/// Sessions and credentials holding `chat:interactive` may run
+/// interactive; everything else is batch. Asking for more is capped.
+pub(crate) fn resolve_priority(user: &AuthUser, requested: Option<Priority>) -> Priority {
+    let highest = if user.has_scope(scope::CHAT_INTERACTIVE) {
+        Priority::Interactive
+    } else {
+        Priority::Batch
+    };
+    requested.map_or(highest, |p| p.max(highest))
+}
+
+/// Open the provider stream behind the output redaction stage
+/// (system-prompt shield + DLP rules), holding `permit` until it ends.
+/// With tools, the stream runs on through every round of tool calls.
+///
+/// The returned stream always ends with a single `done` frame, stamped with
+/// the time spent queued if the request had to wait; mid-stream upstream
+/// errors are yielded first and recorded in metrics. The generation record
+/// is written once the `done` frame passes, hashing the unredacted output.
+async fn start_chat_stream(
+    state: &AppState,
+    user: &AuthUser,
+    prepared: PreparedChat,
+    permit: Permit,
+) -> ApiResult<ChatStream> {
+    let PreparedChat {
+        generation_id,
+        created_at,
+        request,
+        experiment,
+        model,
+        messages,
+        slot,
+        route,
+        cache,
+        conversation,
+        tools,
+        mut citations,
+        options,
+        cfg,
+        ..
+    } = prepared;
+    let queued_ms = permit.queued_for().map(|d| d.as_millis() as u64);
+    let chat_request = ChatRequest { model: model.clone(), messages, tools: tools.specs(), options };
+    let stream = state
+        .provider
+        .chat_stream(chat_request.clone())
+        .await
+        .map_err(|e| {
+            tracing::error!(
+                error = %e,
+                user_id = %user.user_id,
+                model = %model,
+                "chat start failed"
+            );
+            model_error(e)
+        })?;
+    let stream = match tools.is_empty() {
+        true => stream,
+        false => tools::chat_loop(state.clone(), user.user_id, chat_request, stream, tools),
+    };
+    // Listed for operators, who may cut it short, until the stream is dropped
+    let tracked = state.generations.register(generation_id, user.user_id, &model);
+    let stream = tracked.cancellable(stream, &model);
+    let stream = ds_model::with_terminal_frame(stream, model.as_str());
+
+    let metrics = state.metrics.clone();
+    let (db, quota_state) = (state.db.clone(), state.clone());
+    let user_id = user.user_id;
+    let key_usage = user.api_key.as_ref().zip(user.org_id).map(|(key, org)| (key.id, org));
+    let priority = [("priority", permit.priority().as_str())];
+    metrics.incr("deepersensor_chat_generations_total", &priority);
+    metrics.add(QUEUE_WAIT_MS, &priority, queued_ms.unwrap_or(0));
+    let cache_key = (cache_owner(user), model.clone());
+    let mut generation = Some(Generation {
+        id: generation_id,
+        user_id,
+        org_id: user.org_id,
+        api_key_id: user.api_key.as_ref().map(|k| k.id),
+        provider: ds_model::resolve(&state.provider, &model).0.name().to_string(),
+        model,
+        request,
+        finish_reason: String::new(),
+        output_hash: String::new(),
+        output_tokens: 0,
+        queued_ms,
+        replay_of: None,
+        experiment_id: experiment.as_ref().map(|a| a.experiment_id),
+        variant: experiment.map(|a| a.variant),
+        upstream_headers: BTreeMap::new(),
+        created_at,
+    });
+    // On a cache miss the output is collected and stored if it ends cleanly
+    let mut to_cache = match cache {
+        Some(Lookup::Miss(embedding)) => Some((embedding, String::new())),
+        _ => None,
+    };
+    let embedding_model = cfg.semantic_cache.embedding_model.clone();
+    // A conversation turn is stored with its reply unless generation failed
+    let mut to_store = conversation.map(|(id, turn)| (id, turn, String::new(), citations.clone(), state.clone()));
+    // Counted locally and flushed once so the per-chunk path stays lock-free
+    let mut digest = OutputDigest::default();
+    let mut captured = BTreeMap::new();
+    let stream = stream.map(move |mut item| {
+        // Recorded in full; clients only get the allowed ones, on the done frame
+        if let Ok(chunk) = &mut item {
+            if !chunk.upstream_headers.is_empty() {
+                captured = std::mem::take(&mut chunk.upstream_headers);
+            }
+        }
+        match &mut item {
+            Ok(chunk) if chunk.done => {
+                // Held until the stream ends or the client goes away
+                let _ = &permit;
+                slot.sent(chunk.content.len());
+                tracked.update(&chunk.content);
+                chunk.queued_ms = queued_ms;
+                chunk.citations = std::mem::take(&mut citations);
+                let reason = chunk.finish_reason.as_deref().unwrap_or(FINISH_STOP);
+                metrics.incr("deepersensor_chat_streams_total", &[("finish_reason", reason)]);
+                digest.update(&chunk.content);
+                let tokens = digest.tokens();
+                metrics.add("deepersensor_chat_tokens_total", &[("model", &chunk.model)], tokens);
+                if let Some(usage) = chunk.usage {
+                    tracing::info!(
+                        event = "chat.usage",
+                        user_id = %user_id,
+                        model = %chunk.model,
+                        prompt_tokens = usage.prompt_tokens,
+                        completion_tokens = usage.completion_tokens,
+                        "chat usage"
+                    );
+                }
+                chunk.upstream_headers = captured
+                    .iter()
+                    .filter(|(name, _)| cfg.chat.passes_upstream_header(name))
+                    .map(|(name, value)| (generations::pass_through_name(name), value.clone()))
+                    .collect();
+                let mut record = generation.take();
+                if let Some(g) = &mut record {
+                    g.upstream_headers = std::mem::take(&mut captured);
+                    g.finish_reason = reason.to_string();
+                    g.output_hash = digest.hash();
+                    g.output_tokens = tokens;
+                }
+                let cached = to_cache.take().filter(|_| reason == FINISH_STOP).map(|(embedding, mut text)| {
+                    text.push_str(&chunk.content);
+                    (embedding, text)
+                });
+                let stored =
+                    to_store.take().filter(|_| reason != FINISH_ERROR).map(|(id, turn, mut text, citations, state)| {
+                        text.push_str(&chunk.content);
+                        (id, turn, text, citations, state)
+                    });
+                let (db, quota_state, cfg) = (db.clone(), quota_state.clone(), cfg.clone());
+                let (cache_key, embedding_model) = (cache_key.clone(), embedding_model.clone());
+                tokio::spawn(async move {
+                    if let Some((embedding, text)) = cached {
+                        let (owner, model) = (cache_key.0, cache_key.1.as_str());
+                        if let Err(e) =
+                            semantic_cache::store(&db, owner, route, model, &embedding_model, &embedding, &text).await
+                        {
+                            tracing::warn!(error = %e, "storing semantic cache entry failed");
+                        }
+                    }
+                    if let Some(g) = record {
+                        if let Err(e) = generations::record(&db, &g).await {
+                            tracing::warn!(error = %e, generation_id = %g.id, "recording generation failed");
+                        }
+                    }
+                    quota::record(&quota_state, &cfg.quota, user_id, tokens).await;
+                    if let Some((key_id, org_id)) = key_usage {
+                        let day = chrono::Utc::now().date_naive();
+                        let usage = api_keys::KeyUsage { key_id, org_id, user_id, day, tokens };
+                        quota_state.usage.keys.push(usage).await;
+                    }
+                    if let Some((id, turn, reply, citations, state)) = stored {
+                        let message_id = match conversations::append(&db, id, &turn, &reply, &citations).await {
+                            Ok(message_id) => message_id,
+                            Err(e) => {
+                                tracing::warn!(error = %e, conversation_id = %id, "storing conversation turn failed");
+                                return;
+                            }
+                        };
+                        if !state.processors.is_empty() {
+                            let turn = enrich::Turn {
+                                conversation_id: id,
+                                message_id,
+                                user_id,
+                                model: cache_key.1.clone(),
+                                messages: turn,
+                                reply,
+                            };
+                            let state = state.clone();
+                            tokio::spawn(async move { state.processors.run(&state, &turn).await });
+                        }
+                        // Summarization passes count against the quota like the chat
+                        let summarized = conversations::compact(&state, id, &cache_key.1).await.unwrap_or(0);
+                        if summarized > 0 {
+                            quota::record(&state, &cfg.quota, user_id, summarized).await;
+                        }
+                    }
+                });
+            }
+            Ok(chunk) => {
+                slot.sent(chunk.content.len());
+                tracked.update(&chunk.content);
+                digest.update(&chunk.content);
+                if let Some((_, text)) = &mut to_cache {
+                    text.push_str(&chunk.content);
+                }
+                if let Some((_, _, text, ..)) = &mut to_store {
+                    text.push_str(&chunk.content);
+                }
+            }
+            Err(e) => {
+                to_cache = None;
+                to_store = None;
+                tracing::error!(error = %e, user_id = %user_id, "chat stream failed mid-generation");
+                metrics.incr("deepersensor_chat_stream_errors_total", &[]);
+            }
+        }
+        item
+    });
+    Ok(state.redactor.filter(Box::pin(stream)))
+}
+
+/// `X-Generation-Id` (unless served from cache), `X-Cache` when the
+/// semantic cache was consulted, and `X-Quota-Remaining` when a daily
+/// quota is configured
+fn chat_headers(prepared: &PreparedChat) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    match prepared.cache {
+        Some(Lookup::Hit(_)) => {
+            headers.insert(CACHE_STATUS, HeaderValue::from_static("hit"));
+        }
+        ref cache => {
+            let id = HeaderValue::from_str(&prepared.generation_id.to_string()).expect("uuid is a valid header");
+            headers.insert(GENERATION_ID, id);
+            if cache.is_some() {
+                headers.insert(CACHE_STATUS, HeaderValue::from_static("miss"));
+            }
+        }
+    }
+    if let Some(remaining) = prepared.quota_remaining {
+        headers.insert(QUOTA_REMAINING, HeaderValue::from(remaining));
+    }
+    headers
+}
@@ -0,0 +1,165 @@
+//! Collections of documents (JWT or API key; `chat:write` to create,
+//! replace, or delete, `chat:read` to list or read them or their
+//! re-embedding progress). Who may do what is decided in
+//! [`crate::collections`]
+
+use crate::{
+    auth_middleware::{require_auth, require_scope, AuthUser},
+    collections::{self, Access, AccessError, Collection},
+    db_metrics::Timed,
+    extract::{rules, ValidatedJson},
+    reembed::{self, Progress, Target},
+    state::AppState,
+};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    middleware,
+    routing::{get, post, put},
+    Extension, Json, Router,
+};
+use ds_auth::scope;
+use ds_core::error::{ApiError, ApiResult, FieldError};
+use ds_types::{CollectionId, OrgId};
+use serde::{Deserialize, Serialize};
+use validator::Validate;
+
+pub fn router() -> Router<AppState> {
+    let write = Router::new()
+        .route("/v1/collections", post(create_collection))
+        .route("/v1/collections/{id}", put(replace_collection).delete(delete_collection))
+        .route_layer(middleware::from_fn(|req, next| require_scope(scope::CHAT_WRITE, req, next)));
+    let read = Router::new()
+        .route("/v1/collections", get(list_collections))
+        .route("/v1/collections/{id}", get(get_collection))
+        .route("/v1/collections/{id}/reembedding", get(get_reembedding))
+        .route_layer(middleware::from_fn(|req, next| require_scope(scope::CHAT_READ, req, next)));
+    write.merge(read).route_layer(middleware::from_fn(require_auth))
+}
+
+/// Collections the caller cannot read are not found; ones it may read but
+/// not change are forbidden
+pub(crate) fn access_error(e: AccessError) -> ApiError {
+    match e {
+        AccessError::NotFound => ApiError::NotFound,
+        AccessError::Forbidden(_) => ApiError::Forbidden,
+        AccessError::NotMember => ApiError::Validation(vec![FieldError {
+            field: "org_id".into(),
+            code: "not_member".into(),
+            message: e.to_string(),
+        }]),
+        AccessError::Db(e) => {
+            tracing::error!(error = %e, "collection query failed");
+            ApiError::Internal
+        }
+    }
+}
+
+#[derive(Deserialize, Validate)]
+struct CreateIn {
+    #[validate(length(min = 1, max = 100, message = "between 1 and 100 characters required"))]
+    name: String,
+    /// Share with this org, which the caller must belong to
+    org_id: Option<OrgId>,
+    /// `documents.embedding_model` by default
+    #[validate(custom(function = "rules::model_name"))]
+    embedding_model: Option<String>,
+}
+
+async fn create_collection(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    ValidatedJson(input): ValidatedJson<CreateIn>,
+) -> ApiResult<(StatusCode, Json<Collection>)> {
+    let model = input.embedding_model.unwrap_or_else(|| state.config().documents.embedding_model.clone());
+    let collection =
+        collections::create(&state.db, user.user_id, &input.name, input.org_id, &model).await.map_err(access_error)?;
+    tracing::info!(
+        collection_id = %collection.id,
+        org_id = ?collection.org_id,
+        by = %user.user_id,
+        "audit.collection.created"
+    );
+    Ok((StatusCode::CREATED, Json(collection)))
+}
+
+#[derive(Serialize)]
+struct CollectionsOut {
+    collections: Vec<Collection>,
+}
+
+/// The caller's collections and those shared with its orgs
+async fn list_collections(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+) -> ApiResult<Json<CollectionsOut>> {
+    let collections = collections::list(&state.db, user.user_id)
+        .timed(&state.metrics, "collections.list")
+        .await
+        .map_err(|e| access_error(e.into()))?;
+    Ok(Json(CollectionsOut { collections }))
+}
+
+async fn get_collection(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Path(id): Path<CollectionId>,
+) -> ApiResult<Json<Collection>> {
+    collections::authorize(&state.db, user.user_id, id, Access::Read).await.map(Json).map_err(access_error)
+}
+
+#[derive(Deserialize, Validate)]
+struct ReplaceIn {
+    #[validate(length(min = 1, max = 100, message = "between 1 and 100 characters required"))]
+    name: String,
+    /// Absent to stop sharing it
+    org_id: Option<OrgId>,
+    /// Absent to keep it; changing it queues the collection's chunks to be
+    /// re-embedded
+    #[validate(custom(function = "rules::model_name"))]
+    embedding_model: Option<String>,
+}
+
+/// Rename a collection, set whom it is shared with, and change its
+/// embedding model; only its owner may
+async fn replace_collection(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Path(id): Path<CollectionId>,
+    ValidatedJson(input): ValidatedJson<ReplaceIn>,
+) -> ApiResult<Json<Collection>> {
+    let model = input.embedding_model.as_deref();
+    let collection =
+        collections::update(&state.db, user.user_id, id, &input.name, input.org_id, model).await.map_err(access_error)?;
+    tracing::info!(collection_id = %id, org_id = ?collection.org_id, by = %user.user_id, "audit.collection.replaced");
+    if reembed::enqueue(&state, Target::Collection(id)).await.map_err(|e| access_error(e.into()))? {
+        tracing::info!(collection_id = %id, model = %collection.embedding_model, "collection queued for re-embedding");
+    }
+    Ok(Json(collection))
+}
+
+/// The latest re-embedding of a collection the caller may read
+async fn get_reembedding(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Path(id): Path<CollectionId>,
+) -> ApiResult<Json<Progress>> {
+    collections::authorize(&state.db, user.user_id, id, Access::Read).await.map_err(access_error)?;
+    reembed::latest(&state, Target::Collection(id))
+        .timed(&state.metrics, "reembed.latest")
+        .await
+        .map_err(|e| access_error(e.into()))?
+        .map(Json)
+        .ok_or(ApiError::NotFound)
+}
+
+/// Delete a collection and its documents; only its owner may
+async fn delete_collection(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Path(id): Path<CollectionId>,
+) -> ApiResult<StatusCode> {
+    collections::delete(&state.db, user.user_id, id).await.map_err(access_error)?;
+    tracing::info!(collection_id = %id, by = %user.user_id, "audit.collection.deleted");
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,83 @@
+//! Stored conversations (JWT or API key; `chat:write` to create, `chat:read`
+//! to read). Turns are added by chatting with a `conversation_id`.
+
+use crate::{
+    auth_middleware::{require_auth, require_scope, AuthUser},
+    conversations::{self, Conversation, Message},
+    db_metrics::Timed,
+    etag,
+    extract::ValidatedJson,
+    state::AppState,
+};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    middleware,
+    routing::{get, post},
+    Extension, Json, Router,
+};
+use ds_auth::scope;
+use ds_core::error::{ApiError, ApiResult};
+use ds_types::ConversationId;
+use serde::Deserialize;
+use validator::Validate;
+
+pub fn router() -> Router<AppState> {
+    let write = Router::new()
+        .route("/v1/conversations", post(create_conversation))
+        .route_layer(middleware::from_fn(|req, next| require_scope(scope::CHAT_WRITE, req, next)));
+    let read = Router::new()
+        .route("/v1/conversations/{id}", get(get_conversation))
+        .route("/v1/conversations/{id}/messages", get(list_messages))
+        .route_layer(middleware::from_fn(etag::conditional))
+        .route_layer(middleware::from_fn(|req, next| require_scope(scope::CHAT_READ, req, next)));
+    write.merge(read).route_layer(middleware::from_fn(require_auth))
+}
+
+fn db_error(e: sqlx::Error) -> ApiError {
+    tracing::error!(error = %e, "conversation query failed");
+    ApiError::Internal
+}
+
+#[derive(Deserialize, Validate)]
+struct CreateConversationIn {
+    #[validate(length(min = 1, max = 200, message = "between 1 and 200 characters required"))]
+    title: String,
+}
+
+async fn create_conversation(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    ValidatedJson(input): ValidatedJson<CreateConversationIn>,
+) -> ApiResult<(StatusCode, Json<Conversation>)> {
+    let conversation = conversations::create(&state.db, user.user_id, &input.title)
+        .timed(&state.metrics, "conversations.create")
+        .await
+        .map_err(db_error)?;
+    Ok((StatusCode::CREATED, Json(conversation)))
+}
+
+async fn get_conversation(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Path(id): Path<ConversationId>,
+) -> ApiResult<Json<Conversation>> {
+    let conversation = conversations::get(state.db_router.read(), user.user_id, id)
+        .timed(&state.metrics, "conversations.get")
+        .await
+        .map_err(db_error)?;
+    conversation.map(Json).ok_or(ApiError::NotFound)
+}
+
+/// Messages oldest first, with any post-generation `metadata`
+async fn list_messages(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Path(id): Path<ConversationId>,
+) -> ApiResult<Json<Vec<Message>>> {
+    let messages = conversations::messages(state.db_router.read(), user.user_id, id)
+        .timed(&state.metrics, "conversations.messages")
+        .await
+        .map_err(db_error)?;
+    messages.map(Json).ok_or(ApiError::NotFound)
+}
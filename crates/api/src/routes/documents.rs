@@ -0,0 +1,264 @@
+//! Documents for the `rag_search` tool: text or stored files added (JWT or
+//! API key with `chat:write`, rate limited per IP), optionally to a
+//! collection the caller may write to, looked up by the owner or the
+//! collection's readers (`chat:read`), and searched as the tool would
+//! (`chat:read`, rate limited per IP). `GET /v1/documents/reembedding`
+//! reports re-embedding of the caller's documents outside collections
+
+use crate::{
+    auth_middleware::{require_auth, require_scope, AuthUser},
+    chunking::Strategy,
+    collections::{self, Access, Collection},
+    db_metrics::Timed,
+    documents::{self, Document},
+    extract::ValidatedJson,
+    rate_limit,
+    reembed::{self, Progress, Target},
+    retrieval::{self, Hit, Retrieval, Search, SearchError},
+    routes::collections::access_error,
+    state::AppState,
+};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    middleware,
+    routing::{get, post},
+    Extension, Json, Router,
+};
+use ds_auth::scope;
+use ds_core::error::{ApiError, ApiResult, FieldError};
+use ds_types::{CollectionId, DocumentId, FileId};
+use serde::{Deserialize, Serialize};
+use validator::Validate;
+
+pub fn router() -> Router<AppState> {
+    let add = Router::new()
+        .route("/v1/documents", post(add))
+        .route("/v1/documents/files", post(add_file))
+        .route_layer(middleware::from_fn(rate_limit::per_ip))
+        .route_layer(middleware::from_fn(|req, next| require_scope(scope::CHAT_WRITE, req, next)))
+        .route_layer(middleware::from_fn(require_auth));
+    let read = Router::new()
+        .route("/v1/documents/reembedding", get(get_reembedding))
+        .route("/v1/documents/{id}", get(get_document))
+        .route_layer(middleware::from_fn(|req, next| require_scope(scope::CHAT_READ, req, next)))
+        .route_layer(middleware::from_fn(require_auth));
+    let search = Router::new()
+        .route("/v1/documents/search", post(search))
+        .route_layer(middleware::from_fn(rate_limit::per_ip))
+        .route_layer(middleware::from_fn(|req, next| require_scope(scope::CHAT_READ, req, next)))
+        .route_layer(middleware::from_fn(require_auth));
+    add.merge(read).merge(search)
+}
+
+#[derive(Deserialize, Validate)]
+struct AddIn {
+    #[validate(length(min = 1, max = 200, message = "between 1 and 200 characters required"))]
+    title: String,
+    /// At most `documents.max_chars` characters
+    #[validate(length(min = 1, message = "must not be empty"))]
+    text: String,
+    /// `fixed`, `sentence`, or `semantic`; `documents.chunking` by default
+    chunking: Option<String>,
+    /// A collection the caller may add documents to
+    collection_id: Option<CollectionId>,
+}
+
+#[derive(Serialize)]
+struct AddOut {
+    id: DocumentId,
+    title: String,
+    chunks: usize,
+}
+
+/// The collection a document is added to, if the caller may write to it
+async fn collection(state: &AppState, user: &AuthUser, id: Option<CollectionId>) -> ApiResult<Option<Collection>> {
+    let Some(id) = id else { return Ok(None) };
+    collections::authorize(&state.db, user.user_id, id, Access::Write).await.map(Some).map_err(access_error)
+}
+
+/// The requested strategy, or the configured one
+fn strategy(state: &AppState, name: Option<&str>) -> ApiResult<Strategy> {
+    let Some(name) = name else { return Ok(documents::default_strategy(state)) };
+    name.parse().map_err(|message| {
+        ApiError::Validation(vec![FieldError { field: "chunking".into(), code: "invalid".into(), message }])
+    })
+}
+
+async fn add(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    ValidatedJson(input): ValidatedJson<AddIn>,
+) -> ApiResult<(StatusCode, Json<AddOut>)> {
+    let max_chars = state.config().documents.max_chars;
+    if input.text.chars().count() as u64 > max_chars {
+        return Err(ApiError::Validation(vec![FieldError {
+            field: "text".into(),
+            code: "length".into(),
+            message: format!("at most {max_chars} characters"),
+        }]));
+    }
+    let strategy = strategy(&state, input.chunking.as_deref())?;
+    let collection = collection(&state, &user, input.collection_id).await?;
+    let (id, chunks) = documents::add(&state, user.user_id, &input.title, &input.text, strategy, collection.as_ref())
+        .await
+        .map_err(|e| {
+            tracing::error!(error = %e, user_id = %user.user_id, "adding document failed");
+            ApiError::Internal
+        })?;
+    tracing::info!(
+        document_id = %id,
+        collection_id = ?input.collection_id,
+        by = %user.user_id,
+        chunks,
+        "audit.document.added"
+    );
+    Ok((StatusCode::CREATED, Json(AddOut { id, title: input.title, chunks })))
+}
+
+#[derive(Deserialize, Validate)]
+struct AddFileIn {
+    /// One of the caller's files, in a media type `ds_parse` reads
+    file_id: FileId,
+    /// Taken from the file's metadata when absent
+    #[validate(length(min = 1, max = 200, message = "between 1 and 200 characters required"))]
+    title: Option<String>,
+    chunking: Option<String>,
+    collection_id: Option<CollectionId>,
+}
+
+/// Queue one of the caller's files to become a document; it is `pending`
+/// until the `documents` job has parsed and indexed it
+async fn add_file(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    ValidatedJson(input): ValidatedJson<AddFileIn>,
+) -> ApiResult<(StatusCode, Json<Document>)> {
+    let strategy = strategy(&state, input.chunking.as_deref())?;
+    let collection = collection(&state, &user, input.collection_id).await?;
+    let content_type = documents::file_type(&state.db, user.user_id, input.file_id)
+        .timed(&state.metrics, "documents.file_type")
+        .await
+        .map_err(|e| {
+            tracing::error!(error = %e, "file lookup failed");
+            ApiError::Internal
+        })?
+        .ok_or(ApiError::NotFound)?;
+    if !ds_parse::supported(&content_type) {
+        return Err(ApiError::Validation(vec![FieldError {
+            field: "file_id".into(),
+            code: "unsupported".into(),
+            message: format!("{content_type} is not one of {}", ds_parse::SUPPORTED.join(", ")),
+        }]));
+    }
+    let title = input.title.unwrap_or_default();
+    let internal = |e: sqlx::Error| {
+        tracing::error!(error = %e, user_id = %user.user_id, "adding document failed");
+        ApiError::Internal
+    };
+    let collection_id = collection.map(|c| c.id);
+    let id = documents::add_file(&state.db, user.user_id, input.file_id, &content_type, &title, strategy, collection_id)
+        .timed(&state.metrics, "documents.add_file")
+        .await
+        .map_err(internal)?;
+    let document = documents::get(&state.db, user.user_id, id).await.map_err(internal)?.ok_or(ApiError::Internal)?;
+    tracing::info!(
+        document_id = %id,
+        file_id = %input.file_id,
+        collection_id = ?collection_id,
+        by = %user.user_id,
+        "audit.document.queued"
+    );
+    Ok((StatusCode::ACCEPTED, Json(document)))
+}
+
+/// One of the caller's documents, with its processing status
+async fn get_document(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Path(id): Path<DocumentId>,
+) -> ApiResult<Json<Document>> {
+    documents::get(&state.db, user.user_id, id)
+        .timed(&state.metrics, "documents.get")
+        .await
+        .map_err(|e| {
+            tracing::error!(error = %e, "document lookup failed");
+            ApiError::Internal
+        })?
+        .map(Json)
+        .ok_or(ApiError::NotFound)
+}
+
+#[derive(Deserialize, Validate)]
+struct SearchIn {
+    #[validate(length(min = 1, max = 2000, message = "between 1 and 2000 characters required"))]
+    query: String,
+    #[validate(range(min = 1, max = 50, message = "between 1 and 50"))]
+    limit: Option<usize>,
+    /// `vector`, `keyword`, or `hybrid`; `documents.retrieval` by default
+    retrieval: Option<String>,
+    /// Reorder the results with `documents.rerank_model`
+    #[serde(default)]
+    rerank: bool,
+    /// Search these collections instead of the caller's own documents
+    #[serde(default)]
+    #[validate(length(max = 16, message = "at most 16 collections"))]
+    collection_ids: Vec<CollectionId>,
+}
+
+#[derive(Serialize)]
+struct SearchOut {
+    retrieval: &'static str,
+    reranked: bool,
+    results: Vec<Hit>,
+    /// Chunks left out of vector ranking until re-embedded
+    stale_chunks: u64,
+}
+
+/// The chunks best matching a query, of the caller's documents or of
+/// collections it may read
+async fn search(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    ValidatedJson(input): ValidatedJson<SearchIn>,
+) -> ApiResult<Json<SearchOut>> {
+    let invalid = |field: &str, message: String| {
+        ApiError::Validation(vec![FieldError { field: field.into(), code: "invalid".into(), message }])
+    };
+    let retrieval = match input.retrieval.as_deref() {
+        Some(name) => name.parse::<Retrieval>().map_err(|message| invalid("retrieval", message))?,
+        None => retrieval::default_retrieval(&state),
+    };
+    let search = Search { retrieval, rerank: input.rerank, limit: input.limit.unwrap_or(4) };
+    let scope = retrieval::scope(input.collection_ids);
+    let found = retrieval::search(&state, user.user_id, &input.query, &scope, &search).await.map_err(|e| match e {
+        SearchError::RerankDisabled => invalid("rerank", e.to_string()),
+        SearchError::Access(e) => access_error(e),
+        e => {
+            tracing::error!(error = %e, user_id = %user.user_id, "document search failed");
+            ApiError::Internal
+        }
+    })?;
+    Ok(Json(SearchOut {
+        retrieval: retrieval.as_str(),
+        reranked: input.rerank,
+        results: found.hits,
+        stale_chunks: found.stale,
+    }))
+}
+
+/// The latest re-embedding of the caller's documents outside collections
+async fn get_reembedding(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+) -> ApiResult<Json<Progress>> {
+    reembed::latest(&state, Target::Own(user.user_id))
+        .timed(&state.metrics, "reembed.latest")
+        .await
+        .map_err(|e| {
+            tracing::error!(error = %e, "re-embedding lookup failed");
+            ApiError::Internal
+        })?
+        .map(Json)
+        .ok_or(ApiError::NotFound)
+}
@@ -0,0 +1,139 @@
+//! Eval runner under `/v1/admin` (admin role, `admin:*` scope)
+
+use crate::{
+    auth_middleware::{require_admin, require_auth, require_scope, AuthUser},
+    db_metrics::Timed,
+    evals::{self, EvalCase, Progress, RunOut},
+    extract::ValidatedJson,
+    state::AppState,
+};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    middleware,
+    response::sse::{Event, Sse},
+    routing::{get, post},
+    Extension, Json, Router,
+};
+use ds_auth::scope;
+use ds_core::error::{ApiError, ApiResult};
+use ds_types::{EvalRunId, EvalSetId};
+use futures_util::Stream;
+use serde::{Deserialize, Serialize};
+use validator::{Validate, ValidationError};
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/v1/admin/evals", post(create_set))
+        .route("/v1/admin/evals/{id}/runs", post(start_run))
+        .route("/v1/admin/eval-runs/{run_id}", get(get_run))
+        .route_layer(middleware::from_fn(require_admin))
+        .route_layer(middleware::from_fn(|req, next| require_scope(scope::ADMIN_ALL, req, next)))
+        .route_layer(middleware::from_fn(require_auth))
+}
+
+fn db_error(e: sqlx::Error) -> ApiError {
+    tracing::error!(error = %e, "eval query failed");
+    ApiError::Internal
+}
+
+#[derive(Deserialize, Validate)]
+struct CreateSetIn {
+    #[validate(length(min = 1, max = 100, message = "between 1 and 100 characters required"))]
+    name: String,
+    #[validate(length(min = 1, max = 500, message = "between 1 and 500 cases required"), nested)]
+    cases: Vec<EvalCase>,
+}
+
+#[derive(Serialize)]
+struct SetOut {
+    id: EvalSetId,
+    name: String,
+    cases: usize,
+}
+
+async fn create_set(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    ValidatedJson(input): ValidatedJson<CreateSetIn>,
+) -> ApiResult<(StatusCode, Json<SetOut>)> {
+    let id = evals::create_set(&state.db, &input.name, user.user_id, &input.cases)
+        .timed(&state.metrics, "evals.create_set")
+        .await
+        .map_err(db_error)?;
+    tracing::info!(eval_set_id = %id, by = %user.user_id, cases = input.cases.len(), "audit.eval_set.created");
+    Ok((StatusCode::CREATED, Json(SetOut { id, name: input.name, cases: input.cases.len() })))
+}
+
+#[derive(Deserialize, Validate)]
+struct StartRunIn {
+    #[validate(length(min = 1, max = 8, message = "between 1 and 8 models required"))]
+    #[validate(custom(function = "model_names"))]
+    models: Vec<String>,
+}
+
+fn model_names(models: &[String]) -> Result<(), ValidationError> {
+    models.iter().try_for_each(|m| crate::extract::rules::model_name(m))
+}
+
+#[derive(Serialize)]
+struct StartedOut {
+    run_id: EvalRunId,
+    total: usize,
+}
+
+#[derive(Serialize)]
+struct ResultOut<'a> {
+    #[serde(flatten)]
+    result: &'a evals::CaseResult,
+    completed: usize,
+    total: usize,
+}
+
+/// Start a run and stream its progress: `started`, one `result` per case
+/// and model as they finish, then `done` with per-model scores. The run
+/// continues and is stored even if the client disconnects.
+async fn start_run(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Path(id): Path<EvalSetId>,
+    ValidatedJson(input): ValidatedJson<StartRunIn>,
+) -> ApiResult<Sse<impl Stream<Item = Result<Event, axum::Error>>>> {
+    let cases = evals::get_cases(&state.db, id)
+        .timed(&state.metrics, "evals.get_cases")
+        .await
+        .map_err(db_error)?
+        .ok_or(ApiError::NotFound)?;
+    let run_id = evals::create_run(&state.db, id, &input.models)
+        .timed(&state.metrics, "evals.create_run")
+        .await
+        .map_err(db_error)?;
+    tracing::info!(eval_set_id = %id, run_id = %run_id, by = %user.user_id, "audit.eval_run.started");
+    let started = StartedOut { run_id, total: cases.len() * input.models.len() };
+
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(evals::run(state, run_id, user.user_id, cases, input.models, tx));
+    Ok(Sse::new(async_stream::stream! {
+        yield Event::default().event("started").json_data(&started);
+        while let Some(progress) = rx.recv().await {
+            yield match progress {
+                Progress::Result { result, completed, total } => {
+                    Event::default().event("result").json_data(ResultOut { result: &result, completed, total })
+                }
+                Progress::Done(summary) => {
+                    Event::default().event("done").json_data(serde_json::json!({ "run_id": run_id, "summary": summary }))
+                }
+            };
+        }
+    }))
+}
+
+async fn get_run(State(state): State<AppState>, Path(run_id): Path<EvalRunId>) -> ApiResult<Json<RunOut>> {
+    let run = evals::get_run(&state.db, run_id)
+        .timed(&state.metrics, "evals.get_run")
+        .await
+        .map_err(db_error)?
+        .ok_or(ApiError::NotFound)?;
+    Ok(Json(run))
+}
+
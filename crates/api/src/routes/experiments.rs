@@ -0,0 +1,117 @@
+//! A/B model experiments under `/v1/admin` (admin role, `admin:*` scope)
+
+use crate::{
+    auth_middleware::{require_admin, require_auth, require_scope, AuthUser},
+    db_metrics::Timed,
+    experiments::{self, Results, Variant},
+    extract::{rules, ValidatedJson},
+    state::AppState,
+};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    middleware,
+    routing::{get, post},
+    Extension, Json, Router,
+};
+use ds_auth::scope;
+use ds_core::error::{ApiError, ApiResult, FieldError};
+use ds_types::ExperimentId;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use validator::Validate;
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/v1/admin/experiments", post(create_experiment))
+        .route("/v1/admin/experiments/{id}/stop", post(stop_experiment))
+        .route("/v1/admin/experiments/{id}/results", get(results))
+        .route_layer(middleware::from_fn(require_admin))
+        .route_layer(middleware::from_fn(|req, next| require_scope(scope::ADMIN_ALL, req, next)))
+        .route_layer(middleware::from_fn(require_auth))
+}
+
+fn db_error(e: sqlx::Error) -> ApiError {
+    tracing::error!(error = %e, "experiment query failed");
+    ApiError::Internal
+}
+
+#[derive(Deserialize, Validate)]
+struct CreateExperimentIn {
+    #[validate(length(min = 1, max = 100, message = "between 1 and 100 characters required"))]
+    name: String,
+    /// Chats requesting this model are split across the variants
+    #[validate(custom(function = "rules::model_name"))]
+    model: String,
+    #[validate(length(min = 2, max = 10, message = "between 2 and 10 variants required"), nested)]
+    variants: Vec<Variant>,
+}
+
+#[derive(Serialize)]
+struct ExperimentOut {
+    id: ExperimentId,
+    name: String,
+    model: String,
+    variants: Vec<Variant>,
+}
+
+async fn create_experiment(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    ValidatedJson(input): ValidatedJson<CreateExperimentIn>,
+) -> ApiResult<(StatusCode, Json<ExperimentOut>)> {
+    let mut seen = HashSet::new();
+    let duplicates: Vec<FieldError> = input
+        .variants
+        .iter()
+        .enumerate()
+        .filter(|(_, v)| !seen.insert(v.name.as_str()))
+        .map(|(i, _)| FieldError {
+            field: format!("variants[{i}].name"),
+            code: "duplicate".into(),
+            message: "variant names must be unique".into(),
+        })
+        .collect();
+    if !duplicates.is_empty() {
+        return Err(ApiError::Validation(duplicates));
+    }
+
+    let id = match experiments::create(&state.db, &input.name, &input.model, &input.variants)
+        .timed(&state.metrics, "experiments.create")
+        .await {
+            Ok(id) => id,
+            Err(e) if e.as_database_error().is_some_and(|d| d.is_unique_violation()) => {
+                return Err(ApiError::Unprocessable(
+                    "an experiment with this name, or one running on this model, already exists".into(),
+                ));
+            }
+            Err(e) => return Err(db_error(e)),
+        };
+    tracing::info!(experiment_id = %id, model = %input.model, by = %user.user_id, "audit.experiment.created");
+    Ok((
+        StatusCode::CREATED,
+        Json(ExperimentOut { id, name: input.name, model: input.model, variants: input.variants }),
+    ))
+}
+
+/// Stop routing chats through the experiment; results stay available
+async fn stop_experiment(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Path(id): Path<ExperimentId>,
+) -> ApiResult<StatusCode> {
+    if !experiments::stop(&state.db, id).timed(&state.metrics, "experiments.stop").await.map_err(db_error)? {
+        return Err(ApiError::NotFound);
+    }
+    tracing::info!(experiment_id = %id, by = %user.user_id, "audit.experiment.stopped");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn results(State(state): State<AppState>, Path(id): Path<ExperimentId>) -> ApiResult<Json<Results>> {
+    let results = experiments::results(&state.db, id)
+        .timed(&state.metrics, "experiments.results")
+        .await
+        .map_err(db_error)?
+        .ok_or(ApiError::NotFound)?;
+    Ok(Json(results))
+}
@@ -0,0 +1,207 @@
+//! Stored files: uploads (JWT or API key with `chat:write`, rate limited per
+//! IP), downloads by signed URL (no session; rate limited per IP), fresh
+//! URLs for the owner (`chat:read`), and deletion by the owner
+//! (`chat:write`)
+
+use crate::{
+    auth_middleware::{require_auth, require_scope, AuthUser},
+    context::RequestContext,
+    db_metrics::Timed,
+    extract::ValidatedJson,
+    files, rate_limit,
+    state::AppState,
+};
+use axum::{
+    body::Body,
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    middleware,
+    response::IntoResponse,
+    routing::{delete, get, post},
+    Extension, Json, Router,
+};
+use chrono::{DateTime, Utc};
+use ds_auth::scope;
+use ds_core::error::{ApiError, ApiResult, FieldError};
+use ds_types::FileId;
+use serde::{Deserialize, Serialize};
+use validator::Validate;
+
+/// Signed URL downloads by result (`ok`, `bad_signature`, `expired`,
+/// `not_found`)
+pub const DOWNLOADS: &str = "deepersensor_file_downloads_total";
+
+pub fn router() -> Router<AppState> {
+    let download = Router::new()
+        .route("/v1/files/{id}/download", get(download))
+        .route_layer(middleware::from_fn(rate_limit::per_ip));
+    let upload = Router::new()
+        .route("/v1/files", post(upload))
+        .route_layer(middleware::from_fn(rate_limit::per_ip))
+        .route_layer(middleware::from_fn(|req, next| require_scope(scope::CHAT_WRITE, req, next)))
+        .route_layer(middleware::from_fn(require_auth));
+    let sign = Router::new()
+        .route("/v1/files/{id}/url", post(sign_url))
+        .route_layer(middleware::from_fn(|req, next| require_scope(scope::CHAT_READ, req, next)))
+        .route_layer(middleware::from_fn(require_auth));
+    let remove = Router::new()
+        .route("/v1/files/{id}", delete(delete_file))
+        .route_layer(middleware::from_fn(|req, next| require_scope(scope::CHAT_WRITE, req, next)))
+        .route_layer(middleware::from_fn(require_auth));
+    download.merge(upload).merge(sign).merge(remove)
+}
+
+#[derive(Serialize)]
+pub(crate) struct UploadOut {
+    id: FileId,
+    content_type: String,
+    bytes: usize,
+    url: String,
+    expires_at: DateTime<Utc>,
+}
+
+impl UploadOut {
+    /// A stored file with a fresh download URL
+    pub(crate) fn new(state: &AppState, id: FileId, content_type: String, bytes: usize) -> Self {
+        let (url, expires_at) = files::signed_url(state.config(), id, state.config().files.url_ttl_secs);
+        Self { id, content_type, bytes, url, expires_at }
+    }
+}
+
+/// The raw file is the request body, typed by its `Content-Type`; it is
+/// scanned before it is stored
+async fn upload(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    headers: HeaderMap,
+    body: Body,
+) -> ApiResult<(StatusCode, Json<UploadOut>)> {
+    let content_type = content_type(&headers);
+    let limit = state.config().files.max_upload_bytes as usize;
+    let data = axum::body::to_bytes(body, limit)
+        .await
+        .map_err(|_| ApiError::PayloadTooLarge(format!("file exceeds {limit} bytes")))?;
+    if data.is_empty() {
+        return Err(ApiError::BadRequest("file body is empty".into()));
+    }
+    files::check_quota(&state, &state.db, user.user_id, data.len() as u64).await?;
+    let id = files::ingest(&state, user.user_id, &content_type, &data).await?;
+    tracing::info!(file_id = %id, by = %user.user_id, bytes = data.len(), "audit.file.uploaded");
+    Ok((StatusCode::CREATED, Json(UploadOut::new(&state, id, content_type, data.len()))))
+}
+
+/// The media type without parameters, `application/octet-stream` when
+/// missing
+fn content_type(headers: &HeaderMap) -> String {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.split(';').next().unwrap_or("").trim().to_ascii_lowercase())
+        .filter(|v| !v.is_empty())
+        .unwrap_or_else(|| "application/octet-stream".into())
+}
+
+#[derive(Deserialize)]
+struct DownloadQuery {
+    exp: i64,
+    sig: String,
+}
+
+async fn download(
+    State(state): State<AppState>,
+    Path(id): Path<FileId>,
+    Query(query): Query<DownloadQuery>,
+    ctx: RequestContext,
+) -> ApiResult<impl IntoResponse> {
+    let now = Utc::now();
+    if let Err(denied) = files::verify(state.config().file_signing_secret(), id, query.exp, &query.sig, now) {
+        state.metrics.incr(DOWNLOADS, &[("result", denied.as_str())]);
+        tracing::warn!(file_id = %id, ip = %ctx.ip, reason = denied.as_str(), "audit.file.download_denied");
+        return Err(ApiError::Forbidden);
+    }
+    let file = files::get(&state, id).await.map_err(|e| {
+        tracing::error!(error = %e, "file lookup failed");
+        ApiError::Internal
+    })?;
+    let Some(file) = file else {
+        // Signed, then deleted
+        state.metrics.incr(DOWNLOADS, &[("result", "not_found")]);
+        return Err(ApiError::NotFound);
+    };
+    state.metrics.incr(DOWNLOADS, &[("result", "ok")]);
+    tracing::info!(
+        file_id = %id,
+        owner = %file.user_id,
+        ip = %ctx.ip,
+        bytes = file.bytes,
+        expires = query.exp,
+        "audit.file.downloaded"
+    );
+    // Cacheable by the client only until the URL expires
+    let cache_control = format!("private, max-age={}", query.exp - now.timestamp());
+    let headers = [
+        (header::CONTENT_TYPE, file.content_type),
+        (header::CONTENT_LENGTH, file.bytes.to_string()),
+        (header::CACHE_CONTROL, cache_control),
+    ];
+    Ok((headers, Body::from_stream(file.data)))
+}
+
+#[derive(Deserialize, Validate)]
+struct SignIn {
+    /// Defaults to `files.url_ttl_secs`; at most `files.max_url_ttl_secs`
+    #[validate(range(min = 1, message = "at least 1 second"))]
+    expires_in_secs: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct SignOut {
+    url: String,
+    expires_at: DateTime<Utc>,
+}
+
+/// A new download URL for one of the caller's files, e.g. once the URL it
+/// was created with has expired
+async fn sign_url(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Path(id): Path<FileId>,
+    ValidatedJson(input): ValidatedJson<SignIn>,
+) -> ApiResult<Json<SignOut>> {
+    let cfg = &state.config().files;
+    let ttl = input.expires_in_secs.unwrap_or(cfg.url_ttl_secs.min(cfg.max_url_ttl_secs));
+    if ttl > cfg.max_url_ttl_secs {
+        return Err(ApiError::Validation(vec![FieldError {
+            field: "expires_in_secs".into(),
+            code: "range".into(),
+            message: format!("at most {} seconds", cfg.max_url_ttl_secs),
+        }]));
+    }
+    let owned = files::owned(&state.db, user.user_id, id).timed(&state.metrics, "files.owned").await.map_err(|e| {
+        tracing::error!(error = %e, "file lookup failed");
+        ApiError::Internal
+    })?;
+    if !owned {
+        return Err(ApiError::NotFound);
+    }
+    let (url, expires_at) = files::signed_url(state.config(), id, ttl);
+    tracing::info!(file_id = %id, by = %user.user_id, ttl_secs = ttl, "audit.file.url_signed");
+    Ok(Json(SignOut { url, expires_at }))
+}
+
+/// Delete one of the caller's files; its URLs stop working at once
+async fn delete_file(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Path(id): Path<FileId>,
+) -> ApiResult<StatusCode> {
+    let deleted = files::delete(&state, user.user_id, id).await.map_err(|e| {
+        tracing::error!(error = %e, "file delete failed");
+        ApiError::Internal
+    })?;
+    if !deleted {
+        return Err(ApiError::NotFound);
+    }
+    tracing::info!(file_id = %id, by = %user.user_id, "audit.file.deleted");
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,42 @@
+//! Generation records (JWT or API key with `chat:read`; callers see their
+//! own generations, admins see all)
+
+use crate::{
+    auth_middleware::{require_auth, require_scope, AuthUser, ROLE_ADMIN},
+    db_metrics::Timed,
+    generations::{self, Generation},
+    state::AppState,
+};
+use axum::{
+    extract::{Path, State},
+    middleware,
+    routing::get,
+    Extension, Json, Router,
+};
+use ds_auth::scope;
+use ds_core::error::{ApiError, ApiResult};
+use ds_types::GenerationId;
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/v1/generations/{id}", get(get_generation))
+        .route_layer(middleware::from_fn(crate::etag::conditional))
+        .route_layer(middleware::from_fn(|req, next| require_scope(scope::CHAT_READ, req, next)))
+        .route_layer(middleware::from_fn(require_auth))
+}
+
+async fn get_generation(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Path(id): Path<GenerationId>,
+) -> ApiResult<Json<Generation>> {
+    let generation = generations::get(&state.db, id).timed(&state.metrics, "generations.get").await.map_err(|e| {
+        tracing::error!(error = %e, generation_id = %id, "generation lookup failed");
+        ApiError::Internal
+    })?;
+    match generation {
+        // Someone else's generation is indistinguishable from a missing one
+        Some(g) if g.user_id == user.user_id || user.has_role(ROLE_ADMIN) => Ok(Json(g)),
+        _ => Err(ApiError::NotFound),
+    }
+}
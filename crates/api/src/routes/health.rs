@@ -0,0 +1,80 @@
+//! Liveness and readiness probes (public, not rate limited)
+
+use crate::{
+    build_info::{build_info, BuildInfo, VERSION},
+    health::ServiceStatus,
+    migrations::Schema,
+    state::AppState,
+};
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::IntoResponse,
+    routing::get,
+    Json, Router,
+};
+use serde::Serialize;
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/health", get(health))
+        .route("/readiness", get(readiness))
+        .route("/version", get(version))
+}
+
+async fn version() -> Json<BuildInfo> {
+    Json(build_info())
+}
+
+/// Ready once the schema matches this build; a newer one is fine with
+/// `MIGRATIONS_ALLOW_NEWER_SCHEMA` (the old side of a blue/green deploy)
+async fn readiness(State(state): State<AppState>) -> impl IntoResponse {
+    match crate::migrations::schema(&state.db).await {
+        Ok(Schema::Current) => (StatusCode::OK, "ready".to_string()),
+        Ok(Schema::Ahead { .. }) if state.cfg.database.allow_newer_schema => (StatusCode::OK, "ready".to_string()),
+        Ok(schema) => (StatusCode::SERVICE_UNAVAILABLE, schema.to_string()),
+        Err(e) => {
+            tracing::error!(error = %e, "readiness schema check failed");
+            (StatusCode::SERVICE_UNAVAILABLE, "database unavailable".to_string())
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct HealthResponse {
+    status: String,
+    version: String,
+    dependencies: DependencyHealth,
+}
+
+#[derive(Serialize)]
+struct DependencyHealth {
+    database: ServiceStatus,
+    ollama: ServiceStatus,
+}
+
+async fn health(State(state): State<AppState>) -> impl IntoResponse {
+    let (db_status, ollama_status) = crate::health::check_all(&state).await;
+
+    let overall_healthy = db_status.healthy && ollama_status.healthy;
+    let status_code = if overall_healthy {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    let response = HealthResponse {
+        status: if overall_healthy {
+            "healthy".to_string()
+        } else {
+            "unhealthy".to_string()
+        },
+        version: VERSION.to_string(),
+        dependencies: DependencyHealth {
+            database: db_status,
+            ollama: ollama_status,
+        },
+    };
+
+    (status_code, Json(response))
+}
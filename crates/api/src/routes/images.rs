@@ -0,0 +1,152 @@
+//! Image generation (JWT or API key with `chat:write`, rate limited per IP)
+
+use crate::{
+    auth_middleware::{require_auth, require_scope, AuthUser},
+    db_metrics::Timed,
+    extract::{rules, ValidatedJson},
+    files, guard, quota, rate_limit,
+    state::AppState,
+};
+use axum::{extract::State, middleware, routing::post, Extension, Json, Router};
+use chrono::{DateTime, Utc};
+use ds_auth::scope;
+use ds_core::error::{ApiError, ApiResult, FieldError};
+use ds_model::{images::ImageRequest, ChatMessage};
+use ds_types::FileId;
+use serde::{Deserialize, Serialize};
+use validator::Validate;
+
+/// Image generation requests by result (`ok`, `error`)
+pub const IMAGE_GENERATIONS: &str = "deepersensor_image_generations_total";
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/v1/images/generations", post(generate))
+        .route_layer(middleware::from_fn(rate_limit::per_ip))
+        .route_layer(middleware::from_fn(|req, next| require_scope(scope::CHAT_WRITE, req, next)))
+        .route_layer(middleware::from_fn(require_auth))
+}
+
+#[derive(Deserialize, Validate)]
+struct GenerateIn {
+    #[validate(length(min = 1, max = 4000, message = "between 1 and 4000 characters required"))]
+    prompt: String,
+    #[validate(length(max = 4000, message = "at most 4000 characters"))]
+    negative_prompt: Option<String>,
+    /// Defaults to `images.model`
+    #[validate(custom(function = "rules::model_name"))]
+    model: Option<String>,
+    /// Up to `images.max_n`; defaults to 1
+    n: Option<u32>,
+    /// `WIDTHxHEIGHT`; defaults to `1024x1024`
+    size: Option<String>,
+}
+
+#[derive(Serialize)]
+struct GenerateOut {
+    created: i64,
+    data: Vec<ImageOut>,
+}
+
+#[derive(Serialize)]
+struct ImageOut {
+    id: FileId,
+    /// Signed download URL, valid until `expires_at`
+    url: String,
+    content_type: String,
+    expires_at: DateTime<Utc>,
+}
+
+fn field_error(field: &str, code: &str, message: &str) -> ApiError {
+    ApiError::Validation(vec![FieldError { field: field.into(), code: code.into(), message: message.into() }])
+}
+
+/// `WIDTHxHEIGHT` with both sides between 64 and 2048 and multiples of 8
+fn parse_size(size: &str) -> Option<(u32, u32)> {
+    let (w, h) = size.split_once('x')?;
+    let side = |s: &str| s.parse::<u32>().ok().filter(|n| (64..=2048).contains(n) && n % 8 == 0);
+    Some((side(w)?, side(h)?))
+}
+
+fn db_error(e: sqlx::Error) -> ApiError {
+    tracing::error!(error = %e, "image query failed");
+    ApiError::Internal
+}
+
+async fn generate(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    ValidatedJson(input): ValidatedJson<GenerateIn>,
+) -> ApiResult<Json<GenerateOut>> {
+    let Some(provider) = state.images.clone() else {
+        // Disabled unless a backend is configured
+        return Err(ApiError::NotFound);
+    };
+    let cfg = &state.config().images;
+    let n = input.n.unwrap_or(1);
+    if n == 0 || n > cfg.max_n {
+        return Err(field_error("n", "range", &format!("between 1 and {} required", cfg.max_n)));
+    }
+    let (width, height) = parse_size(input.size.as_deref().unwrap_or("1024x1024"))
+        .ok_or_else(|| field_error("size", "size", "expected WIDTHxHEIGHT, each 64-2048 and a multiple of 8"))?;
+    if cfg.daily_images > 0 {
+        let used = quota::images_today(&state.db, user.user_id)
+            .timed(&state.metrics, "quota.images_today")
+            .await
+            .map_err(db_error)?;
+        if used + u64::from(n) > cfg.daily_images {
+            return Err(ApiError::RateLimited);
+        }
+    }
+    // Checked like chat input; the operator system prompt does not apply
+    let prompt = ChatMessage { role: "user".into(), content: input.prompt.clone(), ..Default::default() };
+    guard::prepare_messages(state.config(), user.user_id, vec![prompt])?;
+    tracing::info!(user_id = %user.user_id, backend = provider.name(), n, width, height, "image generation request");
+
+    let request = ImageRequest {
+        prompt: input.prompt,
+        negative_prompt: input.negative_prompt,
+        model: input.model.unwrap_or_else(|| cfg.model.clone()),
+        width,
+        height,
+        n,
+    };
+    let result = provider.generate(request).await;
+    let images = match result {
+        Ok(images) if !images.is_empty() => images,
+        other => {
+            state.metrics.incr(IMAGE_GENERATIONS, &[("result", "error")]);
+            let error = other.err().map_or_else(|| "no images returned".to_string(), |e| e.to_string());
+            tracing::error!(%error, user_id = %user.user_id, backend = provider.name(), "image generation failed");
+            return Err(ApiError::Internal);
+        }
+    };
+    let mut data = Vec::with_capacity(images.len());
+    for image in &images {
+        let id = files::ingest(&state, user.user_id, &image.content_type, &image.data).await?;
+        let (url, expires_at) = files::signed_url(state.config(), id, state.config().files.url_ttl_secs);
+        data.push(ImageOut { id, url, content_type: image.content_type.clone(), expires_at });
+    }
+    quota::record_images(&state.db, user.user_id, data.len() as u64)
+        .timed(&state.metrics, "quota.record_images")
+        .await
+        .map_err(db_error)?;
+    state.metrics.incr(IMAGE_GENERATIONS, &[("result", "ok")]);
+    Ok(Json(GenerateOut { created: Utc::now().timestamp(), data }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("1024x768"), Some((1024, 768)));
+        assert_eq!(parse_size("64x2048"), Some((64, 2048)));
+        assert_eq!(parse_size("1000x1001"), None);
+        assert_eq!(parse_size("32x32"), None);
+        assert_eq!(parse_size("4096x512"), None);
+        assert_eq!(parse_size("1024"), None);
+        assert_eq!(parse_size("axb"), None);
+    }
+}
@@ -0,0 +1,124 @@
+//! The caller's own limits (JWT with any scope, does not consume rate limit)
+
+use crate::{
+    auth_middleware::require_auth,
+    context::RequestContext,
+    db_metrics::Timed,
+    quota,
+    rate_limit::TokenBucket,
+    state::AppState,
+    tenants,
+};
+use axum::{
+    extract::State,
+    middleware,
+    routing::get,
+    Json, Router,
+};
+use ds_core::{
+    error::{ApiError, ApiResult},
+    rate_limit::BucketStatus,
+};
+use serde::Serialize;
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/v1/limits", get(limits))
+        .route_layer(middleware::from_fn(require_auth))
+}
+
+#[derive(Serialize)]
+struct LimitsOut {
+    /// Per-IP request limiter; `null` when rate limiting is off
+    rate_limit: Option<RateLimitOut>,
+    quota: QuotaOut,
+    images: ImagesOut,
+    streams: StreamsOut,
+}
+
+#[derive(Serialize)]
+struct RateLimitOut {
+    requests_per_minute: u64,
+    #[serde(flatten)]
+    bucket: BucketStatus,
+}
+
+#[derive(Serialize)]
+struct QuotaOut {
+    /// Tokens per UTC day; `null` when unlimited
+    daily_tokens: Option<u64>,
+    used_tokens: u64,
+    remaining_tokens: Option<u64>,
+    reset_secs: u64,
+}
+
+/// Generated images, a quota class apart from tokens
+#[derive(Serialize)]
+struct ImagesOut {
+    /// Images per UTC day; `null` when unlimited
+    daily_images: Option<u64>,
+    used_images: u64,
+    remaining_images: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct StreamsOut {
+    /// `null` when unlimited
+    limit: Option<u64>,
+    active: u64,
+}
+
+async fn limits(
+    State(state): State<AppState>,
+    ctx: RequestContext,
+) -> ApiResult<Json<LimitsOut>> {
+    let user = ctx.user()?;
+    let cfg = tenants::for_user(&state, user).await?;
+    let rate_limit = if cfg.rate_limit.enabled {
+        let (rpm, burst) = (cfg.rate_limit.requests_per_minute, cfg.rate_limit.burst);
+        // An IP without a bucket yet has the full burst available
+        let bucket = match state.rate_map.get(&ctx.ip.to_string()).map(|b| b.clone()) {
+            Some(bucket) => bucket.status().await,
+            None => TokenBucket::new(rpm, burst).status().await,
+        };
+        Some(RateLimitOut { requests_per_minute: rpm, bucket })
+    } else {
+        None
+    };
+
+    let db_error = |e: sqlx::Error| {
+        tracing::error!(error = %e, "quota lookup failed");
+        ApiError::Internal
+    };
+    let db = state.db_router.read();
+    let used_tokens = quota::tokens_today(db, user.user_id)
+        .timed(&state.metrics, "quota.tokens_today")
+        .await
+        .map_err(db_error)?;
+    let used_images = quota::images_today(db, user.user_id)
+        .timed(&state.metrics, "quota.images_today")
+        .await
+        .map_err(db_error)?;
+    let daily_images = Some(cfg.images.daily_images).filter(|n| *n > 0);
+    let daily_tokens = Some(cfg.quota.daily_tokens).filter(|n| *n > 0);
+    let max_streams = cfg.chat.max_concurrent_streams;
+
+    Ok(Json(LimitsOut {
+        rate_limit,
+        quota: QuotaOut {
+            daily_tokens,
+            used_tokens,
+            remaining_tokens: daily_tokens.map(|n| n.saturating_sub(used_tokens)),
+            reset_secs: quota::secs_until_reset(),
+        },
+        images: ImagesOut {
+            daily_images,
+            used_images,
+            remaining_images: daily_images.map(|n| n.saturating_sub(used_images)),
+        },
+        streams: StreamsOut {
+            limit: Some(max_streams).filter(|n| *n > 0),
+            active: state.streams.in_use(user.user_id),
+        },
+    }))
+}
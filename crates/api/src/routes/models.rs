@@ -0,0 +1,22 @@
+//! Model catalogue (public, rate limited per IP, with an `ETag`)
+
+use crate::{etag, rate_limit, routes::chat, state::AppState};
+use axum::{extract::State, middleware, routing::get, Json, Router};
+use ds_core::error::ApiResult;
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/v1/models", get(list_models))
+        .route_layer(middleware::from_fn(etag::conditional))
+        .route_layer(middleware::from_fn(rate_limit::per_ip))
+}
+
+async fn list_models(
+    State(state): State<AppState>,
+) -> ApiResult<Json<Vec<String>>> {
+    let models = state.provider.list_models().await.map_err(|e| {
+        tracing::error!(error = %e, "list models failed");
+        chat::model_error(e)
+    })?;
+    Ok(Json(models))
+}
@@ -0,0 +1,96 @@
+//! The caller's notification preferences (JWT or API key; `chat:write` to
+//! change, `chat:read` to read)
+
+use crate::{
+    auth_middleware::{require_auth, require_scope, AuthUser},
+    db_metrics::Timed,
+    notifications::{self, Preferences},
+    state::AppState,
+};
+use axum::{
+    extract::State,
+    middleware,
+    routing::{get, put},
+    Extension, Json, Router,
+};
+use ds_auth::scope;
+use ds_core::{
+    error::{ApiError, ApiResult, FieldError},
+    i18n::Locale,
+};
+use ds_notify::{Kind, EMAIL, WEBHOOK};
+
+pub fn router() -> Router<AppState> {
+    let write = Router::new()
+        .route("/v1/notifications/preferences", put(put_preferences))
+        .route_layer(middleware::from_fn(|req, next| require_scope(scope::CHAT_WRITE, req, next)));
+    let read = Router::new()
+        .route("/v1/notifications/preferences", get(get_preferences))
+        .route_layer(middleware::from_fn(crate::etag::conditional))
+        .route_layer(middleware::from_fn(|req, next| require_scope(scope::CHAT_READ, req, next)));
+    write.merge(read).route_layer(middleware::from_fn(require_auth))
+}
+
+fn db_error(e: sqlx::Error) -> ApiError {
+    tracing::error!(error = %e, "notification preferences query failed");
+    ApiError::Internal
+}
+
+async fn get_preferences(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+) -> ApiResult<Json<Preferences>> {
+    Ok(Json(notifications::preferences(&state.db, user.user_id)
+        .timed(&state.metrics, "notifications.preferences")
+        .await
+        .map_err(db_error)?))
+}
+
+/// Replace the caller's preferences. Account security messages always go
+/// by email, so their kinds cannot be listed.
+async fn put_preferences(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Json(prefs): Json<Preferences>,
+) -> ApiResult<Json<Preferences>> {
+    let mut fields = Vec::new();
+    let mut invalid = |field: String, code: &str, message: String| {
+        fields.push(FieldError { field, code: code.into(), message });
+    };
+    if let Some(url) = &prefs.webhook_url {
+        let valid = reqwest::Url::parse(url).is_ok_and(|u| matches!(u.scheme(), "http" | "https") && u.has_host());
+        if !valid {
+            invalid("webhook_url".into(), "url", "an absolute http or https URL is required".into());
+        }
+    }
+    if let Some(locale) = &prefs.locale {
+        let primary = locale.split('-').next().unwrap_or(locale);
+        let well_formed = locale.len() <= 35 && locale.chars().all(|c| c.is_ascii_alphanumeric() || c == '-');
+        if !well_formed || !Locale::ALL.iter().any(|l| l.tag().eq_ignore_ascii_case(primary)) {
+            let tags: Vec<_> = Locale::ALL.iter().map(|l| l.tag()).collect();
+            invalid("locale".into(), "locale", format!("one of {} (optionally with a region)", tags.join(", ")));
+        }
+    }
+    for (kind, channels) in &prefs.channels {
+        let field = format!("channels.{kind}");
+        match Kind::parse(kind) {
+            None => invalid(field, "kind", "unknown notification kind".into()),
+            Some(k) if k.mandatory() => invalid(field, "mandatory", "always sent by email".into()),
+            Some(_) => {
+                if let Some(other) = channels.iter().find(|c| ![EMAIL, WEBHOOK].contains(&c.as_str())) {
+                    invalid(field, "channel", format!("unknown channel {other}; use email or webhook"));
+                }
+            }
+        }
+    }
+    if !fields.is_empty() {
+        return Err(ApiError::Validation(fields));
+    }
+    notifications::set_preferences(&state.db, user.user_id, &prefs)
+        .timed(&state.metrics, "notifications.set_preferences")
+        .await
+        .map_err(db_error)?;
+    let webhook = prefs.webhook_url.is_some();
+    tracing::info!(user_id = %user.user_id, webhook, "audit.notifications.preferences_set");
+    Ok(Json(prefs))
+}
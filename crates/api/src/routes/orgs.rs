@@ -0,0 +1,299 @@
+//! Organizations, members, org-owned API keys, and org settings (JWT
+//! required, with `orgs:admin` when scoped; managing members, keys, and
+//! settings needs the org `admin` role)
+
+use crate::{
+    api_keys::{self, KeyInfo, NewKey},
+    auth_middleware::{require_auth, require_scope, AuthUser},
+    db_metrics::Timed,
+    extract::{rules, ValidatedJson},
+    orgs::{self, ORG_ADMIN, ORG_MEMBER},
+    state::AppState,
+    tenants,
+};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    middleware,
+    routing::{delete, get, post},
+    Extension, Json, Router,
+};
+use ds_auth::scope;
+use ds_core::{
+    config::TenantOverrides,
+    error::{ApiError, ApiResult, FieldError},
+};
+use ds_types::{ApiKeyId, OrgId, UserId};
+use serde::{Deserialize, Serialize};
+use validator::Validate;
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/v1/orgs", post(create_org))
+        .route("/v1/orgs/{org_id}/members", post(add_member))
+        .route("/v1/orgs/{org_id}/keys", post(create_key).get(list_keys))
+        .route("/v1/orgs/{org_id}/keys/{key_id}", delete(revoke_key))
+        .route("/v1/orgs/{org_id}/settings", get(get_settings).put(put_settings))
+        .route_layer(middleware::from_fn(|req, next| require_scope(scope::ORGS_ADMIN, req, next)))
+        .route_layer(middleware::from_fn(require_auth))
+}
+
+fn db_error(e: sqlx::Error) -> ApiError {
+    tracing::error!(error = %e, "org query failed");
+    ApiError::Internal
+}
+
+/// Keys and impersonation tokens cannot manage orgs or mint keys, and only
+/// org admins may change members or keys
+async fn require_org_admin(state: &AppState, user: &AuthUser, org_id: OrgId) -> ApiResult<()> {
+    if user.api_key.is_some() || user.impersonated_by.is_some() {
+        return Err(ApiError::Forbidden);
+    }
+    match orgs::member_role(&state.db, org_id, user.user_id)
+        .timed(&state.metrics, "orgs.member_role")
+        .await
+        .map_err(db_error)?
+    {
+        Some(role) if role == ORG_ADMIN => Ok(()),
+        Some(_) => Err(ApiError::Forbidden),
+        // Non-members cannot tell the org exists
+        None => Err(ApiError::NotFound),
+    }
+}
+
+#[derive(Deserialize, Validate)]
+struct CreateOrgIn {
+    #[validate(length(min = 1, max = 100, message = "between 1 and 100 characters required"))]
+    name: String,
+}
+
+#[derive(Serialize)]
+struct OrgOut {
+    id: OrgId,
+    name: String,
+}
+
+async fn create_org(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    ValidatedJson(input): ValidatedJson<CreateOrgIn>,
+) -> ApiResult<(StatusCode, Json<OrgOut>)> {
+    if user.api_key.is_some() || user.impersonated_by.is_some() {
+        return Err(ApiError::Forbidden);
+    }
+    let id = orgs::create(&state.db, &input.name, user.user_id)
+        .timed(&state.metrics, "orgs.create")
+        .await
+        .map_err(db_error)?;
+    tracing::info!(org_id = %id, user_id = %user.user_id, "audit.org.created");
+    Ok((StatusCode::CREATED, Json(OrgOut { id, name: input.name })))
+}
+
+#[derive(Deserialize, Validate)]
+struct MemberIn {
+    #[validate(custom(function = "rules::email"))]
+    email: String,
+    /// `member` (default) or `admin`
+    #[serde(default)]
+    role: Option<String>,
+}
+
+async fn add_member(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Path(org_id): Path<OrgId>,
+    ValidatedJson(input): ValidatedJson<MemberIn>,
+) -> ApiResult<StatusCode> {
+    require_org_admin(&state, &user, org_id).await?;
+    let role = input.role.as_deref().unwrap_or(ORG_MEMBER);
+    if role != ORG_MEMBER && role != ORG_ADMIN {
+        return Err(ApiError::Validation(vec![FieldError {
+            field: "role".into(),
+            code: "org_role".into(),
+            message: "role must be member or admin".into(),
+        }]));
+    }
+    if !orgs::upsert_member(&state.db, org_id, &input.email, role)
+        .timed(&state.metrics, "orgs.upsert_member")
+        .await
+        .map_err(db_error)?
+    {
+        return Err(ApiError::NotFound);
+    }
+    tracing::info!(org_id = %org_id, by = %user.user_id, role, "audit.org.member_added");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Deserialize, Validate)]
+struct CreateKeyIn {
+    #[validate(length(min = 1, max = 100, message = "between 1 and 100 characters required"))]
+    name: String,
+    /// At least one scope; keys are never unrestricted
+    #[validate(length(min = 1, message = "at least one scope required"))]
+    scopes: Vec<String>,
+    #[validate(custom(function = "rules::model_name"))]
+    default_model: Option<String>,
+    #[serde(default)]
+    allowed_models: Vec<String>,
+    /// Require requests made with the key to be signed
+    #[serde(default)]
+    signed: bool,
+}
+
+#[derive(Serialize)]
+struct CreatedKeyOut {
+    id: ApiKeyId,
+    org_id: OrgId,
+    created_by: UserId,
+    /// Shown only in this response
+    key: String,
+    /// For signed keys; shown only in this response
+    #[serde(skip_serializing_if = "Option::is_none")]
+    signing_secret: Option<String>,
+}
+
+async fn create_key(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Path(org_id): Path<OrgId>,
+    ValidatedJson(input): ValidatedJson<CreateKeyIn>,
+) -> ApiResult<(StatusCode, Json<CreatedKeyOut>)> {
+    require_org_admin(&state, &user, org_id).await?;
+    let mut fields: Vec<FieldError> = input
+        .scopes
+        .iter()
+        .enumerate()
+        .filter_map(|(i, requested)| {
+            let message = match scope::validate(requested) {
+                Err(_) => format!("unknown scope {requested}"),
+                // A key is never wider than the credential minting it
+                Ok(()) if !user.has_scope(requested) => format!("scope {requested} is not held by the caller"),
+                Ok(()) => return None,
+            };
+            Some(FieldError { field: format!("scopes[{i}]"), code: "scope".into(), message })
+        })
+        .collect();
+    if let Some(default) = &input.default_model {
+        if !input.allowed_models.is_empty() && !input.allowed_models.contains(default) {
+            fields.push(FieldError {
+                field: "default_model".into(),
+                code: "model_policy".into(),
+                message: "default_model must be one of allowed_models".into(),
+            });
+        }
+    }
+    if !fields.is_empty() {
+        return Err(ApiError::Validation(fields));
+    }
+
+    let issued = api_keys::create(
+        &state.db,
+        NewKey {
+            org_id,
+            created_by: user.user_id,
+            name: &input.name,
+            scopes: &input.scopes,
+            default_model: input.default_model.as_deref(),
+            allowed_models: &input.allowed_models,
+            signed: input.signed,
+        },
+    )
+    .timed(&state.metrics, "api_keys.create")
+    .await
+    .map_err(db_error)?;
+    let signed = input.signed;
+    tracing::info!(org_id = %org_id, key_id = %issued.id, by = %user.user_id, signed, "audit.api_key.created");
+    Ok((
+        StatusCode::CREATED,
+        Json(CreatedKeyOut {
+            id: issued.id,
+            org_id,
+            created_by: user.user_id,
+            key: issued.key,
+            signing_secret: issued.signing_secret,
+        }),
+    ))
+}
+
+async fn list_keys(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Path(org_id): Path<OrgId>,
+) -> ApiResult<Json<Vec<KeyInfo>>> {
+    require_org_admin(&state, &user, org_id).await?;
+    Ok(Json(api_keys::list(&state.db, org_id).timed(&state.metrics, "api_keys.list").await.map_err(db_error)?))
+}
+
+async fn revoke_key(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Path((org_id, key_id)): Path<(OrgId, ApiKeyId)>,
+) -> ApiResult<StatusCode> {
+    require_org_admin(&state, &user, org_id).await?;
+    if !api_keys::revoke(&state.db, org_id, key_id).timed(&state.metrics, "api_keys.revoke").await.map_err(db_error)? {
+        return Err(ApiError::NotFound);
+    }
+    tracing::info!(org_id = %org_id, key_id = %key_id, by = %user.user_id, "audit.api_key.revoked");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn get_settings(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Path(org_id): Path<OrgId>,
+) -> ApiResult<Json<TenantOverrides>> {
+    require_org_admin(&state, &user, org_id).await?;
+    Ok(Json(tenants::overrides(&state.db, org_id).timed(&state.metrics, "tenants.overrides").await.map_err(db_error)?))
+}
+
+/// Replace the org's overrides; `{}` restores the deployment's settings.
+/// Each may only narrow what the deployment allows.
+async fn put_settings(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Path(org_id): Path<OrgId>,
+    Json(overrides): Json<TenantOverrides>,
+) -> ApiResult<Json<TenantOverrides>> {
+    require_org_admin(&state, &user, org_id).await?;
+    let cfg = state.config();
+    let mut fields = Vec::new();
+    let mut invalid = |field: String, code: &str, message: String| {
+        fields.push(FieldError { field, code: code.into(), message });
+    };
+    if let Some(models) = &overrides.allowed_models {
+        // An empty list would allow every model
+        if models.is_empty() {
+            invalid("allowed_models".into(), "length", "at least one model required".into());
+        }
+        for (i, model) in models.iter().enumerate() {
+            if rules::model_name(model).is_err() || !cfg.chat.allows_model(model) {
+                invalid(format!("allowed_models[{i}]"), "model_policy", format!("{model} is not available"));
+            }
+        }
+    }
+    if let Some(daily) = overrides.daily_tokens {
+        let max = cfg.quota.daily_tokens;
+        if max > 0 && !(1..=max).contains(&daily) {
+            invalid("daily_tokens".into(), "range", format!("between 1 and {max} required"));
+        }
+    }
+    if let Some(prompt) = &overrides.system_prompt {
+        let max = cfg.chat.max_message_chars;
+        if prompt.chars().count() as u64 > max {
+            invalid("system_prompt".into(), "length", format!("at most {max} characters"));
+        }
+    }
+    if !fields.is_empty() {
+        return Err(ApiError::Validation(fields));
+    }
+    tenants::set_overrides(&state, org_id, &overrides, user.user_id).await.map_err(db_error)?;
+    tracing::info!(
+        org_id = %org_id,
+        by = %user.user_id,
+        allowed_models = ?overrides.allowed_models,
+        daily_tokens = ?overrides.daily_tokens,
+        system_prompt = overrides.system_prompt.is_some(),
+        "audit.org.settings_set"
+    );
+    Ok(Json(overrides))
+}
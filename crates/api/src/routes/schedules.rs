@@ -0,0 +1,195 @@
+//! Scheduled prompts (JWT or API key; `chat:write` to create, replace, or
+//! delete, `chat:read` to read)
+
+use crate::{
+    auth_middleware::{require_auth, require_scope, AuthUser},
+    conversations,
+    db_metrics::Timed,
+    extract::{rules, ValidatedJson},
+    guard,
+    routes::chat,
+    schedules::{self, Definition, Delivery, Schedule},
+    state::AppState,
+    tenants,
+};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    middleware,
+    routing::{get, post, put},
+    Extension, Json, Router,
+};
+use chrono::Utc;
+use ds_auth::scope;
+use ds_core::error::{ApiError, ApiResult, FieldError};
+use ds_model::ChatMessage;
+use ds_types::ScheduleId;
+use serde::Deserialize;
+use validator::Validate;
+
+pub fn router() -> Router<AppState> {
+    let write = Router::new()
+        .route("/v1/schedules", post(create_schedule))
+        .route("/v1/schedules/{id}", put(replace_schedule).delete(delete_schedule))
+        .route_layer(middleware::from_fn(|req, next| require_scope(scope::CHAT_WRITE, req, next)));
+    let read = Router::new()
+        .route("/v1/schedules", get(list_schedules))
+        .route("/v1/schedules/{id}", get(get_schedule))
+        .route_layer(middleware::from_fn(|req, next| require_scope(scope::CHAT_READ, req, next)));
+    write.merge(read).route_layer(middleware::from_fn(require_auth))
+}
+
+fn db_error(e: sqlx::Error) -> ApiError {
+    tracing::error!(error = %e, "schedule query failed");
+    ApiError::Internal
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+#[derive(Deserialize, Validate)]
+struct ScheduleIn {
+    #[validate(length(min = 1, max = 100, message = "between 1 and 100 characters required"))]
+    name: String,
+    /// Five fields (UTC), or six with seconds first, or e.g. `@daily`
+    cron: String,
+    /// May be omitted when the API key has a default model
+    #[validate(custom(function = "rules::model_name"))]
+    model: Option<String>,
+    #[validate(length(min = 1, message = "must not be empty"))]
+    prompt: String,
+    delivery: Delivery,
+    #[serde(default = "default_enabled")]
+    enabled: bool,
+}
+
+/// Check `input` beyond its shape and resolve its model and conversation,
+/// creating one named after the schedule when none is given
+async fn definition(state: &AppState, user: &AuthUser, input: ScheduleIn) -> ApiResult<Definition> {
+    let cfg = tenants::for_user(state, user).await?;
+    let mut fields = Vec::new();
+    let mut invalid = |field: &str, code: &str, message: String| {
+        fields.push(FieldError { field: field.into(), code: code.into(), message });
+    };
+    match schedules::parse(&input.cron) {
+        Err(e) => invalid("cron", "cron", e),
+        Ok(schedule) => {
+            let min = cfg.schedules.min_interval_secs;
+            if schedules::min_gap(&schedule, Utc::now()).is_some_and(|gap| gap.as_secs() < min) {
+                invalid("cron", "too_frequent", format!("runs must be at least {min} seconds apart"));
+            }
+        }
+    }
+    if input.prompt.chars().count() as u64 > cfg.chat.max_message_chars {
+        invalid("prompt", "length", format!("at most {} characters", cfg.chat.max_message_chars));
+    }
+    if let Delivery::Webhook { url } = &input.delivery {
+        let valid = reqwest::Url::parse(url).is_ok_and(|u| matches!(u.scheme(), "http" | "https") && u.has_host());
+        if !valid {
+            invalid("delivery.url", "url", "an absolute http or https URL is required".into());
+        }
+    }
+    if !fields.is_empty() {
+        return Err(ApiError::Validation(fields));
+    }
+    let model = chat::resolve_model(&cfg.chat, user, input.model.as_deref())?;
+    // Refused now rather than at every run
+    let turn = ChatMessage { role: "user".into(), content: input.prompt.clone(), ..Default::default() };
+    guard::prepare_messages(&cfg, user.user_id, vec![turn])?;
+    let delivery = match input.delivery {
+        Delivery::Conversation { conversation_id: Some(id) } => {
+            conversations::get(&state.db, user.user_id, id)
+                .timed(&state.metrics, "conversations.get")
+                .await
+                .map_err(db_error)?
+                .ok_or(ApiError::NotFound)?;
+            Delivery::Conversation { conversation_id: Some(id) }
+        }
+        Delivery::Conversation { conversation_id: None } => {
+            let conversation = conversations::create(&state.db, user.user_id, &input.name)
+                .timed(&state.metrics, "conversations.create")
+                .await
+                .map_err(db_error)?;
+            Delivery::Conversation { conversation_id: Some(conversation.id) }
+        }
+        webhook => webhook,
+    };
+    Ok(Definition { name: input.name, cron: input.cron, model, prompt: input.prompt, delivery, enabled: input.enabled })
+}
+
+async fn create_schedule(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    ValidatedJson(input): ValidatedJson<ScheduleIn>,
+) -> ApiResult<(StatusCode, Json<Schedule>)> {
+    let max = state.config().schedules.max_per_user;
+    if schedules::count(&state.db, user.user_id)
+        .timed(&state.metrics, "schedules.count")
+        .await
+        .map_err(db_error)? >= max
+    {
+        return Err(ApiError::Unprocessable(format!("at most {max} schedules per user")));
+    }
+    let def = definition(&state, &user, input).await?;
+    let schedule = schedules::create(&state.db, user.user_id, &def)
+        .timed(&state.metrics, "schedules.create")
+        .await
+        .map_err(db_error)?;
+    tracing::info!(schedule_id = %schedule.id, by = %user.user_id, cron = %schedule.cron, "audit.schedule.created");
+    Ok((StatusCode::CREATED, Json(schedule)))
+}
+
+async fn list_schedules(State(state): State<AppState>, Extension(user): Extension<AuthUser>) -> ApiResult<Json<Vec<Schedule>>> {
+    Ok(Json(schedules::list(&state.db, user.user_id).timed(&state.metrics, "schedules.list").await.map_err(db_error)?))
+}
+
+async fn get_schedule(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Path(id): Path<ScheduleId>,
+) -> ApiResult<Json<Schedule>> {
+    let schedule = schedules::get(&state.db, user.user_id, id)
+        .timed(&state.metrics, "schedules.get")
+        .await
+        .map_err(db_error)?;
+    schedule.map(Json).ok_or(ApiError::NotFound)
+}
+
+/// Replace the whole definition; the next run is computed afresh
+async fn replace_schedule(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Path(id): Path<ScheduleId>,
+    ValidatedJson(input): ValidatedJson<ScheduleIn>,
+) -> ApiResult<Json<Schedule>> {
+    schedules::get(&state.db, user.user_id, id)
+        .timed(&state.metrics, "schedules.get")
+        .await
+        .map_err(db_error)?
+        .ok_or(ApiError::NotFound)?;
+    let def = definition(&state, &user, input).await?;
+    let schedule = schedules::replace(&state.db, user.user_id, id, &def)
+        .timed(&state.metrics, "schedules.replace")
+        .await
+        .map_err(db_error)?;
+    let schedule = schedule.ok_or(ApiError::NotFound)?;
+    tracing::info!(schedule_id = %id, by = %user.user_id, cron = %schedule.cron, "audit.schedule.replaced");
+    Ok(Json(schedule))
+}
+
+async fn delete_schedule(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Path(id): Path<ScheduleId>,
+) -> ApiResult<StatusCode> {
+    if !schedules::delete(&state.db, user.user_id, id)
+        .timed(&state.metrics, "schedules.delete")
+        .await
+        .map_err(db_error)?
+    {
+        return Err(ApiError::NotFound);
+    }
+    tracing::info!(schedule_id = %id, by = %user.user_id, "audit.schedule.deleted");
+    Ok(StatusCode::NO_CONTENT)
+}
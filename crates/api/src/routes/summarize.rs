@@ -0,0 +1,166 @@
+//! Summarization of long text or a stored conversation (JWT or API key
+//! with `chat:write`)
+
+use crate::{
+    admission::Priority,
+    auth_middleware::{require_auth, require_scope, AuthUser},
+    extract::{rules, ValidatedJson},
+    guard, quota,
+    routes::chat::{self, QUOTA_REMAINING},
+    state::AppState,
+    summarize::{self, Pass},
+    tenants,
+};
+use axum::{
+    extract::State,
+    http::{HeaderMap, HeaderValue},
+    middleware,
+    response::sse::{Event, Sse},
+    routing::post,
+    Extension, Router,
+};
+use ds_auth::scope;
+use ds_core::error::{ApiError, ApiResult, FieldError};
+use ds_model::ChatMessage;
+use ds_types::ConversationId;
+use futures_util::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use validator::Validate;
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/v1/summarize", post(summarize_sse))
+        .route_layer(middleware::from_fn(|req, next| require_scope(scope::CHAT_WRITE, req, next)))
+        .route_layer(middleware::from_fn(require_auth))
+}
+
+#[derive(Deserialize, Validate)]
+struct SummarizeIn {
+    /// May be omitted when the API key has a default model
+    #[validate(custom(function = "rules::model_name"))]
+    model: Option<String>,
+    priority: Option<Priority>,
+    /// Exactly one of `text` and `conversation_id`
+    text: Option<String>,
+    conversation_id: Option<ConversationId>,
+}
+
+#[derive(Serialize)]
+struct ProgressOut {
+    stage: &'static str,
+    completed: usize,
+    total: usize,
+}
+
+/// Conversation messages as `role: content` paragraphs, oldest first
+async fn conversation_text(state: &AppState, user: &AuthUser, id: ConversationId) -> ApiResult<String> {
+    let rows: Vec<(String, String)> = sqlx::query_as(
+        "SELECT m.role, m.content FROM messages m JOIN conversations c ON c.id = m.conversation_id \
+         WHERE c.id = $1 AND c.user_id = $2 ORDER BY m.seq",
+    )
+    .bind(id)
+    .bind(user.user_id)
+    .fetch_all(&state.db)
+    .await
+    .map_err(|e| {
+        tracing::error!(error = %e, "conversation lookup failed");
+        ApiError::Internal
+    })?;
+    if rows.is_empty() {
+        return Err(ApiError::NotFound);
+    }
+    Ok(rows.iter().map(|(role, content)| format!("{role}: {content}")).collect::<Vec<_>>().join("\n\n"))
+}
+
+/// Stream a summary: `progress` events while long input is summarized in
+/// parts, then the final summary as `chunk` events ending in `done`
+async fn summarize_sse(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    ValidatedJson(input): ValidatedJson<SummarizeIn>,
+) -> ApiResult<(HeaderMap, Sse<impl Stream<Item = Result<Event, axum::Error>>>)> {
+    let text = match (input.text, input.conversation_id) {
+        (Some(text), None) if !text.trim().is_empty() => text,
+        (None, Some(id)) => conversation_text(&state, &user, id).await?,
+        _ => {
+            return Err(ApiError::Validation(vec![FieldError {
+                field: "text".into(),
+                code: "source".into(),
+                message: "exactly one of text and conversation_id is required".into(),
+            }]));
+        }
+    };
+    let cfg = tenants::for_user(&state, &user).await?;
+    let max = cfg.summarize.max_input_chars;
+    if text.chars().count() as u64 > max {
+        return Err(ApiError::PayloadTooLarge(format!("input exceeds {max} characters")));
+    }
+    let model = chat::resolve_model(&cfg.chat, &user, input.model.as_deref())?;
+    let priority = chat::resolve_priority(&user, input.priority);
+    let quota_remaining = chat::check_quota(&state, &cfg.quota, &user).await?;
+    let slot = state
+        .streams
+        .acquire(user.user_id, "summarize", &model, cfg.chat.max_concurrent_streams)
+        .ok_or(ApiError::RateLimited)?;
+    // Checked like chat input; the operator system prompt is not added
+    let message = ChatMessage { role: "user".into(), content: text.clone(), ..Default::default() };
+    guard::prepare_messages(&cfg, user.user_id, vec![message])?;
+    tracing::info!(user_id = %user.user_id, model = %model, chars = text.len(), "summarize request");
+
+    let mut headers = HeaderMap::new();
+    if let Some(remaining) = quota_remaining {
+        headers.insert(QUOTA_REMAINING, HeaderValue::from(remaining));
+    }
+    let events = async_stream::stream! {
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let reduce = summarize::reduce_to_prompt(&state, &model, priority, &text, |pass: Pass| {
+            let _ = tx.send(ProgressOut { stage: pass.stage, completed: pass.completed, total: pass.total });
+        });
+        futures_util::pin_mut!(reduce);
+        // Forward progress while the passes run
+        let reduced = loop {
+            tokio::select! {
+                Some(progress) = rx.recv() => yield Event::default().event("progress").json_data(&progress),
+                reduced = &mut reduce => break reduced,
+            }
+        };
+        while let Ok(progress) = rx.try_recv() {
+            yield Event::default().event("progress").json_data(&progress);
+        }
+        let stream = match reduced {
+            Ok((messages, pass_tokens)) => match summarize::admit(&state, priority).await {
+                Ok(permit) => summarize::final_stream(&state, &model, messages, permit).await.map(|s| (s, pass_tokens)),
+                Err(e) => Err(e),
+            },
+            Err(e) => Err(e),
+        };
+        let (stream, mut tokens) = match stream {
+            Ok(opened) => opened,
+            Err(e) => {
+                yield Ok(chat::error_event(&e.to_string()));
+                return;
+            }
+        };
+        // Map and reduce passes count against the quota with the summary
+        let (quota_state, quota_cfg) = (state.clone(), cfg.clone());
+        let user_id = user.user_id;
+        let stream = stream.map(move |item| {
+            if let Ok(chunk) = &item {
+                // Held until the stream ends or the client goes away
+                slot.sent(chunk.content.len());
+                tokens += u64::from(!chunk.content.is_empty());
+                if chunk.done {
+                    let (quota_state, quota_cfg) = (quota_state.clone(), quota_cfg.clone());
+                    tokio::spawn(async move { quota::record(&quota_state, &quota_cfg.quota, user_id, tokens).await });
+                }
+            }
+            item
+        });
+        let events = chat::chunk_events(&state, Box::pin(stream));
+        futures_util::pin_mut!(events);
+        while let Some(event) = events.next().await {
+            yield event;
+        }
+    };
+    Ok((headers, Sse::new(events)))
+}
@@ -0,0 +1,121 @@
+//! Resumable uploads (JWT or API key with `chat:write`): open one, send
+//! parts at `Upload-Offset`, then complete it into a file or abort it.
+//! Opening is rate limited per IP; parts are not, since a large file takes
+//! many.
+
+use crate::{
+    auth_middleware::{require_auth, require_scope, AuthUser},
+    extract::ValidatedJson,
+    rate_limit,
+    routes::files::UploadOut,
+    state::AppState,
+    uploads::{self, Upload},
+};
+use axum::{
+    body::Body,
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    middleware,
+    routing::{get, post},
+    Extension, Json, Router,
+};
+use ds_auth::scope;
+use ds_core::error::{ApiError, ApiResult};
+use ds_types::UploadId;
+use serde::Deserialize;
+use validator::Validate;
+
+/// Where a part starts, in bytes from the start of the file
+const UPLOAD_OFFSET: &str = "upload-offset";
+
+pub fn router() -> Router<AppState> {
+    let open = Router::new()
+        .route("/v1/uploads", post(create))
+        .route_layer(middleware::from_fn(rate_limit::per_ip))
+        .route_layer(middleware::from_fn(|req, next| require_scope(scope::CHAT_WRITE, req, next)))
+        .route_layer(middleware::from_fn(require_auth));
+    let parts = Router::new()
+        .route("/v1/uploads/{id}", get(status).patch(append).delete(abort))
+        .route("/v1/uploads/{id}/complete", post(complete))
+        .route_layer(middleware::from_fn(|req, next| require_scope(scope::CHAT_WRITE, req, next)))
+        .route_layer(middleware::from_fn(require_auth));
+    open.merge(parts)
+}
+
+#[derive(Deserialize, Validate)]
+struct CreateIn {
+    /// Size of the whole file
+    #[validate(range(min = 1, message = "at least 1 byte"))]
+    bytes: u64,
+    /// Defaults to `application/octet-stream`
+    #[validate(length(min = 1, max = 255, message = "between 1 and 255 characters required"))]
+    content_type: Option<String>,
+}
+
+async fn create(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    ValidatedJson(input): ValidatedJson<CreateIn>,
+) -> ApiResult<(StatusCode, Json<Upload>)> {
+    let content_type = input.content_type.map_or_else(|| "application/octet-stream".into(), |t| t.to_ascii_lowercase());
+    let upload = uploads::create(&state, user.user_id, &content_type, input.bytes).await?;
+    tracing::info!(upload_id = %upload.id, by = %user.user_id, bytes = upload.bytes, "audit.upload.created");
+    Ok((StatusCode::CREATED, Json(upload)))
+}
+
+/// Where the upload has got to, to resume from after a lost response
+async fn status(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Path(id): Path<UploadId>,
+) -> ApiResult<Json<Upload>> {
+    let upload = uploads::get(&state.db, user.user_id, id).await.map_err(|e| {
+        tracing::error!(error = %e, "upload lookup failed");
+        ApiError::Internal
+    })?;
+    upload.map(Json).ok_or(ApiError::NotFound)
+}
+
+/// The raw part is the body; `Upload-Offset` must equal the upload's
+/// `offset`, or the part is refused with 409
+async fn append(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Path(id): Path<UploadId>,
+    headers: HeaderMap,
+    body: Body,
+) -> ApiResult<Json<Upload>> {
+    let offset = headers
+        .get(UPLOAD_OFFSET)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok())
+        .ok_or_else(|| ApiError::BadRequest("Upload-Offset header with the part's byte offset is required".into()))?;
+    let limit = state.config().files.upload_part_bytes as usize;
+    let data = axum::body::to_bytes(body, limit)
+        .await
+        .map_err(|_| ApiError::PayloadTooLarge(format!("part exceeds {limit} bytes")))?;
+    Ok(Json(uploads::append(&state, user.user_id, id, offset, data).await?))
+}
+
+async fn complete(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Path(id): Path<UploadId>,
+) -> ApiResult<(StatusCode, Json<UploadOut>)> {
+    let (file_id, upload) = uploads::complete(&state, user.user_id, id).await?;
+    tracing::info!(file_id = %file_id, upload_id = %id, by = %user.user_id, bytes = upload.bytes, "audit.file.uploaded");
+    let out = UploadOut::new(&state, file_id, upload.content_type, upload.bytes as usize);
+    Ok((StatusCode::CREATED, Json(out)))
+}
+
+async fn abort(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Path(id): Path<UploadId>,
+) -> ApiResult<StatusCode> {
+    if !uploads::abort(&state, user.user_id, id).await? {
+        return Err(ApiError::NotFound);
+    }
+    tracing::info!(upload_id = %id, by = %user.user_id, "audit.upload.aborted");
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,86 @@
+//! Well-known URIs (RFC 8615) and `robots.txt`, public and not rate limited
+
+use crate::state::AppState;
+use axum::{
+    extract::State,
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use chrono::{DateTime, Duration, SecondsFormat, Utc};
+use ds_core::config::SecuritySection;
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/.well-known/security.txt", get(security_txt))
+        .route("/robots.txt", get(robots_txt))
+}
+
+/// No crawling anywhere; every response also says `noindex` (see
+/// `security`) for pages reached through links
+async fn robots_txt() -> impl IntoResponse {
+    ([(header::CONTENT_TYPE, "text/plain; charset=utf-8")], "User-agent: *\nDisallow: /\n")
+}
+
+async fn security_txt(State(state): State<AppState>) -> Response {
+    match render_security_txt(&state.cfg.security, Utc::now()) {
+        Some(body) => ([(header::CONTENT_TYPE, "text/plain; charset=utf-8")], body).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+/// RFC 9116 fields in its order, one `Contact` per configured URI; `None`
+/// without a contact, which the format requires
+fn render_security_txt(cfg: &SecuritySection, now: DateTime<Utc>) -> Option<String> {
+    let contacts: Vec<&str> = cfg.security_txt_contact.split(',').map(str::trim).filter(|c| !c.is_empty()).collect();
+    if contacts.is_empty() {
+        return None;
+    }
+    let expires = match cfg.security_txt_expires.trim() {
+        "" => now + Duration::days(365),
+        configured => DateTime::parse_from_rfc3339(configured).map(|t| t.to_utc()).unwrap_or_else(|e| {
+            tracing::warn!(error = %e, configured, "invalid SECURITY_TXT_EXPIRES; using a year ahead");
+            now + Duration::days(365)
+        }),
+    };
+    let mut lines: Vec<String> = contacts.iter().map(|c| format!("Contact: {c}")).collect();
+    lines.push(format!("Expires: {}", expires.to_rfc3339_opts(SecondsFormat::Secs, true)));
+    for (field, value) in [
+        ("Encryption", &cfg.security_txt_encryption),
+        ("Acknowledgments", &cfg.security_txt_acknowledgments),
+        ("Policy", &cfg.security_txt_policy),
+        ("Preferred-Languages", &cfg.security_txt_preferred_languages),
+        ("Canonical", &cfg.security_txt_canonical),
+    ] {
+        if !value.trim().is_empty() {
+            lines.push(format!("{field}: {}", value.trim()));
+        }
+    }
+    Some(lines.join("\n") + "\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_security_txt_fields() {
+        let mut cfg = ds_core::config::AppConfig::load().unwrap().security;
+        cfg.security_txt_contact = "mailto:sec@example.com, https://example.com/report".into();
+        cfg.security_txt_expires = "2027-01-31T00:00:00Z".into();
+        cfg.security_txt_encryption = String::new();
+        cfg.security_txt_acknowledgments = String::new();
+        cfg.security_txt_preferred_languages = "en, de".into();
+        let now = "2026-10-16T12:00:00Z".parse().unwrap();
+        assert_eq!(
+            render_security_txt(&cfg, now).unwrap(),
+            "Contact: mailto:sec@example.com\nContact: https://example.com/report\n\
+             Expires: 2027-01-31T00:00:00Z\nPreferred-Languages: en, de\n"
+        );
+        cfg.security_txt_expires = String::new();
+        assert!(render_security_txt(&cfg, now).unwrap().contains("Expires: 2027-10-16T12:00:00Z\n"));
+        cfg.security_txt_contact = " ".into();
+        assert_eq!(render_security_txt(&cfg, now), None);
+    }
+}
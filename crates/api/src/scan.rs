@@ -0,0 +1,136 @@
+//! Malware scanning of files before they are stored.
+//!
+//! Every file goes through the configured [`Scanner`] before it becomes
+//! retrievable: clean files are stored as usual, infected ones are kept
+//! as quarantined records that no URL serves. `files.scanner = clamav`
+//! streams files to a clamd daemon over TCP.
+
+use async_trait::async_trait;
+use ds_core::config::FilesSection;
+use ds_store::ByteStream;
+use futures_util::StreamExt;
+use std::{sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+/// Scans by result (`clean`, `infected`, `error`)
+pub const SCANS: &str = "deepersensor_file_scans_total";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    Clean,
+    /// With the scanner's name for what it found
+    Infected(String),
+}
+
+pub type ScanResult = Result<Verdict, Box<dyn std::error::Error + Send + Sync>>;
+
+#[async_trait]
+pub trait Scanner: Send + Sync {
+    /// Short backend name for logs
+    fn name(&self) -> &'static str;
+
+    /// Read `data` through to the end, unless the scanner gives up first
+    async fn scan(&self, data: ByteStream) -> ScanResult;
+}
+
+/// The scanner named by `files.scanner`; `None` when scanning is off
+pub fn from_config(cfg: &FilesSection) -> Option<Arc<dyn Scanner>> {
+    let timeout = Duration::from_secs(cfg.scan_timeout_secs);
+    match cfg.scanner.as_str() {
+        "" => None,
+        "clamav" => Some(Arc::new(ClamAv::new(&cfg.clamav_addr, timeout))),
+        other => {
+            // Refusing every file is safer than silently skipping scans
+            tracing::error!(scanner = other, "unknown file scanner; files will be rejected");
+            Some(Arc::new(Unavailable(other.to_string())))
+        }
+    }
+}
+
+/// Largest chunk sent per `INSTREAM` frame, well under clamd's default
+/// `StreamMaxLength`
+const CLAMD_CHUNK: usize = 64 * 1024;
+
+/// clamd `INSTREAM` over TCP
+pub struct ClamAv {
+    addr: String,
+    timeout: Duration,
+}
+
+impl ClamAv {
+    pub fn new(addr: impl Into<String>, timeout: Duration) -> Self {
+        Self { addr: addr.into(), timeout }
+    }
+
+    async fn instream(&self, mut data: ByteStream) -> std::io::Result<String> {
+        let mut stream = TcpStream::connect(&self.addr).await?;
+        stream.write_all(b"zINSTREAM\0").await?;
+        while let Some(chunk) = data.next().await {
+            for frame in chunk?.chunks(CLAMD_CHUNK) {
+                stream.write_all(&(frame.len() as u32).to_be_bytes()).await?;
+                stream.write_all(frame).await?;
+            }
+        }
+        stream.write_all(&0u32.to_be_bytes()).await?;
+        let mut reply = Vec::new();
+        stream.read_to_end(&mut reply).await?;
+        Ok(String::from_utf8_lossy(&reply).into_owned())
+    }
+}
+
+#[async_trait]
+impl Scanner for ClamAv {
+    fn name(&self) -> &'static str {
+        "clamav"
+    }
+
+    async fn scan(&self, data: ByteStream) -> ScanResult {
+        let reply = tokio::time::timeout(self.timeout, self.instream(data)).await.map_err(|_| "clamd timed out")??;
+        Ok(parse_clamd_reply(&reply)?)
+    }
+}
+
+/// `stream: OK`, `stream: <signature> FOUND`, or an error such as
+/// `INSTREAM size limit exceeded. ERROR`
+pub fn parse_clamd_reply(reply: &str) -> Result<Verdict, String> {
+    let reply = reply.trim_end_matches(['\0', '\n']).trim();
+    let status = reply.strip_prefix("stream:").map(str::trim);
+    match status {
+        Some("OK") => Ok(Verdict::Clean),
+        Some(found) if found.ends_with(" FOUND") => Ok(Verdict::Infected(found.trim_end_matches(" FOUND").to_string())),
+        _ => Err(format!("unexpected clamd reply: {reply}")),
+    }
+}
+
+/// Stands in for a misconfigured scanner so nothing is stored unscanned
+struct Unavailable(String);
+
+#[async_trait]
+impl Scanner for Unavailable {
+    fn name(&self) -> &'static str {
+        "unavailable"
+    }
+
+    async fn scan(&self, _data: ByteStream) -> ScanResult {
+        Err(format!("unknown scanner {}", self.0).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_clamd_reply() {
+        assert_eq!(parse_clamd_reply("stream: OK\0"), Ok(Verdict::Clean));
+        assert_eq!(
+            parse_clamd_reply("stream: Win.Test.EICAR_HDB-1 FOUND\0"),
+            Ok(Verdict::Infected("Win.Test.EICAR_HDB-1".into()))
+        );
+        assert!(parse_clamd_reply("INSTREAM size limit exceeded. ERROR\0").is_err());
+        assert!(parse_clamd_reply("").is_err());
+    }
+}
@@ -0,0 +1,403 @@
+//! Scheduled prompts.
+//!
+//! A schedule sends one prompt on a cron expression, evaluated in UTC, and
+//! delivers the reply to a stored conversation or a webhook. The
+//! `schedules` job claims due schedules and moves their `next_run_at`
+//! forward in one transaction, so each run happens once however many
+//! instances poll. Runs are batch-priority generations counted against the
+//! owner's daily quota; webhooks are posted through the tool egress policy,
+//! since their URLs come from users. The owner is notified when a webhook
+//! that was working stops accepting deliveries.
+
+use crate::{
+    admission::Priority,
+    conversations, guard, notifications, quota,
+    db_metrics::Timed,
+    state::AppState,
+    summarize,
+};
+use chrono::{DateTime, Utc};
+use ds_model::{ChatMessage, ChatRequest};
+use ds_notify::Notification;
+use ds_types::{ConversationId, ScheduleId, UserId};
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use sqlx::{postgres::PgRow, PgPool, Row};
+use std::time::Duration;
+
+/// Schedule runs by result (`delivered`, `failed`)
+pub const RUNS: &str = "deepersensor_schedule_runs_total";
+
+/// Where a run's reply goes
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Delivery {
+    /// Appended to a conversation as the prompt and the reply; a new one
+    /// named after the schedule when `conversation_id` is omitted
+    Conversation { conversation_id: Option<ConversationId> },
+    /// POSTed as JSON
+    Webhook { url: String },
+}
+
+#[derive(Debug, Serialize)]
+pub struct Schedule {
+    pub id: ScheduleId,
+    pub name: String,
+    pub cron: String,
+    pub model: String,
+    pub prompt: String,
+    pub delivery: Delivery,
+    pub enabled: bool,
+    /// `null` while disabled
+    pub next_run_at: Option<DateTime<Utc>>,
+    pub last_run_at: Option<DateTime<Utc>>,
+    /// `delivered` or `failed`
+    pub last_status: Option<String>,
+    pub last_error: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// A schedule's definition, as created or replaced
+pub struct Definition {
+    pub name: String,
+    pub cron: String,
+    pub model: String,
+    pub prompt: String,
+    /// With the conversation resolved
+    pub delivery: Delivery,
+    pub enabled: bool,
+}
+
+/// Parse a cron expression: five fields (`minute hour day month weekday`),
+/// six or seven with seconds first and a year last, or a shorthand such as
+/// `@daily`
+pub fn parse(expression: &str) -> Result<cron::Schedule, String> {
+    let expression = expression.trim();
+    let expanded = match expression.split_whitespace().count() {
+        5 => format!("0 {expression}"),
+        _ => expression.to_string(),
+    };
+    expanded.parse().map_err(|e: cron::error::Error| e.to_string())
+}
+
+/// Shortest gap among the next runs of `schedule` after `from`; `None` if
+/// it runs fewer than twice
+pub fn min_gap(schedule: &cron::Schedule, from: DateTime<Utc>) -> Option<Duration> {
+    let runs: Vec<DateTime<Utc>> = schedule.after(&from).take(16).collect();
+    runs.windows(2).filter_map(|w| (w[1] - w[0]).to_std().ok()).min()
+}
+
+/// Next run of `cron` after `from`, if it has one
+fn next_run(cron: &str, from: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    parse(cron).ok()?.after(&from).next()
+}
+
+const COLUMNS: &str = "id, name, cron, model, prompt, delivery, conversation_id, webhook_url, enabled, next_run_at, \
+                       last_run_at, last_status, last_error, created_at";
+
+fn delivery_columns(delivery: &Delivery) -> (&'static str, Option<ConversationId>, Option<&str>) {
+    match delivery {
+        Delivery::Conversation { conversation_id } => ("conversation", *conversation_id, None),
+        Delivery::Webhook { url } => ("webhook", None, Some(url)),
+    }
+}
+
+fn from_row(row: &PgRow) -> sqlx::Result<Schedule> {
+    let delivery = match row.try_get::<String, _>("delivery")?.as_str() {
+        "webhook" => Delivery::Webhook { url: row.try_get::<Option<String>, _>("webhook_url")?.unwrap_or_default() },
+        _ => Delivery::Conversation { conversation_id: row.try_get("conversation_id")? },
+    };
+    Ok(Schedule {
+        id: row.try_get("id")?,
+        name: row.try_get("name")?,
+        cron: row.try_get("cron")?,
+        model: row.try_get("model")?,
+        prompt: row.try_get("prompt")?,
+        delivery,
+        enabled: row.try_get("enabled")?,
+        next_run_at: row.try_get("next_run_at")?,
+        last_run_at: row.try_get("last_run_at")?,
+        last_status: row.try_get("last_status")?,
+        last_error: row.try_get("last_error")?,
+        created_at: row.try_get("created_at")?,
+    })
+}
+
+pub async fn count(db: &PgPool, user: UserId) -> sqlx::Result<u64> {
+    let n: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM schedules WHERE user_id = $1").bind(user).fetch_one(db).await?;
+    Ok(n as u64)
+}
+
+pub async fn create(db: &PgPool, user: UserId, def: &Definition) -> sqlx::Result<Schedule> {
+    let (delivery, conversation_id, webhook_url) = delivery_columns(&def.delivery);
+    let next_run_at = def.enabled.then(|| next_run(&def.cron, Utc::now())).flatten();
+    let row = sqlx::query(&format!(
+        "INSERT INTO schedules (id, user_id, name, cron, model, prompt, delivery, conversation_id, webhook_url, \
+         enabled, next_run_at) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11) RETURNING {COLUMNS}"
+    ))
+    .bind(ScheduleId::generate())
+    .bind(user)
+    .bind(&def.name)
+    .bind(&def.cron)
+    .bind(&def.model)
+    .bind(&def.prompt)
+    .bind(delivery)
+    .bind(conversation_id)
+    .bind(webhook_url)
+    .bind(def.enabled)
+    .bind(next_run_at)
+    .fetch_one(db)
+    .await?;
+    from_row(&row)
+}
+
+/// `user`'s schedules, oldest first
+pub async fn list(db: &PgPool, user: UserId) -> sqlx::Result<Vec<Schedule>> {
+    let rows = sqlx::query(&format!("SELECT {COLUMNS} FROM schedules WHERE user_id = $1 ORDER BY created_at"))
+        .bind(user)
+        .fetch_all(db)
+        .await?;
+    rows.iter().map(from_row).collect()
+}
+
+/// `user`'s schedule; `None` for a missing one or someone else's
+pub async fn get(db: &PgPool, user: UserId, id: ScheduleId) -> sqlx::Result<Option<Schedule>> {
+    let row = sqlx::query(&format!("SELECT {COLUMNS} FROM schedules WHERE id = $1 AND user_id = $2"))
+        .bind(id)
+        .bind(user)
+        .fetch_optional(db)
+        .await?;
+    row.as_ref().map(from_row).transpose()
+}
+
+/// Replace a schedule's definition, recomputing its next run
+pub async fn replace(db: &PgPool, user: UserId, id: ScheduleId, def: &Definition) -> sqlx::Result<Option<Schedule>> {
+    let (delivery, conversation_id, webhook_url) = delivery_columns(&def.delivery);
+    let next_run_at = def.enabled.then(|| next_run(&def.cron, Utc::now())).flatten();
+    let row = sqlx::query(&format!(
+        "UPDATE schedules SET name = $3, cron = $4, model = $5, prompt = $6, delivery = $7, conversation_id = $8, \
+         webhook_url = $9, enabled = $10, next_run_at = $11 WHERE id = $1 AND user_id = $2 RETURNING {COLUMNS}"
+    ))
+    .bind(id)
+    .bind(user)
+    .bind(&def.name)
+    .bind(&def.cron)
+    .bind(&def.model)
+    .bind(&def.prompt)
+    .bind(delivery)
+    .bind(conversation_id)
+    .bind(webhook_url)
+    .bind(def.enabled)
+    .bind(next_run_at)
+    .fetch_optional(db)
+    .await?;
+    row.as_ref().map(from_row).transpose()
+}
+
+/// Returns whether `user` had the schedule
+pub async fn delete(db: &PgPool, user: UserId, id: ScheduleId) -> sqlx::Result<bool> {
+    let deleted = sqlx::query("DELETE FROM schedules WHERE id = $1 AND user_id = $2")
+        .bind(id)
+        .bind(user)
+        .execute(db)
+        .await?
+        .rows_affected();
+    Ok(deleted == 1)
+}
+
+/// A claimed run
+struct Due {
+    id: ScheduleId,
+    user_id: UserId,
+    name: String,
+    model: String,
+    prompt: String,
+    delivery: Delivery,
+    run_at: DateTime<Utc>,
+    /// Outcome of the run before this one
+    last_status: Option<String>,
+}
+
+/// Claim up to `batch` due schedules, moving each to its next run (or
+/// disabling one that has none left)
+async fn claim_due(db: &PgPool, batch: u64) -> sqlx::Result<Vec<Due>> {
+    let mut tx = db.begin().await?;
+    let rows = sqlx::query(&format!(
+        "SELECT user_id, {COLUMNS} FROM schedules WHERE enabled AND next_run_at <= NOW() \
+         ORDER BY next_run_at LIMIT $1 FOR UPDATE SKIP LOCKED"
+    ))
+    .bind(batch as i64)
+    .fetch_all(&mut *tx)
+    .await?;
+    let now = Utc::now();
+    let mut due = Vec::with_capacity(rows.len());
+    for row in &rows {
+        let schedule = from_row(row)?;
+        let next_run_at = next_run(&schedule.cron, now);
+        sqlx::query("UPDATE schedules SET next_run_at = $2, enabled = $3, last_run_at = $4 WHERE id = $1")
+            .bind(schedule.id)
+            .bind(next_run_at)
+            .bind(next_run_at.is_some())
+            .bind(now)
+            .execute(&mut *tx)
+            .await?;
+        due.push(Due {
+            id: schedule.id,
+            user_id: row.try_get("user_id")?,
+            name: schedule.name,
+            model: schedule.model,
+            prompt: schedule.prompt,
+            delivery: schedule.delivery,
+            run_at: now,
+            last_status: schedule.last_status,
+        });
+    }
+    tx.commit().await?;
+    Ok(due)
+}
+
+/// Run every due schedule, returning how many ran
+pub async fn run_due(state: &AppState) -> sqlx::Result<usize> {
+    let due = claim_due(&state.db, state.config().schedules.batch).await?;
+    let ran = due.len();
+    // Generations wait in the admission queue, so there is no limit here
+    futures_util::future::join_all(due.into_iter().map(|due| run_one(state, due))).await;
+    Ok(ran)
+}
+
+async fn run_one(state: &AppState, due: Due) {
+    let result = match generate(state, &due).await {
+        Ok(reply) => deliver(state, &due, &reply).await,
+        Err(e) => Err(e),
+    };
+    let (status, error) = match &result {
+        Ok(()) => ("delivered", None),
+        Err(e) => ("failed", Some(e.as_str())),
+    };
+    state.metrics.incr(RUNS, &[("result", status)]);
+    tracing::info!(schedule_id = %due.id, user_id = %due.user_id, result = status, error, "schedule run");
+    let recorded = sqlx::query("UPDATE schedules SET last_status = $2, last_error = $3 WHERE id = $1")
+        .bind(due.id)
+        .bind(status)
+        .bind(error)
+        .execute(&state.db)
+        .await;
+    if let Err(e) = recorded {
+        tracing::warn!(error = %e, schedule_id = %due.id, "recording schedule run failed");
+    }
+}
+
+/// The prompt as the owner's message
+fn turn(due: &Due) -> Vec<ChatMessage> {
+    vec![ChatMessage { role: "user".into(), content: due.prompt.clone(), ..Default::default() }]
+}
+
+/// One batch-priority generation within the owner's quota, redacted like
+/// a chat response
+async fn generate(state: &AppState, due: &Due) -> Result<String, String> {
+    let quota_cfg = &state.config().quota;
+    if quota_cfg.daily_tokens > 0 {
+        let used = quota::tokens_today(&state.db, due.user_id)
+            .timed(&state.metrics, "quota.tokens_today")
+            .await
+            .map_err(|e| e.to_string())?;
+        if used >= quota_cfg.hard_limit() {
+            return Err("daily token quota exhausted".into());
+        }
+    }
+    let messages = guard::prepare_messages(state.config(), due.user_id, turn(due)).map_err(|e| e.to_string())?;
+    let permit = summarize::admit(state, Priority::Batch).await.map_err(|e| e.to_string())?;
+    let request = ChatRequest { model: due.model.clone(), messages, ..Default::default() };
+    let stream = state.provider.chat_stream(request).await.map_err(|e| e.to_string())?;
+    let mut stream = state.redactor.filter(ds_model::with_terminal_frame(stream, due.model.as_str()));
+    let (mut reply, mut tokens) = (String::new(), 0u64);
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(|e| e.to_string())?;
+        tokens += u64::from(!chunk.content.is_empty());
+        reply.push_str(&chunk.content);
+    }
+    drop(permit);
+    quota::record(state, quota_cfg, due.user_id, tokens).await;
+    Ok(reply)
+}
+
+#[derive(Serialize)]
+struct WebhookEvent<'a> {
+    event: &'static str,
+    schedule_id: ScheduleId,
+    name: &'a str,
+    model: &'a str,
+    run_at: DateTime<Utc>,
+    output: &'a str,
+}
+
+async fn deliver(state: &AppState, due: &Due, reply: &str) -> Result<(), String> {
+    match &due.delivery {
+        Delivery::Conversation { conversation_id: Some(id) } => {
+            conversations::append(&state.db, *id, &turn(due), reply, &[])
+                .timed(&state.metrics, "conversations.append")
+                .await
+                .map_err(|e| e.to_string())?;
+            Ok(())
+        }
+        Delivery::Conversation { conversation_id: None } => Err("conversation was deleted".into()),
+        Delivery::Webhook { url } => {
+            let posted = post(state, due, url, reply).await;
+            if let Err(e) = &posted {
+                // Once per run of failures rather than at every run
+                if due.last_status.as_deref() != Some("failed") {
+                    let failed = Notification::webhook_failed(&format!("run of schedule {}", due.name), url, e);
+                    if let Err(e) = notifications::notify(&state.db, &state.notifier, due.user_id, failed)
+                        .timed(&state.metrics, "notifications.notify")
+                        .await
+                    {
+                        tracing::warn!(error = %e, schedule_id = %due.id, "notifying webhook failure failed");
+                    }
+                }
+            }
+            posted
+        }
+    }
+}
+
+async fn post(state: &AppState, due: &Due, url: &str, reply: &str) -> Result<(), String> {
+    let event = WebhookEvent {
+        event: "schedule.run",
+        schedule_id: due.id,
+        name: &due.name,
+        model: &due.model,
+        run_at: due.run_at,
+        output: reply,
+    };
+    let body = serde_json::to_vec(&event).map_err(|e| e.to_string())?;
+    let sent = state
+        .tool_egress
+        .fetch(&state.metrics, "schedule_webhook", reqwest::Method::POST, url, Some(body))
+        .await
+        .map_err(|e| e.to_string())?;
+    match sent.status {
+        200..=299 => Ok(()),
+        status => Err(format!("webhook answered {status}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_parse_accepts_five_fields_and_shorthands() {
+        let from = Utc.with_ymd_and_hms(2026, 3, 2, 10, 30, 0).unwrap();
+        let next = |expression: &str| parse(expression).unwrap().after(&from).next().unwrap();
+        assert_eq!(next("0 9 * * *"), Utc.with_ymd_and_hms(2026, 3, 3, 9, 0, 0).unwrap());
+        assert_eq!(next("*/15 * * * *"), Utc.with_ymd_and_hms(2026, 3, 2, 10, 45, 0).unwrap());
+        assert_eq!(next("@daily"), Utc.with_ymd_and_hms(2026, 3, 3, 0, 0, 0).unwrap());
+        assert!(parse("61 * * * *").is_err());
+        assert!(parse("every day").is_err());
+
+        assert_eq!(min_gap(&parse("*/15 * * * *").unwrap(), from), Some(Duration::from_secs(900)));
+        assert_eq!(min_gap(&parse("* * * * * *").unwrap(), from), Some(Duration::from_secs(1)));
+        assert_eq!(min_gap(&parse("0 0 0 1 1 * 2026").unwrap(), from), None);
+    }
+}
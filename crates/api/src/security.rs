@@ -1,9 +1,14 @@
 use tower_http::set_header::SetResponseHeaderLayer;
 use axum::http::{self, HeaderValue};
-use tower::{Layer, ServiceBuilder};
+use axum::Router;
+use tower::ServiceBuilder;
 
-#[allow(dead_code)]
-pub fn security_headers() -> impl Layer<axum::routing::Route> + Clone {
+/// Add the security response headers to every route of `router` (set only
+/// if a handler did not already)
+pub fn with_security_headers<S>(router: Router<S>) -> Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
     let strict = SetResponseHeaderLayer::if_not_present(
         http::header::STRICT_TRANSPORT_SECURITY,
         HeaderValue::from_static("max-age=63072000; includeSubDomains; preload"));
@@ -22,12 +27,20 @@ pub fn security_headers() -> impl Layer<axum::routing::Route> + Clone {
     let perms = SetResponseHeaderLayer::if_not_present(
         http::HeaderName::from_static("permissions-policy"),
         HeaderValue::from_static("geolocation=(), microphone=(), camera=(), fullscreen=(self)"));
+    // Nothing the API serves is meant for search results, shared views
+    // included; robots.txt only stops the crawl, not indexing of links
+    let robots = SetResponseHeaderLayer::if_not_present(
+        http::HeaderName::from_static("x-robots-tag"),
+        HeaderValue::from_static("noindex, nofollow, noarchive"));
 
-    ServiceBuilder::new()
-        .layer(strict)
-        .layer(cto)
-        .layer(frame)
-        .layer(csp)
-        .layer(referrer)
-        .layer(perms)
-}
\ No newline at end of file
+    router.layer(
+        ServiceBuilder::new()
+            .layer(strict)
+            .layer(cto)
+            .layer(frame)
+            .layer(csp)
+            .layer(referrer)
+            .layer(perms)
+            .layer(robots),
+    )
+}
@@ -0,0 +1,200 @@
+//! `api --self-test`: check that this build can run against its
+//! configuration, then exit.
+//!
+//! Meant as an init container ahead of a rollout. Every check runs (each
+//! with a timeout) and is reported on its own line; the process exits
+//! non-zero when any failed. Warnings do not fail the run. Nothing is
+//! written: pending migrations are listed, not applied.
+
+use crate::state::AppState;
+use ds_core::config::AppConfig;
+use std::{collections::HashMap, fmt, future::Future, time::Duration};
+
+/// Longest any one check may take
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The shipped default, which must never reach production
+pub const DEFAULT_JWT_SECRET: &str = "dev_insecure_change_me";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Ok,
+    Warn,
+    Skip,
+    Fail,
+}
+
+#[derive(Debug)]
+pub struct Check {
+    pub name: &'static str,
+    pub outcome: Outcome,
+    pub detail: String,
+}
+
+#[derive(Debug, Default)]
+pub struct Report {
+    pub checks: Vec<Check>,
+}
+
+impl Report {
+    pub fn passed(&self) -> bool {
+        self.checks.iter().all(|c| c.outcome != Outcome::Fail)
+    }
+
+    fn add(&mut self, name: &'static str, result: Result<(Outcome, String), String>) {
+        let (outcome, detail) = result.unwrap_or_else(|e| (Outcome::Fail, e));
+        self.checks.push(Check { name, outcome, detail });
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for check in &self.checks {
+            let outcome = match check.outcome {
+                Outcome::Ok => "ok",
+                Outcome::Warn => "WARN",
+                Outcome::Skip => "skip",
+                Outcome::Fail => "FAIL",
+            };
+            writeln!(f, "{outcome:<5} {:<11} {}", check.name, check.detail)?;
+        }
+        let failed = self.checks.iter().filter(|c| c.outcome == Outcome::Fail).count();
+        match failed {
+            0 => writeln!(f, "self-test passed"),
+            n => writeln!(f, "self-test failed: {n} of {} checks", self.checks.len()),
+        }
+    }
+}
+
+/// Why `secret` is unfit to sign tokens, if it is
+pub fn jwt_secret_problem(secret: &str) -> Option<&'static str> {
+    if secret == DEFAULT_JWT_SECRET {
+        Some("it is the shipped default")
+    } else if secret.len() < 32 {
+        Some("it is shorter than 32 characters")
+    } else if secret.chars().collect::<std::collections::HashSet<_>>().len() < 8 {
+        Some("it repeats too few distinct characters")
+    } else {
+        None
+    }
+}
+
+/// Run every check against `state`, built from the configuration under
+/// test
+pub async fn run(state: &AppState) -> Report {
+    let cfg = state.config();
+    let mut report = Report::default();
+    report.add("config", Ok((Outcome::Ok, format!("loaded for env {}", cfg.app.env))));
+    report.add("policy", policy(cfg));
+    report.add("jwt_secret", jwt_secret(cfg));
+    let database = timed(database(state)).await;
+    let connected = database.is_ok();
+    report.add("database", database);
+    match connected {
+        true => report.add("migrations", timed(migrations(state)).await),
+        false => report.add("migrations", Ok((Outcome::Skip, "no database connection".into()))),
+    }
+    report.add("redis", timed(redis(cfg)).await);
+    report.add("provider", timed(provider(state)).await);
+    report
+}
+
+async fn timed<F>(check: F) -> Result<(Outcome, String), String>
+where
+    F: Future<Output = Result<(Outcome, String), String>>,
+{
+    tokio::time::timeout(TIMEOUT, check).await.unwrap_or_else(|_| Err(format!("timed out after {TIMEOUT:?}")))
+}
+
+fn policy(cfg: &AppConfig) -> Result<(Outcome, String), String> {
+    let policy = crate::policy::Policy::from_config(cfg).map_err(|e| e.to_string())?;
+    Ok(match policy.is_empty() {
+        true => (Outcome::Ok, "no route policy".into()),
+        false => (Outcome::Ok, format!("{} rules from {}", policy.len(), cfg.security.policy_file)),
+    })
+}
+
+/// Weak secrets fail in production and warn elsewhere
+fn jwt_secret(cfg: &AppConfig) -> Result<(Outcome, String), String> {
+    match jwt_secret_problem(&cfg.security.jwt_secret) {
+        None => Ok((Outcome::Ok, "strong enough".into())),
+        Some(problem) if cfg.is_production() => Err(format!("JWT_SECRET unfit for production: {problem}")),
+        Some(problem) => Ok((Outcome::Warn, format!("JWT_SECRET unfit for production: {problem}"))),
+    }
+}
+
+async fn database(state: &AppState) -> Result<(Outcome, String), String> {
+    let version: String = sqlx::query_scalar("SHOW server_version")
+        .fetch_one(&state.db)
+        .await
+        .map_err(|e| format!("cannot query: {e}"))?;
+    Ok((Outcome::Ok, format!("PostgreSQL {version}")))
+}
+
+/// Applied migrations must all be complete and, when known to this build,
+/// unchanged; pending ones fail under `MIGRATIONS_MODE=verify`, which will
+/// not apply them
+async fn migrations(state: &AppState) -> Result<(Outcome, String), String> {
+    let migrator = crate::migrations::migrator();
+    let applied: HashMap<i64, (Vec<u8>, bool)> = crate::migrations::applied(&state.db)
+        .await
+        .map_err(|e| e.to_string())?
+        .into_iter()
+        .map(|m| (m.version, (m.checksum, m.success)))
+        .collect();
+    let known: HashMap<i64, &[u8]> = migrator.iter().map(|m| (m.version, &*m.checksum)).collect();
+    if let Some(version) = applied.iter().find(|(_, (_, success))| !success).map(|(v, _)| v) {
+        return Err(format!("migration {version} did not complete"));
+    }
+    let newer = applied.keys().filter(|v| !known.contains_key(v)).count();
+    let changed = applied.iter().find(|(v, (checksum, _))| known.get(v).is_some_and(|k| *k != checksum.as_slice()));
+    if let Some((version, _)) = changed {
+        return Err(format!("migration {version} changed since it was applied"));
+    }
+    let pending: Vec<String> =
+        migrator.iter().filter(|m| !applied.contains_key(&m.version)).map(|m| m.version.to_string()).collect();
+    let (count, list) = (pending.len(), pending.join(", "));
+    Ok(match count {
+        0 if newer > 0 => {
+            let detail = format!("{newer} applied by a newer build; ready only with MIGRATIONS_ALLOW_NEWER_SCHEMA");
+            (Outcome::Warn, detail)
+        }
+        0 => (Outcome::Ok, format!("{} applied, none pending", applied.len())),
+        _ if state.config().database.migrations == "verify" => {
+            return Err(format!("{count} pending, not applied under MIGRATIONS_MODE=verify: {list}"))
+        }
+        _ => (Outcome::Ok, format!("{count} pending, applied at startup: {list}")),
+    })
+}
+
+/// Only the signed-request nonce store uses Redis
+async fn redis(cfg: &AppConfig) -> Result<(Outcome, String), String> {
+    if cfg.signing.nonce_store != "redis" {
+        return Ok((Outcome::Skip, format!("not used (SIGNING_NONCE_STORE={})", cfg.signing.nonce_store)));
+    }
+    let client = redis::Client::open(cfg.redis.url.as_str()).map_err(|e| format!("invalid REDIS_URL: {e}"))?;
+    let mut conn = client.get_multiplexed_async_connection().await.map_err(|e| format!("cannot connect: {e}"))?;
+    let pong: String = redis::cmd("PING").query_async(&mut conn).await.map_err(|e| format!("PING failed: {e}"))?;
+    Ok((Outcome::Ok, pong))
+}
+
+async fn provider(state: &AppState) -> Result<(Outcome, String), String> {
+    let models = state.provider.list_models().await.map_err(|e| format!("{}: {e}", state.provider.name()))?;
+    Ok(match models.len() {
+        0 => (Outcome::Warn, format!("{} answered with no models", state.provider.name())),
+        n => (Outcome::Ok, format!("{} answered with {n} models", state.provider.name())),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_jwt_secret_strength() {
+        assert_eq!(jwt_secret_problem(DEFAULT_JWT_SECRET), Some("it is the shipped default"));
+        assert_eq!(jwt_secret_problem("short"), Some("it is shorter than 32 characters"));
+        assert_eq!(jwt_secret_problem(&"ab".repeat(20)), Some("it repeats too few distinct characters"));
+        assert_eq!(jwt_secret_problem("fO4k2LxQ9vR7mZp1sT8wY3bN6cJ0hGdE"), None);
+    }
+}
@@ -0,0 +1,181 @@
+//! Semantic response cache.
+//!
+//! A chat's normalized messages are embedded with
+//! `semantic_cache.embedding_model`; a stored response whose prompt embeds
+//! at least `similarity_threshold` (cosine) close is served instead of
+//! generating. Entries belong to the caller's org, or the user without one,
+//! so responses never cross tenants. The nearest entry is found by pgvector,
+//! through the HNSW index where one covers the embedding's size.
+
+use crate::state::AppState;
+use ds_model::ChatMessage;
+use sqlx::{PgPool, Row};
+use uuid::Uuid;
+
+/// Lookups by result: `hit`, `miss`, or `error` (embedding failed)
+pub const LOOKUPS: &str = "deepersensor_semantic_cache_lookups_total";
+
+/// Outcome of a lookup for a route and model the cache applies to
+pub enum Lookup {
+    Hit(String),
+    /// Keep the embedding to store the response under once generated
+    Miss(Vec<f32>),
+}
+
+/// Role-tagged messages with case and whitespace folded, so trivially
+/// different prompts embed identically
+pub fn normalize(messages: &[ChatMessage]) -> String {
+    messages
+        .iter()
+        .map(|m| {
+            let content = m.content.split_whitespace().collect::<Vec<_>>().join(" ");
+            format!("{}: {}", m.role.to_lowercase(), content.to_lowercase())
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Cosine similarity; 0 for mismatched or zero vectors
+pub fn cosine(a: &[f32], b: &[f32]) -> f64 {
+    if a.len() != b.len() || a.is_empty() {
+        return 0.0;
+    }
+    let (mut dot, mut norm_a, mut norm_b) = (0f64, 0f64, 0f64);
+    for (x, y) in a.iter().zip(b) {
+        let (x, y) = (f64::from(*x), f64::from(*y));
+        dot += x * y;
+        norm_a += x * x;
+        norm_b += y * y;
+    }
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return 0.0;
+    }
+    dot / (norm_a.sqrt() * norm_b.sqrt())
+}
+
+/// Look `messages` up for `owner`; `None` when the cache is off for this
+/// route and model or the prompt could not be embedded (the chat then
+/// generates as usual)
+pub async fn lookup(
+    state: &AppState,
+    owner: Uuid,
+    route: &str,
+    model: &str,
+    messages: &[ChatMessage],
+) -> Option<Lookup> {
+    let cfg = &state.config().semantic_cache;
+    if !cfg.applies(route, model) {
+        return None;
+    }
+    let embedding = match state.provider.embed(&cfg.embedding_model, &normalize(messages)).await {
+        // pgvector has no zero-dimension vectors, so an empty embedding cannot be cached
+        Ok(embedding) if !embedding.is_empty() => embedding,
+        Ok(_) => {
+            tracing::warn!(model = %cfg.embedding_model, "embedding model returned an empty embedding");
+            state.metrics.incr(LOOKUPS, &[("result", "error")]);
+            return None;
+        }
+        Err(e) => {
+            tracing::warn!(error = %e, model = %cfg.embedding_model, "embedding prompt for semantic cache failed");
+            state.metrics.incr(LOOKUPS, &[("result", "error")]);
+            return None;
+        }
+    };
+    // The cast to a sized vector lets the index of that size serve the
+    // ordering. Zero vectors have a NaN distance, which fails the threshold.
+    let dims = embedding.len();
+    let row = sqlx::query(&format!(
+        "SELECT id, response, distance FROM ( \
+           SELECT id, response, embedding::vector({dims}) <=> $1::real[]::vector({dims}) AS distance \
+           FROM semantic_cache \
+           WHERE vector_dims(embedding) = {dims} AND owner_id = $2 AND route = $3 AND model = $4 \
+           AND embedding_model = $5 AND created_at > NOW() - make_interval(secs => $6) \
+           ORDER BY distance LIMIT 1 \
+         ) nearest WHERE distance <= 1 - $7",
+    ))
+    .bind(&embedding)
+    .bind(owner)
+    .bind(route)
+    .bind(model)
+    .bind(&cfg.embedding_model)
+    .bind(cfg.ttl_secs as f64)
+    .bind(cfg.similarity_threshold)
+    .fetch_optional(&state.db)
+    .await;
+    let row = match row {
+        Ok(Some(row)) => row,
+        Ok(None) => {
+            state.metrics.incr(LOOKUPS, &[("result", "miss")]);
+            return Some(Lookup::Miss(embedding));
+        }
+        Err(e) => {
+            tracing::warn!(error = %e, "semantic cache lookup failed");
+            state.metrics.incr(LOOKUPS, &[("result", "error")]);
+            return None;
+        }
+    };
+    let (id, response): (i64, String) = (row.try_get("id").ok()?, row.try_get("response").ok()?);
+    let similarity = 1.0 - row.try_get::<f64, _>("distance").ok()?;
+    state.metrics.incr(LOOKUPS, &[("result", "hit")]);
+    tracing::debug!(entry = id, similarity, model, "semantic cache hit");
+    let db = state.db.clone();
+    tokio::spawn(async move {
+        let _ = sqlx::query("UPDATE semantic_cache SET hits = hits + 1 WHERE id = $1").bind(id).execute(&db).await;
+    });
+    Some(Lookup::Hit(response))
+}
+
+pub async fn store(
+    db: &PgPool,
+    owner: Uuid,
+    route: &str,
+    model: &str,
+    embedding_model: &str,
+    embedding: &[f32],
+    response: &str,
+) -> sqlx::Result<()> {
+    sqlx::query(
+        "INSERT INTO semantic_cache (owner_id, route, model, embedding_model, embedding, response) \
+         VALUES ($1, $2, $3, $4, $5::real[]::vector, $6)",
+    )
+    .bind(owner)
+    .bind(route)
+    .bind(model)
+    .bind(embedding_model)
+    .bind(embedding)
+    .bind(response)
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+/// Drop every entry, or only those for `model`; returns how many
+pub async fn flush(db: &PgPool, model: Option<&str>) -> sqlx::Result<u64> {
+    let flushed = sqlx::query("DELETE FROM semantic_cache WHERE $1::text IS NULL OR model = $1")
+        .bind(model)
+        .execute(db)
+        .await?
+        .rows_affected();
+    Ok(flushed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_folds_case_and_whitespace() {
+        let messages =
+            |content: &str| vec![ChatMessage { role: "User".into(), content: content.into(), ..Default::default() }];
+        assert_eq!(normalize(&messages("  What IS\n the capital? ")), "user: what is the capital?");
+        assert_eq!(normalize(&messages("what is the capital?")), normalize(&messages("What  is the CAPITAL?")));
+    }
+
+    #[test]
+    fn test_cosine() {
+        assert!((cosine(&[1.0, 2.0], &[2.0, 4.0]) - 1.0).abs() < 1e-9);
+        assert_eq!(cosine(&[1.0, 0.0], &[0.0, 1.0]), 0.0);
+        assert_eq!(cosine(&[1.0], &[1.0, 0.0]), 0.0);
+        assert_eq!(cosine(&[0.0, 0.0], &[1.0, 1.0]), 0.0);
+    }
+}
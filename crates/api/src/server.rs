@@ -0,0 +1,505 @@
+//! The HTTP server's accept loop.
+//!
+//! `axum::serve` gives hyper no timer, so a client may take as long as it
+//! likes over its headers, and the concurrency limit in `app` is global: one
+//! address opening hundreds of connections and trickling bytes down each
+//! holds them all. [`serve`] accepts connections itself and bounds them:
+//!
+//! - At most `SERVER_MAX_CONNECTIONS_PER_IP` are open per peer address; one
+//!   more is answered 429 and closed. Addresses in `TRUSTED_PROXY_IPS` are
+//!   exempt, since every client behind the proxy shares them.
+//! - A request's headers must arrive within `SERVER_READ_TIMEOUT_SECS` of
+//!   the connection opening or of their first byte. A keep-alive connection
+//!   may wait `SERVER_IDLE_TIMEOUT_SECS` for that byte before it is closed.
+//! - Each piece of a body must arrive within `SERVER_READ_TIMEOUT_SECS`,
+//!   and the whole body within `SERVER_BODY_TIMEOUT_SECS`. A handler reading
+//!   past either gets an error, and the connection is not reused.
+//!
+//! Closed connections count in `deepersensor_connections_dropped_total` by
+//! reason.
+
+use crate::metrics::Metrics;
+use axum::{body::Body, extract::ConnectInfo, response::Response, Router};
+use dashmap::DashMap;
+use ds_core::config::HttpSection;
+use hyper::body::{Bytes, Frame, SizeHint};
+use hyper_util::{rt::TokioIo, service::TowerToHyperService};
+use ipnet::IpNet;
+use std::{
+    future::Future,
+    io,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpListener, TcpStream},
+    sync::watch,
+};
+use tower::ServiceBuilder;
+use tower_http::timeout::{RequestBodyDeadlineLayer, RequestBodyTimeoutLayer};
+
+pub const DROPPED: &str = "deepersensor_connections_dropped_total";
+
+/// Written to a connection over its address's limit before closing it
+const REFUSAL: &[u8] = b"HTTP/1.1 429 Too Many Requests\r\nconnection: close\r\ncontent-length: 0\r\n\r\n";
+
+/// How long a refused connection is kept to deliver [`REFUSAL`]
+const REFUSAL_LINGER: Duration = Duration::from_millis(500);
+
+/// What one connection may hold and how slowly it may send
+#[derive(Debug, Clone)]
+pub struct Limits {
+    /// Open connections per address (0 = no limit)
+    pub per_ip: usize,
+    /// Addresses the per-address limit does not apply to
+    pub exempt: Vec<IpNet>,
+    /// Longest a request's headers may take once they have begun
+    pub header_timeout: Option<Duration>,
+    /// Longest a keep-alive connection may wait for its next request
+    pub idle_timeout: Option<Duration>,
+    /// Longest wait for the next piece of a body
+    pub body_idle: Option<Duration>,
+    /// Longest a whole body may take
+    pub body_deadline: Option<Duration>,
+}
+
+impl Limits {
+    pub fn from_config(cfg: &HttpSection) -> Self {
+        let secs = |secs: u64| (secs > 0).then(|| Duration::from_secs(secs));
+        let mut exempt = Vec::new();
+        for entry in cfg.trusted_proxy_ips.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            match entry.parse::<IpNet>().or_else(|_| entry.parse::<IpAddr>().map(IpNet::from)) {
+                Ok(net) => exempt.push(net),
+                Err(_) => tracing::warn!(entry, "ignoring unparsable TRUSTED_PROXY_IPS entry"),
+            }
+        }
+        Self {
+            per_ip: cfg.max_connections_per_ip,
+            exempt,
+            header_timeout: secs(cfg.read_timeout_secs),
+            idle_timeout: secs(cfg.idle_timeout_secs),
+            body_idle: secs(cfg.read_timeout_secs),
+            body_deadline: secs(cfg.body_timeout_secs),
+        }
+    }
+
+    fn capped(&self, ip: IpAddr) -> bool {
+        self.per_ip > 0 && !self.exempt.iter().any(|net| net.contains(&ip))
+    }
+}
+
+/// Open connections by address
+#[derive(Default)]
+struct Open(DashMap<IpAddr, usize>);
+
+impl Open {
+    /// A slot for `ip`, unless it already holds `limit`
+    fn claim(self: &Arc<Self>, ip: IpAddr, limit: usize) -> Option<Slot> {
+        let mut count = self.0.entry(ip).or_insert(0);
+        if *count >= limit {
+            return None;
+        }
+        *count += 1;
+        Some(Slot { open: self.clone(), ip })
+    }
+}
+
+/// One open connection, given back on drop
+struct Slot {
+    open: Arc<Open>,
+    ip: IpAddr,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.open.0.remove_if_mut(&self.ip, |_, count| {
+            *count -= 1;
+            *count == 0
+        });
+    }
+}
+
+/// Where a connection is between requests, and since when
+#[derive(Debug, Clone, Copy)]
+enum Phase {
+    /// Waiting for a request's headers; a new connection starts here
+    Head(Instant),
+    /// A request is being handled or its response written
+    Busy,
+    /// Kept alive with nothing received since the last response
+    Idle(Instant),
+}
+
+/// Resolves with the reason once the connection has spent too long in its
+/// current phase
+async fn watchdog(mut phase: watch::Receiver<Phase>, limits: Limits) -> &'static str {
+    loop {
+        let deadline = match *phase.borrow_and_update() {
+            Phase::Head(since) => limits.header_timeout.map(|limit| (since + limit, "header_timeout")),
+            Phase::Idle(since) => limits.idle_timeout.map(|limit| (since + limit, "idle_timeout")),
+            Phase::Busy => None,
+        };
+        let changed = match deadline {
+            Some((at, reason)) => tokio::select! {
+                _ = tokio::time::sleep_until(at.into()) => return reason,
+                changed = phase.changed() => changed,
+            },
+            None => phase.changed().await,
+        };
+        if changed.is_err() {
+            return std::future::pending().await;
+        }
+    }
+}
+
+/// The connection's socket; the first bytes read while idle start the
+/// header clock
+struct Watched {
+    stream: TcpStream,
+    phase: Arc<watch::Sender<Phase>>,
+}
+
+impl AsyncRead for Watched {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let read = Pin::new(&mut self.stream).poll_read(cx, buf);
+        if buf.filled().len() > before {
+            self.phase.send_if_modified(|phase| match phase {
+                Phase::Idle(_) => {
+                    *phase = Phase::Head(Instant::now());
+                    true
+                }
+                _ => false,
+            });
+        }
+        read
+    }
+}
+
+impl AsyncWrite for Watched {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.stream.is_write_vectored()
+    }
+}
+
+/// A response body that leaves its connection idle once hyper drops it,
+/// which it does when the body is written out or the connection is gone
+struct Tracked {
+    body: Body,
+    phase: Arc<watch::Sender<Phase>>,
+}
+
+impl hyper::body::Body for Tracked {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Bytes>, axum::Error>>> {
+        Pin::new(&mut self.body).poll_frame(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        self.phase.send_replace(Phase::Idle(Instant::now()));
+    }
+}
+
+struct Server {
+    router: Router,
+    limits: Limits,
+    metrics: Arc<Metrics>,
+}
+
+/// Serve `router` on `listener` until `shutdown` resolves, then wait for
+/// open connections to finish their requests
+pub async fn serve<F>(listener: TcpListener, router: Router, limits: Limits, metrics: Arc<Metrics>, shutdown: F)
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    let server = Arc::new(Server { router, limits, metrics });
+    let open = Arc::new(Open::default());
+    let (stop_tx, stop_rx) = watch::channel(());
+    // Every connection holds a receiver; the sender sees them all gone
+    let (done_tx, done_rx) = watch::channel(());
+    tokio::pin!(shutdown);
+    loop {
+        let (stream, addr) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    accept_error(e).await;
+                    continue;
+                }
+            },
+            _ = &mut shutdown => break,
+        };
+        let ip = addr.ip().to_canonical();
+        let slot = match server.limits.capped(ip) {
+            true => match open.claim(ip, server.limits.per_ip) {
+                Some(slot) => Some(slot),
+                None => {
+                    tracing::debug!(%ip, "refusing connection over the per-address limit");
+                    server.metrics.incr(DROPPED, &[("reason", "per_ip_limit")]);
+                    tokio::spawn(refuse(stream));
+                    continue;
+                }
+            },
+            false => None,
+        };
+        tokio::spawn(server.clone().connection(stream, addr, slot, stop_rx.clone(), done_rx.clone()));
+    }
+    drop(listener);
+    drop(done_rx);
+    let _ = stop_tx.send(());
+    done_tx.closed().await;
+}
+
+impl Server {
+    async fn connection(
+        self: Arc<Self>,
+        stream: TcpStream,
+        addr: SocketAddr,
+        _slot: Option<Slot>,
+        mut stop: watch::Receiver<()>,
+        _done: watch::Receiver<()>,
+    ) {
+        let phase = Arc::new(watch::Sender::new(Phase::Head(Instant::now())));
+        let watchdog = watchdog(phase.subscribe(), self.limits.clone());
+        let busy = phase.clone();
+        let idle = phase.clone();
+        let service = ServiceBuilder::new()
+            .map_request(move |mut req: axum::extract::Request<_>| {
+                busy.send_replace(Phase::Busy);
+                req.extensions_mut().insert(ConnectInfo(addr));
+                req
+            })
+            .map_response(move |res: Response| res.map(|body| Body::new(Tracked { body, phase: idle.clone() })))
+            .option_layer(self.limits.body_deadline.map(RequestBodyDeadlineLayer::new))
+            .option_layer(self.limits.body_idle.map(RequestBodyTimeoutLayer::new))
+            .service(self.router.clone());
+        // hyper's own header timeout also runs while a connection sits
+        // idle, so the watchdog keeps both clocks instead
+        let io = TokioIo::new(Watched { stream, phase });
+        let conn = hyper::server::conn::http1::Builder::new()
+            .serve_connection(io, TowerToHyperService::new(service))
+            .with_upgrades();
+        tokio::pin!(conn, watchdog);
+        let mut stopping = false;
+        let result = loop {
+            tokio::select! {
+                result = conn.as_mut() => break result,
+                reason = watchdog.as_mut() => {
+                    if reason == "header_timeout" {
+                        self.metrics.incr(DROPPED, &[("reason", reason)]);
+                    }
+                    tracing::debug!(%addr, reason, "closing connection");
+                    return;
+                }
+                _ = stop.changed(), if !stopping => {
+                    stopping = true;
+                    conn.as_mut().graceful_shutdown();
+                }
+            }
+        };
+        if let Err(e) = result {
+            tracing::debug!(error = %e, %addr, "connection closed with an error");
+        }
+    }
+}
+
+/// Answer 429 and close. The request is read and dropped for a moment
+/// first: closing with it unread resets the connection, and the client
+/// may never see the answer.
+async fn refuse(mut stream: TcpStream) {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let linger = async {
+        stream.write_all(REFUSAL).await?;
+        stream.shutdown().await?;
+        let mut buf = [0u8; 1024];
+        while stream.read(&mut buf).await? > 0 {}
+        io::Result::Ok(())
+    };
+    let _ = tokio::time::timeout(REFUSAL_LINGER, linger).await;
+}
+
+/// Back off when accepting fails for want of resources (file descriptors,
+/// memory); a client giving up mid-handshake is no reason to
+async fn accept_error(e: io::Error) {
+    use io::ErrorKind::{ConnectionAborted, ConnectionRefused, ConnectionReset};
+    if matches!(e.kind(), ConnectionAborted | ConnectionRefused | ConnectionReset) {
+        return;
+    }
+    tracing::error!(error = %e, "accepting a connection failed");
+    tokio::time::sleep(Duration::from_secs(1)).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Bytes, routing::{get, post}};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn limits() -> Limits {
+        Limits {
+            per_ip: 0,
+            exempt: Vec::new(),
+            header_timeout: None,
+            idle_timeout: None,
+            body_idle: None,
+            body_deadline: None,
+        }
+    }
+
+    async fn spawn(limits: Limits) -> (SocketAddr, Arc<Metrics>) {
+        let router = Router::new()
+            .route("/", get(|ConnectInfo(addr): ConnectInfo<SocketAddr>| async move { addr.ip().to_string() }))
+            .route("/echo", post(|body: Bytes| async move { body }));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let metrics = Arc::new(Metrics::default());
+        tokio::spawn(serve(listener, router, limits, metrics.clone(), std::future::pending()));
+        (addr, metrics)
+    }
+
+    /// Everything the server sends until it closes, or what came within 2s
+    async fn read_all(stream: &mut TcpStream) -> String {
+        let mut out = Vec::new();
+        let _ = tokio::time::timeout(Duration::from_secs(2), stream.read_to_end(&mut out)).await;
+        String::from_utf8_lossy(&out).into_owned()
+    }
+
+    async fn get_root(addr: SocketAddr) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\nhost: test\r\nconnection: close\r\n\r\n").await.unwrap();
+        read_all(&mut stream).await
+    }
+
+    fn dropped(metrics: &Metrics) -> Vec<(String, u64)> {
+        metrics.sum_by(DROPPED, "reason").into_iter().collect()
+    }
+
+    #[tokio::test]
+    async fn test_connections_over_the_per_ip_limit_are_refused() {
+        let (addr, metrics) = spawn(Limits { per_ip: 2, ..limits() }).await;
+        let first = TcpStream::connect(addr).await.unwrap();
+        let _second = TcpStream::connect(addr).await.unwrap();
+        assert!(get_root(addr).await.starts_with("HTTP/1.1 429"));
+        assert_eq!(dropped(&metrics), [("per_ip_limit".to_string(), 1)]);
+        // A closed connection frees its slot once the server sees it go
+        drop(first);
+        let mut answer = String::new();
+        for _ in 0..50 {
+            answer = get_root(addr).await;
+            if answer.starts_with("HTTP/1.1 200") {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert!(answer.starts_with("HTTP/1.1 200"), "{answer}");
+        assert!(answer.ends_with("127.0.0.1"), "handlers see the peer address: {answer}");
+    }
+
+    #[tokio::test]
+    async fn test_trusted_proxies_are_not_limited() {
+        let exempt = vec!["127.0.0.0/8".parse().unwrap()];
+        let (addr, metrics) = spawn(Limits { per_ip: 1, exempt, ..limits() }).await;
+        let _held = TcpStream::connect(addr).await.unwrap();
+        assert!(get_root(addr).await.starts_with("HTTP/1.1 200"));
+        assert!(dropped(&metrics).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_trickled_headers_time_out() {
+        let header_timeout = Some(Duration::from_millis(200));
+        let (addr, metrics) = spawn(Limits { header_timeout, ..limits() }).await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\nhost: test\r\n").await.unwrap();
+        let started = std::time::Instant::now();
+        let answer = read_all(&mut stream).await;
+        assert!(started.elapsed() < Duration::from_secs(1), "closed after {:?}", started.elapsed());
+        assert!(!answer.contains("200 OK"), "{answer}");
+        assert_eq!(dropped(&metrics), [("header_timeout".to_string(), 1)]);
+    }
+
+    #[tokio::test]
+    async fn test_idle_keep_alive_outlasts_the_header_timeout() {
+        let header_timeout = Some(Duration::from_millis(100));
+        let idle_timeout = Some(Duration::from_millis(600));
+        let (addr, metrics) = spawn(Limits { header_timeout, idle_timeout, ..limits() }).await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let mut buf = [0u8; 1024];
+        for _ in 0..2 {
+            stream.write_all(b"GET / HTTP/1.1\r\nhost: test\r\n\r\n").await.unwrap();
+            let n = stream.read(&mut buf).await.unwrap();
+            assert!(buf[..n].starts_with(b"HTTP/1.1 200"), "{}", String::from_utf8_lossy(&buf[..n]));
+            // Longer than the header timeout, within the idle one
+            tokio::time::sleep(Duration::from_millis(300)).await;
+        }
+        let started = std::time::Instant::now();
+        assert_eq!(read_all(&mut stream).await, "");
+        let waited = started.elapsed();
+        assert!(waited > Duration::from_millis(200) && waited < Duration::from_secs(1), "closed after {waited:?}");
+        assert!(dropped(&metrics).is_empty());
+    }
+
+    async fn post_slowly(addr: SocketAddr, pause: Duration) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let head = b"POST /echo HTTP/1.1\r\nhost: test\r\nconnection: close\r\ncontent-length: 10\r\n\r\n";
+        stream.write_all(head).await.unwrap();
+        for _ in 0..10 {
+            if stream.write_all(b"x").await.is_err() {
+                break;
+            }
+            tokio::time::sleep(pause).await;
+        }
+        read_all(&mut stream).await
+    }
+
+    #[tokio::test]
+    async fn test_stalled_body_is_cut_off() {
+        let (addr, _) = spawn(Limits { body_idle: Some(Duration::from_millis(100)), ..limits() }).await;
+        assert!(post_slowly(addr, Duration::from_millis(300)).await.starts_with("HTTP/1.1 400"));
+        // Steady pieces are fine
+        assert!(post_slowly(addr, Duration::from_millis(10)).await.starts_with("HTTP/1.1 200"));
+    }
+
+    #[tokio::test]
+    async fn test_trickled_body_hits_the_deadline() {
+        let body_deadline = Some(Duration::from_millis(300));
+        let (addr, _) = spawn(Limits { body_idle: Some(Duration::from_millis(100)), body_deadline, ..limits() }).await;
+        // Every piece comes in time, the whole body does not
+        assert!(post_slowly(addr, Duration::from_millis(50)).await.starts_with("HTTP/1.1 400"));
+    }
+}
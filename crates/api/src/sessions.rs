@@ -0,0 +1,100 @@
+//! Persisted login sessions.
+//!
+//! Each session's refresh tokens form one family: only the most recently
+//! issued `jti` can be redeemed. Presenting an older one means a refresh
+//! token leaked or was replayed, so the whole session is revoked.
+
+use ds_types::{SessionId, TokenId, UserId};
+use sqlx::{PgPool, Row};
+use std::time::Duration;
+
+/// Outcome of redeeming a refresh token
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Redeem {
+    /// Accepted; the session now expects this `jti` next
+    Rotated(TokenId),
+    /// Unknown, expired, or already revoked session
+    Invalid,
+    /// A superseded `jti` was presented; the session has been revoked
+    Reused,
+}
+
+pub const REVOKED_LOGOUT: &str = "logout";
+pub const REVOKED_REUSE: &str = "refresh_reuse";
+
+/// Start a session and return its id and first refresh `jti`
+pub async fn create(db: &PgPool, user_id: UserId, ttl: Duration) -> sqlx::Result<(SessionId, TokenId)> {
+    let (sid, jti) = (SessionId::generate(), TokenId::generate());
+    sqlx::query(
+        "INSERT INTO sessions (id, user_id, current_jti, expires_at) \
+         VALUES ($1, $2, $3, NOW() + make_interval(secs => $4))",
+    )
+    .bind(sid)
+    .bind(user_id)
+    .bind(jti)
+    .bind(ttl.as_secs_f64())
+    .execute(db)
+    .await?;
+    Ok((sid, jti))
+}
+
+/// Swap `presented` for a fresh `jti`, extending the session by `ttl`.
+///
+/// The swap is a single conditional update, so two concurrent redemptions
+/// of the same token cannot both succeed; the loser is treated as reuse.
+pub async fn rotate(
+    db: &PgPool,
+    sid: SessionId,
+    user_id: UserId,
+    presented: TokenId,
+    ttl: Duration,
+) -> sqlx::Result<Redeem> {
+    let next = TokenId::generate();
+    let rotated = sqlx::query(
+        "UPDATE sessions SET current_jti = $4, last_refreshed_at = NOW(), \
+         expires_at = NOW() + make_interval(secs => $5) \
+         WHERE id = $1 AND user_id = $2 AND current_jti = $3 \
+         AND revoked_at IS NULL AND expires_at > NOW()",
+    )
+    .bind(sid)
+    .bind(user_id)
+    .bind(presented)
+    .bind(next)
+    .bind(ttl.as_secs_f64())
+    .execute(db)
+    .await?
+    .rows_affected();
+    if rotated == 1 {
+        return Ok(Redeem::Rotated(next));
+    }
+
+    let row = sqlx::query(
+        "SELECT revoked_at IS NULL AND expires_at > NOW() AS live FROM sessions \
+         WHERE id = $1 AND user_id = $2",
+    )
+    .bind(sid)
+    .bind(user_id)
+    .fetch_optional(db)
+    .await?;
+    match row {
+        Some(row) if row.try_get::<bool, _>("live")? => {
+            revoke(db, sid, REVOKED_REUSE).await?;
+            Ok(Redeem::Reused)
+        }
+        _ => Ok(Redeem::Invalid),
+    }
+}
+
+/// Revoke a session; returns false if it was unknown or already revoked
+pub async fn revoke(db: &PgPool, sid: SessionId, reason: &str) -> sqlx::Result<bool> {
+    let revoked = sqlx::query(
+        "UPDATE sessions SET revoked_at = NOW(), revoked_reason = $2 \
+         WHERE id = $1 AND revoked_at IS NULL",
+    )
+    .bind(sid)
+    .bind(reason)
+    .execute(db)
+    .await?
+    .rows_affected();
+    Ok(revoked == 1)
+}
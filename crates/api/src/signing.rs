@@ -0,0 +1,255 @@
+//! Signed requests for machine-to-machine API keys.
+//!
+//! A key created with `signed: true` also gets a signing secret, and every
+//! request made with it must carry:
+//!
+//! - `X-DS-Timestamp`: unix seconds, within `SIGNING_TOLERANCE_SECS` of
+//!   the server clock
+//! - `X-DS-Nonce`: 16 to 128 characters of `A-Z a-z 0-9 - _`, never reused
+//!   with the key
+//! - `X-DS-Signature`: `v1=` and the hex HMAC-SHA256, keyed by the signing
+//!   secret, of [`canonical`]: the timestamp, nonce, method, path with
+//!   query, and hex SHA-256 of the body, joined by newlines
+//!
+//! Nonces are remembered for twice the tolerance, which covers every
+//! timestamp that would still be accepted, so a captured request cannot be
+//! sent again. Failures are `401` and logged as `security.signature.rejected`.
+
+use crate::{api_keys::KeyAuth, state::AppState};
+use axum::{body::Body, extract::Request};
+use dashmap::DashMap;
+use ds_core::config::AppConfig;
+use ds_types::ApiKeyId;
+use hmac::{Hmac, Mac};
+use redis::aio::ConnectionManager;
+use sha2::{Digest, Sha256};
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
+use tokio::sync::OnceCell;
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub const TIMESTAMP_HEADER: &str = "x-ds-timestamp";
+pub const NONCE_HEADER: &str = "x-ds-nonce";
+pub const SIGNATURE_HEADER: &str = "x-ds-signature";
+
+/// Prefix of every signing secret
+pub const SECRET_PREFIX: &str = "dss_";
+
+/// Signed requests refused, by reason
+pub const REJECTIONS: &str = "deepersensor_signature_rejections_total";
+
+/// A key's signing secret; kept out of `Debug` output
+#[derive(Clone)]
+pub struct SigningSecret(pub String);
+
+impl fmt::Debug for SigningSecret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SigningSecret(********)")
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum Rejected {
+    #[error("signature headers missing")]
+    Missing,
+    #[error("signature headers malformed")]
+    Malformed,
+    #[error("timestamp outside the tolerance")]
+    Stale,
+    #[error("signature does not match")]
+    BadSignature,
+    #[error("nonce already used")]
+    Replayed,
+    #[error("body too large to verify")]
+    TooLarge,
+    #[error("nonce store unavailable")]
+    Unavailable,
+}
+
+impl Rejected {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Missing => "missing",
+            Self::Malformed => "malformed",
+            Self::Stale => "stale",
+            Self::BadSignature => "bad_signature",
+            Self::Replayed => "replayed",
+            Self::TooLarge => "too_large",
+            Self::Unavailable => "unavailable",
+        }
+    }
+}
+
+/// The string a request's signature covers
+pub fn canonical(timestamp: i64, nonce: &str, method: &str, path_and_query: &str, body: &[u8]) -> String {
+    let digest = hex::encode(Sha256::digest(body));
+    format!("{timestamp}\n{nonce}\n{}\n{path_and_query}\n{digest}", method.to_ascii_uppercase())
+}
+
+fn mac(secret: &str, canonical: &str) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(canonical.as_bytes());
+    mac
+}
+
+/// The `X-DS-Signature` value for `canonical`
+pub fn sign(secret: &str, canonical: &str) -> String {
+    format!("v1={}", hex::encode(mac(secret, canonical).finalize().into_bytes()))
+}
+
+fn check_signature(secret: &str, canonical: &str, header: &str) -> Result<(), Rejected> {
+    let sig = header.strip_prefix("v1=").ok_or(Rejected::Malformed)?;
+    let sig = hex::decode(sig).map_err(|_| Rejected::Malformed)?;
+    // Constant-time comparison
+    mac(secret, canonical).verify_slice(&sig).map_err(|_| Rejected::BadSignature)
+}
+
+fn valid_nonce(nonce: &str) -> bool {
+    (16..=128).contains(&nonce.len()) && nonce.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Nonces seen per key, each until it expires
+pub enum NonceStore {
+    /// Shared by every instance; connected on first use
+    Redis { url: String, conn: OnceCell<ConnectionManager> },
+    /// This instance only
+    Memory(DashMap<(ApiKeyId, String), Instant>),
+}
+
+impl NonceStore {
+    pub fn from_config(cfg: &AppConfig) -> Self {
+        match cfg.signing.nonce_store.as_str() {
+            "memory" => Self::Memory(DashMap::new()),
+            other => {
+                if other != "redis" {
+                    tracing::warn!(store = other, "unknown signing nonce store; using redis");
+                }
+                Self::Redis { url: cfg.redis.url.clone(), conn: OnceCell::new() }
+            }
+        }
+    }
+
+    /// Forget expired nonces; run periodically by the `nonce_sweep` job.
+    /// Redis expires its own
+    pub fn sweep(&self) {
+        if let Self::Memory(seen) = self {
+            let now = Instant::now();
+            seen.retain(|_, expires| *expires > now);
+        }
+    }
+
+    /// Record `nonce` for `key` for `ttl`; false if it was already there
+    pub async fn claim(&self, key: ApiKeyId, nonce: &str, ttl: Duration) -> Result<bool, redis::RedisError> {
+        match self {
+            Self::Memory(seen) => {
+                let now = Instant::now();
+                let mut entry = seen.entry((key, nonce.to_string())).or_insert(now);
+                if *entry > now {
+                    return Ok(false);
+                }
+                *entry = now + ttl;
+                Ok(true)
+            }
+            Self::Redis { url, conn } => {
+                let conn = conn
+                    .get_or_try_init(|| async { ConnectionManager::new(redis::Client::open(url.as_str())?).await })
+                    .await?;
+                let set: Option<String> = redis::cmd("SET")
+                    .arg(format!("ds:nonce:{key}:{nonce}"))
+                    .arg(1)
+                    .arg("NX")
+                    .arg("EX")
+                    .arg(ttl.as_secs().max(1))
+                    .query_async(&mut conn.clone())
+                    .await?;
+                Ok(set.is_some())
+            }
+        }
+    }
+}
+
+/// Timestamp, nonce, and signature sent with `req`
+fn signed_headers(req: &Request) -> Result<(i64, String, String), Rejected> {
+    let header = |name| {
+        let value = req.headers().get(name).ok_or(Rejected::Missing)?;
+        value.to_str().map(str::to_string).map_err(|_| Rejected::Malformed)
+    };
+    let timestamp = header(TIMESTAMP_HEADER)?.parse().map_err(|_| Rejected::Malformed)?;
+    let nonce = header(NONCE_HEADER)?;
+    if !valid_nonce(&nonce) {
+        return Err(Rejected::Malformed);
+    }
+    Ok((timestamp, nonce, header(SIGNATURE_HEADER)?))
+}
+
+/// Check the signature on `req`, made with `key`, and hand the request
+/// back with its body restored
+pub async fn verify(state: &AppState, key: &KeyAuth, secret: &SigningSecret, req: Request) -> Result<Request, Rejected> {
+    let cfg = &state.cfg.signing;
+    let (timestamp, nonce, signature) = signed_headers(&req)?;
+    if timestamp.abs_diff(chrono::Utc::now().timestamp()) > cfg.tolerance_secs {
+        return Err(Rejected::Stale);
+    }
+
+    let (parts, body) = req.into_parts();
+    let body = axum::body::to_bytes(body, cfg.max_body_bytes).await.map_err(|_| Rejected::TooLarge)?;
+    let path = parts.uri.path_and_query().map(|p| p.as_str()).unwrap_or("/");
+    let canonical = canonical(timestamp, &nonce, parts.method.as_str(), path, &body);
+    check_signature(&secret.0, &canonical, &signature)?;
+
+    // Only a correctly signed request may use up a nonce
+    let ttl = Duration::from_secs(cfg.tolerance_secs.saturating_mul(2));
+    match state.nonces.claim(key.id, &nonce, ttl).await {
+        Ok(true) => Ok(Request::from_parts(parts, Body::from(body))),
+        Ok(false) => Err(Rejected::Replayed),
+        Err(e) => {
+            tracing::error!(error = %e, "signing nonce store unavailable");
+            Err(Rejected::Unavailable)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_signature_covers_every_part() {
+        let secret = "dss_secret";
+        let base = canonical(1_700_000_000, "nonce-0123456789", "post", "/v1/chat?x=1", b"{}");
+        assert_eq!(base.lines().nth(2), Some("POST"));
+        let header = sign(secret, &base);
+        assert!(check_signature(secret, &base, &header).is_ok());
+        for other in [
+            canonical(1_700_000_001, "nonce-0123456789", "POST", "/v1/chat?x=1", b"{}"),
+            canonical(1_700_000_000, "nonce-0123456780", "POST", "/v1/chat?x=1", b"{}"),
+            canonical(1_700_000_000, "nonce-0123456789", "GET", "/v1/chat?x=1", b"{}"),
+            canonical(1_700_000_000, "nonce-0123456789", "POST", "/v1/chat?x=2", b"{}"),
+            canonical(1_700_000_000, "nonce-0123456789", "POST", "/v1/chat?x=1", b"[]"),
+        ] {
+            assert_eq!(check_signature(secret, &other, &header), Err(Rejected::BadSignature));
+        }
+        assert_eq!(check_signature("dss_other", &base, &header), Err(Rejected::BadSignature));
+        assert_eq!(check_signature(secret, &base, &header[3..]), Err(Rejected::Malformed));
+        assert!(valid_nonce("nonce-0123456789") && !valid_nonce("short") && !valid_nonce("nonce 0123456789"));
+    }
+
+    #[tokio::test]
+    async fn test_memory_store_accepts_a_nonce_once_per_key() {
+        let store = NonceStore::Memory(DashMap::new());
+        let (a, b) = (ApiKeyId::generate(), ApiKeyId::generate());
+        let ttl = Duration::from_secs(600);
+        assert!(store.claim(a, "n", ttl).await.unwrap());
+        assert!(!store.claim(a, "n", ttl).await.unwrap());
+        assert!(store.claim(b, "n", ttl).await.unwrap());
+        // Expired nonces may be seen again, and are swept out
+        assert!(store.claim(a, "m", Duration::ZERO).await.unwrap());
+        store.sweep();
+        let NonceStore::Memory(seen) = &store else { unreachable!() };
+        assert_eq!(seen.len(), 2);
+        assert!(store.claim(a, "m", ttl).await.unwrap());
+    }
+}
@@ -0,0 +1,430 @@
+//! Request timing: slow request and slow query detection, and the
+//! `Server-Timing` header.
+//!
+//! [`watch`] times each request to its response head and, when it takes
+//! longer than its route's threshold or ran a statement slower than
+//! `SLOW_QUERY_MS`, logs a `perf.slow_request` (or `perf.slow_query`)
+//! warning with the request id, user, route, and where the time went:
+//!
+//! - `db_ms`: database statements, as reported by sqlx to [`QueryTimer`]
+//! - `upstream_ms`: model provider calls, through [`TimedProvider`]
+//! - `serialization_ms`: decoding JSON bodies in `ValidatedJson`
+//! - `other_ms`: the rest
+//!
+//! With `SERVER_TIMING` set to `all`, or to `admin` for admins, responses
+//! also carry those phases as `Server-Timing: auth;dur=0.8, ratelimit;dur=0.0,
+//! db;dur=3.1, upstream_ttfb;dur=95.2, stream;dur=410.7, total;dur=512.4`
+//! (milliseconds; phases may overlap, `stream` is reading a model reply
+//! before the response head).
+//!
+//! Work is attributed to the request whose task does it; streams carried
+//! on by spawned tasks after the response head are not counted.
+
+use crate::{auth_middleware::AuthUser, metrics::Metrics};
+use async_trait::async_trait;
+use axum::{
+    extract::{Request, State},
+    http::HeaderValue,
+    middleware::Next,
+    response::Response,
+};
+use ds_core::config::AppConfig;
+use ds_model::{ChatRequest, ChatStream, Detected, ModelProvider, ModelResult};
+use ds_types::UserId;
+use futures_util::StreamExt;
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    time::{Duration, Instant},
+};
+use tracing::{field::Field, Event, Subscriber};
+use tracing_subscriber::{layer::Context, Layer};
+
+pub const SERVER_TIMING_HEADER: &str = "server-timing";
+
+/// Slow requests and statements, by kind (`request`, `query`)
+pub const SLOW: &str = "deepersensor_slow_total";
+
+tokio::task_local! {
+    static CURRENT: Arc<Timings>;
+}
+
+/// Where one request's time went
+#[derive(Debug, Default)]
+pub struct Timings {
+    db_micros: AtomicU64,
+    queries: AtomicU64,
+    slow_queries: AtomicU64,
+    /// Duration and summary of the slowest statement over the threshold
+    slowest: Mutex<Option<(Duration, String)>>,
+    auth_micros: AtomicU64,
+    ratelimit_micros: AtomicU64,
+    /// Until the provider answered
+    upstream_ttfb_micros: AtomicU64,
+    /// Reading a reply stream after that
+    stream_micros: AtomicU64,
+    serialization_micros: AtomicU64,
+    user: OnceLock<UserId>,
+    /// The caller is an admin (not impersonated)
+    admin: AtomicBool,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Phase {
+    Auth,
+    RateLimit,
+    UpstreamTtfb,
+    Stream,
+    Serialization,
+}
+
+fn micros(d: Duration) -> u64 {
+    d.as_micros().try_into().unwrap_or(u64::MAX)
+}
+
+impl Timings {
+    fn add_query(&self, elapsed: Duration, summary: &str, slow: Option<Duration>) {
+        self.db_micros.fetch_add(micros(elapsed), Ordering::Relaxed);
+        self.queries.fetch_add(1, Ordering::Relaxed);
+        if slow.is_some_and(|t| elapsed >= t) {
+            self.slow_queries.fetch_add(1, Ordering::Relaxed);
+            let mut slowest = self.slowest.lock().unwrap_or_else(|e| e.into_inner());
+            if slowest.as_ref().is_none_or(|(d, _)| elapsed > *d) {
+                *slowest = Some((elapsed, summary.to_string()));
+            }
+        }
+    }
+
+    fn counter(&self, phase: Phase) -> &AtomicU64 {
+        match phase {
+            Phase::Auth => &self.auth_micros,
+            Phase::RateLimit => &self.ratelimit_micros,
+            Phase::UpstreamTtfb => &self.upstream_ttfb_micros,
+            Phase::Stream => &self.stream_micros,
+            Phase::Serialization => &self.serialization_micros,
+        }
+    }
+
+    fn ms(counter: &AtomicU64) -> u64 {
+        counter.load(Ordering::Relaxed) / 1000
+    }
+
+    /// `Server-Timing` value for a request that took `total`
+    fn server_timing(&self, total: Duration) -> String {
+        let phases = [
+            ("auth", &self.auth_micros),
+            ("ratelimit", &self.ratelimit_micros),
+            ("db", &self.db_micros),
+            ("upstream_ttfb", &self.upstream_ttfb_micros),
+            ("stream", &self.stream_micros),
+        ];
+        let dur = |micros: u64| format!("{:.1}", micros as f64 / 1000.0);
+        let mut parts: Vec<String> =
+            phases.iter().map(|(name, c)| format!("{name};dur={}", dur(c.load(Ordering::Relaxed)))).collect();
+        parts.push(format!("total;dur={}", dur(micros(total))));
+        parts.join(", ")
+    }
+}
+
+/// Add `elapsed` to `phase` of the request being handled, if any
+pub fn record(phase: Phase, elapsed: Duration) {
+    let _ = CURRENT.try_with(|t| t.counter(phase).fetch_add(micros(elapsed), Ordering::Relaxed));
+}
+
+/// Name the caller of the request being handled, once authenticated
+pub fn note_user(user: &AuthUser) {
+    let _ = CURRENT.try_with(|t| {
+        let _ = t.user.set(user.user_id);
+        let admin = user.has_role(crate::auth_middleware::ROLE_ADMIN) && user.impersonated_by.is_none();
+        t.admin.store(admin, Ordering::Relaxed);
+    });
+}
+
+/// Who gets a `Server-Timing` header
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ServerTiming {
+    #[default]
+    Off,
+    Admin,
+    All,
+}
+
+/// Request thresholds by path
+#[derive(Debug, Default)]
+pub struct Thresholds {
+    default: Option<Duration>,
+    /// Pattern and threshold; `None` never logs
+    routes: Vec<(String, Option<Duration>)>,
+    query: Option<Duration>,
+    server_timing: ServerTiming,
+}
+
+fn threshold(ms: u64) -> Option<Duration> {
+    (ms > 0).then(|| Duration::from_millis(ms))
+}
+
+impl Thresholds {
+    pub fn from_config(cfg: &AppConfig) -> Self {
+        let s = &cfg.slow;
+        let routes = s
+            .routes
+            .split(',')
+            .map(str::trim)
+            .filter(|r| !r.is_empty())
+            .filter_map(|entry| {
+                let parsed = entry.rsplit_once('=').and_then(|(p, ms)| Some((p.trim(), ms.trim().parse().ok()?)));
+                if parsed.is_none() {
+                    tracing::warn!(entry, "ignoring SLOW_REQUEST_ROUTES entry; expected pattern=ms");
+                }
+                parsed.map(|(pattern, ms)| (pattern.to_string(), threshold(ms)))
+            })
+            .collect();
+        let server_timing = match s.server_timing.as_str() {
+            "all" => ServerTiming::All,
+            "admin" => ServerTiming::Admin,
+            "off" | "" => ServerTiming::Off,
+            other => {
+                tracing::warn!(value = other, "unknown SERVER_TIMING; sending no Server-Timing headers");
+                ServerTiming::Off
+            }
+        };
+        Self { default: threshold(s.request_ms), routes, query: threshold(s.query_ms), server_timing }
+    }
+
+    pub fn for_path(&self, path: &str) -> Option<Duration> {
+        match self.routes.iter().find(|(pattern, _)| crate::policy::glob(pattern, path)) {
+            Some((_, t)) => *t,
+            None => self.default,
+        }
+    }
+}
+
+/// State for [`watch`]
+pub struct Watch {
+    pub thresholds: Thresholds,
+    pub metrics: Arc<Metrics>,
+}
+
+/// Time the request, log it when it or a statement it ran was slow, and
+/// send the timings to callers allowed them
+pub async fn watch(State(watch): State<Arc<Watch>>, req: Request, next: Next) -> Response {
+    let t = &watch.thresholds;
+    let threshold = t.for_path(req.uri().path());
+    if threshold.is_none() && t.query.is_none() && t.server_timing == ServerTiming::Off {
+        return next.run(req).await;
+    }
+    let request_id = req
+        .headers()
+        .get(crate::request_id::REQUEST_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+    let (method, path) = (req.method().clone(), req.uri().path().to_string());
+    let timings = Arc::new(Timings::default());
+    let start = Instant::now();
+    let mut response = CURRENT.scope(timings.clone(), next.run(req)).await;
+    let total = start.elapsed();
+    let send_timing = match t.server_timing {
+        ServerTiming::All => true,
+        ServerTiming::Admin => timings.admin.load(Ordering::Relaxed),
+        ServerTiming::Off => false,
+    };
+    if send_timing {
+        if let Ok(value) = HeaderValue::from_str(&timings.server_timing(total)) {
+            response.headers_mut().insert(SERVER_TIMING_HEADER, value);
+        }
+    }
+
+    let slow_request = threshold.is_some_and(|t| total >= t);
+    let slow_queries = timings.slow_queries.load(Ordering::Relaxed);
+    if !slow_request && slow_queries == 0 {
+        return response;
+    }
+    let kind = if slow_request { "request" } else { "query" };
+    watch.metrics.incr(SLOW, &[("kind", kind)]);
+    let (db, upstream, serialization) = (
+        Timings::ms(&timings.db_micros),
+        Timings::ms(&timings.upstream_ttfb_micros) + Timings::ms(&timings.stream_micros),
+        Timings::ms(&timings.serialization_micros),
+    );
+    let total_ms = total.as_millis() as u64;
+    let slowest = timings.slowest.lock().unwrap_or_else(|e| e.into_inner()).take();
+    tracing::warn!(
+        request_id = %request_id,
+        user_id = timings.user.get().map(tracing::field::display),
+        method = %method,
+        path = %path,
+        status = response.status().as_u16(),
+        total_ms,
+        threshold_ms = threshold.map(|t| t.as_millis() as u64),
+        db_ms = db,
+        queries = timings.queries.load(Ordering::Relaxed),
+        slow_queries,
+        slowest_query_ms = slowest.as_ref().map(|(d, _)| d.as_millis() as u64),
+        slowest_query = slowest.as_ref().map(|(_, s)| s.as_str()),
+        upstream_ms = upstream,
+        serialization_ms = serialization,
+        other_ms = total_ms.saturating_sub(db + upstream + serialization),
+        "perf.slow_{kind}"
+    );
+    response
+}
+
+/// Feeds sqlx's per-statement events into the current request's
+/// [`Timings`]; install with a `sqlx::query=debug` filter of its own so
+/// statements are reported whatever `RUST_LOG` says
+pub struct QueryTimer {
+    pub slow: Option<Duration>,
+}
+
+#[derive(Default)]
+struct QueryFields {
+    elapsed_secs: Option<f64>,
+    summary: String,
+}
+
+impl tracing::field::Visit for QueryFields {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        if field.name() == "elapsed_secs" {
+            self.elapsed_secs = Some(value);
+        }
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "summary" {
+            self.summary = value.to_string();
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        if field.name() == "summary" {
+            self.summary = format!("{value:?}").trim_matches('"').to_string();
+        }
+    }
+}
+
+impl<S: Subscriber> Layer<S> for QueryTimer {
+    fn on_event(&self, event: &Event<'_>, _: Context<'_, S>) {
+        if event.metadata().target() != "sqlx::query" {
+            return;
+        }
+        let mut fields = QueryFields::default();
+        event.record(&mut fields);
+        let Some(elapsed) = fields.elapsed_secs.and_then(|s| Duration::try_from_secs_f64(s).ok()) else { return };
+        let _ = CURRENT.try_with(|t| t.add_query(elapsed, &fields.summary, self.slow));
+    }
+}
+
+/// A provider whose calls count as upstream time of the request making
+/// them: until the provider answers as `upstream_ttfb`, then reading a
+/// chat stream until it ends or is dropped as `stream`
+pub struct TimedProvider(pub Arc<dyn ModelProvider>);
+
+/// Records its lifetime as `phase` of the request it was made in
+pub struct PhaseTimer(Option<(Arc<Timings>, Phase, Instant)>);
+
+impl PhaseTimer {
+    pub fn start(phase: Phase) -> Self {
+        Self(CURRENT.try_with(|t| (t.clone(), phase, Instant::now())).ok())
+    }
+}
+
+impl Drop for PhaseTimer {
+    fn drop(&mut self) {
+        if let Some((timings, phase, start)) = &self.0 {
+            timings.counter(*phase).fetch_add(micros(start.elapsed()), Ordering::Relaxed);
+        }
+    }
+}
+
+#[async_trait]
+impl ModelProvider for TimedProvider {
+    fn name(&self) -> &'static str {
+        self.0.name()
+    }
+
+    async fn detect(&self) -> ModelResult<Option<Detected>> {
+        self.0.detect().await
+    }
+
+    fn detected(&self) -> Option<Detected> {
+        self.0.detected()
+    }
+
+    fn route(&self, model: &str) -> Option<(Arc<dyn ModelProvider>, String)> {
+        self.0.route(model)
+    }
+
+    fn routes(&self) -> Vec<&'static str> {
+        self.0.routes()
+    }
+
+    async fn list_models(&self) -> ModelResult<Vec<String>> {
+        let _timer = PhaseTimer::start(Phase::UpstreamTtfb);
+        self.0.list_models().await
+    }
+
+    async fn chat_stream(&self, req: ChatRequest) -> ModelResult<ChatStream> {
+        let ttfb = PhaseTimer::start(Phase::UpstreamTtfb);
+        let stream = self.0.chat_stream(req).await?;
+        drop(ttfb);
+        let timer = PhaseTimer::start(Phase::Stream);
+        Ok(Box::pin(stream.map(move |chunk| {
+            let _ = &timer;
+            chunk
+        })))
+    }
+
+    async fn embed(&self, model: &str, input: &str) -> ModelResult<Vec<f32>> {
+        let _timer = PhaseTimer::start(Phase::UpstreamTtfb);
+        self.0.embed(model, input).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing_subscriber::{filter::Targets, layer::SubscriberExt};
+
+    #[test]
+    fn test_route_thresholds() {
+        let mut cfg = AppConfig::load().unwrap();
+        cfg.slow.request_ms = 2000;
+        cfg.slow.routes = "/v1/chat*=30000, /health=0, broken".into();
+        let t = Thresholds::from_config(&cfg);
+        assert_eq!(t.for_path("/v1/chat/stream"), Some(Duration::from_secs(30)));
+        assert_eq!(t.for_path("/health"), None);
+        assert_eq!(t.for_path("/v1/limits"), Some(Duration::from_secs(2)));
+    }
+
+    #[tokio::test]
+    async fn test_sqlx_events_are_attributed_to_the_request() {
+        let timer = QueryTimer { slow: Some(Duration::from_millis(500)) };
+        let subscriber = tracing_subscriber::registry()
+            .with(timer.with_filter(Targets::new().with_target("sqlx::query", tracing::Level::DEBUG)));
+        let _guard = tracing::subscriber::set_default(subscriber);
+        let timings = Arc::new(Timings::default());
+        CURRENT
+            .scope(timings.clone(), async {
+                tracing::debug!(target: "sqlx::query", summary = "select 1", elapsed_secs = 0.25, "statement");
+                tracing::warn!(target: "sqlx::query", summary = "select pg_sleep", elapsed_secs = 0.75, "slow");
+                record(Phase::UpstreamTtfb, Duration::from_millis(40));
+            })
+            .await;
+        // Outside any request
+        tracing::debug!(target: "sqlx::query", summary = "select 2", elapsed_secs = 1.5, "statement");
+        assert_eq!(Timings::ms(&timings.db_micros), 1000);
+        assert_eq!(timings.queries.load(Ordering::Relaxed), 2);
+        assert_eq!(timings.slow_queries.load(Ordering::Relaxed), 1);
+        let slowest = timings.slowest.lock().unwrap().clone();
+        assert_eq!(slowest, Some((Duration::from_millis(750), "select pg_sleep".into())));
+        assert_eq!(Timings::ms(&timings.upstream_ttfb_micros), 40);
+        let header = timings.server_timing(Duration::from_millis(1200));
+        assert_eq!(
+            header,
+            "auth;dur=0.0, ratelimit;dur=0.0, db;dur=1000.0, upstream_ttfb;dur=40.0, stream;dur=0.0, total;dur=1200.0"
+        );
+    }
+}
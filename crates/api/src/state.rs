@@ -1,19 +1,147 @@
 use std::sync::Arc;
 use dashmap::DashMap;
+use ds_auth::TokenIssuer;
 use ds_core::config::AppConfig;
+use ds_model::images::{Automatic1111Images, ComfyUiImages, ImageProvider, OpenAiImages};
+use ds_model::transcribe::{OpenAiTranscriber, Transcriber, WhisperCppTranscriber};
 use ds_model::ModelProvider;
+use ds_store::{BlobStore, CloudStore, LocalStore, S3Settings};
 
 #[derive(Clone)]
 pub struct AppState {
     pub provider: Arc<dyn ModelProvider>,
     pub rate_map: Arc<DashMap<String, crate::rate_limit::TokenBucket>>, 
+    /// Per-email login attempt buckets
+    pub login_map: Arc<DashMap<String, crate::rate_limit::TokenBucket>>,
     pub cfg: Arc<AppConfig>,
     pub db: sqlx::PgPool,
+    /// Read replicas, for queries that tolerate staleness
+    pub db_router: Arc<crate::db_router::DbRouter>,
+    pub redactor: Arc<crate::redact::Redactor>,
+    pub metrics: Arc<crate::metrics::Metrics>,
+    pub tokens: Arc<TokenIssuer>,
+    /// Outbound client with the egress proxy and CA settings applied
+    pub http: reqwest::Client,
+    /// Open chat streams per user
+    pub streams: Arc<crate::quota::StreamSlots>,
+    /// Global generation slots and their wait queue
+    pub admission: Arc<crate::admission::Admission>,
+    /// Run on every stored conversation turn
+    pub processors: Arc<crate::enrich::Processors>,
+    /// Speech-to-text backend; `None` disables `/v1/audio/transcriptions`
+    pub transcriber: Option<Arc<dyn Transcriber>>,
+    /// Image generation backend; `None` disables `/v1/images/generations`
+    pub images: Option<Arc<dyn ImageProvider>>,
+    /// Malware scanner files pass before they are stored; `None` stores
+    /// them unscanned
+    pub scanner: Option<Arc<dyn crate::scan::Scanner>>,
+    /// Where file contents are kept; `None` keeps them in the database
+    pub blobs: Option<Arc<dyn BlobStore>>,
+    /// The only way server-side tools reach the network
+    pub tool_egress: Arc<crate::tool_egress::ToolEgress>,
+    /// Tools chats may ask the server to run
+    pub tools: Arc<crate::tools::Tools>,
+    /// Delivers notifications to users in the background
+    pub notifier: Arc<ds_notify::Notifier>,
+    /// Each org's config with its overrides applied
+    pub tenants: Arc<crate::tenants::TenantCache>,
+    /// Route policy checked after authentication
+    pub policy: Arc<crate::policy::Policy>,
+    /// Nonces of signed API key requests already seen
+    pub nonces: Arc<crate::signing::NonceStore>,
+    /// Generations streaming on this instance
+    pub generations: Arc<crate::generations::InFlight>,
+    /// Last known state of each dependency
+    pub health: Arc<crate::health::Tracker>,
+    /// Batched token usage writes
+    pub usage: Arc<crate::quota::UsageWriter>,
 }
 
 impl AppState {
-    pub fn new(provider: Arc<dyn ModelProvider>, cfg: Arc<AppConfig>, db: sqlx::PgPool) -> Self {
-        Self { provider, rate_map: Arc::new(DashMap::new()), cfg, db }
+    pub fn new(provider: Arc<dyn ModelProvider>, cfg: Arc<AppConfig>, db: sqlx::PgPool, http: reqwest::Client) -> Self {
+        let metrics = Arc::new(crate::metrics::Metrics::default());
+        let provider = crate::hedge::HedgedProvider::wrap(provider, &cfg.hedge, metrics.clone());
+        let provider: Arc<dyn ModelProvider> = Arc::new(crate::slow::TimedProvider(provider));
+        let redactor = Arc::new(crate::redact::Redactor::from_config(&cfg));
+        let tokens = Arc::new(TokenIssuer::new(&cfg.security.jwt_issuer, &cfg.security.jwt_secret, cfg.access_ttl(), cfg.refresh_ttl()));
+        let admission = Arc::new(crate::admission::Admission::from_config(&cfg.chat));
+        let processors = Arc::new(crate::enrich::Processors::from_config(&cfg));
+        let transcriber = transcriber_from_config(&cfg, &http);
+        let images = images_from_config(&cfg, &http);
+        let scanner = crate::scan::from_config(&cfg.files);
+        let blobs = blobs_from_config(&cfg);
+        let tool_egress = Arc::new(crate::tool_egress::ToolEgress::from_config(&cfg));
+        let tools = Arc::new(crate::tools::Tools::from_config(&cfg));
+        let usage = Arc::new(crate::quota::UsageWriter::from_config(&cfg.quota, metrics.clone()));
+        let db_router = Arc::new(crate::db_router::DbRouter::from_config(&cfg.database, db.clone(), metrics.clone()));
+        // A policy that cannot be read fails closed rather than open
+        let policy = Arc::new(crate::policy::Policy::from_config(&cfg).unwrap_or_else(|e| {
+            tracing::error!(error = %e, "route policy unusable; refusing authenticated requests");
+            crate::policy::Policy::deny_all()
+        }));
+        let nonces = Arc::new(crate::signing::NonceStore::from_config(&cfg));
+        let notifier = Arc::new(crate::notifications::from_config(&cfg, tool_egress.clone(), metrics.clone()));
+        Self { provider, rate_map: Arc::new(DashMap::new()), login_map: Arc::new(DashMap::new()), cfg, db, db_router, redactor, metrics, tokens, http, streams: Arc::default(), admission, processors, transcriber, images, scanner, blobs, tool_egress, tools, notifier, tenants: Arc::default(), policy, nonces, generations: Arc::default(), health: Arc::default(), usage }
     }
     pub fn config(&self) -> &AppConfig { &self.cfg }
 }
+
+fn blobs_from_config(cfg: &AppConfig) -> Option<Arc<dyn BlobStore>> {
+    let f = &cfg.files;
+    let cloud = |store: ds_store::StoreResult<CloudStore>| {
+        store.map(|s| Arc::new(s) as Arc<dyn BlobStore>).map_err(|e| e.to_string())
+    };
+    let store = match f.store.as_str() {
+        "" => return None,
+        "local" => Ok(Arc::new(LocalStore::new(&f.store_dir)) as Arc<dyn BlobStore>),
+        "s3" => cloud(CloudStore::s3(&S3Settings {
+            bucket: f.s3_bucket.clone(),
+            region: f.s3_region.clone(),
+            endpoint: f.s3_endpoint.clone(),
+            access_key_id: f.s3_access_key_id.clone(),
+            secret_access_key: f.s3_secret_access_key.clone(),
+        })),
+        "gcs" => cloud(CloudStore::gcs(&f.gcs_bucket, &f.gcs_credentials_path)),
+        other => Err(format!("unknown file store `{other}`")),
+    };
+    // Rejecting every file beats quietly keeping them somewhere else
+    Some(store.unwrap_or_else(|e| {
+        tracing::error!(error = %e, store = %f.store, "file store unavailable; files will be rejected");
+        Arc::new(ds_store::Unavailable(e))
+    }))
+}
+
+fn transcriber_from_config(cfg: &AppConfig, http: &reqwest::Client) -> Option<Arc<dyn Transcriber>> {
+    let t = &cfg.transcribe;
+    let timeout = std::time::Duration::from_secs(t.timeout_secs);
+    match t.backend.as_str() {
+        "" => None,
+        "whisper_cpp" => Some(Arc::new(WhisperCppTranscriber::new(&t.url, http.clone(), timeout))),
+        "openai" => Some(Arc::new(OpenAiTranscriber::new(&t.url, &t.api_key, http.clone(), timeout))),
+        other => {
+            tracing::warn!(backend = other, "unknown transcription backend; transcriptions disabled");
+            None
+        }
+    }
+}
+
+fn images_from_config(cfg: &AppConfig, http: &reqwest::Client) -> Option<Arc<dyn ImageProvider>> {
+    let i = &cfg.images;
+    let timeout = std::time::Duration::from_secs(i.timeout_secs);
+    match i.backend.as_str() {
+        "" => None,
+        "openai" => Some(Arc::new(OpenAiImages::new(&i.url, &i.api_key, http.clone(), timeout))),
+        "automatic1111" => Some(Arc::new(Automatic1111Images::new(&i.url, http.clone(), timeout))),
+        "comfyui" => match std::fs::read_to_string(&i.comfyui_workflow) {
+            Ok(workflow) => Some(Arc::new(ComfyUiImages::new(&i.url, workflow, http.clone(), timeout))),
+            Err(e) => {
+                tracing::warn!(error = %e, path = %i.comfyui_workflow, "ComfyUI workflow unreadable; images disabled");
+                None
+            }
+        },
+        other => {
+            tracing::warn!(backend = other, "unknown image backend; image generation disabled");
+            None
+        }
+    }
+}
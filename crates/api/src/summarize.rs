@@ -0,0 +1,202 @@
+//! Map-reduce summarization through the model provider.
+//!
+//! Input too long for one prompt is split into chunks of at most
+//! `summarize.chunk_chars` characters, preferring paragraph, then sentence,
+//! then word boundaries. Each chunk is summarized (map), and the partial
+//! summaries are combined (reduce), repeating until they fit one prompt.
+
+use crate::{
+    admission::{Permit, Priority, Ticket},
+    state::AppState,
+};
+use ds_core::error::{ApiError, ApiResult};
+use ds_model::{ChatMessage, ChatRequest, ChatStream, ModelError, ModelResult};
+use futures_util::StreamExt;
+
+/// Instruction for summarizing raw input
+pub const MAP_PROMPT: &str = "Summarize the following text concisely. Keep key facts, names, \
+     numbers, and decisions. Reply with the summary only.";
+/// Instruction for combining partial summaries
+pub const REDUCE_PROMPT: &str = "The following are summaries of consecutive parts of one text. \
+     Combine them into a single coherent summary. Reply with the summary only.";
+
+/// Split `text` into chunks of at most `max_chars` characters
+pub fn split(text: &str, max_chars: usize) -> Vec<String> {
+    let max = max_chars.max(1);
+    let mut chunks = Vec::new();
+    let (mut current, mut len) = (String::new(), 0);
+    for piece in pieces(text, max) {
+        let piece_len = piece.chars().count();
+        if len > 0 && len + piece_len > max {
+            chunks.push(std::mem::take(&mut current));
+            len = 0;
+        }
+        current.push_str(piece);
+        len += piece_len;
+    }
+    if !current.trim().is_empty() {
+        chunks.push(current);
+    }
+    chunks
+}
+
+/// Paragraphs, or sentences, words, and finally raw runs where a larger
+/// unit would not fit
+fn pieces(text: &str, max: usize) -> Vec<&str> {
+    let fits = |s: &str| s.chars().count() <= max;
+    let mut out = Vec::new();
+    for paragraph in text.split_inclusive("\n\n") {
+        if fits(paragraph) {
+            out.push(paragraph);
+            continue;
+        }
+        for sentence in paragraph.split_inclusive(". ") {
+            if fits(sentence) {
+                out.push(sentence);
+                continue;
+            }
+            for word in sentence.split_inclusive(' ') {
+                if fits(word) {
+                    out.push(word);
+                    continue;
+                }
+                let mut start = 0;
+                for (n, (i, _)) in word.char_indices().enumerate() {
+                    if n > 0 && n % max == 0 {
+                        out.push(&word[start..i]);
+                        start = i;
+                    }
+                }
+                out.push(&word[start..]);
+            }
+        }
+    }
+    out
+}
+
+/// The prompt summarizing `text` under `instruction`
+pub fn prompt(instruction: &str, text: &str) -> Vec<ChatMessage> {
+    vec![
+        ChatMessage { role: "system".into(), content: instruction.into(), ..Default::default() },
+        ChatMessage { role: "user".into(), content: text.into(), ..Default::default() },
+    ]
+}
+
+/// Wait for a generation slot at `priority`
+pub async fn admit(state: &AppState, priority: Priority) -> ApiResult<Permit> {
+    match state.admission.enqueue(priority).ok_or(ApiError::RateLimited)? {
+        Ticket::Admitted(permit) => Ok(permit),
+        Ticket::Queued(mut queued) => Ok(queued.admitted().await),
+    }
+}
+
+/// Run one prompt to completion; returns the text and its content chunks
+/// (roughly tokens)
+pub async fn complete(state: &AppState, model: &str, messages: Vec<ChatMessage>) -> ModelResult<(String, u64)> {
+    let stream = state.provider.chat_stream(ChatRequest { model: model.into(), messages, ..Default::default() }).await?;
+    let mut stream = ds_model::with_terminal_frame(stream, model);
+    let (mut text, mut tokens) = (String::new(), 0u64);
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk?;
+        tokens += u64::from(!chunk.content.is_empty());
+        text.push_str(&chunk.content);
+    }
+    Ok((text, tokens))
+}
+
+/// Progress of the map and reduce passes before the final summary streams
+pub struct Pass {
+    /// `map` for the first pass over the input, `reduce` after
+    pub stage: &'static str,
+    pub completed: usize,
+    pub total: usize,
+}
+
+/// Summarize chunks until what is left fits one prompt (or a pass stops
+/// shrinking it), reporting each finished chunk to `progress`; returns the
+/// final prompt and the tokens generated on the way
+pub async fn reduce_to_prompt(
+    state: &AppState,
+    model: &str,
+    priority: Priority,
+    text: &str,
+    mut progress: impl FnMut(Pass),
+) -> ApiResult<(Vec<ChatMessage>, u64)> {
+    let cfg = &state.config().summarize;
+    let max = cfg.chunk_chars as usize;
+    let (mut instruction, mut stage, mut input) = (MAP_PROMPT, "map", text.to_string());
+    let mut tokens = 0;
+    loop {
+        let chunks = split(&input, max);
+        if chunks.len() <= 1 {
+            return Ok((prompt(instruction, &input), tokens));
+        }
+        let total = chunks.len();
+        let mut summaries = vec![String::new(); total];
+        let mut pending = futures_util::stream::iter(chunks.into_iter().enumerate())
+            .map(move |(i, chunk)| async move {
+                let _permit = admit(state, priority).await?;
+                let done = complete(state, model, prompt(instruction, &chunk)).await;
+                Ok::<_, ApiError>((i, done.map_err(|e| upstream_error(model, e))?))
+            })
+            .buffer_unordered((cfg.concurrency as usize).max(1));
+        let mut completed = 0;
+        while let Some(result) = pending.next().await {
+            let (i, (summary, generated)) = result?;
+            summaries[i] = summary;
+            tokens += generated;
+            completed += 1;
+            progress(Pass { stage, completed, total });
+        }
+        let combined = summaries.join("\n\n");
+        // A model that does not shorten its input would never converge
+        if combined.chars().count() >= input.chars().count() {
+            return Ok((prompt(REDUCE_PROMPT, &combined), tokens));
+        }
+        (instruction, stage, input) = (REDUCE_PROMPT, "reduce", combined);
+    }
+}
+
+pub fn upstream_error(model: &str, e: ModelError) -> ApiError {
+    tracing::error!(error = %e, model, "summarization pass failed");
+    ApiError::Internal
+}
+
+/// Open the final summary stream, holding `permit` until it ends
+pub async fn final_stream(state: &AppState, model: &str, messages: Vec<ChatMessage>, permit: Permit) -> ApiResult<ChatStream> {
+    let stream = state
+        .provider
+        .chat_stream(ChatRequest { model: model.into(), messages, ..Default::default() })
+        .await
+        .map_err(|e| upstream_error(model, e))?;
+    let stream = ds_model::with_terminal_frame(stream, model).map(move |item| {
+        let _ = &permit;
+        item
+    });
+    Ok(state.redactor.filter(Box::pin(stream)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_prefers_larger_boundaries() {
+        let text = "First paragraph here.\n\nSecond one. It has two sentences.\n\nThird.";
+        assert_eq!(split(text, 1000), [text]);
+        assert_eq!(
+            split(text, 40),
+            ["First paragraph here.\n\n", "Second one. It has two sentences.\n\n", "Third."]
+        );
+        assert_eq!(split(text, 25), ["First paragraph here.\n\n", "Second one. ", "It has two sentences.\n\n", "Third."]);
+    }
+
+    #[test]
+    fn test_split_never_exceeds_the_limit() {
+        let text = "ünïcödé ".repeat(50) + &"x".repeat(95);
+        let chunks = split(&text, 30);
+        assert!(chunks.iter().all(|c| c.chars().count() <= 30), "{chunks:?}");
+        assert_eq!(chunks.concat(), text);
+        assert!(split("   ", 10).is_empty());
+    }
+}
@@ -0,0 +1,91 @@
+//! Per-org settings: each org's [`TenantOverrides`] applied on top of the
+//! deployment's config for requests made in its name.
+//!
+//! The resulting configs are cached per instance, dropped when the org's
+//! overrides change here, and re-read after `TENANT_CACHE_SECS` otherwise.
+
+use crate::{auth_middleware::AuthUser, state::AppState};
+use dashmap::DashMap;
+use ds_core::{
+    config::{AppConfig, TenantOverrides},
+    error::{ApiError, ApiResult},
+};
+use ds_types::{OrgId, UserId};
+use sqlx::PgPool;
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+/// Effective configs by org, with when they were read
+#[derive(Default)]
+pub struct TenantCache {
+    entries: DashMap<OrgId, (Instant, Arc<AppConfig>)>,
+}
+
+impl TenantCache {
+    fn get(&self, org_id: OrgId, ttl: Duration) -> Option<Arc<AppConfig>> {
+        let entry = self.entries.get(&org_id)?;
+        (entry.0.elapsed() < ttl).then(|| entry.1.clone())
+    }
+
+    pub fn invalidate(&self, org_id: OrgId) {
+        self.entries.remove(&org_id);
+    }
+}
+
+/// The config requests made in `org_id`'s name run under; the
+/// deployment's own for requests outside any org
+pub async fn config(state: &AppState, org_id: Option<OrgId>) -> sqlx::Result<Arc<AppConfig>> {
+    let Some(org_id) = org_id else { return Ok(state.cfg.clone()) };
+    let ttl = Duration::from_secs(state.cfg.tenants.cache_secs);
+    if let Some(cfg) = state.tenants.get(org_id, ttl) {
+        return Ok(cfg);
+    }
+    let overrides = overrides(&state.db, org_id).await?;
+    let cfg = match overrides == TenantOverrides::default() {
+        true => state.cfg.clone(),
+        false => Arc::new(state.cfg.with_overrides(&overrides)),
+    };
+    state.tenants.entries.insert(org_id, (Instant::now(), cfg.clone()));
+    Ok(cfg)
+}
+
+/// [`config`] for the caller's org
+pub async fn for_user(state: &AppState, user: &AuthUser) -> ApiResult<Arc<AppConfig>> {
+    config(state, user.org_id).await.map_err(|e| {
+        tracing::error!(error = %e, org_id = ?user.org_id, "org settings lookup failed");
+        ApiError::Internal
+    })
+}
+
+/// The org's overrides; none set is the default
+pub async fn overrides(db: &PgPool, org_id: OrgId) -> sqlx::Result<TenantOverrides> {
+    let stored: Option<String> = sqlx::query_scalar("SELECT overrides::text FROM org_settings WHERE org_id = $1")
+        .bind(org_id)
+        .fetch_optional(db)
+        .await?;
+    Ok(stored.and_then(|json| serde_json::from_str(&json).ok()).unwrap_or_default())
+}
+
+/// Replace the org's overrides and drop its cached config
+pub async fn set_overrides(
+    state: &AppState,
+    org_id: OrgId,
+    overrides: &TenantOverrides,
+    by: UserId,
+) -> sqlx::Result<()> {
+    let json = serde_json::to_string(overrides).unwrap_or_else(|_| "{}".into());
+    sqlx::query(
+        "INSERT INTO org_settings (org_id, overrides, updated_by) VALUES ($1, $2::jsonb, $3) \
+         ON CONFLICT (org_id) DO UPDATE SET overrides = EXCLUDED.overrides, \
+         updated_by = EXCLUDED.updated_by, updated_at = NOW()",
+    )
+    .bind(org_id)
+    .bind(json)
+    .bind(by)
+    .execute(&state.db)
+    .await?;
+    state.tenants.invalidate(org_id);
+    Ok(())
+}
@@ -0,0 +1,301 @@
+//! Outbound HTTP for server-side tools.
+//!
+//! A tool reaches the network only through [`ToolEgress`], which admits a
+//! URL when its host is listed in `tools.allowed_hosts` or every address it
+//! resolves to lies in `tools.allowed_cidrs`; with neither set nothing is
+//! allowed. Redirects are followed here so each hop is admitted the same
+//! way, and a host admitted by its addresses is connected to at exactly
+//! those addresses, so a second lookup cannot swap them.
+
+use crate::{egress, metrics::Metrics};
+use ds_core::config::{AppConfig, EgressSection};
+use futures_util::StreamExt;
+use ipnet::IpNet;
+use reqwest::{header, redirect::Policy, Method, StatusCode, Url};
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
+/// Tool HTTP calls by tool and result (`allowed`, `denied`, `error`)
+pub const EGRESS: &str = "deepersensor_tool_egress_total";
+
+/// Redirect hops followed before giving up
+const MAX_REDIRECTS: usize = 5;
+
+#[derive(Debug, thiserror::Error)]
+pub enum EgressError {
+    #[error("egress denied: {0}")]
+    Denied(String),
+    #[error("response exceeds {0} bytes")]
+    TooLarge(u64),
+    #[error("request timed out")]
+    Timeout,
+    #[error("request failed: {0}")]
+    Request(String),
+}
+
+/// A tool's HTTP response, read in full
+#[derive(Debug)]
+pub struct Fetched {
+    pub status: u16,
+    pub content_type: Option<String>,
+    pub body: Vec<u8>,
+}
+
+pub struct ToolEgress {
+    /// Lowercased; `*.` prefixed entries match subdomains
+    hosts: Vec<String>,
+    cidrs: Vec<IpNet>,
+    max_response_bytes: u64,
+    timeout: Duration,
+    egress: EgressSection,
+    /// For hosts allowed by name; `None` if it could not be built, which
+    /// denies them
+    client: Option<reqwest::Client>,
+}
+
+/// How an admitted URL is connected to
+enum Route {
+    /// Through the egress proxies like any upstream call
+    Shared,
+    /// Directly, to the addresses that were checked
+    Pinned(String, Vec<SocketAddr>),
+}
+
+impl ToolEgress {
+    pub fn from_config(cfg: &AppConfig) -> Self {
+        let t = &cfg.tools;
+        let list = |s: &str| s.split(',').map(str::trim).filter(|v| !v.is_empty()).map(str::to_string).collect::<Vec<_>>();
+        let hosts = list(&t.allowed_hosts).into_iter().map(|h| h.trim_end_matches('.').to_ascii_lowercase()).collect();
+        let cidrs = list(&t.allowed_cidrs)
+            .into_iter()
+            .filter_map(|c| {
+                // A bare address allows just itself
+                let parsed = c.parse::<IpNet>().or_else(|_| c.parse::<IpAddr>().map(IpNet::from));
+                parsed.map_err(|_| tracing::warn!(cidr = %c, "invalid TOOLS_ALLOWED_CIDRS entry; skipped")).ok()
+            })
+            .collect();
+        let client = egress::client_builder(&cfg.egress, None)
+            .and_then(|b| Ok(b.redirect(Policy::none()).build()?))
+            .map_err(|e| tracing::error!(error = %e, "tool egress client unavailable; named hosts will be denied"))
+            .ok();
+        Self {
+            hosts,
+            cidrs,
+            max_response_bytes: t.max_response_bytes,
+            timeout: Duration::from_secs(t.timeout_secs),
+            egress: cfg.egress.clone(),
+            client,
+        }
+    }
+
+    fn host_allowed(&self, host: &str) -> bool {
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        self.hosts.iter().any(|pattern| match pattern.strip_prefix("*.") {
+            Some(domain) => host.strip_suffix(domain).is_some_and(|sub| sub.len() > 1 && sub.ends_with('.')),
+            None => *pattern == host,
+        })
+    }
+
+    fn ip_allowed(&self, ip: IpAddr) -> bool {
+        self.cidrs.iter().any(|net| net.contains(&ip))
+    }
+
+    /// How to reach `url`, or why it may not be reached
+    async fn admit(&self, url: &Url) -> Result<Route, String> {
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(format!("scheme {} not allowed", url.scheme()));
+        }
+        let host = url.host_str().ok_or("URL has no host")?;
+        if self.host_allowed(host) {
+            return Ok(Route::Shared);
+        }
+        if let Ok(ip) = host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+            return if self.ip_allowed(ip) { Ok(Route::Shared) } else { Err(format!("{ip} not in allowed CIDRs")) };
+        }
+        if self.cidrs.is_empty() {
+            return Err(format!("host {host} not allowed"));
+        }
+        let port = url.port_or_known_default().unwrap_or(80);
+        let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, port))
+            .await
+            .map_err(|e| format!("host {host} did not resolve: {e}"))?
+            .collect();
+        if addrs.is_empty() {
+            return Err(format!("host {host} did not resolve"));
+        }
+        // Every address, so the connection cannot land on an unchecked one
+        if let Some(outside) = addrs.iter().find(|a| !self.ip_allowed(a.ip())) {
+            return Err(format!("host {host} resolves to {} outside allowed CIDRs", outside.ip()));
+        }
+        Ok(Route::Pinned(host.to_string(), addrs))
+    }
+
+    fn client(&self, route: &Route) -> Result<reqwest::Client, EgressError> {
+        match route {
+            Route::Shared => self.client.clone().ok_or_else(|| EgressError::Denied("egress client unavailable".into())),
+            // No proxy: it would resolve the host again itself
+            Route::Pinned(host, addrs) => egress::client_builder(&self.egress, None)
+                .and_then(|b| Ok(b.no_proxy().redirect(Policy::none()).resolve_to_addrs(host, addrs).build()?))
+                .map_err(|e| EgressError::Request(e.to_string())),
+        }
+    }
+
+    /// Make `tool`'s request if policy allows it, following redirects and
+    /// reading at most `tools.max_response_bytes` within `tools.timeout_secs`.
+    /// A `body` is sent as JSON.
+    pub async fn fetch(
+        &self,
+        metrics: &Metrics,
+        tool: &str,
+        method: Method,
+        url: &str,
+        body: Option<Vec<u8>>,
+    ) -> Result<Fetched, EgressError> {
+        let result = tokio::time::timeout(self.timeout, self.follow(tool, method, url, body))
+            .await
+            .unwrap_or(Err(EgressError::Timeout));
+        let outcome = match &result {
+            Ok(_) => "allowed",
+            Err(EgressError::Denied(_)) => "denied",
+            Err(_) => "error",
+        };
+        metrics.incr(EGRESS, &[("tool", tool), ("result", outcome)]);
+        result
+    }
+
+    async fn follow(
+        &self,
+        tool: &str,
+        mut method: Method,
+        url: &str,
+        mut body: Option<Vec<u8>>,
+    ) -> Result<Fetched, EgressError> {
+        let mut url = Url::parse(url).map_err(|e| EgressError::Denied(format!("invalid URL: {e}")))?;
+        for _ in 0..=MAX_REDIRECTS {
+            let route = self.admit(&url).await.map_err(|reason| {
+                tracing::warn!(tool, url = %url, reason = %reason, "audit.tool.egress_denied");
+                EgressError::Denied(reason)
+            })?;
+            let mut request = self.client(&route)?.request(method.clone(), url.clone());
+            if let Some(body) = &body {
+                request = request.header(header::CONTENT_TYPE, "application/json").body(body.clone());
+            }
+            let resp = request.send().await.map_err(request_error)?;
+            let location = resp.headers().get(header::LOCATION).and_then(|v| v.to_str().ok());
+            match location {
+                Some(location) if resp.status().is_redirection() => {
+                    url = url.join(location).map_err(|e| EgressError::Request(format!("invalid redirect: {e}")))?;
+                    // As browsers do: only 307 and 308 repeat the request as made
+                    let keeps_method = matches!(resp.status(), StatusCode::TEMPORARY_REDIRECT | StatusCode::PERMANENT_REDIRECT);
+                    if !keeps_method && method != Method::HEAD {
+                        method = Method::GET;
+                        body = None;
+                    }
+                }
+                _ => return self.read(resp).await,
+            }
+        }
+        Err(EgressError::Request(format!("more than {MAX_REDIRECTS} redirects")))
+    }
+
+    async fn read(&self, resp: reqwest::Response) -> Result<Fetched, EgressError> {
+        let max = self.max_response_bytes;
+        if resp.content_length().is_some_and(|len| len > max) {
+            return Err(EgressError::TooLarge(max));
+        }
+        let status = resp.status().as_u16();
+        let content_type = resp.headers().get(header::CONTENT_TYPE).and_then(|v| v.to_str().ok()).map(str::to_string);
+        let mut body = Vec::new();
+        let mut stream = resp.bytes_stream();
+        // Checked per chunk, since the length header may be absent or wrong
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk.map_err(request_error)?;
+            if (body.len() + chunk.len()) as u64 > max {
+                return Err(EgressError::TooLarge(max));
+            }
+            body.extend_from_slice(&chunk);
+        }
+        Ok(Fetched { status, content_type, body })
+    }
+}
+
+fn request_error(e: reqwest::Error) -> EgressError {
+    if e.is_timeout() { EgressError::Timeout } else { EgressError::Request(e.to_string()) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{response::Redirect, routing::get, Router};
+
+    fn policy(hosts: &str, cidrs: &str, max_response_bytes: u64) -> ToolEgress {
+        let mut cfg = AppConfig::load().expect("default config");
+        cfg.tools.allowed_hosts = hosts.into();
+        cfg.tools.allowed_cidrs = cidrs.into();
+        cfg.tools.max_response_bytes = max_response_bytes;
+        ToolEgress::from_config(&cfg)
+    }
+
+    async fn serve() -> SocketAddr {
+        let app = Router::new()
+            .route("/ok", get(|| async { "hello" }))
+            .route("/big", get(|| async { "x".repeat(4096) }))
+            .route("/hop", get(|| async { Redirect::temporary("/ok") }))
+            .route("/away", get(|| async { Redirect::temporary("http://10.255.255.1/ok") }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        addr
+    }
+
+    #[test]
+    fn test_host_patterns() {
+        let egress = policy("api.example.com, *.docs.example.org", "", 1024);
+        assert!(egress.host_allowed("api.example.com"));
+        assert!(egress.host_allowed("API.Example.com."));
+        assert!(egress.host_allowed("v2.docs.example.org"));
+        assert!(!egress.host_allowed("docs.example.org"));
+        assert!(!egress.host_allowed("evildocs.example.org"));
+        assert!(!egress.host_allowed("api.example.com.evil.net"));
+    }
+
+    #[tokio::test]
+    async fn test_fetch_within_allowed_cidr() {
+        let addr = serve().await;
+        let metrics = Metrics::default();
+        let egress = policy("", "127.0.0.0/8", 1024);
+        let fetched = egress.fetch(&metrics, "http", Method::GET, &format!("http://{addr}/hop"), None).await.unwrap();
+        assert_eq!(fetched.status, 200);
+        assert_eq!(fetched.body, b"hello");
+        assert_eq!(metrics.sum_by(EGRESS, "result").get("allowed"), Some(&1));
+    }
+
+    #[tokio::test]
+    async fn test_fetch_denials() {
+        let addr = serve().await;
+        let metrics = Metrics::default();
+        let closed = policy("", "", 1024);
+        let denied = closed.fetch(&metrics, "http", Method::GET, &format!("http://{addr}/ok"), None).await;
+        assert!(matches!(denied, Err(EgressError::Denied(_))));
+
+        let egress = policy("", "127.0.0.0/8", 1024);
+        let denied = egress.fetch(&metrics, "http", Method::GET, "http://example.com/", None).await;
+        assert!(matches!(denied, Err(EgressError::Denied(_))), "{denied:?}");
+        let denied = egress.fetch(&metrics, "http", Method::GET, "file:///etc/passwd", None).await;
+        assert!(matches!(denied, Err(EgressError::Denied(_))));
+        // Each redirect hop is admitted on its own
+        let denied = egress.fetch(&metrics, "http", Method::GET, &format!("http://{addr}/away"), None).await;
+        assert!(matches!(denied, Err(EgressError::Denied(_))));
+        assert_eq!(metrics.sum_by(EGRESS, "result").get("denied"), Some(&4));
+    }
+
+    #[tokio::test]
+    async fn test_fetch_caps_response_size() {
+        let addr = serve().await;
+        let egress = policy("", "127.0.0.1", 1024);
+        let fetched = egress.fetch(&Metrics::default(), "http", Method::GET, &format!("http://{addr}/big"), None).await;
+        assert!(matches!(fetched, Err(EgressError::TooLarge(1024))));
+    }
+}
@@ -0,0 +1,566 @@
+//! Server-side tools the model may call during a chat.
+//!
+//! A chat names the tools it wants in `tools`; each must be registered and
+//! allowed by the caller's scopes. When the model asks for calls they run
+//! here, concurrently, each within its timeout and under its tool's
+//! concurrency limit, and the results go back to the model as `tool`
+//! messages until it answers without calling anything or
+//! `tools.max_rounds` is reached. Built-ins are enabled with
+//! `tools.enabled`; deployments add their own with [`Tools::with`] on
+//! `AppState::tools`.
+
+use crate::{
+    auth_middleware::AuthUser,
+    retrieval::{self, Retrieval, Search},
+    state::AppState,
+};
+use async_trait::async_trait;
+use ds_auth::scope;
+use ds_core::{
+    config::{AppConfig, ToolsSection},
+    error::{ApiError, ApiResult, FieldError},
+};
+use ds_model::{ChatChunk, ChatMessage, ChatRequest, ChatStream, ToolCall, ToolSpec, Usage, FINISH_TOOL_CALLS};
+use ds_types::{CollectionId, UserId};
+use futures_util::{future::join_all, StreamExt};
+use serde_json::{json, Value};
+use std::{sync::Arc, time::Duration};
+use tokio::sync::Semaphore;
+
+/// Tool calls by tool and result (`ok`, `error`, `timeout`, `unknown`)
+pub const CALLS: &str = "deepersensor_tool_calls_total";
+
+pub type ToolResult = Result<Value, Box<dyn std::error::Error + Send + Sync>>;
+
+#[async_trait]
+pub trait Tool: Send + Sync {
+    /// Name the model calls the tool by
+    fn name(&self) -> &'static str;
+
+    /// What the tool does, told to the model
+    fn description(&self) -> &'static str;
+
+    /// JSON Schema of the arguments
+    fn parameters(&self) -> Value;
+
+    /// Scope a credential needs for its chats to offer this tool
+    fn scope(&self) -> &'static str;
+
+    async fn call(&self, state: &AppState, user_id: UserId, args: Value) -> ToolResult;
+}
+
+/// A tool with its limits
+struct Registered {
+    tool: Arc<dyn Tool>,
+    timeout: Duration,
+    /// Calls that may run at once, across every chat
+    slots: Semaphore,
+}
+
+/// Every tool chats may ask for
+#[derive(Clone, Default)]
+pub struct Tools(Vec<Arc<Registered>>);
+
+impl Tools {
+    /// Built-ins named in `tools.enabled`; unknown names are skipped
+    pub fn from_config(cfg: &AppConfig) -> Self {
+        let mut tools = Self::default();
+        for name in cfg.tools.enabled.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+            tools = match name {
+                "http_fetch" => tools.with(HttpFetch, &cfg.tools),
+                "calculator" => tools.with(Calculator, &cfg.tools),
+                "rag_search" => tools.with(RagSearch, &cfg.tools),
+                other => {
+                    tracing::warn!(tool = other, "unknown tool; skipped");
+                    tools
+                }
+            };
+        }
+        tools
+    }
+
+    /// Register `tool` with its limits from `tools.limits`
+    pub fn with(mut self, tool: impl Tool + 'static, cfg: &ToolsSection) -> Self {
+        let (timeout, concurrency) = cfg.limits_for(tool.name());
+        self.0.push(Arc::new(Registered {
+            tool: Arc::new(tool),
+            timeout,
+            slots: Semaphore::new(concurrency as usize),
+        }));
+        self
+    }
+
+    /// The tools named by a chat, refusing unknown names (422) and tools
+    /// the caller's scopes do not allow (403)
+    pub fn select(&self, user: &AuthUser, names: &[String]) -> ApiResult<Toolset> {
+        let mut selected = Toolset::default();
+        let mut unknown = Vec::new();
+        for (i, name) in names.iter().enumerate() {
+            let Some(registered) = self.0.iter().find(|r| r.tool.name() == name) else {
+                unknown.push(FieldError {
+                    field: format!("tools[{i}]"),
+                    code: "unknown_tool".into(),
+                    message: format!("no tool named {name}"),
+                });
+                continue;
+            };
+            if !user.has_scope(registered.tool.scope()) {
+                tracing::warn!(user_id = %user.user_id, tool = %name, "tool not allowed for credential");
+                return Err(ApiError::Forbidden);
+            }
+            if !selected.0.iter().any(|r| Arc::ptr_eq(r, registered)) {
+                selected.0.push(registered.clone());
+            }
+        }
+        if unknown.is_empty() { Ok(selected) } else { Err(ApiError::Validation(unknown)) }
+    }
+}
+
+/// The tools one chat may call
+#[derive(Clone, Default)]
+pub struct Toolset(Vec<Arc<Registered>>);
+
+impl Toolset {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn names(&self) -> Vec<&'static str> {
+        self.0.iter().map(|r| r.tool.name()).collect()
+    }
+
+    /// Declarations sent to the model
+    pub fn specs(&self) -> Vec<ToolSpec> {
+        self.0
+            .iter()
+            .map(|r| ToolSpec {
+                name: r.tool.name().into(),
+                description: r.tool.description().into(),
+                parameters: r.tool.parameters(),
+            })
+            .collect()
+    }
+
+    /// Run `call` and render its result, or its failure, for the model
+    pub async fn call(&self, state: &AppState, user_id: UserId, call: &ToolCall) -> Called {
+        let Some(registered) = self.0.iter().find(|r| r.tool.name() == call.name) else {
+            state.metrics.incr(CALLS, &[("tool", "unknown"), ("result", "unknown")]);
+            let content = json!({ "error": format!("no tool named {}", call.name) }).to_string();
+            return Called { outcome: "unknown", content };
+        };
+        let name = registered.tool.name();
+        let started = std::time::Instant::now();
+        let run = async {
+            // Closed only on drop, so acquiring cannot fail
+            let _slot = registered.slots.acquire().await;
+            registered.tool.call(state, user_id, call.arguments.clone()).await
+        };
+        let (outcome, content) = match tokio::time::timeout(registered.timeout, run).await {
+            Ok(Ok(value)) => ("ok", value.to_string()),
+            Ok(Err(e)) => {
+                tracing::warn!(error = %e, tool = name, user_id = %user_id, "tool call failed");
+                ("error", json!({ "error": e.to_string() }).to_string())
+            }
+            Err(_) => ("timeout", json!({ "error": "tool call timed out" }).to_string()),
+        };
+        state.metrics.incr(CALLS, &[("tool", name), ("result", outcome)]);
+        tracing::info!(tool = name, user_id = %user_id, result = outcome, ms = started.elapsed().as_millis() as u64, "tool call");
+        Called { outcome, content: truncate(content, state.config().tools.max_result_chars as usize) }
+    }
+}
+
+/// A finished tool call
+pub struct Called {
+    /// `ok`, `error`, `timeout`, or `unknown`, as counted in [`CALLS`]
+    pub outcome: &'static str,
+    /// The result, or `{"error": ...}`, as handed to the model
+    pub content: String,
+}
+
+/// At most `max` characters of `text`
+fn truncate(mut text: String, max: usize) -> String {
+    if let Some((cut, _)) = text.char_indices().nth(max) {
+        text.truncate(cut);
+    }
+    text
+}
+
+/// Continue `first`, the stream for `request`, through tool calls: each
+/// round's calls are answered and the model asked again, so the caller
+/// sees one stream of content (and the calls made) ending in one `done`
+/// frame, which carries the usage of every round.
+///
+/// A round that ends without a `done` frame ends the stream, leaving the
+/// terminal frame to [`ds_model::with_terminal_frame`].
+pub fn chat_loop(state: AppState, user_id: UserId, mut request: ChatRequest, first: ChatStream, tools: Toolset) -> ChatStream {
+    Box::pin(async_stream::try_stream! {
+        let max_rounds = state.config().tools.max_rounds;
+        let mut stream = first;
+        let mut round = 0;
+        let mut usage = None;
+        loop {
+            let (mut reply, mut calls) = (String::new(), Vec::new());
+            let mut done = None;
+            while let Some(chunk) = stream.next().await {
+                let chunk = chunk?;
+                reply.push_str(&chunk.content);
+                calls.extend(chunk.tool_calls.iter().cloned());
+                if chunk.done {
+                    done = Some(chunk);
+                    break;
+                }
+                yield chunk;
+            }
+            let Some(mut done) = done else { break };
+            if let Some(counted) = done.usage.take() {
+                *usage.get_or_insert_with(Usage::default) += counted;
+            }
+            done.usage = usage;
+            if calls.is_empty() {
+                yield done;
+                break;
+            }
+            if round == max_rounds {
+                done.finish_reason = Some(FINISH_TOOL_CALLS.to_string());
+                yield done;
+                break;
+            }
+            round += 1;
+            if !done.content.is_empty() || !done.tool_calls.is_empty() {
+                // Whatever arrived with this round's `done` frame, which is not the last
+                yield ChatChunk { done: false, finish_reason: None, usage: None, ..done };
+            }
+            let results = join_all(calls.iter().map(|call| tools.call(&state, user_id, call))).await;
+            request.messages.push(ChatMessage { role: "assistant".into(), content: reply, tool_calls: calls });
+            for Called { content, .. } in results {
+                request.messages.push(ChatMessage { role: "tool".into(), content, ..Default::default() });
+            }
+            stream = state.provider.chat_stream(request.clone()).await?;
+        }
+    })
+}
+
+/// `GET` a URL through the tool egress policy
+pub struct HttpFetch;
+
+#[async_trait]
+impl Tool for HttpFetch {
+    fn name(&self) -> &'static str {
+        "http_fetch"
+    }
+
+    fn description(&self) -> &'static str {
+        "Fetch a web page or API response by URL. Returns the HTTP status, content type, and body text."
+    }
+
+    fn parameters(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": { "url": { "type": "string", "description": "Absolute http or https URL" } },
+            "required": ["url"],
+        })
+    }
+
+    fn scope(&self) -> &'static str {
+        scope::TOOLS_HTTP_FETCH
+    }
+
+    async fn call(&self, state: &AppState, _user_id: UserId, args: Value) -> ToolResult {
+        let url = args.get("url").and_then(Value::as_str).ok_or("url is required")?;
+        let fetched = state.tool_egress.fetch(&state.metrics, self.name(), reqwest::Method::GET, url, None).await?;
+        let text = fetched.content_type.as_deref().is_none_or(|ct| {
+            ct.starts_with("text/") || ct.contains("json") || ct.contains("xml") || ct.contains("javascript")
+        });
+        let body = match text {
+            true => String::from_utf8_lossy(&fetched.body).into_owned(),
+            false => format!("[{} bytes of binary content omitted]", fetched.body.len()),
+        };
+        Ok(json!({ "status": fetched.status, "content_type": fetched.content_type, "body": body }))
+    }
+}
+
+/// Arithmetic on numbers: `+ - * / % ^`, parentheses, and `sqrt`, `abs`,
+/// `ln`, `log10`, `exp`, `sin`, `cos`, `tan`, `pi`, `e`
+pub struct Calculator;
+
+#[async_trait]
+impl Tool for Calculator {
+    fn name(&self) -> &'static str {
+        "calculator"
+    }
+
+    fn description(&self) -> &'static str {
+        "Evaluate an arithmetic expression, e.g. \"(3.5 + 4) * 2^10 / sqrt(2)\". \
+         Supports + - * / % ^, parentheses, sqrt, abs, ln, log10, exp, sin, cos, tan, pi, and e."
+    }
+
+    fn parameters(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": { "expression": { "type": "string" } },
+            "required": ["expression"],
+        })
+    }
+
+    fn scope(&self) -> &'static str {
+        scope::TOOLS_CALCULATOR
+    }
+
+    async fn call(&self, _state: &AppState, _user_id: UserId, args: Value) -> ToolResult {
+        let expression = args.get("expression").and_then(Value::as_str).ok_or("expression is required")?;
+        Ok(json!({ "result": evaluate(expression)? }))
+    }
+}
+
+/// Longest expression the calculator parses
+const MAX_EXPRESSION_CHARS: usize = 1000;
+/// Deepest nesting of parentheses and unary operators
+const MAX_DEPTH: usize = 64;
+
+/// Evaluate an arithmetic expression
+pub fn evaluate(expression: &str) -> Result<f64, String> {
+    if expression.chars().count() > MAX_EXPRESSION_CHARS {
+        return Err(format!("expression exceeds {MAX_EXPRESSION_CHARS} characters"));
+    }
+    let mut parser = Parser { input: expression.as_bytes(), pos: 0, depth: 0 };
+    let value = parser.sum()?;
+    parser.skip_spaces();
+    if parser.pos < parser.input.len() {
+        return Err(format!("unexpected input at position {}", parser.pos));
+    }
+    if !value.is_finite() {
+        return Err("result is not a finite number".into());
+    }
+    Ok(value)
+}
+
+/// Recursive descent over `sum := product (('+'|'-') product)*`,
+/// `product := unary (('*'|'/'|'%') unary)*`, `unary := ('-'|'+') unary | power`,
+/// `power := atom ('^' unary)?`
+struct Parser<'a> {
+    input: &'a [u8],
+    pos: usize,
+    depth: usize,
+}
+
+impl Parser<'_> {
+    fn skip_spaces(&mut self) {
+        while self.input.get(self.pos).is_some_and(u8::is_ascii_whitespace) {
+            self.pos += 1;
+        }
+    }
+
+    /// The next non-space byte, consumed if it is one of `ops`
+    fn eat(&mut self, ops: &[u8]) -> Option<u8> {
+        self.skip_spaces();
+        let op = *self.input.get(self.pos)?;
+        ops.contains(&op).then(|| {
+            self.pos += 1;
+            op
+        })
+    }
+
+    fn nested<T>(&mut self, parse: impl FnOnce(&mut Self) -> Result<T, String>) -> Result<T, String> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return Err("expression nested too deeply".into());
+        }
+        let result = parse(self);
+        self.depth -= 1;
+        result
+    }
+
+    fn sum(&mut self) -> Result<f64, String> {
+        let mut value = self.product()?;
+        while let Some(op) = self.eat(b"+-") {
+            let rhs = self.product()?;
+            value = if op == b'+' { value + rhs } else { value - rhs };
+        }
+        Ok(value)
+    }
+
+    fn product(&mut self) -> Result<f64, String> {
+        let mut value = self.unary()?;
+        while let Some(op) = self.eat(b"*/%") {
+            let rhs = self.unary()?;
+            if op != b'*' && rhs == 0.0 {
+                return Err("division by zero".into());
+            }
+            value = match op {
+                b'*' => value * rhs,
+                b'/' => value / rhs,
+                _ => value % rhs,
+            };
+        }
+        Ok(value)
+    }
+
+    fn unary(&mut self) -> Result<f64, String> {
+        if self.eat(b"-").is_some() {
+            return self.nested(Self::unary).map(|v| -v);
+        }
+        if self.eat(b"+").is_some() {
+            return self.nested(Self::unary);
+        }
+        self.power()
+    }
+
+    /// Binds tighter than a leading minus (-2^2 = -4) and associates to
+    /// the right (2^3^2 = 2^9)
+    fn power(&mut self) -> Result<f64, String> {
+        let base = self.atom()?;
+        if self.eat(b"^").is_some() {
+            let exponent = self.nested(Self::unary)?;
+            return Ok(base.powf(exponent));
+        }
+        Ok(base)
+    }
+
+    fn atom(&mut self) -> Result<f64, String> {
+        if self.eat(b"(").is_some() {
+            let value = self.nested(Self::sum)?;
+            return match self.eat(b")") {
+                Some(_) => Ok(value),
+                None => Err("missing closing parenthesis".into()),
+            };
+        }
+        self.skip_spaces();
+        let start = self.pos;
+        let rest = &self.input[start..];
+        if rest.first().is_some_and(u8::is_ascii_alphabetic) {
+            let len = rest.iter().take_while(|b| b.is_ascii_alphanumeric()).count();
+            self.pos += len;
+            let name = std::str::from_utf8(&rest[..len]).unwrap_or_default();
+            return match name {
+                "pi" => Ok(std::f64::consts::PI),
+                "e" => Ok(std::f64::consts::E),
+                _ => {
+                    let apply: fn(f64) -> f64 = match name {
+                        "sqrt" => f64::sqrt,
+                        "abs" => f64::abs,
+                        "ln" => f64::ln,
+                        "log10" => f64::log10,
+                        "exp" => f64::exp,
+                        "sin" => f64::sin,
+                        "cos" => f64::cos,
+                        "tan" => f64::tan,
+                        _ => return Err(format!("unknown function {name}")),
+                    };
+                    if self.eat(b"(").is_none() {
+                        return Err(format!("{name} needs parentheses"));
+                    }
+                    let arg = self.nested(Self::sum)?;
+                    self.eat(b")").ok_or("missing closing parenthesis")?;
+                    Ok(apply(arg))
+                }
+            };
+        }
+        let mut len = rest.iter().take_while(|b| b.is_ascii_digit() || **b == b'.').count();
+        // Exponent, as in 1.5e-3
+        if len > 0 && matches!(rest.get(len), Some(b'e' | b'E')) {
+            let sign = usize::from(matches!(rest.get(len + 1), Some(b'+' | b'-')));
+            let digits = rest[len + 1 + sign..].iter().take_while(|b| b.is_ascii_digit()).count();
+            if digits > 0 {
+                len += 1 + sign + digits;
+            }
+        }
+        let number = std::str::from_utf8(&rest[..len]).unwrap_or_default();
+        self.pos += len;
+        number.parse().map_err(|_| match number {
+            "" => format!("expected a number at position {start}"),
+            _ => format!("invalid number {number}"),
+        })
+    }
+}
+
+/// Most chunks `rag_search` returns
+const RAG_MAX_RESULTS: u64 = 10;
+
+/// Search the caller's documents, or collections it may read
+pub struct RagSearch;
+
+#[async_trait]
+impl Tool for RagSearch {
+    fn name(&self) -> &'static str {
+        "rag_search"
+    }
+
+    fn description(&self) -> &'static str {
+        "Search the user's uploaded documents, or the document collections given by id. Returns the passages \
+         most relevant to the query, best first."
+    }
+
+    fn parameters(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "query": { "type": "string" },
+                "limit": { "type": "integer", "minimum": 1, "maximum": RAG_MAX_RESULTS, "default": 4 },
+                "retrieval": { "type": "string", "enum": Retrieval::NAMES },
+                "rerank": { "type": "boolean", "default": false },
+                "collection_ids": { "type": "array", "items": { "type": "string", "format": "uuid" }, "maxItems": 16 },
+            },
+            "required": ["query"],
+        })
+    }
+
+    fn scope(&self) -> &'static str {
+        scope::TOOLS_RAG_SEARCH
+    }
+
+    async fn call(&self, state: &AppState, user_id: UserId, args: Value) -> ToolResult {
+        let query = args.get("query").and_then(Value::as_str).ok_or("query is required")?;
+        let limit = args.get("limit").and_then(Value::as_u64).unwrap_or(4).clamp(1, RAG_MAX_RESULTS);
+        let retrieval = match args.get("retrieval").and_then(Value::as_str) {
+            Some(name) => name.parse()?,
+            None => retrieval::default_retrieval(state),
+        };
+        let rerank = args.get("rerank").and_then(Value::as_bool).unwrap_or(false);
+        let collections: Vec<CollectionId> = match args.get("collection_ids") {
+            Some(ids) => serde_json::from_value(ids.clone()).map_err(|_| "collection_ids must be an array of ids")?,
+            None => Vec::new(),
+        };
+        if collections.len() > 16 {
+            return Err("at most 16 collection_ids".into());
+        }
+        let search = Search { retrieval, rerank, limit: limit as usize };
+        let found = retrieval::search(state, user_id, query, &retrieval::scope(collections), &search).await?;
+        Ok(json!({ "results": found.hits }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_evaluate() {
+        let close = |expression: &str, expected: f64| {
+            let value = evaluate(expression).unwrap();
+            assert!((value - expected).abs() < 1e-9, "{expression} = {value}");
+        };
+        close("1 + 2 * 3", 7.0);
+        close("(1 + 2) * 3", 9.0);
+        close("2^3^2", 512.0);
+        close("-2^2", -4.0);
+        close("10 % 4 - -1", 3.0);
+        close("2^-1", 0.5);
+        close("1.5e3 / 3", 500.0);
+        close("sqrt(16) + abs(-2) * pi", 4.0 + 2.0 * std::f64::consts::PI);
+        close("ln(e)", 1.0);
+        assert!(evaluate("1 / 0").is_err());
+        assert!(evaluate("(1 + 2").is_err());
+        assert!(evaluate("1 +").is_err());
+        assert!(evaluate("2 3").is_err());
+        assert!(evaluate("system(1)").is_err());
+        assert!(evaluate("sqrt(-1)").is_err());
+        assert!(evaluate(&"(".repeat(100)).is_err());
+        assert!(evaluate(&"-".repeat(100)).is_err());
+    }
+
+    #[test]
+    fn test_truncate_counts_characters() {
+        assert_eq!(truncate("héllo".into(), 2), "hé");
+        assert_eq!(truncate("hi".into(), 5), "hi");
+    }
+}
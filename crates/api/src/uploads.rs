@@ -0,0 +1,313 @@
+//! Resumable uploads, for files too large to send in one request.
+//!
+//! An upload declares its size up front ([`create`]), then receives parts
+//! in order: each part names the offset it starts at, which must be where
+//! the last one ended, so a client that lost a response asks for the
+//! upload ([`get`]) and carries on from `offset`. Parts are written to the
+//! blob store as they arrive. [`complete`] scans and hashes the assembled
+//! contents, stores them as a file through [`blobs`] and drops the parts;
+//! [`abort`] drops them without a file. Uploads left open past
+//! `files.upload_ttl_secs` are discarded by the `upload_expiry` job.
+//!
+//! The declared size counts against the owner's storage quota from the
+//! moment the upload is created, so a quota cannot be overrun by parts.
+//! Uploads a user opens at once are created one at a time, each counting
+//! the others, so neither can it be overrun by opening many together.
+
+use crate::{
+    blobs,
+    db_metrics::{self, Timed},
+    files::{self, NewFile},
+    state::AppState,
+};
+use axum::body::Bytes;
+use chrono::{DateTime, Utc};
+use ds_core::error::{ApiError, ApiResult};
+use ds_store::{BlobStore, ByteStream};
+use ds_types::{FileId, UploadId, UserId};
+use futures_util::{stream, StreamExt, TryStreamExt};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use sqlx::{Connection, PgConnection, PgPool, Row};
+use std::sync::Arc;
+
+/// Uploads by how they ended (`completed`, `aborted`, `expired`)
+pub const UPLOADS: &str = "deepersensor_resumable_uploads_total";
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Upload {
+    pub id: UploadId,
+    pub content_type: String,
+    /// Declared size of the whole file
+    pub bytes: u64,
+    /// Bytes received so far, where the next part starts
+    pub offset: u64,
+    pub expires_at: DateTime<Utc>,
+}
+
+fn from_row(row: &sqlx::postgres::PgRow) -> sqlx::Result<Upload> {
+    Ok(Upload {
+        id: row.try_get("id")?,
+        content_type: row.try_get("content_type")?,
+        bytes: row.try_get::<i64, _>("bytes")? as u64,
+        offset: row.try_get::<i64, _>("received")? as u64,
+        expires_at: row.try_get("expires_at")?,
+    })
+}
+
+fn internal(e: impl std::fmt::Display) -> ApiError {
+    tracing::error!(error = %e, "resumable upload failed");
+    ApiError::Internal
+}
+
+/// Parts live in the blob store, so uploads need one
+fn store(state: &AppState) -> ApiResult<Arc<dyn BlobStore>> {
+    state
+        .blobs
+        .clone()
+        .ok_or_else(|| ApiError::BadRequest("resumable uploads need a blob store (FILES_STORE)".into()))
+}
+
+/// Open an upload of `bytes` bytes for `user`
+pub async fn create(state: &AppState, user: UserId, content_type: &str, bytes: u64) -> ApiResult<Upload> {
+    store(state)?;
+    let cfg = &state.config().files;
+    if bytes > cfg.max_resumable_bytes {
+        return Err(ApiError::PayloadTooLarge(format!("file exceeds {} bytes", cfg.max_resumable_bytes)));
+    }
+    let mut conn = db_metrics::acquire(&state.metrics, &state.db).await.map_err(internal)?;
+    let mut tx = conn.begin().await.map_err(internal)?;
+    sqlx::query("SELECT pg_advisory_xact_lock(hashtext($1::TEXT))")
+        .bind(user)
+        .execute(&mut *tx)
+        .await
+        .map_err(internal)?;
+    files::check_quota(state, &mut *tx, user, bytes).await?;
+    let row = sqlx::query(
+        "INSERT INTO uploads (id, user_id, content_type, bytes, expires_at) \
+         VALUES ($1, $2, $3, $4, NOW() + make_interval(secs => $5)) \
+         RETURNING id, content_type, bytes, received, expires_at",
+    )
+    .bind(UploadId::generate())
+    .bind(user)
+    .bind(content_type)
+    .bind(bytes as i64)
+    .bind(cfg.upload_ttl_secs as f64)
+    .fetch_one(&mut *tx)
+    .timed(&state.metrics, "uploads.create")
+    .await
+    .map_err(internal)?;
+    tx.commit().await.map_err(internal)?;
+    from_row(&row).map_err(internal)
+}
+
+/// One of `user`'s open uploads
+pub async fn get(db: &PgPool, user: UserId, id: UploadId) -> sqlx::Result<Option<Upload>> {
+    let row = sqlx::query(
+        "SELECT id, content_type, bytes, received, expires_at FROM uploads \
+         WHERE id = $1 AND user_id = $2 AND expires_at > NOW()",
+    )
+    .bind(id)
+    .bind(user)
+    .fetch_optional(db)
+    .await?;
+    row.as_ref().map(from_row).transpose()
+}
+
+async fn open(state: &AppState, user: UserId, id: UploadId) -> ApiResult<Upload> {
+    get(&state.db, user, id)
+        .timed(&state.metrics, "uploads.get")
+        .await
+        .map_err(internal)?
+        .ok_or(ApiError::NotFound)
+}
+
+/// Add `data` at `offset`, which must be where the upload has got to
+pub async fn append(state: &AppState, user: UserId, id: UploadId, offset: u64, data: Bytes) -> ApiResult<Upload> {
+    let store = store(state)?;
+    let upload = open(state, user, id).await?;
+    if offset != upload.offset {
+        return Err(ApiError::Conflict(format!("upload is at offset {}", upload.offset)));
+    }
+    if data.is_empty() {
+        return Err(ApiError::BadRequest("part body is empty".into()));
+    }
+    let len = data.len() as u64;
+    if offset + len > upload.bytes {
+        return Err(ApiError::BadRequest(format!("part ends past the declared {} bytes", upload.bytes)));
+    }
+    // Unique per attempt, so a part racing another at the same offset
+    // never overwrites the one that was kept
+    let key = format!("uploads/{id}/{offset}-{}", uuid::Uuid::new_v4().simple());
+    store.put(&key, ds_store::once(data)).await.map_err(internal)?;
+    let kept = async {
+        let mut tx = state.db.begin().await?;
+        let moved = sqlx::query("UPDATE uploads SET received = received + $3 WHERE id = $1 AND received = $2")
+            .bind(id)
+            .bind(offset as i64)
+            .bind(len as i64)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+        if moved == 0 {
+            return sqlx::Result::Ok(false);
+        }
+        sqlx::query("INSERT INTO upload_parts (upload_id, offset_bytes, bytes, blob_key) VALUES ($1, $2, $3, $4)")
+            .bind(id)
+            .bind(offset as i64)
+            .bind(len as i64)
+            .bind(&key)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(true)
+    }
+    .timed(&state.metrics, "uploads.append")
+    .await;
+    if !matches!(kept, Ok(true)) {
+        let _ = store.delete(&key).await;
+    }
+    match kept {
+        Ok(true) => Ok(Upload { offset: offset + len, ..upload }),
+        Ok(false) => Err(ApiError::Conflict("another part was received at this offset".into())),
+        Err(e) => Err(internal(e)),
+    }
+}
+
+/// Keys of an upload's parts, in order
+async fn parts(db: &PgPool, id: UploadId) -> sqlx::Result<Vec<String>> {
+    sqlx::query_scalar("SELECT blob_key FROM upload_parts WHERE upload_id = $1 ORDER BY offset_bytes")
+        .bind(id)
+        .fetch_all(db)
+        .await
+}
+
+/// The parts one after another, each fetched once the last is read
+fn contents(store: Arc<dyn BlobStore>, keys: Vec<String>) -> ByteStream {
+    stream::iter(keys)
+        .then(move |key| {
+            let store = store.clone();
+            async move {
+                store
+                    .get(&key)
+                    .await
+                    .map_err(std::io::Error::other)?
+                    .ok_or_else(|| std::io::Error::other(format!("upload part {key} is missing")))
+            }
+        })
+        .try_flatten()
+        .boxed()
+}
+
+/// Turn a fully received upload into a file, scanned like any other
+pub async fn complete(state: &AppState, user: UserId, id: UploadId) -> ApiResult<(FileId, Upload)> {
+    let store = store(state)?;
+    let upload = open(state, user, id).await?;
+    if upload.offset != upload.bytes {
+        return Err(ApiError::Conflict(format!("upload has {} of {} bytes", upload.offset, upload.bytes)));
+    }
+    let keys = parts(&state.db, id).await.map_err(internal)?;
+    let mut hasher = Sha256::new();
+    let mut data = contents(store.clone(), keys.clone());
+    while let Some(chunk) = data.next().await {
+        hasher.update(chunk.map_err(internal)?);
+    }
+    let hash = hex::encode(hasher.finalize());
+    let screened = files::screen(state, user, contents(store.clone(), keys.clone())).await?;
+    let file = async {
+        let mut tx = state.db.begin().await?;
+        // Taken by one completion only; a concurrent one finds it gone
+        let claimed = sqlx::query("DELETE FROM uploads WHERE id = $1").bind(id).execute(&mut *tx).await?;
+        if claimed.rows_affected() == 0 {
+            return anyhow::Ok(None);
+        }
+        let data = contents(store.clone(), keys.clone());
+        let blob = blobs::acquire_stream(&mut tx, store.as_ref(), &state.metrics, hash, upload.bytes, data).await?;
+        let file = NewFile {
+            user,
+            content_type: &upload.content_type,
+            bytes: upload.bytes,
+            data: None,
+            blob: Some(&blob),
+            status: screened.status,
+            scan_result: screened.result.as_deref(),
+        };
+        let file_id = files::insert_row(&mut tx, &state.metrics, &file).await?;
+        tx.commit().await?;
+        Ok(Some(file_id))
+    }
+    .await
+    .map_err(files::store_error)?;
+    let Some(file_id) = file else { return Err(ApiError::NotFound) };
+    state.metrics.incr(UPLOADS, &[("result", "completed")]);
+    discard_parts(store.as_ref(), &keys).await;
+    screened.accept(file_id, user).map(|file_id| (file_id, upload))
+}
+
+/// Drop one of `user`'s open uploads and its parts; false when there is no
+/// such upload
+pub async fn abort(state: &AppState, user: UserId, id: UploadId) -> ApiResult<bool> {
+    let store = store(state)?;
+    let removed = async {
+        let mut tx = state.db.begin().await?;
+        let keys = remove(&mut tx, id, Some(user)).await?;
+        tx.commit().await?;
+        sqlx::Result::Ok(keys)
+    }
+    .timed(&state.metrics, "uploads.abort")
+    .await
+    .map_err(internal)?;
+    let Some(keys) = removed else { return Ok(false) };
+    state.metrics.incr(UPLOADS, &[("result", "aborted")]);
+    discard_parts(store.as_ref(), &keys).await;
+    Ok(true)
+}
+
+/// Discard uploads open past their expiry, returning how many
+pub async fn expire(state: &AppState, store: &dyn BlobStore) -> anyhow::Result<u64> {
+    let mut expired = 0;
+    loop {
+        let mut tx = state.db.begin().await?;
+        let id: Option<UploadId> =
+            sqlx::query_scalar("SELECT id FROM uploads WHERE expires_at <= NOW() LIMIT 1 FOR UPDATE SKIP LOCKED")
+                .fetch_optional(&mut *tx)
+                .await?;
+        let Some(id) = id else { break };
+        let keys = remove(&mut tx, id, None).await?.unwrap_or_default();
+        tx.commit().await?;
+        discard_parts(store, &keys).await;
+        state.metrics.incr(UPLOADS, &[("result", "expired")]);
+        expired += 1;
+    }
+    Ok(expired)
+}
+
+/// Delete an upload (`user`'s, when given) in the caller's transaction,
+/// returning the keys of its parts; `None` when there is no such upload.
+/// The row is locked first, so no part is added meanwhile.
+async fn remove(conn: &mut PgConnection, id: UploadId, user: Option<UserId>) -> sqlx::Result<Option<Vec<String>>> {
+    let found: Option<UploadId> =
+        sqlx::query_scalar("SELECT id FROM uploads WHERE id = $1 AND ($2::uuid IS NULL OR user_id = $2) FOR UPDATE")
+            .bind(id)
+            .bind(user)
+            .fetch_optional(&mut *conn)
+            .await?;
+    if found.is_none() {
+        return Ok(None);
+    }
+    let keys = sqlx::query_scalar("DELETE FROM upload_parts WHERE upload_id = $1 RETURNING blob_key")
+        .bind(id)
+        .fetch_all(&mut *conn)
+        .await?;
+    sqlx::query("DELETE FROM uploads WHERE id = $1").bind(id).execute(&mut *conn).await?;
+    Ok(Some(keys))
+}
+
+/// Best effort: parts left behind only cost space
+async fn discard_parts(store: &dyn BlobStore, keys: &[String]) {
+    for key in keys {
+        if let Err(e) = store.delete(key).await {
+            tracing::warn!(error = %e, key, "deleting upload part failed");
+        }
+    }
+}
@@ -53,18 +53,19 @@ pub fn validate_password(password: &str) -> ApiResult<()> {
     Ok(())
 }
 
-/// Validate chat message content
-pub fn validate_message_content(content: &str, max_length: usize) -> ApiResult<()> {
+/// Validate chat message content; the limit counts characters (Unicode
+/// scalar values), not bytes
+pub fn validate_message_content(content: &str, max_chars: usize) -> ApiResult<()> {
     if content.is_empty() {
         return Err(ApiError::Unprocessable(
             "message content cannot be empty".into(),
         ));
     }
 
-    if content.len() > max_length {
+    if content.chars().count() > max_chars {
         return Err(ApiError::Unprocessable(format!(
             "message too long (max {} characters)",
-            max_length
+            max_chars
         )));
     }
 
@@ -81,10 +82,12 @@ pub fn validate_model_name(model: &str) -> ApiResult<()> {
         return Err(ApiError::Unprocessable("model name too long".into()));
     }
 
-    // Only allow alphanumeric, dash, underscore, colon (for Ollama model naming)
+    // Only allow alphanumeric, dash, underscore, colon (for Ollama model naming),
+    // and slashes between parts: an Ollama namespace or a provider prefix
     if !model
         .chars()
-        .all(|c| c.is_alphanumeric() || c == '-' || c == '_' || c == ':' || c == '.')
+        .all(|c| c.is_alphanumeric() || c == '-' || c == '_' || c == ':' || c == '.' || c == '/')
+        || model.split('/').any(|part| part.is_empty() || part == "." || part == "..")
     {
         return Err(ApiError::Unprocessable(
             "invalid characters in model name".into(),
@@ -94,6 +97,59 @@ pub fn validate_model_name(model: &str) -> ApiResult<()> {
     Ok(())
 }
 
+/// Bounds on the generation options a chat may ask for
+pub const MAX_TEMPERATURE: f64 = 2.0;
+pub const MAX_TOP_K: u32 = 1000;
+pub const MAX_GENERATION_TOKENS: u32 = 65536;
+/// OpenAI's limit, so requests stay valid for every provider
+pub const MAX_STOP_SEQUENCES: usize = 4;
+pub const MAX_STOP_SEQUENCE_CHARS: usize = 100;
+
+/// Validate a sampling temperature
+pub fn validate_temperature(temperature: f64) -> ApiResult<()> {
+    if !(0.0..=MAX_TEMPERATURE).contains(&temperature) {
+        return Err(ApiError::Unprocessable(format!("temperature must be between 0 and {MAX_TEMPERATURE}")));
+    }
+    Ok(())
+}
+
+/// Validate a nucleus sampling probability mass
+pub fn validate_top_p(top_p: f64) -> ApiResult<()> {
+    if !(top_p > 0.0 && top_p <= 1.0) {
+        return Err(ApiError::Unprocessable("top_p must be above 0 and at most 1".into()));
+    }
+    Ok(())
+}
+
+/// Validate how many candidate tokens are sampled from
+pub fn validate_top_k(top_k: u32) -> ApiResult<()> {
+    if !(1..=MAX_TOP_K).contains(&top_k) {
+        return Err(ApiError::Unprocessable(format!("top_k must be between 1 and {MAX_TOP_K}")));
+    }
+    Ok(())
+}
+
+/// Validate a cap on reply length
+pub fn validate_max_tokens(max_tokens: u32) -> ApiResult<()> {
+    if !(1..=MAX_GENERATION_TOKENS).contains(&max_tokens) {
+        return Err(ApiError::Unprocessable(format!("max_tokens must be between 1 and {MAX_GENERATION_TOKENS}")));
+    }
+    Ok(())
+}
+
+/// Validate stop sequences; lengths count characters
+pub fn validate_stop_sequences(stop: &[String]) -> ApiResult<()> {
+    if stop.len() > MAX_STOP_SEQUENCES {
+        return Err(ApiError::Unprocessable(format!("at most {MAX_STOP_SEQUENCES} stop sequences")));
+    }
+    if stop.iter().any(|s| s.is_empty() || s.chars().count() > MAX_STOP_SEQUENCE_CHARS) {
+        return Err(ApiError::Unprocessable(format!(
+            "stop sequences must be between 1 and {MAX_STOP_SEQUENCE_CHARS} characters"
+        )));
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -127,18 +183,103 @@ mod tests {
         assert!(validate_password(&"a1".repeat(100)).is_err()); // too long
     }
 
+    #[test]
+    fn test_validate_message_content_counts_characters() {
+        // 3 bytes per CJK character, 4 per emoji; both count as one
+        assert!(validate_message_content(&"你".repeat(10), 10).is_ok());
+        assert!(validate_message_content(&"你".repeat(11), 10).is_err());
+        assert!(validate_message_content(&"😀".repeat(10), 10).is_ok());
+        assert!(validate_message_content(&"😀".repeat(11), 10).is_err());
+        assert!(validate_message_content("", 10).is_err());
+    }
+
     #[test]
     fn test_validate_model_name_valid() {
         assert!(validate_model_name("llama3.2").is_ok());
         assert!(validate_model_name("mistral:7b").is_ok());
         assert!(validate_model_name("model_name-v2").is_ok());
+        assert!(validate_model_name("openai/gpt-4o").is_ok());
     }
 
     #[test]
     fn test_validate_model_name_invalid() {
         assert!(validate_model_name("").is_err());
         assert!(validate_model_name("   ").is_err());
-        assert!(validate_model_name("model/with/slash").is_err());
+        assert!(validate_model_name("/llama3").is_err());
+        assert!(validate_model_name("openai//gpt-4o").is_err());
+        assert!(validate_model_name("../llama3").is_err());
         assert!(validate_model_name(&"a".repeat(150)).is_err());
     }
+
+    #[test]
+    fn test_generation_option_bounds() {
+        assert!(validate_temperature(0.0).is_ok());
+        assert!(validate_temperature(2.0).is_ok());
+        assert!(validate_temperature(-0.1).is_err());
+        assert!(validate_temperature(f64::NAN).is_err());
+        assert!(validate_top_p(1.0).is_ok());
+        assert!(validate_top_p(0.0).is_err());
+        assert!(validate_top_k(0).is_err());
+        assert!(validate_top_k(40).is_ok());
+        assert!(validate_max_tokens(0).is_err());
+        assert!(validate_max_tokens(MAX_GENERATION_TOKENS + 1).is_err());
+        assert!(validate_stop_sequences(&["\n\n".into(), "User:".into()]).is_ok());
+        assert!(validate_stop_sequences(&[String::new()]).is_err());
+        assert!(validate_stop_sequences(&vec!["x".to_string(); 5]).is_err());
+        assert!(validate_stop_sequences(&["你".repeat(MAX_STOP_SEQUENCE_CHARS)]).is_ok());
+    }
+
+    #[cfg(feature = "proptest")]
+    mod props {
+        use super::*;
+        use proptest::prelude::*;
+
+        fn model_char_ok(c: char) -> bool {
+            c.is_alphanumeric() || matches!(c, '-' | '_' | ':' | '.' | '/')
+        }
+
+        proptest! {
+            #[test]
+            fn prop_well_formed_emails_accepted(
+                email in "[a-zA-Z0-9._%+-]{1,64}@[a-zA-Z0-9-]{1,63}\\.[a-zA-Z]{2,10}"
+            ) {
+                prop_assert!(validate_email(&email).is_ok());
+            }
+
+            #[test]
+            fn prop_accepted_emails_are_bounded(email in any::<String>()) {
+                if validate_email(&email).is_ok() {
+                    prop_assert!(email.len() <= 190);
+                    prop_assert_eq!(email.matches('@').count(), 1);
+                    prop_assert!(!email.chars().any(char::is_whitespace));
+                }
+            }
+
+            #[test]
+            fn prop_model_name_matches_spec(model in any::<String>()) {
+                let expected = !model.trim().is_empty()
+                    && model.len() <= 100
+                    && model.chars().all(model_char_ok)
+                    && model.split('/').all(|part| !matches!(part, "" | "." | ".."));
+                prop_assert_eq!(validate_model_name(&model).is_ok(), expected);
+            }
+
+            #[test]
+            fn prop_model_name_rejects_path_chars(
+                head in "[a-z0-9]{0,20}",
+                sep in "[\\\\ ]",
+                tail in "[a-z0-9]{0,20}",
+            ) {
+                let model = format!("{head}{sep}{tail}");
+                prop_assert!(validate_model_name(&model).is_err());
+            }
+
+            #[test]
+            fn prop_model_name_rejects_empty_path_parts(part in "[a-z0-9]{1,20}") {
+                for model in [format!("/{part}"), format!("{part}/"), format!("{part}//{part}")] {
+                    prop_assert!(validate_model_name(&model).is_err());
+                }
+            }
+        }
+    }
 }
@@ -0,0 +1,167 @@
+//! API versions and route deprecation.
+//!
+//! Versions are path prefixes. `/v2` routers hold only the routes whose
+//! behaviour changes; any other `/v2/...` path is served by its `/v1`
+//! handler, so a version costs only what it breaks. Routes listed in
+//! `API_DEPRECATED_ROUTES` answer with `Deprecation` (RFC 9745), `Sunset`
+//! (RFC 8594), and, with `API_DEPRECATION_LINK`, a `Link` to the migration
+//! notes. Requests are counted by version, and deprecated ones by route,
+//! to show when a removal is safe.
+
+use crate::{metrics::Metrics, state::AppState};
+use axum::{
+    extract::{Request, State},
+    http::{HeaderValue, StatusCode, Uri},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Router,
+};
+use chrono::{DateTime, NaiveDate, Utc};
+use ds_core::config::AppConfig;
+use std::{
+    convert::Infallible,
+    sync::{Arc, OnceLock},
+};
+use tower::ServiceExt;
+
+/// Every version served, oldest first
+pub const VERSIONS: &[&str] = &["v1", "v2"];
+
+/// Requests by API version
+pub const REQUESTS: &str = "deepersensor_api_requests_total";
+
+/// Requests to deprecated routes, by configured pattern
+pub const DEPRECATED: &str = "deepersensor_deprecated_requests_total";
+
+pub const DEPRECATION_HEADER: &str = "deprecation";
+pub const SUNSET_HEADER: &str = "sunset";
+
+/// One `API_DEPRECATED_ROUTES` entry
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Deprecation {
+    pub pattern: String,
+    pub since: DateTime<Utc>,
+    pub sunset: Option<DateTime<Utc>>,
+}
+
+impl Deprecation {
+    /// `pattern=since[:sunset]`, dates as `YYYY-MM-DD` (UTC midnight)
+    fn parse(entry: &str) -> Option<Self> {
+        let (pattern, dates) = entry.rsplit_once('=')?;
+        let date = |s: &str| {
+            let day = NaiveDate::parse_from_str(s.trim(), "%Y-%m-%d").ok()?;
+            Some(day.and_time(Default::default()).and_utc())
+        };
+        let (since, sunset) = match dates.split_once(':') {
+            Some((since, sunset)) => (date(since)?, Some(date(sunset)?)),
+            None => (date(dates)?, None),
+        };
+        Some(Self { pattern: pattern.trim().to_string(), since, sunset })
+    }
+}
+
+pub struct Versions {
+    deprecations: Vec<Deprecation>,
+    link: Option<HeaderValue>,
+    metrics: Arc<Metrics>,
+}
+
+impl Versions {
+    pub fn from_config(cfg: &AppConfig, metrics: Arc<Metrics>) -> Self {
+        let deprecations = cfg
+            .http
+            .deprecated_routes
+            .split(',')
+            .map(str::trim)
+            .filter(|r| !r.is_empty())
+            .filter_map(|entry| {
+                let parsed = Deprecation::parse(entry);
+                if parsed.is_none() {
+                    tracing::warn!(entry, "ignoring API_DEPRECATED_ROUTES entry; expected pattern=since[:sunset]");
+                }
+                parsed
+            })
+            .collect();
+        let link = Some(cfg.http.deprecation_link.trim())
+            .filter(|l| !l.is_empty())
+            .and_then(|l| HeaderValue::from_str(&format!("<{l}>; rel=\"deprecation\"")).ok());
+        Self { deprecations, link, metrics }
+    }
+
+    /// The first entry matching `path`
+    pub fn deprecation(&self, path: &str) -> Option<&Deprecation> {
+        self.deprecations.iter().find(|d| crate::policy::glob(&d.pattern, path))
+    }
+}
+
+/// The version a path is under, or `none` for unversioned routes
+pub fn version_of(path: &str) -> &'static str {
+    let prefix = path.trim_start_matches('/').split('/').next().unwrap_or_default();
+    VERSIONS.iter().find(|v| **v == prefix).copied().unwrap_or("none")
+}
+
+/// Count the request by version and mark deprecated routes
+pub async fn track(State(versions): State<Arc<Versions>>, req: Request, next: Next) -> Response {
+    let path = req.uri().path();
+    let version = version_of(path);
+    let deprecation = versions.deprecation(path).cloned();
+    let mut response = next.run(req).await;
+    versions.metrics.incr(REQUESTS, &[("version", version)]);
+    let Some(deprecation) = deprecation else {
+        return response;
+    };
+    versions.metrics.incr(DEPRECATED, &[("route", &deprecation.pattern)]);
+    let headers = response.headers_mut();
+    // Structured date: `@` and unix seconds
+    let since = format!("@{}", deprecation.since.timestamp());
+    headers.insert(DEPRECATION_HEADER, HeaderValue::from_str(&since).expect("digits are ASCII"));
+    if let Some(sunset) = deprecation.sunset {
+        let date = sunset.format("%a, %d %b %Y %H:%M:%S GMT").to_string();
+        headers.insert(SUNSET_HEADER, HeaderValue::from_str(&date).expect("formatted date is ASCII"));
+    }
+    if let Some(link) = &versions.link {
+        headers.append(axum::http::header::LINK, link.clone());
+    }
+    response
+}
+
+/// Fallback for paths no route matched: `/v2/...` is handed to the `/v1`
+/// router under the `/v1` path, anything else is `404`
+pub async fn fall_back(
+    v1: Router<AppState>,
+    served: Arc<OnceLock<Router>>,
+    state: AppState,
+    mut req: Request,
+) -> Response {
+    let Some(rest) = req.uri().path().strip_prefix("/v2/") else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let path = match req.uri().query() {
+        Some(query) => format!("/v1/{rest}?{query}"),
+        None => format!("/v1/{rest}"),
+    };
+    let Ok(uri) = path.parse::<Uri>() else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    *req.uri_mut() = uri;
+    let router = served.get_or_init(|| v1.with_state(state)).clone();
+    let Ok::<_, Infallible>(response) = router.oneshot(req).await;
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deprecation_entries() {
+        let d = Deprecation::parse("/v1/summarize*=2026-10-01:2027-04-01").unwrap();
+        assert_eq!(d.pattern, "/v1/summarize*");
+        assert_eq!(d.since.timestamp(), 1_790_812_800);
+        assert_eq!(d.sunset.map(|s| s.to_rfc3339()).as_deref(), Some("2027-04-01T00:00:00+00:00"));
+        assert_eq!(Deprecation::parse("/v1/x = 2026-10-01").unwrap().sunset, None);
+        assert_eq!(Deprecation::parse("/v1/x=soon"), None);
+        assert_eq!(Deprecation::parse("/v1/x"), None);
+        assert_eq!((version_of("/v2/chat"), version_of("/health"), version_of("/v3/chat")), ("v2", "none", "none"));
+    }
+}
@@ -1,13 +1,15 @@
 use argon2::{
-    password_hash::{PasswordHash, PasswordVerifier, SaltString},
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordVerifier, SaltString},
     Argon2, PasswordHasher,
 };
-use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
-use rand::rngs::OsRng;
-use serde::{Deserialize, Serialize};
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use ds_types::UserId;
+use std::time::Duration;
 use thiserror::Error;
 
+pub mod scope;
+mod token;
+pub use token::{Actor, Claims, ClaimsBuilder, TokenIssuer, TYP_ACCESS, TYP_REFRESH};
+
 #[derive(Debug, Error)]
 pub enum AuthError {
     #[error("hash error")]
@@ -18,6 +20,10 @@ pub enum AuthError {
     TokenEncode,
     #[error("token decode error")]
     TokenDecode,
+    #[error("required claim missing")]
+    MissingClaim,
+    #[error("unknown scope: {0}")]
+    UnknownScope(String),
 }
 
 // Tuned Argon2id parameters (balanced for security vs. latency; adjust after load tests)
@@ -77,54 +83,33 @@ pub fn verify_password(raw: &str, hash: &str) -> Result<(bool, bool), AuthError>
     Ok((true, needs_rehash))
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct Claims {
-    pub sub: String,
-    pub exp: u64,
-    pub iss: String,
-    pub iat: u64,
-    pub typ: String,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub email: Option<String>,
+/// Argon2id hash (current parameters) of a password nobody knows; verified
+/// against when the user does not exist so that path costs the same
+const DUMMY_HASH: &str =
+    "$argon2id$v=19$m=19456,t=2,p=1$RzhhtF7iuNdlwQO8Ls7LqA$gAv1cQewtV9gFHq2/2ixMNwRuUc7t6WENh7wFIIQmOY";
+
+/// Do the work of [`verify_password`] for a login whose user does not
+/// exist, so response time does not reveal which emails are registered.
+/// Always fails.
+pub fn verify_password_dummy(raw: &str) -> bool {
+    let _ = verify_password(raw, DUMMY_HASH);
+    false
 }
 
+/// Mint an access token with no optional claims; see [`TokenIssuer`] for
+/// email, roles, scopes, org, and session
 pub fn generate_tokens(
-    user_id: &str,
+    user_id: UserId,
     issuer: &str,
     secret: &str,
     access_ttl: Duration,
 ) -> Result<String, AuthError> {
-    let now = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap()
-        .as_secs();
-    let exp = now + access_ttl.as_secs();
-    let claims = Claims {
-        sub: user_id.to_string(),
-        exp,
-        iss: issuer.to_string(),
-        iat: now,
-        typ: "access".into(),
-        email: None, // Can be added during token generation if needed
-    };
-    encode(
-        &Header::new(Algorithm::HS256),
-        &claims,
-        &EncodingKey::from_secret(secret.as_bytes()),
-    )
-    .map_err(|_| AuthError::TokenEncode)
+    let tokens = TokenIssuer::new(issuer, secret, access_ttl, Duration::ZERO);
+    tokens.issue(&tokens.claims(user_id).build())
 }
 
 pub fn verify_jwt(token: &str, secret: &str, issuer: &str) -> Result<Claims, AuthError> {
-    let mut validation = Validation::new(Algorithm::HS256);
-    validation.set_issuer(&[issuer]);
-    let data = decode::<Claims>(
-        token,
-        &DecodingKey::from_secret(secret.as_bytes()),
-        &validation,
-    )
-    .map_err(|_| AuthError::TokenDecode)?;
-    Ok(data.claims)
+    TokenIssuer::new(issuer, secret, Duration::ZERO, Duration::ZERO).verify(token)
 }
 
 pub fn decode_token(token: &str, secret: &str, issuer: &str) -> Result<Claims, AuthError> {
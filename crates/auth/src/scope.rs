@@ -0,0 +1,88 @@
+//! Permission scopes carried by service tokens and API keys.
+//!
+//! A scope is `resource:action`; `resource:*` grants every action on that
+//! resource. Credentials with no scopes at all are interactive sessions and
+//! are not restricted by scope (role checks still apply).
+use crate::AuthError;
+
+pub const CHAT_READ: &str = "chat:read";
+pub const CHAT_WRITE: &str = "chat:write";
+/// Run chats in the interactive priority class (otherwise batch)
+pub const CHAT_INTERACTIVE: &str = "chat:interactive";
+pub const MODELS_READ: &str = "models:read";
+pub const ADMIN_ALL: &str = "admin:*";
+/// Let chats call the `http_fetch` tool
+pub const TOOLS_HTTP_FETCH: &str = "tools:http_fetch";
+/// Let chats call the `calculator` tool
+pub const TOOLS_CALCULATOR: &str = "tools:calculator";
+/// Let chats call the `rag_search` tool
+pub const TOOLS_RAG_SEARCH: &str = "tools:rag_search";
+/// Manage orgs, their members, keys, and settings
+pub const ORGS_ADMIN: &str = "orgs:admin";
+
+/// Every scope a credential may be issued with
+pub const KNOWN: &[&str] = &[
+    CHAT_READ,
+    CHAT_WRITE,
+    CHAT_INTERACTIVE,
+    MODELS_READ,
+    ADMIN_ALL,
+    TOOLS_HTTP_FETCH,
+    TOOLS_CALCULATOR,
+    TOOLS_RAG_SEARCH,
+    ORGS_ADMIN,
+];
+
+/// Does the single scope `granted` cover `required`?
+pub fn grants(granted: &str, required: &str) -> bool {
+    if granted == required {
+        return true;
+    }
+    match (granted.split_once(':'), required.split_once(':')) {
+        (Some((resource, "*")), Some((wanted, _))) => resource == wanted,
+        _ => false,
+    }
+}
+
+/// Does a credential holding `granted` pass a `required` check?
+/// An empty set is an unrestricted session.
+pub fn allows(granted: &[String], required: &str) -> bool {
+    granted.is_empty() || granted.iter().any(|g| grants(g, required))
+}
+
+/// Reject scopes outside [`KNOWN`] (or a `resource:*` of a known resource)
+/// before they are attached to a credential
+pub fn validate(scope: &str) -> Result<(), AuthError> {
+    let known = KNOWN.contains(&scope)
+        || scope
+            .strip_suffix(":*")
+            .is_some_and(|resource| KNOWN.iter().any(|k| k.split(':').next() == Some(resource)));
+    known.then_some(()).ok_or(AuthError::UnknownScope(scope.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wildcards_cover_only_their_resource() {
+        assert!(grants(CHAT_WRITE, CHAT_WRITE));
+        assert!(!grants(CHAT_READ, CHAT_WRITE));
+        assert!(grants("chat:*", CHAT_WRITE));
+        assert!(grants(ADMIN_ALL, ADMIN_ALL));
+        assert!(!grants(ADMIN_ALL, CHAT_READ));
+        assert!(!grants("chat", CHAT_READ));
+        assert!(allows(&[], ADMIN_ALL));
+        assert!(!allows(&[MODELS_READ.to_string()], CHAT_READ));
+    }
+
+    #[test]
+    fn test_validate() {
+        for scope in KNOWN.iter().copied().chain(["chat:*", "models:*", "tools:*", "orgs:*"]) {
+            assert!(validate(scope).is_ok(), "{scope}");
+        }
+        for scope in ["chat", "chat:delete", "billing:*", "*", ""] {
+            assert!(validate(scope).is_err(), "{scope}");
+        }
+    }
+}
@@ -0,0 +1,325 @@
+use crate::AuthError;
+use ds_types::{OrgId, SessionId, TokenId, UserId};
+use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// `typ` of short-lived access tokens
+pub const TYP_ACCESS: &str = "access";
+/// `typ` of refresh tokens, redeemable once at the refresh endpoint
+pub const TYP_REFRESH: &str = "refresh";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Claims {
+    pub sub: UserId,
+    pub exp: u64,
+    pub iss: String,
+    pub iat: u64,
+    pub typ: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub roles: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub scopes: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub org_id: Option<OrgId>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sid: Option<SessionId>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jti: Option<TokenId>,
+    /// Present on impersonation tokens: the admin acting as `sub`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub act: Option<Actor>,
+}
+
+/// The party actually holding a delegated token (RFC 8693 `act`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Actor {
+    pub sub: UserId,
+}
+
+impl Claims {
+    pub fn has_role(&self, role: &str) -> bool {
+        self.roles.iter().any(|r| r == role)
+    }
+
+    /// Holds `scope` itself or a `resource:*` covering it
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.scopes.iter().any(|s| crate::scope::grants(s, scope))
+    }
+
+    pub fn require_role(&self, role: &str) -> Result<(), AuthError> {
+        self.has_role(role).then_some(()).ok_or(AuthError::MissingClaim)
+    }
+
+    pub fn require_scope(&self, scope: &str) -> Result<(), AuthError> {
+        self.has_scope(scope).then_some(()).ok_or(AuthError::MissingClaim)
+    }
+
+    /// The token must be scoped to `org`
+    pub fn require_org(&self, org: OrgId) -> Result<(), AuthError> {
+        (self.org_id == Some(org)).then_some(()).ok_or(AuthError::MissingClaim)
+    }
+
+    pub fn require_typ(&self, typ: &str) -> Result<(), AuthError> {
+        (self.typ == typ).then_some(()).ok_or(AuthError::MissingClaim)
+    }
+}
+
+/// Assembles [`Claims`]; `iat`/`exp` are stamped when [`build`](Self::build)
+/// is called.
+#[derive(Debug, Clone)]
+pub struct ClaimsBuilder {
+    sub: UserId,
+    iss: String,
+    ttl: Duration,
+    typ: String,
+    email: Option<String>,
+    roles: Vec<String>,
+    scopes: Vec<String>,
+    org_id: Option<OrgId>,
+    sid: Option<SessionId>,
+    jti: Option<TokenId>,
+    act: Option<Actor>,
+}
+
+impl ClaimsBuilder {
+    pub fn new(sub: UserId, issuer: impl Into<String>, ttl: Duration) -> Self {
+        Self {
+            sub,
+            iss: issuer.into(),
+            ttl,
+            typ: TYP_ACCESS.into(),
+            email: None,
+            roles: Vec::new(),
+            scopes: Vec::new(),
+            org_id: None,
+            sid: None,
+            jti: None,
+            act: None,
+        }
+    }
+
+    pub fn typ(mut self, typ: impl Into<String>) -> Self {
+        self.typ = typ.into();
+        self
+    }
+
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    pub fn email(mut self, email: impl Into<String>) -> Self {
+        self.email = Some(email.into());
+        self
+    }
+
+    pub fn role(mut self, role: impl Into<String>) -> Self {
+        self.roles.push(role.into());
+        self
+    }
+
+    pub fn roles<I: IntoIterator<Item = S>, S: Into<String>>(mut self, roles: I) -> Self {
+        self.roles.extend(roles.into_iter().map(Into::into));
+        self
+    }
+
+    pub fn scope(mut self, scope: impl Into<String>) -> Self {
+        self.scopes.push(scope.into());
+        self
+    }
+
+    pub fn scopes<I: IntoIterator<Item = S>, S: Into<String>>(mut self, scopes: I) -> Self {
+        self.scopes.extend(scopes.into_iter().map(Into::into));
+        self
+    }
+
+    pub fn org_id(mut self, org_id: OrgId) -> Self {
+        self.org_id = Some(org_id);
+        self
+    }
+
+    pub fn session_id(mut self, sid: SessionId) -> Self {
+        self.sid = Some(sid);
+        self
+    }
+
+    pub fn token_id(mut self, jti: TokenId) -> Self {
+        self.jti = Some(jti);
+        self
+    }
+
+    /// Mark the token as used by `actor` on behalf of `sub`
+    pub fn actor(mut self, actor: UserId) -> Self {
+        self.act = Some(Actor { sub: actor });
+        self
+    }
+
+    pub fn build(self) -> Claims {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        Claims {
+            sub: self.sub,
+            exp: now + self.ttl.as_secs(),
+            iss: self.iss,
+            iat: now,
+            typ: self.typ,
+            email: self.email,
+            roles: self.roles,
+            scopes: self.scopes,
+            org_id: self.org_id,
+            sid: self.sid,
+            jti: self.jti,
+            act: self.act,
+        }
+    }
+}
+
+/// Signs and verifies HS256 tokens for one issuer, keeping the keys
+/// instead of re-deriving them per call.
+#[derive(Clone)]
+pub struct TokenIssuer {
+    issuer: String,
+    access_ttl: Duration,
+    refresh_ttl: Duration,
+    encoding: EncodingKey,
+    decoding: DecodingKey,
+}
+
+impl TokenIssuer {
+    pub fn new(issuer: impl Into<String>, secret: &str, access_ttl: Duration, refresh_ttl: Duration) -> Self {
+        Self {
+            issuer: issuer.into(),
+            access_ttl,
+            refresh_ttl,
+            encoding: EncodingKey::from_secret(secret.as_bytes()),
+            decoding: DecodingKey::from_secret(secret.as_bytes()),
+        }
+    }
+
+    /// Builder for an access token for `sub` from this issuer
+    pub fn claims(&self, sub: UserId) -> ClaimsBuilder {
+        ClaimsBuilder::new(sub, self.issuer.clone(), self.access_ttl)
+    }
+
+    /// Builder for a refresh token of session `sid`; `jti` must be the one
+    /// recorded for the session
+    pub fn refresh_claims(&self, sub: UserId, sid: SessionId, jti: TokenId) -> ClaimsBuilder {
+        ClaimsBuilder::new(sub, self.issuer.clone(), self.refresh_ttl)
+            .typ(TYP_REFRESH)
+            .session_id(sid)
+            .token_id(jti)
+    }
+
+    pub fn refresh_ttl(&self) -> Duration {
+        self.refresh_ttl
+    }
+
+    pub fn issue(&self, claims: &Claims) -> Result<String, AuthError> {
+        encode(&Header::new(Algorithm::HS256), claims, &self.encoding)
+            .map_err(|_| AuthError::TokenEncode)
+    }
+
+    /// Check signature, issuer, and expiry
+    pub fn verify(&self, token: &str) -> Result<Claims, AuthError> {
+        let mut validation = Validation::new(Algorithm::HS256);
+        validation.set_issuer(&[&self.issuer]);
+        decode::<Claims>(token, &self.decoding, &validation)
+            .map(|data| data.claims)
+            .map_err(|_| AuthError::TokenDecode)
+    }
+
+    /// [`verify`](Self::verify) and require an access token
+    pub fn verify_access(&self, token: &str) -> Result<Claims, AuthError> {
+        let claims = self.verify(token)?;
+        claims.require_typ(TYP_ACCESS)?;
+        Ok(claims)
+    }
+
+    /// [`verify`](Self::verify) a refresh token, returning its claims and
+    /// the session/token ids it carries
+    pub fn verify_refresh(&self, token: &str) -> Result<(Claims, SessionId, TokenId), AuthError> {
+        let claims = self.verify(token)?;
+        claims.require_typ(TYP_REFRESH)?;
+        let sid = claims.sid.ok_or(AuthError::MissingClaim)?;
+        let jti = claims.jti.ok_or(AuthError::MissingClaim)?;
+        Ok((claims, sid, jti))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "test_secret_key_at_least_32_characters_long";
+
+    fn issuer() -> TokenIssuer {
+        TokenIssuer::new("deepersensor", SECRET, Duration::from_secs(900), Duration::from_secs(3600))
+    }
+
+    #[test]
+    fn test_builder_roundtrip() {
+        let issuer = issuer();
+        let org = OrgId::generate();
+        let claims = issuer
+            .claims(UserId::generate())
+            .email("a@example.com")
+            .role("admin")
+            .scopes(["chat", "models:read"])
+            .org_id(org)
+            .session_id(SessionId::generate())
+            .actor(UserId::generate())
+            .build();
+        let decoded = issuer.verify_access(&issuer.issue(&claims).unwrap()).unwrap();
+        assert_eq!(decoded, claims);
+        assert_eq!(decoded.exp - decoded.iat, 900);
+        assert!(decoded.require_role("admin").is_ok());
+        assert!(decoded.require_role("owner").is_err());
+        assert!(decoded.require_scope("models:read").is_ok());
+        assert!(decoded.require_org(org).is_ok());
+        assert!(decoded.require_org(OrgId::generate()).is_err());
+    }
+
+    #[test]
+    fn test_minimal_claims_omit_optional_fields() {
+        let issuer = issuer();
+        let claims = issuer.claims(UserId::generate()).build();
+        let json = serde_json::to_value(&claims).unwrap();
+        for key in ["email", "roles", "scopes", "org_id", "sid", "jti", "act"] {
+            assert!(json.get(key).is_none(), "{key} serialized");
+        }
+    }
+
+    #[test]
+    fn test_verify_rejects_foreign_tokens() {
+        let claims = issuer().claims(UserId::generate()).build();
+        let other_issuer = TokenIssuer::new("someone-else", SECRET, Duration::from_secs(900), Duration::ZERO);
+        let token = other_issuer.issue(&ClaimsBuilder::new(claims.sub, "someone-else", Duration::from_secs(900)).build()).unwrap();
+        assert!(issuer().verify(&token).is_err());
+        let other_secret = TokenIssuer::new("deepersensor", "another_secret_key_at_least_32_chars", Duration::from_secs(900), Duration::ZERO);
+        assert!(issuer().verify(&other_secret.issue(&claims).unwrap()).is_err());
+        let refresh = issuer().claims(claims.sub).typ(TYP_REFRESH).build();
+        assert!(issuer().verify_access(&issuer().issue(&refresh).unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_refresh_claims_carry_family() {
+        let issuer = issuer();
+        let (sid, jti) = (SessionId::generate(), TokenId::generate());
+        let claims = issuer.refresh_claims(UserId::generate(), sid, jti).build();
+        assert_eq!(claims.exp - claims.iat, 3600);
+        let token = issuer.issue(&claims).unwrap();
+        let (_, got_sid, got_jti) = issuer.verify_refresh(&token).unwrap();
+        assert_eq!((got_sid, got_jti), (sid, jti));
+        assert!(issuer.verify_access(&token).is_err());
+        // Access tokens and refresh tokens without a family are refused
+        let access = issuer.issue(&issuer.claims(claims.sub).build()).unwrap();
+        assert!(issuer.verify_refresh(&access).is_err());
+        let orphan = issuer.issue(&issuer.claims(claims.sub).typ(TYP_REFRESH).build()).unwrap();
+        assert!(issuer.verify_refresh(&orphan).is_err());
+    }
+}
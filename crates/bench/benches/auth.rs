@@ -0,0 +1,40 @@
+//! Cost of the auth hot paths: Argon2id hashing on signup/login and JWT
+//! verification on every authenticated request.
+use criterion::{criterion_group, criterion_main, Criterion};
+use ds_auth::{generate_tokens, hash_password, verify_jwt, verify_password};
+use ds_types::UserId;
+use std::hint::black_box;
+use std::time::Duration;
+
+const SECRET: &str = "bench_secret_key_at_least_32_characters_long";
+const ISSUER: &str = "deepersensor";
+
+fn bench_password(c: &mut Criterion) {
+    let hash = hash_password("correct horse battery staple").unwrap();
+    let mut group = c.benchmark_group("password");
+    // Argon2id is deliberately slow; keep the sample count modest
+    group.sample_size(10);
+    group.bench_function("hash", |b| {
+        b.iter(|| hash_password(black_box("correct horse battery staple")).unwrap())
+    });
+    group.bench_function("verify", |b| {
+        b.iter(|| verify_password(black_box("correct horse battery staple"), &hash).unwrap())
+    });
+    group.finish();
+}
+
+fn bench_jwt(c: &mut Criterion) {
+    let user = UserId::generate();
+    let token = generate_tokens(user, ISSUER, SECRET, Duration::from_secs(900)).unwrap();
+    let mut group = c.benchmark_group("jwt");
+    group.bench_function("generate", |b| {
+        b.iter(|| generate_tokens(black_box(user), ISSUER, SECRET, Duration::from_secs(900)).unwrap())
+    });
+    group.bench_function("verify", |b| {
+        b.iter(|| verify_jwt(black_box(&token), SECRET, ISSUER).unwrap())
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_password, bench_jwt);
+criterion_main!(benches);
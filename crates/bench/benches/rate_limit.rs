@@ -0,0 +1,34 @@
+//! Per-request overhead of the token-bucket limiter, for a single hot key
+//! and for a keyspace spread across many client IPs.
+use criterion::{criterion_group, criterion_main, Criterion};
+use dashmap::DashMap;
+use ds_core::rate_limit::TokenBucket;
+use std::hint::black_box;
+
+fn bench_rate_limit(c: &mut Criterion) {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let mut group = c.benchmark_group("rate_limit");
+
+    let bucket = TokenBucket::new(u64::MAX / 2, u64::MAX / 2);
+    group.bench_function("single_key", |b| {
+        b.to_async(&rt).iter(|| async { black_box(bucket.allow().await) })
+    });
+
+    let map: DashMap<String, TokenBucket> = DashMap::new();
+    let keys: Vec<String> = (0..10_000).map(|i| format!("10.0.{}.{}", i / 256, i % 256)).collect();
+    let mut i = 0usize;
+    group.bench_function("keyed_10k", |b| {
+        b.to_async(&rt).iter(|| {
+            i = (i + 1) % keys.len();
+            let entry = map
+                .entry(keys[i].clone())
+                .or_insert_with(|| TokenBucket::new(60, 10))
+                .clone();
+            async move { black_box(entry.allow().await) }
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_rate_limit);
+criterion_main!(benches);
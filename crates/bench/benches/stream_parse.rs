@@ -0,0 +1,68 @@
+//! Throughput of the Ollama chat stream hot path for long generations.
+//!
+//! `value_path` reproduces the previous pipeline (lossy String -> Value ->
+//! owned content + cloned model name -> re-serialize) for comparison with the
+//! borrowed `parse_chat_line` path.
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use ds_model::{ndjson::NdjsonDecoder, parse_chat_line};
+use std::hint::black_box;
+use std::sync::Arc;
+
+const TOKENS: usize = 4096;
+
+fn transcript() -> Vec<u8> {
+    let mut out = String::new();
+    for i in 0..TOKENS {
+        out.push_str(&format!(
+            "{{\"model\":\"llama3:8b\",\"created_at\":\"2025-01-01T00:00:00Z\",\"message\":{{\"role\":\"assistant\",\"content\":\"tok{i} \"}},\"done\":false}}\n"
+        ));
+    }
+    out.push_str("{\"model\":\"llama3:8b\",\"message\":{\"role\":\"assistant\",\"content\":\"\"},\"done\":true,\"done_reason\":\"stop\"}\n");
+    out.into_bytes()
+}
+
+fn value_path(bytes: &[u8], model: &str) -> usize {
+    let mut total = 0;
+    for line in bytes.split(|b| *b == b'\n').filter(|l| !l.is_empty()) {
+        let line = String::from_utf8_lossy(line);
+        let v: serde_json::Value = serde_json::from_str(&line).unwrap();
+        let content = v
+            .get("message")
+            .and_then(|m| m.get("content"))
+            .and_then(|c| c.as_str())
+            .unwrap_or("")
+            .to_string();
+        let done = v.get("done").and_then(|d| d.as_bool()).unwrap_or(false);
+        let json = serde_json::json!({"model": model.to_string(), "content": content, "done": done});
+        total += serde_json::to_string(&json).unwrap().len();
+    }
+    total
+}
+
+fn borrowed_path(bytes: &[u8], model: &Arc<str>) -> usize {
+    let mut total = 0;
+    let mut decoder = NdjsonDecoder::new(ds_model::ndjson::MAX_LINE_BYTES);
+    decoder.push(bytes);
+    while let Some(line) = decoder.next_line() {
+        let chunk = parse_chat_line(&line, model).unwrap();
+        total += serde_json::to_vec(&chunk).unwrap().len();
+    }
+    total
+}
+
+fn bench_chat_parse(c: &mut Criterion) {
+    let bytes = transcript();
+    let model: Arc<str> = Arc::from("llama3:8b");
+    let mut group = c.benchmark_group("ollama_chat_stream");
+    group.throughput(Throughput::Bytes(bytes.len() as u64));
+    group.bench_function("value_path", |b| {
+        b.iter(|| value_path(black_box(&bytes), "llama3:8b"))
+    });
+    group.bench_function("borrowed_path", |b| {
+        b.iter(|| borrowed_path(black_box(&bytes), &model))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_chat_parse);
+criterion_main!(benches);
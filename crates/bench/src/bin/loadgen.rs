@@ -0,0 +1,192 @@
+//! Load generator for `/v1/chat/stream`.
+//!
+//! Drives a running API with a fixed number of concurrent SSE clients and
+//! reports time-to-first-chunk and end-to-end latency percentiles.
+//!
+//! ```text
+//! cargo run --release -p ds-bench --bin loadgen -- \
+//!     --url http://localhost:8080 --token "$TOKEN" --concurrency 16 --requests 400
+//! ```
+use anyhow::{bail, Context};
+use ds_model::ChatChunk;
+use futures_util::StreamExt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+struct Args {
+    url: String,
+    token: String,
+    model: String,
+    prompt: String,
+    concurrency: usize,
+    requests: usize,
+}
+
+impl Args {
+    fn parse() -> anyhow::Result<Self> {
+        let mut args = Args {
+            url: "http://localhost:8080".into(),
+            token: std::env::var("LOADGEN_TOKEN").unwrap_or_default(),
+            model: "llama3".into(),
+            prompt: "Write one sentence about load testing.".into(),
+            concurrency: 8,
+            requests: 100,
+        };
+        let mut it = std::env::args().skip(1);
+        while let Some(flag) = it.next() {
+            let mut value = || it.next().with_context(|| format!("missing value for {flag}"));
+            match flag.as_str() {
+                "--url" => args.url = value()?,
+                "--token" => args.token = value()?,
+                "--model" => args.model = value()?,
+                "--prompt" => args.prompt = value()?,
+                "--concurrency" => args.concurrency = value()?.parse()?,
+                "--requests" => args.requests = value()?.parse()?,
+                "-h" | "--help" => {
+                    println!("usage: loadgen [--url URL] [--token JWT] [--model NAME] [--prompt TEXT] [--concurrency N] [--requests N]");
+                    std::process::exit(0);
+                }
+                other => bail!("unknown flag {other}"),
+            }
+        }
+        if args.token.is_empty() {
+            bail!("an access token is required (--token or LOADGEN_TOKEN)");
+        }
+        Ok(args)
+    }
+}
+
+#[derive(Default)]
+struct Samples {
+    first_chunk: Vec<Duration>,
+    total: Vec<Duration>,
+    chunks: u64,
+    errors: u64,
+}
+
+/// Issue one streaming request; returns (time to first chunk, total, chunks)
+async fn run_one(
+    client: &reqwest::Client,
+    args: &Args,
+) -> anyhow::Result<(Duration, Duration, u64)> {
+    let started = Instant::now();
+    let body = serde_json::json!({
+        "model": args.model,
+        "messages": [{"role": "user", "content": args.prompt}],
+    });
+    let resp = client
+        .post(format!("{}/v1/chat/stream", args.url.trim_end_matches('/')))
+        .bearer_auth(&args.token)
+        .json(&body)
+        .send()
+        .await?;
+    if !resp.status().is_success() {
+        bail!("status {}", resp.status());
+    }
+
+    let mut first = None;
+    let mut chunks = 0;
+    let mut buf = String::new();
+    let mut bytes = resp.bytes_stream();
+    while let Some(part) = bytes.next().await {
+        buf.push_str(&String::from_utf8_lossy(&part?));
+        while let Some(end) = buf.find("\n\n") {
+            let frame: String = buf.drain(..end + 2).collect();
+            let mut event = "message";
+            let mut data = String::new();
+            for line in frame.lines() {
+                if let Some(v) = line.strip_prefix("event:") {
+                    event = if v.trim() == "error" { "error" } else { "chunk" };
+                } else if let Some(v) = line.strip_prefix("data:") {
+                    data.push_str(v.trim_start());
+                }
+            }
+            if event == "error" {
+                bail!("stream error: {data}");
+            }
+            if data.is_empty() {
+                continue;
+            }
+            let chunk: ChatChunk = serde_json::from_str(&data)?;
+            first.get_or_insert_with(|| started.elapsed());
+            chunks += 1;
+            if chunk.done {
+                return Ok((first.unwrap_or_default(), started.elapsed(), chunks));
+            }
+        }
+    }
+    bail!("stream ended without a done frame")
+}
+
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let idx = ((sorted.len() as f64 - 1.0) * p).round() as usize;
+    sorted[idx]
+}
+
+fn report(name: &str, samples: &mut [Duration]) {
+    samples.sort();
+    println!(
+        "{name:<12} p50={:>8.1?} p90={:>8.1?} p99={:>8.1?} max={:>8.1?}",
+        percentile(samples, 0.50),
+        percentile(samples, 0.90),
+        percentile(samples, 0.99),
+        samples.last().copied().unwrap_or_default(),
+    );
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let args = Arc::new(Args::parse()?);
+    let client = reqwest::Client::new();
+    let samples = Arc::new(Mutex::new(Samples::default()));
+    let next = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+
+    let started = Instant::now();
+    let workers = (0..args.concurrency.max(1)).map(|_| {
+        let (args, client, samples, next) =
+            (args.clone(), client.clone(), samples.clone(), next.clone());
+        tokio::spawn(async move {
+            while next.fetch_add(1, std::sync::atomic::Ordering::Relaxed) < args.requests {
+                let result = run_one(&client, &args).await;
+                let mut s = samples.lock().await;
+                match result {
+                    Ok((first, total, chunks)) => {
+                        s.first_chunk.push(first);
+                        s.total.push(total);
+                        s.chunks += chunks;
+                    }
+                    Err(e) => {
+                        s.errors += 1;
+                        eprintln!("request failed: {e:#}");
+                    }
+                }
+            }
+        })
+    });
+    for worker in workers.collect::<Vec<_>>() {
+        worker.await?;
+    }
+    let elapsed = started.elapsed();
+
+    let mut s = samples.lock().await;
+    let ok = s.total.len();
+    println!(
+        "requests={} ok={ok} errors={} concurrency={} elapsed={elapsed:.2?}",
+        args.requests, s.errors, args.concurrency
+    );
+    println!(
+        "throughput   {:.1} req/s, {:.1} chunks/s",
+        ok as f64 / elapsed.as_secs_f64(),
+        s.chunks as f64 / elapsed.as_secs_f64()
+    );
+    report("first_chunk", &mut s.first_chunk);
+    report("total", &mut s.total);
+    if s.errors > 0 {
+        std::process::exit(1);
+    }
+    Ok(())
+}
@@ -1,37 +1,183 @@
-use axum::{http::StatusCode, response::{IntoResponse, Response}, Json};
+use crate::i18n::{self, Locale};
+use axum::{
+    http::{header, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
 use serde::Serialize;
 use thiserror::Error;
 
-#[derive(Debug, Error)]
+#[derive(Debug, Clone, Error)]
 pub enum ApiError {
     #[error("Not Found")] NotFound,
     #[error("Unauthorized")] Unauthorized,
     #[error("Forbidden")] Forbidden,
     #[error("Bad Request: {0}")] BadRequest(String),
     #[error("Unprocessable: {0}")] Unprocessable(String),
+    #[error("Conflict: {0}")] Conflict(String),
+    #[error("Validation failed")] Validation(Vec<FieldError>),
+    #[error("Payload Too Large: {0}")] PayloadTooLarge(String),
     #[error("Too Many Requests")] RateLimited,
     #[error("Internal Server Error")] Internal,
+    /// The model provider failed; the detail is safe to show
+    #[error("Bad Gateway: {0}")] BadGateway(String),
+}
+
+/// One rejected request field, reported under `error.fields`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FieldError {
+    pub field: String,
+    pub code: String,
+    pub message: String,
 }
 
 #[derive(Serialize)]
 struct ErrorBody<'a> { error: ErrorObj<'a> }
 #[derive(Serialize)]
-struct ErrorObj<'a> { code: &'a str, message: &'a str }
+struct ErrorObj<'a> {
+    code: &'a str,
+    message: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fields: Option<&'a [FieldError]>,
+}
 
-impl IntoResponse for ApiError {
-    fn into_response(self) -> Response {
-        let (status, code) = match self {
+impl ApiError {
+    /// HTTP status and the stable machine-readable `error.code`
+    pub fn status_and_code(&self) -> (StatusCode, &'static str) {
+        match self {
             ApiError::NotFound => (StatusCode::NOT_FOUND, "not_found"),
             ApiError::Unauthorized => (StatusCode::UNAUTHORIZED, "unauthorized"),
             ApiError::Forbidden => (StatusCode::FORBIDDEN, "forbidden"),
             ApiError::BadRequest(_) => (StatusCode::BAD_REQUEST, "bad_request"),
             ApiError::Unprocessable(_) => (StatusCode::UNPROCESSABLE_ENTITY, "unprocessable"),
+            ApiError::Conflict(_) => (StatusCode::CONFLICT, "conflict"),
+            ApiError::Validation(_) => (StatusCode::UNPROCESSABLE_ENTITY, "validation_failed"),
+            ApiError::PayloadTooLarge(_) => (StatusCode::PAYLOAD_TOO_LARGE, "payload_too_large"),
             ApiError::RateLimited => (StatusCode::TOO_MANY_REQUESTS, "rate_limited"),
             ApiError::Internal => (StatusCode::INTERNAL_SERVER_ERROR, "internal_error"),
+            ApiError::BadGateway(_) => (StatusCode::BAD_GATEWAY, "upstream_error"),
+        }
+    }
+
+    /// `error.message` in `locale`: the catalog title plus any detail
+    pub fn message(&self, locale: Locale) -> String {
+        let (_, code) = self.status_and_code();
+        let Some(title) = i18n::title(code, locale) else {
+            return self.to_string();
+        };
+        match self {
+            ApiError::BadRequest(detail)
+            | ApiError::Unprocessable(detail)
+            | ApiError::Conflict(detail)
+            | ApiError::PayloadTooLarge(detail)
+            | ApiError::BadGateway(detail) => format!("{title}: {detail}"),
+            _ => title.to_string(),
+        }
+    }
+
+    /// Render the JSON error body in `locale`.
+    ///
+    /// The error rides along as a response extension so outer middleware
+    /// can re-render it for the caller's `Accept-Language`.
+    pub fn to_response(&self, locale: Locale) -> Response {
+        let (status, code) = self.status_and_code();
+        let msg = self.message(locale);
+        let fields = match self {
+            ApiError::Validation(fields) => Some(fields.as_slice()),
+            _ => None,
         };
-        let msg = self.to_string();
-        (status, Json(ErrorBody { error: ErrorObj { code, message: &msg } })).into_response()
+        let mut response =
+            (status, Json(ErrorBody { error: ErrorObj { code, message: &msg, fields } })).into_response();
+        response
+            .headers_mut()
+            .insert(header::CONTENT_LANGUAGE, HeaderValue::from_static(locale.tag()));
+        response.extensions_mut().insert(self.clone());
+        response
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        self.to_response(Locale::En)
     }
 }
 
 pub type ApiResult<T> = Result<T, ApiError>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// One of every variant; the match makes a new variant fail to compile
+    /// here until it is given a snapshot.
+    fn every_variant() -> Vec<(&'static str, ApiError)> {
+        let all = vec![
+            ("not_found", ApiError::NotFound),
+            ("unauthorized", ApiError::Unauthorized),
+            ("forbidden", ApiError::Forbidden),
+            ("bad_request", ApiError::BadRequest("missing field `model`".into())),
+            ("unprocessable", ApiError::Unprocessable("invalid email format".into())),
+            ("conflict", ApiError::Conflict("upload is at offset 1024".into())),
+            (
+                "validation",
+                ApiError::Validation(vec![FieldError {
+                    field: "email".into(),
+                    code: "email".into(),
+                    message: "invalid email format".into(),
+                }]),
+            ),
+            ("payload_too_large", ApiError::PayloadTooLarge("request body exceeds 1024 bytes".into())),
+            ("rate_limited", ApiError::RateLimited),
+            ("internal", ApiError::Internal),
+            ("bad_gateway", ApiError::BadGateway("the model provider is unavailable".into())),
+        ];
+        for (_, e) in &all {
+            match e {
+                ApiError::NotFound
+                | ApiError::Unauthorized
+                | ApiError::Forbidden
+                | ApiError::BadRequest(_)
+                | ApiError::Unprocessable(_)
+                | ApiError::Conflict(_)
+                | ApiError::Validation(_)
+                | ApiError::PayloadTooLarge(_)
+                | ApiError::RateLimited
+                | ApiError::Internal
+                | ApiError::BadGateway(_) => {}
+            }
+        }
+        all
+    }
+
+    #[tokio::test]
+    async fn test_error_response_shapes() {
+        for (name, err) in every_variant() {
+            let response = err.into_response();
+            let status = response.status().as_u16();
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            insta::assert_json_snapshot!(
+                format!("error_{name}"),
+                serde_json::json!({ "status": status, "body": body })
+            );
+        }
+    }
+
+    #[test]
+    fn test_every_code_is_localized() {
+        for (_, err) in every_variant() {
+            let (_, code) = err.status_and_code();
+            for locale in Locale::ALL {
+                assert!(i18n::title(code, locale).is_some(), "{code} missing for {locale:?}");
+            }
+        }
+        // The English catalog matches the Display text used in logs
+        for (_, err) in every_variant() {
+            assert_eq!(err.message(Locale::En), err.to_string());
+        }
+        assert_eq!(
+            ApiError::PayloadTooLarge("chat body exceeds 10 bytes".into()).message(Locale::De),
+            "Anfrage zu groß: chat body exceeds 10 bytes"
+        );
+    }
+}
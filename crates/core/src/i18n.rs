@@ -0,0 +1,104 @@
+//! Localized titles for error responses.
+//!
+//! Error `code`s are the stable, machine-readable contract and never change
+//! with the locale; only the human-readable `message` does. Details carried
+//! by an error (a limit, a parser message) are appended untranslated.
+
+/// Locales with a catalog entry for every error code
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Locale {
+    #[default]
+    En,
+    De,
+    Es,
+    Fr,
+    Ja,
+    Pt,
+    Zh,
+}
+
+impl Locale {
+    pub const ALL: [Locale; 7] = [Locale::En, Locale::De, Locale::Es, Locale::Fr, Locale::Ja, Locale::Pt, Locale::Zh];
+
+    /// BCP 47 tag, as sent in `Content-Language`
+    pub fn tag(self) -> &'static str {
+        match self {
+            Locale::En => "en",
+            Locale::De => "de",
+            Locale::Es => "es",
+            Locale::Fr => "fr",
+            Locale::Ja => "ja",
+            Locale::Pt => "pt",
+            Locale::Zh => "zh",
+        }
+    }
+
+    /// Best supported match for an `Accept-Language` header.
+    ///
+    /// Ranges are tried by descending q-value (ties keep header order) and
+    /// matched on their primary subtag, so `pt-BR` selects `pt`. Anything
+    /// unsupported or malformed falls back to English.
+    pub fn negotiate(header: &str) -> Self {
+        let mut ranges: Vec<(&str, f32)> = header
+            .split(',')
+            .filter_map(|range| {
+                let mut parts = range.split(';');
+                let tag = parts.next()?.trim();
+                let q = parts
+                    .find_map(|p| p.trim().strip_prefix("q="))
+                    .map_or(Some(1.0), |q| q.trim().parse::<f32>().ok())?;
+                (!tag.is_empty() && q > 0.0).then_some((tag, q))
+            })
+            .collect();
+        ranges.sort_by(|a, b| b.1.total_cmp(&a.1));
+        ranges
+            .into_iter()
+            .find_map(|(tag, _)| {
+                let primary = tag.split('-').next().unwrap_or(tag);
+                Self::ALL.into_iter().find(|l| l.tag().eq_ignore_ascii_case(primary))
+            })
+            .unwrap_or_default()
+    }
+
+    fn index(self) -> usize {
+        Self::ALL.iter().position(|l| *l == self).unwrap_or(0)
+    }
+}
+
+/// Error titles by code, in `Locale::ALL` order
+const CATALOG: &[(&str, [&str; 7])] = &[
+    ("not_found", ["Not Found", "Nicht gefunden", "No encontrado", "Introuvable", "見つかりません", "Não encontrado", "未找到"]),
+    ("unauthorized", ["Unauthorized", "Nicht autorisiert", "No autorizado", "Non autorisé", "認証が必要です", "Não autorizado", "未授权"]),
+    ("forbidden", ["Forbidden", "Verboten", "Prohibido", "Interdit", "アクセスが拒否されました", "Proibido", "禁止访问"]),
+    ("bad_request", ["Bad Request", "Ungültige Anfrage", "Solicitud incorrecta", "Requête incorrecte", "不正なリクエストです", "Requisição inválida", "请求无效"]),
+    ("unprocessable", ["Unprocessable", "Nicht verarbeitbar", "No procesable", "Requête non traitable", "処理できません", "Não processável", "无法处理"]),
+    ("conflict", ["Conflict", "Konflikt", "Conflicto", "Conflit", "競合しています", "Conflito", "冲突"]),
+    ("validation_failed", ["Validation failed", "Validierung fehlgeschlagen", "La validación falló", "Échec de la validation", "検証に失敗しました", "Falha na validação", "验证失败"]),
+    ("payload_too_large", ["Payload Too Large", "Anfrage zu groß", "Carga demasiado grande", "Charge utile trop volumineuse", "ペイロードが大きすぎます", "Carga muito grande", "请求体过大"]),
+    ("rate_limited", ["Too Many Requests", "Zu viele Anfragen", "Demasiadas solicitudes", "Trop de requêtes", "リクエストが多すぎます", "Muitas requisições", "请求过多"]),
+    ("internal_error", ["Internal Server Error", "Interner Serverfehler", "Error interno del servidor", "Erreur interne du serveur", "内部サーバーエラー", "Erro interno do servidor", "服务器内部错误"]),
+    ("upstream_error", ["Bad Gateway", "Fehlerhaftes Gateway", "Puerta de enlace incorrecta", "Passerelle incorrecte", "不正なゲートウェイ", "Gateway inválido", "网关错误"]),
+];
+
+/// Localized title for an error code, if the catalog has one
+pub fn title(code: &str, locale: Locale) -> Option<&'static str> {
+    CATALOG
+        .iter()
+        .find(|(c, _)| *c == code)
+        .map(|(_, titles)| titles[locale.index()])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(Locale::negotiate("de-CH, de;q=0.9, en;q=0.8"), Locale::De);
+        assert_eq!(Locale::negotiate("en;q=0.5, pt-BR"), Locale::Pt);
+        assert_eq!(Locale::negotiate("ko, ja;q=0.7"), Locale::Ja);
+        assert_eq!(Locale::negotiate("fr;q=0, es;q=0.1"), Locale::Es);
+        assert_eq!(Locale::negotiate("*"), Locale::En);
+        assert_eq!(Locale::negotiate(";;q=abc,"), Locale::En);
+    }
+}
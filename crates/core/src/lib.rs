@@ -1,2 +1,4 @@
 pub mod config;
 pub mod error;
+pub mod i18n;
+pub mod rate_limit;
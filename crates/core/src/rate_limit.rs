@@ -0,0 +1,235 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+/// Per-key request limiter: `rate_per_min` sustained, up to `burst` at once.
+///
+/// Implemented as GCRA (a virtual-scheduling token bucket): the state is a
+/// single theoretical arrival time, so there is no token count to truncate
+/// and every fraction of elapsed time counts toward the next request.
+#[derive(Clone)]
+pub struct TokenBucket { tat: Arc<tokio::sync::Mutex<Instant>>, rate_per_min: u64, burst: u64 }
+
+impl TokenBucket {
+    pub fn new(rate_per_min: u64, burst: u64) -> Self { Self { tat: Arc::new(tokio::sync::Mutex::new(Instant::now())), rate_per_min, burst } }
+    pub async fn allow(&self) -> bool {
+        let mut guard = self.tat.lock().await;
+        take(&mut guard, Instant::now(), self.rate_per_min, self.burst)
+    }
+
+    /// Current state without consuming anything
+    pub async fn status(&self) -> BucketStatus {
+        let tat = *self.tat.lock().await;
+        status(tat, Instant::now(), self.rate_per_min, self.burst)
+    }
+
+    /// True once the bucket has refilled to its full burst, when dropping it
+    /// loses nothing. A bucket locked by a request in flight is never idle.
+    pub fn is_idle(&self) -> bool {
+        self.tat.try_lock().is_ok_and(|tat| *tat <= Instant::now())
+    }
+}
+
+/// Snapshot of one bucket, for clients and operators
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+pub struct BucketStatus {
+    /// Requests allowed at once when the bucket is full (the burst)
+    pub limit: u64,
+    /// Requests that would be admitted right now
+    pub remaining: u64,
+    /// Seconds until the bucket is full again (rounded up)
+    pub reset_secs: u64,
+}
+
+fn interval(rate_per_min: u64) -> Duration {
+    match rate_per_min {
+        0 => NEVER,
+        rate => Duration::from_secs_f64(60.0 / rate as f64),
+    }
+}
+
+fn status(tat: Instant, now: Instant, rate_per_min: u64, burst: u64) -> BucketStatus {
+    let interval = interval(rate_per_min);
+    let backlog = tat.saturating_duration_since(now);
+    let tolerance = interval.saturating_mul(u32::try_from(burst).unwrap_or(u32::MAX));
+    let headroom = tolerance.saturating_sub(backlog);
+    let remaining = if interval.is_zero() {
+        burst
+    } else {
+        ((headroom.as_nanos() / interval.as_nanos()) as u64).min(burst)
+    };
+    BucketStatus { limit: burst, remaining, reset_secs: backlog.as_secs_f64().ceil() as u64 }
+}
+
+/// Emission interval for a zero rate: the burst is never replenished
+const NEVER: Duration = Duration::from_secs(1_000_000_000);
+
+/// Admit one request at `now` against the theoretical arrival time `tat`.
+///
+/// Each admitted request pushes `tat` one emission interval past
+/// `max(tat, now)`; a request is refused when that would put `tat` more
+/// than `burst` intervals ahead of `now`. A `now` earlier than previous
+/// calls only makes the check stricter.
+fn take(tat: &mut Instant, now: Instant, rate_per_min: u64, burst: u64) -> bool {
+    let interval = interval(rate_per_min);
+    let tolerance = interval.saturating_mul(u32::try_from(burst).unwrap_or(u32::MAX));
+    let Some(next) = (*tat).max(now).checked_add(interval) else { return false };
+    if next.saturating_duration_since(now) > tolerance {
+        return false;
+    }
+    *tat = next;
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_take_respects_burst_and_refill() {
+        let start = Instant::now();
+        let mut tat = start;
+        assert!(take(&mut tat, start, 60, 2));
+        assert!(take(&mut tat, start, 60, 2));
+        assert!(!take(&mut tat, start, 60, 2));
+        // 60/min = one token per second
+        assert!(!take(&mut tat, start + Duration::from_millis(900), 60, 2));
+        assert!(take(&mut tat, start + Duration::from_secs(1), 60, 2));
+        // Long idle periods never exceed the burst
+        let later = start + Duration::from_secs(3600);
+        assert!(take(&mut tat, later, 60, 2));
+        assert!(take(&mut tat, later, 60, 2));
+        assert!(!take(&mut tat, later, 60, 2));
+    }
+
+    /// Requests at a fixed period against a 1/s limit with a burst of 2
+    fn granted_at_period(period: Duration, count: u32) -> u32 {
+        let start = Instant::now();
+        let mut tat = start;
+        (0..count).filter(|i| take(&mut tat, start + period * *i, 60, 2)).count() as u32
+    }
+
+    #[test]
+    fn test_fractional_intervals_are_not_lost() {
+        // Every 0.75s for 60s: the burst plus one per second gets through.
+        // Truncating refills would admit only one per 1.5s.
+        assert_eq!(granted_at_period(Duration::from_millis(750), 81), 2 + 60);
+        // Every 1.9s is always under the rate
+        assert_eq!(granted_at_period(Duration::from_millis(1900), 50), 50);
+        // Every 0.1s for 10s against 6/min: one per 10s
+        let start = Instant::now();
+        let mut tat = start;
+        let granted = (0..=100)
+            .filter(|i| take(&mut tat, start + Duration::from_millis(100) * *i, 6, 1))
+            .count();
+        assert_eq!(granted, 2);
+    }
+
+    #[test]
+    fn test_hammering_does_not_starve_later_requests() {
+        // Denied requests cost nothing: a client retrying every millisecond
+        // still gets exactly the sustained rate
+        let start = Instant::now();
+        let mut tat = start;
+        let granted = (0..10_000)
+            .filter(|i| take(&mut tat, start + Duration::from_millis(*i), 600, 5))
+            .count();
+        // 5 burst + one per 100ms over 9.999s
+        assert_eq!(granted, 5 + 99);
+    }
+
+    #[test]
+    fn test_status_tracks_consumption() {
+        let start = Instant::now();
+        let mut tat = start;
+        assert_eq!(status(tat, start, 60, 3), BucketStatus { limit: 3, remaining: 3, reset_secs: 0 });
+        assert!(take(&mut tat, start, 60, 3));
+        assert!(take(&mut tat, start, 60, 3));
+        assert_eq!(status(tat, start, 60, 3), BucketStatus { limit: 3, remaining: 1, reset_secs: 2 });
+        let later = start + Duration::from_millis(1500);
+        assert_eq!(status(tat, later, 60, 3), BucketStatus { limit: 3, remaining: 2, reset_secs: 1 });
+    }
+
+    #[tokio::test]
+    async fn test_bucket_is_idle_once_refilled() {
+        let bucket = TokenBucket::new(60_000, 2);
+        assert!(bucket.is_idle());
+        assert!(bucket.allow().await);
+        assert!(!bucket.is_idle());
+        // 60000/min refills one token per millisecond
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        assert!(bucket.is_idle());
+    }
+
+    #[test]
+    fn test_zero_rate_allows_only_the_burst() {
+        let start = Instant::now();
+        let mut tat = start;
+        assert!(take(&mut tat, start, 0, 2));
+        assert!(take(&mut tat, start, 0, 2));
+        assert!(!take(&mut tat, start + Duration::from_secs(86_400), 0, 2));
+    }
+
+    #[cfg(feature = "proptest")]
+    mod props {
+        use super::*;
+        use proptest::prelude::*;
+
+        proptest! {
+            /// Tokens granted over any sequence of (possibly jittery, tiny or
+            /// zero) clock steps never exceed burst + rate * elapsed.
+            #[test]
+            fn prop_no_tokens_from_clock_jitter(
+                rate_per_min in 1u64..10_000,
+                burst in 1u64..100,
+                steps in prop::collection::vec(0u64..2_000_000, 1..300),
+            ) {
+                let start = Instant::now();
+                let mut tat = start;
+                let mut now = start;
+                let mut granted = 0u64;
+                for micros in steps {
+                    now += Duration::from_micros(micros);
+                    if take(&mut tat, now, rate_per_min, burst) {
+                        granted += 1;
+                    }
+                }
+                let elapsed = now.duration_since(start).as_secs_f64();
+                // Slack for the nanosecond rounding of the emission interval
+                let budget = burst as f64 + rate_per_min as f64 / 60.0 * elapsed + 1e-6;
+                prop_assert!(granted as f64 <= budget.floor(), "granted {} > budget {}", granted, budget);
+            }
+
+            /// Steady traffic at or under the rate is never refused
+            #[test]
+            fn prop_under_rate_always_allowed(
+                rate_per_min in 1u64..10_000,
+                burst in 1u64..10,
+                slack_pct in 0u64..100,
+                count in 1u32..200,
+            ) {
+                let interval = Duration::from_secs_f64(60.0 / rate_per_min as f64);
+                let period = interval + interval * slack_pct as u32 / 100;
+                let start = Instant::now();
+                let mut tat = start;
+                for i in 0..count {
+                    prop_assert!(take(&mut tat, start + period * i, rate_per_min, burst));
+                }
+            }
+
+            /// A clock that steps backwards never refills the bucket
+            #[test]
+            fn prop_backwards_clock_adds_nothing(back_ms in 0u64..10_000) {
+                let base = Instant::now() + Duration::from_secs(20);
+                let mut tat = base;
+                for _ in 0..10 {
+                    prop_assert!(take(&mut tat, base, 60, 10));
+                }
+                let drained = tat;
+                prop_assert!(!take(&mut tat, base - Duration::from_millis(back_ms), 60, 10));
+                prop_assert_eq!(tat, drained);
+            }
+        }
+    }
+}
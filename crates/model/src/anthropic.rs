@@ -0,0 +1,387 @@
+//! Anthropic's Messages API.
+//!
+//! `POST {base}/v1/messages` streams typed events: a `content_block_start`
+//! opens each text or `tool_use` block, `content_block_delta`s carry its
+//! text or fragments of its input JSON, `message_delta` the stop reason,
+//! and `message_stop` ends the message. `base` is the API root without its
+//! version, e.g. `https://api.anthropic.com`.
+//!
+//! The request differs from the other dialects in shape: system prompts go
+//! in a top-level `system`, roles must alternate (so consecutive turns of
+//! one role are merged), a call is a `tool_use` block of the assistant's
+//! turn and its result a `tool_result` block of the next user turn, and
+//! `max_tokens` is required. Temperatures go up to 1 rather than 2.
+
+use crate::{
+    errors, sse, ChatChunk, ChatRequest, ChatStream, ModelError, ModelProvider, ModelResult, ToolCall, Usage,
+    FINISH_STOP, FINISH_TOOL_CALLS,
+};
+use async_stream::try_stream;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    sync::Arc,
+    time::Duration,
+};
+
+/// The API version requests are written against
+const API_VERSION: &str = "2023-06-01";
+
+/// The highest temperature the API accepts
+const MAX_TEMPERATURE: f64 = 1.0;
+
+pub struct AnthropicProvider {
+    base: String,
+    api_key: String,
+    client: reqwest::Client,
+    timeout: Duration,
+    /// Required by the API; sent with every chat
+    max_tokens: u32,
+}
+
+impl AnthropicProvider {
+    pub fn new(
+        base: impl Into<String>,
+        api_key: impl Into<String>,
+        client: reqwest::Client,
+        timeout: Duration,
+        max_tokens: u32,
+    ) -> Self {
+        let base = base.into().trim_end_matches('/').to_string();
+        Self { base, api_key: api_key.into(), client, timeout, max_tokens: max_tokens.max(1) }
+    }
+
+    async fn send(&self, req: reqwest::RequestBuilder, what: &str) -> ModelResult<reqwest::Response> {
+        let req = req.header("anthropic-version", API_VERSION);
+        let req = if self.api_key.is_empty() { req } else { req.header("x-api-key", &self.api_key) };
+        let resp = req.timeout(self.timeout).send().await.map_err(|e| {
+            tracing::error!(error = %e, "anthropic {what} request failed");
+            if e.is_timeout() { ModelError::Timeout } else { ModelError::Upstream(e.to_string()) }
+        })?;
+        if !resp.status().is_success() {
+            tracing::error!(status = %resp.status(), "anthropic {what} returned non-success status");
+            return Err(errors::status_error(resp).await);
+        }
+        Ok(resp)
+    }
+}
+
+/// The request body for `req`, capped at `max_tokens` unless it asks for
+/// another cap. Calls get ids by position, and each tool message answers
+/// the oldest call not yet answered.
+fn messages_body(req: &ChatRequest, max_tokens: u32) -> Value {
+    let mut system = Vec::new();
+    let mut turns: Vec<(&str, Vec<Value>)> = Vec::new();
+    let mut unanswered = VecDeque::new();
+    let mut issued = 0;
+    for m in &req.messages {
+        let text = || (!m.content.is_empty()).then(|| json!({ "type": "text", "text": m.content }));
+        let (role, blocks) = match m.role.as_str() {
+            "system" => {
+                system.push(m.content.as_str());
+                continue;
+            }
+            "assistant" => {
+                let calls = m.tool_calls.iter().map(|call| {
+                    issued += 1;
+                    let id = format!("call_{issued}");
+                    unanswered.push_back(id.clone());
+                    // The API takes objects only
+                    let input = if call.arguments.is_object() { call.arguments.clone() } else { json!({}) };
+                    json!({ "type": "tool_use", "id": id, "name": call.name, "input": input })
+                });
+                ("assistant", text().into_iter().chain(calls).collect())
+            }
+            "tool" => match unanswered.pop_front() {
+                Some(id) => ("user", vec![json!({ "type": "tool_result", "tool_use_id": id, "content": m.content })]),
+                None => ("user", text().into_iter().collect()),
+            },
+            _ => ("user", text().into_iter().collect()),
+        };
+        match turns.last_mut() {
+            Some((last, content)) if *last == role => content.extend(blocks),
+            _ if blocks.is_empty() => {}
+            _ => turns.push((role, blocks)),
+        }
+    }
+    let messages: Vec<_> =
+        turns.into_iter().map(|(role, content)| json!({ "role": role, "content": content })).collect();
+    let max_tokens = req.options.max_tokens.unwrap_or(max_tokens);
+    let mut body = json!({ "model": req.model, "max_tokens": max_tokens, "messages": messages, "stream": true });
+    for (name, value) in req.options.given() {
+        match name {
+            "stop" => body["stop_sequences"] = value,
+            // Set above; and the API has no seed
+            "max_tokens" | "seed" => {}
+            _ => body[name] = value,
+        }
+    }
+    if !system.is_empty() {
+        body["system"] = system.join("\n\n").into();
+    }
+    if !req.tools.is_empty() {
+        let tools: Vec<_> = req
+            .tools
+            .iter()
+            .map(|t| json!({ "name": t.name, "description": t.description, "input_schema": t.parameters }))
+            .collect();
+        body["tools"] = tools.into();
+    }
+    body
+}
+
+/// One streamed event, by its `type`
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Event {
+    ContentBlockStart { index: usize, content_block: Block },
+    ContentBlockDelta { index: usize, delta: BlockDelta },
+    MessageStart { message: MessageStart },
+    MessageDelta {
+        delta: MessageDelta,
+        #[serde(default)]
+        usage: Option<OutputUsage>,
+    },
+    MessageStop,
+    Error { error: ErrorEvent },
+    /// `content_block_stop`, `ping`, and any added later
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Block {
+    ToolUse { name: String },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum BlockDelta {
+    TextDelta { text: String },
+    InputJsonDelta { partial_json: String },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Deserialize)]
+struct MessageDelta {
+    #[serde(default)]
+    stop_reason: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct MessageStart {
+    #[serde(default)]
+    usage: Option<InputUsage>,
+}
+
+#[derive(Deserialize)]
+struct InputUsage {
+    #[serde(default)]
+    input_tokens: u64,
+}
+
+/// The running count of generated tokens
+#[derive(Deserialize)]
+struct OutputUsage {
+    #[serde(default)]
+    output_tokens: u64,
+}
+
+#[derive(Deserialize)]
+struct ErrorEvent {
+    #[serde(rename = "type")]
+    kind: String,
+    message: String,
+}
+
+/// What a message stream has said so far beyond its text
+#[derive(Default)]
+struct Message {
+    /// Name and input JSON text of each `tool_use` block, by block index
+    calls: BTreeMap<usize, (String, String)>,
+    stop_reason: Option<String>,
+    stopped: bool,
+    usage: Option<Usage>,
+}
+
+impl Message {
+    /// Read one event's `data`; returns the text it adds, if any
+    fn push(&mut self, data: &str) -> ModelResult<Option<String>> {
+        let event: Event =
+            serde_json::from_str(data).map_err(|e| ModelError::Other(format!("JSON parse error: {e}")))?;
+        match event {
+            Event::ContentBlockStart { index, content_block: Block::ToolUse { name } } => {
+                self.calls.insert(index, (name, String::new()));
+            }
+            Event::ContentBlockDelta { delta: BlockDelta::TextDelta { text }, .. } if !text.is_empty() => {
+                return Ok(Some(text));
+            }
+            Event::ContentBlockDelta { index, delta: BlockDelta::InputJsonDelta { partial_json } } => {
+                if let Some((_, input)) = self.calls.get_mut(&index) {
+                    input.push_str(&partial_json);
+                }
+            }
+            Event::MessageStart { message } => {
+                if let Some(usage) = message.usage {
+                    self.usage.get_or_insert_with(Usage::default).prompt_tokens = usage.input_tokens;
+                }
+            }
+            Event::MessageDelta { delta, usage } => {
+                self.stop_reason = delta.stop_reason.or(self.stop_reason.take());
+                if let Some(usage) = usage {
+                    self.usage.get_or_insert_with(Usage::default).completion_tokens = usage.output_tokens;
+                }
+            }
+            Event::MessageStop => self.stopped = true,
+            Event::Error { error } => {
+                return Err(match error.kind.as_str() {
+                    "overloaded_error" => ModelError::Unavailable(error.message),
+                    "rate_limit_error" => ModelError::RateLimited(error.message),
+                    _ => ModelError::Upstream(error.message),
+                });
+            }
+            _ => {}
+        }
+        Ok(None)
+    }
+
+    /// The terminal chunk, carrying the calls made
+    fn finish(self, model: &Arc<str>) -> ChatChunk {
+        let tool_calls = self
+            .calls
+            .into_values()
+            .map(|(name, input)| {
+                let arguments = match input.trim() {
+                    "" => json!({}),
+                    text => serde_json::from_str(text).unwrap_or(Value::String(input)),
+                };
+                ToolCall { name, arguments }
+            })
+            .collect();
+        let finish_reason = self.stop_reason.map(|reason| {
+            match reason.as_str() {
+                "end_turn" | "stop_sequence" => FINISH_STOP,
+                "max_tokens" => "length",
+                "tool_use" => FINISH_TOOL_CALLS,
+                other => other,
+            }
+            .to_string()
+        });
+        let usage = self.usage;
+        ChatChunk { model: model.clone(), done: true, finish_reason, tool_calls, usage, ..Default::default() }
+    }
+}
+
+#[async_trait::async_trait]
+impl ModelProvider for AnthropicProvider {
+    fn name(&self) -> &'static str {
+        "anthropic"
+    }
+
+    async fn list_models(&self) -> ModelResult<Vec<String>> {
+        #[derive(Deserialize)]
+        struct Models {
+            data: Vec<Model>,
+        }
+        #[derive(Deserialize)]
+        struct Model {
+            id: String,
+        }
+        let resp = self.send(self.client.get(format!("{}/v1/models", self.base)), "list_models").await?;
+        let models: Models = resp.json().await.map_err(|e| ModelError::Upstream(e.to_string()))?;
+        Ok(models.data.into_iter().map(|m| m.id).collect())
+    }
+
+    async fn chat_stream(&self, req: ChatRequest) -> ModelResult<ChatStream> {
+        let model: Arc<str> = Arc::from(req.model.as_str());
+        // Checked here so the caller learns why, not just that Anthropic said 400
+        if req.options.temperature.is_some_and(|t| t > MAX_TEMPERATURE) {
+            return Err(ModelError::Rejected(format!(
+                "temperature must be at most {MAX_TEMPERATURE} for Anthropic models"
+            )));
+        }
+        let body = messages_body(&req, self.max_tokens);
+        tracing::debug!(model = %model, messages = req.messages.len(), "starting anthropic chat stream");
+        let resp = self.send(self.client.post(format!("{}/v1/messages", self.base)).json(&body), "chat").await?;
+        let events = sse::data_lines(resp.bytes_stream());
+
+        let stream = try_stream! {
+            use futures_util::StreamExt;
+
+            let mut message = Message::default();
+            tokio::pin!(events);
+            while let Some(data) = events.next().await {
+                if let Some(text) = message.push(&data?)? {
+                    yield ChatChunk { model: model.clone(), content: text, ..Default::default() };
+                }
+                if message.stopped {
+                    break;
+                }
+            }
+            if !message.stopped {
+                Err(ModelError::Upstream("stream ended before the message stopped".into()))?;
+            }
+            yield message.finish(&model);
+        };
+
+        Ok(Box::pin(stream))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ChatMessage;
+
+    fn message(role: &str, content: &str, tool_calls: Vec<ToolCall>) -> ChatMessage {
+        ChatMessage { role: role.into(), content: content.into(), tool_calls }
+    }
+
+    #[test]
+    fn test_turns_alternate_with_tool_results_in_user_turns() {
+        let call = |name: &str| ToolCall { name: name.into(), arguments: json!({ "x": 1 }) };
+        let req = ChatRequest {
+            model: "claude".into(),
+            messages: vec![
+                message("system", "Be brief.", vec![]),
+                message("system", "Cite sources.", vec![]),
+                message("user", "Hi", vec![]),
+                message("assistant", "Checking.", vec![call("a"), call("b")]),
+                message("tool", "1", vec![]),
+                message("tool", "2", vec![]),
+                message("user", "Thanks", vec![]),
+            ],
+            ..Default::default()
+        };
+        let body = messages_body(&req, 1024);
+        assert_eq!(body["system"], "Be brief.\n\nCite sources.");
+        assert_eq!(body["max_tokens"], 1024);
+        let roles: Vec<_> = body["messages"].as_array().unwrap().iter().map(|m| m["role"].clone()).collect();
+        assert_eq!(roles, ["user", "assistant", "user"]);
+        let second = json!({ "type": "tool_use", "id": "call_2", "name": "b", "input": { "x": 1 } });
+        assert_eq!(body["messages"][1]["content"][2], second);
+        assert_eq!(
+            body["messages"][2]["content"],
+            json!([
+                { "type": "tool_result", "tool_use_id": "call_1", "content": "1" },
+                { "type": "tool_result", "tool_use_id": "call_2", "content": "2" },
+                { "type": "text", "text": "Thanks" },
+            ])
+        );
+    }
+
+    #[test]
+    fn test_stop_reasons_map_to_finish_reasons() {
+        let reasons = [("end_turn", "stop"), ("max_tokens", "length"), ("tool_use", "tool_calls")];
+        for (stop, finish) in reasons.into_iter().chain([("refusal", "refusal")]) {
+            let mut message = Message::default();
+            message.push(&format!(r#"{{"type":"message_delta","delta":{{"stop_reason":"{stop}"}}}}"#)).unwrap();
+            assert_eq!(message.finish(&Arc::from("m")).finish_reason.as_deref(), Some(finish));
+        }
+    }
+}
@@ -0,0 +1,157 @@
+//! Upstream error payloads, mapped onto [`ModelError`].
+//!
+//! Ollama answers a failed request with `{"error": "model 'x' not found"}`,
+//! OpenAI-compatible servers and Anthropic with `{"error": {"message": ..., "type": ...}}`
+//! (older vLLM releases with a bare `{"message": ...}`). The status picks
+//! the variant; the message is kept for logs. What clients may see is
+//! [`ModelError::client_message`]: the upstream's words only where they are
+//! about the request, with anything that locates the upstream taken out.
+
+use crate::ModelError;
+use serde::Deserialize;
+use std::net::{IpAddr, SocketAddr};
+
+/// Longest upstream message passed on to a client
+const MAX_CLIENT_MESSAGE_CHARS: usize = 200;
+
+#[derive(Deserialize)]
+struct ErrorBody {
+    #[serde(default)]
+    error: Option<ErrorDetail>,
+    #[serde(default)]
+    message: Option<String>,
+}
+
+/// `error` as Ollama (a string) or OpenAI (an object) sends it
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub(crate) enum ErrorDetail {
+    Text(String),
+    Object { message: String },
+}
+
+impl ErrorDetail {
+    pub(crate) fn message(self) -> String {
+        match self {
+            ErrorDetail::Text(message) | ErrorDetail::Object { message } => message,
+        }
+    }
+}
+
+/// The message of an error body in any of the dialects spoken upstream
+fn error_message(body: &[u8]) -> Option<String> {
+    let body: ErrorBody = serde_json::from_slice(body).ok()?;
+    body.error.map(ErrorDetail::message).or(body.message).filter(|m| !m.trim().is_empty())
+}
+
+/// The error a non-success response stands for
+pub(crate) async fn status_error(resp: reqwest::Response) -> ModelError {
+    let status = resp.status();
+    let body = resp.bytes().await.unwrap_or_default();
+    ModelError::from_status(status.as_u16(), error_message(&body))
+}
+
+impl ModelError {
+    /// Classify a response by its status, keeping the upstream's message
+    pub fn from_status(status: u16, message: Option<String>) -> Self {
+        let reason = reqwest::StatusCode::from_u16(status).ok().and_then(|s| s.canonical_reason());
+        let detail = || message.clone().unwrap_or_else(|| format!("HTTP {status}"));
+        match status {
+            404 => ModelError::ModelNotFound(detail()),
+            400 | 413 | 422 => ModelError::Rejected(detail()),
+            401 | 403 => ModelError::Unauthorized(detail()),
+            429 => ModelError::RateLimited(detail()),
+            // 529: Anthropic's overloaded
+            502 | 503 | 529 => ModelError::Unavailable(detail()),
+            408 | 504 => ModelError::Timeout,
+            _ => match (message, reason) {
+                (Some(message), _) => ModelError::Upstream(format!("HTTP {status}: {message}")),
+                (None, Some(reason)) => ModelError::Upstream(format!("HTTP {status} {reason}")),
+                (None, None) => ModelError::Upstream(format!("HTTP {status}")),
+            },
+        }
+    }
+
+    /// What a client may be told about this failure
+    pub fn client_message(&self) -> String {
+        match self {
+            ModelError::ModelNotFound(detail) | ModelError::Rejected(detail) => sanitize(detail),
+            // Written here, not upstream
+            ModelError::Unsupported(reason) => reason.clone(),
+            ModelError::RateLimited(_) => "the model provider is rate limiting requests; try again later".into(),
+            ModelError::Unavailable(_) => "the model provider is unavailable".into(),
+            ModelError::Timeout => "the model provider timed out".into(),
+            // A misconfigured credential is the operator's to fix, not the caller's
+            ModelError::Upstream(_) | ModelError::Other(_) | ModelError::Unauthorized(_) => {
+                "the model provider failed".into()
+            }
+        }
+    }
+}
+
+/// `text` with URLs, network addresses, and absolute paths replaced and
+/// control characters dropped, cut to [`MAX_CLIENT_MESSAGE_CHARS`]
+fn sanitize(text: &str) -> String {
+    let words: Vec<&str> = text
+        .split_whitespace()
+        .map(|word| {
+            let bare = word.trim_matches(|c: char| "()[]{}<>\"'`,;".contains(c));
+            let bare = bare.strip_suffix(['.', ':']).unwrap_or(bare);
+            if bare.contains("://") {
+                "[url]"
+            } else if bare.parse::<SocketAddr>().is_ok() || bare.parse::<IpAddr>().is_ok() {
+                "[address]"
+            } else if bare.starts_with('/') && bare[1..].contains('/') {
+                "[path]"
+            } else {
+                word
+            }
+        })
+        .collect();
+    let clean: String = words.join(" ").chars().filter(|c| !c.is_control()).collect();
+    match clean.char_indices().nth(MAX_CLIENT_MESSAGE_CHARS) {
+        Some((cut, _)) => format!("{}…", &clean[..cut]),
+        None => clean,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_bodies_of_each_dialect() {
+        assert_eq!(error_message(br#"{"error":"model 'x' not found"}"#).as_deref(), Some("model 'x' not found"));
+        let openai = br#"{"error":{"message":"Rate limit reached","type":"requests","code":"rate_limit_exceeded"}}"#;
+        assert_eq!(error_message(openai).as_deref(), Some("Rate limit reached"));
+        assert_eq!(error_message(br#"{"object":"error","message":"bad","code":400}"#).as_deref(), Some("bad"));
+        assert_eq!(error_message(b"<html>Bad Gateway</html>"), None);
+        assert_eq!(error_message(br#"{"error":""}"#), None);
+    }
+
+    #[test]
+    fn test_statuses_map_to_variants() {
+        let missing = ModelError::from_status(404, Some("model 'x' not found".into()));
+        assert!(matches!(missing, ModelError::ModelNotFound(ref m) if m == "model 'x' not found"));
+        assert!(matches!(ModelError::from_status(400, None), ModelError::Rejected(ref m) if m == "HTTP 400"));
+        assert!(matches!(ModelError::from_status(429, None), ModelError::RateLimited(_)));
+        assert!(matches!(ModelError::from_status(401, None), ModelError::Unauthorized(_)));
+        assert!(matches!(ModelError::from_status(503, None), ModelError::Unavailable(_)));
+        assert!(matches!(ModelError::from_status(504, None), ModelError::Timeout));
+        let other = ModelError::from_status(500, None);
+        assert!(matches!(other, ModelError::Upstream(ref m) if m == "HTTP 500 Internal Server Error"), "{other}");
+    }
+
+    #[test]
+    fn test_client_messages_do_not_locate_the_upstream() {
+        let detail = "open /root/.ollama/models/blobs/sha256-abc: no such file \
+                      (see http://ollama:11434/api/chat, 10.0.0.7:11434)";
+        let rejected = ModelError::Rejected(detail.into());
+        assert_eq!(rejected.client_message(), "open [path] no such file (see [url] [address]");
+        let down = ModelError::Upstream("error sending request for url (http://10.0.0.7:11434/api/chat)".into());
+        assert_eq!(down.client_message(), "the model provider failed");
+        let long = ModelError::ModelNotFound("x".repeat(500));
+        assert_eq!(long.client_message().chars().count(), MAX_CLIENT_MESSAGE_CHARS + 1);
+        assert_eq!(sanitize("line\u{7}one\r\ntwo"), "lineone two");
+    }
+}
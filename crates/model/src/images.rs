@@ -0,0 +1,314 @@
+//! Image generation backends: any OpenAI-compatible API, an
+//! Automatic1111 (`sdapi`) server, or a ComfyUI server running a workflow
+//! template.
+
+use crate::{ModelError, ModelResult};
+use base64::Engine as _;
+use bytes::Bytes;
+use serde::Deserialize;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone)]
+pub struct ImageRequest {
+    pub prompt: String,
+    pub negative_prompt: Option<String>,
+    /// Backend model; Automatic1111 and ComfyUI use whatever is loaded
+    pub model: String,
+    pub width: u32,
+    pub height: u32,
+    /// Images to generate
+    pub n: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct GeneratedImage {
+    pub data: Bytes,
+    /// MIME type of `data`
+    pub content_type: String,
+}
+
+#[async_trait::async_trait]
+pub trait ImageProvider: Send + Sync + 'static {
+    /// Short backend name for logs
+    fn name(&self) -> &'static str;
+    async fn generate(&self, req: ImageRequest) -> ModelResult<Vec<GeneratedImage>>;
+}
+
+fn request_error(backend: &str, e: reqwest::Error) -> ModelError {
+    tracing::error!(error = %e, backend, "image generation request failed");
+    if e.is_timeout() { ModelError::Timeout } else { ModelError::Upstream(e.to_string()) }
+}
+
+/// The response, or an upstream error carrying its status and the start of
+/// its body
+async fn success(resp: reqwest::Response, backend: &str) -> ModelResult<reqwest::Response> {
+    let status = resp.status();
+    if status.is_success() {
+        return Ok(resp);
+    }
+    let body = resp.text().await.unwrap_or_default();
+    tracing::error!(%status, backend, "image backend returned non-success status");
+    let detail: String = body.trim().chars().take(200).collect();
+    Err(ModelError::Upstream(format!("HTTP {status}: {detail}")))
+}
+
+fn decode_png(encoded: &str) -> ModelResult<GeneratedImage> {
+    // Automatic1111 may prefix a data URL header
+    let encoded = encoded.split_once("base64,").map_or(encoded, |(_, data)| data);
+    let data = base64::engine::general_purpose::STANDARD
+        .decode(encoded.trim())
+        .map_err(|e| ModelError::Upstream(format!("invalid base64 image: {e}")))?;
+    Ok(GeneratedImage { data: data.into(), content_type: "image/png".into() })
+}
+
+/// OpenAI-compatible `POST /v1/images/generations`
+pub struct OpenAiImages {
+    base: String,
+    api_key: String,
+    client: reqwest::Client,
+    timeout: Duration,
+}
+
+impl OpenAiImages {
+    /// `api_key` may be empty for servers that do not authenticate
+    pub fn new(base: impl Into<String>, api_key: impl Into<String>, client: reqwest::Client, timeout: Duration) -> Self {
+        Self { base: base.into(), api_key: api_key.into(), client, timeout }
+    }
+}
+
+#[async_trait::async_trait]
+impl ImageProvider for OpenAiImages {
+    fn name(&self) -> &'static str {
+        "openai"
+    }
+
+    async fn generate(&self, req: ImageRequest) -> ModelResult<Vec<GeneratedImage>> {
+        #[derive(Deserialize)]
+        struct Response {
+            data: Vec<Item>,
+        }
+        #[derive(Deserialize)]
+        struct Item {
+            b64_json: String,
+        }
+        let body = serde_json::json!({
+            "model": req.model,
+            "prompt": req.prompt,
+            "n": req.n,
+            "size": format!("{}x{}", req.width, req.height),
+            "response_format": "b64_json",
+        });
+        let mut builder = self.client.post(format!("{}/v1/images/generations", self.base)).json(&body);
+        if !self.api_key.is_empty() {
+            builder = builder.bearer_auth(&self.api_key);
+        }
+        let resp = builder.timeout(self.timeout).send().await.map_err(|e| request_error(self.name(), e))?;
+        let parsed: Response = success(resp, self.name())
+            .await?
+            .json()
+            .await
+            .map_err(|e| ModelError::Upstream(format!("invalid image response: {e}")))?;
+        parsed.data.iter().map(|item| decode_png(&item.b64_json)).collect()
+    }
+}
+
+/// Automatic1111 web UI API (`POST /sdapi/v1/txt2img`)
+pub struct Automatic1111Images {
+    base: String,
+    client: reqwest::Client,
+    timeout: Duration,
+}
+
+impl Automatic1111Images {
+    pub fn new(base: impl Into<String>, client: reqwest::Client, timeout: Duration) -> Self {
+        Self { base: base.into(), client, timeout }
+    }
+}
+
+#[async_trait::async_trait]
+impl ImageProvider for Automatic1111Images {
+    fn name(&self) -> &'static str {
+        "automatic1111"
+    }
+
+    async fn generate(&self, req: ImageRequest) -> ModelResult<Vec<GeneratedImage>> {
+        #[derive(Deserialize)]
+        struct Response {
+            images: Vec<String>,
+        }
+        let body = serde_json::json!({
+            "prompt": req.prompt,
+            "negative_prompt": req.negative_prompt.unwrap_or_default(),
+            "width": req.width,
+            "height": req.height,
+            "batch_size": req.n,
+        });
+        let resp = self
+            .client
+            .post(format!("{}/sdapi/v1/txt2img", self.base))
+            .json(&body)
+            .timeout(self.timeout)
+            .send()
+            .await
+            .map_err(|e| request_error(self.name(), e))?;
+        let parsed: Response = success(resp, self.name())
+            .await?
+            .json()
+            .await
+            .map_err(|e| ModelError::Upstream(format!("invalid image response: {e}")))?;
+        // Extra entries (e.g. a grid preview) beyond the batch are dropped
+        parsed.images.iter().take(req.n as usize).map(|image| decode_png(image)).collect()
+    }
+}
+
+/// How often ComfyUI's history is polled for a queued workflow
+const COMFYUI_POLL: Duration = Duration::from_millis(500);
+
+/// ComfyUI server running an API-format workflow template.
+///
+/// `{{prompt}}`, `{{negative_prompt}}`, `{{width}}`, `{{height}}`,
+/// `{{batch_size}}`, and `{{seed}}` in the template are replaced (string
+/// placeholders inside their JSON quotes); the images of every output node
+/// are returned.
+pub struct ComfyUiImages {
+    base: String,
+    workflow: String,
+    client: reqwest::Client,
+    timeout: Duration,
+}
+
+impl ComfyUiImages {
+    pub fn new(
+        base: impl Into<String>,
+        workflow: impl Into<String>,
+        client: reqwest::Client,
+        timeout: Duration,
+    ) -> Self {
+        Self { base: base.into(), workflow: workflow.into(), client, timeout }
+    }
+
+    /// The workflow with `req` filled in
+    pub fn render(&self, req: &ImageRequest, seed: u64) -> ModelResult<serde_json::Value> {
+        // Escaped as JSON string contents, without the surrounding quotes
+        let escape = |s: &str| {
+            let quoted = serde_json::Value::String(s.to_string()).to_string();
+            quoted[1..quoted.len() - 1].to_string()
+        };
+        let value = |name: &str| match name {
+            "prompt" => Some(escape(&req.prompt)),
+            "negative_prompt" => Some(escape(req.negative_prompt.as_deref().unwrap_or(""))),
+            "width" => Some(req.width.to_string()),
+            "height" => Some(req.height.to_string()),
+            "batch_size" => Some(req.n.to_string()),
+            "seed" => Some(seed.to_string()),
+            _ => None,
+        };
+        // One pass, so placeholders inside the prompt stay as typed
+        let (mut workflow, mut rest) = (String::with_capacity(self.workflow.len()), self.workflow.as_str());
+        while let Some(start) = rest.find("{{") {
+            let filled = rest[start + 2..].split_once("}}").and_then(|(name, after)| Some((value(name)?, after)));
+            workflow.push_str(&rest[..start]);
+            match filled {
+                Some((value, after)) => {
+                    workflow.push_str(&value);
+                    rest = after;
+                }
+                None => {
+                    workflow.push_str("{{");
+                    rest = &rest[start + 2..];
+                }
+            }
+        }
+        workflow.push_str(rest);
+        serde_json::from_str(&workflow).map_err(|e| ModelError::Other(format!("invalid ComfyUI workflow: {e}")))
+    }
+}
+
+#[async_trait::async_trait]
+impl ImageProvider for ComfyUiImages {
+    fn name(&self) -> &'static str {
+        "comfyui"
+    }
+
+    async fn generate(&self, req: ImageRequest) -> ModelResult<Vec<GeneratedImage>> {
+        #[derive(Deserialize)]
+        struct Queued {
+            prompt_id: String,
+        }
+        #[derive(Deserialize)]
+        struct OutputImage {
+            filename: String,
+            #[serde(default)]
+            subfolder: String,
+            #[serde(default, rename = "type")]
+            kind: String,
+        }
+        let seed = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos() as u64);
+        let workflow = self.render(&req, seed)?;
+        let deadline = Instant::now() + self.timeout;
+        let resp = self
+            .client
+            .post(format!("{}/prompt", self.base))
+            .json(&serde_json::json!({ "prompt": workflow }))
+            .timeout(self.timeout)
+            .send()
+            .await
+            .map_err(|e| request_error(self.name(), e))?;
+        let queued: Queued = success(resp, self.name())
+            .await?
+            .json()
+            .await
+            .map_err(|e| ModelError::Upstream(format!("invalid ComfyUI response: {e}")))?;
+
+        let outputs = loop {
+            if Instant::now() >= deadline {
+                return Err(ModelError::Timeout);
+            }
+            let resp = self
+                .client
+                .get(format!("{}/history/{}", self.base, queued.prompt_id))
+                .timeout(self.timeout)
+                .send()
+                .await
+                .map_err(|e| request_error(self.name(), e))?;
+            let history: serde_json::Value = success(resp, self.name())
+                .await?
+                .json()
+                .await
+                .map_err(|e| ModelError::Upstream(format!("invalid ComfyUI history: {e}")))?;
+            // Empty until the workflow finishes
+            if let Some(outputs) = history.get(&queued.prompt_id).and_then(|h| h.get("outputs")) {
+                break outputs.clone();
+            }
+            tokio::time::sleep(COMFYUI_POLL).await;
+        };
+
+        let mut images = Vec::new();
+        let nodes = outputs.as_object().into_iter().flat_map(|nodes| nodes.values());
+        for node in nodes {
+            // Nodes without images (e.g. text previews) list none
+            let listed: Vec<OutputImage> =
+                node.get("images").and_then(|images| serde_json::from_value(images.clone()).ok()).unwrap_or_default();
+            for image in listed {
+                let resp = self
+                    .client
+                    .get(format!("{}/view", self.base))
+                    .query(&[("filename", &image.filename), ("subfolder", &image.subfolder), ("type", &image.kind)])
+                    .timeout(self.timeout)
+                    .send()
+                    .await
+                    .map_err(|e| request_error(self.name(), e))?;
+                let resp = success(resp, self.name()).await?;
+                let content_type = resp
+                    .headers()
+                    .get(reqwest::header::CONTENT_TYPE)
+                    .and_then(|v| v.to_str().ok())
+                    .unwrap_or("image/png")
+                    .to_string();
+                let data = resp.bytes().await.map_err(|e| request_error(self.name(), e))?;
+                images.push(GeneratedImage { data, content_type });
+            }
+        }
+        Ok(images)
+    }
+}
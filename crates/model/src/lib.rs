@@ -1,62 +1,617 @@
 use async_stream::try_stream;
+use ds_types::DocumentId;
 use futures_core::Stream;
 use serde::{Deserialize, Serialize};
-use std::{pin::Pin, time::Duration};
+use std::{
+    borrow::Cow,
+    collections::BTreeMap,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, PoisonError, RwLock,
+    },
+    time::Duration,
+};
 use thiserror::Error;
 
+mod errors;
+pub mod anthropic;
+pub mod images;
+pub mod ndjson;
+pub mod openai;
+pub mod registry;
+pub mod sse;
+pub mod transcribe;
+
+pub use anthropic::AnthropicProvider;
+pub use openai::OpenAiProvider;
+pub use registry::ProviderRegistry;
+
+/// Failures of a model provider. The upstream's own message, kept for
+/// logs, may locate it; clients are told [`ModelError::client_message`].
 #[derive(Debug, Error)]
 pub enum ModelError {
     #[error("Upstream request failed: {0}")] Upstream(String),
     #[error("Timeout")] Timeout,
     #[error("Other: {0}")] Other(String),
+    /// The upstream's detected version lacks a feature the request needs
+    #[error("Unsupported: {0}")] Unsupported(String),
+    /// The upstream does not serve the requested model
+    #[error("Model not found: {0}")] ModelNotFound(String),
+    /// The upstream refused the request itself, e.g. a prompt longer than
+    /// the model's context
+    #[error("Rejected: {0}")] Rejected(String),
+    /// The upstream is throttling this service
+    #[error("Rate limited: {0}")] RateLimited(String),
+    /// The upstream refused this service's credentials
+    #[error("Unauthorized: {0}")] Unauthorized(String),
+    /// The upstream is overloaded or down
+    #[error("Unavailable: {0}")] Unavailable(String),
 }
 
 pub type ModelResult<T> = Result<T, ModelError>;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct ChatMessage { pub role: String, pub content: String }
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ChatMessage {
+    pub role: String,
+    pub content: String,
+    /// Tools an `assistant` message asked for; answered by `tool` messages
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tool_calls: Vec<ToolCall>,
+}
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct ChatRequest { pub model: String, pub messages: Vec<ChatMessage> }
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ChatRequest {
+    pub model: String,
+    pub messages: Vec<ChatMessage>,
+    /// Tools the model may call; empty for plain chat
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tools: Vec<ToolSpec>,
+    #[serde(default, skip_serializing_if = "GenerationOptions::is_empty")]
+    pub options: GenerationOptions,
+}
 
+/// Sampling settings for one chat; each left out keeps the upstream's
+/// default. Providers pass on those their API has under its own names
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct GenerationOptions {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_k: Option<u32>,
+    /// Longest reply, in tokens
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+    /// Sequences that end the reply when generated
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub stop: Vec<String>,
+}
+
+impl GenerationOptions {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Each setting given, by its name here
+    pub fn given(&self) -> impl Iterator<Item = (&'static str, serde_json::Value)> {
+        let stop = (!self.stop.is_empty()).then(|| serde_json::json!(self.stop));
+        [
+            ("temperature", self.temperature.map(Into::into)),
+            ("top_p", self.top_p.map(Into::into)),
+            ("top_k", self.top_k.map(Into::into)),
+            ("max_tokens", self.max_tokens.map(Into::into)),
+            ("seed", self.seed.map(Into::into)),
+            ("stop", stop),
+        ]
+        .into_iter()
+        .filter_map(|(name, value)| Some((name, value?)))
+    }
+}
+
+/// A function the model may call, described by a JSON Schema for its
+/// arguments
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct ChatChunk { pub model: String, pub content: String, pub done: bool }
+pub struct ToolSpec {
+    pub name: String,
+    pub description: String,
+    pub parameters: serde_json::Value,
+}
+
+/// A call the model asked for
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ToolCall {
+    pub name: String,
+    #[serde(default)]
+    pub arguments: serde_json::Value,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ChatChunk {
+    /// Shared across every chunk of a stream instead of cloned per chunk
+    pub model: Arc<str>,
+    pub content: String,
+    pub done: bool,
+    /// Set on the terminal frame: `stop`, `length`, or `error`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub finish_reason: Option<String>,
+    /// Chunks discarded because the client could not keep up (terminal frame only)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dropped_chunks: Option<u64>,
+    /// Time spent waiting in the admission queue, if any (terminal frame only)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub queued_ms: Option<u64>,
+    /// Tools the model asked to call in this chunk
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tool_calls: Vec<ToolCall>,
+    /// Response headers the provider was asked to capture, on the first
+    /// chunk of each upstream call
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub upstream_headers: BTreeMap<String, String>,
+    /// Document passages the model was given, in the order it was told to
+    /// cite them: `[1]` is the first (terminal frame only)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub citations: Vec<Citation>,
+    /// Tokens the provider counted for the generation, when it reports
+    /// them (terminal frame only)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usage: Option<Usage>,
+}
+
+/// Tokens a generation took, as the provider counted them
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Usage {
+    /// Tokens of the prompt the model read
+    pub prompt_tokens: u64,
+    /// Tokens the model generated
+    pub completion_tokens: u64,
+}
+
+impl Usage {
+    pub fn total(&self) -> u64 {
+        self.prompt_tokens + self.completion_tokens
+    }
+}
+
+impl std::ops::AddAssign for Usage {
+    fn add_assign(&mut self, other: Usage) {
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+    }
+}
+
+/// A document passage retrieved for a chat
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Citation {
+    pub document_id: DocumentId,
+    pub title: String,
+    /// Position of the chunk within its document
+    pub ordinal: i32,
+    /// Character offsets of the chunk in the document's text, end
+    /// exclusive; absent for chunks indexed before they were recorded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub start: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub end: Option<i32>,
+    /// The retrieval's score for it, as in a document search
+    pub score: f64,
+}
+
+pub const FINISH_STOP: &str = "stop";
+pub const FINISH_ERROR: &str = "error";
+/// Stopped by an operator
+pub const FINISH_CANCELLED: &str = "cancelled";
+/// The model still wanted tools when no more calls were allowed
+pub const FINISH_TOOL_CALLS: &str = "tool_calls";
+
+/// Features an upstream may lack, by the release that added them
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Capabilities {
+    /// Function calling (`tools` in chat requests)
+    pub tools: bool,
+    /// How long a model stays loaded after a request (`keep_alive`)
+    pub keep_alive: bool,
+    /// JSON mode (`format: "json"`)
+    pub format: bool,
+    /// A JSON schema as `format`
+    pub structured_outputs: bool,
+}
+
+impl Capabilities {
+    /// Assumed of an upstream whose version is not known
+    pub const ALL: Capabilities =
+        Capabilities { tools: true, keep_alive: true, format: true, structured_outputs: true };
+
+    /// Those of an Ollama release; `None` for a version that cannot be read,
+    /// or a development build's `0.0.0`
+    pub fn ollama(version: &str) -> Option<Self> {
+        let mut parts = version.trim_start_matches('v').split(['.', '-', '+']).map(str::parse::<u32>);
+        let release = (parts.next()?.ok()?, parts.next()?.ok()?, parts.next()?.ok()?);
+        if release == (0, 0, 0) {
+            return None;
+        }
+        Some(Capabilities {
+            tools: release >= (0, 3, 0),
+            keep_alive: release >= (0, 1, 23),
+            format: release >= (0, 1, 9),
+            structured_outputs: release >= (0, 5, 0),
+        })
+    }
+}
+
+/// An upstream's version and what it supports, as last detected
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Detected {
+    pub version: String,
+    pub capabilities: Capabilities,
+}
 
 #[async_trait::async_trait]
 pub trait ModelProvider: Send + Sync + 'static {
+    /// Short backend name recorded with each generation
+    fn name(&self) -> &'static str {
+        "unknown"
+    }
+    /// Ask the upstream its version again and remember it; `None` for
+    /// backends that do not report one
+    async fn detect(&self) -> ModelResult<Option<Detected>> {
+        Ok(None)
+    }
+    /// What [`detect`](Self::detect) last found
+    fn detected(&self) -> Option<Detected> {
+        None
+    }
+    /// What the upstream supports: everything until detected otherwise
+    fn capabilities(&self) -> Capabilities {
+        self.detected().map_or(Capabilities::ALL, |d| d.capabilities)
+    }
+    /// The provider serving `model` and the name it knows the model by,
+    /// for providers that pass models on to others; `None` serves it here
+    fn route(&self, model: &str) -> Option<(Arc<dyn ModelProvider>, String)> {
+        let _ = model;
+        None
+    }
+    /// Provider names `route` takes as model prefixes, the default first
+    fn routes(&self) -> Vec<&'static str> {
+        Vec::new()
+    }
     async fn list_models(&self) -> ModelResult<Vec<String>>;
     async fn chat_stream(&self, req: ChatRequest) -> ModelResult<ChatStream>;
+    /// Embedding vector of `input`; providers without embeddings refuse
+    async fn embed(&self, model: &str, input: &str) -> ModelResult<Vec<f32>> {
+        let _ = (model, input);
+        Err(ModelError::Other(format!("{} does not support embeddings", self.name())))
+    }
 }
 
 pub type ChatStream = Pin<Box<dyn Stream<Item = ModelResult<ChatChunk>> + Send>>;
 
+/// The provider that ends up serving `model` behind `provider`, and the
+/// model's name there
+pub fn resolve(provider: &Arc<dyn ModelProvider>, model: &str) -> (Arc<dyn ModelProvider>, String) {
+    match provider.route(model) {
+        Some((routed, model)) => resolve(&routed, &model),
+        None => (provider.clone(), model.to_string()),
+    }
+}
+
+/// Guarantee a stream ends with exactly one `done` frame.
+///
+/// After an upstream error (or a close before `done`) the error is passed
+/// through, a final chunk with `finish_reason: "error"` is emitted, and the
+/// stream terminates.
+pub fn with_terminal_frame(stream: ChatStream, model: impl Into<Arc<str>>) -> ChatStream {
+    let mut model = model.into();
+    Box::pin(async_stream::stream! {
+        use futures_util::StreamExt;
+        let mut stream = stream;
+        while let Some(item) = stream.next().await {
+            match item {
+                Ok(mut chunk) => {
+                    model.clone_from(&chunk.model);
+                    if chunk.done {
+                        chunk.finish_reason.get_or_insert_with(|| FINISH_STOP.to_string());
+                        yield Ok(chunk);
+                        return;
+                    }
+                    yield Ok(chunk);
+                }
+                Err(e) => {
+                    yield Err(e);
+                    break;
+                }
+            }
+        }
+        yield Ok(ChatChunk { model, done: true, finish_reason: Some(FINISH_ERROR.to_string()), ..Default::default() });
+    })
+}
+
+/// Credentials for an authenticating proxy in front of Ollama.
+///
+/// `Debug` never prints the secret, and reqwest marks the header sensitive.
+#[derive(Clone, Default)]
+pub enum UpstreamAuth {
+    #[default]
+    None,
+    Bearer(String),
+    Basic { username: String, password: String },
+}
+
+impl std::fmt::Debug for UpstreamAuth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UpstreamAuth::None => f.write_str("None"),
+            UpstreamAuth::Bearer(_) => f.write_str("Bearer(********)"),
+            UpstreamAuth::Basic { username, .. } => write!(f, "Basic({username}:********)"),
+        }
+    }
+}
+
+impl UpstreamAuth {
+    fn apply(&self, req: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        match self {
+            UpstreamAuth::None => req,
+            UpstreamAuth::Bearer(token) => req.bearer_auth(token),
+            UpstreamAuth::Basic { username, password } => req.basic_auth(username, Some(password)),
+        }
+    }
+}
+
 pub struct OllamaProvider {
-    base: String,
-    client: reqwest::Client,
+    /// Primary base URL first, then failover candidates in order
+    bases: Vec<String>,
+    /// Index into `bases` new requests start from
+    active: AtomicUsize,
+    auth: UpstreamAuth,
+    /// Swappable so a rotated client certificate applies without a restart
+    client: RwLock<reqwest::Client>,
     timeout: Duration,
+    /// Response headers copied onto the first chunk of a chat stream
+    capture: Vec<reqwest::header::HeaderName>,
+    /// Version last read from `/api/version`
+    detected: RwLock<Option<Detected>>,
 }
 
 impl OllamaProvider {
-    pub fn new(base: impl Into<String>, timeout: Duration) -> Self { Self { base: base.into(), client: reqwest::Client::new(), timeout } }
+    pub fn new(base: impl Into<String>, timeout: Duration) -> Self { Self::with_client(base, reqwest::Client::new(), timeout) }
+
+    /// Use a preconfigured client (proxy, extra CAs) shared with other callers
+    pub fn with_client(base: impl Into<String>, client: reqwest::Client, timeout: Duration) -> Self {
+        Self {
+            bases: vec![base.into()],
+            active: AtomicUsize::new(0),
+            auth: UpstreamAuth::None,
+            client: RwLock::new(client),
+            timeout,
+            capture: Vec::new(),
+            detected: RwLock::new(None),
+        }
+    }
+
+    /// Capture these response headers of chat requests (names that are not
+    /// valid headers are skipped)
+    pub fn with_captured_headers<S: AsRef<str>>(mut self, names: impl IntoIterator<Item = S>) -> Self {
+        self.capture = names.into_iter().filter_map(|n| n.as_ref().trim().parse().ok()).collect();
+        self
+    }
+
+    fn captured(&self, headers: &reqwest::header::HeaderMap) -> BTreeMap<String, String> {
+        self.capture
+            .iter()
+            .filter_map(|name| {
+                let value = headers.get(name)?.to_str().ok()?;
+                Some((name.as_str().to_string(), value.to_string()))
+            })
+            .collect()
+    }
+
+    /// Base URLs to fail over to when the primary cannot be reached
+    pub fn with_fallbacks(mut self, fallbacks: impl IntoIterator<Item = String>) -> Self {
+        self.bases.extend(fallbacks);
+        self
+    }
+
+    pub fn endpoints(&self) -> &[String] {
+        &self.bases
+    }
+
+    /// Base URL new requests are sent to first
+    pub fn active_endpoint(&self) -> &str {
+        &self.bases[self.active.load(Ordering::Relaxed) % self.bases.len()]
+    }
+
+    /// Return to the primary endpoint if it answers again; true if switched
+    pub async fn fail_back(&self) -> bool {
+        if self.active.load(Ordering::Relaxed) == 0 {
+            return false;
+        }
+        let probe = self.auth.apply(self.client().get(format!("{}/api/tags", self.bases[0])));
+        match probe.timeout(self.timeout).send().await {
+            Ok(resp) if resp.status().is_success() => {
+                self.active.store(0, Ordering::Relaxed);
+                tracing::info!(endpoint = %self.bases[0], "ollama primary endpoint recovered");
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Send to the active endpoint, moving on to the next one when the
+    /// connection fails or times out before a response. Errors after the
+    /// response started are not retried, so a generation never runs twice.
+    async fn send(
+        &self,
+        path: &str,
+        build: impl Fn(&reqwest::Client, String) -> reqwest::RequestBuilder,
+    ) -> Result<reqwest::Response, reqwest::Error> {
+        let client = self.client();
+        let start = self.active.load(Ordering::Relaxed);
+        let n = self.bases.len();
+        for attempt in 0..n {
+            let idx = (start + attempt) % n;
+            let base = &self.bases[idx];
+            let req = self.auth.apply(build(&client, format!("{base}{path}")));
+            match req.timeout(self.timeout).send().await {
+                Ok(resp) => {
+                    if attempt > 0 {
+                        self.active.store(idx, Ordering::Relaxed);
+                        tracing::warn!(endpoint = %base, "ollama failed over");
+                    }
+                    return Ok(resp);
+                }
+                Err(e) if (e.is_connect() || e.is_timeout()) && attempt + 1 < n => {
+                    tracing::warn!(error = %e, endpoint = %base, "ollama endpoint unreachable, trying next");
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        unreachable!("the last attempt always returns")
+    }
+
+    /// Send `auth` with every request
+    pub fn with_auth(mut self, auth: UpstreamAuth) -> Self {
+        self.auth = auth;
+        self
+    }
+
+    /// Replace the client for subsequent requests; streams already open
+    /// keep the connection they started on
+    pub fn set_client(&self, client: reqwest::Client) {
+        *self.client.write().unwrap_or_else(PoisonError::into_inner) = client;
+    }
+
+    fn client(&self) -> reqwest::Client {
+        self.client.read().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+}
+
+/// Borrowed view of one Ollama `/api/chat` NDJSON line; `content` only
+/// allocates when the JSON string contains escapes.
+#[derive(Deserialize)]
+struct OllamaChatLine<'a> {
+    #[serde(borrow, default)]
+    message: Option<OllamaChatMessage<'a>>,
+    #[serde(default)]
+    done: bool,
+    #[serde(borrow, default)]
+    done_reason: Option<Cow<'a, str>>,
+    /// Set instead of `message` when generation fails mid-stream
+    #[serde(borrow, default)]
+    error: Option<Cow<'a, str>>,
+    /// Prompt and generated token counts, on the `done` line
+    #[serde(default)]
+    prompt_eval_count: Option<u64>,
+    #[serde(default)]
+    eval_count: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct OllamaChatMessage<'a> {
+    #[serde(borrow, default)]
+    content: Cow<'a, str>,
+    #[serde(default)]
+    tool_calls: Vec<OllamaToolCall>,
+}
+
+/// Ollama nests each call under `function`, in requests and responses
+#[derive(Serialize, Deserialize)]
+struct OllamaToolCall {
+    function: ToolCall,
+}
+
+/// One message in Ollama's `/api/chat` request format
+fn ollama_message(m: &ChatMessage) -> serde_json::Value {
+    let mut message = serde_json::json!({ "role": m.role, "content": m.content });
+    if !m.tool_calls.is_empty() {
+        let calls: Vec<_> = m.tool_calls.iter().map(|c| OllamaToolCall { function: c.clone() }).collect();
+        message["tool_calls"] = serde_json::json!(calls);
+    }
+    message
+}
+
+/// Parse one Ollama `/api/chat` NDJSON line into a chunk
+pub fn parse_chat_line(line: &str, model: &Arc<str>) -> ModelResult<ChatChunk> {
+    let parsed: OllamaChatLine<'_> = serde_json::from_str(line)
+        .map_err(|e| ModelError::Other(format!("JSON parse error: {}", e)))?;
+    if let Some(error) = parsed.error {
+        return Err(ModelError::Upstream(error.into_owned()));
+    }
+    
+    let (content, tool_calls) = parsed
+        .message
+        .map(|m| (m.content.into_owned(), m.tool_calls.into_iter().map(|c| c.function).collect()))
+        .unwrap_or_default();
+    let finish_reason = parsed.done_reason
+        .filter(|_| parsed.done)
+        .map(Cow::into_owned);
+    // Ollama leaves `prompt_eval_count` out when the whole prompt was cached
+    let counted = parsed.prompt_eval_count.is_some() || parsed.eval_count.is_some();
+    let usage = (parsed.done && counted).then(|| Usage {
+        prompt_tokens: parsed.prompt_eval_count.unwrap_or(0),
+        completion_tokens: parsed.eval_count.unwrap_or(0),
+    });
+    
+    Ok(ChatChunk {
+        model: model.clone(),
+        content,
+        done: parsed.done,
+        finish_reason,
+        tool_calls,
+        usage,
+        ..Default::default()
+    })
 }
 
 #[async_trait::async_trait]
 impl ModelProvider for OllamaProvider {
+    fn name(&self) -> &'static str {
+        "ollama"
+    }
+
+    async fn detect(&self) -> ModelResult<Option<Detected>> {
+        #[derive(Deserialize)]
+        struct VersionResponse {
+            version: String,
+        }
+        let resp = self.send("/api/version", |client, url| client.get(url))
+            .await
+            .map_err(|e| ModelError::Upstream(e.to_string()))?;
+        if !resp.status().is_success() {
+            return Err(errors::status_error(resp).await);
+        }
+        let version = resp.json::<VersionResponse>().await.map_err(|e| ModelError::Upstream(e.to_string()))?.version;
+        let detected = Capabilities::ollama(&version).map(|capabilities| Detected { version, capabilities });
+        let mut last = self.detected.write().unwrap_or_else(PoisonError::into_inner);
+        if *last != detected {
+            match &detected {
+                Some(d) => {
+                    tracing::info!(version = %d.version, capabilities = ?d.capabilities, "ollama version detected")
+                }
+                None => tracing::warn!("ollama version unreadable; assuming every feature is supported"),
+            }
+        }
+        last.clone_from(&detected);
+        Ok(detected)
+    }
+
+    fn detected(&self) -> Option<Detected> {
+        self.detected.read().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+
     async fn list_models(&self) -> ModelResult<Vec<String>> {
-        let url = format!("{}/api/tags", self.base);
-        let resp = self.client
-            .get(&url)
-            .timeout(self.timeout)
-            .send()
+        let resp = self.send("/api/tags", |client, url| client.get(url))
             .await
             .map_err(|e| {
-                tracing::error!(error = %e, url = %url, "ollama list_models request failed");
+                tracing::error!(error = %e, "ollama list_models request failed");
                 ModelError::Upstream(e.to_string())
             })?;
         
         if !resp.status().is_success() {
             tracing::error!(status = %resp.status(), "ollama returned non-success status");
-            return Err(ModelError::Upstream(format!("HTTP {}", resp.status())));
+            return Err(errors::status_error(resp).await);
         }
         
         let v: serde_json::Value = resp.json().await.map_err(|e| {
@@ -78,86 +633,233 @@ impl ModelProvider for OllamaProvider {
     }
 
     async fn chat_stream(&self, req: ChatRequest) -> ModelResult<ChatStream> {
-        let url = format!("{}/api/chat", self.base);
-        let model = req.model.clone();
+        let model: Arc<str> = Arc::from(req.model.as_str());
+        if !req.tools.is_empty() && !self.capabilities().tools {
+            let version = self.detected().map(|d| d.version).unwrap_or_default();
+            let reason = format!("tools need Ollama 0.3.0 or later; the upstream runs {version}");
+            return Err(ModelError::Unsupported(reason));
+        }
         
         // Build Ollama-specific request body
-        let ollama_messages: Vec<serde_json::Value> = req.messages
-            .iter()
-            .map(|m| serde_json::json!({
-                "role": m.role,
-                "content": m.content,
-            }))
-            .collect();
+        let ollama_messages: Vec<serde_json::Value> = req.messages.iter().map(ollama_message).collect();
         
-        let body = serde_json::json!({
-            "model": model,
+        let mut body = serde_json::json!({
+            "model": &*model,
             "messages": ollama_messages,
             "stream": true,
         });
+        if !req.tools.is_empty() {
+            let tools: Vec<_> =
+                req.tools.iter().map(|t| serde_json::json!({"type": "function", "function": t})).collect();
+            body["tools"] = tools.into();
+        }
+        if !req.options.is_empty() {
+            let options: serde_json::Map<_, _> = req
+                .options
+                .given()
+                .map(|(name, value)| (if name == "max_tokens" { "num_predict" } else { name }.to_string(), value))
+                .collect();
+            body["options"] = options.into();
+        }
         
         tracing::debug!(model = %model, messages = req.messages.len(), "starting ollama chat stream");
         
-        let resp = self.client
-            .post(&url)
-            .json(&body)
-            .timeout(self.timeout)
-            .send()
+        let resp = self.send("/api/chat", |client, url| client.post(url).json(&body))
             .await
             .map_err(|e| {
-                tracing::error!(error = %e, url = %url, "ollama chat request failed");
+                tracing::error!(error = %e, "ollama chat request failed");
                 ModelError::Upstream(e.to_string())
             })?;
         
         if !resp.status().is_success() {
             tracing::error!(status = %resp.status(), "ollama chat returned non-success status");
-            return Err(ModelError::Upstream(format!("HTTP {}", resp.status())));
+            return Err(errors::status_error(resp).await);
         }
         
+        let mut captured = self.captured(resp.headers());
         let byte_stream = resp.bytes_stream();
         
         let stream = try_stream! {
             use futures_util::StreamExt;
             
-            let mut buffer = bytes::BytesMut::new();
+            let mut decoder = ndjson::NdjsonDecoder::new(ndjson::MAX_LINE_BYTES);
             tokio::pin!(byte_stream);
             
+            let mut finished = false;
             while let Some(chunk) = byte_stream.next().await {
                 let bytes = chunk.map_err(|e| ModelError::Upstream(e.to_string()))?;
-                buffer.extend_from_slice(&bytes);
+                decoder.push(&bytes);
                 
                 // Process complete JSON lines
-                while let Some(newline_pos) = buffer.iter().position(|&b| b == b'\n') {
-                    let line_bytes = buffer.split_to(newline_pos + 1);
-                    let line = String::from_utf8_lossy(&line_bytes[..line_bytes.len()-1]);
-                    
-                    if line.trim().is_empty() {
-                        continue;
-                    }
-                    
-                    let v: serde_json::Value = serde_json::from_str(&line)
-                        .map_err(|e| ModelError::Other(format!("JSON parse error: {}", e)))?;
-                    
-                    let content = v.get("message")
-                        .and_then(|m| m.get("content"))
-                        .and_then(|c| c.as_str())
-                        .unwrap_or("");
-                    
-                    let done = v.get("done").and_then(|d| d.as_bool()).unwrap_or(false);
-                    
-                    yield ChatChunk {
-                        model: model.clone(),
-                        content: content.to_string(),
-                        done,
-                    };
-                    
-                    if done {
+                while let Some(line) = decoder.next_line() {
+                    let mut chunk = parse_chat_line(&line, &model)?;
+                    chunk.upstream_headers = std::mem::take(&mut captured);
+                    finished = chunk.done;
+                    yield chunk;
+                    if finished {
                         break;
                     }
                 }
+                if finished {
+                    break;
+                }
+            }
+            
+            // Upstream may close without a trailing newline on the last line
+            if !finished {
+                if let Some(line) = decoder.finish() {
+                    let mut chunk = parse_chat_line(&line, &model)?;
+                    chunk.upstream_headers = captured;
+                    yield chunk;
+                }
             }
         };
         
         Ok(Box::pin(stream))
     }
+
+    async fn embed(&self, model: &str, input: &str) -> ModelResult<Vec<f32>> {
+        #[derive(Deserialize)]
+        struct EmbedResponse {
+            embeddings: Vec<Vec<f32>>,
+        }
+        let body = serde_json::json!({ "model": model, "input": input });
+        let resp = self.send("/api/embed", |client, url| client.post(url).json(&body))
+            .await
+            .map_err(|e| {
+                tracing::error!(error = %e, "ollama embed request failed");
+                ModelError::Upstream(e.to_string())
+            })?;
+        if !resp.status().is_success() {
+            return Err(errors::status_error(resp).await);
+        }
+        let parsed: EmbedResponse = resp.json().await.map_err(|e| ModelError::Upstream(e.to_string()))?;
+        parsed
+            .embeddings
+            .into_iter()
+            .next()
+            .ok_or_else(|| ModelError::Upstream("empty embeddings response".into()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::StreamExt;
+
+    fn chunk(content: &str, done: bool) -> ModelResult<ChatChunk> {
+        Ok(ChatChunk { model: "m".into(), content: content.into(), done, ..Default::default() })
+    }
+
+    #[test]
+    fn test_parse_chat_line() {
+        let model: Arc<str> = Arc::from("llama3");
+        let c = parse_chat_line(r#"{"model":"llama3","message":{"role":"assistant","content":"a \"q\""},"done":false}"#, &model).unwrap();
+        assert_eq!(c.content, "a \"q\"");
+        assert!(!c.done);
+        assert!(Arc::ptr_eq(&c.model, &model));
+        let c = parse_chat_line(r#"{"model":"llama3","message":{"role":"assistant","content":""},"done":true,"done_reason":"length","eval_count":3}"#, &model).unwrap();
+        assert!(c.done);
+        assert_eq!(c.finish_reason.as_deref(), Some("length"));
+        assert_eq!(c.usage, Some(Usage { prompt_tokens: 0, completion_tokens: 3 }));
+        assert!(parse_chat_line("{not json", &model).is_err());
+        assert!(matches!(
+            parse_chat_line(r#"{"error":"model runner crashed"}"#, &model),
+            Err(ModelError::Upstream(msg)) if msg == "model runner crashed"
+        ));
+    }
+
+    #[test]
+    fn test_only_configured_headers_are_captured() {
+        let provider = OllamaProvider::new("http://ollama", Duration::from_secs(1))
+            .with_captured_headers(["X-Model-Version", " x-ratelimit-remaining", "bad header"]);
+        let mut headers = reqwest::header::HeaderMap::new();
+        headers.insert("x-model-version", "2024-06".parse().unwrap());
+        headers.insert("x-internal", "secret".parse().unwrap());
+        let captured = provider.captured(&headers);
+        assert_eq!(captured.into_iter().collect::<Vec<_>>(), [("x-model-version".to_string(), "2024-06".to_string())]);
+    }
+
+    #[test]
+    fn test_ollama_capabilities_by_version() {
+        let old = Capabilities::ollama("0.2.8").unwrap();
+        assert!(!old.tools && old.keep_alive && old.format && !old.structured_outputs);
+        assert_eq!(Capabilities::ollama("0.5.7"), Some(Capabilities::ALL));
+        assert!(Capabilities::ollama("0.3.0-rc1").unwrap().tools);
+        assert_eq!(Capabilities::ollama("0.0.0"), None);
+        assert_eq!(Capabilities::ollama("main"), None);
+    }
+
+    #[tokio::test]
+    async fn test_tools_refused_before_sending_to_an_old_upstream() {
+        let provider = OllamaProvider::new("http://127.0.0.1:9", Duration::from_secs(1));
+        let capabilities = Capabilities::ollama("0.2.8").unwrap();
+        *provider.detected.write().unwrap() = Some(Detected { version: "0.2.8".into(), capabilities });
+        let tool = ToolSpec { name: "clock".into(), description: String::new(), parameters: serde_json::json!({}) };
+        let req = ChatRequest { model: "llama3".into(), tools: vec![tool], ..Default::default() };
+        assert!(matches!(provider.chat_stream(req).await, Err(ModelError::Unsupported(msg)) if msg.contains("0.2.8")));
+    }
+
+    async fn collect(items: Vec<ModelResult<ChatChunk>>) -> Vec<ModelResult<ChatChunk>> {
+        with_terminal_frame(Box::pin(futures_util::stream::iter(items)), "m").collect().await
+    }
+
+    #[tokio::test]
+    async fn test_terminal_frame_on_clean_finish() {
+        let out = collect(vec![chunk("a", false), chunk("", true), chunk("late", false)]).await;
+        assert_eq!(out.len(), 2);
+        let last = out[1].as_ref().unwrap();
+        assert!(last.done);
+        assert_eq!(last.finish_reason.as_deref(), Some(FINISH_STOP));
+    }
+
+    #[tokio::test]
+    async fn test_terminal_frame_after_error() {
+        let out = collect(vec![chunk("a", false), Err(ModelError::Other("bad json".into())), chunk("b", false)]).await;
+        assert_eq!(out.len(), 3);
+        assert!(out[1].is_err());
+        let last = out[2].as_ref().unwrap();
+        assert!(last.done);
+        assert_eq!(last.finish_reason.as_deref(), Some(FINISH_ERROR));
+    }
+
+    #[cfg(feature = "proptest")]
+    mod props {
+        use super::*;
+        use proptest::prelude::*;
+
+        proptest! {
+            #[test]
+            fn prop_parse_chat_line_never_panics(line in any::<String>()) {
+                let _ = parse_chat_line(&line, &Arc::from("m"));
+            }
+
+            #[test]
+            fn prop_parse_chat_line_roundtrips_content(
+                content in any::<String>(),
+                done in any::<bool>(),
+                reason in proptest::option::of("[a-z_]{1,12}"),
+            ) {
+                let line = serde_json::json!({
+                    "model": "m",
+                    "message": {"role": "assistant", "content": content},
+                    "done": done,
+                    "done_reason": reason,
+                })
+                .to_string();
+                let chunk = parse_chat_line(&line, &Arc::from("m")).unwrap();
+                prop_assert_eq!(&chunk.content, &content);
+                prop_assert_eq!(chunk.done, done);
+                prop_assert_eq!(chunk.finish_reason, reason.filter(|_| done));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_terminal_frame_on_premature_close() {
+        let out = collect(vec![chunk("a", false)]).await;
+        let last = out.last().unwrap().as_ref().unwrap();
+        assert!(last.done);
+        assert_eq!(last.finish_reason.as_deref(), Some(FINISH_ERROR));
+    }
 }
@@ -0,0 +1,212 @@
+use bytes::BytesMut;
+use tokio_util::codec::{Decoder, LinesCodec, LinesCodecError};
+
+/// Default cap on a single NDJSON line; longer lines are discarded
+pub const MAX_LINE_BYTES: usize = 1024 * 1024;
+
+/// Incremental NDJSON line splitter with bounded memory.
+///
+/// Bytes are pushed as they arrive; complete lines are yielded without the
+/// trailing `\n` / `\r\n`. Blank lines are skipped, lines longer than the cap
+/// (and non UTF-8 lines) are discarded with a warning instead of buffering
+/// without bound, and a final line lacking a newline is returned by `finish`.
+pub struct NdjsonDecoder {
+    codec: LinesCodec,
+    buf: BytesMut,
+    max_line_bytes: usize,
+}
+
+impl NdjsonDecoder {
+    pub fn new(max_line_bytes: usize) -> Self {
+        Self {
+            codec: LinesCodec::new_with_max_length(max_line_bytes),
+            buf: BytesMut::new(),
+            max_line_bytes,
+        }
+    }
+
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    /// Next complete, non-blank line buffered so far
+    pub fn next_line(&mut self) -> Option<String> {
+        loop {
+            let decoded = self.codec.decode(&mut self.buf);
+            match self.accept(decoded) {
+                Step::Line(line) => return Some(line),
+                Step::Skip => continue,
+                Step::Pending => return None,
+            }
+        }
+    }
+
+    /// Flush a trailing line left without a newline at end of stream
+    pub fn finish(&mut self) -> Option<String> {
+        loop {
+            let decoded = self.codec.decode_eof(&mut self.buf);
+            match self.accept(decoded) {
+                Step::Line(line) => return Some(line),
+                Step::Skip => continue,
+                Step::Pending => return None,
+            }
+        }
+    }
+
+    fn accept(&self, decoded: Result<Option<String>, LinesCodecError>) -> Step {
+        match decoded {
+            Ok(Some(line)) if line.trim().is_empty() => Step::Skip,
+            Ok(Some(line)) => Step::Line(line),
+            Ok(None) => Step::Pending,
+            Err(LinesCodecError::MaxLineLengthExceeded) => {
+                tracing::warn!(max = self.max_line_bytes, "ndjson line exceeds limit, discarding");
+                Step::Skip
+            }
+            Err(LinesCodecError::Io(e)) => {
+                tracing::warn!(error = %e, "invalid ndjson line, discarding");
+                Step::Skip
+            }
+        }
+    }
+}
+
+enum Step {
+    Line(String),
+    Skip,
+    Pending,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_all(pieces: &[&[u8]], max: usize) -> Vec<String> {
+        let mut decoder = NdjsonDecoder::new(max);
+        let mut out = Vec::new();
+        for piece in pieces {
+            decoder.push(piece);
+            while let Some(line) = decoder.next_line() {
+                out.push(line);
+            }
+        }
+        out.extend(decoder.finish());
+        out
+    }
+
+    /// Deterministic pseudo-random split points so failures are reproducible
+    fn splits(len: usize, seed: u64) -> Vec<usize> {
+        let mut state = seed;
+        let mut points = Vec::new();
+        let mut at = 0;
+        while at < len {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            at += 1 + (state >> 33) as usize % 7;
+            points.push(at.min(len));
+        }
+        points
+    }
+
+    const PAYLOAD: &str = "{\"a\":1}\n\n{\"b\":\"ü\"}\r\n{\"c\":3}";
+
+    #[test]
+    fn test_trailing_line_without_newline() {
+        let out = decode_all(&[PAYLOAD.as_bytes()], MAX_LINE_BYTES);
+        assert_eq!(out, vec!["{\"a\":1}", "{\"b\":\"ü\"}", "{\"c\":3}"]);
+    }
+
+    #[test]
+    fn test_every_two_way_split_yields_same_lines() {
+        let bytes = PAYLOAD.as_bytes();
+        let expected = decode_all(&[bytes], MAX_LINE_BYTES);
+        for i in 0..=bytes.len() {
+            let (a, b) = bytes.split_at(i);
+            assert_eq!(decode_all(&[a, b], MAX_LINE_BYTES), expected, "split at {i}");
+        }
+    }
+
+    #[test]
+    fn test_random_chunking_yields_same_lines() {
+        let payload = PAYLOAD.repeat(20);
+        let bytes = payload.as_bytes();
+        let expected = decode_all(&[bytes], MAX_LINE_BYTES);
+        for seed in 0..200 {
+            let mut pieces = Vec::new();
+            let mut start = 0;
+            for end in splits(bytes.len(), seed) {
+                pieces.push(&bytes[start..end]);
+                start = end;
+            }
+            assert_eq!(decode_all(&pieces, MAX_LINE_BYTES), expected, "seed {seed}");
+        }
+    }
+
+    #[test]
+    fn test_oversized_line_is_discarded() {
+        let big = format!("{{\"x\":\"{}\"}}\n", "y".repeat(500));
+        let input = format!("{{\"a\":1}}\n{big}{{\"b\":2}}\n");
+        for seed in 0..50 {
+            let bytes = input.as_bytes();
+            let mut pieces = Vec::new();
+            let mut start = 0;
+            for end in splits(bytes.len(), seed) {
+                pieces.push(&bytes[start..end]);
+                start = end;
+            }
+            assert_eq!(decode_all(&pieces, 64), vec!["{\"a\":1}", "{\"b\":2}"]);
+        }
+    }
+
+    #[test]
+    fn test_oversized_trailing_line_is_discarded() {
+        let input = format!("{{\"a\":1}}\n{}", "z".repeat(200));
+        assert_eq!(decode_all(&[input.as_bytes()], 64), vec!["{\"a\":1}"]);
+    }
+
+    #[cfg(feature = "proptest")]
+    mod props {
+        use super::*;
+        use proptest::prelude::*;
+
+        proptest! {
+            /// Any chunking of the same byte stream yields the same lines
+            #[test]
+            fn prop_chunking_is_transparent(
+                lines in prop::collection::vec("[^\r\n]{0,40}", 0..20),
+                crlf in any::<bool>(),
+                cuts in prop::collection::vec(any::<prop::sample::Index>(), 0..16),
+            ) {
+                let sep = if crlf { "\r\n" } else { "\n" };
+                let payload = lines.join(sep);
+                let bytes = payload.as_bytes();
+                let expected = decode_all(&[bytes], MAX_LINE_BYTES);
+
+                let mut points: Vec<usize> = cuts.iter().map(|i| i.index(bytes.len() + 1)).collect();
+                points.sort_unstable();
+                let mut pieces = Vec::new();
+                let mut start = 0;
+                for end in points.into_iter().chain([bytes.len()]) {
+                    pieces.push(&bytes[start..end]);
+                    start = end;
+                }
+                prop_assert_eq!(decode_all(&pieces, MAX_LINE_BYTES), expected);
+            }
+
+            #[test]
+            fn prop_arbitrary_bytes_never_panic(
+                pieces in prop::collection::vec(prop::collection::vec(any::<u8>(), 0..64), 0..16),
+            ) {
+                let pieces: Vec<&[u8]> = pieces.iter().map(Vec::as_slice).collect();
+                for line in decode_all(&pieces, 32) {
+                    prop_assert!(line.len() <= 32);
+                    prop_assert!(!line.trim().is_empty());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_invalid_utf8_line_is_skipped() {
+        let out = decode_all(&[b"{\"a\":1}\n\xff\xfe\n{\"b\":2}\n"], MAX_LINE_BYTES);
+        assert_eq!(out, vec!["{\"a\":1}", "{\"b\":2}"]);
+    }
+}
@@ -0,0 +1,316 @@
+//! OpenAI-compatible chat completions.
+//!
+//! vLLM, OpenRouter, LiteLLM, and most hosted gateways speak OpenAI's
+//! dialect rather than Ollama's: `POST {base}/chat/completions` streams
+//! server-sent events, one `data:` line of JSON per delta, ending with
+//! `data: [DONE]`. `base` is the API root including its version, e.g.
+//! `https://api.openai.com/v1` or `http://vllm:8000/v1`, as with OpenAI's
+//! own SDKs.
+//!
+//! Tool call arguments arrive as string fragments spread over deltas; they
+//! are joined and parsed once the completion finishes, so every call is
+//! reported on the final chunk.
+
+use crate::{
+    errors, sse, ChatChunk, ChatMessage, ChatRequest, ChatStream, ModelError, ModelProvider, ModelResult, ToolCall,
+    Usage,
+};
+use async_stream::try_stream;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::{collections::VecDeque, sync::Arc, time::Duration};
+
+pub struct OpenAiProvider {
+    base: String,
+    api_key: String,
+    client: reqwest::Client,
+    timeout: Duration,
+}
+
+impl OpenAiProvider {
+    /// `api_key` may be empty for servers that do not authenticate
+    pub fn new(base: impl Into<String>, api_key: impl Into<String>, client: reqwest::Client, timeout: Duration) -> Self {
+        let base = base.into().trim_end_matches('/').to_string();
+        Self { base, api_key: api_key.into(), client, timeout }
+    }
+
+    async fn send(&self, req: reqwest::RequestBuilder, what: &str) -> ModelResult<reqwest::Response> {
+        let req = if self.api_key.is_empty() { req } else { req.bearer_auth(&self.api_key) };
+        let resp = req.timeout(self.timeout).send().await.map_err(|e| {
+            tracing::error!(error = %e, "openai {what} request failed");
+            if e.is_timeout() { ModelError::Timeout } else { ModelError::Upstream(e.to_string()) }
+        })?;
+        if !resp.status().is_success() {
+            tracing::error!(status = %resp.status(), "openai {what} returned non-success status");
+            return Err(errors::status_error(resp).await);
+        }
+        Ok(resp)
+    }
+}
+
+/// Messages in the chat completions format. Calls get ids by position, and
+/// each tool message answers the oldest call not yet answered, the order
+/// tool results are sent back in.
+fn openai_messages(messages: &[ChatMessage]) -> Vec<Value> {
+    let mut unanswered = VecDeque::new();
+    let mut issued = 0;
+    messages
+        .iter()
+        .map(|m| {
+            let mut message = json!({ "role": m.role, "content": m.content });
+            if !m.tool_calls.is_empty() {
+                let calls: Vec<_> = m
+                    .tool_calls
+                    .iter()
+                    .map(|call| {
+                        issued += 1;
+                        let id = format!("call_{issued}");
+                        unanswered.push_back(id.clone());
+                        let function = json!({ "name": call.name, "arguments": call.arguments.to_string() });
+                        json!({ "id": id, "type": "function", "function": function })
+                    })
+                    .collect();
+                message["tool_calls"] = calls.into();
+            }
+            if m.role == "tool" {
+                if let Some(id) = unanswered.pop_front() {
+                    message["tool_call_id"] = id.into();
+                }
+            }
+            message
+        })
+        .collect()
+}
+
+/// One `data:` payload of a streamed completion; fields servers send as
+/// `null` are read as absent
+#[derive(Deserialize)]
+struct StreamEvent {
+    #[serde(default)]
+    choices: Vec<StreamChoice>,
+    /// Set instead of `choices` when generation fails mid-stream
+    #[serde(default)]
+    error: Option<errors::ErrorDetail>,
+    /// On a last event of its own, with no choices, when asked for
+    #[serde(default)]
+    usage: Option<Usage>,
+}
+
+#[derive(Deserialize)]
+struct StreamChoice {
+    #[serde(default)]
+    delta: Option<Delta>,
+    #[serde(default)]
+    finish_reason: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Delta {
+    #[serde(default)]
+    content: Option<String>,
+    #[serde(default)]
+    tool_calls: Option<Vec<DeltaToolCall>>,
+}
+
+#[derive(Deserialize)]
+struct DeltaToolCall {
+    #[serde(default)]
+    index: usize,
+    #[serde(default)]
+    function: Option<DeltaFunction>,
+}
+
+#[derive(Deserialize)]
+struct DeltaFunction {
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(default)]
+    arguments: Option<String>,
+}
+
+/// What a completion stream has said so far beyond its content
+#[derive(Default)]
+struct Completion {
+    /// Name and argument text of each call, by index
+    calls: Vec<(String, String)>,
+    finish_reason: Option<String>,
+    usage: Option<Usage>,
+}
+
+impl Completion {
+    /// Read one event's `data`; returns the content it adds, if any
+    fn push(&mut self, data: &str) -> ModelResult<Option<String>> {
+        let event: StreamEvent =
+            serde_json::from_str(data).map_err(|e| ModelError::Other(format!("JSON parse error: {e}")))?;
+        if let Some(error) = event.error {
+            return Err(ModelError::Upstream(error.message()));
+        }
+        if event.usage.is_some() {
+            self.usage = event.usage;
+        }
+        // One completion is asked for, so only the first choice counts
+        let Some(choice) = event.choices.into_iter().next() else { return Ok(None) };
+        if choice.finish_reason.is_some() {
+            self.finish_reason = choice.finish_reason;
+        }
+        let Some(delta) = choice.delta else { return Ok(None) };
+        for call in delta.tool_calls.unwrap_or_default() {
+            if self.calls.len() <= call.index {
+                self.calls.resize_with(call.index + 1, Default::default);
+            }
+            let Some(function) = call.function else { continue };
+            let (name, arguments) = &mut self.calls[call.index];
+            name.push_str(function.name.as_deref().unwrap_or_default());
+            arguments.push_str(function.arguments.as_deref().unwrap_or_default());
+        }
+        Ok(delta.content.filter(|c| !c.is_empty()))
+    }
+
+    fn finished(&self) -> bool {
+        self.finish_reason.is_some()
+    }
+
+    /// The terminal chunk, carrying the calls made. Arguments that are not
+    /// JSON are passed on as a string for the tool to refuse.
+    fn finish(self, model: &Arc<str>) -> ChatChunk {
+        let tool_calls = self
+            .calls
+            .into_iter()
+            .filter(|(name, _)| !name.is_empty())
+            .map(|(name, arguments)| {
+                let arguments = match arguments.trim() {
+                    "" => json!({}),
+                    text => serde_json::from_str(text).unwrap_or(Value::String(arguments)),
+                };
+                ToolCall { name, arguments }
+            })
+            .collect();
+        let (finish_reason, usage) = (self.finish_reason, self.usage);
+        ChatChunk { model: model.clone(), done: true, finish_reason, tool_calls, usage, ..Default::default() }
+    }
+}
+
+#[async_trait::async_trait]
+impl ModelProvider for OpenAiProvider {
+    fn name(&self) -> &'static str {
+        "openai"
+    }
+
+    async fn list_models(&self) -> ModelResult<Vec<String>> {
+        #[derive(Deserialize)]
+        struct Models {
+            data: Vec<Model>,
+        }
+        #[derive(Deserialize)]
+        struct Model {
+            id: String,
+        }
+        let resp = self.send(self.client.get(format!("{}/models", self.base)), "list_models").await?;
+        let models: Models = resp.json().await.map_err(|e| ModelError::Upstream(e.to_string()))?;
+        Ok(models.data.into_iter().map(|m| m.id).collect())
+    }
+
+    async fn chat_stream(&self, req: ChatRequest) -> ModelResult<ChatStream> {
+        let model: Arc<str> = Arc::from(req.model.as_str());
+        let mut body = json!({
+            "model": &*model,
+            "messages": openai_messages(&req.messages),
+            "stream": true,
+            // Token counts come in one more event after the finish reason
+            "stream_options": { "include_usage": true },
+        });
+        if !req.tools.is_empty() {
+            let tools: Vec<_> = req.tools.iter().map(|t| json!({ "type": "function", "function": t })).collect();
+            body["tools"] = tools.into();
+        }
+        // `top_k` is not OpenAI's, but vLLM and OpenRouter take it
+        for (name, value) in req.options.given() {
+            body[name] = value;
+        }
+        tracing::debug!(model = %model, messages = req.messages.len(), "starting openai chat stream");
+        let resp = self.send(self.client.post(format!("{}/chat/completions", self.base)).json(&body), "chat").await?;
+        let events = sse::data_lines(resp.bytes_stream());
+
+        let stream = try_stream! {
+            use futures_util::StreamExt;
+
+            let mut completion = Completion::default();
+            tokio::pin!(events);
+            while let Some(data) = events.next().await {
+                let data = data?;
+                if data.trim() == "[DONE]" {
+                    break;
+                }
+                if let Some(content) = completion.push(&data)? {
+                    yield ChatChunk { model: model.clone(), content, ..Default::default() };
+                }
+            }
+            if !completion.finished() {
+                Err(ModelError::Upstream("stream ended before the completion finished".into()))?;
+            }
+            yield completion.finish(&model);
+        };
+
+        Ok(Box::pin(stream))
+    }
+
+    async fn embed(&self, model: &str, input: &str) -> ModelResult<Vec<f32>> {
+        #[derive(Deserialize)]
+        struct Embeddings {
+            data: Vec<Embedding>,
+        }
+        #[derive(Deserialize)]
+        struct Embedding {
+            embedding: Vec<f32>,
+        }
+        let body = json!({ "model": model, "input": input });
+        let resp = self.send(self.client.post(format!("{}/embeddings", self.base)).json(&body), "embed").await?;
+        let parsed: Embeddings = resp.json().await.map_err(|e| ModelError::Upstream(e.to_string()))?;
+        parsed
+            .data
+            .into_iter()
+            .next()
+            .map(|e| e.embedding)
+            .ok_or_else(|| ModelError::Upstream("empty embeddings response".into()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tool_results_answer_calls_in_order() {
+        let call = |name: &str| ToolCall { name: name.into(), arguments: json!({ "x": 1 }) };
+        let message = |role: &str, calls| ChatMessage { role: role.into(), tool_calls: calls, ..Default::default() };
+        let messages = [
+            message("assistant", vec![call("a"), call("b")]),
+            message("tool", vec![]),
+            message("tool", vec![]),
+            message("assistant", vec![call("c")]),
+            message("tool", vec![]),
+        ];
+        let converted = openai_messages(&messages);
+        assert_eq!(converted[0]["tool_calls"][1]["id"], "call_2");
+        assert_eq!(converted[0]["tool_calls"][0]["function"]["arguments"], r#"{"x":1}"#);
+        let answered: Vec<_> = converted.iter().filter_map(|m| m.get("tool_call_id")).collect();
+        assert_eq!(answered, ["call_1", "call_2", "call_3"]);
+    }
+
+    #[test]
+    fn test_call_fragments_are_joined() {
+        let mut completion = Completion::default();
+        let fragments = [
+            r#"{"choices":[{"delta":{"tool_calls":[{"index":0,"id":"x","function":{"name":"calc","arguments":""}}]}}]}"#,
+            r#"{"choices":[{"delta":{"tool_calls":[{"index":0,"function":{"arguments":"{\"expr\":"}}]}}]}"#,
+            r#"{"choices":[{"delta":{"tool_calls":[{"index":0,"function":{"arguments":"\"6*7\"}"}}]}}]}"#,
+            r#"{"choices":[{"delta":{},"finish_reason":"tool_calls"}]}"#,
+        ];
+        for data in fragments {
+            assert_eq!(completion.push(data).unwrap(), None);
+        }
+        let done = completion.finish(&Arc::from("m"));
+        assert_eq!(done.tool_calls, [ToolCall { name: "calc".into(), arguments: json!({ "expr": "6*7" }) }]);
+        assert_eq!(done.finish_reason.as_deref(), Some("tool_calls"));
+    }
+}
@@ -0,0 +1,162 @@
+//! Several named model providers behind one.
+//!
+//! A [`ProviderRegistry`] is itself a [`ModelProvider`]: a model named
+//! `<provider>/<model>`, where `<provider>` is registered, goes to that
+//! provider as `<model>`; any other name (Ollama's own `namespace/model`
+//! included) goes to the default provider, the first registered, as is.
+//! Models of the default provider are listed without a prefix, those of the
+//! others with theirs.
+
+use crate::{ChatRequest, ChatStream, Detected, ModelError, ModelProvider, ModelResult};
+use std::sync::Arc;
+
+#[derive(Default)]
+pub struct ProviderRegistry {
+    /// Default first
+    providers: Vec<(&'static str, Arc<dyn ModelProvider>)>,
+}
+
+impl ProviderRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Serve models prefixed `name/` with `provider`; the first registered
+    /// is the default. A name registered again replaces the provider
+    pub fn with(mut self, name: &'static str, provider: Arc<dyn ModelProvider>) -> Self {
+        match self.providers.iter_mut().find(|(n, _)| *n == name) {
+            Some(entry) => entry.1 = provider,
+            None => self.providers.push((name, provider)),
+        }
+        self
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Arc<dyn ModelProvider>> {
+        self.providers.iter().find(|(n, _)| *n == name).map(|(_, p)| p)
+    }
+
+    fn default_provider(&self) -> ModelResult<&Arc<dyn ModelProvider>> {
+        self.providers.first().map(|(_, p)| p).ok_or_else(|| ModelError::Other("no model provider registered".into()))
+    }
+
+    /// The provider serving `model` and the name it knows the model by
+    fn resolve<'a>(&self, model: &'a str) -> ModelResult<(&Arc<dyn ModelProvider>, &'a str)> {
+        if let Some((prefix, rest)) = model.split_once('/') {
+            if let Some(provider) = self.get(prefix) {
+                return Ok((provider, rest));
+            }
+        }
+        Ok((self.default_provider()?, model))
+    }
+}
+
+#[async_trait::async_trait]
+impl ModelProvider for ProviderRegistry {
+    /// The default provider's
+    fn name(&self) -> &'static str {
+        self.providers.first().map_or("unknown", |(_, p)| p.name())
+    }
+
+    /// Detects every provider's version; returns the default's
+    async fn detect(&self) -> ModelResult<Option<Detected>> {
+        let mut default = None;
+        for (i, (name, provider)) in self.providers.iter().enumerate() {
+            match provider.detect().await {
+                Ok(detected) if i == 0 => default = detected,
+                Ok(_) => {}
+                Err(e) if i == 0 => return Err(e),
+                Err(e) => tracing::warn!(error = %e, provider = name, "version detection failed"),
+            }
+        }
+        Ok(default)
+    }
+
+    fn detected(&self) -> Option<Detected> {
+        self.default_provider().ok()?.detected()
+    }
+
+    fn route(&self, model: &str) -> Option<(Arc<dyn ModelProvider>, String)> {
+        let (provider, model) = self.resolve(model).ok()?;
+        Some((provider.clone(), model.to_string()))
+    }
+
+    fn routes(&self) -> Vec<&'static str> {
+        self.providers.iter().map(|(name, _)| *name).collect()
+    }
+
+    async fn list_models(&self) -> ModelResult<Vec<String>> {
+        let mut models = self.default_provider()?.list_models().await?;
+        for (name, provider) in self.providers.iter().skip(1) {
+            match provider.list_models().await {
+                Ok(listed) => models.extend(listed.into_iter().map(|m| format!("{name}/{m}"))),
+                // One backend down should not hide the others' models
+                Err(e) => tracing::warn!(error = %e, provider = name, "listing models failed"),
+            }
+        }
+        Ok(models)
+    }
+
+    async fn chat_stream(&self, req: ChatRequest) -> ModelResult<ChatStream> {
+        let (provider, model) = self.resolve(&req.model)?;
+        let model = model.to_string();
+        provider.chat_stream(ChatRequest { model, ..req }).await
+    }
+
+    async fn embed(&self, model: &str, input: &str) -> ModelResult<Vec<f32>> {
+        let (provider, model) = self.resolve(model)?;
+        provider.embed(model, input).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ChatChunk;
+    use futures_util::StreamExt;
+
+    /// Answers with its own name and the model it was asked for
+    struct Named(&'static str);
+
+    #[async_trait::async_trait]
+    impl ModelProvider for Named {
+        fn name(&self) -> &'static str {
+            self.0
+        }
+
+        async fn list_models(&self) -> ModelResult<Vec<String>> {
+            Ok(vec![format!("{}-model", self.0)])
+        }
+
+        async fn chat_stream(&self, req: ChatRequest) -> ModelResult<ChatStream> {
+            let chunk = ChatChunk { content: format!("{}:{}", self.0, req.model), done: true, ..Default::default() };
+            Ok(Box::pin(futures_util::stream::iter([Ok(chunk)])))
+        }
+    }
+
+    async fn answer(registry: &ProviderRegistry, model: &str) -> String {
+        let req = ChatRequest { model: model.into(), ..Default::default() };
+        let mut stream = registry.chat_stream(req).await.unwrap();
+        stream.next().await.unwrap().unwrap().content
+    }
+
+    #[tokio::test]
+    async fn test_models_route_by_registered_prefix() {
+        let registry =
+            ProviderRegistry::new().with("ollama", Arc::new(Named("ollama"))).with("openai", Arc::new(Named("openai")));
+        assert_eq!(answer(&registry, "llama3").await, "ollama:llama3");
+        assert_eq!(answer(&registry, "openai/gpt-4o").await, "openai:gpt-4o");
+        assert_eq!(answer(&registry, "ollama/llama3").await, "ollama:llama3");
+        // Not a registered prefix: an Ollama namespace
+        assert_eq!(answer(&registry, "hf.co/org/model").await, "ollama:hf.co/org/model");
+        assert_eq!(registry.list_models().await.unwrap(), ["ollama-model", "openai/openai-model"]);
+        assert_eq!(registry.routes(), ["ollama", "openai"]);
+        assert_eq!(registry.route("openai/gpt-4o").map(|(p, m)| (p.name(), m)), Some(("openai", "gpt-4o".into())));
+    }
+
+    #[tokio::test]
+    async fn test_empty_registry_refuses() {
+        let registry = ProviderRegistry::new();
+        assert!(registry.chat_stream(ChatRequest::default()).await.is_err());
+        assert_eq!(registry.route("llama3").map(|(p, _)| p.name()), None);
+    }
+}
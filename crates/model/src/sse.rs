@@ -0,0 +1,63 @@
+//! Server-sent events as OpenAI-compatible servers and Anthropic stream them.
+//!
+//! Both put each event's JSON on a single `data:` line, so lines are split
+//! like NDJSON ones (with the same bounds) and the `data` read from each;
+//! `event:` names repeat the JSON's own `type` and are skipped, as are
+//! comments such as the `: keep-alive` lines OpenRouter sends while waiting.
+
+use crate::{ndjson::{NdjsonDecoder, MAX_LINE_BYTES}, ModelError, ModelResult};
+use async_stream::try_stream;
+use bytes::Bytes;
+use futures_core::Stream;
+
+/// The `data` of an event-stream line, if it is one
+pub fn data(line: &str) -> Option<&str> {
+    let data = line.strip_prefix("data:")?;
+    Some(data.strip_prefix(' ').unwrap_or(data))
+}
+
+/// The `data` payloads of a response body, in order
+pub fn data_lines(
+    body: impl Stream<Item = reqwest::Result<Bytes>> + Send + 'static,
+) -> impl Stream<Item = ModelResult<String>> + Send {
+    try_stream! {
+        use futures_util::StreamExt;
+
+        let mut decoder = NdjsonDecoder::new(MAX_LINE_BYTES);
+        tokio::pin!(body);
+        while let Some(bytes) = body.next().await {
+            decoder.push(&bytes.map_err(|e| ModelError::Upstream(e.to_string()))?);
+            while let Some(line) = decoder.next_line() {
+                if let Some(payload) = data(&line) {
+                    yield payload.to_string();
+                }
+            }
+        }
+        // Upstream may close without a trailing newline on the last line
+        if let Some(payload) = decoder.finish().as_deref().and_then(data) {
+            yield payload.to_string();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::StreamExt;
+
+    #[test]
+    fn test_data_of_each_line() {
+        assert_eq!(data("data: {}"), Some("{}"));
+        assert_eq!(data("data:[DONE]"), Some("[DONE]"));
+        assert_eq!(data(": OPENROUTER PROCESSING"), None);
+        assert_eq!(data("event: message_stop"), None);
+    }
+
+    #[tokio::test]
+    async fn test_payloads_across_chunks() {
+        let pieces = ["event: ping\r\nda", "ta: {\"a\":1}\r\n\r\n: keep-alive\n\ndata: [DO", "NE]"];
+        let body = futures_util::stream::iter(pieces.map(|p| Ok(Bytes::from_static(p.as_bytes()))));
+        let payloads: Vec<_> = data_lines(body).map(Result::unwrap).collect().await;
+        assert_eq!(payloads, ["{\"a\":1}", "[DONE]"]);
+    }
+}
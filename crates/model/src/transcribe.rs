@@ -0,0 +1,178 @@
+//! Speech-to-text backends.
+//!
+//! Both supported servers take the audio as a `multipart/form-data` upload
+//! and answer with JSON carrying `text`: a whisper.cpp server (`/inference`)
+//! and any OpenAI-compatible API (`/v1/audio/transcriptions`).
+
+use crate::{ModelError, ModelResult};
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Audio to transcribe, as uploaded
+#[derive(Debug, Clone)]
+pub struct TranscriptionRequest {
+    pub audio: Bytes,
+    /// MIME type of `audio`, e.g. `audio/wav`
+    pub content_type: String,
+    /// Backend model; whisper.cpp serves the one model it was started with
+    pub model: String,
+    /// ISO-639-1 hint; detected when absent
+    pub language: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Transcription {
+    pub text: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
+    /// Length of the audio, when the backend reports it
+    #[serde(default, skip_serializing_if = "Option::is_none", alias = "duration")]
+    pub duration_secs: Option<f64>,
+}
+
+#[async_trait::async_trait]
+pub trait Transcriber: Send + Sync + 'static {
+    /// Short backend name for logs
+    fn name(&self) -> &'static str;
+    async fn transcribe(&self, req: TranscriptionRequest) -> ModelResult<Transcription>;
+}
+
+/// whisper.cpp `server` example (`POST /inference`)
+pub struct WhisperCppTranscriber {
+    base: String,
+    client: reqwest::Client,
+    timeout: Duration,
+}
+
+impl WhisperCppTranscriber {
+    pub fn new(base: impl Into<String>, client: reqwest::Client, timeout: Duration) -> Self {
+        Self { base: base.into(), client, timeout }
+    }
+}
+
+#[async_trait::async_trait]
+impl Transcriber for WhisperCppTranscriber {
+    fn name(&self) -> &'static str {
+        "whisper_cpp"
+    }
+
+    async fn transcribe(&self, req: TranscriptionRequest) -> ModelResult<Transcription> {
+        let mut form = Multipart::new(&req.audio);
+        form.text("response_format", "json");
+        form.text("language", req.language.as_deref().unwrap_or("auto"));
+        form.file("file", &req.content_type, &req.audio);
+        let resp = form
+            .attach(self.client.post(format!("{}/inference", self.base)))
+            .timeout(self.timeout)
+            .send()
+            .await;
+        read_transcription(resp, self.name()).await
+    }
+}
+
+/// OpenAI-compatible `POST /v1/audio/transcriptions`
+pub struct OpenAiTranscriber {
+    base: String,
+    api_key: String,
+    client: reqwest::Client,
+    timeout: Duration,
+}
+
+impl OpenAiTranscriber {
+    /// `api_key` may be empty for servers that do not authenticate
+    pub fn new(base: impl Into<String>, api_key: impl Into<String>, client: reqwest::Client, timeout: Duration) -> Self {
+        Self { base: base.into(), api_key: api_key.into(), client, timeout }
+    }
+}
+
+#[async_trait::async_trait]
+impl Transcriber for OpenAiTranscriber {
+    fn name(&self) -> &'static str {
+        "openai"
+    }
+
+    async fn transcribe(&self, req: TranscriptionRequest) -> ModelResult<Transcription> {
+        let mut form = Multipart::new(&req.audio);
+        form.text("model", &req.model);
+        form.text("response_format", "json");
+        if let Some(language) = &req.language {
+            form.text("language", language);
+        }
+        form.file("file", &req.content_type, &req.audio);
+        let mut builder = self.client.post(format!("{}/v1/audio/transcriptions", self.base));
+        if !self.api_key.is_empty() {
+            builder = builder.bearer_auth(&self.api_key);
+        }
+        let resp = form.attach(builder).timeout(self.timeout).send().await;
+        read_transcription(resp, self.name()).await
+    }
+}
+
+async fn read_transcription(
+    resp: Result<reqwest::Response, reqwest::Error>,
+    backend: &str,
+) -> ModelResult<Transcription> {
+    let resp = resp.map_err(|e| {
+        tracing::error!(error = %e, backend, "transcription request failed");
+        if e.is_timeout() { ModelError::Timeout } else { ModelError::Upstream(e.to_string()) }
+    })?;
+    let status = resp.status();
+    if !status.is_success() {
+        let body = resp.text().await.unwrap_or_default();
+        tracing::error!(%status, backend, "transcriber returned non-success status");
+        let detail: String = body.trim().chars().take(200).collect();
+        return Err(ModelError::Upstream(format!("HTTP {status}: {detail}")));
+    }
+    let mut transcription: Transcription =
+        resp.json().await.map_err(|e| ModelError::Upstream(format!("invalid transcription response: {e}")))?;
+    transcription.text = transcription.text.trim().to_string();
+    Ok(transcription)
+}
+
+/// A `multipart/form-data` body built by hand, so no multipart dependency
+/// is needed for two fixed-shape uploads
+struct Multipart {
+    boundary: String,
+    body: Vec<u8>,
+}
+
+impl Multipart {
+    /// A boundary that does not occur in `payload`
+    fn new(payload: &[u8]) -> Self {
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+        let boundary = loop {
+            let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos() as u64);
+            let candidate = format!("ds-{:016x}{:08x}", nanos, COUNTER.fetch_add(1, Ordering::Relaxed));
+            if !payload.windows(candidate.len()).any(|w| w == candidate.as_bytes()) {
+                break candidate;
+            }
+        };
+        Self { boundary, body: Vec::new() }
+    }
+
+    fn text(&mut self, name: &str, value: &str) {
+        let part = format!("--{}\r\nContent-Disposition: form-data; name=\"{name}\"\r\n\r\n{value}\r\n", self.boundary);
+        self.body.extend_from_slice(part.as_bytes());
+    }
+
+    fn file(&mut self, name: &str, content_type: &str, data: &[u8]) {
+        let head = format!(
+            "--{}\r\nContent-Disposition: form-data; name=\"{name}\"; filename=\"audio\"\r\nContent-Type: {content_type}\r\n\r\n",
+            self.boundary
+        );
+        self.body.extend_from_slice(head.as_bytes());
+        self.body.extend_from_slice(data);
+        self.body.extend_from_slice(b"\r\n");
+    }
+
+    fn attach(mut self, builder: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        self.body.extend_from_slice(format!("--{}--\r\n", self.boundary).as_bytes());
+        builder
+            .header(reqwest::header::CONTENT_TYPE, format!("multipart/form-data; boundary={}", self.boundary))
+            .body(self.body)
+    }
+}
@@ -0,0 +1,211 @@
+// Contract tests for the Anthropic Messages API wire format.
+//
+// Recorded `/v1/models` and `/v1/messages` transcripts under
+// `fixtures/anthropic` are replayed by a local HTTP server that streams the
+// body in small chunked-encoding pieces, the way the API flushes events.
+// When upstream changes its schema, record a new transcript and add a case.
+
+use ds_model::{
+    AnthropicProvider, ChatChunk, ChatMessage, ChatRequest, GenerationOptions, ModelError, ModelProvider, ToolCall,
+    ToolSpec, Usage,
+};
+use futures_util::StreamExt;
+use std::time::Duration;
+
+mod common;
+
+use common::{Reply, Requests};
+
+fn fixture(name: &str) -> Vec<u8> {
+    common::fixture("anthropic", name)
+}
+
+/// Serve one canned response for every request, streamed in `piece`-byte
+/// chunks; returns the base URL and the requests received so far.
+async fn mock_anthropic(status: u16, body: Vec<u8>, piece: usize) -> (String, Requests) {
+    common::serve(Reply::new(status, "text/event-stream", body).chunked(piece)).await
+}
+
+fn provider(base: &str) -> AnthropicProvider {
+    AnthropicProvider::new(base, "sk-ant-test", reqwest::Client::new(), Duration::from_secs(5), 1024)
+}
+
+fn request() -> ChatRequest {
+    ChatRequest {
+        model: "claude-3-5-haiku-20241022".into(),
+        messages: vec![ChatMessage { role: "user".into(), content: "Hi".into(), ..Default::default() }],
+        ..Default::default()
+    }
+}
+
+/// Replay a messages fixture split into `piece`-byte writes
+async fn replay_chat(name: &str, piece: usize) -> Vec<Result<ChatChunk, ModelError>> {
+    let (base, _) = mock_anthropic(200, fixture(name), piece).await;
+    let stream = provider(&base).chat_stream(request()).await.unwrap();
+    stream.collect().await
+}
+
+fn text(items: &[Result<ChatChunk, ModelError>]) -> String {
+    items.iter().filter_map(|i| i.as_ref().ok()).map(|c| c.content.as_str()).collect()
+}
+
+#[tokio::test]
+async fn test_models_lists_ids() {
+    let (base, recorded) = mock_anthropic(200, fixture("models.json"), 64).await;
+    let models = provider(&base).list_models().await.unwrap();
+    assert_eq!(models, vec!["claude-sonnet-4-20250514", "claude-3-5-haiku-20241022"]);
+    let req = recorded.lock().unwrap()[0].clone();
+    assert_eq!((req.method.as_str(), req.path.as_str()), ("GET", "/v1/models"));
+    assert_eq!(req.header("x-api-key"), Some("sk-ant-test"));
+    assert_eq!(req.header("anthropic-version"), Some("2023-06-01"));
+}
+
+#[tokio::test]
+async fn test_messages_request_shape() {
+    let (base, recorded) = mock_anthropic(200, fixture("messages_stop.sse"), 4096).await;
+    let call = ToolCall { name: "get_weather".into(), arguments: serde_json::json!({"city": "Paris"}) };
+    let mut req = request();
+    req.messages.insert(0, ChatMessage { role: "system".into(), content: "Be brief.".into(), ..Default::default() });
+    req.tools = vec![ToolSpec {
+        name: "get_weather".into(),
+        description: "Current weather".into(),
+        parameters: serde_json::json!({"type": "object"}),
+    }];
+    req.messages.push(ChatMessage { role: "assistant".into(), tool_calls: vec![call], ..Default::default() });
+    req.messages.push(ChatMessage { role: "tool".into(), content: "18C".into(), ..Default::default() });
+    let _ = provider(&base).chat_stream(req).await.unwrap().collect::<Vec<_>>().await;
+    let req = recorded.lock().unwrap()[0].clone();
+    assert_eq!((req.method.as_str(), req.path.as_str()), ("POST", "/v1/messages"));
+    assert_eq!(
+        req.json(),
+        serde_json::json!({
+            "model": "claude-3-5-haiku-20241022",
+            "max_tokens": 1024,
+            "system": "Be brief.",
+            "messages": [
+                {"role": "user", "content": [{"type": "text", "text": "Hi"}]},
+                {
+                    "role": "assistant",
+                    "content": [{"type": "tool_use", "id": "call_1", "name": "get_weather", "input": {"city": "Paris"}}],
+                },
+                {"role": "user", "content": [{"type": "tool_result", "tool_use_id": "call_1", "content": "18C"}]},
+            ],
+            "stream": true,
+            "tools": [{"name": "get_weather", "description": "Current weather", "input_schema": {"type": "object"}}],
+        })
+    );
+}
+
+fn options() -> GenerationOptions {
+    GenerationOptions {
+        temperature: Some(0.2),
+        top_p: Some(0.9),
+        top_k: Some(40),
+        max_tokens: Some(64),
+        seed: Some(7),
+        stop: vec!["\n\n".into()],
+    }
+}
+
+#[tokio::test]
+async fn test_messages_request_options_shape() {
+    let (base, recorded) = mock_anthropic(200, fixture("messages_stop.sse"), 4096).await;
+    let req = ChatRequest { options: options(), ..request() };
+    let _ = provider(&base).chat_stream(req).await.unwrap().collect::<Vec<_>>().await;
+    let body = recorded.lock().unwrap()[0].json();
+    // The requested cap replaces the configured one; there is no seed
+    assert_eq!((body["max_tokens"].clone(), body.get("seed")), (serde_json::json!(64), None));
+    assert_eq!(body["stop_sequences"], serde_json::json!(["\n\n"]));
+    let sampling = ["temperature", "top_p", "top_k"].map(|k| body[k].clone());
+    assert_eq!(sampling, [serde_json::json!(0.2), serde_json::json!(0.9), serde_json::json!(40)]);
+}
+
+#[tokio::test]
+async fn test_temperature_above_one_is_rejected_before_sending() {
+    let (base, recorded) = mock_anthropic(200, fixture("messages_stop.sse"), 4096).await;
+    let at = |temperature| ChatRequest {
+        options: GenerationOptions { temperature: Some(temperature), ..options() },
+        ..request()
+    };
+    let _ = provider(&base).chat_stream(at(1.0)).await.unwrap().collect::<Vec<_>>().await;
+    assert_eq!(recorded.lock().unwrap()[0].json()["temperature"], serde_json::json!(1.0));
+    let err = match provider(&base).chat_stream(at(1.5)).await {
+        Err(e) => e,
+        Ok(_) => panic!("expected a temperature above 1 to be rejected"),
+    };
+    assert!(matches!(err, ModelError::Rejected(_)), "{err}");
+    assert_eq!(err.client_message(), "temperature must be at most 1 for Anthropic models");
+    assert_eq!(recorded.lock().unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn test_messages_stop_transcript() {
+    // Byte-sized writes split every event line
+    for piece in [1, 7, 64, 4096] {
+        let items = replay_chat("messages_stop.sse", piece).await;
+        assert!(items.iter().all(Result::is_ok), "piece {piece}");
+        assert_eq!(text(&items), "Hello! How can I help you today?");
+        let last = items.last().unwrap().as_ref().unwrap();
+        assert!(last.done);
+        assert_eq!(last.finish_reason.as_deref(), Some("stop"));
+        assert_eq!(&*last.model, "claude-3-5-haiku-20241022");
+        // Input from `message_start`, output from the last `message_delta`
+        assert_eq!(last.usage, Some(Usage { prompt_tokens: 8, completion_tokens: 12 }));
+        assert_eq!(items.iter().filter(|i| i.as_ref().unwrap().done).count(), 1);
+    }
+}
+
+#[tokio::test]
+async fn test_messages_tool_use_blocks_become_calls() {
+    let items = replay_chat("messages_tool_use.sse", 7).await;
+    assert_eq!(text(&items), "Let me check the weather.");
+    let last = items.last().unwrap().as_ref().unwrap();
+    assert!(last.done);
+    assert_eq!(last.finish_reason.as_deref(), Some("tool_calls"));
+    let calls = [
+        ToolCall { name: "get_weather".into(), arguments: serde_json::json!({"city": "Paris"}) },
+        // A tool without parameters streams no input at all
+        ToolCall { name: "get_time".into(), arguments: serde_json::json!({}) },
+    ];
+    assert_eq!(last.tool_calls, calls);
+}
+
+#[tokio::test]
+async fn test_messages_midstream_error_surfaces() {
+    // CRLF line endings, and the connection closes after the error event
+    let items = replay_chat("messages_overloaded.sse", 32).await;
+    assert_eq!(text(&items), "Partial answer");
+    match items.last().unwrap() {
+        Err(e @ ModelError::Unavailable(msg)) => {
+            assert_eq!(msg, "Overloaded");
+            assert_eq!(e.client_message(), "the model provider is unavailable");
+        }
+        other => panic!("expected unavailable, got {other:?}"),
+    }
+}
+
+#[tokio::test]
+async fn test_messages_truncated_stream_errors() {
+    let items = replay_chat("messages_truncated.sse", 8).await;
+    assert_eq!(text(&items), "Cut");
+    assert!(items.last().unwrap().is_err());
+    assert!(items.iter().filter_map(|i| i.as_ref().ok()).all(|c| !c.done));
+}
+
+#[tokio::test]
+async fn test_messages_model_not_found() {
+    let (base, _) = mock_anthropic(404, fixture("error.json"), 64).await;
+    let err = match provider(&base).chat_stream(request()).await {
+        Err(e) => e,
+        Ok(_) => panic!("expected an error for a missing model"),
+    };
+    assert!(matches!(err, ModelError::ModelNotFound(ref m) if m == "model: claude-9"), "{err}");
+}
+
+#[tokio::test]
+async fn test_overloaded_status_is_unavailable() {
+    let body = br#"{"type":"error","error":{"type":"overloaded_error","message":"Overloaded"}}"#.to_vec();
+    let (base, _) = mock_anthropic(529, body, 64).await;
+    let err = provider(&base).list_models().await.unwrap_err();
+    assert!(matches!(err, ModelError::Unavailable(ref m) if m == "Overloaded"), "{err}");
+}
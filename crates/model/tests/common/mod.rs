@@ -0,0 +1,174 @@
+// Mock upstream shared by the contract suites.
+//
+// `serve` answers every request with a canned `Reply`, and `serve_routes`
+// answers by path. Replies can be streamed in small chunked-encoding pieces
+// the way upstreams flush tokens, and each request received is kept so the
+// wire format can be checked.
+
+// Each suite uses a different part of this module
+#![allow(dead_code)]
+
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Read `tests/fixtures/{dir}/{name}`
+pub fn fixture(dir: &str, name: &str) -> Vec<u8> {
+    let path = format!("{}/tests/fixtures/{dir}/{name}", env!("CARGO_MANIFEST_DIR"));
+    std::fs::read(&path).unwrap_or_else(|e| panic!("reading {path}: {e}"))
+}
+
+/// Request as seen by the mock server
+#[derive(Debug, Clone)]
+pub struct Recorded {
+    pub method: String,
+    /// Path and query
+    pub path: String,
+    headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Recorded {
+    /// Value of the header `name`, matched case-insensitively
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(k, _)| k.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str())
+    }
+
+    /// The body as JSON; `Null` when it is not
+    pub fn json(&self) -> Value {
+        serde_json::from_slice(&self.body).unwrap_or(Value::Null)
+    }
+
+    /// Value of the multipart field `name`
+    pub fn field(&self, name: &str) -> Option<String> {
+        let body = String::from_utf8_lossy(&self.body);
+        let marker = format!("name=\"{name}\"");
+        let part = body.split("\r\n--").find(|p| p.contains(&marker))?;
+        let (_, value) = part.split_once("\r\n\r\n")?;
+        Some(value.trim_end_matches("\r\n").to_string())
+    }
+}
+
+/// Every request the mock server has received, in order
+pub type Requests = Arc<Mutex<Vec<Recorded>>>;
+
+/// A canned response
+#[derive(Debug, Clone)]
+pub struct Reply {
+    status: u16,
+    content_type: &'static str,
+    body: Vec<u8>,
+    /// Chunked-encoding piece size, or `None` to send a content-length
+    piece: Option<usize>,
+}
+
+impl Reply {
+    pub fn new(status: u16, content_type: &'static str, body: Vec<u8>) -> Self {
+        Self { status, content_type, body, piece: None }
+    }
+
+    /// Stream the body in chunked-encoding pieces of `piece` bytes
+    pub fn chunked(self, piece: usize) -> Self {
+        Self { piece: Some(piece.max(1)), ..self }
+    }
+}
+
+/// Replies by path prefix, the longest matching prefix winning. A prefix
+/// with several replies serves them in turn and then repeats the last.
+pub type Routes = HashMap<&'static str, Vec<Reply>>;
+
+/// Serve `reply` to every request; returns the base URL and the requests
+/// received so far
+pub async fn serve(reply: Reply) -> (String, Requests) {
+    serve_routes(HashMap::from([("/", vec![reply])])).await
+}
+
+/// Serve `routes`, answering 404 where no prefix matches; returns the base
+/// URL and the requests received so far
+pub async fn serve_routes(routes: Routes) -> (String, Requests) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let base = format!("http://{}", listener.local_addr().unwrap());
+    let recorded = Requests::default();
+    let log = recorded.clone();
+    let routes = Arc::new(Mutex::new(routes));
+    tokio::spawn(async move {
+        loop {
+            let Ok((mut socket, _)) = listener.accept().await else { return };
+            let (routes, log) = (routes.clone(), log.clone());
+            tokio::spawn(async move {
+                let request = read_request(&mut socket).await;
+                let reply = next_reply(&mut routes.lock().unwrap(), &request.path);
+                log.lock().unwrap().push(request);
+                write_reply(&mut socket, &reply).await;
+            });
+        }
+    });
+    (base, recorded)
+}
+
+fn next_reply(routes: &mut Routes, path: &str) -> Reply {
+    let matching = routes.iter_mut().filter(|(prefix, _)| path.starts_with(**prefix));
+    match matching.max_by_key(|(prefix, _)| prefix.len()) {
+        Some((_, replies)) if replies.len() > 1 => replies.remove(0),
+        Some((_, replies)) => replies[0].clone(),
+        None => Reply::new(404, "text/plain", b"no route".to_vec()),
+    }
+}
+
+async fn read_request(socket: &mut TcpStream) -> Recorded {
+    let mut buf = Vec::new();
+    let mut tmp = [0u8; 4096];
+    let head_end = loop {
+        let n = socket.read(&mut tmp).await.unwrap();
+        buf.extend_from_slice(&tmp[..n]);
+        if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos + 4;
+        }
+        assert!(n > 0, "connection closed before headers");
+    };
+    let head = String::from_utf8_lossy(&buf[..head_end]).to_string();
+    let mut lines = head.lines();
+    let mut request_line = lines.next().unwrap().split_whitespace();
+    let method = request_line.next().unwrap().to_string();
+    let path = request_line.next().unwrap().to_string();
+    let headers: Vec<(String, String)> = lines
+        .filter_map(|l| l.split_once(':'))
+        .map(|(k, v)| (k.trim().to_string(), v.trim().to_string()))
+        .collect();
+    let content_length = headers
+        .iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, v)| v.parse::<usize>().ok())
+        .unwrap_or(0);
+    while buf.len() < head_end + content_length {
+        let n = socket.read(&mut tmp).await.unwrap();
+        assert!(n > 0, "connection closed before body");
+        buf.extend_from_slice(&tmp[..n]);
+    }
+    let body = buf[head_end..head_end + content_length].to_vec();
+    Recorded { method, path, headers, body }
+}
+
+async fn write_reply(socket: &mut TcpStream, reply: &Reply) {
+    let Reply { status, content_type, body, piece } = reply;
+    let framing = match piece {
+        Some(_) => "transfer-encoding: chunked".to_string(),
+        None => format!("content-length: {}", body.len()),
+    };
+    let head =
+        format!("HTTP/1.1 {status} Mock\r\ncontent-type: {content_type}\r\n{framing}\r\nconnection: close\r\n\r\n");
+    socket.write_all(head.as_bytes()).await.unwrap();
+    let Some(piece) = piece else {
+        socket.write_all(body).await.unwrap();
+        return;
+    };
+    for part in body.chunks(*piece) {
+        let frame = [format!("{:x}\r\n", part.len()).as_bytes(), part, b"\r\n"].concat();
+        socket.write_all(&frame).await.unwrap();
+        socket.flush().await.unwrap();
+        tokio::task::yield_now().await;
+    }
+    socket.write_all(b"0\r\n\r\n").await.unwrap();
+}
@@ -0,0 +1,122 @@
+// Contract tests for the image generation backends.
+//
+// Responses recorded under `fixtures/images` are served by a local HTTP
+// server that answers by path and keeps each request, so the payload every
+// backend sends and the multi-step ComfyUI exchange can be checked.
+
+use ds_model::images::{Automatic1111Images, ComfyUiImages, ImageProvider, ImageRequest, OpenAiImages};
+use ds_model::ModelError;
+use std::collections::HashMap;
+use std::time::Duration;
+
+mod common;
+
+use common::{Reply, Requests, Routes};
+
+const PNG: &[u8] = b"\x89PNG\r\n\x1a\nfake-image";
+
+fn fixture(name: &str) -> Vec<u8> {
+    common::fixture("images", name)
+}
+
+fn json(body: Vec<u8>) -> Reply {
+    Reply::new(200, "application/json", body)
+}
+
+async fn mock_backend(routes: Routes) -> (String, Requests) {
+    common::serve_routes(routes).await
+}
+
+fn request(n: u32) -> ImageRequest {
+    ImageRequest {
+        prompt: "a \"lighthouse\" at dusk".into(),
+        negative_prompt: Some("blurry".into()),
+        model: "dall-e-3".into(),
+        width: 768,
+        height: 512,
+        n,
+    }
+}
+
+#[tokio::test]
+async fn test_openai_requests_base64_images() {
+    let routes = HashMap::from([("/v1/images/generations", vec![json(fixture("openai.json"))])]);
+    let (base, recorded) = mock_backend(routes).await;
+    let provider = OpenAiImages::new(&base, "sk-test", reqwest::Client::new(), Duration::from_secs(5));
+    let images = provider.generate(request(2)).await.unwrap();
+    assert_eq!(images.len(), 2);
+    assert_eq!((&images[0].data[..], images[0].content_type.as_str()), (PNG, "image/png"));
+
+    let sent = recorded.lock().unwrap()[0].json();
+    assert_eq!(sent["model"], "dall-e-3");
+    assert_eq!(sent["size"], "768x512");
+    assert_eq!(sent["n"], 2);
+    assert_eq!(sent["response_format"], "b64_json");
+}
+
+#[tokio::test]
+async fn test_automatic1111_sends_txt2img_and_strips_data_urls() {
+    let routes = HashMap::from([("/sdapi/v1/txt2img", vec![json(fixture("automatic1111.json"))])]);
+    let (base, recorded) = mock_backend(routes).await;
+    let provider = Automatic1111Images::new(&base, reqwest::Client::new(), Duration::from_secs(5));
+    let images = provider.generate(request(2)).await.unwrap();
+    assert!(images.iter().all(|image| &image.data[..] == PNG));
+
+    let req = recorded.lock().unwrap()[0].clone();
+    assert_eq!((req.method.as_str(), req.path.as_str()), ("POST", "/sdapi/v1/txt2img"));
+    let sent = req.json();
+    assert_eq!((sent["width"].as_u64(), sent["height"].as_u64()), (Some(768), Some(512)));
+    assert_eq!(sent["negative_prompt"], "blurry");
+    assert_eq!(sent["batch_size"], 2);
+}
+
+#[tokio::test]
+async fn test_comfyui_queues_the_workflow_and_fetches_outputs() {
+    let workflow = String::from_utf8(fixture("comfyui_workflow.json")).unwrap();
+    let routes = HashMap::from([
+        ("/prompt", vec![json(br#"{"prompt_id":"pid-1","number":3}"#.to_vec())]),
+        // Still running on the first poll
+        ("/history/pid-1", vec![json(b"{}".to_vec()), json(fixture("comfyui_history.json"))]),
+        ("/view", vec![Reply::new(200, "image/png", PNG.to_vec())]),
+    ]);
+    let (base, recorded) = mock_backend(routes).await;
+    let provider = ComfyUiImages::new(&base, workflow, reqwest::Client::new(), Duration::from_secs(5));
+    // Placeholder syntax in the prompt itself is sent as typed
+    let req = ImageRequest { prompt: "a \"lighthouse\" at {{seed}}".into(), ..request(1) };
+    let images = provider.generate(req).await.unwrap();
+    assert_eq!(images.len(), 1);
+    assert_eq!((&images[0].data[..], images[0].content_type.as_str()), (PNG, "image/png"));
+
+    let requests = recorded.lock().unwrap().clone();
+    let queued = requests[0].json();
+    assert_eq!(queued["prompt"]["6"]["inputs"]["text"], "a \"lighthouse\" at {{seed}}");
+    assert_eq!(queued["prompt"]["7"]["inputs"]["text"], "blurry");
+    assert_eq!(queued["prompt"]["5"]["inputs"]["width"], 768);
+    assert!(queued["prompt"]["3"]["inputs"]["seed"].is_u64());
+    let polls = requests.iter().filter(|r| r.method == "GET" && r.path == "/history/pid-1").count();
+    assert_eq!(polls, 2);
+    let view = requests.last().unwrap();
+    assert_eq!(view.method, "GET");
+    assert_eq!(view.path, "/view?filename=ds_00001_.png&subfolder=&type=output");
+}
+
+#[tokio::test]
+async fn test_comfyui_rejects_a_broken_workflow() {
+    let provider =
+        ComfyUiImages::new("http://127.0.0.1:9", "{ not json", reqwest::Client::new(), Duration::from_secs(5));
+    assert!(matches!(provider.generate(request(1)).await, Err(ModelError::Other(_))));
+}
+
+#[tokio::test]
+async fn test_backend_errors_carry_status_and_detail() {
+    let body = br#"{"error":{"message":"content policy violation"}}"#.to_vec();
+    let (base, _) = common::serve(Reply::new(400, "application/json", body)).await;
+    let provider = OpenAiImages::new(&base, "", reqwest::Client::new(), Duration::from_secs(5));
+    match provider.generate(request(1)).await {
+        Err(ModelError::Upstream(message)) => {
+            assert!(message.starts_with("HTTP 400"), "{message}");
+            assert!(message.contains("content policy violation"), "{message}");
+        }
+        other => panic!("expected an upstream error, got {other:?}"),
+    }
+}
@@ -0,0 +1,278 @@
+// Contract tests for the Ollama wire format.
+//
+// Recorded `/api/tags`, `/api/chat`, and `/api/embed` transcripts under `fixtures/ollama`
+// are replayed by a local HTTP server that streams the body in small
+// chunked-encoding pieces, the way Ollama flushes tokens. When upstream
+// changes its schema, record a new transcript and add a case here.
+
+use ds_model::{
+    ChatChunk, ChatMessage, ChatRequest, GenerationOptions, ModelError, ModelProvider, OllamaProvider, ToolCall,
+    ToolSpec, UpstreamAuth, Usage,
+};
+use futures_util::StreamExt;
+use std::time::Duration;
+use tokio::net::TcpListener;
+
+mod common;
+
+use common::{Reply, Requests};
+
+fn fixture(name: &str) -> Vec<u8> {
+    common::fixture("ollama", name)
+}
+
+/// Serve one canned response for every request, streamed in `piece`-byte
+/// chunks; returns the base URL and the requests received so far.
+async fn mock_ollama(status: u16, body: Vec<u8>, piece: usize) -> (String, Requests) {
+    common::serve(Reply::new(status, "application/x-ndjson", body).chunked(piece)).await
+}
+
+fn provider(base: &str) -> OllamaProvider {
+    OllamaProvider::new(base, Duration::from_secs(5))
+}
+
+fn request() -> ChatRequest {
+    ChatRequest {
+        model: "llama3.2".into(),
+        messages: vec![ChatMessage { role: "user".into(), content: "Hi".into(), ..Default::default() }],
+        ..Default::default()
+    }
+}
+
+/// Replay a chat fixture split into `piece`-byte writes
+async fn replay_chat(name: &str, piece: usize) -> Vec<Result<ChatChunk, ModelError>> {
+    let (base, _) = mock_ollama(200, fixture(name), piece).await;
+    let stream = provider(&base).chat_stream(request()).await.unwrap();
+    stream.collect().await
+}
+
+fn text(items: &[Result<ChatChunk, ModelError>]) -> String {
+    items.iter().filter_map(|i| i.as_ref().ok()).map(|c| c.content.as_str()).collect()
+}
+
+#[tokio::test]
+async fn test_tags_lists_model_names() {
+    let (base, recorded) = mock_ollama(200, fixture("tags.json"), 64).await;
+    let models = provider(&base).list_models().await.unwrap();
+    assert_eq!(models, vec!["llama3.2:latest", "mistral:7b"]);
+    let req = recorded.lock().unwrap()[0].clone();
+    assert_eq!((req.method.as_str(), req.path.as_str()), ("GET", "/api/tags"));
+}
+
+#[tokio::test]
+async fn test_upstream_credentials_sent_on_every_request() {
+    let (base, recorded) = mock_ollama(200, fixture("tags.json"), 64).await;
+    let bearer = provider(&base).with_auth(UpstreamAuth::Bearer("s3cret".into()));
+    bearer.list_models().await.unwrap();
+    let _ = bearer.chat_stream(request()).await;
+    let basic = provider(&base).with_auth(UpstreamAuth::Basic { username: "ds".into(), password: "pw".into() });
+    basic.list_models().await.unwrap();
+
+    let recorded = recorded.lock().unwrap();
+    let seen: Vec<_> = recorded.iter().map(|r| r.header("authorization")).collect();
+    assert_eq!(seen, [Some("Bearer s3cret"), Some("Bearer s3cret"), Some("Basic ZHM6cHc=")]);
+    assert_eq!(format!("{:?}", UpstreamAuth::Bearer("s3cret".into())), "Bearer(********)");
+}
+
+#[tokio::test]
+async fn test_fails_over_to_next_endpoint() {
+    // Bound then dropped, so connections are refused
+    let dead = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let dead_base = format!("http://{}", dead.local_addr().unwrap());
+    drop(dead);
+    let (base, recorded) = mock_ollama(200, fixture("tags.json"), 64).await;
+    let provider = provider(&dead_base).with_fallbacks([base.clone()]);
+
+    assert_eq!(provider.list_models().await.unwrap().len(), 2);
+    assert_eq!(provider.active_endpoint(), base);
+    // Later requests start at the endpoint that answered
+    provider.list_models().await.unwrap();
+    assert_eq!(recorded.lock().unwrap().len(), 2);
+    assert!(!provider.fail_back().await);
+}
+
+#[tokio::test]
+async fn test_tags_empty() {
+    let (base, _) = mock_ollama(200, fixture("tags_empty.json"), 64).await;
+    assert!(provider(&base).list_models().await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn test_tags_error_status() {
+    let (base, _) = mock_ollama(500, br#"{"error":"internal"}"#.to_vec(), 64).await;
+    let err = provider(&base).list_models().await.unwrap_err();
+    assert!(matches!(err, ModelError::Upstream(ref m) if m.contains("500") && m.contains("internal")), "{err}");
+}
+
+#[tokio::test]
+async fn test_chat_request_shape() {
+    let (base, recorded) = mock_ollama(200, fixture("chat_stop.ndjson"), 4096).await;
+    let _ = provider(&base).chat_stream(request()).await.unwrap().collect::<Vec<_>>().await;
+    let req = recorded.lock().unwrap()[0].clone();
+    assert_eq!((req.method.as_str(), req.path.as_str()), ("POST", "/api/chat"));
+    assert_eq!(
+        req.json(),
+        serde_json::json!({
+            "model": "llama3.2",
+            "messages": [{"role": "user", "content": "Hi"}],
+            "stream": true,
+        })
+    );
+}
+
+#[tokio::test]
+async fn test_chat_request_with_tools_shape() {
+    let (base, recorded) = mock_ollama(200, fixture("chat_stop.ndjson"), 4096).await;
+    let call = ToolCall { name: "get_weather".into(), arguments: serde_json::json!({"city": "Paris"}) };
+    let mut req = request();
+    req.tools = vec![ToolSpec {
+        name: "get_weather".into(),
+        description: "Current weather".into(),
+        parameters: serde_json::json!({"type": "object"}),
+    }];
+    req.messages.push(ChatMessage { role: "assistant".into(), tool_calls: vec![call], ..Default::default() });
+    req.messages.push(ChatMessage { role: "tool".into(), content: "18C".into(), ..Default::default() });
+    let _ = provider(&base).chat_stream(req).await.unwrap().collect::<Vec<_>>().await;
+    let body = recorded.lock().unwrap()[0].json();
+    assert_eq!(
+        body["tools"],
+        serde_json::json!([{
+            "type": "function",
+            "function": {"name": "get_weather", "description": "Current weather", "parameters": {"type": "object"}},
+        }])
+    );
+    assert_eq!(
+        body["messages"][1],
+        serde_json::json!({
+            "role": "assistant",
+            "content": "",
+            "tool_calls": [{"function": {"name": "get_weather", "arguments": {"city": "Paris"}}}],
+        })
+    );
+    assert_eq!(body["messages"][2], serde_json::json!({"role": "tool", "content": "18C"}));
+}
+
+fn options() -> GenerationOptions {
+    GenerationOptions {
+        temperature: Some(0.2),
+        top_p: Some(0.9),
+        top_k: Some(40),
+        max_tokens: Some(64),
+        seed: Some(7),
+        stop: vec!["\n\n".into()],
+    }
+}
+
+#[tokio::test]
+async fn test_chat_request_options_shape() {
+    let (base, recorded) = mock_ollama(200, fixture("chat_stop.ndjson"), 4096).await;
+    let req = ChatRequest { options: options(), ..request() };
+    let _ = provider(&base).chat_stream(req).await.unwrap().collect::<Vec<_>>().await;
+    let body = recorded.lock().unwrap()[0].json();
+    assert_eq!(
+        body["options"],
+        serde_json::json!({
+            "temperature": 0.2, "top_p": 0.9, "top_k": 40, "num_predict": 64, "seed": 7, "stop": ["\n\n"],
+        })
+    );
+}
+
+#[tokio::test]
+async fn test_chat_stop_transcript() {
+    // Byte-sized writes split every JSON line and UTF-8 sequence
+    for piece in [1, 7, 64, 4096] {
+        let items = replay_chat("chat_stop.ndjson", piece).await;
+        assert!(items.iter().all(Result::is_ok), "piece {piece}");
+        assert_eq!(text(&items), "Hello! How can I help you today?");
+        let last = items.last().unwrap().as_ref().unwrap();
+        assert!(last.done);
+        assert_eq!(last.finish_reason.as_deref(), Some("stop"));
+        assert_eq!(&*last.model, "llama3.2");
+        assert_eq!(last.usage, Some(Usage { prompt_tokens: 26, completion_tokens: 5 }));
+        assert_eq!(items.iter().filter(|i| i.as_ref().unwrap().done).count(), 1);
+    }
+}
+
+#[tokio::test]
+async fn test_chat_length_transcript() {
+    let items = replay_chat("chat_length.ndjson", 16).await;
+    assert_eq!(text(&items), "Once upon a time");
+    assert_eq!(items.last().unwrap().as_ref().unwrap().finish_reason.as_deref(), Some("length"));
+}
+
+#[tokio::test]
+async fn test_chat_keepalive_lines_are_tolerated() {
+    // Blank lines, CRLF, and empty-content heartbeats while the model loads
+    let items = replay_chat("chat_keepalive.ndjson", 5).await;
+    assert!(items.iter().all(Result::is_ok));
+    assert_eq!(text(&items), "Ready.");
+    assert!(items.last().unwrap().as_ref().unwrap().done);
+}
+
+#[tokio::test]
+async fn test_chat_load_only_response() {
+    let items = replay_chat("chat_load.ndjson", 32).await;
+    assert_eq!(items.len(), 1);
+    let only = items[0].as_ref().unwrap();
+    assert!(only.done);
+    assert_eq!(only.finish_reason.as_deref(), Some("load"));
+    assert!(only.content.is_empty());
+}
+
+#[tokio::test]
+async fn test_chat_unknown_fields_are_ignored() {
+    let items = replay_chat("chat_extra_fields.ndjson", 3).await;
+    assert!(items.iter().all(Result::is_ok));
+    assert_eq!(text(&items), "It's 18°C in \"Paris\" 🌤");
+    assert!(items.last().unwrap().as_ref().unwrap().done);
+}
+
+#[tokio::test]
+async fn test_chat_tool_calls_are_parsed() {
+    let items = replay_chat("chat_extra_fields.ndjson", 7).await;
+    let calls: Vec<&ToolCall> = items.iter().flat_map(|i| &i.as_ref().unwrap().tool_calls).collect();
+    assert_eq!(calls, [&ToolCall { name: "get_weather".into(), arguments: serde_json::json!({"city": "Paris"}) }]);
+}
+
+#[tokio::test]
+async fn test_chat_midstream_error_surfaces() {
+    let items = replay_chat("chat_midstream_error.ndjson", 32).await;
+    assert_eq!(text(&items), "Partial answer");
+    match items.last().unwrap() {
+        Err(ModelError::Upstream(msg)) => assert!(msg.contains("unexpected EOF"), "{msg}"),
+        other => panic!("expected upstream error, got {other:?}"),
+    }
+}
+
+#[tokio::test]
+async fn test_chat_truncated_stream_errors() {
+    let items = replay_chat("chat_truncated.ndjson", 8).await;
+    assert_eq!(text(&items), "Cut");
+    assert!(items.last().unwrap().is_err());
+    assert!(items.iter().filter_map(|i| i.as_ref().ok()).all(|c| !c.done));
+}
+
+#[tokio::test]
+async fn test_chat_model_not_found() {
+    let (base, _) = mock_ollama(404, fixture("chat_model_not_found.json"), 64).await;
+    let err = match provider(&base).chat_stream(request()).await {
+        Err(e) => e,
+        Ok(_) => panic!("expected an error for a missing model"),
+    };
+    assert!(
+        matches!(err, ModelError::ModelNotFound(ref m) if m.contains("not found")),
+        "{err}"
+    );
+    // Ollama's message is about the request, so the client sees it
+    assert_eq!(err.client_message(), r#"model "nope" not found, try pulling it first"#);
+}
+
+#[tokio::test]
+async fn test_embed_request_and_response() {
+    let (base, recorded) = mock_ollama(200, fixture("embed.json"), 16).await;
+    let embedding = provider(&base).embed("nomic-embed-text", "Hi there").await.unwrap();
+    assert_eq!(embedding, [0.010071, -0.0017829, 0.050072, 0.1]);
+    let req = recorded.lock().unwrap()[0].clone();
+    assert_eq!((req.method.as_str(), req.path.as_str()), ("POST", "/api/embed"));
+    assert_eq!(req.json(), serde_json::json!({ "model": "nomic-embed-text", "input": "Hi there" }));
+}
@@ -0,0 +1,209 @@
+// Contract tests for the OpenAI chat completions wire format.
+//
+// Recorded `/models`, `/chat/completions`, and `/embeddings` transcripts
+// under `fixtures/openai` are replayed by a local HTTP server that streams
+// the body in small chunked-encoding pieces, the way servers flush events.
+// When upstream changes its schema, record a new transcript and add a case.
+
+use ds_model::{
+    ChatChunk, ChatMessage, ChatRequest, GenerationOptions, ModelError, ModelProvider, OpenAiProvider, ToolCall,
+    ToolSpec, Usage,
+};
+use futures_util::StreamExt;
+use std::time::Duration;
+
+mod common;
+
+use common::{Reply, Requests};
+
+fn fixture(name: &str) -> Vec<u8> {
+    common::fixture("openai", name)
+}
+
+/// Serve one canned response for every request, streamed in `piece`-byte
+/// chunks; returns the base URL (with its `/v1`) and the requests received
+/// so far.
+async fn mock_openai(status: u16, body: Vec<u8>, piece: usize) -> (String, Requests) {
+    let (base, recorded) = common::serve(Reply::new(status, "text/event-stream", body).chunked(piece)).await;
+    (format!("{base}/v1"), recorded)
+}
+
+fn provider(base: &str) -> OpenAiProvider {
+    OpenAiProvider::new(base, "sk-test", reqwest::Client::new(), Duration::from_secs(5))
+}
+
+fn request() -> ChatRequest {
+    ChatRequest {
+        model: "gpt-4o-mini".into(),
+        messages: vec![ChatMessage { role: "user".into(), content: "Hi".into(), ..Default::default() }],
+        ..Default::default()
+    }
+}
+
+/// Replay a chat fixture split into `piece`-byte writes
+async fn replay_chat(name: &str, piece: usize) -> Vec<Result<ChatChunk, ModelError>> {
+    let (base, _) = mock_openai(200, fixture(name), piece).await;
+    let stream = provider(&base).chat_stream(request()).await.unwrap();
+    stream.collect().await
+}
+
+fn text(items: &[Result<ChatChunk, ModelError>]) -> String {
+    items.iter().filter_map(|i| i.as_ref().ok()).map(|c| c.content.as_str()).collect()
+}
+
+#[tokio::test]
+async fn test_models_lists_ids() {
+    let (base, recorded) = mock_openai(200, fixture("models.json"), 64).await;
+    let models = provider(&base).list_models().await.unwrap();
+    assert_eq!(models, vec!["gpt-4o-mini", "meta-llama/Llama-3.1-8B-Instruct"]);
+    let req = recorded.lock().unwrap()[0].clone();
+    assert_eq!((req.method.as_str(), req.path.as_str()), ("GET", "/v1/models"));
+    assert_eq!(req.header("authorization"), Some("Bearer sk-test"));
+}
+
+#[tokio::test]
+async fn test_no_credentials_without_an_api_key() {
+    let (base, recorded) = mock_openai(200, fixture("models.json"), 64).await;
+    OpenAiProvider::new(&base, "", reqwest::Client::new(), Duration::from_secs(5)).list_models().await.unwrap();
+    assert_eq!(recorded.lock().unwrap()[0].header("authorization"), None);
+}
+
+#[tokio::test]
+async fn test_chat_request_shape() {
+    let (base, recorded) = mock_openai(200, fixture("chat_stop.sse"), 4096).await;
+    let call = ToolCall { name: "get_weather".into(), arguments: serde_json::json!({"city": "Paris"}) };
+    let mut req = request();
+    req.tools = vec![ToolSpec {
+        name: "get_weather".into(),
+        description: "Current weather".into(),
+        parameters: serde_json::json!({"type": "object"}),
+    }];
+    req.messages.push(ChatMessage { role: "assistant".into(), tool_calls: vec![call], ..Default::default() });
+    req.messages.push(ChatMessage { role: "tool".into(), content: "18C".into(), ..Default::default() });
+    let _ = provider(&base).chat_stream(req).await.unwrap().collect::<Vec<_>>().await;
+    let req = recorded.lock().unwrap()[0].clone();
+    assert_eq!((req.method.as_str(), req.path.as_str()), ("POST", "/v1/chat/completions"));
+    assert_eq!(
+        req.json(),
+        serde_json::json!({
+            "model": "gpt-4o-mini",
+            "messages": [
+                {"role": "user", "content": "Hi"},
+                {
+                    "role": "assistant",
+                    "content": "",
+                    "tool_calls": [{
+                        "id": "call_1",
+                        "type": "function",
+                        "function": {"name": "get_weather", "arguments": "{\"city\":\"Paris\"}"},
+                    }],
+                },
+                {"role": "tool", "content": "18C", "tool_call_id": "call_1"},
+            ],
+            "stream": true,
+            "stream_options": {"include_usage": true},
+            "tools": [{
+                "type": "function",
+                "function": {"name": "get_weather", "description": "Current weather", "parameters": {"type": "object"}},
+            }],
+        })
+    );
+}
+
+fn options() -> GenerationOptions {
+    GenerationOptions {
+        temperature: Some(0.2),
+        top_p: Some(0.9),
+        top_k: Some(40),
+        max_tokens: Some(64),
+        seed: Some(7),
+        stop: vec!["\n\n".into()],
+    }
+}
+
+#[tokio::test]
+async fn test_chat_request_options_shape() {
+    let (base, recorded) = mock_openai(200, fixture("chat_stop.sse"), 4096).await;
+    let req = ChatRequest { options: options(), ..request() };
+    let _ = provider(&base).chat_stream(req).await.unwrap().collect::<Vec<_>>().await;
+    let body = recorded.lock().unwrap()[0].json();
+    let sent = ["temperature", "top_p", "top_k", "max_tokens", "seed", "stop"].map(|k| body[k].clone());
+    assert_eq!(serde_json::json!(sent), serde_json::json!([0.2, 0.9, 40, 64, 7, ["\n\n"]]));
+}
+
+#[tokio::test]
+async fn test_chat_stop_transcript() {
+    // Byte-sized writes split every event line
+    for piece in [1, 7, 64, 4096] {
+        let items = replay_chat("chat_stop.sse", piece).await;
+        assert!(items.iter().all(Result::is_ok), "piece {piece}");
+        assert_eq!(text(&items), "Hello! How can I help you today?");
+        let last = items.last().unwrap().as_ref().unwrap();
+        assert!(last.done);
+        assert_eq!(last.finish_reason.as_deref(), Some("stop"));
+        assert_eq!(&*last.model, "gpt-4o-mini");
+        // From the event after the finish reason
+        assert_eq!(last.usage, Some(Usage { prompt_tokens: 9, completion_tokens: 9 }));
+        assert_eq!(items.iter().filter(|i| i.as_ref().unwrap().done).count(), 1);
+    }
+}
+
+#[tokio::test]
+async fn test_chat_finishes_without_done_marker() {
+    // CRLF line endings, and the connection closes after the finishing event
+    let items = replay_chat("chat_length_no_done.sse", 16).await;
+    assert_eq!(text(&items), "Once upon a time");
+    assert_eq!(items.last().unwrap().as_ref().unwrap().finish_reason.as_deref(), Some("length"));
+}
+
+#[tokio::test]
+async fn test_chat_tool_call_fragments_are_joined() {
+    let items = replay_chat("chat_tool_calls.sse", 7).await;
+    let last = items.last().unwrap().as_ref().unwrap();
+    assert!(last.done);
+    assert_eq!(last.finish_reason.as_deref(), Some("tool_calls"));
+    let call = ToolCall { name: "get_weather".into(), arguments: serde_json::json!({"city": "Paris"}) };
+    assert_eq!(last.tool_calls, [call]);
+    assert_eq!(text(&items), "");
+}
+
+#[tokio::test]
+async fn test_chat_midstream_error_surfaces() {
+    let items = replay_chat("chat_midstream_error.sse", 32).await;
+    assert_eq!(text(&items), "Partial answer");
+    match items.last().unwrap() {
+        Err(ModelError::Upstream(msg)) => assert!(msg.contains("connection reset"), "{msg}"),
+        other => panic!("expected upstream error, got {other:?}"),
+    }
+}
+
+#[tokio::test]
+async fn test_chat_truncated_stream_errors() {
+    let items = replay_chat("chat_truncated.sse", 8).await;
+    assert_eq!(text(&items), "Cut");
+    assert!(items.last().unwrap().is_err());
+    assert!(items.iter().filter_map(|i| i.as_ref().ok()).all(|c| !c.done));
+}
+
+#[tokio::test]
+async fn test_chat_model_not_found() {
+    let (base, _) = mock_openai(404, fixture("error.json"), 64).await;
+    let err = match provider(&base).chat_stream(request()).await {
+        Err(e) => e,
+        Ok(_) => panic!("expected an error for a missing model"),
+    };
+    assert!(
+        matches!(err, ModelError::ModelNotFound(ref m) if m.contains("does not exist")),
+        "{err}"
+    );
+}
+
+#[tokio::test]
+async fn test_embed_request_and_response() {
+    let (base, recorded) = mock_openai(200, fixture("embeddings.json"), 16).await;
+    let embedding = provider(&base).embed("text-embedding-3-small", "Hi there").await.unwrap();
+    assert_eq!(embedding, [0.010071, -0.0017829, 0.050072, 0.1]);
+    let req = recorded.lock().unwrap()[0].clone();
+    assert_eq!((req.method.as_str(), req.path.as_str()), ("POST", "/v1/embeddings"));
+    assert_eq!(req.json(), serde_json::json!({ "model": "text-embedding-3-small", "input": "Hi there" }));
+}
@@ -0,0 +1,79 @@
+// Contract tests for the speech-to-text backends.
+//
+// Responses recorded under `fixtures/transcribe` are served by a local
+// HTTP server that keeps the raw request, so the multipart upload each
+// backend expects can be checked field by field.
+
+use ds_model::transcribe::{OpenAiTranscriber, Transcriber, TranscriptionRequest, WhisperCppTranscriber};
+use ds_model::ModelError;
+use std::time::Duration;
+
+mod common;
+
+use common::{Reply, Requests};
+
+fn fixture(name: &str) -> Vec<u8> {
+    common::fixture("transcribe", name)
+}
+
+async fn mock_backend(status: u16, body: Vec<u8>) -> (String, Requests) {
+    common::serve(Reply::new(status, "application/json", body)).await
+}
+
+fn request(language: Option<&str>) -> TranscriptionRequest {
+    TranscriptionRequest {
+        audio: bytes::Bytes::from_static(b"RIFF\x00\x01fake-wave\r\n--data"),
+        content_type: "audio/wav".into(),
+        model: "whisper-1".into(),
+        language: language.map(Into::into),
+    }
+}
+
+#[tokio::test]
+async fn test_whisper_cpp_uploads_to_inference() {
+    let (base, recorded) = mock_backend(200, fixture("whisper_cpp.json")).await;
+    let transcriber = WhisperCppTranscriber::new(&base, reqwest::Client::new(), Duration::from_secs(5));
+    let out = transcriber.transcribe(request(None)).await.unwrap();
+    assert_eq!(out.text, "Ship the release on Friday.");
+    assert_eq!(out.duration_secs, None);
+
+    let req = recorded.lock().unwrap()[0].clone();
+    assert_eq!((req.method.as_str(), req.path.as_str()), ("POST", "/inference"));
+    assert_eq!(req.field("response_format").as_deref(), Some("json"));
+    assert_eq!(req.field("language").as_deref(), Some("auto"));
+    assert_eq!(req.field("model"), None);
+    // The audio goes through byte for byte, even where it looks like a boundary
+    let audio = request(None).audio;
+    assert!(req.body.windows(audio.len()).any(|w| w == &audio[..]));
+    let content_type = req.header("content-type").unwrap_or_default();
+    assert!(content_type.starts_with("multipart/form-data; boundary=ds-"), "{content_type}");
+}
+
+#[tokio::test]
+async fn test_openai_sends_model_language_and_key() {
+    let (base, recorded) = mock_backend(200, fixture("openai.json")).await;
+    let transcriber = OpenAiTranscriber::new(&base, "sk-test", reqwest::Client::new(), Duration::from_secs(5));
+    let out = transcriber.transcribe(request(Some("en"))).await.unwrap();
+    assert_eq!(out.text, "Ship the release on Friday.");
+    assert_eq!((out.language.as_deref(), out.duration_secs), (Some("english"), Some(2.48)));
+
+    let req = recorded.lock().unwrap()[0].clone();
+    assert_eq!((req.method.as_str(), req.path.as_str()), ("POST", "/v1/audio/transcriptions"));
+    assert_eq!(req.header("authorization"), Some("Bearer sk-test"));
+    assert_eq!(req.field("model").as_deref(), Some("whisper-1"));
+    assert_eq!(req.field("language").as_deref(), Some("en"));
+    assert!(req.body.ends_with(b"--\r\n"));
+}
+
+#[tokio::test]
+async fn test_backend_errors_carry_status_and_detail() {
+    let (base, _) = mock_backend(400, br#"{"error":{"message":"unsupported format"}}"#.to_vec()).await;
+    let transcriber = OpenAiTranscriber::new(&base, "", reqwest::Client::new(), Duration::from_secs(5));
+    match transcriber.transcribe(request(None)).await {
+        Err(ModelError::Upstream(message)) => {
+            assert!(message.starts_with("HTTP 400"), "{message}");
+            assert!(message.contains("unsupported format"), "{message}");
+        }
+        other => panic!("expected an upstream error, got {other:?}"),
+    }
+}
@@ -0,0 +1,130 @@
+//! Email as a channel, over a pluggable [`Mailer`]
+
+use crate::{Channel, DeliveryError, Notification, Recipient, Rendered, EMAIL};
+use async_trait::async_trait;
+use lettre::{
+    message::{header::ContentType, Mailbox},
+    AsyncFileTransport, AsyncTransport, Message, Tokio1Executor,
+};
+use std::{path::Path, sync::Arc};
+
+#[derive(Debug, Clone)]
+pub struct Email {
+    pub from: String,
+    pub to: String,
+    pub subject: String,
+    pub text: String,
+}
+
+/// Sends one email
+#[async_trait]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, email: &Email) -> Result<(), DeliveryError>;
+}
+
+/// Writes each email to the log instead of sending it, for development
+pub struct LogMailer;
+
+#[async_trait]
+impl Mailer for LogMailer {
+    async fn send(&self, email: &Email) -> Result<(), DeliveryError> {
+        tracing::info!(from = %email.from, to = %email.to, subject = %email.subject, text = %email.text, "email");
+        Ok(())
+    }
+}
+
+/// The MIME message for `email`; an address that does not parse fails
+/// for good
+pub(crate) fn message(email: &Email) -> Result<Message, DeliveryError> {
+    let mailbox = |address: &str| {
+        address.parse::<Mailbox>().map_err(|e| DeliveryError::Permanent(format!("invalid address {address}: {e}")))
+    };
+    Message::builder()
+        .from(mailbox(&email.from)?)
+        .to(mailbox(&email.to)?)
+        .subject(&email.subject)
+        .header(ContentType::TEXT_PLAIN)
+        .body(email.text.clone())
+        .map_err(|e| DeliveryError::Permanent(e.to_string()))
+}
+
+/// Writes each email to a `.eml` file in a directory instead of sending
+/// it, for development
+pub struct FileMailer {
+    transport: AsyncFileTransport<Tokio1Executor>,
+}
+
+impl FileMailer {
+    pub fn new(dir: impl AsRef<Path>) -> Self {
+        Self { transport: AsyncFileTransport::new(dir) }
+    }
+}
+
+#[async_trait]
+impl Mailer for FileMailer {
+    async fn send(&self, email: &Email) -> Result<(), DeliveryError> {
+        let id = self.transport.send(message(email)?).await.map_err(|e| DeliveryError::Retryable(e.to_string()))?;
+        tracing::debug!(to = %email.to, subject = %email.subject, file = %format!("{id}.eml"), "email written");
+        Ok(())
+    }
+}
+
+pub struct EmailChannel {
+    from: String,
+    mailer: Arc<dyn Mailer>,
+}
+
+impl EmailChannel {
+    pub fn new(from: impl Into<String>, mailer: Arc<dyn Mailer>) -> Self {
+        Self { from: from.into(), mailer }
+    }
+}
+
+#[async_trait]
+impl Channel for EmailChannel {
+    fn name(&self) -> &'static str {
+        EMAIL
+    }
+
+    fn address<'a>(&self, to: &'a Recipient) -> Option<&'a str> {
+        to.email.as_deref()
+    }
+
+    async fn deliver(&self, address: &str, _: &Notification, rendered: &Rendered) -> Result<(), DeliveryError> {
+        let email = Email {
+            from: self.from.clone(),
+            to: address.to_string(),
+            subject: rendered.subject.clone(),
+            text: rendered.text.clone(),
+        };
+        self.mailer.send(&email).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn email(to: &str) -> Email {
+        Email {
+            from: "DeeperSensor <no-reply@example.com>".into(),
+            to: to.into(),
+            subject: "Reset your password".into(),
+            text: "Open this link".into(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_file_mailer_writes_one_message_per_email() {
+        let dir = std::env::temp_dir().join(format!("ds-notify-mail-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        FileMailer::new(&dir).send(&email("ada@example.com")).await.unwrap();
+        let written: Vec<_> = std::fs::read_dir(&dir).unwrap().map(|e| e.unwrap().path()).collect();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(written.len(), 1);
+        assert_eq!(written[0].extension().and_then(|e| e.to_str()), Some("eml"));
+
+        let refused = FileMailer::new(&dir).send(&email("not an address")).await;
+        assert!(matches!(refused, Err(DeliveryError::Permanent(_))));
+    }
+}
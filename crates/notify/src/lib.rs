@@ -0,0 +1,406 @@
+//! Notifications to users about their account and usage.
+//!
+//! A [`Notification`] is one of a fixed set of [`Kind`]s with the values
+//! its template needs. The [`Notifier`] renders it and hands it to every
+//! requested [`Channel`] the recipient has an address for, in the
+//! background, retrying failed deliveries with exponential backoff. A
+//! webhook that still fails after its last attempt is reported to the
+//! recipient by email as a `webhook.failed` notification. Subjects and
+//! texts come from [`Templates`], in the recipient's locale.
+
+use async_trait::async_trait;
+use ds_types::UserId;
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::{sync::Arc, time::Duration};
+
+mod email;
+mod smtp;
+pub mod templates;
+
+pub use email::{Email, EmailChannel, FileMailer, LogMailer, Mailer};
+pub use smtp::{DkimSettings, MailerError, SmtpMailer, SmtpSettings, SmtpTls};
+pub use templates::{Rendered, TemplateError, Templates};
+
+/// Channel names, as used in preferences
+pub const EMAIL: &str = "email";
+pub const WEBHOOK: &str = "webhook";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Kind {
+    /// Confirm an email address
+    Verification,
+    PasswordReset,
+    /// Daily token usage passed a threshold
+    QuotaWarning,
+    /// A webhook of the recipient's could not be delivered
+    WebhookFailed,
+}
+
+impl Kind {
+    pub const ALL: [Kind; 4] = [Kind::Verification, Kind::PasswordReset, Kind::QuotaWarning, Kind::WebhookFailed];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Kind::Verification => "account.verification",
+            Kind::PasswordReset => "account.password_reset",
+            Kind::QuotaWarning => "quota.warning",
+            Kind::WebhookFailed => "webhook.failed",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Kind> {
+        Kind::ALL.into_iter().find(|k| k.as_str() == name)
+    }
+
+    /// Account security messages always go by email and cannot be
+    /// redirected or turned off
+    pub fn mandatory(self) -> bool {
+        matches!(self, Kind::Verification | Kind::PasswordReset)
+    }
+
+    /// Channels used when the recipient has not chosen
+    pub fn default_channels(self) -> &'static [&'static str] {
+        match self {
+            Kind::QuotaWarning => &[EMAIL, WEBHOOK],
+            _ => &[EMAIL],
+        }
+    }
+}
+
+/// A kind and the values its template refers to
+#[derive(Debug, Clone)]
+pub struct Notification {
+    pub kind: Kind,
+    pub vars: Map<String, Value>,
+}
+
+impl Notification {
+    pub fn new(kind: Kind) -> Self {
+        Self { kind, vars: Map::new() }
+    }
+
+    pub fn var(mut self, name: &str, value: impl Into<Value>) -> Self {
+        self.vars.insert(name.into(), value.into());
+        self
+    }
+
+    /// `link` confirms the address
+    pub fn verification(link: &str) -> Self {
+        Self::new(Kind::Verification).var("link", link)
+    }
+
+    /// `link` sets a new password
+    pub fn password_reset(link: &str) -> Self {
+        Self::new(Kind::PasswordReset).var("link", link)
+    }
+
+    pub fn quota_warning(threshold_percent: u64, used_tokens: u64, daily_tokens: u64) -> Self {
+        Self::new(Kind::QuotaWarning)
+            .var("threshold_percent", threshold_percent)
+            .var("used_tokens", used_tokens)
+            .var("daily_tokens", daily_tokens)
+    }
+
+    /// `source` names what was being delivered, such as a schedule
+    pub fn webhook_failed(source: &str, url: &str, error: &str) -> Self {
+        Self::new(Kind::WebhookFailed).var("source", source).var("url", url).var("error", error)
+    }
+}
+
+/// Where one user can be reached
+#[derive(Debug, Clone, Default)]
+pub struct Recipient {
+    pub user_id: Option<UserId>,
+    pub email: Option<String>,
+    pub webhook_url: Option<String>,
+    /// BCP 47 tag templates are chosen by; English when unset
+    pub locale: Option<String>,
+}
+
+/// JSON posted to a recipient's webhook
+#[derive(Debug, Serialize)]
+pub struct WebhookEvent<'a> {
+    pub event: &'static str,
+    pub subject: &'a str,
+    pub text: &'a str,
+    pub data: &'a Map<String, Value>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum DeliveryError {
+    /// Worth another attempt, such as a timeout or a 5xx
+    #[error("{0}")]
+    Retryable(String),
+    /// Will fail the same way again, such as a refused address
+    #[error("{0}")]
+    Permanent(String),
+}
+
+/// A way of reaching recipients
+#[async_trait]
+pub trait Channel: Send + Sync {
+    /// Name preferences refer to, e.g. [`EMAIL`]
+    fn name(&self) -> &'static str;
+
+    /// The recipient's address on this channel, if they have one
+    fn address<'a>(&self, to: &'a Recipient) -> Option<&'a str>;
+
+    async fn deliver(
+        &self,
+        address: &str,
+        notification: &Notification,
+        rendered: &Rendered,
+    ) -> Result<(), DeliveryError>;
+}
+
+/// Delays between delivery attempts: `base`, doubling up to `max`
+#[derive(Debug, Clone, Copy)]
+pub struct Backoff {
+    pub max_attempts: u32,
+    pub base: Duration,
+    pub max: Duration,
+}
+
+impl Backoff {
+    /// Wait after failed attempt `attempt` (counting from 1)
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        self.base.saturating_mul(factor).min(self.max)
+    }
+}
+
+/// Final outcome of one delivery, reported to the observer: `delivered`,
+/// `failed`, or `skipped` (no address on the channel)
+pub type Observer = Arc<dyn Fn(Kind, &'static str, &'static str) + Send + Sync>;
+
+/// Every channel notifications may go out on
+pub struct Notifier {
+    channels: Vec<Arc<dyn Channel>>,
+    backoff: Backoff,
+    observer: Option<Observer>,
+    templates: Templates,
+}
+
+impl Notifier {
+    pub fn new(backoff: Backoff) -> Self {
+        Self { channels: Vec::new(), backoff, observer: None, templates: Templates::default() }
+    }
+
+    /// Render with `templates` instead of the built-in ones alone
+    pub fn templates(mut self, templates: Templates) -> Self {
+        self.templates = templates;
+        self
+    }
+
+    pub fn with(mut self, channel: impl Channel + 'static) -> Self {
+        self.channels.push(Arc::new(channel));
+        self
+    }
+
+    /// Called with every delivery's outcome, e.g. to count them
+    pub fn observe(mut self, observer: Observer) -> Self {
+        self.observer = Some(observer);
+        self
+    }
+
+    /// Render `notification` in the recipient's locale and deliver it on
+    /// each of `channels` in the background; mandatory kinds go by email
+    /// whatever `channels` says
+    pub fn send(self: &Arc<Self>, to: &Recipient, channels: &[&str], notification: Notification) {
+        let channels = match notification.kind.mandatory() {
+            true => &[EMAIL][..],
+            false => channels,
+        };
+        let channels: Vec<_> = self.channels.iter().filter(|c| channels.contains(&c.name())).collect();
+        let locale = to.locale.as_deref().unwrap_or(templates::FALLBACK_LOCALE);
+        let rendered = match self.templates.render(&notification, locale) {
+            Ok(rendered) => Arc::new(rendered),
+            Err(e) => {
+                tracing::error!(error = %e, kind = notification.kind.as_str(), "notification template failed");
+                for channel in channels {
+                    self.report(notification.kind, channel.name(), "failed");
+                }
+                return;
+            }
+        };
+        let notification = Arc::new(notification);
+        for channel in channels {
+            let Some(address) = channel.address(to) else {
+                self.report(notification.kind, channel.name(), "skipped");
+                continue;
+            };
+            let (notifier, channel, address) = (self.clone(), channel.clone(), address.to_string());
+            let (to, notification, rendered) = (to.clone(), notification.clone(), rendered.clone());
+            tokio::spawn(async move {
+                if let Err(e) = notifier.deliver(channel.as_ref(), &address, &notification, &rendered).await {
+                    notifier.failed(&to, channel.name(), &address, &notification, &e);
+                }
+            });
+        }
+    }
+
+    /// Attempt delivery until it succeeds, fails permanently, or runs out
+    /// of attempts
+    async fn deliver(
+        &self,
+        channel: &dyn Channel,
+        address: &str,
+        notification: &Notification,
+        rendered: &Rendered,
+    ) -> Result<(), DeliveryError> {
+        let mut attempt = 1;
+        loop {
+            match channel.deliver(address, notification, rendered).await {
+                Ok(()) => {
+                    self.report(notification.kind, channel.name(), "delivered");
+                    return Ok(());
+                }
+                Err(DeliveryError::Retryable(e)) if attempt < self.backoff.max_attempts => {
+                    let delay = self.backoff.delay(attempt);
+                    let channel_name = channel.name();
+                    tracing::debug!(error = %e, channel = channel_name, attempt, ?delay, "notification delivery retry");
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Log a delivery that gave up and, for a webhook, tell the recipient
+    /// by email (unless that was itself about a failed webhook)
+    fn failed(
+        self: &Arc<Self>,
+        to: &Recipient,
+        channel: &'static str,
+        address: &str,
+        n: &Notification,
+        e: &DeliveryError,
+    ) {
+        self.report(n.kind, channel, "failed");
+        tracing::warn!(
+            error = %e,
+            kind = n.kind.as_str(),
+            channel,
+            user_id = ?to.user_id,
+            "notification delivery failed"
+        );
+        if channel == WEBHOOK && n.kind != Kind::WebhookFailed {
+            let source = format!("{} notification", n.kind.as_str());
+            self.send(to, &[EMAIL], Notification::webhook_failed(&source, address, &e.to_string()));
+        }
+    }
+
+    fn report(&self, kind: Kind, channel: &'static str, outcome: &'static str) {
+        if let Some(observer) = &self.observer {
+            observer(kind, channel, outcome);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    type Log = Arc<Mutex<Vec<(String, Kind)>>>;
+
+    /// Fails its first `failures` deliveries, then records the rest
+    struct Flaky {
+        name: &'static str,
+        failures: Mutex<u32>,
+        retryable: bool,
+        delivered: Log,
+    }
+
+    #[async_trait]
+    impl Channel for Flaky {
+        fn name(&self) -> &'static str {
+            self.name
+        }
+
+        fn address<'a>(&self, to: &'a Recipient) -> Option<&'a str> {
+            match self.name {
+                EMAIL => to.email.as_deref(),
+                _ => to.webhook_url.as_deref(),
+            }
+        }
+
+        async fn deliver(&self, address: &str, n: &Notification, _: &Rendered) -> Result<(), DeliveryError> {
+            let mut failures = self.failures.lock().unwrap();
+            if *failures > 0 {
+                *failures -= 1;
+                return Err(match self.retryable {
+                    true => DeliveryError::Retryable("try again".into()),
+                    false => DeliveryError::Permanent("gone".into()),
+                });
+            }
+            self.delivered.lock().unwrap().push((address.to_string(), n.kind));
+            Ok(())
+        }
+    }
+
+    fn flaky(name: &'static str, failures: u32, retryable: bool) -> (Flaky, Log) {
+        let delivered = Arc::default();
+        let channel = Flaky { name, failures: Mutex::new(failures), retryable, delivered: Arc::clone(&delivered) };
+        (channel, delivered)
+    }
+
+    fn recipient() -> Recipient {
+        Recipient {
+            user_id: None,
+            email: Some("ada@example.com".into()),
+            webhook_url: Some("https://hooks.example.com/ada".into()),
+            locale: None,
+        }
+    }
+
+    async fn settle() {
+        for _ in 0..20 {
+            tokio::task::yield_now().await;
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    }
+
+    const FAST: Backoff = Backoff { max_attempts: 3, base: Duration::from_millis(1), max: Duration::from_millis(2) };
+
+    #[test]
+    fn test_backoff_doubles_up_to_max() {
+        let backoff = Backoff { max_attempts: 10, base: Duration::from_secs(1), max: Duration::from_secs(30) };
+        let delays: Vec<u64> = (1..=7).map(|a| backoff.delay(a).as_secs()).collect();
+        assert_eq!(delays, [1, 2, 4, 8, 16, 30, 30]);
+        assert_eq!(backoff.delay(100), Duration::from_secs(30));
+    }
+
+    #[tokio::test]
+    async fn test_retries_until_delivered() {
+        let (email, delivered) = flaky(EMAIL, 2, true);
+        let notifier = Arc::new(Notifier::new(FAST).with(email));
+        notifier.send(&recipient(), &[EMAIL], Notification::quota_warning(80, 8, 10));
+        settle().await;
+        assert_eq!(*delivered.lock().unwrap(), [("ada@example.com".to_string(), Kind::QuotaWarning)]);
+    }
+
+    #[tokio::test]
+    async fn test_failed_webhook_is_reported_by_email() {
+        let (email, emailed) = flaky(EMAIL, 0, true);
+        let (webhook, posted) = flaky(WEBHOOK, 1, false);
+        let notifier = Arc::new(Notifier::new(FAST).with(email).with(webhook));
+        notifier.send(&recipient(), &[WEBHOOK], Notification::quota_warning(95, 10, 10));
+        settle().await;
+        assert!(posted.lock().unwrap().is_empty());
+        assert_eq!(*emailed.lock().unwrap(), [("ada@example.com".to_string(), Kind::WebhookFailed)]);
+    }
+
+    #[tokio::test]
+    async fn test_mandatory_kinds_go_by_email_only() {
+        let (email, emailed) = flaky(EMAIL, 0, true);
+        let (webhook, posted) = flaky(WEBHOOK, 0, true);
+        let notifier = Arc::new(Notifier::new(FAST).with(email).with(webhook));
+        notifier.send(&recipient(), &[WEBHOOK], Notification::password_reset("https://app.example.com/reset/x"));
+        settle().await;
+        assert!(posted.lock().unwrap().is_empty());
+        assert_eq!(emailed.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,220 @@
+//! Sending email over SMTP.
+//!
+//! Connections are pooled and reused, optionally DKIM-signed, and sends
+//! are spaced out to at most `max_per_sec` so a burst of notifications
+//! does not trip the relay's rate limits.
+
+use crate::{
+    email::{message, Email, Mailer},
+    DeliveryError,
+};
+use async_trait::async_trait;
+use lettre::{
+    message::dkim::{DkimConfig, DkimSigningAlgorithm, DkimSigningKey},
+    transport::smtp::{authentication::Credentials, PoolConfig},
+    AsyncSmtpTransport, AsyncTransport, Tokio1Executor,
+};
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SmtpTls {
+    /// Plain connection upgraded with STARTTLS, which is required
+    StartTls,
+    /// TLS from the first byte (usually port 465)
+    Implicit,
+    /// Unencrypted, for a relay on the local network only
+    None,
+}
+
+impl SmtpTls {
+    pub fn parse(mode: &str) -> Option<SmtpTls> {
+        match mode {
+            "starttls" => Some(SmtpTls::StartTls),
+            "tls" => Some(SmtpTls::Implicit),
+            "none" => Some(SmtpTls::None),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SmtpSettings {
+    pub host: String,
+    pub port: u16,
+    pub tls: SmtpTls,
+    /// Empty sends without authenticating
+    pub username: String,
+    pub password: String,
+    /// Connections kept open to the relay
+    pub pool_size: u32,
+    pub timeout: Duration,
+    /// Emails sent per second at most; 0 is unlimited
+    pub max_per_sec: u32,
+    pub dkim: Option<DkimSettings>,
+}
+
+#[derive(Debug, Clone)]
+pub struct DkimSettings {
+    /// Published as `<selector>._domainkey.<domain>`
+    pub selector: String,
+    pub domain: String,
+    /// PKCS#1 PEM for `rsa`, base64 of the 32 byte seed for `ed25519`
+    pub private_key: String,
+    pub algorithm: String,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum MailerError {
+    #[error("invalid SMTP relay: {0}")]
+    Relay(String),
+    #[error("unknown DKIM algorithm {0}; use rsa or ed25519")]
+    DkimAlgorithm(String),
+    #[error("invalid DKIM key: {0}")]
+    DkimKey(String),
+}
+
+pub struct SmtpMailer {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    dkim: Option<DkimConfig>,
+    /// Gap between sends; zero when unthrottled
+    spacing: Duration,
+    /// Earliest time the next send may start
+    next_slot: Mutex<Instant>,
+}
+
+impl SmtpMailer {
+    pub fn new(settings: &SmtpSettings) -> Result<Self, MailerError> {
+        let builder = match settings.tls {
+            SmtpTls::StartTls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&settings.host),
+            SmtpTls::Implicit => AsyncSmtpTransport::<Tokio1Executor>::relay(&settings.host),
+            SmtpTls::None => Ok(AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&settings.host)),
+        };
+        let mut builder = builder
+            .map_err(|e| MailerError::Relay(e.to_string()))?
+            .port(settings.port)
+            .timeout(Some(settings.timeout))
+            .pool_config(PoolConfig::new().max_size(settings.pool_size.max(1)));
+        if !settings.username.is_empty() {
+            builder = builder.credentials(Credentials::new(settings.username.clone(), settings.password.clone()));
+        }
+        let dkim = settings.dkim.as_ref().map(dkim_config).transpose()?;
+        let spacing = match settings.max_per_sec {
+            0 => Duration::ZERO,
+            n => Duration::from_secs(1) / n,
+        };
+        Ok(Self { transport: builder.build(), dkim, spacing, next_slot: Mutex::new(Instant::now()) })
+    }
+
+    /// Wait for this send's turn under `max_per_sec`
+    async fn throttle(&self) {
+        if self.spacing.is_zero() {
+            return;
+        }
+        let wait = {
+            let mut next = self.next_slot.lock().unwrap_or_else(|e| e.into_inner());
+            let now = Instant::now();
+            let slot = (*next).max(now);
+            *next = slot + self.spacing;
+            slot - now
+        };
+        tokio::time::sleep(wait).await;
+    }
+}
+
+fn dkim_config(dkim: &DkimSettings) -> Result<DkimConfig, MailerError> {
+    let algorithm = match dkim.algorithm.as_str() {
+        "rsa" => DkimSigningAlgorithm::Rsa,
+        "ed25519" => DkimSigningAlgorithm::Ed25519,
+        other => return Err(MailerError::DkimAlgorithm(other.to_string())),
+    };
+    let key = DkimSigningKey::new(dkim.private_key.trim(), algorithm).map_err(|e| MailerError::DkimKey(e.to_string()))?;
+    Ok(DkimConfig::default_config(dkim.selector.clone(), dkim.domain.clone(), key))
+}
+
+#[async_trait]
+impl Mailer for SmtpMailer {
+    async fn send(&self, email: &Email) -> Result<(), DeliveryError> {
+        let mut message = message(email)?;
+        if let Some(dkim) = &self.dkim {
+            message.sign(dkim);
+        }
+        self.throttle().await;
+        match self.transport.send(message).await {
+            Ok(_) => Ok(()),
+            // 5xx replies (such as an unknown mailbox) and messages the
+            // client itself refuses will fail the same way again
+            Err(e) if e.is_permanent() || e.is_client() => Err(DeliveryError::Permanent(e.to_string())),
+            Err(e) => Err(DeliveryError::Retryable(e.to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(max_per_sec: u32) -> SmtpSettings {
+        SmtpSettings {
+            host: "127.0.0.1".into(),
+            port: 2525,
+            tls: SmtpTls::None,
+            username: String::new(),
+            password: String::new(),
+            pool_size: 1,
+            timeout: Duration::from_secs(1),
+            max_per_sec,
+            dkim: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_throttle_spaces_sends() {
+        let mailer = SmtpMailer::new(&settings(50)).unwrap();
+        let started = Instant::now();
+        for _ in 0..4 {
+            mailer.throttle().await;
+        }
+        // The first goes at once, each later one 20ms after the last
+        assert!(started.elapsed() >= Duration::from_millis(60), "{:?}", started.elapsed());
+
+        let unthrottled = SmtpMailer::new(&settings(0)).unwrap();
+        let started = Instant::now();
+        for _ in 0..100 {
+            unthrottled.throttle().await;
+        }
+        assert!(started.elapsed() < Duration::from_millis(50));
+    }
+
+    #[tokio::test]
+    async fn test_dkim_key_is_checked_up_front() {
+        let dkim = |algorithm: &str, private_key: &str| {
+            let dkim = DkimSettings {
+                selector: "mail".into(),
+                domain: "example.com".into(),
+                private_key: private_key.into(),
+                algorithm: algorithm.into(),
+            };
+            SmtpMailer::new(&SmtpSettings { dkim: Some(dkim), ..settings(0) })
+        };
+        // Base64 of a 32 byte ed25519 seed
+        let seed = "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=";
+        let mailer = dkim("ed25519", seed).unwrap();
+
+        let email = Email {
+            from: "no-reply@example.com".into(),
+            to: "ada@example.com".into(),
+            subject: "Hello".into(),
+            text: "Signed".into(),
+        };
+        let mut signed = message(&email).unwrap();
+        signed.sign(mailer.dkim.as_ref().unwrap());
+        let formatted = String::from_utf8(signed.formatted()).unwrap();
+        assert!(formatted.contains("DKIM-Signature: v=1; a=ed25519-sha256; d=example.com; s=mail;"), "{formatted}");
+
+        assert!(matches!(dkim("ed25519", "not base64"), Err(MailerError::DkimKey(_))));
+        assert!(matches!(dkim("dsa", seed), Err(MailerError::DkimAlgorithm(_))));
+    }
+}
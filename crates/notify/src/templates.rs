@@ -0,0 +1,155 @@
+//! Subject and text per [`Kind`] and locale, as minijinja templates.
+//!
+//! Each template is `<locale>/<kind>.txt` with a `subject` and a `body`
+//! block, and sees the notification's values by name. The templates built
+//! in from `templates/` can be replaced one file at a time by a file at
+//! the same path under an override directory; files are read once, when
+//! first used. A locale without its own template falls back to its
+//! primary language (`pt-BR` to `pt`), then to English.
+
+use crate::{Kind, Notification};
+use include_dir::{include_dir, Dir};
+use minijinja::{Environment, ErrorKind};
+use std::path::PathBuf;
+
+static EMBEDDED: Dir<'_> = include_dir!("$CARGO_MANIFEST_DIR/templates");
+
+/// Used when nothing closer to the requested locale exists
+pub const FALLBACK_LOCALE: &str = "en";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rendered {
+    pub subject: String,
+    pub text: String,
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("rendering {template}: {source}")]
+pub struct TemplateError {
+    template: String,
+    source: minijinja::Error,
+}
+
+pub struct Templates {
+    env: Environment<'static>,
+}
+
+impl Default for Templates {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
+impl Templates {
+    /// The built-in templates, each replaced by its namesake under
+    /// `overrides` when there is one
+    pub fn new(overrides: Option<PathBuf>) -> Self {
+        let mut env = Environment::new();
+        env.set_loader(move |name| {
+            if let Some(dir) = &overrides {
+                match std::fs::read_to_string(dir.join(name)) {
+                    Ok(source) => return Ok(Some(source)),
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                    Err(e) => {
+                        let message = format!("reading override {name}: {e}");
+                        return Err(minijinja::Error::new(ErrorKind::InvalidOperation, message));
+                    }
+                }
+            }
+            Ok(EMBEDDED.get_file(name).and_then(|f| f.contents_utf8()).map(str::to_string))
+        });
+        Self { env }
+    }
+
+    /// `notification` in `locale` (a BCP 47 tag), or the nearest locale
+    /// there is a template for
+    pub fn render(&self, notification: &Notification, locale: &str) -> Result<Rendered, TemplateError> {
+        let kind = notification.kind.as_str();
+        let primary = locale.split('-').next().unwrap_or(locale);
+        let mut last = None;
+        for candidate in [locale, primary, FALLBACK_LOCALE] {
+            // The tag becomes part of a path
+            if candidate.is_empty() || !candidate.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+                continue;
+            }
+            let name = format!("{}/{kind}.txt", candidate.to_ascii_lowercase());
+            match self.render_blocks(&name, notification) {
+                Err(e) if e.kind() == ErrorKind::TemplateNotFound => last = Some((name, e)),
+                rendered => return rendered.map_err(|source| TemplateError { template: name, source }),
+            }
+        }
+        let (template, source) = last.expect("at least one locale was tried");
+        Err(TemplateError { template, source })
+    }
+
+    fn render_blocks(&self, name: &str, notification: &Notification) -> Result<Rendered, minijinja::Error> {
+        let template = self.env.get_template(name)?;
+        let mut captured = template.render_captured(&notification.vars)?;
+        let mut block = |name| {
+            let rendered = captured.with_state_mut(|state| state.render_block(name));
+            rendered.map(|s| s.trim().to_string())
+        };
+        Ok(Rendered { subject: block("subject")?, text: block("body")? })
+    }
+
+    /// Every built-in template, for checking they all render
+    pub fn embedded() -> impl Iterator<Item = (&'static str, Kind)> {
+        EMBEDDED.dirs().flat_map(|locale| {
+            let tag = locale.path().to_str().unwrap_or_default();
+            Kind::ALL.into_iter().map(move |kind| (tag, kind))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_fills_variables_in_the_nearest_locale() {
+        let templates = Templates::default();
+        let warning = Notification::quota_warning(80, 800, 1000);
+        let rendered = templates.render(&warning, "en").unwrap();
+        assert_eq!(rendered.subject, "You have used 80% of today's tokens");
+        assert!(rendered.text.starts_with("You have used 800 of your 1000 tokens for today."));
+
+        let rendered = templates.render(&warning, "de-AT").unwrap();
+        assert_eq!(rendered.subject, "Sie haben 80 % Ihrer heutigen Tokens verbraucht");
+        // No Japanese templates ship, so English is used
+        for locale in ["ja", "../en"] {
+            let rendered = templates.render(&warning, locale).unwrap();
+            assert_eq!(rendered.subject, "You have used 80% of today's tokens");
+        }
+    }
+
+    #[test]
+    fn test_every_embedded_template_renders() {
+        let templates = Templates::default();
+        let link = "https://app.example.com/x";
+        for (locale, kind) in Templates::embedded() {
+            let notification = match kind {
+                Kind::Verification => Notification::verification(link),
+                Kind::PasswordReset => Notification::password_reset(link),
+                Kind::QuotaWarning => Notification::quota_warning(95, 950, 1000),
+                Kind::WebhookFailed => Notification::webhook_failed("run of schedule digest", link, "timed out"),
+            };
+            let rendered = templates.render(&notification, locale).unwrap();
+            assert!(!rendered.subject.is_empty() && !rendered.subject.contains('\n'), "{locale} {kind:?}");
+            assert!(!rendered.text.contains("{{"), "{locale} {kind:?}");
+        }
+    }
+
+    #[test]
+    fn test_override_replaces_one_template() {
+        let dir = std::env::temp_dir().join(format!("ds-notify-templates-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("en")).unwrap();
+        let custom = "{% block subject %}Heads up{% endblock %}{% block body %}{{ used_tokens }} used{% endblock %}";
+        std::fs::write(dir.join("en/quota.warning.txt"), custom).unwrap();
+        let templates = Templates::new(Some(dir.clone()));
+        let warning = templates.render(&Notification::quota_warning(80, 8, 10), "en").unwrap();
+        let reset = templates.render(&Notification::password_reset("https://x"), "en").unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(warning, Rendered { subject: "Heads up".into(), text: "8 used".into() });
+        assert_eq!(reset.subject, "Reset your password");
+    }
+}
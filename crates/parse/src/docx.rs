@@ -0,0 +1,135 @@
+//! DOCX: a zip of WordprocessingML. Text comes from the runs of
+//! `word/document.xml`, one paragraph per `w:p`; metadata from the core
+//! properties in `docProps/core.xml`.
+
+use crate::{present, Metadata, ParseError, Parsed};
+use quick_xml::{escape::unescape, events::Event, Reader};
+use std::io::{Cursor, Read};
+
+/// Largest part read out of the archive, so a zip bomb cannot exhaust
+/// memory
+const MAX_PART_BYTES: u64 = 64 * 1024 * 1024;
+
+pub(crate) fn parse(data: &[u8]) -> Result<Parsed, ParseError> {
+    let malformed = |e: String| ParseError::malformed("docx", e);
+    let mut archive = zip::ZipArchive::new(Cursor::new(data)).map_err(|e| malformed(e.to_string()))?;
+    let body = part(&mut archive, "word/document.xml")?.ok_or_else(|| malformed("no word/document.xml".into()))?;
+    let text = body_text(&body).map_err(malformed)?;
+    let metadata = match part(&mut archive, "docProps/core.xml")? {
+        Some(core) => core_properties(&core).map_err(malformed)?,
+        None => Metadata::default(),
+    };
+    Ok(Parsed { text, metadata })
+}
+
+/// One archive member as text, `None` when missing
+fn part(archive: &mut zip::ZipArchive<Cursor<&[u8]>>, name: &str) -> Result<Option<String>, ParseError> {
+    let file = match archive.by_name(name) {
+        Ok(file) => file,
+        Err(zip::result::ZipError::FileNotFound) => return Ok(None),
+        Err(e) => return Err(ParseError::malformed("docx", e)),
+    };
+    let mut xml = String::new();
+    file.take(MAX_PART_BYTES + 1).read_to_string(&mut xml).map_err(|e| ParseError::malformed("docx", e))?;
+    if xml.len() as u64 > MAX_PART_BYTES {
+        return Err(ParseError::malformed("docx", format!("{name} exceeds {MAX_PART_BYTES} bytes")));
+    }
+    Ok(Some(xml))
+}
+
+fn body_text(xml: &str) -> Result<String, String> {
+    let mut reader = Reader::from_str(xml);
+    let (mut out, mut in_text) = (String::new(), false);
+    loop {
+        match reader.read_event().map_err(|e| e.to_string())? {
+            Event::Start(e) if e.local_name().as_ref() == b"t" => in_text = true,
+            Event::End(e) => match e.local_name().as_ref() {
+                b"t" => in_text = false,
+                b"p" => out.push_str("\n\n"),
+                _ => {}
+            },
+            Event::Empty(e) => match e.local_name().as_ref() {
+                b"tab" => out.push('\t'),
+                b"br" | b"cr" => out.push('\n'),
+                _ => {}
+            },
+            Event::Text(t) if in_text => out.push_str(&t.decode().map_err(|e| e.to_string())?),
+            Event::GeneralRef(r) if in_text => out.push_str(&reference(&r)?),
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    Ok(out)
+}
+
+/// Dublin Core elements of `docProps/core.xml`
+fn core_properties(xml: &str) -> Result<Metadata, String> {
+    let mut reader = Reader::from_str(xml);
+    let (mut metadata, mut field, mut value) = (Metadata::default(), None, String::new());
+    loop {
+        match reader.read_event().map_err(|e| e.to_string())? {
+            Event::Start(e) => {
+                field = Some(String::from_utf8_lossy(e.local_name().as_ref()).into_owned());
+                value.clear();
+            }
+            Event::Text(t) => value.push_str(&t.decode().map_err(|e| e.to_string())?),
+            Event::GeneralRef(r) => value.push_str(&reference(&r)?),
+            Event::End(_) => {
+                let slot = match field.take().as_deref() {
+                    Some("title") => &mut metadata.title,
+                    Some("creator") => &mut metadata.author,
+                    Some("description") => &mut metadata.description,
+                    Some("created") => &mut metadata.created,
+                    Some("language") => &mut metadata.language,
+                    _ => continue,
+                };
+                *slot = present(&value);
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    Ok(metadata)
+}
+
+/// `&amp;`, `&#233;` and the like
+fn reference(r: &quick_xml::events::BytesRef) -> Result<String, String> {
+    if let Some(c) = r.resolve_char_ref().map_err(|e| e.to_string())? {
+        return Ok(c.to_string());
+    }
+    let name = r.decode().map_err(|e| e.to_string())?;
+    unescape(&format!("&{name};")).map(|s| s.into_owned()).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{parse, ParseError, DOCX};
+    use std::io::Write;
+
+    fn docx(files: &[(&str, &str)]) -> Vec<u8> {
+        let mut zip = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        for (name, body) in files {
+            zip.start_file(*name, zip::write::SimpleFileOptions::default()).unwrap();
+            zip.write_all(body.as_bytes()).unwrap();
+        }
+        zip.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn test_paragraph_runs_and_core_properties() {
+        let body = r#"<w:document xmlns:w="w"><w:body>
+            <w:p><w:r><w:t>Fish </w:t></w:r><w:r><w:t>&amp; chips</w:t></w:r></w:p>
+            <w:p><w:r><w:t>caf&#233;</w:t><w:br/><w:t>menu</w:t></w:r></w:p></w:body></w:document>"#;
+        let core = r#"<cp:coreProperties xmlns:cp="cp" xmlns:dc="dc" xmlns:dcterms="dcterms">
+            <dc:title>Lunch &amp; more</dc:title><dc:creator>Chef</dc:creator>
+            <dcterms:created>2024-05-01T10:00:00Z</dcterms:created></cp:coreProperties>"#;
+        let parsed = parse(DOCX, &docx(&[("word/document.xml", body), ("docProps/core.xml", core)])).unwrap();
+        assert_eq!(parsed.text, "Fish & chips\n\ncafé\nmenu");
+        assert_eq!(parsed.metadata.title.as_deref(), Some("Lunch & more"));
+        assert_eq!(parsed.metadata.author.as_deref(), Some("Chef"));
+        assert_eq!(parsed.metadata.created.as_deref(), Some("2024-05-01T10:00:00Z"));
+
+        assert!(matches!(parse(DOCX, &docx(&[("other.xml", "<a/>")])), Err(ParseError::Malformed { .. })));
+        assert!(matches!(parse(DOCX, b"not a zip"), Err(ParseError::Malformed { .. })));
+    }
+}
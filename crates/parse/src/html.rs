@@ -0,0 +1,78 @@
+//! HTML: the visible text of the body, one paragraph per block element,
+//! and the title, description, author and language from the head.
+
+use crate::{present, Metadata, Parsed};
+use scraper::{node::Node, ElementRef, Html, Selector};
+
+/// Never visible text
+const SKIPPED: &[&str] = &["head", "script", "style", "noscript", "template", "svg", "iframe"];
+
+/// Elements ending a paragraph
+const BLOCKS: &[&str] = &[
+    "address", "article", "aside", "blockquote", "dd", "div", "dl", "dt", "figcaption", "figure", "footer",
+    "form", "h1", "h2", "h3", "h4", "h5", "h6", "header", "hr", "li", "main", "nav", "ol", "p", "pre", "section",
+    "table", "tr", "ul",
+];
+
+pub(crate) fn parse(data: &[u8]) -> Parsed {
+    let document = Html::parse_document(&String::from_utf8_lossy(data));
+    let mut text = String::new();
+    walk(document.root_element(), &mut text);
+    let first = |selector: &str| {
+        let selector = Selector::parse(selector).expect("valid selector");
+        document.select(&selector).next()
+    };
+    let meta = |name: &str| {
+        first(&format!("meta[name={name:?}]")).and_then(|e| e.value().attr("content")).and_then(present)
+    };
+    let metadata = Metadata {
+        title: first("title").map(|e| e.text().collect::<String>()).and_then(present),
+        author: meta("author"),
+        description: meta("description"),
+        language: document.root_element().value().attr("lang").and_then(present),
+        ..Metadata::default()
+    };
+    Parsed { text, metadata }
+}
+
+fn walk(element: ElementRef, out: &mut String) {
+    for child in element.children() {
+        match child.value() {
+            Node::Text(text) => out.push_str(text),
+            Node::Element(e) if SKIPPED.contains(&e.name()) => {}
+            Node::Element(e) if e.name() == "br" => out.push('\n'),
+            Node::Element(e) => {
+                let block = BLOCKS.contains(&e.name());
+                if block {
+                    out.push_str("\n\n");
+                }
+                walk(ElementRef::wrap(child).expect("element node"), out);
+                if block {
+                    out.push_str("\n\n");
+                } else if matches!(e.name(), "td" | "th") {
+                    out.push(' ');
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parse;
+
+    #[test]
+    fn test_body_text_and_head_metadata() {
+        let html = r#"<!doctype html><html lang="en"><head><title> Field notes </title>
+            <meta name="author" content="A. Writer"><style>p { color: red }</style></head>
+            <body><h1>Heading</h1><p>First <b>bold</b> paragraph.</p><script>track()</script>
+            <ul><li>one</li><li>two</li></ul>Line<br>break</body></html>"#;
+        let parsed = parse("text/html", html.as_bytes()).unwrap();
+        assert_eq!(parsed.text, "Heading\n\nFirst bold paragraph.\n\none\n\ntwo\n\nLine\nbreak");
+        assert_eq!(parsed.metadata.title.as_deref(), Some("Field notes"));
+        assert_eq!(parsed.metadata.author.as_deref(), Some("A. Writer"));
+        assert_eq!(parsed.metadata.language.as_deref(), Some("en"));
+        assert_eq!(parsed.metadata.description, None);
+    }
+}
@@ -0,0 +1,119 @@
+//! Text and metadata out of uploaded documents.
+//!
+//! [`parse`] picks a parser by media type: PDF, DOCX, HTML, or plain text
+//! and Markdown as they are. Each returns the document's text with
+//! paragraphs separated by blank lines, ready to be chunked, and whatever
+//! [`Metadata`] the format carries. Parsing is CPU-bound and synchronous;
+//! callers on an async runtime run it on a blocking thread.
+
+use serde::Serialize;
+use thiserror::Error;
+
+mod docx;
+mod html;
+mod pdf;
+
+pub const PDF: &str = "application/pdf";
+pub const DOCX: &str = "application/vnd.openxmlformats-officedocument.wordprocessingml.document";
+pub const HTML: &str = "text/html";
+
+/// Media types [`parse`] accepts
+pub const SUPPORTED: &[&str] = &[PDF, DOCX, HTML, "application/xhtml+xml", "text/plain", "text/markdown"];
+
+/// A parsed document
+#[derive(Debug, Clone, PartialEq)]
+pub struct Parsed {
+    pub text: String,
+    pub metadata: Metadata,
+}
+
+/// What a document says about itself; absent fields were not given
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Metadata {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub author: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// As the document states it (PDF date, ISO 8601)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub created: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pages: Option<u32>,
+}
+
+#[derive(Debug, Error)]
+pub enum ParseError {
+    #[error("unsupported content type {0:?}")]
+    Unsupported(String),
+    #[error("malformed {format}: {message}")]
+    Malformed { format: &'static str, message: String },
+}
+
+impl ParseError {
+    fn malformed(format: &'static str, e: impl std::fmt::Display) -> Self {
+        ParseError::Malformed { format, message: e.to_string() }
+    }
+}
+
+pub fn supported(content_type: &str) -> bool {
+    SUPPORTED.contains(&content_type)
+}
+
+/// Extract the text and metadata of `data`, typed by `content_type`
+/// (without parameters)
+pub fn parse(content_type: &str, data: &[u8]) -> Result<Parsed, ParseError> {
+    let parsed = match content_type {
+        PDF => pdf::parse(data)?,
+        DOCX => docx::parse(data)?,
+        HTML | "application/xhtml+xml" => html::parse(data),
+        "text/plain" | "text/markdown" => Parsed {
+            text: String::from_utf8(data.to_vec()).map_err(|e| ParseError::malformed("text", e))?,
+            metadata: Metadata::default(),
+        },
+        other => return Err(ParseError::Unsupported(other.to_string())),
+    };
+    Ok(Parsed { text: tidy(&parsed.text), ..parsed })
+}
+
+/// Lines trimmed with inner runs of whitespace collapsed, and paragraphs
+/// separated by exactly one blank line
+fn tidy(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut blank = 0;
+    for line in text.lines() {
+        let line = line.split_whitespace().collect::<Vec<_>>().join(" ");
+        if line.is_empty() {
+            blank += 1;
+            continue;
+        }
+        if !out.is_empty() {
+            out.push_str(if blank > 0 { "\n\n" } else { "\n" });
+        }
+        out.push_str(&line);
+        blank = 0;
+    }
+    out
+}
+
+/// Metadata values trimmed, with empty ones dropped
+fn present(value: impl AsRef<str>) -> Option<String> {
+    let value = value.as_ref().trim();
+    (!value.is_empty()).then(|| value.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_text_is_tidied_and_unknown_types_refused() {
+        let parsed = parse("text/plain", b"  first   line \n second\n\n\n\n  next  paragraph ").unwrap();
+        assert_eq!(parsed.text, "first line\nsecond\n\nnext paragraph");
+        assert!(matches!(parse("image/png", b"\x89PNG"), Err(ParseError::Unsupported(_))));
+        assert!(matches!(parse("text/plain", b"\xff\xfe"), Err(ParseError::Malformed { format: "text", .. })));
+    }
+}
@@ -0,0 +1,110 @@
+//! PDF: the text of each page in order, a blank line between pages, and
+//! the document information dictionary. Encrypted documents are refused
+//! unless they open with the empty password.
+
+use crate::{present, Metadata, ParseError, Parsed};
+use pdf_extract::{decode_text_string, Document, Object, PlainTextOutput};
+
+pub(crate) fn parse(data: &[u8]) -> Result<Parsed, ParseError> {
+    let malformed = |e: String| ParseError::malformed("pdf", e);
+    // The extractor panics on some malformed files rather than erroring
+    let parsed = std::panic::catch_unwind(|| -> Result<Parsed, String> {
+        let mut document = Document::load_mem(data).map_err(|e| e.to_string())?;
+        if document.is_encrypted() {
+            document.decrypt("").map_err(|_| "encrypted with a password".to_string())?;
+        }
+        let pages = document.get_pages().len() as u32;
+        let mut pages_text = Vec::with_capacity(pages as usize);
+        for page in 1..=pages {
+            let mut text = String::new();
+            pdf_extract::output_doc_page(&document, &mut PlainTextOutput::new(&mut text), page)
+                .map_err(|e| e.to_string())?;
+            pages_text.push(text);
+        }
+        let metadata = Metadata { pages: Some(pages), ..info(&document) };
+        Ok(Parsed { text: pages_text.join("\n\n"), metadata })
+    });
+    match parsed {
+        Ok(parsed) => parsed.map_err(malformed),
+        Err(_) => Err(malformed("unreadable".into())),
+    }
+}
+
+/// The trailer's `/Info` dictionary, when there is one
+fn info(document: &Document) -> Metadata {
+    let Ok((_, Object::Dictionary(info))) =
+        document.trailer.get(b"Info").and_then(|info| document.dereference(info))
+    else {
+        return Metadata::default();
+    };
+    let field = |key: &[u8]| info.get(key).ok().and_then(|v| decode_text_string(v).ok()).and_then(present);
+    Metadata {
+        title: field(b"Title"),
+        author: field(b"Author"),
+        description: field(b"Subject"),
+        created: field(b"CreationDate"),
+        ..Metadata::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{parse, ParseError, PDF};
+    use pdf_extract::{Dictionary, Document, Object, Stream, StringFormat};
+
+    /// A document of one page per entry of `pages`, in Helvetica
+    fn pdf(pages: &[&str], title: &str) -> Vec<u8> {
+        let mut doc = Document::with_version("1.5");
+        let pages_id = doc.new_object_id();
+        let mut font = Dictionary::new();
+        font.set("Type", Object::Name(b"Font".to_vec()));
+        font.set("Subtype", Object::Name(b"Type1".to_vec()));
+        font.set("BaseFont", Object::Name(b"Helvetica".to_vec()));
+        let font_id = doc.add_object(font);
+        let mut fonts = Dictionary::new();
+        fonts.set("F1", Object::Reference(font_id));
+        let mut resources = Dictionary::new();
+        resources.set("Font", Object::Dictionary(fonts));
+        let resources_id = doc.add_object(resources);
+        let mut kids = Vec::new();
+        for text in pages {
+            let content = format!("BT /F1 12 Tf 72 700 Td ({text}) Tj ET");
+            let content_id = doc.add_object(Stream::new(Dictionary::new(), content.into_bytes()));
+            let mut page = Dictionary::new();
+            page.set("Type", Object::Name(b"Page".to_vec()));
+            page.set("Parent", Object::Reference(pages_id));
+            page.set("Contents", Object::Reference(content_id));
+            page.set("Resources", Object::Reference(resources_id));
+            page.set("MediaBox", vec![0.into(), 0.into(), 595.into(), 842.into()]);
+            kids.push(Object::Reference(doc.add_object(page)));
+        }
+        let mut tree = Dictionary::new();
+        tree.set("Type", Object::Name(b"Pages".to_vec()));
+        tree.set("Count", pages.len() as i64);
+        tree.set("Kids", kids);
+        doc.objects.insert(pages_id, Object::Dictionary(tree));
+        let mut catalog = Dictionary::new();
+        catalog.set("Type", Object::Name(b"Catalog".to_vec()));
+        catalog.set("Pages", Object::Reference(pages_id));
+        let catalog_id = doc.add_object(catalog);
+        doc.trailer.set("Root", Object::Reference(catalog_id));
+        let mut info = Dictionary::new();
+        info.set("Title", Object::String(title.as_bytes().to_vec(), StringFormat::Literal));
+        let info_id = doc.add_object(info);
+        doc.trailer.set("Info", Object::Reference(info_id));
+        let mut out = Vec::new();
+        doc.save_to(&mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn test_pages_text_and_information() {
+        let parsed = parse(PDF, &pdf(&["Quarterly report", "Revenue grew"], "Q3")).unwrap();
+        assert!(parsed.text.contains("Quarterly report"), "{:?}", parsed.text);
+        assert!(parsed.text.contains("Revenue grew"), "{:?}", parsed.text);
+        assert!(parsed.text.find("Quarterly").unwrap() < parsed.text.find("Revenue").unwrap());
+        assert_eq!(parsed.metadata.pages, Some(2));
+        assert_eq!(parsed.metadata.title.as_deref(), Some("Q3"));
+        assert!(matches!(parse(PDF, b"%PDF-1.4 truncated"), Err(ParseError::Malformed { format: "pdf", .. })));
+    }
+}
@@ -0,0 +1,113 @@
+//! Blobs in an object store bucket: S3 and S3-compatible services (MinIO,
+//! Ceph), or Google Cloud Storage. Uploads go up in parts as they stream
+//! in, so large blobs are never buffered whole.
+
+use crate::{check_key, BlobStore, ByteStream, StoreError, StoreResult};
+use async_trait::async_trait;
+use futures_util::{StreamExt, TryStreamExt};
+use object_store::{aws::AmazonS3Builder, buffered::BufWriter, gcp::GoogleCloudStorageBuilder, path::Path, ObjectStore};
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+
+/// How to reach an S3-compatible bucket
+#[derive(Debug, Clone, Default)]
+pub struct S3Settings {
+    pub bucket: String,
+    pub region: String,
+    /// Empty for AWS itself; the service URL (e.g. `http://minio:9000`)
+    /// otherwise, addressed path-style
+    pub endpoint: String,
+    /// Empty keys fall back to the AWS environment and instance
+    /// credentials
+    pub access_key_id: String,
+    pub secret_access_key: String,
+}
+
+pub struct CloudStore {
+    name: &'static str,
+    inner: Arc<dyn ObjectStore>,
+}
+
+impl CloudStore {
+    pub fn s3(settings: &S3Settings) -> StoreResult<Self> {
+        let mut builder = AmazonS3Builder::from_env()
+            .with_bucket_name(&settings.bucket)
+            .with_region(&settings.region);
+        if !settings.endpoint.is_empty() {
+            builder = builder
+                .with_endpoint(&settings.endpoint)
+                .with_allow_http(settings.endpoint.starts_with("http://"))
+                .with_virtual_hosted_style_request(false);
+        }
+        if !settings.access_key_id.is_empty() {
+            builder = builder
+                .with_access_key_id(&settings.access_key_id)
+                .with_secret_access_key(&settings.secret_access_key);
+        }
+        let inner = builder.build().map_err(|e| backend("s3", e))?;
+        Ok(Self { name: "s3", inner: Arc::new(inner) })
+    }
+
+    /// `credentials_path` is a service account JSON file; empty uses the
+    /// application default credentials
+    pub fn gcs(bucket: &str, credentials_path: &str) -> StoreResult<Self> {
+        let mut builder = GoogleCloudStorageBuilder::from_env().with_bucket_name(bucket);
+        if !credentials_path.is_empty() {
+            builder = builder.with_service_account_path(credentials_path);
+        }
+        let inner = builder.build().map_err(|e| backend("gcs", e))?;
+        Ok(Self { name: "gcs", inner: Arc::new(inner) })
+    }
+
+    fn path(&self, key: &str) -> StoreResult<Path> {
+        check_key(key)?;
+        Path::parse(key).map_err(|_| StoreError::InvalidKey(key.to_string()))
+    }
+}
+
+fn backend(name: &'static str, e: object_store::Error) -> StoreError {
+    StoreError::Backend { backend: name, message: e.to_string() }
+}
+
+#[async_trait]
+impl BlobStore for CloudStore {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    async fn put(&self, key: &str, mut data: ByteStream) -> StoreResult<u64> {
+        // A single PUT for small blobs, a multipart upload past the buffer;
+        // either only becomes visible once complete
+        let mut writer = BufWriter::new(self.inner.clone(), self.path(key)?);
+        let mut written = 0u64;
+        let result = async {
+            while let Some(chunk) = data.next().await {
+                let chunk = chunk?;
+                writer.write_all(&chunk).await?;
+                written += chunk.len() as u64;
+            }
+            writer.shutdown().await
+        }
+        .await;
+        if let Err(e) = result {
+            let _ = writer.abort().await;
+            return Err(e.into());
+        }
+        Ok(written)
+    }
+
+    async fn get(&self, key: &str) -> StoreResult<Option<ByteStream>> {
+        match self.inner.get(&self.path(key)?).await {
+            Ok(found) => Ok(Some(found.into_stream().map_err(std::io::Error::other).boxed())),
+            Err(object_store::Error::NotFound { .. }) => Ok(None),
+            Err(e) => Err(backend(self.name, e)),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> StoreResult<()> {
+        match self.inner.delete(&self.path(key)?).await {
+            Ok(()) | Err(object_store::Error::NotFound { .. }) => Ok(()),
+            Err(e) => Err(backend(self.name, e)),
+        }
+    }
+}
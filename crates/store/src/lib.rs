@@ -0,0 +1,123 @@
+//! Where file contents live.
+//!
+//! A [`BlobStore`] keeps opaque blobs under `/`-separated keys and moves
+//! them as streams of chunks, so a file never has to be held in memory
+//! whole on its way in or out. [`LocalStore`] writes under a directory;
+//! [`CloudStore`] talks to S3-compatible services (AWS, MinIO) or Google
+//! Cloud Storage. Which one a deployment uses is configuration, so callers
+//! only ever see the trait.
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures_util::stream::{self, BoxStream, StreamExt};
+use thiserror::Error;
+
+mod cloud;
+mod local;
+
+pub use cloud::{CloudStore, S3Settings};
+pub use local::LocalStore;
+
+/// Blob contents, chunk by chunk
+pub type ByteStream = BoxStream<'static, std::io::Result<Bytes>>;
+
+#[derive(Debug, Error)]
+pub enum StoreError {
+    #[error("invalid blob key: {0:?}")]
+    InvalidKey(String),
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("{backend} error: {message}")]
+    Backend { backend: &'static str, message: String },
+}
+
+pub type StoreResult<T> = Result<T, StoreError>;
+
+#[async_trait]
+pub trait BlobStore: Send + Sync {
+    /// Short backend name for logs
+    fn name(&self) -> &'static str;
+
+    /// Write `data` under `key`, replacing any blob there, and return the
+    /// bytes written. Readers never see a partly written blob.
+    async fn put(&self, key: &str, data: ByteStream) -> StoreResult<u64>;
+
+    /// The blob under `key`, or `None` if there is none
+    async fn get(&self, key: &str) -> StoreResult<Option<ByteStream>>;
+
+    /// Remove the blob under `key`; a missing blob is not an error
+    async fn delete(&self, key: &str) -> StoreResult<()>;
+}
+
+/// A stream of one chunk
+pub fn once(data: impl Into<Bytes>) -> ByteStream {
+    stream::once(std::future::ready(Ok(data.into()))).boxed()
+}
+
+/// Read a whole stream into memory
+pub async fn collect(mut data: ByteStream) -> std::io::Result<Vec<u8>> {
+    let mut out = Vec::new();
+    while let Some(chunk) = data.next().await {
+        out.extend_from_slice(&chunk?);
+    }
+    Ok(out)
+}
+
+/// Stands in for a configured store that could not be set up, failing
+/// every call rather than letting files land somewhere else
+pub struct Unavailable(pub String);
+
+#[async_trait]
+impl BlobStore for Unavailable {
+    fn name(&self) -> &'static str {
+        "unavailable"
+    }
+
+    async fn put(&self, _key: &str, _data: ByteStream) -> StoreResult<u64> {
+        Err(self.error())
+    }
+
+    async fn get(&self, _key: &str) -> StoreResult<Option<ByteStream>> {
+        Err(self.error())
+    }
+
+    async fn delete(&self, _key: &str) -> StoreResult<()> {
+        Err(self.error())
+    }
+}
+
+impl Unavailable {
+    fn error(&self) -> StoreError {
+        StoreError::Backend { backend: "unavailable", message: self.0.clone() }
+    }
+}
+
+/// Keys are relative paths of `[A-Za-z0-9._-]` segments, none of them
+/// `.` or `..`, so no backend can be led outside its root
+fn check_key(key: &str) -> StoreResult<()> {
+    let valid = !key.is_empty()
+        && key.split('/').all(|segment| {
+            !segment.is_empty()
+                && segment != "."
+                && segment != ".."
+                && segment.bytes().all(|b| b.is_ascii_alphanumeric() || matches!(b, b'.' | b'_' | b'-'))
+        });
+    if valid {
+        Ok(())
+    } else {
+        Err(StoreError::InvalidKey(key.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keys_stay_inside_the_root() {
+        assert!(check_key("files/0b5e-12.bin").is_ok());
+        for bad in ["", "/abs", "a//b", "a/../b", "..", "a/./b", "a\\b", "sp ace", "trailing/"] {
+            assert!(matches!(check_key(bad), Err(StoreError::InvalidKey(_))), "{bad:?}");
+        }
+    }
+}
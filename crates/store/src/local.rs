@@ -0,0 +1,103 @@
+//! Blobs as files under a directory, for single-instance deployments and
+//! shared volumes.
+
+use crate::{check_key, BlobStore, ByteStream, StoreResult};
+use async_trait::async_trait;
+use futures_util::{StreamExt, TryStreamExt};
+use std::{io::ErrorKind, path::PathBuf};
+use tokio::io::AsyncWriteExt;
+use tokio_util::io::ReaderStream;
+
+/// Chunk size of downloads
+const READ_CHUNK: usize = 64 * 1024;
+
+pub struct LocalStore {
+    root: PathBuf,
+}
+
+impl LocalStore {
+    /// Blobs live under `root`, created on first write
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    fn path(&self, key: &str) -> StoreResult<PathBuf> {
+        check_key(key)?;
+        Ok(self.root.join(key))
+    }
+}
+
+#[async_trait]
+impl BlobStore for LocalStore {
+    fn name(&self) -> &'static str {
+        "local"
+    }
+
+    async fn put(&self, key: &str, mut data: ByteStream) -> StoreResult<u64> {
+        let path = self.path(key)?;
+        if let Some(dir) = path.parent() {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+        // Written beside the target and renamed into place once complete
+        let partial = path.with_file_name(format!(".{}.partial", uuid::Uuid::new_v4().simple()));
+        let written = async {
+            let mut file = tokio::fs::File::create(&partial).await?;
+            let mut written = 0u64;
+            while let Some(chunk) = data.next().await {
+                let chunk = chunk?;
+                file.write_all(&chunk).await?;
+                written += chunk.len() as u64;
+            }
+            file.sync_all().await?;
+            tokio::fs::rename(&partial, &path).await?;
+            std::io::Result::Ok(written)
+        }
+        .await;
+        if written.is_err() {
+            let _ = tokio::fs::remove_file(&partial).await;
+        }
+        Ok(written?)
+    }
+
+    async fn get(&self, key: &str) -> StoreResult<Option<ByteStream>> {
+        let path = self.path(key)?;
+        match tokio::fs::File::open(&path).await {
+            Ok(file) => Ok(Some(ReaderStream::with_capacity(file, READ_CHUNK).map_err(Into::into).boxed())),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> StoreResult<()> {
+        match tokio::fs::remove_file(self.path(key)?).await {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{collect, once, StoreError};
+
+    #[tokio::test]
+    async fn test_blobs_round_trip_and_delete() {
+        let root = std::env::temp_dir().join(format!("ds-store-{}", uuid::Uuid::new_v4().simple()));
+        let store = LocalStore::new(&root);
+        let chunks = futures_util::stream::iter([Ok("hello ".into()), Ok("world".into())]).boxed();
+        assert_eq!(store.put("files/a/1", chunks).await.unwrap(), 11);
+        assert_eq!(collect(store.get("files/a/1").await.unwrap().unwrap()).await.unwrap(), b"hello world");
+
+        // Replaced whole; nothing partial is left behind
+        store.put("files/a/1", once("bye")).await.unwrap();
+        assert_eq!(collect(store.get("files/a/1").await.unwrap().unwrap()).await.unwrap(), b"bye");
+        assert_eq!(std::fs::read_dir(root.join("files/a")).unwrap().count(), 1);
+
+        store.delete("files/a/1").await.unwrap();
+        store.delete("files/a/1").await.unwrap();
+        assert!(store.get("files/a/1").await.unwrap().is_none());
+        assert!(matches!(store.get("../etc/passwd").await, Err(StoreError::InvalidKey(_))));
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
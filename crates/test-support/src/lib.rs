@@ -0,0 +1,603 @@
+//! Shared harness for the API integration tests.
+//!
+//! Every [`TestApp`] gets its own freshly migrated Postgres database, so tests
+//! run in parallel without truncating tables between them; the database is
+//! dropped with the app. The database
+//! server is `TEST_DATABASE_URL` when set, otherwise a Postgres container
+//! started through testcontainers and reused across test runs. The model
+//! provider is replaced with [`StubProvider`] so no Ollama is needed.
+use anyhow::{Context, Result};
+use axum::body::Body;
+use axum::extract::connect_info::MockConnectInfo;
+use axum::http::{header, HeaderMap, Method, Request, StatusCode};
+use axum::Router;
+use async_trait::async_trait;
+use bytes::Bytes;
+use ds_core::config::AppConfig;
+use ds_model::{
+    Capabilities, ChatChunk, ChatRequest, ChatStream, Detected, ModelError, ModelProvider, ModelResult, ToolCall,
+    Usage,
+};
+use ds_types::UserId;
+use futures_util::StreamExt;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use sqlx::{Connection, Executor, PgConnection};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use testcontainers_modules::postgres::Postgres;
+use testcontainers_modules::redis::Redis;
+use testcontainers_modules::testcontainers::{runners::AsyncRunner, ImageExt, ReuseDirective};
+use tokio::sync::OnceCell;
+use tower::ServiceExt;
+
+/// Label on containers started by this crate (`docker ps --filter label=ds.test-support`)
+const CONTAINER_LABEL: &str = "ds.test-support";
+
+static POSTGRES: OnceCell<String> = OnceCell::const_new();
+static REDIS: OnceCell<String> = OnceCell::const_new();
+
+/// Admin URL of the Postgres server tests create their databases on
+async fn postgres_server() -> Result<&'static str> {
+    let url = POSTGRES
+        .get_or_try_init(|| async {
+            if let Ok(url) = std::env::var("TEST_DATABASE_URL") {
+                return Ok(url);
+            }
+            let container = Postgres::default()
+                .with_name("pgvector/pgvector")
+                .with_tag("pg16")
+                .with_label(CONTAINER_LABEL, "postgres")
+                .with_reuse(ReuseDirective::Always)
+                .start()
+                .await
+                .context("starting postgres container (is Docker running, or set TEST_DATABASE_URL)")?;
+            let host = container.get_host().await?;
+            let port = container.get_host_port_ipv4(5432).await?;
+            // Reused containers outlive the test binary; forget instead of stopping on drop
+            std::mem::forget(container);
+            anyhow::Ok(format!("postgres://postgres:postgres@{host}:{port}/postgres"))
+        })
+        .await?;
+    Ok(url)
+}
+
+/// URL of a Redis server (`TEST_REDIS_URL` or a reused container)
+pub async fn redis_url() -> Result<&'static str> {
+    let url = REDIS
+        .get_or_try_init(|| async {
+            if let Ok(url) = std::env::var("TEST_REDIS_URL") {
+                return Ok(url);
+            }
+            let container = Redis::default()
+                .with_tag("7-alpine")
+                .with_label(CONTAINER_LABEL, "redis")
+                .with_reuse(ReuseDirective::Always)
+                .start()
+                .await
+                .context("starting redis container")?;
+            let host = container.get_host().await?;
+            let port = container.get_host_port_ipv4(6379).await?;
+            std::mem::forget(container);
+            anyhow::Ok(format!("redis://{host}:{port}/0"))
+        })
+        .await?;
+    Ok(url)
+}
+
+/// JSON bodies posted to a [`webhook_sink`], in the order they arrived
+pub type Received = Arc<Mutex<Vec<Value>>>;
+
+/// Serve a webhook receiver on a local port; returns its URL and the
+/// bodies posted to it so far
+pub async fn webhook_sink() -> Result<(String, Received)> {
+    let received = Received::default();
+    let sink = received.clone();
+    let hook = Router::new().route(
+        "/hook",
+        axum::routing::post(move |axum::Json(event): axum::Json<Value>| async move {
+            sink.lock().unwrap().push(event);
+        }),
+    );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let url = format!("http://{}/hook", listener.local_addr()?);
+    tokio::spawn(async move { axum::serve(listener, hook).await });
+    Ok((url, received))
+}
+
+/// Swap the database name in a Postgres URL, keeping credentials and query
+fn with_database(url: &str, name: &str) -> String {
+    let (base, query) = match url.split_once('?') {
+        Some((base, query)) => (base, Some(query)),
+        None => (url, None),
+    };
+    let authority_end = base.find("://").map(|i| i + 3).unwrap_or(0);
+    let base = match base[authority_end..].find('/') {
+        Some(slash) => &base[..authority_end + slash],
+        None => base,
+    };
+    match query {
+        Some(query) => format!("{base}/{name}?{query}"),
+        None => format!("{base}/{name}"),
+    }
+}
+
+/// A database created for one [`TestApp`] and dropped along with it
+struct TestDatabase {
+    server: &'static str,
+    name: String,
+}
+
+impl TestDatabase {
+    /// Create an empty database on the test server
+    async fn create() -> Result<Self> {
+        let server = postgres_server().await?;
+        let name = format!("ds_test_{}", uuid::Uuid::new_v4().simple());
+        let mut admin = PgConnection::connect(server)
+            .await
+            .context("connecting to test postgres server")?;
+        admin
+            .execute(format!("CREATE DATABASE \"{name}\"").as_str())
+            .await?;
+        admin.close().await?;
+
+        Ok(Self { server, name })
+    }
+
+    fn url(&self) -> String {
+        with_database(self.server, &self.name)
+    }
+}
+
+impl Drop for TestDatabase {
+    fn drop(&mut self) {
+        // The test's runtime cannot be blocked on from here, so the drop
+        // gets a thread and runtime of its own. FORCE ends the connections
+        // background tasks still hold.
+        let (server, name) = (self.server, self.name.clone());
+        let dropped = std::thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+            runtime.block_on(async {
+                let mut admin = PgConnection::connect(server).await?;
+                admin
+                    .execute(format!("DROP DATABASE IF EXISTS \"{name}\" WITH (FORCE)").as_str())
+                    .await?;
+                admin.close().await?;
+                anyhow::Ok(())
+            })
+        })
+        .join();
+        if let Ok(Err(e)) = dropped {
+            eprintln!("test database {} was not dropped: {e:#}", self.name);
+        }
+    }
+}
+
+/// A fully wired application backed by its own database
+pub struct TestApp {
+    pub cfg: Arc<AppConfig>,
+    pub state: api::state::AppState,
+    router: Router,
+    /// Declared last so it is dropped after the pools
+    _database: TestDatabase,
+}
+
+impl TestApp {
+    pub async fn spawn() -> Result<Self> {
+        Self::spawn_with(|_| {}).await
+    }
+
+    /// Spawn with config overrides applied on top of the environment defaults
+    pub async fn spawn_with(configure: impl FnOnce(&mut AppConfig)) -> Result<Self> {
+        Self::spawn_with_provider(Arc::new(StubProvider), configure).await
+    }
+
+    /// Spawn with a custom model provider (see [`ScriptedProvider`])
+    pub async fn spawn_with_provider(
+        provider: Arc<dyn ModelProvider>,
+        configure: impl FnOnce(&mut AppConfig),
+    ) -> Result<Self> {
+        let mut cfg = AppConfig::load()?;
+        let database = TestDatabase::create().await?;
+        cfg.database.url = database.url();
+        configure(&mut cfg);
+        let cfg = Arc::new(cfg);
+
+        let mut app = api::app::build_app(cfg.clone()).await;
+        api::migrations::prepare(&cfg, &app.state.db).await?;
+        app.state.provider = provider;
+        let state = app.state.clone();
+        let router = app
+            .into_router()
+            .layer(MockConnectInfo(SocketAddr::from(([127, 0, 0, 1], 0))));
+        Ok(Self {
+            cfg,
+            state,
+            router,
+            _database: database,
+        })
+    }
+
+    /// Send a raw request through the full middleware stack
+    pub async fn request(&self, req: Request<Body>) -> Result<TestResponse> {
+        let response = self.router.clone().oneshot(req).await?;
+        let status = response.status();
+        let headers = response.headers().clone();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await?;
+        Ok(TestResponse {
+            status,
+            headers,
+            body,
+        })
+    }
+
+    pub async fn get(&self, uri: &str) -> Result<TestResponse> {
+        self.send(Method::GET, uri, None, None).await
+    }
+
+    pub async fn post_json(&self, uri: &str, body: &Value) -> Result<TestResponse> {
+        self.send(Method::POST, uri, Some(body), None).await
+    }
+
+    pub async fn get_authed(&self, uri: &str, token: &str) -> Result<TestResponse> {
+        self.send(Method::GET, uri, None, Some(token)).await
+    }
+
+    pub async fn post_json_authed(&self, uri: &str, body: &Value, token: &str) -> Result<TestResponse> {
+        self.send(Method::POST, uri, Some(body), Some(token)).await
+    }
+
+    async fn send(
+        &self,
+        method: Method,
+        uri: &str,
+        body: Option<&Value>,
+        token: Option<&str>,
+    ) -> Result<TestResponse> {
+        let mut builder = Request::builder().method(method).uri(uri);
+        if let Some(token) = token {
+            builder = builder.header(header::AUTHORIZATION, format!("Bearer {token}"));
+        }
+        let req = match body {
+            Some(body) => builder
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body.to_string()))?,
+            None => builder.body(Body::empty())?,
+        };
+        self.request(req).await
+    }
+
+    /// Sign up and log in a user, returning an access token
+    pub async fn signup_and_login(&self, email: &str, password: &str) -> Result<String> {
+        let creds = serde_json::json!({ "email": email, "password": password });
+        let signup = self.post_json("/v1/auth/signup", &creds).await?;
+        anyhow::ensure!(signup.status.is_success(), "signup failed: {}", signup.text());
+        let login = self.post_json("/v1/auth/login", &creds).await?;
+        anyhow::ensure!(login.status.is_success(), "login failed: {}", login.text());
+        login.json::<Value>()?["access_token"]
+            .as_str()
+            .map(str::to_owned)
+            .context("login response missing access_token")
+    }
+
+    /// Sign up a user, promote them to admin, and log in
+    pub async fn admin_token(&self, email: &str) -> Result<String> {
+        let creds = serde_json::json!({ "email": email, "password": "password123" });
+        let signup = self.post_json("/v1/auth/signup", &creds).await?;
+        anyhow::ensure!(signup.status.is_success(), "signup failed: {}", signup.text());
+        sqlx::query("UPDATE users SET role = 'admin' WHERE email = $1")
+            .bind(email)
+            .execute(&self.state.db)
+            .await?;
+        let login = self.post_json("/v1/auth/login", &creds).await?;
+        login.json::<Value>()?["access_token"]
+            .as_str()
+            .map(str::to_owned)
+            .context("login response missing access_token")
+    }
+
+    /// Mint an access token directly, skipping signup
+    pub fn token_for(&self, user_id: UserId) -> String {
+        ds_auth::generate_tokens(
+            user_id,
+            &self.cfg.security.jwt_issuer,
+            &self.cfg.security.jwt_secret,
+            self.cfg.access_ttl(),
+        )
+        .expect("token generation")
+    }
+}
+
+impl Drop for TestApp {
+    fn drop(&mut self) {
+        // Closing takes effect at once, before the returned future is
+        // polled: no connection is handed out again
+        drop(self.state.db.close());
+    }
+}
+
+/// Model provider that echoes the last message back word by word
+pub struct StubProvider;
+
+/// Model name reported by [`StubProvider`]
+pub const STUB_MODEL: &str = "stub-model";
+
+#[async_trait]
+impl ModelProvider for StubProvider {
+    fn name(&self) -> &'static str {
+        "stub"
+    }
+
+    async fn list_models(&self) -> ModelResult<Vec<String>> {
+        Ok(vec![STUB_MODEL.to_string()])
+    }
+
+    /// Bag of words hashed into 32 buckets, so texts sharing most words
+    /// embed close together
+    async fn embed(&self, _model: &str, input: &str) -> ModelResult<Vec<f32>> {
+        let mut embedding = vec![0f32; 32];
+        for word in input.split(|c: char| !c.is_alphanumeric()).filter(|w| !w.is_empty()) {
+            let bucket = word.bytes().fold(0usize, |h, b| h.wrapping_mul(31).wrapping_add(b as usize));
+            embedding[bucket % 32] += 1.0;
+        }
+        Ok(embedding)
+    }
+
+    async fn chat_stream(&self, req: ChatRequest) -> ModelResult<ChatStream> {
+        let model: Arc<str> = Arc::from(req.model.as_str());
+        let text = req.messages.last().map(|m| m.content.clone()).unwrap_or_default();
+        let mut chunks: Vec<ModelResult<ChatChunk>> = text
+            .split_inclusive(' ')
+            .map(|word| {
+                Ok(ChatChunk {
+                    model: model.clone(),
+                    content: word.to_string(),
+                    ..Default::default()
+                })
+            })
+            .collect();
+        chunks.push(Ok(ChatChunk {
+            model,
+            done: true,
+            finish_reason: Some(ds_model::FINISH_STOP.to_string()),
+            ..Default::default()
+        }));
+        Ok(Box::pin(futures_util::stream::iter(chunks)))
+    }
+}
+
+/// One step of a [`ScriptedProvider`] stream
+#[derive(Clone, Debug)]
+pub enum Step {
+    /// Emit a content chunk
+    Token(&'static str),
+    /// Fail the stream with an upstream error
+    Fail(&'static str),
+    /// Finish with the given `finish_reason`
+    Done(&'static str),
+    /// Stop emitting without ending the stream
+    Hang,
+    /// Attach an upstream response header to the next chunk, as a
+    /// provider capturing it would
+    Header(&'static str, &'static str),
+    /// Report prompt and generated token counts on the next `done` chunk
+    Usage(u64, u64),
+}
+
+/// Model provider that replays a fixed script for every request
+pub struct ScriptedProvider(pub Vec<Step>);
+
+#[async_trait]
+impl ModelProvider for ScriptedProvider {
+    fn name(&self) -> &'static str {
+        "stub"
+    }
+
+    async fn list_models(&self) -> ModelResult<Vec<String>> {
+        Ok(vec![STUB_MODEL.to_string()])
+    }
+
+    async fn chat_stream(&self, req: ChatRequest) -> ModelResult<ChatStream> {
+        let model: Arc<str> = Arc::from(req.model.as_str());
+        let hangs = self.0.iter().any(|step| matches!(step, Step::Hang));
+        let mut headers = std::collections::BTreeMap::new();
+        let mut usage = None;
+        let items: Vec<ModelResult<ChatChunk>> = self
+            .0
+            .iter()
+            .take_while(|step| !matches!(step, Step::Hang))
+            .filter_map(|step| match step {
+                Step::Header(name, value) => {
+                    headers.insert(name.to_string(), value.to_string());
+                    None
+                }
+                Step::Usage(prompt_tokens, completion_tokens) => {
+                    usage = Some(Usage { prompt_tokens: *prompt_tokens, completion_tokens: *completion_tokens });
+                    None
+                }
+                Step::Token(text) => Some(Ok(ChatChunk {
+                    model: model.clone(),
+                    content: text.to_string(),
+                    upstream_headers: std::mem::take(&mut headers),
+                    ..Default::default()
+                })),
+                Step::Fail(msg) => Some(Err(ModelError::Upstream(msg.to_string()))),
+                Step::Done(reason) => Some(Ok(ChatChunk {
+                    model: model.clone(),
+                    done: true,
+                    finish_reason: Some(reason.to_string()),
+                    upstream_headers: std::mem::take(&mut headers),
+                    usage: usage.take(),
+                    ..Default::default()
+                })),
+                Step::Hang => unreachable!("taken while not hanging"),
+            })
+            .collect();
+        let items = futures_util::stream::iter(items);
+        if hangs {
+            return Ok(Box::pin(items.chain(futures_util::stream::pending())));
+        }
+        Ok(Box::pin(items))
+    }
+}
+
+/// Model provider that asks for one tool call, then answers with its result.
+///
+/// While the last message is not a `tool` result it requests the call;
+/// after that it replies `tool said: <result>`. Each reply reports one
+/// prompt token per message and one generated token. Embeds like
+/// [`StubProvider`].
+pub struct ToolCallingProvider(pub ToolCall);
+
+#[async_trait]
+impl ModelProvider for ToolCallingProvider {
+    fn name(&self) -> &'static str {
+        "stub"
+    }
+
+    async fn list_models(&self) -> ModelResult<Vec<String>> {
+        Ok(vec![STUB_MODEL.to_string()])
+    }
+
+    async fn embed(&self, model: &str, input: &str) -> ModelResult<Vec<f32>> {
+        StubProvider.embed(model, input).await
+    }
+
+    async fn chat_stream(&self, req: ChatRequest) -> ModelResult<ChatStream> {
+        let model: Arc<str> = Arc::from(req.model.as_str());
+        let chunk = match req.messages.last().filter(|m| m.role == "tool") {
+            Some(result) => ChatChunk {
+                model: model.clone(),
+                content: format!("tool said: {}", result.content),
+                ..Default::default()
+            },
+            None => ChatChunk { model: model.clone(), tool_calls: vec![self.0.clone()], ..Default::default() },
+        };
+        let done = ChatChunk {
+            model,
+            done: true,
+            finish_reason: Some(ds_model::FINISH_STOP.to_string()),
+            usage: Some(Usage { prompt_tokens: req.messages.len() as u64, completion_tokens: 1 }),
+            ..Default::default()
+        };
+        Ok(Box::pin(futures_util::stream::iter([Ok(chunk), Ok(done)])))
+    }
+}
+
+/// [`StubProvider`] reporting an Ollama version, for capability checks
+pub struct VersionedProvider(pub &'static str);
+
+#[async_trait]
+impl ModelProvider for VersionedProvider {
+    fn name(&self) -> &'static str {
+        "ollama"
+    }
+
+    async fn detect(&self) -> ModelResult<Option<Detected>> {
+        Ok(self.detected())
+    }
+
+    fn detected(&self) -> Option<Detected> {
+        let capabilities = Capabilities::ollama(self.0)?;
+        Some(Detected { version: self.0.into(), capabilities })
+    }
+
+    async fn list_models(&self) -> ModelResult<Vec<String>> {
+        StubProvider.list_models().await
+    }
+
+    async fn chat_stream(&self, req: ChatRequest) -> ModelResult<ChatStream> {
+        StubProvider.chat_stream(req).await
+    }
+}
+
+/// [`StubProvider`] whose upstream answers every chat with this status and
+/// error message
+pub struct RefusingProvider(pub u16, pub &'static str);
+
+#[async_trait]
+impl ModelProvider for RefusingProvider {
+    fn name(&self) -> &'static str {
+        "stub"
+    }
+
+    async fn list_models(&self) -> ModelResult<Vec<String>> {
+        StubProvider.list_models().await
+    }
+
+    async fn chat_stream(&self, _req: ChatRequest) -> ModelResult<ChatStream> {
+        Err(ModelError::from_status(self.0, Some(self.1.into())))
+    }
+}
+
+/// [`StubProvider`] keeping every chat request it is sent
+#[derive(Default)]
+pub struct RecordingProvider(pub std::sync::Mutex<Vec<ChatRequest>>);
+
+#[async_trait]
+impl ModelProvider for RecordingProvider {
+    fn name(&self) -> &'static str {
+        "stub"
+    }
+
+    async fn list_models(&self) -> ModelResult<Vec<String>> {
+        StubProvider.list_models().await
+    }
+
+    async fn chat_stream(&self, req: ChatRequest) -> ModelResult<ChatStream> {
+        self.0.lock().unwrap().push(req.clone());
+        StubProvider.chat_stream(req).await
+    }
+}
+
+/// Model provider whose upstream cannot be reached
+pub struct DownProvider;
+
+#[async_trait]
+impl ModelProvider for DownProvider {
+    fn name(&self) -> &'static str {
+        "stub"
+    }
+
+    async fn list_models(&self) -> ModelResult<Vec<String>> {
+        Err(ModelError::Upstream("connection refused".into()))
+    }
+
+    async fn chat_stream(&self, _req: ChatRequest) -> ModelResult<ChatStream> {
+        Err(ModelError::Upstream("connection refused".into()))
+    }
+}
+
+pub struct TestResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+impl TestResponse {
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+
+    pub fn json<T: DeserializeOwned>(&self) -> Result<T> {
+        serde_json::from_slice(&self.body).with_context(|| format!("invalid json body: {}", self.text()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_with_database() {
+        assert_eq!(
+            with_database("postgres://u:p@localhost:5432/postgres", "t1"),
+            "postgres://u:p@localhost:5432/t1"
+        );
+        assert_eq!(
+            with_database("postgresql://u:p@db/app?sslmode=disable", "t1"),
+            "postgresql://u:p@db/t1?sslmode=disable"
+        );
+        assert_eq!(with_database("postgres://u@db", "t1"), "postgres://u@db/t1");
+    }
+}
@@ -0,0 +1,142 @@
+//! Strongly-typed identifiers shared across crates.
+//!
+//! Each id wraps a `Uuid` so a user id cannot be passed where a
+//! conversation id is expected. They serialize as plain UUID strings and,
+//! with the `sqlx` feature, bind as Postgres `UUID` columns.
+
+use serde::{Deserialize, Serialize};
+use std::{fmt, str::FromStr};
+use uuid::Uuid;
+
+macro_rules! id_type {
+    ($(#[$meta:meta])* $name:ident) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+        #[cfg_attr(feature = "sqlx", derive(sqlx::Type))]
+        #[serde(transparent)]
+        #[cfg_attr(feature = "sqlx", sqlx(transparent))]
+        pub struct $name(Uuid);
+
+        impl $name {
+            /// Fresh random (v4) id
+            pub fn generate() -> Self {
+                Self(Uuid::new_v4())
+            }
+
+            pub const fn from_uuid(id: Uuid) -> Self {
+                Self(id)
+            }
+
+            pub const fn as_uuid(&self) -> &Uuid {
+                &self.0
+            }
+        }
+
+        impl From<Uuid> for $name {
+            fn from(id: Uuid) -> Self {
+                Self(id)
+            }
+        }
+
+        impl From<$name> for Uuid {
+            fn from(id: $name) -> Self {
+                id.0
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                self.0.fmt(f)
+            }
+        }
+
+        impl FromStr for $name {
+            type Err = uuid::Error;
+
+            fn from_str(s: &str) -> Result<Self, Self::Err> {
+                Uuid::parse_str(s).map(Self)
+            }
+        }
+    };
+}
+
+id_type!(
+    /// Primary key of `users`; the JWT `sub` claim
+    UserId
+);
+id_type!(
+    /// Identifies one chat conversation
+    ConversationId
+);
+id_type!(
+    /// Identifies an issued API key (never the secret itself)
+    ApiKeyId
+);
+id_type!(
+    /// Organization a user acts on behalf of
+    OrgId
+);
+id_type!(
+    /// One signed-in device or client; the JWT `sid` claim
+    SessionId
+);
+id_type!(
+    /// One issued token; the JWT `jti` claim
+    TokenId
+);
+id_type!(
+    /// One model generation; returned as `X-Generation-Id`
+    GenerationId
+);
+id_type!(
+    /// A stored set of eval cases
+    EvalSetId
+);
+id_type!(
+    /// One run of an eval set against one or more models
+    EvalRunId
+);
+id_type!(
+    /// An A/B model experiment
+    ExperimentId
+);
+id_type!(
+    /// A stored file, such as a generated image
+    FileId
+);
+id_type!(
+    /// A resumable upload, until it is completed into a file
+    UploadId
+);
+id_type!(
+    /// A text document searched by the `rag_search` tool
+    DocumentId
+);
+id_type!(
+    /// A shared group of documents searched together
+    CollectionId
+);
+id_type!(
+    /// One managed agent run
+    AgentRunId
+);
+id_type!(
+    /// A scheduled prompt
+    ScheduleId
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ids_roundtrip_as_uuid_strings() {
+        let id = UserId::generate();
+        let json = serde_json::to_string(&id).unwrap();
+        assert_eq!(json, format!("\"{}\"", id.as_uuid()));
+        assert_eq!(serde_json::from_str::<UserId>(&json).unwrap(), id);
+        assert_eq!(id.to_string().parse::<UserId>().unwrap(), id);
+        assert!("user-1".parse::<ConversationId>().is_err());
+        assert!(serde_json::from_str::<ApiKeyId>("\"user-1\"").is_err());
+    }
+}
@@ -0,0 +1,16 @@
+#![no_main]
+use ds_model::parse_chat_line;
+use libfuzzer_sys::fuzz_target;
+use std::sync::Arc;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(line) = std::str::from_utf8(data) else {
+        return;
+    };
+    let model: Arc<str> = Arc::from("m");
+    if let Ok(chunk) = parse_chat_line(line, &model) {
+        assert!(chunk.done || chunk.finish_reason.is_none());
+        // Parsed chunks always re-serialize
+        serde_json::to_vec(&chunk).unwrap();
+    }
+});
@@ -0,0 +1,33 @@
+#![no_main]
+use ds_model::ndjson::NdjsonDecoder;
+use libfuzzer_sys::fuzz_target;
+
+const MAX: usize = 256;
+
+fn decode(pieces: &[&[u8]]) -> Vec<String> {
+    let mut decoder = NdjsonDecoder::new(MAX);
+    let mut out = Vec::new();
+    for piece in pieces {
+        decoder.push(piece);
+        while let Some(line) = decoder.next_line() {
+            out.push(line);
+        }
+    }
+    out.extend(decoder.finish());
+    out
+}
+
+// First byte picks a split point; the rest is the stream. Splitting the
+// input must never change the decoded lines.
+fuzz_target!(|data: &[u8]| {
+    let Some((&split, bytes)) = data.split_first() else {
+        return;
+    };
+    let whole = decode(&[bytes]);
+    let at = split as usize % (bytes.len() + 1);
+    let (a, b) = bytes.split_at(at);
+    assert_eq!(decode(&[a, b]), whole);
+    for line in &whole {
+        assert!(line.len() <= MAX);
+    }
+});
@@ -0,0 +1,22 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+// Compile the validators directly to keep the server stack out of fuzz builds
+#[path = "../../crates/api/src/validation.rs"]
+#[allow(dead_code)]
+mod validation;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(input) = std::str::from_utf8(data) else {
+        return;
+    };
+    if validation::validate_email(input).is_ok() {
+        assert!(input.len() <= 190);
+        assert_eq!(input.matches('@').count(), 1);
+    }
+    if validation::validate_model_name(input).is_ok() {
+        assert!(input.len() <= 100);
+        assert!(!input.contains(['/', '\\', ' ']));
+    }
+    let _ = validation::validate_password(input);
+});
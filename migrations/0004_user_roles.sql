@@ -0,0 +1,4 @@
+-- user roles: 'admin' unlocks /v1/admin/*. Promote with
+--   UPDATE users SET role = 'admin' WHERE email = '...';
+ALTER TABLE users ADD COLUMN IF NOT EXISTS role TEXT NOT NULL DEFAULT 'user'
+    CHECK (role IN ('user','admin'));
@@ -0,0 +1,8 @@
+-- Daily token consumption per user, for quotas and GET /v1/limits.
+-- No FK to users: service tokens may name principals without a row.
+CREATE TABLE IF NOT EXISTS token_usage_daily (
+    user_id UUID NOT NULL,
+    day DATE NOT NULL,
+    tokens BIGINT NOT NULL DEFAULT 0,
+    PRIMARY KEY (user_id, day)
+);
@@ -0,0 +1,9 @@
+-- quota.threshold events already sent, so each fires once per user and day
+-- even with several API instances
+CREATE TABLE IF NOT EXISTS quota_notifications (
+    user_id UUID NOT NULL,
+    day DATE NOT NULL,
+    threshold SMALLINT NOT NULL,
+    sent_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (user_id, day, threshold)
+);
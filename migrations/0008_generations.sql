@@ -0,0 +1,20 @@
+-- One row per model generation, independent of conversations. The output
+-- is kept only as a SHA-256; replays point at the generation they re-ran.
+CREATE TABLE IF NOT EXISTS generations (
+    id UUID PRIMARY KEY,
+    user_id UUID NOT NULL,
+    org_id UUID,
+    api_key_id UUID,
+    provider TEXT NOT NULL,
+    model TEXT NOT NULL,
+    -- { messages, priority } as received
+    request JSONB NOT NULL,
+    finish_reason TEXT NOT NULL,
+    output_hash TEXT NOT NULL,
+    output_tokens BIGINT NOT NULL,
+    queued_ms BIGINT,
+    replay_of UUID REFERENCES generations(id) ON DELETE SET NULL,
+    created_at TIMESTAMPTZ NOT NULL,
+    completed_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+CREATE INDEX IF NOT EXISTS generations_user_id_idx ON generations(user_id, created_at);
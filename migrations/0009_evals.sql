@@ -0,0 +1,35 @@
+-- Eval sets (prompts plus expected properties) and scored runs of them
+-- against one or more models.
+CREATE TABLE IF NOT EXISTS eval_sets (
+    id UUID PRIMARY KEY,
+    name TEXT NOT NULL,
+    created_by UUID NOT NULL REFERENCES users(id),
+    -- [{ prompt, expect }]
+    cases JSONB NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE TABLE IF NOT EXISTS eval_runs (
+    id UUID PRIMARY KEY,
+    eval_set_id UUID NOT NULL REFERENCES eval_sets(id) ON DELETE CASCADE,
+    models TEXT[] NOT NULL,
+    status TEXT NOT NULL DEFAULT 'running' CHECK (status IN ('running','completed')),
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    finished_at TIMESTAMPTZ
+);
+
+-- One row per case and model
+CREATE TABLE IF NOT EXISTS eval_results (
+    run_id UUID NOT NULL REFERENCES eval_runs(id) ON DELETE CASCADE,
+    case_index INTEGER NOT NULL,
+    model TEXT NOT NULL,
+    output TEXT NOT NULL,
+    output_tokens BIGINT NOT NULL,
+    latency_ms BIGINT NOT NULL,
+    -- [{ check, passed }]
+    checks JSONB NOT NULL,
+    similarity DOUBLE PRECISION,
+    passed BOOLEAN NOT NULL,
+    error TEXT,
+    PRIMARY KEY (run_id, case_index, model)
+);
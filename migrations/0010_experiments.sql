@@ -0,0 +1,34 @@
+-- A/B model experiments. An active experiment on `model` sends each user
+-- to one of its variants, weighted, and keeps them there.
+CREATE TABLE IF NOT EXISTS experiments (
+    id UUID PRIMARY KEY,
+    name TEXT NOT NULL UNIQUE,
+    -- Requested model the experiment intercepts
+    model TEXT NOT NULL,
+    status TEXT NOT NULL DEFAULT 'active' CHECK (status IN ('active','stopped')),
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    stopped_at TIMESTAMPTZ
+);
+-- At most one running experiment per model
+CREATE UNIQUE INDEX IF NOT EXISTS experiments_active_model_idx ON experiments(model) WHERE status = 'active';
+
+CREATE TABLE IF NOT EXISTS experiment_variants (
+    experiment_id UUID NOT NULL REFERENCES experiments(id) ON DELETE CASCADE,
+    name TEXT NOT NULL,
+    model TEXT NOT NULL,
+    weight INTEGER NOT NULL CHECK (weight > 0),
+    PRIMARY KEY (experiment_id, name)
+);
+
+-- First assignment wins, so later weight changes never move a user
+CREATE TABLE IF NOT EXISTS experiment_assignments (
+    experiment_id UUID NOT NULL REFERENCES experiments(id) ON DELETE CASCADE,
+    user_id UUID NOT NULL,
+    variant TEXT NOT NULL,
+    assigned_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (experiment_id, user_id)
+);
+
+ALTER TABLE generations ADD COLUMN IF NOT EXISTS experiment_id UUID REFERENCES experiments(id) ON DELETE SET NULL;
+ALTER TABLE generations ADD COLUMN IF NOT EXISTS variant TEXT;
+CREATE INDEX IF NOT EXISTS generations_experiment_idx ON generations(experiment_id, variant) WHERE experiment_id IS NOT NULL;
@@ -0,0 +1,20 @@
+-- Responses keyed by the embedding of the prompt that produced them.
+-- Entries belong to one org (or one user without an org) and are never
+-- served across owners. Embeddings are pgvector vectors of whatever size
+-- the embedding model gives; the HNSW index covers 768 dimensions (the
+-- default nomic-embed-text), and other sizes need an index of their own.
+CREATE EXTENSION IF NOT EXISTS vector;
+CREATE TABLE IF NOT EXISTS semantic_cache (
+    id BIGSERIAL PRIMARY KEY,
+    owner_id UUID NOT NULL,
+    route TEXT NOT NULL,
+    model TEXT NOT NULL,
+    embedding_model TEXT NOT NULL,
+    embedding vector NOT NULL,
+    response TEXT NOT NULL,
+    hits BIGINT NOT NULL DEFAULT 0,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+CREATE INDEX IF NOT EXISTS semantic_cache_lookup_idx ON semantic_cache(owner_id, route, model, created_at DESC);
+CREATE INDEX IF NOT EXISTS semantic_cache_embedding_768_idx ON semantic_cache
+    USING hnsw ((embedding::vector(768)) vector_cosine_ops) WHERE vector_dims(embedding) = 768;
@@ -0,0 +1,9 @@
+-- Rolling conversation summaries. Messages get an insertion sequence so
+-- turns stored in one transaction keep their order; `summarized_through`
+-- is the last sequence folded into `summary`.
+ALTER TABLE messages ADD COLUMN IF NOT EXISTS seq BIGSERIAL;
+CREATE INDEX IF NOT EXISTS messages_conversation_seq_idx ON messages(conversation_id, seq);
+
+ALTER TABLE conversations ADD COLUMN IF NOT EXISTS summary TEXT NOT NULL DEFAULT '';
+ALTER TABLE conversations ADD COLUMN IF NOT EXISTS summarized_through BIGINT NOT NULL DEFAULT 0;
+ALTER TABLE conversations ADD COLUMN IF NOT EXISTS summary_updated_at TIMESTAMPTZ;
@@ -0,0 +1,2 @@
+-- Results of post-generation processors, keyed by processor name
+ALTER TABLE messages ADD COLUMN IF NOT EXISTS metadata JSONB NOT NULL DEFAULT '{}'::jsonb;
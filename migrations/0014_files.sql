@@ -0,0 +1,20 @@
+-- Stored files (generated images for now), served through signed URLs.
+-- Contents live in the row until an external blob store is configured.
+CREATE TABLE IF NOT EXISTS files (
+    id UUID PRIMARY KEY,
+    user_id UUID NOT NULL,
+    content_type TEXT NOT NULL,
+    bytes BIGINT NOT NULL,
+    data BYTEA NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+CREATE INDEX IF NOT EXISTS files_user_idx ON files(user_id, created_at);
+
+-- Images generated per user per UTC day: a quota class of its own,
+-- separate from token_usage_daily
+CREATE TABLE IF NOT EXISTS image_usage_daily (
+    user_id UUID NOT NULL,
+    day DATE NOT NULL,
+    images BIGINT NOT NULL DEFAULT 0,
+    PRIMARY KEY (user_id, day)
+);
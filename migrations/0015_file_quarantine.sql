@@ -0,0 +1,5 @@
+-- Malware scan outcome per file. Only `clean` files are served; infected
+-- uploads are kept as `quarantined` for review, with what was found.
+ALTER TABLE files ADD COLUMN IF NOT EXISTS status TEXT NOT NULL DEFAULT 'clean';
+ALTER TABLE files ADD COLUMN IF NOT EXISTS scan_result TEXT;
+ALTER TABLE files ADD COLUMN IF NOT EXISTS scanned_at TIMESTAMPTZ;
@@ -0,0 +1,23 @@
+-- Text documents for the rag_search tool, split into chunks embedded with
+-- documents.embedding_model. Like the semantic cache, embeddings are
+-- pgvector vectors of the model's size, with an HNSW index for 768
+-- dimensions.
+CREATE TABLE IF NOT EXISTS documents (
+    id UUID PRIMARY KEY,
+    user_id UUID NOT NULL,
+    title TEXT NOT NULL,
+    chars BIGINT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+CREATE INDEX IF NOT EXISTS documents_user_idx ON documents(user_id, created_at);
+
+CREATE TABLE IF NOT EXISTS document_chunks (
+    document_id UUID NOT NULL REFERENCES documents(id) ON DELETE CASCADE,
+    ordinal INT NOT NULL,
+    content TEXT NOT NULL,
+    embedding_model TEXT NOT NULL,
+    embedding vector NOT NULL,
+    PRIMARY KEY (document_id, ordinal)
+);
+CREATE INDEX IF NOT EXISTS document_chunks_embedding_768_idx ON document_chunks
+    USING hnsw ((embedding::vector(768)) vector_cosine_ops) WHERE vector_dims(embedding) = 768;
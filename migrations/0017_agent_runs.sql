@@ -0,0 +1,29 @@
+-- Managed agent runs (POST /v1/agents/run) and their step traces: one
+-- agent_steps row per model generation or tool call, in order.
+CREATE TABLE IF NOT EXISTS agent_runs (
+    id UUID PRIMARY KEY,
+    user_id UUID NOT NULL,
+    model TEXT NOT NULL,
+    -- { model, messages, tools, max_steps, budget_secs, priority } as received
+    request JSONB NOT NULL,
+    status TEXT NOT NULL DEFAULT 'running' CHECK (status IN ('running','completed')),
+    finish_reason TEXT,
+    output TEXT,
+    steps INT NOT NULL DEFAULT 0,
+    output_tokens BIGINT NOT NULL DEFAULT 0,
+    error TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    finished_at TIMESTAMPTZ
+);
+CREATE INDEX IF NOT EXISTS agent_runs_user_idx ON agent_runs(user_id, created_at);
+
+CREATE TABLE IF NOT EXISTS agent_steps (
+    run_id UUID NOT NULL REFERENCES agent_runs(id) ON DELETE CASCADE,
+    seq INT NOT NULL,
+    -- model | tool
+    kind TEXT NOT NULL,
+    -- The step as streamed to the client
+    detail JSONB NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (run_id, seq)
+);
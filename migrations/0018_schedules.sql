@@ -0,0 +1,23 @@
+-- Scheduled prompts: each runs on a cron expression (UTC) and delivers its
+-- reply to a conversation or a webhook. next_run_at is moved forward when
+-- a run is claimed, so every run happens once across instances.
+CREATE TABLE IF NOT EXISTS schedules (
+    id UUID PRIMARY KEY,
+    user_id UUID NOT NULL,
+    name TEXT NOT NULL,
+    cron TEXT NOT NULL,
+    model TEXT NOT NULL,
+    prompt TEXT NOT NULL,
+    delivery TEXT NOT NULL CHECK (delivery IN ('conversation','webhook')),
+    conversation_id UUID REFERENCES conversations(id) ON DELETE SET NULL,
+    webhook_url TEXT,
+    enabled BOOLEAN NOT NULL DEFAULT TRUE,
+    next_run_at TIMESTAMPTZ,
+    last_run_at TIMESTAMPTZ,
+    -- delivered | failed
+    last_status TEXT,
+    last_error TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+CREATE INDEX IF NOT EXISTS schedules_user_idx ON schedules(user_id, created_at);
+CREATE INDEX IF NOT EXISTS schedules_due_idx ON schedules(next_run_at) WHERE enabled;
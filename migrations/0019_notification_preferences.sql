@@ -0,0 +1,9 @@
+-- Where each user wants notifications: an optional webhook, and per kind
+-- the channels to use ({"quota.warning": ["webhook"]}); kinds not listed
+-- use their defaults
+CREATE TABLE IF NOT EXISTS notification_preferences (
+    user_id UUID PRIMARY KEY,
+    webhook_url TEXT,
+    channels JSONB NOT NULL DEFAULT '{}',
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
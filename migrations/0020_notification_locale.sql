@@ -0,0 +1,2 @@
+-- Language notifications are written in (a BCP 47 tag); NULL is English
+ALTER TABLE notification_preferences ADD COLUMN IF NOT EXISTS locale TEXT;
@@ -0,0 +1,9 @@
+-- Per-org overrides of a subset of the deployment's settings, as the
+-- JSON form of TenantOverrides ({"daily_tokens": 5000}); fields left out
+-- keep the deployment's values
+CREATE TABLE IF NOT EXISTS org_settings (
+    org_id UUID PRIMARY KEY REFERENCES organizations(id) ON DELETE CASCADE,
+    overrides JSONB NOT NULL DEFAULT '{}',
+    updated_by UUID REFERENCES users(id) ON DELETE SET NULL,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
@@ -0,0 +1,4 @@
+-- Secret for HMAC request signing; NULL for keys that send unsigned
+-- requests. Kept as is rather than hashed, since verifying a signature
+-- needs the secret itself
+ALTER TABLE api_keys ADD COLUMN IF NOT EXISTS signing_secret TEXT;
@@ -0,0 +1,13 @@
+-- What selected counters gained, summed over instances, per UTC hour and,
+-- once compacted, per UTC day. `labels` is the Prometheus label set as
+-- rendered on /metrics ({model="llama3"}), empty for unlabelled series
+CREATE TABLE IF NOT EXISTS metric_snapshots (
+    granularity TEXT NOT NULL CHECK (granularity IN ('hour', 'day')),
+    bucket TIMESTAMPTZ NOT NULL,
+    name TEXT NOT NULL,
+    labels TEXT NOT NULL,
+    value BIGINT NOT NULL,
+    PRIMARY KEY (granularity, bucket, name, labels)
+);
+
+CREATE INDEX IF NOT EXISTS metric_snapshots_name_bucket ON metric_snapshots (name, bucket);
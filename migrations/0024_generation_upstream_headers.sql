@@ -0,0 +1,2 @@
+-- Response headers captured from the provider (OLLAMA_CAPTURE_HEADERS)
+ALTER TABLE generations ADD COLUMN IF NOT EXISTS upstream_headers JSONB NOT NULL DEFAULT '{}';
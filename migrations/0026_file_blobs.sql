@@ -0,0 +1,6 @@
+-- File contents may live in a blob store (FILES_STORE) under `blob_key`
+-- instead of the row; rows written before keep theirs in `data`
+ALTER TABLE files ALTER COLUMN data DROP NOT NULL;
+ALTER TABLE files ADD COLUMN IF NOT EXISTS blob_key TEXT;
+ALTER TABLE files DROP CONSTRAINT IF EXISTS files_contents;
+ALTER TABLE files ADD CONSTRAINT files_contents CHECK (data IS NOT NULL OR blob_key IS NOT NULL);
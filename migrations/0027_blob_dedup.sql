@@ -0,0 +1,16 @@
+-- Blob store contents addressed by their SHA-256, shared by every file
+-- with the same bytes. `refs` counts those files; a blob with none since
+-- `unreferenced_at` is deleted by the `blob_gc` job after FILES_GC_GRACE_SECS.
+-- `stored` turns true once the contents are in the store.
+CREATE TABLE IF NOT EXISTS blobs (
+    hash TEXT PRIMARY KEY,
+    bytes BIGINT NOT NULL,
+    refs INT NOT NULL DEFAULT 0 CHECK (refs >= 0),
+    stored BOOLEAN NOT NULL DEFAULT false,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    unreferenced_at TIMESTAMPTZ
+);
+
+CREATE INDEX IF NOT EXISTS blobs_unreferenced ON blobs (unreferenced_at) WHERE refs = 0;
+
+ALTER TABLE files ADD COLUMN IF NOT EXISTS content_hash TEXT REFERENCES blobs (hash);
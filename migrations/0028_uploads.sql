@@ -0,0 +1,23 @@
+-- Resumable uploads: a file sent in parts at increasing offsets, each part
+-- a blob of its own until the upload is completed into a `files` row.
+-- `received` is the offset the next part must start at; the declared
+-- `bytes` count against the owner's storage quota while the upload is open.
+CREATE TABLE IF NOT EXISTS uploads (
+    id UUID PRIMARY KEY,
+    user_id UUID NOT NULL,
+    content_type TEXT NOT NULL,
+    bytes BIGINT NOT NULL CHECK (bytes > 0),
+    received BIGINT NOT NULL DEFAULT 0 CHECK (received <= bytes),
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    expires_at TIMESTAMPTZ NOT NULL
+);
+CREATE INDEX IF NOT EXISTS uploads_user_idx ON uploads(user_id);
+CREATE INDEX IF NOT EXISTS uploads_expires_idx ON uploads(expires_at);
+
+CREATE TABLE IF NOT EXISTS upload_parts (
+    upload_id UUID NOT NULL REFERENCES uploads(id) ON DELETE CASCADE,
+    offset_bytes BIGINT NOT NULL,
+    bytes BIGINT NOT NULL,
+    blob_key TEXT NOT NULL,
+    PRIMARY KEY (upload_id, offset_bytes)
+);
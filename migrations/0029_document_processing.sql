@@ -0,0 +1,22 @@
+-- Documents added from stored files are parsed and indexed in the
+-- background: `status` goes pending -> processing -> ready or failed, with
+-- the reason in `error`. Documents added as text are ready at once.
+-- `metadata` is what the parser found (title, author, pages, ...).
+ALTER TABLE documents
+    ADD COLUMN IF NOT EXISTS status TEXT NOT NULL DEFAULT 'ready'
+        CHECK (status IN ('pending', 'processing', 'ready', 'failed')),
+    ADD COLUMN IF NOT EXISTS error TEXT,
+    ADD COLUMN IF NOT EXISTS file_id UUID REFERENCES files(id) ON DELETE SET NULL,
+    ADD COLUMN IF NOT EXISTS content_type TEXT,
+    ADD COLUMN IF NOT EXISTS chunking TEXT NOT NULL DEFAULT 'sentence',
+    ADD COLUMN IF NOT EXISTS metadata JSONB NOT NULL DEFAULT '{}',
+    ADD COLUMN IF NOT EXISTS chunks INT NOT NULL DEFAULT 0,
+    ADD COLUMN IF NOT EXISTS attempts INT NOT NULL DEFAULT 0,
+    ADD COLUMN IF NOT EXISTS claimed_at TIMESTAMPTZ,
+    ADD COLUMN IF NOT EXISTS processed_at TIMESTAMPTZ;
+
+CREATE INDEX IF NOT EXISTS documents_unprocessed_idx ON documents(created_at)
+    WHERE status IN ('pending', 'processing');
+
+UPDATE documents d SET chunks = (SELECT COUNT(*) FROM document_chunks c WHERE c.document_id = d.id)
+    WHERE status = 'ready' AND chunks = 0;
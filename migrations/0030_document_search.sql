@@ -0,0 +1,7 @@
+-- Keyword side of hybrid document search: each chunk's English text
+-- vector, ranked with ts_rank_cd and fused with embedding similarity.
+ALTER TABLE document_chunks
+    ADD COLUMN IF NOT EXISTS search TSVECTOR
+        GENERATED ALWAYS AS (to_tsvector('english', content)) STORED;
+
+CREATE INDEX IF NOT EXISTS document_chunks_search_idx ON document_chunks USING GIN (search);
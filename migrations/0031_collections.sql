@@ -0,0 +1,18 @@
+-- Collections group documents for searching together. A collection is
+-- managed by its owner; shared with an org (`org_id`), its members may
+-- search it and its admins may add documents to it. Chunks of documents in
+-- a collection are embedded with the collection's `embedding_model`.
+CREATE TABLE IF NOT EXISTS collections (
+    id UUID PRIMARY KEY,
+    owner_id UUID NOT NULL,
+    org_id UUID REFERENCES organizations(id) ON DELETE SET NULL,
+    name TEXT NOT NULL,
+    embedding_model TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+CREATE INDEX IF NOT EXISTS collections_owner_idx ON collections(owner_id);
+CREATE INDEX IF NOT EXISTS collections_org_idx ON collections(org_id) WHERE org_id IS NOT NULL;
+
+ALTER TABLE documents
+    ADD COLUMN IF NOT EXISTS collection_id UUID REFERENCES collections(id) ON DELETE CASCADE;
+CREATE INDEX IF NOT EXISTS documents_collection_idx ON documents(collection_id) WHERE collection_id IS NOT NULL;
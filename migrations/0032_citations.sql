@@ -0,0 +1,9 @@
+-- Where each chunk lies in its document's text, in characters (end
+-- exclusive), for citing it; NULL for chunks indexed before this.
+ALTER TABLE document_chunks
+    ADD COLUMN IF NOT EXISTS start_char INT,
+    ADD COLUMN IF NOT EXISTS end_char INT;
+
+-- The document passages an assistant reply was given, numbered as the
+-- model was told to cite them.
+ALTER TABLE messages ADD COLUMN IF NOT EXISTS citations JSONB NOT NULL DEFAULT '[]'::jsonb;
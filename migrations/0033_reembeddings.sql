@@ -0,0 +1,28 @@
+-- The version of its model each chunk was embedded under
+-- (`documents.embedding_version` at the time); a chunk whose model or
+-- version is not the one expected now is stale, and is re-embedded.
+ALTER TABLE document_chunks ADD COLUMN IF NOT EXISTS embedding_version TEXT NOT NULL DEFAULT '';
+
+-- Runs re-embedding the stale chunks of a collection, or of a user's
+-- documents outside any collection (`user_id`). `total` is the stale
+-- chunks found, `done` those re-embedded so far. At most one run per scope
+-- is active (`pending` or `running`); a run left `running` by an instance
+-- that died is taken over once `claimed_at` is old enough.
+CREATE TABLE IF NOT EXISTS reembeddings (
+    id UUID PRIMARY KEY,
+    collection_id UUID REFERENCES collections(id) ON DELETE CASCADE,
+    user_id UUID,
+    status TEXT NOT NULL DEFAULT 'pending' CHECK (status IN ('pending', 'running', 'done', 'failed')),
+    total INT NOT NULL,
+    done INT NOT NULL DEFAULT 0,
+    attempts INT NOT NULL DEFAULT 0,
+    error TEXT,
+    claimed_at TIMESTAMPTZ,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    finished_at TIMESTAMPTZ,
+    CHECK ((collection_id IS NULL) <> (user_id IS NULL))
+);
+CREATE UNIQUE INDEX IF NOT EXISTS reembeddings_active_idx
+    ON reembeddings ((COALESCE(collection_id, user_id))) WHERE status IN ('pending', 'running');
+CREATE INDEX IF NOT EXISTS reembeddings_scope_idx ON reembeddings ((COALESCE(collection_id, user_id)), created_at);